@@ -0,0 +1,117 @@
+/*
+ * Drives the emulator through the C interface the way an embedding frontend would: loads the
+ * library with dlopen, runs a small RV64 program printing "hi" and exiting with code 7 through
+ * semihosting, and checks what comes back. A second machine stops on an illegal instruction.
+ *
+ *   cargo build --release --features ffi
+ *   cc -I include example/ffi/step.c -o target/step -ldl
+ *   target/step target/release/libriscv_emulator.so
+ */
+
+#include <dlfcn.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "riscv_emulator.h"
+
+#define DRAM_BASE 0x80000000u
+
+static const uint32_t program[] = {
+    0x100002b7, /* lui t0, 0x10000, the UART */
+    0x06800313, /* li t1, 'h' */
+    0x00628023, /* sb t1, 0(t0) */
+    0x06900313, /* li t1, 'i' */
+    0x00628023, /* sb t1, 0(t0) */
+    0x01800513, /* li a0, 0x18, SYS_EXIT */
+    0x00000597, /* auipc a1, 0 */
+    0x01858593, /* addi a1, a1, 24, the argument block below */
+    0x01f01013, /* slli x0, x0, 0x1f */
+    0x00100073, /* ebreak */
+    0x40705013, /* srai x0, x0, 7 */
+    0x00000000,
+    0x00020026, 0x00000000, /* ADP_Stopped_ApplicationExit */
+    0x00000007, 0x00000000, /* the exit code */
+};
+
+static char console[16];
+static size_t console_len;
+
+static void on_console(void *ctx, const uint8_t *data, size_t len) {
+    (void)ctx;
+    for (size_t i = 0; i < len && console_len < sizeof(console) - 1; i++) {
+        console[console_len++] = (char)data[i];
+    }
+}
+
+#define LOAD(name) __typeof__(&name) p_##name = (__typeof__(&name))dlsym(lib, #name)
+
+#define CHECK(cond)                                                          \
+    do {                                                                     \
+        if (!(cond)) {                                                       \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                                  \
+            return 1;                                                        \
+        }                                                                    \
+    } while (0)
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s <libriscv_emulator.so>\n", argv[0]);
+        return 2;
+    }
+    void *lib = dlopen(argv[1], RTLD_NOW);
+    if (!lib) {
+        fprintf(stderr, "%s\n", dlerror());
+        return 2;
+    }
+    LOAD(riscv_machine_new);
+    LOAD(riscv_machine_free);
+    LOAD(riscv_machine_set_console);
+    LOAD(riscv_machine_load_binary);
+    LOAD(riscv_machine_read_memory);
+    LOAD(riscv_machine_get_reg);
+    LOAD(riscv_machine_set_reg);
+    LOAD(riscv_machine_get_pc);
+    LOAD(riscv_machine_step);
+    LOAD(riscv_machine_exit_code);
+
+    CHECK(p_riscv_machine_new(48, 1 << 20) == NULL);
+    RiscvMachine *machine = p_riscv_machine_new(64, 1 << 20);
+    CHECK(machine != NULL);
+    CHECK(p_riscv_machine_set_console(machine, on_console, NULL) == RISCV_OK);
+    CHECK(p_riscv_machine_load_binary(machine, DRAM_BASE, (const uint8_t *)program,
+                                      sizeof(program)) == RISCV_OK);
+    CHECK(p_riscv_machine_load_binary(machine, 0, (const uint8_t *)program, 4) == RISCV_ERROR);
+
+    uint32_t word = 0;
+    CHECK(p_riscv_machine_read_memory(machine, DRAM_BASE + 4, (uint8_t *)&word, 4) == RISCV_OK);
+    CHECK(word == program[1]);
+    CHECK(p_riscv_machine_set_reg(machine, 32, 0) == RISCV_ERROR);
+    CHECK(p_riscv_machine_set_reg(machine, 0, 5) == RISCV_OK);
+    CHECK(p_riscv_machine_get_reg(machine, 0) == 0);
+
+    uint64_t taken = 0;
+    CHECK(p_riscv_machine_step(machine, 3, &taken) == RISCV_STOP_BUDGET);
+    CHECK(taken == 3);
+    CHECK(p_riscv_machine_get_pc(machine) == DRAM_BASE + 12);
+    CHECK(p_riscv_machine_get_reg(machine, 5) == 0x10000000);
+    CHECK(p_riscv_machine_exit_code(machine) == -1);
+
+    CHECK(p_riscv_machine_step(machine, 100, &taken) == RISCV_STOP_EXIT);
+    CHECK(taken == 7);
+    CHECK(p_riscv_machine_exit_code(machine) == 7);
+    CHECK(strcmp(console, "hi") == 0);
+
+    p_riscv_machine_free(machine);
+
+    /* An illegal instruction without a trap handler stops the machine at the instruction. */
+    machine = p_riscv_machine_new(64, 1 << 20);
+    CHECK(machine != NULL);
+    CHECK(p_riscv_machine_step(machine, 10, &taken) == RISCV_STOP_FATAL);
+    CHECK(taken == 1);
+    CHECK(p_riscv_machine_get_pc(machine) == DRAM_BASE);
+    p_riscv_machine_free(machine);
+
+    printf("ok\n");
+    return 0;
+}
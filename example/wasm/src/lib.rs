@@ -0,0 +1,183 @@
+//! Runs the emulator in a browser. JavaScript loads a raw RV32/RV64 binary, steps it and reads
+//! back the registers and the console output.
+//!
+//! ```bash
+//! cargo build --release --target wasm32-unknown-unknown -p riscv-emulator-wasm
+//! wasm-bindgen --target web --out-dir pkg \
+//!     target/wasm32-unknown-unknown/release/riscv_emulator_wasm.wasm
+//! ```
+//!
+//! ```js
+//! import init, { Emulator } from "./pkg/riscv_emulator_wasm.js";
+//! await init();
+//! const emulator = new Emulator(64, program);
+//! emulator.step(1000);
+//! console.log(emulator.registers(), new TextDecoder().decode(emulator.take_output()));
+//! ```
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use riscv_emulator::{
+    cpu::{Cpu, CpuBuilder, StopReason},
+    XLen,
+};
+use wasm_bindgen::prelude::*;
+
+/// The start of the DRAM, where the program is loaded and starts, skipping the boot ROM.
+const DRAM_BASE: u64 = 0x8000_0000;
+/// The DRAM of the machine, the program is loaded at its start.
+const DRAM_SIZE: usize = 16 * 1024 * 1024;
+
+/// Collects the console output until JavaScript takes it.
+#[derive(Clone, Default)]
+struct Console(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+pub struct Emulator {
+    cpu: Cpu,
+    console: Console,
+    exit_code: Option<i32>,
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /// Creates a machine of `xlen` bits, 32 or 64, starting `program` at the beginning of the
+    /// DRAM. Guests exit through semihosting.
+    #[wasm_bindgen(constructor)]
+    pub fn new(xlen: u32, program: &[u8]) -> Result<Emulator, JsError> {
+        let xlen = match xlen {
+            32 => XLen::X32,
+            64 => XLen::X64,
+            _ => return Err(JsError::new("xlen must be 32 or 64")),
+        };
+        let mut dram = program.to_vec();
+        dram.resize(DRAM_SIZE.max(program.len()), 0);
+        let console = Console::default();
+        let cpu = CpuBuilder::new(dram)
+            .xlen(xlen)
+            .entry(DRAM_BASE)
+            .console(Box::new(console.clone()))
+            .semihosting(true)
+            .build();
+        Ok(Emulator {
+            cpu,
+            console,
+            exit_code: None,
+        })
+    }
+
+    /// Executes up to `steps` instructions and returns how many were executed, fewer if the
+    /// guest exited or raised an exception it can't handle. The report of such an exception is
+    /// written to the console output.
+    pub fn step(&mut self, steps: u32) -> u32 {
+        let mut done = 0;
+        while done < steps && self.exit_code.is_none() {
+            let (taken, stop) = self.cpu.run_block((steps - done) as u64);
+            done += taken as u32;
+            match stop {
+                Some(StopReason::Exit(code)) => self.exit_code = Some(code),
+                Some(StopReason::Fatal(report)) => {
+                    self.console
+                        .0
+                        .borrow_mut()
+                        .extend(report.to_string().bytes());
+                    break;
+                }
+                Some(StopReason::DoubleFault(report)) => {
+                    self.console
+                        .0
+                        .borrow_mut()
+                        .extend(report.to_string().bytes());
+                    break;
+                }
+                _ => {}
+            }
+        }
+        done
+    }
+
+    /// The code the guest exited with, if it has.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// x0 to x31 followed by pc.
+    pub fn registers(&self) -> Vec<u64> {
+        let mut regs: Vec<u64> = (0..32).map(|reg| self.cpu.state.xs.reg(reg)).collect();
+        regs.push(self.cpu.state.pc);
+        regs
+    }
+
+    /// Returns the console output written since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.console.0.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Prints "hi" through the UART, then exits with code 7 through semihosting.
+    const PROGRAM: [u32; 16] = [
+        0x1000_02b7, // lui t0, 0x10000, the UART
+        0x0680_0313, // li t1, 'h'
+        0x0062_8023, // sb t1, 0(t0)
+        0x0690_0313, // li t1, 'i'
+        0x0062_8023, // sb t1, 0(t0)
+        0x0180_0513, // li a0, 0x18, SYS_EXIT
+        0x0000_0597, // auipc a1, 0
+        0x0185_8593, // addi a1, a1, 24, the argument block below
+        0x01f0_1013, // slli x0, x0, 0x1f
+        0x0010_0073, // ebreak
+        0x4070_5013, // srai x0, x0, 7
+        0x0000_0000,
+        0x0002_0026, // ADP_Stopped_ApplicationExit
+        0x0000_0000,
+        0x0000_0007, // the exit code
+        0x0000_0000,
+    ];
+
+    fn program() -> Vec<u8> {
+        PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn a_program_prints_and_exits() {
+        let mut emulator = Emulator::new(64, &program()).unwrap();
+        assert_eq!(emulator.step(3), 3);
+        assert_eq!(emulator.registers()[32], 0x8000_000c);
+        assert_eq!(emulator.registers()[5], 0x1000_0000);
+        assert_eq!(emulator.exit_code(), None);
+        assert_eq!(emulator.step(100), 7);
+        assert_eq!(emulator.exit_code(), Some(7));
+        assert_eq!(emulator.take_output(), b"hi");
+        assert!(emulator.take_output().is_empty());
+        // An exited guest doesn't run any further.
+        assert_eq!(emulator.step(100), 0);
+    }
+
+    #[test]
+    fn a_fatal_exception_is_reported_on_the_console() {
+        let mut emulator = Emulator::new(32, &[0xff; 4]).unwrap();
+        assert_eq!(emulator.step(100), 1);
+        assert_eq!(emulator.exit_code(), None);
+        let output = String::from_utf8(emulator.take_output()).unwrap();
+        assert!(output.contains("Illegal instruction"), "{}", output);
+    }
+}
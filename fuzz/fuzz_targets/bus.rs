@@ -0,0 +1,7 @@
+//! Performs arbitrary reads and writes on the physical bus, see `testing::fuzz_bus`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| riscv_emulator::testing::fuzz_bus(data));
@@ -0,0 +1,7 @@
+//! Runs arbitrary bytes as a program, see `testing::fuzz_program`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| riscv_emulator::testing::fuzz_program(data));
@@ -0,0 +1,70 @@
+/*
+ * The C interface of the riscv-emulator library, built with `cargo build --release --features ffi`
+ * as target/release/libriscv_emulator.so. It mirrors src/ffi.rs, see there for the details of
+ * every function. Keep the two in sync, cbindgen.toml regenerates this header with
+ * `cbindgen --config cbindgen.toml --output include/riscv_emulator.h`.
+ */
+
+#ifndef RISCV_EMULATOR_H
+#define RISCV_EMULATOR_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RISCV_OK 0
+/* An argument was invalid, e.g. an address outside of the memory or a register beyond x31. */
+#define RISCV_ERROR -1
+/* The emulator panicked. The machine can only be freed. */
+#define RISCV_POISONED -2
+
+/* riscv_machine_step executed every instruction it was asked to. */
+#define RISCV_STOP_BUDGET 0
+/* The guest exited, see riscv_machine_exit_code. */
+#define RISCV_STOP_EXIT 1
+/* The guest raised an exception it can't handle, e.g. an access fault. pc points at the faulting
+   instruction, or at the M-mode trap vector if fetching the handler faulted. */
+#define RISCV_STOP_FATAL 2
+
+/* A machine created by riscv_machine_new. */
+typedef struct RiscvMachine RiscvMachine;
+
+/* Receives len bytes of console output, ctx is the pointer passed along with the callback. */
+typedef void (*RiscvConsoleFn)(void *ctx, const uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+RiscvMachine *riscv_machine_new(uint32_t xlen, uint64_t mem_size);
+
+void riscv_machine_free(RiscvMachine *machine);
+
+int riscv_machine_set_console(RiscvMachine *machine, RiscvConsoleFn callback, void *ctx);
+
+int riscv_machine_load_binary(RiscvMachine *machine, uint64_t addr, const uint8_t *data,
+                              size_t len);
+
+int riscv_machine_load_elf(RiscvMachine *machine, const uint8_t *data, size_t len);
+
+int riscv_machine_read_memory(RiscvMachine *machine, uint64_t addr, uint8_t *buf, size_t len);
+
+int riscv_machine_write_memory(RiscvMachine *machine, uint64_t addr, const uint8_t *data,
+                               size_t len);
+
+uint64_t riscv_machine_get_reg(RiscvMachine *machine, uint32_t reg);
+
+int riscv_machine_set_reg(RiscvMachine *machine, uint32_t reg, uint64_t value);
+
+uint64_t riscv_machine_get_pc(RiscvMachine *machine);
+
+int riscv_machine_set_pc(RiscvMachine *machine, uint64_t pc);
+
+int riscv_machine_step(RiscvMachine *machine, uint64_t steps, uint64_t *taken);
+
+int riscv_machine_exit_code(RiscvMachine *machine);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RISCV_EMULATOR_H */
@@ -3,124 +3,133 @@ macro_rules! def_insn {
   ($(#[$attr:meta])*, $name:ident) => {
       $(#[$attr])*
       pub struct $name{
-          code: u32
+          code: u32,
+          rd: u32,
+          rs1: u32,
+          rs2: u32,
+          imm: u32,
+          imm_sext: RegT,
       }
   };
 }
 
+/// Operand fields are extracted once when an instruction is decoded, the `Format` methods only
+/// read the stored fields. Fields a format doesn't have are 0.
 #[macro_export]
 macro_rules! impl_format {
-    ($name:ident, R) => {
+    (@fields $name:ident, $imm_len:expr $(, $extra:item)*) => {
         impl Format for $name {
             fn op(&self) -> u32 {
                 self.code & 0x7f
             }
             fn rd(&self) -> u32 {
-                (self.code >> 7) & 0x1f
+                self.rd
             }
             fn rs1(&self) -> u32 {
-                (self.code >> 15) & 0x1f
+                self.rs1
             }
             fn rs2(&self) -> u32 {
-                (self.code >> 20) & 0x1f
-            }
-        }
-    };
-    ($name:ident, I) => {
-        impl Format for $name {
-            fn op(&self) -> u32 {
-                self.code & 0x7f
-            }
-            fn rd(&self) -> u32 {
-                (self.code >> 7) & 0x1f
-            }
-            fn rs1(&self) -> u32 {
-                (self.code >> 15) & 0x1f
+                self.rs2
             }
             fn imm(&self) -> u32 {
-                (self.code >> 20) & 0xfff
+                self.imm
+            }
+            fn imm_sext(&self) -> RegT {
+                self.imm_sext
             }
             fn imm_len(&self) -> usize {
-                12
+                $imm_len
             }
+            $($extra)*
         }
     };
-    ($name:ident, S) => {
-        impl Format for $name {
-            fn op(&self) -> u32 {
-                self.code & 0x7f
-            }
-            fn rs1(&self) -> u32 {
-                (self.code >> 15) & 0x1f
-            }
-            fn rs2(&self) -> u32 {
-                (self.code >> 20) & 0x1f
-            }
-            fn imm(&self) -> u32 {
-                ((self.code >> 7) & 0x1f) | ((self.code >> 25) & 0x7f) << 5
-            }
-
-            fn imm_len(&self) -> usize {
-                12
+    (@new $name:ident, $imm_len:expr, |$code:ident| ($rd:expr, $rs1:expr, $rs2:expr, $imm:expr)
+        $(, $extra:item)*) => {
+        impl $name {
+            fn new($code: u32) -> Self {
+                let imm = $imm;
+                Self {
+                    code: $code,
+                    rd: $rd,
+                    rs1: $rs1,
+                    rs2: $rs2,
+                    imm,
+                    imm_sext: if $imm_len == 0 {
+                        0
+                    } else {
+                        sext(imm as RegT, $imm_len)
+                    },
+                }
             }
         }
+        impl_format!(@fields $name, $imm_len $(, $extra)*);
+    };
+    ($name:ident, R) => {
+        impl_format!(@new $name, 0, |code| (
+            (code >> 7) & 0x1f,
+            (code >> 15) & 0x1f,
+            (code >> 20) & 0x1f,
+            0
+        ));
+    };
+    ($name:ident, A) => {
+        impl_format!(@new $name, 0, |code| (
+            (code >> 7) & 0x1f,
+            (code >> 15) & 0x1f,
+            (code >> 20) & 0x1f,
+            0
+        ),
+        fn aq(&self) -> bool {
+            self.code & (1 << 26) != 0
+        },
+        fn rl(&self) -> bool {
+            self.code & (1 << 25) != 0
+        });
+    };
+    ($name:ident, I) => {
+        impl_format!(@new $name, 12, |code| (
+            (code >> 7) & 0x1f,
+            (code >> 15) & 0x1f,
+            0,
+            (code >> 20) & 0xfff
+        ));
+    };
+    ($name:ident, S) => {
+        impl_format!(@new $name, 12, |code| (
+            0,
+            (code >> 15) & 0x1f,
+            (code >> 20) & 0x1f,
+            ((code >> 7) & 0x1f) | ((code >> 25) & 0x7f) << 5
+        ));
     };
     ($name:ident, B) => {
-        impl Format for $name {
-            fn op(&self) -> u32 {
-                self.code & 0x7f
-            }
-            fn rs2(&self) -> u32 {
-                (self.code >> 20) & 0x1f
-            }
-            fn rs1(&self) -> u32 {
-                (self.code >> 15) & 0x1f
-            }
-            fn imm(&self) -> u32 {
-                ((self.code >> 31) & 0x1) << 12
-                    | ((self.code >> 7) & 0x1) << 11
-                    | ((self.code >> 25) & 0x3f) << 5
-                    | ((self.code >> 8) & 0xf) << 1
-            }
-            fn imm_len(&self) -> usize {
-                13
-            }
-        }
+        impl_format!(@new $name, 13, |code| (
+            0,
+            (code >> 15) & 0x1f,
+            (code >> 20) & 0x1f,
+            ((code >> 31) & 0x1) << 12
+                | ((code >> 7) & 0x1) << 11
+                | ((code >> 25) & 0x3f) << 5
+                | ((code >> 8) & 0xf) << 1
+        ));
     };
     ($name:ident, U) => {
-        impl Format for $name {
-            fn op(&self) -> u32 {
-                self.code & 0x7f
-            }
-            fn rd(&self) -> u32 {
-                (self.code >> 7) & 0x1f
-            }
-            fn imm(&self) -> u32 {
-                (self.code >> 12) << 12
-            }
-            fn imm_len(&self) -> usize {
-                32
-            }
-        }
+        impl_format!(@new $name, 32, |code| (
+            (code >> 7) & 0x1f,
+            0,
+            0,
+            (code >> 12) << 12
+        ));
     };
     ($name:ident, J) => {
-        impl Format for $name {
-            fn op(&self) -> u32 {
-                self.code & 0x7f
-            }
-            fn rd(&self) -> u32 {
-                (self.code >> 7) & 0x1f
-            }
-            fn imm(&self) -> u32 {
-                ((self.code >> 31) & 0x1) << 20
-                    | ((self.code >> 12) & 0xff) << 12
-                    | ((self.code >> 20) & 0x1) << 11
-                    | ((self.code >> 21) & 0x3ff) << 1
-            }
-
-            fn imm_len(&self) -> usize {
-                21
-            }
-        }
+        impl_format!(@new $name, 21, |code| (
+            (code >> 7) & 0x1f,
+            0,
+            0,
+            ((code >> 31) & 0x1) << 20
+                | ((code >> 12) & 0xff) << 12
+                | ((code >> 20) & 0x1) << 11
+                | ((code >> 21) & 0x3ff) << 1
+        ));
     };
 }
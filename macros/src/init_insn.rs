@@ -14,75 +14,226 @@ macro_rules! init_insn {
             fn imm(&self) -> u32 {
                 0
             }
+            /// The immediate sign-extended to the register width.
+            fn imm_sext(&self) -> RegT {
+                0
+            }
             fn op(&self) -> u32 {
                 0
             }
             fn imm_len(&self) -> usize {
                 0
             }
+            /// The acquire bit of an atomic instruction.
+            fn aq(&self) -> bool {
+                false
+            }
+            /// The release bit of an atomic instruction.
+            fn rl(&self) -> bool {
+                false
+            }
         }
 
         pub trait Executable: std::fmt::Display {
             fn exec(&self, cpu: &mut $cpu) -> Result<(), $exception>;
         }
 
+        /// An instruction executed through dynamic dispatch. Without the `dyn-insn` feature `Insn`
+        /// is the enum generated by `insn_enum!` instead.
+        #[cfg(feature = "dyn-insn")]
         pub struct Insn(Box<dyn Executable>);
 
+        #[cfg(feature = "dyn-insn")]
         impl Insn {
             pub fn new<T: 'static + Executable>(e: T) -> Self {
                 Self(Box::new(e))
             }
-            fn exec(&self, cpu: &mut $cpu) -> Result<(), $exception> {
+            pub fn exec(&self, cpu: &mut $cpu) -> Result<(), $exception> {
                 self.0.exec(cpu)
             }
         }
 
+        #[cfg(feature = "dyn-insn")]
         impl std::fmt::Display for Insn {
             fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
                 self.0.fmt(f)
             }
         }
 
-        // fn -> (match_code, mask, insn_creator)
+        /// (match_code, mask, the only XLEN the instruction is defined on if any, insn_creator)
+        pub type InsnEntry = (u32, u32, Option<XLen>, fn(u32) -> Insn);
+
+        #[cfg(not(target_family = "wasm"))]
         #[distributed_slice]
-        pub static INSN_SLICE: [fn() -> (u32, u32, fn(u32) -> Insn)] = [..];
+        pub static INSN_SLICE: [fn() -> InsnEntry] = [..];
+
+        // linkme doesn't support wasm, the instructions listed by `insn_enum!` are used instead.
+        #[cfg(target_family = "wasm")]
+        pub use isa::{INSN_META, INSN_SLICE};
 
-        use std::collections::HashMap;
+        #[cfg(all(target_family = "wasm", feature = "dyn-insn"))]
+        compile_error!(
+            "dyn-insn needs linkme to collect the instructions, which doesn't support wasm"
+        );
+
+        /// The instruction formats of `#[format]`.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum FormatKind {
+            R,
+            I,
+            S,
+            B,
+            U,
+            J,
+            /// R with the aq and rl bits of the A extension in funct7.
+            A,
+        }
+
+        /// Describes an instruction registered with the Instruction derive, see
+        /// `instruction_table`.
+        #[derive(Debug)]
+        pub struct InsnMeta {
+            /// The name the instruction is displayed with.
+            pub name: &'static str,
+            pub match_code: u32,
+            pub mask: u32,
+            pub format: FormatKind,
+            /// The extension of the module defining the instruction, e.g. "I" or "M".
+            pub ext: &'static str,
+            /// The only XLEN the instruction is defined on, if any.
+            pub xlen: Option<XLen>,
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        #[distributed_slice]
+        pub static INSN_META: [InsnMeta] = [..];
+
+        /// Every registered instruction, in no particular order.
+        pub fn instruction_table() -> &'static [InsnMeta] {
+            &INSN_META
+        }
+
+        /// Returns true if every code `special` matches is matched by `general` too, which
+        /// covers fewer bits. The decoder prefers `special`, e.g. PAUSE within FENCE.
+        fn refines(special: &InsnMeta, general: &InsnMeta) -> bool {
+            special.mask != general.mask
+                && special.mask & general.mask == general.mask
+                && special.match_code & general.mask == general.match_code
+        }
+
+        /// Panics if an instruction doesn't match an opcode, or if a code matches two instructions
+        /// defined on the same XLEN, it would decode depending on the registration order. An
+        /// instruction may be a special case of another one, see `refines`.
+        pub fn check_instruction_table() {
+            let table = instruction_table();
+            for (i, insn) in table.iter().enumerate() {
+                assert_eq!(
+                    insn.mask & 0x7f,
+                    0x7f,
+                    "{} doesn't match an opcode",
+                    insn.name
+                );
+                for other in table[..i].iter() {
+                    let same_xlen =
+                        insn.xlen.is_none() || other.xlen.is_none() || insn.xlen == other.xlen;
+                    if same_xlen
+                        && (insn.match_code ^ other.match_code) & insn.mask & other.mask == 0
+                        && !refines(insn, other)
+                        && !refines(other, insn)
+                    {
+                        panic!("{} overlaps {}", insn.name, other.name);
+                    }
+                }
+            }
+        }
+
+        /// Indexes the decode table by opcode (bits 0..7) and funct3 (bits 12..15).
+        fn decode_index(code: u32) -> usize {
+            ((code & 0x7f) | ((code >> 5) & 0x380)) as usize
+        }
 
         pub struct InsnDecoder {
-            // HashMap<opcode, vec<(match_code, mask, insn_creator)>>
-            insn_map: HashMap<u32, Vec<(u32, u32, fn(u32) -> Insn)>>,
+            // Vec<bucket of (match_code, mask, insn_creator)> indexed by `decode_index`. Every
+            // instruction sits in each bucket it can match, which is a single one unless its mask
+            // doesn't cover funct3, so a lookup scans only a few entries.
+            table: Vec<Vec<(u32, u32, fn(u32) -> Insn)>>,
         }
 
         impl InsnDecoder {
-            fn new() -> Self {
-                let mut insn_map = HashMap::new();
+            /// Creates a decoder for the instructions defined on `xlen`, the others decode as
+            /// illegal instructions.
+            fn new(xlen: XLen) -> Self {
+                check_instruction_table();
+                let mut table = vec![Vec::new(); 0x400];
                 for f in INSN_SLICE.iter() {
-                    let (match_code, mask, insn_fn) = f();
-                    let opcode = match_code & 0x7f;
-                    insn_map
-                        .entry(opcode)
-                        .and_modify(|v: &mut Vec<(u32, u32, fn(u32) -> Insn)>| {
-                            v.push((match_code, mask, insn_fn));
-                        })
-                        .or_insert_with(|| vec![(match_code, mask, insn_fn)]);
+                    let (match_code, mask, only_xlen, insn_fn) = f();
+                    if only_xlen.is_some_and(|only| only != xlen) {
+                        continue;
+                    }
+                    for funct3 in 0..8 {
+                        let code = (match_code & 0x7f) | (funct3 << 12);
+                        if (code ^ match_code) & mask & 0x707f == 0 {
+                            table[decode_index(code)].push((match_code, mask, insn_fn));
+                        }
+                    }
+                }
+                // The special cases come first.
+                for bucket in table.iter_mut() {
+                    bucket.sort_by_key(|(_, mask, _)| std::cmp::Reverse(mask.count_ones()));
                 }
-                Self { insn_map: insn_map }
+                Self { table }
             }
 
             fn decode(&self, code: u32) -> Option<Insn> {
-                let opcode = code & 0x7f;
-                if let Some(v) = self.insn_map.get(&opcode) {
-                    for (match_code, mask, insn_fn) in v {
-                        if code & mask == *match_code {
-                            return Some(insn_fn(code));
-                        }
-                    }
-                    None
-                } else {
-                    None
+                self.table[decode_index(code)]
+                    .iter()
+                    .find(|(match_code, mask, _)| code & mask == *match_code)
+                    .map(|(_, _, insn_fn)| insn_fn(code))
+            }
+        }
+    };
+}
+
+/// Generates `Insn` as an enum with a variant per instruction, executed with a `match` instead of
+/// a virtual call. Every instruction registered in `INSN_SLICE` must be listed, on wasm the list
+/// is the registry itself.
+#[macro_export]
+macro_rules! insn_enum {
+    ($cpu:ident, $exception:ident, $($name:ident),* $(,)?) => {
+        pub enum Insn {
+            $($name($name),)*
+        }
+
+        impl Insn {
+            pub fn new<T: Into<Insn>>(e: T) -> Self {
+                e.into()
+            }
+            pub fn exec(&self, cpu: &mut $cpu) -> Result<(), $exception> {
+                match self {
+                    $(Insn::$name(insn) => insn.exec(cpu),)*
+                }
+            }
+        }
+
+        $(impl From<$name> for Insn {
+            fn from(insn: $name) -> Self {
+                Insn::$name(insn)
+            }
+        })*
+
+        impl std::fmt::Display for Insn {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                match self {
+                    $(Insn::$name(insn) => std::fmt::Display::fmt(insn, f),)*
                 }
             }
         }
+
+        /// The registered instructions where linkme can't collect them, see `init_insn!`.
+        #[cfg(target_family = "wasm")]
+        pub static INSN_SLICE: &[fn() -> InsnEntry] = &[$($name::ENTRY,)*];
+
+        #[cfg(target_family = "wasm")]
+        pub static INSN_META: &[InsnMeta] = &[$($name::META,)*];
     };
 }
@@ -1,37 +1,179 @@
+use std::sync::Mutex;
+
 use proc_macro2::Span;
-use syn::{DeriveInput, Error, Ident, NestedMeta, Result};
+use syn::{spanned::Spanned, DeriveInput, Error, Ident, NestedMeta, Result};
+
+/// The instruction formats `impl_format!` knows.
+const FORMATS: [&str; 7] = ["R", "I", "S", "B", "U", "J", "A"];
+/// The bits holding the major opcode, every instruction has to match them.
+const OPCODE_MASK: u32 = 0x7f;
+
+/// An instruction derived in this compilation.
+struct Registered {
+    match_code: u32,
+    mask: u32,
+    xlen: Option<u32>,
+    name: String,
+}
+
+/// Every instruction derived in this compilation, to catch two instructions decoding the same
+/// codes. The decoder checks overlaps at startup as well, this only turns the common copy and
+/// paste mistake into a compile error.
+static REGISTERED: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
 
 pub fn expand(ast: &DeriveInput) -> Result<proc_macro2::TokenStream> {
     let name = &ast.ident;
-    let match_code = parse_code_attr(ast, "match_code")?;
-    let mask = parse_code_attr(ast, "mask")?;
+    let (match_code, match_span) = parse_code_attr(ast, "match_code")?;
+    let (mask, mask_span) = parse_code_attr(ast, "mask")?;
     let format = parse_format_attr(ast)?;
+    let xlen = parse_xlen_attr(ast)?;
+    // The rest of the impls are still generated, so the error is the only one reported.
+    let error = check_codes(name, match_code, match_span, mask, mask_span, xlen)
+        .err()
+        .map(|e| e.to_compile_error());
     let ident_fn = format_ident!(
         "{}_FN",
         Ident::new(&name.to_string().to_uppercase(), name.span())
     );
+    let ident_meta = format_ident!(
+        "{}_META",
+        Ident::new(&name.to_string().to_uppercase(), name.span())
+    );
 
-    let name_str = name.to_string().to_lowercase();
+    let xlen = match xlen {
+        Some(32) => quote!(Some(crate::XLen::X32)),
+        Some(_) => quote!(Some(crate::XLen::X64)),
+        None => quote!(None),
+    };
+    let name_str = match parse_mnemonic_attr(ast)? {
+        Some(mnemonic) => mnemonic,
+        None => name.to_string().to_lowercase(),
+    };
+    // An instruction with the `operands` attribute writes them with its `fmt_operands`, e.g.
+    // "fence r,rw". Atomic instructions are displayed with their ordering bits, e.g.
+    // "amoaddw.aqrl".
+    let display = if ast.attrs.iter().any(|a| a.path.is_ident("operands")) {
+        quote!(
+            write!(f, "{} ", #name_str)?;
+            self.fmt_operands(f)
+        )
+    } else if format == "A" {
+        quote!(
+            let ordering = match (self.aq(), self.rl()) {
+                (false, false) => "",
+                (true, false) => ".aq",
+                (false, true) => ".rl",
+                (true, true) => ".aqrl",
+            };
+            write!(f, "{}{}", #name_str, ordering)
+        )
+    } else {
+        quote!(write!(f, "{}", #name_str))
+    };
     Ok(quote!(
+        #error
         impl_format!(#name, #format);
         impl std::fmt::Display for #name {
             fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(f, "{}", #name_str)
+                #display
             }
         }
 
-        #[distributed_slice(INSN_SLICE)]
-        static #ident_fn: fn() -> (u32, u32, fn(u32) -> Insn) = || -> (u32, u32, fn(u32) -> Insn) {
-            (#match_code, #mask, |code: u32| { Insn::new(#name{code: code}) })
-        };
+        impl #name {
+            pub(crate) const ENTRY: fn() -> InsnEntry = || -> InsnEntry {
+                (#match_code, #mask, #xlen, |code: u32| { Insn::new(#name::new(code)) })
+            };
+
+            // `EXT` is the extension of the module defining the instruction.
+            pub(crate) const META: crate::InsnMeta = crate::InsnMeta {
+                name: #name_str,
+                match_code: #match_code,
+                mask: #mask,
+                format: crate::FormatKind::#format,
+                ext: EXT,
+                xlen: #xlen,
+            };
+        }
+
+        // linkme can't collect the instructions on wasm, `insn_enum!` lists them there.
+        #[cfg(not(target_family = "wasm"))]
+        #[distributed_slice(crate::INSN_SLICE)]
+        static #ident_fn: fn() -> InsnEntry = #name::ENTRY;
+
+        #[cfg(not(target_family = "wasm"))]
+        #[distributed_slice(crate::INSN_META)]
+        static #ident_meta: crate::InsnMeta = #name::META;
     ))
 }
 
-fn parse_code_attr(ast: &DeriveInput, name: &str) -> Result<u32> {
+/// Checks that the instruction can match at all and doesn't repeat another one.
+fn check_codes(
+    name: &Ident,
+    match_code: u32,
+    match_span: Span,
+    mask: u32,
+    mask_span: Span,
+    xlen: Option<u32>,
+) -> Result<()> {
+    if mask & OPCODE_MASK != OPCODE_MASK {
+        return Err(Error::new(
+            mask_span,
+            format!(
+                "mask {:#x} doesn't cover the opcode bits {:#x}",
+                mask, OPCODE_MASK
+            ),
+        ));
+    }
+    if match_code & !mask != 0 {
+        return Err(Error::new(
+            match_span,
+            format!(
+                "match_code {:#x} has bits {:#x} outside of mask {:#x}, it can never match",
+                match_code,
+                match_code & !mask,
+                mask
+            ),
+        ));
+    }
+    register(name, match_code, mask, xlen, match_span)
+}
+
+/// Records the instruction, failing if another one was derived with the same match_code and mask
+/// for the same XLEN. The same name may come again, e.g. when an IDE expands the derive
+/// repeatedly.
+fn register(name: &Ident, match_code: u32, mask: u32, xlen: Option<u32>, span: Span) -> Result<()> {
+    let mut registered = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+    let name = name.to_string();
+    match registered.iter().find(|other| {
+        (other.match_code, other.mask) == (match_code, mask)
+            && (other.xlen.is_none() || xlen.is_none() || other.xlen == xlen)
+            && other.name != name
+    }) {
+        Some(other) => Err(Error::new(
+            span,
+            format!(
+                "match_code {:#x} with mask {:#x} is already used by {}",
+                match_code, mask, other.name
+            ),
+        )),
+        None => {
+            registered.push(Registered {
+                match_code,
+                mask,
+                xlen,
+                name,
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Returns the value of the integer attribute `name` and the span of the value.
+fn parse_code_attr(ast: &DeriveInput, name: &str) -> Result<(u32, Span)> {
     let attr = parse_attr(ast, name)?;
 
     match attr.attr {
-        NestedMeta::Lit(syn::Lit::Int(raw)) => Ok(raw.base10_parse()?),
+        NestedMeta::Lit(syn::Lit::Int(raw)) => Ok((raw.base10_parse()?, raw.span())),
         _ => Err(Error::new(
             attr.ident.span(),
             format!("\"{}\" is expected a int value", name),
@@ -39,13 +181,50 @@ fn parse_code_attr(ast: &DeriveInput, name: &str) -> Result<u32> {
     }
 }
 
+/// Returns the XLEN given by the optional `xlen` attribute, an instruction without one is defined
+/// on both RV32 and RV64.
+fn parse_xlen_attr(ast: &DeriveInput) -> Result<Option<u32>> {
+    if !ast.attrs.iter().any(|a| a.path.is_ident("xlen")) {
+        return Ok(None);
+    }
+    let (xlen, span) = parse_code_attr(ast, "xlen")?;
+    match xlen {
+        32 | 64 => Ok(Some(xlen)),
+        _ => Err(Error::new(span, "\"xlen\" is expected to be 32 or 64")),
+    }
+}
+
+/// Returns the name given by the optional `mnemonic` attribute, e.g. "fence.tso" for a name the
+/// struct can't have. Without one the lowercase struct name is used.
+fn parse_mnemonic_attr(ast: &DeriveInput) -> Result<Option<String>> {
+    if !ast.attrs.iter().any(|a| a.path.is_ident("mnemonic")) {
+        return Ok(None);
+    }
+    let attr = parse_attr(ast, "mnemonic")?;
+    match attr.attr {
+        NestedMeta::Lit(syn::Lit::Str(raw)) => Ok(Some(raw.value())),
+        _ => Err(Error::new(
+            attr.ident.span(),
+            "\"mnemonic\" is expected a string value",
+        )),
+    }
+}
+
 fn parse_format_attr(ast: &DeriveInput) -> Result<Ident> {
     let attr = parse_attr(ast, "format")?;
     match attr.attr {
         NestedMeta::Meta(syn::Meta::Path(path)) => match path.get_ident() {
-            Some(ident) => Ok(ident.clone()),
+            Some(ident) if FORMATS.iter().any(|f| ident == f) => Ok(ident.clone()),
+            Some(ident) => Err(Error::new(
+                ident.span(),
+                format!(
+                    "unknown format \"{}\", expected one of {}",
+                    ident,
+                    FORMATS.join(", ")
+                ),
+            )),
             None => Err(Error::new(
-                attr.ident.span(),
+                path.span(),
                 format!("\"{}\" is expected as Ident", "format"),
             )),
         },
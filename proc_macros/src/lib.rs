@@ -8,7 +8,10 @@ mod derive_insn;
 
 use proc_macro::TokenStream;
 
-#[proc_macro_derive(Instruction, attributes(match_code, mask, format))]
+#[proc_macro_derive(
+    Instruction,
+    attributes(match_code, mask, format, xlen, mnemonic, operands)
+)]
 pub fn instruction(input: TokenStream) -> TokenStream {
     let ast = syn::parse(input).unwrap();
     match derive_insn::expand(&ast) {
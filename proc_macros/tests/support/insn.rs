@@ -0,0 +1,69 @@
+// The items the Instruction derive expands to use, standing in for the ones the emulator crate
+// defines with `init_insn!` and `impl_format!`.
+
+use linkme::distributed_slice;
+use proc_macros::Instruction;
+
+#[allow(unused_macros)]
+macro_rules! impl_format {
+    ($name:ident, A) => {
+        impl_format!($name, R);
+        impl $name {
+            fn aq(&self) -> bool {
+                self.0 & (1 << 26) != 0
+            }
+            fn rl(&self) -> bool {
+                self.0 & (1 << 25) != 0
+            }
+        }
+    };
+    ($name:ident, $format:ident) => {
+        impl $name {
+            fn new(code: u32) -> Self {
+                Self(code)
+            }
+        }
+    };
+}
+
+pub enum XLen {
+    X32,
+    X64,
+}
+
+pub enum FormatKind {
+    R,
+    I,
+    S,
+    B,
+    U,
+    J,
+    A,
+}
+
+pub struct InsnMeta {
+    pub name: &'static str,
+    pub match_code: u32,
+    pub mask: u32,
+    pub format: FormatKind,
+    pub ext: &'static str,
+    pub xlen: Option<XLen>,
+}
+
+pub struct Insn;
+
+impl Insn {
+    pub fn new<T>(_insn: T) -> Self {
+        Insn
+    }
+}
+
+pub type InsnEntry = (u32, u32, Option<XLen>, fn(u32) -> Insn);
+
+#[distributed_slice]
+pub static INSN_SLICE: [fn() -> InsnEntry] = [..];
+
+#[distributed_slice]
+pub static INSN_META: [InsnMeta] = [..];
+
+const EXT: &str = "Test";
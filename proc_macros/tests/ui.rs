@@ -0,0 +1,13 @@
+//! The errors the Instruction derive reports for attributes that can't describe an instruction.
+
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/valid.rs");
+    t.compile_fail("tests/ui/match_outside_mask.rs");
+    t.compile_fail("tests/ui/unknown_format.rs");
+    t.compile_fail("tests/ui/zero_mask.rs");
+    t.compile_fail("tests/ui/duplicate_codes.rs");
+    t.compile_fail("tests/ui/duplicate_codes_for_an_xlen.rs");
+    t.compile_fail("tests/ui/invalid_xlen.rs");
+}
@@ -0,0 +1,17 @@
+#![allow(dead_code)]
+
+include!("../support/insn.rs");
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x33)]
+#[mask(0xfe00707f)]
+struct Add(u32);
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x33)]
+#[mask(0xfe00707f)]
+struct Plus(u32);
+
+fn main() {}
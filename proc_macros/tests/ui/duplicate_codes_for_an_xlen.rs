@@ -0,0 +1,19 @@
+#![allow(dead_code)]
+
+include!("../support/insn.rs");
+
+// An instruction without an XLEN is defined on RV64 too.
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x3b)]
+#[mask(0xfe00707f)]
+struct Addw(u32);
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x3b)]
+#[mask(0xfe00707f)]
+#[xlen(64)]
+struct Plusw(u32);
+
+fn main() {}
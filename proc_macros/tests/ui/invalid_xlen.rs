@@ -0,0 +1,12 @@
+#![allow(dead_code)]
+
+include!("../support/insn.rs");
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x3b)]
+#[mask(0xfe00707f)]
+#[xlen(128)]
+struct Addw(u32);
+
+fn main() {}
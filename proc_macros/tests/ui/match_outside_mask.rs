@@ -0,0 +1,11 @@
+#![allow(dead_code)]
+
+include!("../support/insn.rs");
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x1033)]
+#[mask(0x7f)]
+struct Add(u32);
+
+fn main() {}
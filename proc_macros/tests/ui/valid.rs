@@ -0,0 +1,44 @@
+#![allow(dead_code)]
+
+include!("../support/insn.rs");
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x33)]
+#[mask(0xfe00707f)]
+struct Add(u32);
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x3b)]
+#[mask(0xfe00707f)]
+#[xlen(64)]
+struct Addw(u32);
+
+// Two instructions may share an encoding if they are defined on different XLENs.
+#[derive(Instruction)]
+#[format(I)]
+#[match_code(0x08004033)]
+#[mask(0xfff0707f)]
+#[xlen(32)]
+struct ZextH(u32);
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0x08004033)]
+#[mask(0xfff0707f)]
+#[xlen(64)]
+struct Pack(u32);
+
+#[derive(Instruction)]
+#[format(A)]
+#[match_code(0x202f)]
+#[mask(0xf800707f)]
+struct AmoaddW(u32);
+
+fn main() {
+    assert_eq!(INSN_SLICE.len(), 5);
+    assert_eq!(Add(0).to_string(), "add");
+    assert_eq!(AmoaddW(0x202f).to_string(), "amoaddw");
+    assert_eq!(AmoaddW(0x0600_202f).to_string(), "amoaddw.aqrl");
+}
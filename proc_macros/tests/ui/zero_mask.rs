@@ -0,0 +1,11 @@
+#![allow(dead_code)]
+
+include!("../support/insn.rs");
+
+#[derive(Instruction)]
+#[format(R)]
+#[match_code(0)]
+#[mask(0)]
+struct Add(u32);
+
+fn main() {}
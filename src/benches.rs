@@ -0,0 +1,135 @@
+//! Benchmarks of the paths every executed instruction takes: fetching through the cache of
+//! decoded instructions and the LRU of decoded words, decoding, executing whole blocks, and
+//! translating addresses. Compare `--features bench,dyn-insn` for the cost of executing through
+//! `Box<dyn Executable>` instead of the enum.
+
+extern crate test;
+
+use std::io;
+
+use crate::{
+    cpu::{Cpu, CpuBuilder},
+    testing::*,
+    InsnDecoder, PrivilegeMode, XLen,
+};
+use test::{black_box, Bencher};
+
+/// The steps every iteration of a benchmark executes.
+const STEPS: u64 = 10_000;
+/// The data the loops read and write, a page after the code.
+const DATA: u64 = DRAM_BASE + 0x2000;
+
+/// Counts up in a0, loads the word at a1, adds the count and stores the sum after it.
+const LOOP: [u32; 5] = [
+    addi(10, 10, 1),
+    lw(12, 11, 0),
+    r_type(0, 10, 12, 0, 13, 0x33),
+    sw(13, 11, 4),
+    jal(0, -16),
+];
+
+/// `LOOP` with a store of a NOP over the NOP in its own code page, which drops the decoded
+/// instructions of the page on every iteration. They are decoded again, through the LRU of
+/// decoded words if there is one.
+const SELF_MODIFYING_LOOP: [u32; 6] = [
+    addi(10, 10, 1),
+    lw(12, 11, 0),
+    sw(14, 15, 0),
+    NOP,
+    sw(13, 11, 4),
+    jal(0, -20),
+];
+
+fn cpu(program: &[u32], configure: impl FnOnce(CpuBuilder) -> CpuBuilder) -> Cpu {
+    let binary = program.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+    let builder = CpuBuilder::new(binary)
+        .xlen(XLen::X64)
+        .entry(DRAM_BASE)
+        .console(Box::new(io::sink()));
+    let mut cpu = configure(builder).build();
+    cpu.state.xs.set_reg(11, DATA);
+    cpu.state.xs.set_reg(14, NOP as u64);
+    cpu.state.xs.set_reg(15, DRAM_BASE + 12);
+    cpu
+}
+
+fn steps(b: &mut Bencher, mut cpu: Cpu) {
+    b.iter(|| {
+        for _ in 0..STEPS {
+            assert!(cpu.one_step().is_none());
+        }
+    });
+}
+
+fn blocks(b: &mut Bencher, mut cpu: Cpu) {
+    b.iter(|| {
+        let mut left = STEPS;
+        while left > 0 {
+            let (taken, stop) = cpu.run_block(left);
+            assert!(stop.is_none());
+            left -= taken;
+        }
+    });
+}
+
+#[bench]
+fn step_cached_instructions(b: &mut Bencher) {
+    steps(b, cpu(&LOOP, |builder| builder));
+}
+
+#[bench]
+fn step_decoding_through_the_lru(b: &mut Bencher) {
+    steps(b, cpu(&SELF_MODIFYING_LOOP, |builder| builder));
+}
+
+#[bench]
+fn step_decoding_without_the_lru(b: &mut Bencher) {
+    steps(
+        b,
+        cpu(&SELF_MODIFYING_LOOP, |builder| builder.decode_cache(0)),
+    );
+}
+
+#[bench]
+fn run_blocks(b: &mut Bencher) {
+    blocks(b, cpu(&LOOP, |builder| builder));
+}
+
+#[bench]
+fn run_blocks_without_the_block_cache(b: &mut Bencher) {
+    blocks(b, cpu(&LOOP, |builder| builder.block_cache(false)));
+}
+
+/// Decodes the instructions of `LOOP` without any cache, including extracting their operand
+/// fields.
+#[bench]
+fn decode_instructions(b: &mut Bencher) {
+    let decoder = InsnDecoder::new(XLen::X64);
+    b.iter(|| {
+        for &code in LOOP.iter() {
+            black_box(decoder.decode(code));
+        }
+    });
+}
+
+/// The loop of `step_cached_instructions` in S-mode, with its code and data mapped by Sv39
+/// tables. In M-mode nothing is translated, here every fetch, load and store walks the tables.
+#[bench]
+fn translate_sv39(b: &mut Bencher) {
+    const CODE: u64 = 0x1000_0000;
+    const MAPPED_DATA: u64 = 0x2000_0000;
+    let mut cpu = cpu(&LOOP, |builder| builder);
+    let mut tables = PageTables::new(DRAM_BASE + 0x10_0000);
+    tables.map(&mut cpu, CODE, DRAM_BASE, PTE_V | PTE_R | PTE_X | PTE_A);
+    tables.map(
+        &mut cpu,
+        MAPPED_DATA,
+        DATA,
+        PTE_V | PTE_R | PTE_W | PTE_A | PTE_D,
+    );
+    cpu.state.csrs.set_satp(tables.satp());
+    cpu.state.privilege = PrivilegeMode::Supervisor;
+    cpu.state.pc = CODE;
+    cpu.state.xs.set_reg(11, MAPPED_DATA);
+    steps(b, cpu);
+}
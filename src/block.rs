@@ -0,0 +1,46 @@
+//! Basic blocks: straight-line runs of decoded instructions executed back to back by
+//! `Cpu::run_block`, without going through the fetch and the interrupt check of every step.
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::Insn;
+
+/// The most instructions in a block. Interrupts are only taken between blocks, so this bounds
+/// the steps an interrupt can be delivered late by.
+pub const MAX_BLOCK_LEN: usize = 64;
+
+/// The instruction words of a block along with their decoded instructions.
+pub type Block = Rc<[(u32, Rc<Insn>)]>;
+
+/// Returns true if the instruction `code` ends a block: branches and jumps, which leave the
+/// straight line, and the SYSTEM and MISC-MEM instructions, which may trap, change the privilege,
+/// the address space or the interrupt enables, or flush the caches the block was built from.
+pub fn ends_block(code: u32) -> bool {
+    matches!(code & 0x7f, 0x63 | 0x67 | 0x6f | 0x73 | 0x0f)
+}
+
+/// Blocks keyed by the physical address of their first instruction. Like the decoded instruction
+/// cache it is dropped whenever satp changes, so the address space needn't be part of the key.
+pub struct BlockCache {
+    blocks: HashMap<u64, Block>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, p_addr: u64) -> Option<Block> {
+        self.blocks.get(&p_addr).cloned()
+    }
+
+    pub fn insert(&mut self, p_addr: u64, block: Block) {
+        self.blocks.insert(p_addr, block);
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+}
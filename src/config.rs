@@ -0,0 +1,508 @@
+//! Machine manifests: the harts, the memory map, the devices and the boot files of a machine in a
+//! TOML file, loaded with `--machine`. Flags given on the command line override the manifest. For
+//! example:
+//!
+//! ```toml
+//! [cpu]
+//! xlen = 64
+//! harts = 2
+//! start-pc = 0x8000_0000
+//!
+//! [memory]
+//! dram = { base = 0x8000_0000, size = 0x800_0000 }
+//! regions = [{ base = 0x2000_0000, size = 0x1_0000 }]
+//!
+//! [devices]
+//! console-uart = 0
+//! serial = "serial.log"
+//! disk = "fs.img"
+//! roms = [{ base = 0x2100_0000, size = 0x1000, file = "firmware.bin" }]
+//!
+//! [boot]
+//! kernel = "kernel.bin"
+//! dtb = 0x8700_0000
+//! ```
+//!
+//! Every section and key is optional, relative paths are relative to the manifest. An unknown key
+//! is an error rather than ignored, so a typo doesn't silently change the machine.
+
+use std::{
+    convert::TryFrom,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use toml::{Table, Value};
+
+use crate::{
+    cpu::CpuBuilder,
+    device::{
+        rom::Rom, CLINT_BASE, CLINT_SIZE, PLIC_BASE, PLIC_SIZE, UART1_BASE, UART_BASE, UART_SIZE,
+        VIRTIO_BASE, VIRTIO_SIZE,
+    },
+    XLen,
+};
+
+/// The windows of the devices every machine has, which the manifest can't put anything over.
+const FIXED_WINDOWS: [(&str, u64, u64); 5] = [
+    ("the CLINT", CLINT_BASE, CLINT_SIZE),
+    ("the PLIC", PLIC_BASE, PLIC_SIZE),
+    ("the UART", UART_BASE, UART_SIZE),
+    ("the second UART", UART1_BASE, UART_SIZE),
+    ("the virtio disk", VIRTIO_BASE, VIRTIO_SIZE),
+];
+
+/// A machine described by a manifest. `None` and empty lists leave the default, or what the
+/// command line says.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MachineConfig {
+    pub xlen: Option<XLen>,
+    pub harts: Option<usize>,
+    pub start_pc: Option<u64>,
+    /// The base and size of the DRAM.
+    pub dram: Option<(u64, usize)>,
+    /// The base and size of every memory besides the DRAM.
+    pub regions: Vec<(u64, usize)>,
+    /// Which UART is the console, 0 or 1.
+    pub console_uart: Option<usize>,
+    /// Where the UART that isn't the console writes to.
+    pub serial: Option<PathBuf>,
+    /// The image backing the virtio disk.
+    pub disk: Option<PathBuf>,
+    pub roms: Vec<RomImage>,
+    /// The program loaded at the start of the DRAM.
+    pub kernel: Option<PathBuf>,
+    /// The device tree address passed in a1, see `CpuBuilder::boot_protocol`.
+    pub dtb: Option<u64>,
+}
+
+/// A read-only memory holding the contents of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomImage {
+    pub base: u64,
+    pub size: u64,
+    pub file: PathBuf,
+}
+
+/// A manifest that doesn't describe a machine, along with the key it's about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// The dotted path of the offending key, e.g. `memory.regions[1].size`. Empty for a syntax
+    /// error, whose message has the position.
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(e: ConfigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl MachineConfig {
+    /// Reads the manifest at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&text, dir).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Parses a manifest whose relative paths are relative to `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Self, ConfigError> {
+        let table = text.parse::<Table>().map_err(|e| ConfigError {
+            key: String::new(),
+            message: e.to_string().trim_end().to_string(),
+        })?;
+        let root = Section::new(&table, String::new());
+        root.allow(&["cpu", "memory", "devices", "boot"])?;
+        let mut config = Self::default();
+
+        if let Some(cpu) = root.section("cpu")? {
+            cpu.allow(&["xlen", "harts", "start-pc"])?;
+            config.xlen = match cpu.number("xlen")? {
+                None => None,
+                Some(32) => Some(XLen::X32),
+                Some(64) => Some(XLen::X64),
+                Some(_) => return Err(cpu.error("xlen", "expected 32 or 64")),
+            };
+            config.harts = match cpu.number("harts")? {
+                Some(0) => return Err(cpu.error("harts", "a machine needs at least one hart")),
+                harts => harts.map(|harts| harts as usize),
+            };
+            config.start_pc = cpu.number("start-pc")?;
+        }
+
+        if let Some(memory) = root.section("memory")? {
+            memory.allow(&["dram", "regions"])?;
+            if let Some(dram) = memory.section("dram")? {
+                config.dram = Some(dram.region()?);
+            }
+            for region in memory.sections("regions")? {
+                config.regions.push(region.region()?);
+            }
+        }
+
+        if let Some(devices) = root.section("devices")? {
+            devices.allow(&["console-uart", "serial", "disk", "roms"])?;
+            config.console_uart = match devices.number("console-uart")? {
+                Some(uart) if uart > 1 => {
+                    return Err(devices.error("console-uart", "expected 0 or 1"));
+                }
+                uart => uart.map(|uart| uart as usize),
+            };
+            config.serial = devices.path("serial", dir)?;
+            config.disk = devices.path("disk", dir)?;
+            for rom in devices.sections("roms")? {
+                rom.allow(&["base", "size", "file"])?;
+                config.roms.push(RomImage {
+                    base: rom.required_number("base")?,
+                    size: rom.required_number("size")?,
+                    file: rom
+                        .path("file", dir)?
+                        .ok_or_else(|| rom.error("file", "missing"))?,
+                });
+            }
+        }
+
+        if let Some(boot) = root.section("boot")? {
+            boot.allow(&["kernel", "dtb"])?;
+            config.kernel = boot.path("kernel", dir)?;
+            config.dtb = boot.number("dtb")?;
+        }
+
+        config.check_windows()?;
+        Ok(config)
+    }
+
+    /// Checks that the DRAM, the regions and the ROMs neither overlap each other nor the devices
+    /// every machine has.
+    fn check_windows(&self) -> Result<(), ConfigError> {
+        let mut windows: Vec<(String, u64, u64)> = FIXED_WINDOWS
+            .iter()
+            .map(|&(name, base, len)| (name.to_string(), base, len))
+            .collect();
+        let mut own = Vec::new();
+        if let Some((base, size)) = self.dram {
+            own.push(("memory.dram".to_string(), base, size as u64));
+        }
+        for (i, &(base, size)) in self.regions.iter().enumerate() {
+            own.push((format!("memory.regions[{}]", i), base, size as u64));
+        }
+        for (i, rom) in self.roms.iter().enumerate() {
+            own.push((format!("devices.roms[{}]", i), rom.base, rom.size));
+        }
+        for (key, base, len) in own {
+            if len == 0 {
+                return Err(error(&key, "the size can't be 0"));
+            }
+            let end = base
+                .checked_add(len)
+                .ok_or_else(|| error(&key, "the window reaches past the end of memory"))?;
+            if let Some((other, other_base, _)) =
+                windows.iter().find(|&&(_, other_base, other_len)| {
+                    base < other_base + other_len && other_base < end
+                })
+            {
+                let message = format!(
+                    "[{:#x}, {:#x}) overlaps {} at {:#x}",
+                    base, end, other, other_base
+                );
+                return Err(error(&key, &message));
+            }
+            windows.push((key, base, len));
+        }
+        Ok(())
+    }
+
+    /// Applies what the manifest says about the memory map, the devices and the boot protocol to
+    /// `builder`. Reads the ROM and disk images, the kernel is loaded by whoever created the
+    /// builder.
+    pub fn apply(&self, mut builder: CpuBuilder) -> io::Result<CpuBuilder> {
+        if let Some(xlen) = self.xlen {
+            builder = builder.xlen(xlen);
+        }
+        if let Some((base, size)) = self.dram {
+            builder = builder.dram(base, size);
+        }
+        for &(base, size) in &self.regions {
+            builder = builder.memory(base, size);
+        }
+        for (i, rom) in self.roms.iter().enumerate() {
+            let data = fs::read(&rom.file)?;
+            if data.len() as u64 > rom.size {
+                let message = format!(
+                    "{} has {} bytes, more than the size {:#x}",
+                    rom.file.display(),
+                    data.len(),
+                    rom.size
+                );
+                return Err(error(&format!("devices.roms[{}].file", i), &message).into());
+            }
+            builder = builder.device(rom.base, rom.size, None, Box::new(Rom::new(data)));
+        }
+        if let Some(pc) = self.start_pc {
+            builder = builder.entry(pc);
+        }
+        if let Some(dtb) = self.dtb {
+            builder = builder.boot_protocol(dtb);
+        }
+        if let Some(disk) = &self.disk {
+            builder = builder.disk(fs::read(disk)?);
+        }
+        Ok(builder)
+    }
+}
+
+fn error(key: &str, message: &str) -> ConfigError {
+    ConfigError {
+        key: key.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// A table of the manifest along with its dotted path, for the error messages.
+struct Section<'a> {
+    table: &'a Table,
+    path: String,
+}
+
+impl<'a> Section<'a> {
+    fn new(table: &'a Table, path: String) -> Self {
+        Self { table, path }
+    }
+
+    fn key(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.path, name)
+        }
+    }
+
+    fn error(&self, name: &str, message: &str) -> ConfigError {
+        error(&self.key(name), message)
+    }
+
+    /// Fails on the first key that isn't one of `known`.
+    fn allow(&self, known: &[&str]) -> Result<(), ConfigError> {
+        match self.table.keys().find(|key| !known.contains(&key.as_str())) {
+            Some(key) => Err(self.error(key, "unknown key")),
+            None => Ok(()),
+        }
+    }
+
+    fn section(&self, name: &str) -> Result<Option<Section<'a>>, ConfigError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(Value::Table(table)) => Ok(Some(Section::new(table, self.key(name)))),
+            Some(_) => Err(self.error(name, "expected a table")),
+        }
+    }
+
+    /// Returns the tables of the array `name`, e.g. `[[memory.regions]]`.
+    fn sections(&self, name: &str) -> Result<Vec<Section<'a>>, ConfigError> {
+        let array = match self.table.get(name) {
+            None => return Ok(Vec::new()),
+            Some(Value::Array(array)) => array,
+            Some(_) => return Err(self.error(name, "expected an array of tables")),
+        };
+        array
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let path = format!("{}[{}]", self.key(name), i);
+                match value {
+                    Value::Table(table) => Ok(Section::new(table, path)),
+                    _ => Err(error(&path, "expected a table")),
+                }
+            })
+            .collect()
+    }
+
+    fn number(&self, name: &str) -> Result<Option<u64>, ConfigError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(Value::Integer(n)) if *n >= 0 => Ok(Some(*n as u64)),
+            Some(_) => Err(self.error(name, "expected a non-negative integer")),
+        }
+    }
+
+    fn required_number(&self, name: &str) -> Result<u64, ConfigError> {
+        self.number(name)?
+            .ok_or_else(|| self.error(name, "missing"))
+    }
+
+    /// Returns the path `name`, relative to `dir` unless it's absolute.
+    fn path(&self, name: &str, dir: &Path) -> Result<Option<PathBuf>, ConfigError> {
+        match self.table.get(name) {
+            None => Ok(None),
+            Some(Value::String(path)) => Ok(Some(dir.join(path))),
+            Some(_) => Err(self.error(name, "expected a path")),
+        }
+    }
+
+    /// Reads a `{ base, size }` window.
+    fn region(&self) -> Result<(u64, usize), ConfigError> {
+        self.allow(&["base", "size"])?;
+        let base = self.required_number("base")?;
+        let size = self.required_number("size")?;
+        let size = usize::try_from(size).map_err(|_| self.error("size", "too large"))?;
+        Ok((base, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::{DRAM_BASE, UART_BASE},
+        machine::Machine,
+    };
+
+    const MANIFEST: &str = r#"
+        [cpu]
+        harts = 2
+        start-pc = 0x8000_0000
+
+        [memory]
+        dram = { base = 0x8000_0000, size = 0x10_0000 }
+        regions = [
+            { base = 0x2000_0000, size = 0x1_0000 },
+            { base = 0x3000_0000, size = 0x2000 },
+        ]
+
+        [devices]
+        console-uart = 1
+        roms = [{ base = 0x2100_0000, size = 0x1000, file = "firmware.bin" }]
+
+        [boot]
+        kernel = "kernel.bin"
+        dtb = 0x8008_0000
+    "#;
+
+    #[test]
+    fn a_manifest_describes_the_machine() {
+        let dir = std::env::temp_dir().join(format!("config-machine-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("firmware.bin"), [0x13, 0, 0, 0]).unwrap();
+        let config = MachineConfig::parse(MANIFEST, &dir).unwrap();
+        assert_eq!(config.xlen, None);
+        assert_eq!(config.console_uart, Some(1));
+        assert_eq!(config.kernel, Some(dir.join("kernel.bin")));
+
+        let builder = config.apply(CpuBuilder::new(Vec::new())).unwrap();
+        let machine = Machine::new(builder, config.harts.unwrap());
+        let windows = machine.bus().windows();
+        for window in [
+            (0x2000_0000, 0x1_0000, None),
+            (0x2100_0000, 0x1000, None),
+            (0x3000_0000, 0x2000, None),
+            (DRAM_BASE, 0x10_0000, None),
+            (UART_BASE, 0x100, Some(10)),
+        ] {
+            assert!(windows.contains(&window), "{:x?} in {:x?}", window, windows);
+        }
+        assert_eq!(machine.bus().read::<u32>(0x2100_0000), Ok(0x13));
+        assert_eq!(machine.bus().read::<u32>(0x3000_1ffc), Ok(0));
+        // Both harts follow the boot protocol from the start pc.
+        for id in 0..2 {
+            let hart = machine.hart(id);
+            assert_eq!(hart.state.pc, DRAM_BASE);
+            assert_eq!(hart.state.xs.reg(10), id as u64);
+            assert_eq!(hart.state.xs.reg(11), 0x8008_0000);
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_key() {
+        let cases = [
+            ("[cpu]\nxlen = 128\n", "cpu.xlen", "expected 32 or 64"),
+            (
+                "[cpu]\nharts = 0\n",
+                "cpu.harts",
+                "a machine needs at least one hart",
+            ),
+            (
+                "[cpu]\nstart-pc = -4\n",
+                "cpu.start-pc",
+                "expected a non-negative integer",
+            ),
+            ("[cpu]\nxlne = 32\n", "cpu.xlne", "unknown key"),
+            (
+                "[boot]\ninitrd = \"initrd.img\"\n",
+                "boot.initrd",
+                "unknown key",
+            ),
+            ("[gpu]\n", "gpu", "unknown key"),
+            ("memory = 1\n", "memory", "expected a table"),
+            (
+                "[memory]\nregions = [{ base = 0x2000_0000 }]\n",
+                "memory.regions[0].size",
+                "missing",
+            ),
+            (
+                "[memory]\nregions = [{ base = 0x2000_0000, size = 0x1000 }, 5]\n",
+                "memory.regions[1]",
+                "expected a table",
+            ),
+            (
+                "[devices]\nroms = [{ base = 0x2000_0000, size = 0x1000 }]\n",
+                "devices.roms[0].file",
+                "missing",
+            ),
+            (
+                "[devices]\nserial = 1\n",
+                "devices.serial",
+                "expected a path",
+            ),
+            (
+                "[devices]\nconsole-uart = 2\n",
+                "devices.console-uart",
+                "expected 0 or 1",
+            ),
+            (
+                "[memory]\nregions = [{ base = 0x1000_0080, size = 0x1000 }]\n",
+                "memory.regions[0]",
+                "[0x10000080, 0x10001080) overlaps the UART at 0x10000000",
+            ),
+            (
+                "[memory]\ndram = { base = 0x8000_0000, size = 0x1000 }\n\
+                 regions = [{ base = 0x8000_0800, size = 0x1000 }]\n",
+                "memory.regions[0]",
+                "[0x80000800, 0x80001800) overlaps memory.dram at 0x80000000",
+            ),
+        ];
+        for &(manifest, key, message) in cases.iter() {
+            let err = MachineConfig::parse(manifest, Path::new("")).unwrap_err();
+            assert_eq!(
+                (err.key.as_str(), err.message.as_str()),
+                (key, message),
+                "{}",
+                manifest
+            );
+        }
+
+        // Syntax errors come with the position from the parser.
+        let err = MachineConfig::parse("[cpu\n", Path::new("")).unwrap_err();
+        assert_eq!(err.key, "");
+        assert!(err.to_string().contains("line 1"), "{}", err);
+    }
+}
@@ -0,0 +1,684 @@
+//! Console input delivered at step boundaries, so that a run can be reproduced exactly. A console
+//! script has a `<step> <byte>` line per received byte, the byte in hex, e.g. `1200345 0x6c`. The
+//! step counts the steps since the run loop started. Steps rather than minstret timestamp the
+//! bytes: minstret stands still while the hart waits in WFI, so it can't tell when during an idle
+//! period a byte arrived, while steps keep counting and the timers advance with them.
+//!
+//! An interactive run reads the host stdin instead, see `StdinConsole`.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::device::uart::UartInput;
+
+/// Feeds the UART before every step.
+pub enum ConsoleFeed {
+    /// Replays a console script. A byte is received at its step, or later if the guest hasn't read
+    /// the previous byte by then.
+    Replay(VecDeque<(u64, u8)>),
+    /// Receives bytes from stdin, or another input, and writes the steps they were received at as a
+    /// console script.
+    Record {
+        stdin: Receiver<u8>,
+        pending: Option<u8>,
+        script: Box<dyn Write>,
+    },
+}
+
+impl ConsoleFeed {
+    pub fn replay(script: impl Read) -> io::Result<Self> {
+        let mut events = VecDeque::new();
+        for line in BufReader::new(script).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            events.push_back(parse_event(&line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid console script line: {}", line),
+                )
+            })?);
+        }
+        Ok(ConsoleFeed::Replay(events))
+    }
+
+    /// Starts a thread reading `input`, usually stdin, the bytes are passed on by `before_step`.
+    pub fn record(mut input: impl Read + Send + 'static, script: Box<dyn Write>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut byte = [0; 1];
+            // Stops when the input is closed or the run loop is gone.
+            while let Ok(1) = input.read(&mut byte) {
+                if tx.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        ConsoleFeed::Record {
+            stdin: rx,
+            pending: None,
+            script,
+        }
+    }
+
+    pub fn before_step(&mut self, step: u64, input: &UartInput) -> io::Result<()> {
+        match self {
+            ConsoleFeed::Replay(events) => {
+                if let Some(&(at, byte)) = events.front() {
+                    if at <= step && input.try_receive(byte) {
+                        events.pop_front();
+                    }
+                }
+            }
+            ConsoleFeed::Record {
+                stdin,
+                pending,
+                script,
+            } => {
+                if pending.is_none() {
+                    *pending = stdin.try_recv().ok();
+                }
+                if let Some(byte) = *pending {
+                    if input.try_receive(byte) {
+                        *pending = None;
+                        writeln!(script, "{} {:#04x}", step, byte)?;
+                        script.flush()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_event(line: &str) -> Option<(u64, u8)> {
+    let mut words = line.split_whitespace();
+    let step = words.next()?.parse().ok()?;
+    let byte = u8::from_str_radix(words.next()?.strip_prefix("0x")?, 16).ok()?;
+    Some((step, byte))
+}
+
+/// Ctrl+A, the default escape byte.
+pub const DEFAULT_ESCAPE: u8 = 0x01;
+
+/// How long a partial line of console output, e.g. a shell prompt, may wait before it's flushed.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(5);
+/// The console output buffered before it's written out without waiting for a newline.
+pub const OUTPUT_BUFFER_LEN: usize = 4096;
+
+/// The console output of the guest. Bytes are collected and written to the host in one go per
+/// line, when `OUTPUT_BUFFER_LEN` bytes are pending or every `FLUSH_INTERVAL`, so the output of a
+/// busy guest isn't a write per byte. Diagnostics go to stderr; flushing this first keeps them from
+/// landing in the middle of a line the guest printed. Clones share the buffer.
+#[derive(Clone)]
+pub struct ConsoleOutput {
+    shared: Arc<Mutex<OutputState>>,
+}
+
+struct OutputState {
+    out: Box<dyn Write + Send>,
+    buf: Vec<u8>,
+    log: Option<ConsoleLog>,
+}
+
+impl OutputState {
+    fn write_out(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.out.write_all(&self.buf)?;
+        self.out.flush()?;
+        if let Some(log) = self.log.as_mut() {
+            log.write_all(&self.buf)?;
+        }
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl ConsoleOutput {
+    /// Buffers the output for `out`, partial lines are only written out by `flush`.
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(OutputState {
+                out,
+                buf: Vec::with_capacity(OUTPUT_BUFFER_LEN),
+                log: None,
+            })),
+        }
+    }
+
+    /// Buffers the output for `out` and starts a thread flushing partial lines, e.g. a shell
+    /// prompt, every `FLUSH_INTERVAL`. The thread stops once every clone is dropped.
+    pub fn spawn(out: Box<dyn Write + Send>) -> Self {
+        let output = Self::new(out);
+        let shared = Arc::downgrade(&output.shared);
+        thread::spawn(move || flush_periodically(shared));
+        output
+    }
+
+    /// Also copies the output to `log`, see `ConsoleLog`.
+    pub fn tee(&self, log: ConsoleLog) {
+        self.lock().log = Some(log);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, OutputState> {
+        self.shared
+            .lock()
+            .expect("failed to get the console output")
+    }
+}
+
+fn flush_periodically(shared: Weak<Mutex<OutputState>>) {
+    loop {
+        thread::sleep(FLUSH_INTERVAL);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => break,
+        };
+        let mut state = shared.lock().expect("failed to get the console output");
+        if let Err(e) = state.write_out() {
+            log::error!("failed to write the console output: {}", e);
+            break;
+        }
+    }
+}
+
+impl Write for ConsoleOutput {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        state.buf.extend_from_slice(bytes);
+        if bytes.contains(&b'\n') || state.buf.len() >= OUTPUT_BUFFER_LEN {
+            state.write_out()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().write_out()
+    }
+}
+
+/// A copy of the console output for `--console-log`. Every line starts with the host time since
+/// the log was created and the instructions retired by then, e.g. `[   1.204311s     52013377] `.
+/// The run loop keeps the count up to date through `retired`.
+pub struct ConsoleLog {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+    retired: Arc<AtomicU64>,
+    line_start: bool,
+}
+
+impl ConsoleLog {
+    pub fn new(out: Box<dyn Write + Send>, retired: Arc<AtomicU64>) -> Self {
+        Self {
+            out,
+            start: Instant::now(),
+            retired,
+            line_start: true,
+        }
+    }
+}
+
+impl Write for ConsoleLog {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+            if self.line_start {
+                let elapsed = self.start.elapsed().as_secs_f64();
+                let retired = self.retired.load(Ordering::Relaxed);
+                write!(self.out, "[{:11.6}s {:12}] ", elapsed, retired)?;
+            }
+            self.out.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+        self.out.flush()?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// What a byte read from the host stdin amounts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escaped {
+    /// A byte for the guest.
+    Byte(u8),
+    /// The escape byte followed by `x`: stop the emulator.
+    Quit,
+    /// The escape byte followed by `h`: print the escape commands.
+    Help,
+}
+
+/// Separates the escape commands from the bytes for the guest. After the escape byte, `x` stops
+/// the emulator, `h` prints the commands and a second escape byte sends it to the guest. Any
+/// other byte after the escape byte is dropped.
+pub struct EscapeParser {
+    escape: u8,
+    escaped: bool,
+}
+
+impl EscapeParser {
+    pub fn new(escape: u8) -> Self {
+        Self {
+            escape,
+            escaped: false,
+        }
+    }
+
+    /// Takes the next byte read from stdin, `None` while it's part of an escape sequence.
+    pub fn feed(&mut self, byte: u8) -> Option<Escaped> {
+        if !self.escaped {
+            if byte == self.escape {
+                self.escaped = true;
+                return None;
+            }
+            return Some(Escaped::Byte(byte));
+        }
+        self.escaped = false;
+        match byte {
+            b'x' | b'X' => Some(Escaped::Quit),
+            b'h' | b'H' | b'?' => Some(Escaped::Help),
+            _ if byte == self.escape => Some(Escaped::Byte(byte)),
+            _ => None,
+        }
+    }
+
+    fn help(&self) -> String {
+        let key = describe_key(self.escape);
+        format!(
+            "\n{key} x  exit the emulator\n{key} h  print this help\n{key} {key}  send {key} to the guest\n",
+            key = key
+        )
+    }
+}
+
+/// Returns the name of a control key like `C-a`, or the byte itself.
+fn describe_key(byte: u8) -> String {
+    if (1..=26).contains(&byte) {
+        format!("C-{}", (b'a' + byte - 1) as char)
+    } else {
+        format!("{:#04x}", byte)
+    }
+}
+
+/// Parses the escape byte of `--escape-char`: a letter stands for Ctrl and the letter.
+pub fn parse_escape(arg: &str) -> Option<u8> {
+    match arg.as_bytes() {
+        [letter] if letter.is_ascii_alphabetic() => Some(letter.to_ascii_lowercase() & 0x1f),
+        _ => None,
+    }
+}
+
+/// The interactive console: passes the host stdin to the UART and watches it for the escape
+/// commands. With raw mode the host terminal neither echoes nor buffers lines, so control
+/// characters such as Ctrl+C reach the guest; the terminal is restored when this is dropped.
+pub struct StdinConsole {
+    quit: Arc<AtomicBool>,
+    _raw: Option<RawTerminal>,
+}
+
+impl StdinConsole {
+    /// Starts a thread reading stdin, each byte is received by the UART once the guest has read
+    /// the previous one.
+    pub fn spawn(input: UartInput, escape: u8, raw: bool) -> io::Result<Self> {
+        let raw = if raw { RawTerminal::enable()? } else { None };
+        let quit = Arc::new(AtomicBool::new(false));
+        let stop = quit.clone();
+        thread::spawn(move || forward_input(io::stdin(), &input, escape, &stop));
+        Ok(Self { quit, _raw: raw })
+    }
+
+    /// Returns true once the escape command to stop the emulator was typed.
+    pub fn quit_requested(&self) -> bool {
+        self.quit.load(Ordering::Acquire)
+    }
+}
+
+/// Passes the bytes read from `reader` to `input` until the input ends, fails or the escape
+/// command to stop the emulator is typed, which sets `quit`.
+fn forward_input(mut reader: impl Read, input: &UartInput, escape: u8, quit: &AtomicBool) {
+    let mut parser = EscapeParser::new(escape);
+    let mut byte = [0; 1];
+    loop {
+        match reader.read(&mut byte) {
+            // The end of the input, e.g. of a file piped to stdin. Nothing more will come.
+            Ok(0) => break,
+            Ok(_) => match parser.feed(byte[0]) {
+                Some(Escaped::Byte(byte)) => input.receive_blocking(byte),
+                Some(Escaped::Quit) => {
+                    quit.store(true, Ordering::Release);
+                    break;
+                }
+                Some(Escaped::Help) => eprint!("{}", parser.help()),
+                None => {}
+            },
+            Err(e) => {
+                log::error!("failed to read stdin: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Keeps the host terminal in raw mode for input and restores its attributes when dropped, or
+/// when the emulator panics. Output processing stays on, so the guest's `\n` still starts a new
+/// line.
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    /// Switches stdin to raw mode, `None` if stdin isn't a terminal.
+    fn enable() -> io::Result<Option<Self>> {
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+            return Ok(None);
+        }
+        // SAFETY: termios is plain data, tcgetattr only writes the termios it's given.
+        let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        set_attributes(&raw)?;
+        // A panic on another thread doesn't unwind the guard, the hook restores the terminal
+        // before the message is printed.
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let _ = set_attributes(&original);
+            hook(info);
+        }));
+        Ok(Some(Self { original }))
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Err(e) = set_attributes(&self.original) {
+            log::error!("failed to restore the terminal: {}", e);
+        }
+    }
+}
+
+fn set_attributes(attrs: &libc::termios) -> io::Result<()> {
+    // SAFETY: tcsetattr only reads the termios it's given.
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, attrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::CpuBuilder,
+        device::{
+            uart::{Uart, RX_FIFO_LEN},
+            Device, UART_BASE,
+        },
+        testing::*,
+        RegT,
+    };
+
+    /// An in-memory host terminal, unlike `testing::SharedOutput` it can be sent to other threads.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn contents(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for Captured {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Reads RHR like the guest, or None if nothing was received.
+    fn read_byte(uart: &Uart) -> Option<u8> {
+        let lsr = uart.read(5, 1).unwrap();
+        (lsr & 1 != 0).then(|| uart.read(0, 1).unwrap() as u8)
+    }
+
+    #[test]
+    fn replay_delivers_the_bytes_at_their_steps() {
+        let uart = Uart::with_output(Box::new(io::sink()));
+        let input = uart.input();
+        let script = "3 0x6c\n\n3 0x73\n10 0x0a\n";
+        let mut feed = ConsoleFeed::replay(script.as_bytes()).unwrap();
+        let mut received = Vec::new();
+        for step in 0..12 {
+            feed.before_step(step, &input).unwrap();
+            if let Some(byte) = read_byte(&uart) {
+                received.push((step, byte));
+            }
+        }
+        // One byte a step at most, a late byte comes at the next step.
+        assert_eq!(received, [(3, b'l'), (4, b's'), (10, b'\n')]);
+    }
+
+    #[test]
+    fn replay_waits_for_room_in_the_fifo() {
+        let uart = Uart::with_output(Box::new(io::sink()));
+        let input = uart.input();
+        let script: String = (0..=RX_FIFO_LEN)
+            .map(|i| format!("0 {:#04x}\n", i))
+            .collect();
+        let mut feed = ConsoleFeed::replay(script.as_bytes()).unwrap();
+        for step in 0..2 * RX_FIFO_LEN as u64 {
+            feed.before_step(step, &input).unwrap();
+        }
+        for i in 0..RX_FIFO_LEN {
+            assert_eq!(read_byte(&uart), Some(i as u8));
+        }
+        assert_eq!(read_byte(&uart), None);
+        feed.before_step(2 * RX_FIFO_LEN as u64, &input).unwrap();
+        assert_eq!(read_byte(&uart), Some(RX_FIFO_LEN as u8));
+    }
+
+    const SESSION: &[u8] = b"ls -l\n";
+
+    /// Echoes `SESSION.len()` bytes from the UART, polling for each. a0 sums the bytes and a2
+    /// counts the polls, which depends on the steps the bytes arrived at.
+    fn echo(feed: &mut ConsoleFeed) -> (Vec<u8>, Vec<RegT>) {
+        let program = [
+            lui(11, (UART_BASE >> 12) as u32),
+            i_type(5, 11, 4, 5, 0x03),
+            i_type(1, 5, 7, 5, 0x13),
+            addi(12, 12, 1),
+            b_type(-12, 0, 5, 0),
+            i_type(0, 11, 4, 6, 0x03),
+            s_type(0, 6, 11, 0, 0x23),
+            r_type(0, 6, 10, 0, 10, 0x33),
+            addi(13, 13, 1),
+            addi(7, 0, SESSION.len() as i32),
+            b_type(-36, 7, 13, 1),
+            EBREAK,
+        ];
+        let binary = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let output = SharedOutput::default();
+        let mut cpu = CpuBuilder::new(binary)
+            .entry(DRAM_BASE)
+            .console(Box::new(output.clone()))
+            .deterministic(true)
+            .build();
+        let input = cpu.mmu.bus.borrow().uart_input.clone();
+        let mut step = 0;
+        while cpu.state.pc != DRAM_BASE + 4 * 11 {
+            feed.before_step(step, &input).unwrap();
+            assert!(cpu.one_step().is_none());
+            step += 1;
+            assert!(step < 100_000_000, "the input never came");
+        }
+        (output.contents(), registers(&cpu))
+    }
+
+    #[test]
+    fn a_recorded_session_replays_the_same() {
+        let script = SharedOutput::default();
+        let mut feed = ConsoleFeed::record(io::Cursor::new(SESSION), Box::new(script.clone()));
+        let (output, registers) = echo(&mut feed);
+        assert_eq!(output, SESSION);
+        let sum: RegT = SESSION.iter().map(|&byte| byte as RegT).sum();
+        assert_eq!(registers[10], sum);
+        let script = script.contents();
+        assert_eq!(
+            script.iter().filter(|&&byte| byte == b'\n').count(),
+            SESSION.len()
+        );
+
+        let mut replay = ConsoleFeed::replay(&script[..]).unwrap();
+        assert_eq!(echo(&mut replay), (output, registers));
+    }
+
+    #[test]
+    fn invalid_scripts_are_rejected() {
+        for &script in ["12 6c\n", "0x10 0x6c\n", "12\n", "12 0x100\n"].iter() {
+            let err = ConsoleFeed::replay(script.as_bytes()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", script);
+        }
+    }
+
+    fn feed_all(parser: &mut EscapeParser, bytes: &[u8]) -> Vec<Escaped> {
+        bytes.iter().filter_map(|&byte| parser.feed(byte)).collect()
+    }
+
+    #[test]
+    fn escape_parser_passes_other_bytes_through() {
+        let mut parser = EscapeParser::new(DEFAULT_ESCAPE);
+        // Ctrl+C, escape sequences of the terminal and x without the escape byte.
+        let bytes = b"ls\x03\x1b[Ax\n";
+        let expected: Vec<_> = bytes.iter().map(|&byte| Escaped::Byte(byte)).collect();
+        assert_eq!(feed_all(&mut parser, bytes), expected);
+    }
+
+    #[test]
+    fn escape_parser_recognizes_the_commands() {
+        let mut parser = EscapeParser::new(DEFAULT_ESCAPE);
+        assert_eq!(parser.feed(0x01), None);
+        assert_eq!(parser.feed(b'x'), Some(Escaped::Quit));
+        assert_eq!(
+            feed_all(&mut parser, b"\x01h\x01X\x01?"),
+            [Escaped::Help, Escaped::Quit, Escaped::Help]
+        );
+        // A doubled escape byte goes to the guest once, and the parser starts over after it.
+        assert_eq!(
+            feed_all(&mut parser, b"\x01\x01x"),
+            [Escaped::Byte(0x01), Escaped::Byte(b'x')]
+        );
+        // Unknown commands are dropped along with the escape byte.
+        assert_eq!(feed_all(&mut parser, b"\x01qa"), [Escaped::Byte(b'a')]);
+    }
+
+    #[test]
+    fn escape_char_is_a_control_key() {
+        assert_eq!(parse_escape("a"), Some(0x01));
+        assert_eq!(parse_escape("B"), Some(0x02));
+        assert_eq!(parse_escape("]"), None);
+        assert_eq!(parse_escape("ab"), None);
+
+        let mut parser = EscapeParser::new(0x02);
+        assert_eq!(
+            feed_all(&mut parser, b"\x01x\x02x"),
+            [Escaped::Byte(0x01), Escaped::Byte(b'x'), Escaped::Quit]
+        );
+        assert!(parser.help().contains("C-b x  exit the emulator"));
+        assert_eq!(describe_key(0x1d), "0x1d");
+    }
+
+    #[test]
+    fn stdin_forwarding_ends_with_the_input() {
+        let uart = Uart::with_output(Box::new(io::sink()));
+        let quit = AtomicBool::new(false);
+        // Returns instead of receiving the last byte again and again.
+        forward_input(&b"l"[..], &uart.input(), DEFAULT_ESCAPE, &quit);
+        assert_eq!(read_byte(&uart), Some(b'l'));
+        assert_eq!(read_byte(&uart), None);
+        assert!(!quit.load(Ordering::Acquire));
+
+        forward_input(&b"a\x01xb"[..], &uart.input(), DEFAULT_ESCAPE, &quit);
+        assert_eq!(read_byte(&uart), Some(b'a'));
+        assert_eq!(read_byte(&uart), None);
+        assert!(quit.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn console_output_is_written_a_line_at_a_time() {
+        let host = Captured::default();
+        let mut output = ConsoleOutput::new(Box::new(host.clone()));
+        let mut uart = Uart::with_output(Box::new(output.clone()));
+        for &byte in b"$ ls" {
+            uart.write(0, 1, byte as u64).unwrap();
+        }
+        assert_eq!(host.contents(), b"");
+        uart.write(0, 1, b'\n' as u64).unwrap();
+        assert_eq!(host.contents(), b"$ ls\n");
+
+        // A burst without newlines is written out whenever the buffer fills up, in order.
+        let burst: Vec<u8> = (0..3 * OUTPUT_BUFFER_LEN + 100)
+            .map(|i| b'a' + (i % 26) as u8)
+            .collect();
+        for &byte in &burst {
+            uart.write(0, 1, byte as u64).unwrap();
+        }
+        assert_eq!(host.contents().len(), 5 + 3 * OUTPUT_BUFFER_LEN);
+        output.flush().unwrap();
+        assert_eq!(host.contents()[5..], burst[..]);
+    }
+
+    #[test]
+    fn partial_lines_are_flushed_by_the_timer() {
+        let host = Captured::default();
+        let mut output = ConsoleOutput::spawn(Box::new(host.clone()));
+        output.write_all(b"login: ").unwrap();
+        let start = Instant::now();
+        while host.contents().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(FLUSH_INTERVAL);
+        }
+        assert_eq!(host.contents(), b"login: ");
+    }
+
+    #[test]
+    fn the_console_log_timestamps_every_line() {
+        let host = Captured::default();
+        let log = Captured::default();
+        let retired = Arc::new(AtomicU64::new(0));
+        let mut output = ConsoleOutput::new(Box::new(host.clone()));
+        output.tee(ConsoleLog::new(Box::new(log.clone()), retired.clone()));
+        output.write_all(b"boot").unwrap();
+        output.flush().unwrap();
+        retired.store(1234, Ordering::Relaxed);
+        output.write_all(b"ing\nok\n").unwrap();
+
+        assert_eq!(host.contents(), b"booting\nok\n");
+        let log = String::from_utf8(log.contents()).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        // A line is stamped when it starts, the rest of it is appended as is.
+        assert!(lines[0].starts_with('[') && lines[0].ends_with("s            0] booting"));
+        assert!(lines[1].ends_with("s         1234] ok"), "{}", lines[1]);
+    }
+}
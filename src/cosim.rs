@@ -0,0 +1,486 @@
+//! Lock-step comparison against a reference implementation. Every instruction retired by the cpu
+//! must match the next one retired by the reference in pc, instruction word and the registers it
+//! wrote, the first difference stops the run.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, BufRead},
+    rc::Rc,
+};
+#[cfg(feature = "host")]
+use std::{
+    io::BufReader,
+    path::Path,
+    process::{Child, ChildStderr, Command, Stdio},
+};
+
+use crate::{
+    cpu::{Cpu, CpuStatus, StopReason},
+    hook::{ExecHook, HookAction},
+    register::xs::{Xs, ABI_NAMES},
+    trap::Exception,
+    InsnDecoder, RegT, XLen,
+};
+
+/// An instruction retired by the reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub pc: RegT,
+    pub code: u32,
+    /// Integer registers written by the instruction.
+    pub xs: Vec<(u8, RegT)>,
+    /// CSRs written by the instruction.
+    pub csrs: Vec<(u16, RegT)>,
+}
+
+pub trait ReferenceModel {
+    /// Retires the next instruction, `None` once the reference has no more.
+    fn step(&mut self) -> io::Result<Option<Commit>>;
+}
+
+/// Replays a commit log in the format of `spike --log-commits`, with lines like
+/// `core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000`. The format follows
+/// spike's source. The parser hasn't been run on a log written by a real spike yet.
+pub struct SpikeLog<R: BufRead> {
+    lines: io::Lines<R>,
+    /// The commits are skipped until one is at this pc.
+    start: Option<RegT>,
+}
+
+impl<R: BufRead> SpikeLog<R> {
+    pub fn new(log: R) -> Self {
+        Self {
+            lines: log.lines(),
+            start: None,
+        }
+    }
+
+    /// Skips the commits before the first one at `pc`, e.g. spike's boot ROM jumping to the
+    /// entry point of the program.
+    pub fn starting_at(log: R, pc: RegT) -> Self {
+        Self {
+            lines: log.lines(),
+            start: Some(pc),
+        }
+    }
+}
+
+impl<R: BufRead> ReferenceModel for SpikeLog<R> {
+    fn step(&mut self) -> io::Result<Option<Commit>> {
+        for line in &mut self.lines {
+            // Lines that aren't commits, e.g. exceptions, are skipped.
+            let commit = match parse_commit(&line?) {
+                Some(commit) => commit,
+                None => continue,
+            };
+            match self.start {
+                Some(start) if commit.pc != start => {}
+                _ => {
+                    self.start = None;
+                    return Ok(Some(commit));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Runs spike on the same program and reads the commits it logs to stderr as they come.
+///
+/// Unverified: this hasn't been run against a real spike. The command line and the boot ROM
+/// commits it skips are as spike documents them, a spike that logs otherwise shows up as a
+/// mismatch at the first instruction.
+#[cfg(feature = "host")]
+pub struct Spike {
+    child: Child,
+    log: SpikeLog<BufReader<ChildStderr>>,
+}
+
+#[cfg(feature = "host")]
+impl Spike {
+    /// Starts `spike --log-commits --isa=<isa> <elf>`, `spike` is looked up in PATH. The commits
+    /// of its boot ROM are skipped, the log starts at `entry`, where the cpu starts.
+    pub fn spawn(isa: &str, elf: &Path, entry: RegT) -> io::Result<Self> {
+        let mut child = Command::new("spike")
+            .arg("--log-commits")
+            .arg(format!("--isa={}", isa))
+            .arg(elf)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to run spike: {}", e)))?;
+        let stderr = child.stderr.take().expect("stderr is piped");
+        Ok(Self {
+            child,
+            log: SpikeLog::starting_at(BufReader::new(stderr), entry),
+        })
+    }
+}
+
+#[cfg(feature = "host")]
+impl ReferenceModel for Spike {
+    fn step(&mut self) -> io::Result<Option<Commit>> {
+        self.log.step()
+    }
+}
+
+#[cfg(feature = "host")]
+impl Drop for Spike {
+    /// Spike keeps running a program that doesn't exit through its host interface.
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn parse_commit(line: &str) -> Option<Commit> {
+    let mut words = line.split_whitespace().peekable();
+    if words.next()? != "core" || !words.next()?.ends_with(':') {
+        return None;
+    }
+    // Newer versions log the privilege level before pc.
+    if !words.peek()?.starts_with("0x") {
+        words.next();
+    }
+    let pc = parse_hex(words.next()?)?;
+    let code = words.next()?.strip_prefix('(')?.strip_suffix(')')?;
+    let mut commit = Commit {
+        pc,
+        code: parse_hex(code)? as u32,
+        xs: Vec::new(),
+        csrs: Vec::new(),
+    };
+    while let Some(word) = words.next() {
+        if word == "mem" {
+            // A memory access isn't compared, skip its address and the stored value if any.
+            words.next();
+            if words.peek().is_some_and(|w| w.starts_with("0x")) {
+                words.next();
+            }
+        } else if let Some(reg) = word.strip_prefix('x') {
+            let reg = reg.parse().ok().filter(|reg| *reg < 32)?;
+            commit.xs.push((reg, parse_hex(words.next()?)?));
+        } else if let Some(csr) = word.strip_prefix('c') {
+            // Written as `c768_mstatus`.
+            let num = csr.split('_').next()?.parse().ok()?;
+            commit.csrs.push((num, parse_hex(words.next()?)?));
+        } else {
+            // Floating point or vector state, which isn't compared.
+            words.next();
+        }
+    }
+    Some(commit)
+}
+
+fn parse_hex(s: &str) -> Option<RegT> {
+    RegT::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// The first instruction that differs from the reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The number of instructions that matched before this one.
+    pub index: u64,
+    pub pc: RegT,
+    pub code: u32,
+    pub expected: Commit,
+    pub reason: String,
+    /// The XLEN the instruction is decoded for.
+    pub xlen: XLen,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let insn = InsnDecoder::new(self.xlen)
+            .decode(self.code)
+            .map_or_else(|| "unknown".to_string(), |insn| insn.to_string());
+        writeln!(
+            f,
+            "mismatch at instruction {}: pc {:#x} insn {:#010x} ({})",
+            self.index, self.pc, self.code, insn
+        )?;
+        writeln!(f, "  {}", self.reason)?;
+        write!(
+            f,
+            "  reference: pc {:#x} insn {:#010x}",
+            self.expected.pc, self.expected.code
+        )?;
+        for (reg, value) in self.expected.xs.iter() {
+            write!(f, " {} {:#x}", ABI_NAMES[*reg as usize], value)?;
+        }
+        for (csr, value) in self.expected.csrs.iter() {
+            write!(f, " csr {:#x} {:#x}", csr, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a co-simulation ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every instruction of the reference matched.
+    Finished(u64),
+    Mismatch(Box<Mismatch>),
+}
+
+struct CosimState {
+    reference: Box<dyn ReferenceModel>,
+    /// The commit the instruction being executed is compared against.
+    expected: Option<Commit>,
+    /// The registers after the previous instruction, to find the ones written by this one.
+    last_xs: Xs,
+    matched: u64,
+    outcome: Option<io::Result<Outcome>>,
+    xlen: XLen,
+}
+
+impl CosimState {
+    fn mismatch(&mut self, pc: RegT, code: u32, expected: Commit, reason: String) {
+        self.outcome = Some(Ok(Outcome::Mismatch(Box::new(Mismatch {
+            index: self.matched,
+            pc,
+            code,
+            expected,
+            reason,
+            xlen: self.xlen,
+        }))));
+    }
+}
+
+struct CosimHook {
+    state: Rc<RefCell<CosimState>>,
+    code: u32,
+}
+
+impl ExecHook for CosimHook {
+    fn before(&mut self, pc: RegT, code: u32) -> Option<HookAction> {
+        let mut state = self.state.borrow_mut();
+        if state.outcome.is_some() {
+            return None;
+        }
+        self.code = code;
+        // The commit is only taken once the instruction retires, one raising an exception isn't
+        // in the log.
+        if state.expected.is_none() {
+            match state.reference.step() {
+                Ok(Some(commit)) => state.expected = Some(commit),
+                Ok(None) => state.outcome = Some(Ok(Outcome::Finished(state.matched))),
+                Err(e) => state.outcome = Some(Err(e)),
+            }
+        }
+        if let Some(expected) = state.expected.clone() {
+            if expected.pc != pc || expected.code != code {
+                let reason = "pc or instruction differs".to_string();
+                state.mismatch(pc, code, expected, reason);
+            }
+        }
+        None
+    }
+
+    fn after(&mut self, pc: RegT, cpu: &CpuStatus, result: Result<(), Exception>) {
+        let mut state = self.state.borrow_mut();
+        if state.outcome.is_some() || result.is_err() {
+            return;
+        }
+        let expected = state.expected.take().expect("compared in before");
+        let mut reason = None;
+        for (reg, value) in expected.xs.iter() {
+            let actual = cpu.xs.reg(*reg);
+            if *reg != 0 && actual != *value {
+                reason = Some(format!(
+                    "{} is {:#x}, expected {:#x}",
+                    ABI_NAMES[*reg as usize], actual, value
+                ));
+                break;
+            }
+        }
+        for (csr, value) in expected.csrs.iter() {
+            let actual = cpu.csrs.csr(*csr);
+            if reason.is_none() && actual != *value {
+                reason = Some(format!(
+                    "csr {:#x} is {:#x}, expected {:#x}",
+                    csr, actual, value
+                ));
+            }
+        }
+        if reason.is_none() {
+            let last_xs = &state.last_xs;
+            reason = (1..32u8)
+                .find(|reg| {
+                    cpu.xs.reg(*reg) != last_xs.reg(*reg)
+                        && !expected.xs.iter().any(|(r, _)| r == reg)
+                })
+                .map(|reg| {
+                    let name = ABI_NAMES[reg as usize];
+                    format!(
+                        "{} written with {:#x}, not by the reference",
+                        name,
+                        cpu.xs.reg(reg)
+                    )
+                });
+        }
+        match reason {
+            Some(reason) => state.mismatch(pc, self.code, expected, reason),
+            None => state.matched += 1,
+        }
+        state.last_xs = cpu.xs.clone();
+    }
+}
+
+/// Runs `cpu` in lock-step with `reference` until the reference ends or they differ. A hart taking
+/// `max_idle` steps in a row without retiring an instruction, e.g. in WFI or in a loop of traps
+/// on fetch, differs from the reference, which has a next instruction.
+pub fn run(
+    cpu: &mut Cpu,
+    reference: Box<dyn ReferenceModel>,
+    max_idle: u64,
+) -> io::Result<Outcome> {
+    let state = Rc::new(RefCell::new(CosimState {
+        reference,
+        expected: None,
+        last_xs: cpu.state.xs.clone(),
+        matched: 0,
+        outcome: None,
+        xlen: cpu.xlen,
+    }));
+    cpu.add_hook(Box::new(CosimHook {
+        state: state.clone(),
+        code: 0,
+    }));
+    let (mut idle, mut retired) = (0, 0);
+    loop {
+        match cpu.one_step() {
+            Some(StopReason::Fatal(report)) => return Err(io::Error::other(report.to_string())),
+            Some(StopReason::DoubleFault(report)) => {
+                return Err(io::Error::other(report.to_string()))
+            }
+            _ => {}
+        }
+        let mut state = state.borrow_mut();
+        if let Some(outcome) = state.outcome.take() {
+            return outcome;
+        }
+        if state.matched != retired {
+            (idle, retired) = (0, state.matched);
+            continue;
+        }
+        idle += 1;
+        if idle >= max_idle {
+            let expected = match state.expected.take() {
+                Some(commit) => commit,
+                None => match state.reference.step()? {
+                    Some(commit) => commit,
+                    None => return Ok(Outcome::Finished(state.matched)),
+                },
+            };
+            let pc = cpu.state.pc;
+            let code = match cpu.mmu.peek::<u32>(&cpu.state, pc) {
+                // Only the low half is the instruction if it's compressed.
+                Ok(code) if code & 0b11 != 0b11 => code & 0xffff,
+                Ok(code) => code,
+                Err(_) => 0,
+            };
+            let reason = format!("no instruction retired in {} steps", idle);
+            state.mismatch(pc, code, expected, reason);
+            return state.outcome.take().expect("set by mismatch");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::*, XLen};
+
+    /// A commit log of `LOOP` in the format `SpikeLog` parses, 2204 instructions. It was written
+    /// from a model of the program outside the emulator, not by spike, so it tests the comparison
+    /// but not that the parser reads real spike output.
+    const LOG: &str = include_str!("../tests/data/loop_commits.log");
+
+    const LOOP: [u32; 16] = [
+        auipc(6, 0),
+        addi(5, 0, 200),
+        addi(10, 0, 0),
+        addi(11, 0, 1),
+        // xorshift64 in a1, summed up in a0, which goes through memory to a3.
+        i_type(13, 11, 1, 12, 0x13),
+        r_type(0, 12, 11, 4, 11, 0x33),
+        i_type(7, 11, 5, 12, 0x13),
+        r_type(0, 12, 11, 4, 11, 0x33),
+        i_type(17, 11, 1, 12, 0x13),
+        r_type(0, 12, 11, 4, 11, 0x33),
+        r_type(0, 11, 10, 0, 10, 0x33),
+        sd(10, 6, 0x200),
+        ld(13, 6, 0x200),
+        addi(5, 5, -1),
+        b_type(-40, 0, 5, 1),
+        EBREAK,
+    ];
+
+    fn cosim(log: String) -> Outcome {
+        let mut cpu = Cpu::with_program(XLen::X64, &LOOP);
+        let log = SpikeLog::new(io::Cursor::new(log));
+        run(&mut cpu, Box::new(log), 100).unwrap()
+    }
+
+    #[test]
+    fn the_loop_matches_the_reference() {
+        assert_eq!(cosim(LOG.to_string()), Outcome::Finished(2204));
+    }
+
+    #[test]
+    fn the_first_divergent_instruction_is_reported() {
+        // The add of the 91st iteration writes a0 with something else.
+        let mut lines: Vec<String> = LOG.lines().map(str::to_string).collect();
+        let (commit, _) = lines[1000].rsplit_once(' ').unwrap();
+        lines[1000] = format!("{} 0x0000000000000000", commit);
+        let mismatch = match cosim(lines.join("\n")) {
+            Outcome::Mismatch(mismatch) => mismatch,
+            outcome => panic!("{:?}", outcome),
+        };
+        assert_eq!((mismatch.index, mismatch.pc), (1000, DRAM_BASE + 0x28));
+        assert_eq!(mismatch.code, LOOP[10]);
+        assert!(mismatch.reason.starts_with("a0 is "), "{}", mismatch.reason);
+        assert!(
+            mismatch.reason.ends_with("expected 0x0"),
+            "{}",
+            mismatch.reason
+        );
+        let report = mismatch.to_string();
+        assert!(
+            report.starts_with("mismatch at instruction 1000: pc 0x80000028 insn 0x00b50533 (add"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn the_commits_before_the_start_are_skipped() {
+        // Commits like those of the reset vector in spike's boot ROM, which jumps to the program.
+        let boot = "core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000\n\
+                    core   0: 3 0x0000000000001010 (0x00028067)\n";
+        let log = format!("{}{}", boot, LOG);
+        let mut cpu = Cpu::with_program(XLen::X64, &LOOP);
+        let log = SpikeLog::starting_at(io::Cursor::new(log), DRAM_BASE);
+        let outcome = run(&mut cpu, Box::new(log), 100).unwrap();
+        assert_eq!(outcome, Outcome::Finished(2204));
+    }
+
+    #[test]
+    fn a_hart_waiting_for_an_interrupt_differs() {
+        // The reference takes WFI as a NOP and goes on, no interrupt ever wakes up the hart.
+        let log = "core   0: 3 0x0000000080000000 (0x10500073)\n\
+                   core   0: 3 0x0000000080000004 (0x00100513) x10 0x0000000000000001\n";
+        let mut cpu = Cpu::with_program(XLen::X64, &[WFI, addi(10, 0, 1)]);
+        let log = SpikeLog::new(io::Cursor::new(log));
+        let mismatch = match run(&mut cpu, Box::new(log), 1000).unwrap() {
+            Outcome::Mismatch(mismatch) => mismatch,
+            outcome => panic!("{:?}", outcome),
+        };
+        assert_eq!((mismatch.index, mismatch.pc), (1, DRAM_BASE + 4));
+        assert_eq!(mismatch.code, addi(10, 0, 1));
+        assert_eq!(mismatch.reason, "no instruction retired in 1000 steps");
+    }
+}
@@ -0,0 +1,144 @@
+//! Collects how often every guest pc was executed, through an `ExecHook` so that a cpu without
+//! coverage enabled doesn't pay for it.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    hook::{ExecHook, HookAction},
+    symbols::Symbols,
+    RegT,
+};
+
+/// Execution counts per pc.
+#[derive(Default)]
+pub struct Coverage {
+    hits: HashMap<RegT, u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hits(&self) -> &HashMap<RegT, u64> {
+        &self.hits
+    }
+
+    /// Writes an `addr count` line per executed pc, sorted by address.
+    pub fn write_text(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut hits: Vec<_> = self.hits.iter().collect();
+        hits.sort();
+        for (pc, count) in hits {
+            writeln!(w, "{:#x} {}", pc, count)?;
+        }
+        Ok(())
+    }
+
+    /// Writes function coverage in the lcov tracefile format. There is no line information, so
+    /// every symbol is recorded as a function hit as often as the instruction at its address.
+    pub fn write_lcov(&self, w: &mut dyn Write, symbols: &Symbols) -> io::Result<()> {
+        writeln!(w, "TN:")?;
+        writeln!(w, "SF:guest")?;
+        let mut found = 0;
+        let mut hit = 0;
+        for (addr, name) in symbols.iter() {
+            let count = self.hits.get(&addr).copied().unwrap_or(0);
+            writeln!(w, "FN:0,{}", name)?;
+            writeln!(w, "FNDA:{},{}", count, name)?;
+            found += 1;
+            if count > 0 {
+                hit += 1;
+            }
+        }
+        writeln!(w, "FNF:{}", found)?;
+        writeln!(w, "FNH:{}", hit)?;
+        writeln!(w, "end_of_record")
+    }
+}
+
+/// Counts every executed pc into the shared `Coverage`.
+pub struct CoverageHook {
+    coverage: Rc<RefCell<Coverage>>,
+}
+
+impl CoverageHook {
+    pub fn new(coverage: Rc<RefCell<Coverage>>) -> Self {
+        Self { coverage }
+    }
+}
+
+impl ExecHook for CoverageHook {
+    fn before(&mut self, pc: RegT, _code: u32) -> Option<HookAction> {
+        *self.coverage.borrow_mut().hits.entry(pc).or_insert(0) += 1;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::Cpu, testing::*, XLen};
+
+    const STT_FUNC: u8 = 2;
+
+    /// Calls a function at +16 three times in a loop.
+    const PROGRAM: [u32; 6] = [
+        addi(10, 0, 3),
+        jal(1, 12),
+        b_type(-4, 0, 10, 1),
+        EBREAK,
+        addi(10, 10, -1),
+        i_type(0, 1, 0, 0, 0x67),
+    ];
+
+    fn covered() -> Cpu {
+        let mut cpu = Cpu::with_program(XLen::X64, &PROGRAM);
+        assert!(cpu.coverage().is_none());
+        cpu.enable_coverage();
+        cpu.run_until_ebreak(100);
+        cpu
+    }
+
+    #[test]
+    fn every_executed_pc_is_counted() {
+        let cpu = covered();
+        let coverage = cpu.coverage().unwrap();
+        let mut text = Vec::new();
+        coverage.write_text(&mut text).unwrap();
+        let expected = [(0, 1), (4, 3), (8, 3), (16, 3), (20, 3)]
+            .iter()
+            .map(|(offset, count)| format!("{:#x} {}\n", DRAM_BASE + offset, count))
+            .collect::<String>();
+        assert_eq!(String::from_utf8(text).unwrap(), expected);
+    }
+
+    #[test]
+    fn lcov_counts_the_calls_of_every_symbol() {
+        let cpu = covered();
+        let mut symbols = Symbols::new();
+        let elf = symbol_elf(&[
+            ("_start", DRAM_BASE, 16, STT_FUNC, 1),
+            ("dec", DRAM_BASE + 16, 8, STT_FUNC, 1),
+            ("unused", DRAM_BASE + 24, 4, STT_FUNC, 1),
+        ]);
+        symbols.add_elf("guest", &elf).unwrap();
+        let mut lcov = Vec::new();
+        cpu.coverage()
+            .unwrap()
+            .write_lcov(&mut lcov, &symbols)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "TN:\nSF:guest\n\
+             FN:0,_start\nFNDA:1,_start\n\
+             FN:0,dec\nFNDA:3,dec\n\
+             FN:0,unused\nFNDA:0,unused\n\
+             FNF:3\nFNH:2\nend_of_record\n"
+        );
+    }
+}
@@ -1,112 +1,1224 @@
-use std::rc::Rc;
+use std::{
+    cell::{Ref, RefCell},
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    thread,
+    time::Duration,
+};
 
+#[cfg(feature = "framebuffer")]
+use crate::device::{framebuffer::Framebuffer, FRAMEBUFFER_BASE};
 use crate::{
+    block::{self, Block, BlockCache, MAX_BLOCK_LEN},
+    coverage::{Coverage, CoverageHook},
     device::{
-        uart::UART_IRQ,
-        virtio::{Virtio, VIRTIO_IRQ},
-        DRAM_BASE, DRAM_SIZE,
+        bus::Bus,
+        rom::Rom,
+        syscon::{PowerLine, PowerRequest},
+        Data, Device, DRAM_BASE, DRAM_SIZE, ROM_BASE, ROM_SIZE,
     },
-    mmu::Mmu,
-    register::mip::Mip,
+    hook::{AccessHook, CpuEvent, EcallHandler, EventHook, ExecHook, HookAction, Transition},
+    isa::{insn_bits, is_rv32_only, is_rv64_only, INSN_ALIGN_MASK},
+    mmu::{AccessType, Mmu, PAGE_SIZE},
+    profile::{Profile, ProfileHook},
+    register::mip::{self, Mip},
+    register::satp::Mode,
+    report::{DoubleFault, FaultReport, Hang, History, StateReport, SuspectedHang},
+    sbi, snapshot,
+    stats::{Stats, StatsReport},
+    symbols::Symbols,
     trap::{Exception, Interrupt, Trap},
+    watchpoint::{WatchHit, WatchKind},
     Insn, InsnDecoder, PrivilegeMode, RegT,
 };
+use log::{debug, trace};
 use lru::LruCache;
 
 use crate::{
-    register::{csrs::Csrs, xs::Xs},
+    register::{
+        csrs::{Csrs, MEDELEG_MASK, MIDELEG_MASK},
+        fs::Fs,
+        xs::Xs,
+    },
     XLen,
 };
+
+/// The schema version of `Cpu::state_json`.
+#[cfg(feature = "json")]
+pub const STATE_JSON_VERSION: u32 = 1;
+
+/// The CSRs in `Cpu::state_json`.
+#[cfg(feature = "json")]
+const STATE_JSON_CSRS: [u16; 11] = [
+    0x300, 0x342, 0x341, 0x343, 0x180, 0x304, 0x344, 0x100, 0x141, 0x142, 0x143,
+];
+
+/// `addi x0, x0, 0`, the NOP assemblers emit. The other HINT encodings aren't counted as NOPs.
+const CANONICAL_NOP: u32 = 0x13;
+
+/// How `Cpu::set_spin_detection` recognizes a guest spinning in a wait loop. The host thread
+/// idles for the step like in WFI, the timers and devices keep running.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpinDetection {
+    /// A run of this many canonical NOPs counts as spinning too, not just PAUSE.
+    pub nop_run: Option<u64>,
+}
+
+/// How `Cpu::set_hang_detection` recognizes a guest stuck for good, see `Hang`. The machine stops
+/// with `StopReason::SuspectedHang`. A `None` threshold turns its heuristic off.
+#[derive(Clone, Copy, Debug)]
+pub struct HangDetection {
+    /// Steps in a row at the same pc without a change of the x registers. Steps in WFI don't
+    /// count.
+    pub same_pc: Option<u64>,
+    /// Whether pc going below the DRAM, outside the boot ROM, with satp Bare counts. Guests
+    /// running from memory mapped below the DRAM turn it off.
+    pub low_pc: bool,
+    /// Traps taken in a row without an MRET or SRET.
+    pub trap_storm: Option<u64>,
+}
+
+impl Default for HangDetection {
+    fn default() -> Self {
+        Self {
+            same_pc: Some(100_000),
+            low_pc: true,
+            trap_storm: Some(100),
+        }
+    }
+}
+
+/// What an access to a CSR the emulator doesn't implement does, see `Csrs::is_implemented`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CsrPolicy {
+    /// Every CSR number holds a value, so bare-metal programs poking at unknown CSRs keep
+    /// working.
+    #[default]
+    Permissive,
+    /// Raises an illegal instruction exception like hardware does.
+    Strict,
+}
+
+/// What the instruction executed by `Cpu::step_one` did.
+pub enum StepOutcome {
+    /// The instruction retired.
+    Retired,
+    /// The instruction raised the exception, which was taken: pc is at the trap handler. An
+    /// EBREAK is a `Exception::Breakpoint`.
+    Trapped(Exception),
+    /// The hart is in WFI, no instruction was executed.
+    Waiting,
+    /// The machine should stop, e.g. on a watchpoint hit or a fatal exception.
+    Stopped(StopReason),
+}
+
+/// Why `Cpu::one_step` asks to stop the machine.
+pub enum StopReason {
+    Watchpoint(WatchHit),
+    /// The guest asked to exit with this code through semihosting.
+    Exit(i32),
+    /// The guest raised an exception it can't handle, see `Exception::is_fatal`, or one it never
+    /// installed a trap handler for. The exception wasn't taken, pc still points at the faulting
+    /// instruction.
+    Fatal(Box<FaultReport>),
+    /// Fetching the first instruction of an M-mode trap handler faulted. pc is the trap vector.
+    DoubleFault(Box<DoubleFault>),
+    /// The guest asked for a reset through the syscon. `Machine::run_block` resets the machine
+    /// instead of returning it.
+    Reset,
+    /// The machine ran out of the steps given by `Machine::set_fuel`. The state is of the hart
+    /// that took the last step.
+    FuelExhausted(Box<StateReport>),
+    /// The deadline set by `Machine::set_timeout` passed.
+    Timeout(Box<StateReport>),
+    /// A heuristic of `Cpu::set_hang_detection` found the guest stuck.
+    SuspectedHang(Box<SuspectedHang>),
+}
+
 pub struct Cpu {
     pub state: CpuStatus,
     pub mmu: Mmu,
     pub xlen: XLen,
+    /// Set by WFI. While set the hart stops fetching instructions and only keeps the timer and
+    /// external interrupt sources running until an enabled interrupt becomes pending.
+    pub waiting_for_interrupt: bool,
+    /// Symbols of the loaded images, used to annotate addresses in reports.
+    pub symbols: Symbols,
+    /// How long to sleep the host thread on every idle step. `None` means busy waiting.
+    idle_sleep: Option<Duration>,
+    /// `None` if a spinning guest isn't detected, e.g. for a deterministic run.
+    spin_detection: Option<SpinDetection>,
+    /// Canonical NOPs executed in a row, counted if `SpinDetection::nop_run` is set.
+    nop_run: u64,
+    /// Set by PAUSE or a long enough run of NOPs, the host thread idles at the end of the step.
+    spinning: bool,
+    /// Called on every step the host thread idles, in WFI or while the guest spins.
+    idle_callback: Option<Box<dyn FnMut()>>,
+    /// `None` unless a stuck guest stops the machine.
+    hang_detection: Option<HangDetection>,
+    /// The pc at the end of the previous step, the steps in a row that ended there and the x
+    /// registers they started comparing against, for `HangDetection::same_pc`.
+    same_pc: (RegT, u64, Xs),
+    /// Traps taken since the last xRET, for `HangDetection::trap_storm`.
+    traps_in_a_row: u64,
+    /// The number of steps the timers and the mcycle and minstret counters are advanced by at
+    /// once. 1 advances them on every step, larger values save time at the cost of delivering
+    /// timer interrupts up to that many steps late.
+    tick_batch: u64,
+    /// Steps taken since the timers and mcycle were last advanced.
+    pending_ticks: u64,
+    /// Instructions retired since minstret was last advanced.
+    pending_retired: u64,
+    /// Steps taken since the hart was created or reset, idle ones and traps included. Unlike the
+    /// counters the guest can't write it, and it's saved in snapshots, so it tells where in its
+    /// run a restored hart is.
+    steps: u64,
     insn_decoder: InsnDecoderWithLru,
+    insn_cache: InsnCache,
+    /// `None` if `run_block` always takes single steps.
+    block_cache: Option<BlockCache>,
+    /// Recently executed instructions, reported on a fatal exception.
+    history: History,
+    /// The trap last delivered and the pc it was taken at, until the first instruction of its
+    /// handler is fetched. A fault in between comes from the trap vector itself.
+    delivering: Option<(Trap, RegT)>,
+    hooks: Vec<Box<dyn ExecHook>>,
+    event_hooks: Vec<Box<dyn EventHook>>,
+    /// Shared with the hook collecting it, `None` unless enabled.
+    coverage: Option<Rc<RefCell<Coverage>>>,
+    /// Shared with the hook sampling it, `None` unless enabled.
+    profile: Option<Rc<RefCell<Profile>>>,
+    /// `None` unless enabled.
+    stats: Option<Box<Stats>>,
+    /// Whether EBREAK performs semihosting calls, see `semihosting`.
+    pub semihosting: bool,
+    /// Whether the CSR instructions trap on the CSRs the emulator doesn't implement.
+    pub csr_policy: CsrPolicy,
+    /// Set by a semihosting exit, reported by the next `one_step`.
+    exit_code: Option<i32>,
+    /// Serves ECALL instead of the trap handler of the guest if set.
+    ecall_handler: Option<Box<dyn EcallHandler>>,
+    /// Set by an atomic instruction with the rl bit or a fence ordering earlier writes, see
+    /// `take_release`.
+    released: bool,
+    /// Shared with the syscon of the bus, taken after every step.
+    power: PowerLine,
 }
 
 impl Cpu {
+    /// Creates a cpu with the default machine around it, see `CpuBuilder` for the rest of the
+    /// configuration.
     pub fn new(xlen: XLen, binary: Vec<u8>, start_address: u64) -> Self {
-        let mut cpu_status = CpuStatus::new(start_address);
-        cpu_status.reset();
+        CpuBuilder::new(binary)
+            .xlen(xlen)
+            .entry(start_address)
+            .build()
+    }
+
+    /// Creates a hart sharing `bus` with the harts created before it. Hart ids are handed out by
+    /// the bus in creation order.
+    fn with_bus(
+        xlen: XLen,
+        bus: Rc<RefCell<Bus>>,
+        start_address: u64,
+        decode_cache_capacity: usize,
+    ) -> Self {
+        let mmu = Mmu::new(xlen, bus);
+        let mut cpu_status = CpuStatus::new(xlen, start_address);
+        cpu_status.csrs.set_mhartid(mmu.hart() as RegT);
+        cpu_status
+            .csrs
+            .set_timebase(mmu.bus.borrow().clint.timebase());
+        cpu_status.reset(mmu.bus.borrow().dram_end());
+        let power = mmu.bus.borrow().power.clone();
         Self {
             state: cpu_status,
-            mmu: Mmu::new(xlen, binary),
-            xlen: xlen,
-            insn_decoder: InsnDecoderWithLru::new(InsnDecoder::new()),
+            mmu,
+            xlen,
+            waiting_for_interrupt: false,
+            symbols: Symbols::new(),
+            idle_sleep: None,
+            spin_detection: None,
+            nop_run: 0,
+            spinning: false,
+            idle_callback: None,
+            hang_detection: None,
+            same_pc: (0, 0, Xs::new()),
+            traps_in_a_row: 0,
+            tick_batch: 64,
+            pending_ticks: 0,
+            pending_retired: 0,
+            steps: 0,
+            insn_decoder: InsnDecoderWithLru::new(InsnDecoder::new(xlen), decode_cache_capacity),
+            insn_cache: InsnCache::new(),
+            block_cache: Some(BlockCache::new()),
+            history: History::new(),
+            delivering: None,
+            hooks: Vec::new(),
+            event_hooks: Vec::new(),
+            coverage: None,
+            profile: None,
+            stats: None,
+            semihosting: false,
+            csr_policy: CsrPolicy::Permissive,
+            exit_code: None,
+            ecall_handler: None,
+            released: false,
+            power,
+        }
+    }
+
+    /// Sleep the host thread for `duration` on every step the hart spends idle in WFI, so that an
+    /// idle guest doesn't pin a host core.
+    pub fn set_idle_sleep(&mut self, duration: Option<Duration>) {
+        self.idle_sleep = duration;
+    }
+
+    /// Idles the host thread for a step when the guest is visibly spinning, see `SpinDetection`.
+    /// `None` turns the detection off, which a deterministic run does.
+    pub fn set_spin_detection(&mut self, detection: Option<SpinDetection>) {
+        self.spin_detection = detection;
+        self.nop_run = 0;
+        self.spinning = false;
+    }
+
+    /// Stops the machine with `StopReason::SuspectedHang` when the guest looks stuck, see
+    /// `HangDetection`. `None` turns the detection off, the default.
+    pub fn set_hang_detection(&mut self, detection: Option<HangDetection>) {
+        self.hang_detection = detection;
+        self.same_pc.1 = 0;
+        self.traps_in_a_row = 0;
+    }
+
+    /// Calls `callback` on every step the host thread idles, in WFI or while the guest spins.
+    pub fn set_idle_callback(&mut self, callback: Box<dyn FnMut()>) {
+        self.idle_callback = Some(callback);
+    }
+
+    /// Advance the timers and counters every `batch` steps. 1 keeps mtime, time, mcycle and
+    /// minstret exact on every step.
+    pub fn set_tick_batch(&mut self, batch: u64) {
+        self.tick_batch = batch.max(1);
+    }
+
+    /// Writes the whole machine state to `w`.
+    pub fn save_snapshot(&self, mut w: impl Write) -> io::Result<()> {
+        let w: &mut dyn Write = &mut w;
+        snapshot::write_header(w, None)?;
+        self.save_hart(w)?;
+        self.mmu.bus.borrow().save(w)
+    }
+
+    /// Writes a checkpoint to `w`. Without a `parent` it's a full snapshot, and the DRAM pages
+    /// written from then on are tracked. With one it's an incremental snapshot naming `parent`,
+    /// which must be the path of the previous checkpoint, holding only the pages written since.
+    pub fn save_checkpoint(&mut self, mut w: impl Write, parent: Option<&str>) -> io::Result<()> {
+        let w: &mut dyn Write = &mut w;
+        let mut bus = self.mmu.bus.borrow_mut();
+        match parent {
+            None => {
+                bus.track_dirty_pages();
+                bus.take_dirty_pages();
+                snapshot::write_header(w, None)?;
+                self.save_hart(w)?;
+                bus.save(w)
+            }
+            Some("") => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the parent of a checkpoint needs a name",
+            )),
+            Some(_) if !bus.tracks_dirty_pages() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "an incremental checkpoint needs a full one before it",
+            )),
+            Some(_) => {
+                let pages = bus.take_dirty_pages();
+                snapshot::write_header(w, parent)?;
+                self.save_hart(w)?;
+                bus.save_pages(w, &pages)
+            }
+        }
+    }
+
+    fn save_hart(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.state.save(w)?;
+        snapshot::write_u64(w, self.waiting_for_interrupt as u64)?;
+        snapshot::write_u64(w, self.pending_ticks)?;
+        snapshot::write_u64(w, self.pending_retired)?;
+        snapshot::write_u64(w, self.steps)
+    }
+
+    /// Restores the machine state written by `save_snapshot` or `save_checkpoint`. The cpu must
+    /// have been created with the same disk image, host side resources such as the console stay
+    /// attached. An incremental snapshot is restored over the machine restored from its parent.
+    pub fn load_snapshot(&mut self, mut r: impl Read) -> io::Result<()> {
+        let r: &mut dyn Read = &mut r;
+        let parent = snapshot::check_header(r)?;
+        self.state.restore(r)?;
+        self.waiting_for_interrupt = snapshot::read_u64(r)? != 0;
+        self.pending_ticks = snapshot::read_u64(r)?;
+        self.pending_retired = snapshot::read_u64(r)?;
+        self.steps = snapshot::read_u64(r)?;
+        let mut bus = self.mmu.bus.borrow_mut();
+        match parent {
+            Some(_) => bus.restore_pages(r)?,
+            None => bus.restore(r)?,
+        }
+        drop(bus);
+        self.delivering = None;
+        self.flush_insn_cache();
+        self.update_interrupt_lines();
+        Ok(())
+    }
+
+    /// Restores the snapshot at `path` like `load_snapshot`, after the chain of parents an
+    /// incremental one is based on.
+    pub fn load_snapshot_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut chain = vec![path.as_ref().to_path_buf()];
+        while let Some(parent) = snapshot::check_header(&mut File::open(chain.last().unwrap())?)? {
+            let parent = PathBuf::from(parent);
+            if chain.contains(&parent) {
+                return Err(snapshot::invalid_data("the snapshot chain has a cycle"));
+            }
+            chain.push(parent);
+        }
+        chain
+            .iter()
+            .rev()
+            .try_for_each(|path| self.load_snapshot(BufReader::new(File::open(path)?)))
+    }
+
+    /// The number of steps the hart took, see `one_step` and `run_block`.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Executes a single instruction. Returns the reason if the machine should stop afterwards.
+    pub fn one_step(&mut self) -> Option<StopReason> {
+        self.steps += 1;
+        if self.waiting_for_interrupt {
+            self.idle();
+        } else {
+            match self.exec() {
+                Ok(()) => self.pending_retired += 1,
+                Err(trap) => {
+                    if let Some(stop) = self.take_trap(trap) {
+                        return Some(stop);
+                    }
+                }
+            }
+        }
+        self.end_step()
+    }
+
+    /// Executes exactly the instruction at pc, for a debugger. Unlike `one_step`, pending
+    /// interrupts aren't taken before it, they are left for the next step. In WFI the hart only
+    /// idles for a step.
+    pub fn step_one(&mut self) -> StepOutcome {
+        self.steps += 1;
+        let outcome = if self.waiting_for_interrupt {
+            self.idle();
+            StepOutcome::Waiting
+        } else {
+            let result = self.fetch().and_then(|(code, insn)| {
+                self.delivering = None;
+                self.exec_fetched(code, &insn)
+            });
+            match result {
+                Ok(()) => {
+                    self.pending_retired += 1;
+                    StepOutcome::Retired
+                }
+                Err(e) => match self.take_trap(e.into()) {
+                    Some(stop) => return StepOutcome::Stopped(stop),
+                    None => StepOutcome::Trapped(e),
+                },
+            }
+        };
+        match self.end_step() {
+            Some(stop) => StepOutcome::Stopped(stop),
+            None => outcome,
+        }
+    }
+
+    /// Executes up to `budget` instructions of the basic block at pc, one step at a time where a
+    /// block can't be used, e.g. while hooks are registered. Pending interrupts are only taken
+    /// before the block. Returns the steps taken and the reason if the machine should stop.
+    pub fn run_block(&mut self, budget: u64) -> (u64, Option<StopReason>) {
+        if budget <= 1
+            || self.waiting_for_interrupt
+            || !self.hooks.is_empty()
+            || self.mmu.has_access_hooks()
+            || self.state.csrs.triggers_armed()
+        {
+            return (1, self.one_step());
+        }
+        let block = match self.fetch_block() {
+            Some(block) => block,
+            // Faults and everything else out of the ordinary are left to a single step.
+            None => return (1, self.one_step()),
+        };
+        self.delivering = None;
+        if let Some(interrupt) = self.take_interrupt() {
+            self.steps += 1;
+            self.handle_trap(interrupt.into());
+            return (1, self.end_step());
+        }
+        let hart = self.mmu.hart();
+        let mut steps = 0;
+        for (code, insn) in block.iter().take(budget as usize) {
+            self.history.push(self.state.pc, *code);
+            let result = self.exec_insn(*code, insn);
+            steps += 1;
+            self.steps += 1;
+            match result {
+                Ok(()) => self.pending_retired += 1,
+                Err(e) => {
+                    if let Some(stop) = self.take_trap(Trap::Exception(e)) {
+                        return (steps, Some(stop));
+                    }
+                }
+            }
+            let stop = self.end_step();
+            // A store into a code page may have changed the rest of the block.
+            if result.is_err() || stop.is_some() || self.mmu.bus.borrow().code_written(hart) {
+                return (steps, stop);
+            }
+        }
+        (steps, None)
+    }
+
+    /// Records that the instruction being executed has release semantics.
+    pub(crate) fn release(&mut self) {
+        self.released = true;
+    }
+
+    /// Returns true if an atomic instruction with the rl bit, or a fence ordering earlier writes,
+    /// was executed since the previous call. Harts run one at a time, so every access is already
+    /// sequentially consistent and an acquire needs nothing, but the machine ends the slice of a
+    /// releasing hart so the other harts observe its stores promptly.
+    pub fn take_release(&mut self) -> bool {
+        std::mem::take(&mut self.released)
+    }
+
+    /// Makes `run_block` always take single steps if `enabled` is false.
+    pub fn set_block_cache(&mut self, enabled: bool) {
+        self.block_cache = enabled.then(BlockCache::new);
+    }
+
+    /// Takes `trap`, unless it is a fatal exception. Returns why the machine stops instead.
+    fn take_trap(&mut self, trap: Trap) -> Option<StopReason> {
+        if let (Trap::Exception(e), Some((first, first_epc))) = (trap, self.delivering) {
+            // Nothing ran since the delivery, the hart is still in the mode the trap went to.
+            if self.state.privilege == PrivilegeMode::Machine {
+                return Some(StopReason::DoubleFault(Box::new(DoubleFault {
+                    first,
+                    first_epc,
+                    report: self.fault_report(e),
+                })));
+            }
+            // The handler can't take a fault of its own vector, which would overwrite the state
+            // of the trap it was handed. M-mode takes it whatever the delegation.
+            self.deliver_trap(trap, PrivilegeMode::Machine);
+            return None;
+        }
+        if let Trap::Exception(e) = trap {
+            // Illegal instructions go to the guest, which may emulate them, and so do access
+            // faults, e.g. of PMP, unless it never installed a trap handler.
+            let unhandled = matches!(
+                e,
+                Exception::IllegalInstruction(_)
+                    | Exception::InstructionFault(_)
+                    | Exception::LoadFault(_)
+                    | Exception::StoreFault(_)
+            ) && self.trap_vector(trap) == 0;
+            if e.is_fatal() || unhandled {
+                return Some(StopReason::Fatal(Box::new(self.fault_report(e))));
+            }
         }
+        self.handle_trap(trap);
+        None
     }
 
-    pub fn setup_disk(&mut self, disk_img: Vec<u8>) {
-        self.mmu.bus.virtio.initialize(disk_img);
+    /// Advances the timers and reports why the machine should stop after a step, if it should.
+    fn end_step(&mut self) -> Option<StopReason> {
+        self.mmu.bus.borrow_mut().poll_devices();
+        self.pending_ticks += 1;
+        if self.pending_ticks >= self.tick_batch {
+            self.increment();
+        }
+        if std::mem::take(&mut self.spinning) && !self.idle_host() {
+            thread::yield_now();
+        }
+        if let Some(code) = self.exit_code.take() {
+            return Some(StopReason::Exit(code));
+        }
+        if let Some(request) = self.power.take() {
+            return Some(match request {
+                PowerRequest::Exit(code) => StopReason::Exit(code),
+                PowerRequest::Reset => StopReason::Reset,
+            });
+        }
+        if let Some(hang) = self
+            .hang_detection
+            .and_then(|detection| self.detect_hang(detection))
+        {
+            let state = self.state_report();
+            return Some(StopReason::SuspectedHang(Box::new(SuspectedHang {
+                hang,
+                state,
+            })));
+        }
+        if self.mmu.watchpoints.is_empty() {
+            return None;
+        }
+        self.mmu.take_watch_hit().map(StopReason::Watchpoint)
     }
 
-    pub fn one_step(&mut self) {
-        if let Err(trap) = self.exec() {
-            if let Trap::Exception(e) = trap {
-                if e.is_fatal() {
-                    panic!("{:?}", e);
+    /// Returns the heuristic of `detection` the step that just ended trips, if any.
+    fn detect_hang(&mut self, detection: HangDetection) -> Option<Hang> {
+        let pc = self.state.pc;
+        if let Some(threshold) = detection.same_pc {
+            let (last_pc, steps, xs) = &mut self.same_pc;
+            if pc != *last_pc || self.waiting_for_interrupt {
+                *last_pc = pc;
+                *steps = 0;
+            } else if *steps == 0 || *xs != self.state.xs {
+                // The first repeat, or one that changed a register, starts a new run.
+                xs.clone_from(&self.state.xs);
+                *steps = 1;
+            } else {
+                *steps += 1;
+                if *steps >= threshold {
+                    return Some(Hang::SamePc(*steps));
                 }
             }
-            self.handle_trap(trap);
         }
-        self.increment();
+        if detection.low_pc
+            && self.state.csrs.satp().mode(&self.xlen) == Mode::Bare
+            && pc < self.mmu.bus.borrow().dram_base()
+            && !(ROM_BASE..ROM_BASE + ROM_SIZE).contains(&pc)
+        {
+            return Some(Hang::LowPc);
+        }
+        match detection.trap_storm {
+            Some(threshold) if self.traps_in_a_row >= threshold => {
+                Some(Hang::TrapStorm(self.traps_in_a_row))
+            }
+            _ => None,
+        }
+    }
+
+    /// Records that an MRET or SRET returned from a trap handler.
+    pub(crate) fn trap_returned(&mut self) {
+        self.traps_in_a_row = 0;
+    }
+
+    /// Serves every ECALL with `handler` instead of raising an environment call exception.
+    pub fn set_ecall_handler(&mut self, handler: Box<dyn EcallHandler>) {
+        self.ecall_handler = Some(handler);
+    }
+
+    /// Passes the ECALL at pc to the handler set with `set_ecall_handler`. Returns `None` if
+    /// there is none.
+    pub fn handle_ecall(&mut self) -> Option<Result<(), Exception>> {
+        // Taken out for the duration so that the handler can change the state.
+        let mut handler = self.ecall_handler.take()?;
+        let result = handler.ecall(self);
+        self.ecall_handler = Some(handler);
+        Some(result)
+    }
+
+    /// Asks the machine to stop and exit with `code` after the current step.
+    pub fn exit(&mut self, code: i32) {
+        self.exit_code = Some(code);
+    }
+
+    /// Asks the machine to reset before the next step, like the guest can through the syscon.
+    pub fn request_reset(&self) {
+        self.power.set(Some(PowerRequest::Reset));
+    }
+
+    /// Puts the hart back into `state`, the state it was created in, for a machine reset after
+    /// the bus was reset. Hooks, watchpoints and symbols stay attached.
+    pub fn reset(&mut self, state: CpuStatus) {
+        self.state = state;
+        self.waiting_for_interrupt = false;
+        self.nop_run = 0;
+        self.spinning = false;
+        self.same_pc.1 = 0;
+        self.traps_in_a_row = 0;
+        self.pending_ticks = 0;
+        self.pending_retired = 0;
+        self.steps = 0;
+        self.delivering = None;
+        self.released = false;
+        self.exit_code = None;
+        self.flush_insn_cache();
+        self.update_interrupt_lines();
+    }
+
+    /// Stops the machine after an access to `len` bytes at virtual address `addr`.
+    pub fn add_watchpoint(&mut self, addr: RegT, len: RegT, kind: WatchKind) {
+        self.mmu.watchpoints.add(addr, len, kind);
+    }
+
+    /// Returns true if any interrupt is both pending and enabled in `mie`. Global interrupt enable
+    /// bits are deliberately ignored, WFI must resume even if the interrupt won't be taken.
+    pub fn interrupt_pending(&self) -> bool {
+        self.state.csrs.mip().bits() & self.state.csrs.mie().bits() != 0
+    }
+
+    fn idle(&mut self) {
+        self.update_interrupt_lines();
+        self.check_external_interrupts();
+        if self.interrupt_pending() {
+            self.waiting_for_interrupt = false;
+        } else {
+            self.idle_host();
+        }
+    }
+
+    /// Records that the guest spins, e.g. in PAUSE. Nothing happens unless the detection is on.
+    pub(crate) fn spin(&mut self) {
+        self.spinning = self.spin_detection.is_some();
+    }
+
+    /// Counts the canonical NOPs executed in a row, a run of `threshold` of them is spinning.
+    fn count_nop(&mut self, code: u32, threshold: u64) {
+        if code != CANONICAL_NOP {
+            self.nop_run = 0;
+            return;
+        }
+        self.nop_run += 1;
+        if self.nop_run >= threshold {
+            self.nop_run = 0;
+            self.spinning = true;
+        }
+    }
+
+    /// Tells the idle callback, then sleeps the host thread for the idle sleep. Returns false if
+    /// there is none to sleep for.
+    fn idle_host(&mut self) -> bool {
+        if let Some(callback) = self.idle_callback.as_mut() {
+            callback();
+        }
+        match self.idle_sleep {
+            Some(duration) => {
+                thread::sleep(duration);
+                true
+            }
+            None => false,
+        }
     }
 
     fn increment(&mut self) {
-        // Increment the timer register (mtimer) in Clint.
-        self.mmu.bus.clint.increment(&mut self.state);
-        // Increment the value in the TIME register.
-        let time = self.state.csrs.time();
-        self.state.csrs.set_time(time.wrapping_add(1));
+        let ticks = self.pending_ticks;
+        self.pending_ticks = 0;
+        // Every step is a cycle, idle ones in WFI included, but only completed instructions retire.
+        let retired = std::mem::take(&mut self.pending_retired);
+        self.state.csrs.advance_counters(ticks, retired);
+        let hart = self.mmu.hart();
+        let mut bus = self.mmu.bus.borrow_mut();
+        // Increment the timer register (mtimer) in Clint, which the time CSR reads. Harts are
+        // stepped in turn, so only hart 0 advances it.
+        if hart == 0 {
+            bus.clint.increment(ticks);
+        }
+        bus.clint.update_pending(hart, &mut self.state);
+    }
+
+    /// Drops every cached instruction, e.g. after FENCE.I or a change of the address space.
+    pub fn flush_insn_cache(&mut self) {
+        self.insn_cache.clear();
+        if let Some(block_cache) = self.block_cache.as_mut() {
+            block_cache.clear();
+        }
+        self.mmu.bus.borrow_mut().clear_code_pages(self.mmu.hart());
+    }
+
+    /// Drops the instruction words decoded so far, for FENCE.I. Decoding only depends on the word
+    /// itself, so this only matters for caches keyed by more than the word.
+    pub fn flush_decode_cache(&mut self) {
+        self.insn_decoder.clear();
+    }
+
+    /// Returns how often the decoder found an instruction word already decoded. Fetches served by
+    /// the decoded instruction cache don't get to the decoder.
+    pub fn decode_stats(&self) -> DecodeStats {
+        self.insn_decoder.stats
+    }
+
+    /// Returns the ratio of fetches served by the decoded instruction cache.
+    pub fn insn_cache_hit_rate(&self) -> f64 {
+        self.insn_cache.hit_rate()
     }
 
     fn exec(&mut self) -> Result<(), Trap> {
-        let code = self.fetch()?;
-        let insn = self.decode(code)?;
+        let (code, insn) = self.fetch()?;
+        self.delivering = None;
+        self.poll_interrupt()?;
+        self.exec_fetched(code, &insn)?;
+        Ok(())
+    }
+
+    /// Raises the pending interrupt the hart takes before the next instruction, if any.
+    fn poll_interrupt(&mut self) -> Result<(), Trap> {
         if let Some(interrupt) = self.take_interrupt() {
+            trace!(
+                "delivering {}, mip {:#x} mie {:#x}",
+                interrupt,
+                self.state.csrs.mip().bits(),
+                self.state.csrs.mie().bits()
+            );
             return Err(interrupt.into());
         }
-        insn.exec(self)?;
         Ok(())
     }
 
-    fn fetch(&self) -> Result<u32, Exception> {
+    /// Executes the instruction fetched from pc, through the hooks if any are registered.
+    fn exec_fetched(&mut self, code: u32, insn: &Insn) -> Result<(), Exception> {
+        self.history.push(self.state.pc, code);
+        if self.hooks.is_empty() {
+            self.exec_insn(code, insn)
+        } else {
+            self.exec_hooked(code, insn)
+        }
+    }
+
+    /// Executes `insn`, decoded from `code`. The instructions don't know their own bits, so they
+    /// are filled into the illegal instruction exceptions here.
+    fn exec_insn(&mut self, code: u32, insn: &Insn) -> Result<(), Exception> {
+        let result = insn.exec(self).map_err(|e| match e {
+            Exception::IllegalInstruction(_) => Exception::IllegalInstruction(insn_bits(code)),
+            e => e,
+        });
+        if let Some(stats) = self.stats.as_mut() {
+            stats.executed(code, result.is_ok());
+        }
+        if let Some(threshold) = self.spin_detection.and_then(|detection| detection.nop_run) {
+            self.count_nop(code, threshold);
+        }
+        result
+    }
+
+    /// Registers a hook called around every executed instruction.
+    pub fn add_hook(&mut self, hook: Box<dyn ExecHook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Registers a hook told about every memory access, see `AccessHook`.
+    pub fn add_access_hook(&mut self, hook: Box<dyn AccessHook>) {
+        self.mmu.add_access_hook(hook);
+    }
+
+    /// Registers a hook told about the changes of the privileged state, see `CpuEvent`.
+    pub fn add_event_hook(&mut self, hook: Box<dyn EventHook>) {
+        self.event_hooks.push(hook);
+    }
+
+    /// Whether any event hook is registered, so that the state to report is only collected then.
+    pub(crate) fn events_enabled(&self) -> bool {
+        !self.event_hooks.is_empty()
+    }
+
+    pub(crate) fn emit(&mut self, event: CpuEvent) {
+        for hook in self.event_hooks.iter_mut() {
+            hook.event(&event);
+        }
+    }
+
+    /// Reports the trap or xRET at `pc` that took the hart from `from` to the current privilege:
+    /// first the CSRs in `csrs` that changed from the values `before`, then the transition.
+    pub(crate) fn emit_transition(
+        &mut self,
+        pc: RegT,
+        from: PrivilegeMode,
+        cause: Transition,
+        csrs: &[u16],
+        before: &[RegT],
+    ) {
+        for (&csr, &old) in csrs.iter().zip(before) {
+            let new = self.state.csrs.csr(csr);
+            if new != old {
+                self.emit(CpuEvent::CsrWrite { pc, csr, old, new });
+            }
+        }
+        self.emit(CpuEvent::Privilege {
+            from,
+            to: self.state.privilege,
+            cause,
+            pc: self.state.pc,
+        });
+    }
+
+    /// Starts counting how often every pc is executed.
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            let coverage = Rc::new(RefCell::new(Coverage::new()));
+            self.add_hook(Box::new(CoverageHook::new(coverage.clone())));
+            self.coverage = Some(coverage);
+        }
+    }
+
+    /// Returns the coverage collected so far, `None` unless `enable_coverage` was called.
+    pub fn coverage(&self) -> Option<Ref<'_, Coverage>> {
+        self.coverage.as_ref().map(|coverage| coverage.borrow())
+    }
+
+    /// Starts sampling pc every `period` retired instructions, with the return addresses of up to
+    /// `depth` callers found through the frame pointer, see `profile`.
+    pub fn enable_profiling(&mut self, period: u64, depth: usize) {
+        if self.profile.is_none() {
+            let profile = Rc::new(RefCell::new(Profile::new(period, depth)));
+            self.add_hook(Box::new(ProfileHook::new(profile.clone())));
+            self.profile = Some(profile);
+        }
+    }
+
+    /// Returns the samples taken so far, `None` unless `enable_profiling` was called.
+    pub fn profile(&self) -> Option<Ref<'_, Profile>> {
+        self.profile.as_ref().map(|profile| profile.borrow())
+    }
+
+    /// Starts collecting the statistics of `stats`, including the MMIO accesses of the whole
+    /// machine.
+    pub fn enable_stats(&mut self) {
+        if self.stats.is_none() {
+            self.stats = Some(Box::new(Stats::new()));
+            self.mmu.bus.borrow_mut().count_mmio();
+        }
+    }
+
+    /// Returns the statistics collected so far, `None` unless `enable_stats` was called.
+    pub fn stats(&self) -> Option<StatsReport> {
+        let stats = self.stats.as_ref()?;
+        let mnemonic = |code| match self.insn_decoder.inner.decode(code) {
+            Some(insn) => insn.to_string(),
+            None => "unknown".to_string(),
+        };
+        Some(stats.report(
+            mnemonic,
+            self.decode_stats(),
+            self.insn_cache_hit_rate(),
+            self.mmu.bus.borrow().mmio_accesses(),
+        ))
+    }
+
+    /// Returns the architectural state as JSON, for scripts and CI to assert on:
+    ///
+    /// ```text
+    /// {"version": 1, "xlen": 64, "pc": "0x80000000", "privilege": "machine",
+    ///  "x": {"zero": "0x0", "ra": "0x0", ...}, "csrs": {"mstatus": "0x0", ...}}
+    /// ```
+    ///
+    /// Register values are hex strings, JSON numbers can't hold 64 bits in every reader. Bump
+    /// `STATE_JSON_VERSION` when a field changes meaning or goes away, new fields keep it.
+    #[cfg(feature = "json")]
+    pub fn state_json(&self) -> serde_json::Value {
+        use crate::register::xs::ABI_NAMES;
+        use serde_json::{json, Map, Value};
+
+        let hex = |value: RegT| Value::String(format!("{:#x}", value));
+        let xs: Map<String, Value> = ABI_NAMES
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (name.to_string(), hex(self.state.xs.reg(id as u8))))
+            .collect();
+        let csrs: Map<String, Value> = STATE_JSON_CSRS
+            .iter()
+            .map(|num| {
+                let name = Csrs::name(*num).expect("named csr");
+                (name.to_string(), hex(self.state.csrs.csr(*num)))
+            })
+            .collect();
+        let privilege = match self.state.privilege {
+            PrivilegeMode::User => "user",
+            PrivilegeMode::Supervisor => "supervisor",
+            PrivilegeMode::Machine => "machine",
+        };
+        json!({
+            "version": STATE_JSON_VERSION,
+            "xlen": self.xlen as u32,
+            "pc": hex(self.state.pc),
+            "privilege": privilege,
+            "x": xs,
+            "csrs": csrs,
+        })
+    }
+
+    fn exec_hooked(&mut self, code: u32, insn: &Insn) -> Result<(), Exception> {
+        let pc = self.state.pc;
+        // Taken out for the duration so that the hooks can look at the state.
+        let mut hooks = std::mem::take(&mut self.hooks);
+        let action = hooks.iter_mut().find_map(|hook| hook.before(pc, code));
+        let result = match action {
+            None => self.exec_insn(code, insn),
+            Some(HookAction::Skip) => {
+                self.state.advance_pc(4);
+                Ok(())
+            }
+            Some(HookAction::Raise(e)) => Err(e),
+        };
+        for hook in hooks.iter_mut() {
+            hook.after(pc, &self.state, result);
+            hook.observe(self);
+        }
+        self.hooks = hooks;
+        result
+    }
+
+    /// Collects the machine state for diagnosing `exception`, raised by the instruction at pc.
+    pub fn fault_report(&self, exception: Exception) -> FaultReport {
+        FaultReport {
+            exception,
+            state: self.state_report(),
+        }
+    }
+
+    /// Collects the machine state for diagnosing where the hart is, e.g. a guest that hangs.
+    pub fn state_report(&self) -> StateReport {
+        let history = self.history.to_vec();
+        let insn = history
+            .last()
+            .filter(|(pc, _)| *pc == self.state.pc)
+            .and_then(|(_, code)| {
+                let insn = self.insn_decoder.inner.decode(*code)?;
+                Some((*code, insn.to_string()))
+            });
+        let csrs = &self.state.csrs;
+        StateReport {
+            privilege: self.state.privilege,
+            pc: self.state.pc,
+            pc_symbol: self.symbols.describe(self.state.pc),
+            insn,
+            xs: self.state.xs.clone(),
+            csrs: vec![
+                ("mstatus", csrs.mstatus().bits()),
+                ("mcause", csrs.mcause()),
+                ("mepc", csrs.mepc()),
+                ("mtval", csrs.mtval()),
+                ("satp", csrs.satp().bits()),
+                ("mhartid", csrs.mhartid()),
+            ],
+            history: history
+                .into_iter()
+                .map(|(pc, code)| (pc, code, self.symbols.describe(pc)))
+                .collect(),
+        }
+    }
+
+    /// Returns the instruction at pc along with the instruction word it was decoded from.
+    fn fetch(&mut self) -> Result<(u32, Rc<Insn>), Exception> {
+        let pc = self.state.pc;
+        // Backstop for targets that weren't produced by a jump, e.g. xRET to a misaligned xepc.
+        if pc & INSN_ALIGN_MASK != 0 {
+            return Err(Exception::InstructionMisaligned(pc));
+        }
+        self.mmu
+            .check_triggers(&self.state, pc, AccessType::Fetch)?;
+        self.check_code_caches();
+
+        // A 32-bit instruction at the last halfword of a page is fetched as two halfwords, each
+        // translated and checked on its own, so a fault on the upper half reports its address.
+        let straddles = (pc + 2).is_multiple_of(PAGE_SIZE);
+        let size = if straddles { 2 } else { 4 };
+        let ctx = self.mmu.translate_fetch(&self.state, pc, size)?;
+        self.mmu.observe(&ctx);
+        let p_pc = ctx.p_addr;
+        if let Some(entry) = self.insn_cache.get(p_pc) {
+            return Ok(entry);
+        }
+        let code = if straddles {
+            self.read_straddling_code(p_pc)?
+        } else {
+            self.read_code::<u32>(pc, p_pc)?
+        };
+        let insn = self.decode(code)?;
+        let hart = self.mmu.hart();
+        // The upper half of a straddling instruction lives on another page whose mapping the
+        // cache doesn't track, so only instructions within one page are cached.
+        if !straddles && self.mmu.bus.borrow_mut().mark_code_page(hart, p_pc) {
+            self.insn_cache.insert(p_pc, code, insn.clone());
+        }
+        Ok((code, insn))
+    }
+
+    /// Reads the instruction at pc, the last halfword of a page, whose lower half was translated
+    /// to `p_low`. The upper half is translated on its own, unless the lower half is a compressed
+    /// instruction that doesn't reach the next page.
+    fn read_straddling_code(&self, p_low: u64) -> Result<u32, Exception> {
+        let low = self.read_code::<u16>(self.state.pc, p_low)? as u32;
+        if low & 0b11 != 0b11 {
+            return Ok(low);
+        }
+        let high = self.state.pc.wrapping_add(2) & self.xlen.mask();
+        let p_high = self.mmu.translate_fetch(&self.state, high, 2)?.p_addr;
+        Ok(low | (self.read_code::<u16>(high, p_high)? as u32) << 16)
+    }
+
+    /// Reads instruction bits at `p_addr`, the address `addr` translated to. Nothing mapped there
+    /// is an instruction access fault at `addr`, not the load fault the bus reports.
+    fn read_code<T: Data>(&self, addr: u64, p_addr: u64) -> Result<T, Exception> {
+        self.mmu
+            .bus
+            .borrow()
+            .read::<T>(p_addr)
+            .map_err(|_| Exception::InstructionFault(addr))
+    }
+
+    /// Drops the cached instructions if code was written to or the address space changed since
+    /// the last fetch.
+    fn check_code_caches(&mut self) {
+        let hart = self.mmu.hart();
+        let satp = self.state.csrs.satp().bits();
+        if self.mmu.bus.borrow().code_written(hart) || satp != self.insn_cache.satp {
+            self.flush_insn_cache();
+            self.insn_cache.satp = satp;
+        }
+    }
+
+    /// Returns the block at pc, building it on its first execution. Returns `None` if there is
+    /// no block cache, or if fetching the first instruction fails, which a single step reports.
+    fn fetch_block(&mut self) -> Option<Block> {
+        self.block_cache.as_ref()?;
         let pc = self.state.pc;
-        self.mmu.fetch(&self.state, pc)
+        if pc & INSN_ALIGN_MASK != 0 {
+            return None;
+        }
+        self.check_code_caches();
+        let p_pc = self.mmu.translate_fetch(&self.state, pc, 4).ok()?.p_addr;
+        let block = match self.block_cache.as_ref()?.get(p_pc) {
+            Some(block) => block,
+            None => self.build_block(p_pc)?,
+        };
+        // Only the first instruction was checked by the translation, PMP may still deny the rest.
+        let len = block.len() * 4;
+        if len > 4
+            && !self
+                .state
+                .csrs
+                .pmp()
+                .check(p_pc, len, self.state.privilege, &AccessType::Fetch)
+        {
+            return None;
+        }
+        Some(block)
+    }
+
+    /// Decodes the instructions from `p_pc` up to the first one ending the block, stopping early
+    /// at the end of the page or at a word that doesn't decode.
+    fn build_block(&mut self, p_pc: u64) -> Option<Block> {
+        let mut insns = Vec::new();
+        let mut p_addr = p_pc;
+        while insns.len() < MAX_BLOCK_LEN {
+            let code = match self.mmu.bus.borrow().read::<u32>(p_addr) {
+                Ok(code) => code,
+                Err(_) => break,
+            };
+            let insn = match self.try_decode(code) {
+                Some(insn) => insn,
+                None => break,
+            };
+            insns.push((code, insn));
+            p_addr += 4;
+            if block::ends_block(code) || p_addr.is_multiple_of(PAGE_SIZE) {
+                break;
+            }
+        }
+        if insns.is_empty() {
+            return None;
+        }
+        let block: Block = insns.into();
+        let hart = self.mmu.hart();
+        // Stores into the page are noticed like for the decoded instruction cache.
+        if self.mmu.bus.borrow_mut().mark_code_page(hart, p_pc) {
+            self.block_cache.as_mut()?.insert(p_pc, block.clone());
+        }
+        Some(block)
+    }
+
+    /// Decodes `code` for this hart's XLEN.
+    fn try_decode(&mut self, code: u32) -> Option<Rc<Insn>> {
+        match self.xlen {
+            XLen::X32 if is_rv64_only(code) => None,
+            XLen::X64 if is_rv32_only(code) => None,
+            _ => self.insn_decoder.decode(code),
+        }
     }
 
     fn decode(&mut self, code: u32) -> Result<Rc<Insn>, Exception> {
-        let insn = self.insn_decoder.decode(code);
-        insn.ok_or_else(|| {
-            println!("IllegalInstruction code: {:x}", code);
-            Exception::IllegalInstruction
+        self.try_decode(code).ok_or_else(|| {
+            debug!("illegal instruction {:#010x} at {:#x}", code, self.state.pc);
+            Exception::IllegalInstruction(insn_bits(code))
         })
     }
 
-    fn handle_trap(&mut self, trap: Trap) {
-        let csrs = &mut self.state.csrs;
-        let (deleg, mut cause, is_interrupt) = match trap {
-            Trap::Interrupt(i) => (csrs.mideleg().bits(), i.code(), true),
-            Trap::Exception(e) => (csrs.medeleg().bits(), e.code(), false),
+    /// Returns the privilege mode `trap` is taken in. Machine-level interrupts and ECALL from
+    /// M-mode stay in M-mode even if a restored snapshot set their delegation bits.
+    fn trap_privilege(&self, trap: Trap) -> PrivilegeMode {
+        let csrs = &self.state.csrs;
+        let (deleg, code) = match trap {
+            Trap::Interrupt(i) => (csrs.mideleg().bits() & MIDELEG_MASK, i.code()),
+            Trap::Exception(e) => (csrs.medeleg().bits() & MEDELEG_MASK, e.code()),
         };
+        if self.state.privilege != PrivilegeMode::Machine && (deleg >> code) & 1 == 1 {
+            // deleg to s-mode
+            PrivilegeMode::Supervisor
+        } else {
+            PrivilegeMode::Machine
+        }
+    }
 
-        if is_interrupt {
-            cause = (1 << (self.xlen.len() - 1)) | cause;
+    /// Returns the base address of the trap handler taking `trap`.
+    fn trap_vector(&self, trap: Trap) -> RegT {
+        match self.trap_privilege(trap) {
+            PrivilegeMode::Supervisor => self.state.csrs.stvec().address(),
+            _ => self.state.csrs.mtvec().address(),
         }
+    }
 
-        let next_privilege =
-            if self.state.privilege != PrivilegeMode::Machine && (deleg >> cause) & 1 == 1 {
-                // deleg to s-mode
-                PrivilegeMode::Supervisor
-            } else {
-                PrivilegeMode::Machine
-            };
+    fn handle_trap(&mut self, trap: Trap) {
+        self.deliver_trap(trap, self.trap_privilege(trap));
+    }
+
+    /// Takes `trap` in `next_privilege`, regardless of the delegation.
+    fn deliver_trap(&mut self, trap: Trap, next_privilege: PrivilegeMode) {
+        if let Some(stats) = self.stats.as_mut() {
+            stats.trap(trap);
+        }
+        self.traps_in_a_row += 1;
+        let (epc, privilege) = (self.state.pc, self.state.privilege);
+        // xepc, xcause, xtval and xstatus, compared afterwards to report the changed ones.
+        let watched = match next_privilege {
+            PrivilegeMode::Supervisor => [0x141, 0x142, 0x143, 0x100],
+            _ => [0x341, 0x342, 0x343, 0x300],
+        };
+        let before = self
+            .events_enabled()
+            .then(|| watched.map(|csr| self.state.csrs.csr(csr)));
+        if let (Trap::Interrupt(interrupt), true) = (trap, before.is_some()) {
+            self.emit(CpuEvent::Interrupt {
+                interrupt,
+                target: next_privilege,
+                epc,
+            });
+        }
+        let csrs = &mut self.state.csrs;
+        let (code, tval, is_interrupt) = match trap {
+            Trap::Interrupt(i) => (i.code(), 0, true),
+            Trap::Exception(e) => (e.code(), e.tval(), false),
+        };
+        let cause = trap.cause_code(self.xlen);
 
         let xtvec = match next_privilege {
             PrivilegeMode::Supervisor => {
                 csrs.set_sepc(self.state.pc);
                 csrs.set_scause(cause);
-                csrs.set_stval(0);
+                csrs.set_stval(tval);
 
                 let mut sstatus = csrs.sstatus();
                 // Set a privious interrupt-enable bit for supervisor mode (SPIE, 5) to the value
@@ -122,7 +1234,7 @@ impl Cpu {
             PrivilegeMode::Machine => {
                 csrs.set_mepc(self.state.pc);
                 csrs.set_mcause(cause);
-                csrs.set_mtval(0);
+                csrs.set_mtval(tval);
 
                 let mut mstatus = csrs.mstatus();
                 // Set a privious interrupt-enable bit for supervisor mode (MPIE, 7) to the value
@@ -140,128 +1252,1833 @@ impl Cpu {
 
         let trap_pc = xtvec
             .trap_mode()
-            .trap_pc(xtvec.address(), cause, is_interrupt);
+            .trap_pc(xtvec.address(), code, is_interrupt);
+        trace!(
+            "{} cause {:#x} epc {:#x} tval {:#x}, {:?} -> {:?} at {:#x}",
+            trap,
+            cause,
+            self.state.pc,
+            tval,
+            self.state.privilege,
+            next_privilege,
+            trap_pc
+        );
 
+        self.delivering = Some((trap, self.state.pc));
         self.state.update_pc(trap_pc);
         self.state.privilege = next_privilege;
+        if let Some(before) = before {
+            self.emit_transition(epc, privilege, Transition::Trap(trap), &watched, &before);
+        }
     }
 
     fn take_interrupt(&mut self) -> Option<Interrupt> {
-        // 检查中断使能
-        match self.state.privilege {
-            PrivilegeMode::Supervisor => {
-                if !self.state.csrs.sstatus().sie() {
-                    return None;
-                }
-            }
-            PrivilegeMode::Machine => {
-                if !self.state.csrs.mstatus().mie() {
-                    return None;
-                }
-            }
-            _ => {}
+        // Pending bits are levels driven by the devices, taking the interrupt doesn't clear them.
+        // The CLINT refreshes its lines on every tick, writes to the devices take effect at once.
+        if self.mmu.bus.borrow().lines_changed(self.mmu.hart()) {
+            self.update_interrupt_lines();
         }
 
-        self.check_external_interrupts();
+        // 检查中断使能：未委托的中断在低于 M 模式时总是使能，委托给 S 模式的中断在 M 模式下不会发生。
+        let privilege = self.state.privilege;
+        let m_enabled = privilege != PrivilegeMode::Machine || self.state.csrs.mstatus().mie();
+        let s_enabled = privilege == PrivilegeMode::User
+            || (privilege == PrivilegeMode::Supervisor && self.state.csrs.sstatus().sie());
+        if !m_enabled && !s_enabled {
+            return None;
+        }
 
-        let mip = self.state.csrs.mip();
-        let mie = self.state.csrs.mie();
-        let pendings = mip.bits() & mie.bits();
-        let interrupts = Mip::from(pendings);
-        let mut mip = self.state.csrs.mip();
+        let mideleg = self.state.csrs.mideleg().bits();
+        let enabled = match (m_enabled, s_enabled) {
+            (true, true) => !0,
+            (true, false) => !mideleg,
+            _ => mideleg,
+        } & self.state.csrs.mie().bits();
+        if enabled & mip::SEIP != 0 {
+            self.check_external_interrupts();
+        }
+        let pendings = self.state.csrs.mip().bits();
+        let interrupts = Mip::from(pendings & enabled);
 
-        let ret = if interrupts.mext() {
-            mip.set_mext(false);
+        if interrupts.mext() {
             Some(Interrupt::MachineExternal)
         } else if interrupts.msoft() {
-            mip.set_msoft(false);
             Some(Interrupt::MachineSoft)
         } else if interrupts.mtimer() {
-            mip.set_mtimer(false);
             Some(Interrupt::MachineTimer)
         } else if interrupts.sext() {
-            mip.set_sext(false);
             Some(Interrupt::SupervisorExternal)
         } else if interrupts.ssoft() {
-            mip.set_ssoft(false);
             Some(Interrupt::SupervisorSoft)
         } else if interrupts.stimer() {
-            mip.set_stimer(false);
             Some(Interrupt::SupervisorTimer)
         } else {
             None
-        };
-        self.state.csrs.set_mip(mip.bits());
-        ret
+        }
+    }
+
+    /// Refreshes the interrupt-pending lines from the CLINT and the PLIC, e.g. after the guest
+    /// wrote mtimecmp or completed an interrupt.
+    fn update_interrupt_lines(&mut self) {
+        let hart = self.mmu.hart();
+        let mut bus = self.mmu.bus.borrow_mut();
+        bus.clear_lines_changed(hart);
+        bus.clint.update_pending(hart, &mut self.state);
+        let seip = bus.plic.is_interrupting_supervisor(hart);
+        self.state.csrs.set_interrupt_line(mip::SEIP, seip);
     }
 
     fn check_external_interrupts(&mut self) {
-        let irq = if self.mmu.bus.uart.is_interrupting() {
-            UART_IRQ
-        } else if self.mmu.bus.virtio.is_interrupting() {
-            // An interrupt is raised after a disk access is done.
-            Virtio::disk_access(&mut self.mmu.bus).expect("failed to access the disk");
-            VIRTIO_IRQ
-        } else {
-            0
-        };
-        if irq != 0 {
-            self.mmu.bus.plic.update_pending(irq);
-            let mut mip = self.state.csrs.mip();
-            mip.set_sext(true);
-            self.state.csrs.set_mip(mip.bits());
+        let mut bus = self.mmu.bus.borrow_mut();
+        if bus.update_irq_lines() {
+            // The interrupts may be routed to the other harts as well.
+            bus.notify_lines_changed();
+            drop(bus);
+            self.update_interrupt_lines();
+        }
+    }
+}
+
+/// A device registered with `CpuBuilder::device`: its base, length, IRQ and the device itself.
+type DeviceMapping = (u64, u64, Option<u64>, Box<dyn Device>);
+
+/// Configures the cpu and the machine around it. Anything not set keeps the default of
+/// `Cpu::new`: RV64, the default DRAM, entry at the start of the DRAM in M-mode, and the console
+/// on the host stdout.
+pub struct CpuBuilder {
+    binary: Vec<u8>,
+    xlen: XLen,
+    dram_base: u64,
+    dram_size: usize,
+    /// Memory regions besides the DRAM, as base and size.
+    memories: Vec<(u64, usize)>,
+    /// Defaults to the start of the DRAM.
+    entry: Option<u64>,
+    privilege: PrivilegeMode,
+    regs: Vec<(u8, RegT)>,
+    /// The device tree address passed in a1 if the boot protocol is followed.
+    boot_dtb: Option<RegT>,
+    devices: Vec<DeviceMapping>,
+    disk: Option<Vec<u8>>,
+    console: Option<Box<dyn Write>>,
+    serial: Option<Box<dyn Write>>,
+    semihosting: bool,
+    builtin_sbi: bool,
+    csr_policy: CsrPolicy,
+    decode_cache_capacity: usize,
+    block_cache: bool,
+    spin_detection: Option<SpinDetection>,
+    hang_detection: Option<HangDetection>,
+    deterministic: bool,
+}
+
+// Not every option is used by the CLI.
+#[allow(dead_code)]
+impl CpuBuilder {
+    /// Starts configuring a machine whose DRAM begins with `binary`.
+    pub fn new(binary: Vec<u8>) -> Self {
+        Self {
+            binary,
+            xlen: XLen::X64,
+            dram_base: DRAM_BASE,
+            dram_size: DRAM_SIZE,
+            memories: Vec::new(),
+            entry: None,
+            privilege: PrivilegeMode::Machine,
+            regs: Vec::new(),
+            boot_dtb: None,
+            devices: Vec::new(),
+            disk: None,
+            console: None,
+            serial: None,
+            semihosting: false,
+            builtin_sbi: false,
+            csr_policy: CsrPolicy::Permissive,
+            decode_cache_capacity: DEFAULT_DECODE_CACHE_CAPACITY,
+            block_cache: true,
+            spin_detection: None,
+            hang_detection: None,
+            deterministic: false,
+        }
+    }
+
+    pub fn xlen(mut self, xlen: XLen) -> Self {
+        self.xlen = xlen;
+        self
+    }
+
+    /// Places `size` bytes of DRAM at `base`. The initial stack pointer is the end of the DRAM.
+    pub fn dram(mut self, base: u64, size: usize) -> Self {
+        self.dram_base = base;
+        self.dram_size = size;
+        self
+    }
+
+    /// Starts executing at `pc` instead of the reset vector in the boot ROM, which jumps to the
+    /// start of the DRAM.
+    pub fn entry(mut self, pc: u64) -> Self {
+        self.entry = Some(pc);
+        self
+    }
+
+    pub fn privilege(mut self, privilege: PrivilegeMode) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Sets the initial value of register x`reg`, overriding the boot protocol and the stack
+    /// pointer.
+    pub fn reg(mut self, reg: u8, value: RegT) -> Self {
+        self.regs.push((reg, value));
+        self
+    }
+
+    /// Follows the RISC-V boot protocol: a0 holds the hart id and a1 the address of the device
+    /// tree.
+    pub fn boot_protocol(mut self, dtb_addr: RegT) -> Self {
+        self.boot_dtb = Some(dtb_addr);
+        self
+    }
+
+    /// Adds `size` bytes of memory at `base` besides the DRAM, see `Bus::add_memory`.
+    pub fn memory(mut self, base: u64, size: usize) -> Self {
+        self.memories.push((base, size));
+        self
+    }
+
+    /// Maps `device` at `[base, base + len)`, see `Bus::register_device`.
+    pub fn device(
+        mut self,
+        base: u64,
+        len: u64,
+        irq: Option<u64>,
+        device: Box<dyn Device>,
+    ) -> Self {
+        self.devices.push((base, len, irq, device));
+        self
+    }
+
+    /// Maps `framebuffer` at `FRAMEBUFFER_BASE`.
+    #[cfg(feature = "framebuffer")]
+    pub fn framebuffer(self, framebuffer: Framebuffer) -> Self {
+        let size = framebuffer.size();
+        self.device(FRAMEBUFFER_BASE, size, None, Box::new(framebuffer))
+    }
+
+    /// Backs the virtio disk with `image`.
+    pub fn disk(mut self, image: Vec<u8>) -> Self {
+        self.disk = Some(image);
+        self
+    }
+
+    /// Sends the console output to `output` instead of the host stdout.
+    pub fn console(mut self, output: Box<dyn Write>) -> Self {
+        self.console = Some(output);
+        self
+    }
+
+    /// Sends the output of the second UART to `output` instead of dropping it.
+    pub fn serial(mut self, output: Box<dyn Write>) -> Self {
+        self.serial = Some(output);
+        self
+    }
+
+    /// Lets EBREAK perform semihosting calls instead of always raising a breakpoint exception.
+    pub fn semihosting(mut self, enabled: bool) -> Self {
+        self.semihosting = enabled;
+        self
+    }
+
+    /// Starts the harts in S-mode at the start of the DRAM, with the SBI calls served by the
+    /// emulator instead of firmware, see `sbi`.
+    pub fn builtin_sbi(mut self, enabled: bool) -> Self {
+        self.builtin_sbi = enabled;
+        self
+    }
+
+    /// Sets what accessing a CSR the emulator doesn't implement does, permissive by default.
+    pub fn csr_policy(mut self, policy: CsrPolicy) -> Self {
+        self.csr_policy = policy;
+        self
+    }
+
+    /// Keeps up to `capacity` instruction words decoded, 0 decodes every fetched word again.
+    pub fn decode_cache(mut self, capacity: usize) -> Self {
+        self.decode_cache_capacity = capacity;
+        self
+    }
+
+    /// Lets `Cpu::run_block` execute whole basic blocks, on by default.
+    pub fn block_cache(mut self, enabled: bool) -> Self {
+        self.block_cache = enabled;
+        self
+    }
+
+    /// Idles the host thread while the guest spins, off by default, see `Cpu::set_spin_detection`.
+    pub fn spin_detection(mut self, detection: Option<SpinDetection>) -> Self {
+        self.spin_detection = detection;
+        self
+    }
+
+    /// Stops the machine when the guest looks stuck, off by default, see
+    /// `Cpu::set_hang_detection`.
+    pub fn hang_detection(mut self, detection: Option<HangDetection>) -> Self {
+        self.hang_detection = detection;
+        self
+    }
+
+    /// Keeps the host's timing out of the run: spin detection stays off whatever `spin_detection`
+    /// says, so the host thread never idles. The timers only count steps anyway, and console input
+    /// has to come from a script, see `console::ConsoleFeed`.
+    pub fn deterministic(mut self, enabled: bool) -> Self {
+        self.deterministic = enabled;
+        self
+    }
+
+    pub fn build(self) -> Cpu {
+        self.build_harts(1).remove(0)
+    }
+
+    /// Creates `harts` harts sharing one bus, configured the same apart from their hart ids.
+    pub fn build_harts(mut self, harts: usize) -> Vec<Cpu> {
+        let console = self
+            .console
+            .take()
+            .unwrap_or_else(|| Box::new(io::stdout()));
+        let serial = self.serial.take().unwrap_or_else(|| Box::new(io::sink()));
+        let binary = std::mem::take(&mut self.binary);
+        let mut bus = Bus::with_dram(binary, self.dram_base, self.dram_size, console, serial);
+        for (base, size) in self.memories.iter() {
+            bus.add_memory(*base, *size);
+        }
+        for (base, len, irq, device) in self.devices.drain(..) {
+            bus.register_device(base, len, irq, device);
+        }
+        if let Some(image) = self.disk.take() {
+            bus.virtio.initialize(image);
         }
+        // User mode emulation puts DRAM over the low addresses, the guest has no boot ROM there.
+        let rom = self.dram_base >= ROM_BASE + ROM_SIZE
+            || self.dram_base + self.dram_size as u64 <= ROM_BASE;
+        if rom {
+            let dtb = self.boot_dtb.unwrap_or(0);
+            let vector = Rom::reset_vector(self.xlen, self.dram_base, dtb);
+            bus.register_device(ROM_BASE, ROM_SIZE, None, Box::new(vector));
+        }
+        let bus = Rc::new(RefCell::new(bus));
+        let entry = match self.entry {
+            Some(entry) => entry,
+            // The reset vector is M-mode code, the kernel is entered directly.
+            None if rom && !self.builtin_sbi => ROM_BASE,
+            None => self.dram_base,
+        };
+        (0..harts)
+            .map(|_| {
+                let mut cpu =
+                    Cpu::with_bus(self.xlen, bus.clone(), entry, self.decode_cache_capacity);
+                cpu.state.privilege = self.privilege;
+                if self.builtin_sbi {
+                    sbi::install(&mut cpu);
+                }
+                cpu.semihosting = self.semihosting;
+                cpu.csr_policy = self.csr_policy;
+                cpu.set_block_cache(self.block_cache);
+                if !self.deterministic {
+                    cpu.set_spin_detection(self.spin_detection);
+                }
+                cpu.set_hang_detection(self.hang_detection);
+                if let Some(dtb_addr) = self.boot_dtb {
+                    let hart_id = cpu.mmu.hart() as RegT;
+                    cpu.state.xs.set_reg(10, hart_id);
+                    cpu.state.xs.set_reg(11, dtb_addr);
+                }
+                for (reg, value) in self.regs.iter() {
+                    cpu.state.xs.set_reg(*reg, *value & self.xlen.mask());
+                }
+                cpu
+            })
+            .collect()
     }
 }
 
+#[derive(Clone)]
 pub struct CpuStatus {
     pub privilege: PrivilegeMode,
     pub xs: Xs,
+    pub fs: Fs,
     pub csrs: Csrs,
     pub pc: RegT,
+    /// pc wraps around at XLEN bits.
+    pc_mask: RegT,
 }
 
 impl CpuStatus {
-    fn new(start_address: u64) -> Self {
+    fn new(xlen: XLen, start_address: u64) -> Self {
         Self {
             privilege: PrivilegeMode::Machine,
             xs: Xs::new(),
-            csrs: Csrs::new(),
+            fs: Fs::new(),
+            csrs: Csrs::new(xlen),
             pc: start_address,
+            pc_mask: xlen.mask(),
         }
     }
 
-    fn reset(&mut self) {
-        // The stack pointer (SP) must be set up at first.;
-        self.xs.set_reg(2, DRAM_BASE + DRAM_SIZE as u64);
+    /// Starts in M-mode with the stack pointer (SP) at `stack_top`, the end of the DRAM.
+    fn reset(&mut self, stack_top: RegT) {
+        self.xs.set_reg(2, stack_top);
         self.privilege = PrivilegeMode::Machine;
     }
 
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        snapshot::write_u64(w, self.privilege as u64)?;
+        snapshot::write_u64(w, self.pc)?;
+        self.xs.save(w)?;
+        self.fs.save(w)?;
+        self.csrs.save(w)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.privilege = match snapshot::read_u64(r)? {
+            0 => PrivilegeMode::User,
+            1 => PrivilegeMode::Supervisor,
+            2 => PrivilegeMode::Machine,
+            _ => return Err(snapshot::invalid_data("invalid privilege mode")),
+        };
+        self.pc = snapshot::read_u64(r)?;
+        self.xs.restore(r)?;
+        self.fs.restore(r)?;
+        self.csrs.restore(r)
+    }
+
     pub fn update_pc(&mut self, value: RegT) {
-        self.pc = value;
+        self.pc = value & self.pc_mask;
+    }
+
+    /// Moves pc past the `delta` bytes of the instruction just executed, wrapping at XLEN bits.
+    pub fn advance_pc(&mut self, delta: RegT) {
+        self.update_pc(self.pc.wrapping_add(delta));
+    }
+
+    /// Updates pc to the target of a taken branch or jump. The misaligned exception is raised on
+    /// the branch itself, leaving pc untouched.
+    pub fn jump(&mut self, target: RegT) -> Result<(), Exception> {
+        let target = target & self.pc_mask;
+        if target & INSN_ALIGN_MASK != 0 {
+            return Err(Exception::InstructionMisaligned(target));
+        }
+        self.update_pc(target);
+        Ok(())
+    }
+}
+
+/// The default number of instruction words `InsnDecoderWithLru` keeps decoded.
+pub const DEFAULT_DECODE_CACHE_CAPACITY: usize = 127;
+
+/// How often `InsnDecoderWithLru` found an instruction word already decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl DecodeStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
     }
 }
 
 struct InsnDecoderWithLru {
     inner: InsnDecoder,
-    cache: LruCache<u32, Option<Rc<Insn>>>,
+    /// `None` if the capacity is 0, every word is decoded again.
+    cache: Option<LruCache<u32, Option<Rc<Insn>>>>,
+    stats: DecodeStats,
 }
 
 impl InsnDecoderWithLru {
-    fn new(insn_decoder: InsnDecoder) -> Self {
+    fn new(insn_decoder: InsnDecoder, capacity: usize) -> Self {
         Self {
             inner: insn_decoder,
-            cache: LruCache::new(127),
+            cache: (capacity > 0).then(|| LruCache::new(capacity)),
+            stats: DecodeStats::default(),
         }
     }
+
     fn decode(&mut self, code: u32) -> Option<Rc<Insn>> {
-        match self.cache.get(&code) {
-            Some(insn) => insn.clone(),
+        let cache = match self.cache.as_mut() {
+            Some(cache) => cache,
             None => {
-                let insn = self.inner.decode(code).map(|insn| Rc::new(insn));
-                self.cache.put(code, insn.clone());
+                self.stats.misses += 1;
+                return self.inner.decode(code).map(Rc::new);
+            }
+        };
+        match cache.get(&code) {
+            Some(insn) => {
+                self.stats.hits += 1;
+                insn.clone()
+            }
+            None => {
+                self.stats.misses += 1;
+                let insn = self.inner.decode(code).map(Rc::new);
+                cache.put(code, insn.clone());
                 insn
             }
         }
     }
+
+    fn clear(&mut self) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+    }
+}
+
+/// Decoded instructions keyed by the physical address they were fetched from. Unlike the LRU in
+/// `InsnDecoderWithLru` a hit skips the bus read as well as the decoding.
+struct InsnCache {
+    insns: HashMap<u64, (u32, Rc<Insn>)>,
+    /// The satp the cached instructions were fetched under.
+    satp: RegT,
+    hits: u64,
+    misses: u64,
+}
+
+impl InsnCache {
+    fn new() -> Self {
+        Self {
+            insns: HashMap::new(),
+            satp: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, p_addr: u64) -> Option<(u32, Rc<Insn>)> {
+        let insn = self.insns.get(&p_addr).cloned();
+        match insn {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        insn
+    }
+
+    fn insert(&mut self, p_addr: u64, code: u32, insn: Rc<Insn>) {
+        self.insns.insert(p_addr, (code, insn));
+    }
+
+    fn clear(&mut self) {
+        self.insns.clear();
+    }
+
+    fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{device::CLINT_BASE, register::envcfg, testing::*};
+
+    #[test]
+    fn wfi_waits_until_an_enabled_interrupt_is_pending() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[WFI, addi(10, 0, 1), EBREAK]);
+        // MSIE, mstatus.MIE stays clear so the interrupt isn't taken.
+        cpu.state.csrs.set_mie(1 << 3);
+        cpu.one_step();
+        for _ in 0..3 {
+            cpu.one_step();
+            assert!(cpu.waiting_for_interrupt);
+            assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        }
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u32>(CLINT_BASE, 1)
+            .unwrap();
+        // The software interrupt reaches mip with the next batch of timer ticks.
+        cpu.run_until_ebreak(100);
+        assert!(!cpu.waiting_for_interrupt);
+        assert_eq!(cpu.state.xs.reg(10), 1);
+    }
+
+    #[test]
+    fn wfi_waits_for_the_timer_to_reach_mtimecmp() {
+        // The trap handler at +0x100 is an EBREAK.
+        let mut program = vec![NOP; 0x41];
+        program[..3].copy_from_slice(&[WFI, addi(10, 0, 1), EBREAK]);
+        program[0x40] = EBREAK;
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.set_tick_batch(1);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        // MTIE and mstatus.MIE.
+        cpu.state.csrs.set_mie(1 << 7);
+        cpu.state.csrs.set_mstatus(1 << 3);
+        let mtime = |cpu: &Cpu| {
+            cpu.mmu
+                .bus
+                .borrow()
+                .read::<u64>(CLINT_BASE + 0xbff8)
+                .unwrap()
+        };
+        let mtimecmp = mtime(&cpu) + 5;
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u64>(CLINT_BASE + 0x4000, mtimecmp)
+            .unwrap();
+        cpu.one_step();
+        while mtime(&cpu) < mtimecmp {
+            assert!(cpu.waiting_for_interrupt);
+            assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+            cpu.one_step();
+        }
+        // The step that brought mtime to mtimecmp made MTIP pending, the next one wakes the hart
+        // and the one after takes the interrupt before the ADDI.
+        cpu.one_step();
+        assert!(!cpu.waiting_for_interrupt);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        cpu.one_step();
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.csrs.mcause(), 1 << 63 | 7);
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE + 4);
+        assert_eq!(cpu.state.xs.reg(10), 0);
+    }
+
+    #[test]
+    fn wfi_below_machine_mode_is_illegal_with_tw() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[WFI, EBREAK]);
+        cpu.state.csrs.set_mstatus(1 << 21);
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        assert_eq!(
+            cpu.exec(),
+            Err(Trap::Exception(Exception::IllegalInstruction(WFI as RegT)))
+        );
+        assert!(!cpu.waiting_for_interrupt);
+        assert_eq!(cpu.state.pc, DRAM_BASE);
+    }
+
+    /// Runs the spin loop `program` for 60 steps, returns how often the host thread idled.
+    fn idle_steps(program: &[u32], detection: Option<SpinDetection>) -> usize {
+        let mut cpu = Cpu::with_program(XLen::X64, program);
+        cpu.set_spin_detection(detection);
+        let idled = Rc::new(Cell::new(0));
+        let counter = idled.clone();
+        cpu.set_idle_callback(Box::new(move || counter.set(counter.get() + 1)));
+        let mut steps = 0;
+        while steps < 60 {
+            let (taken, stop) = cpu.run_block(60 - steps);
+            assert!(stop.is_none());
+            steps += taken;
+        }
+        idled.get()
+    }
+
+    #[test]
+    fn a_spinning_guest_idles_the_host() {
+        let pause_loop = [PAUSE, jal(0, -4)];
+        assert_eq!(idle_steps(&pause_loop, Some(SpinDetection::default())), 30);
+        // Off for a deterministic run, PAUSE is only a FENCE then.
+        assert_eq!(idle_steps(&pause_loop, None), 0);
+
+        // Every third NOP ends a run, the jump in between starts the count over.
+        let nop_loop = [NOP, NOP, NOP, NOP, NOP, jal(0, -20)];
+        let nop_run = SpinDetection { nop_run: Some(3) };
+        assert_eq!(idle_steps(&nop_loop, Some(nop_run)), 10);
+        assert_eq!(idle_steps(&nop_loop, Some(SpinDetection::default())), 0);
+    }
+
+    #[test]
+    fn stores_over_cached_instructions_take_effect() {
+        let mut program = vec![
+            auipc(5, 0),
+            addi(11, 0, 2),
+            // Replaced by the word at 0x30 after the first iteration.
+            addi(10, 10, 1),
+            lw(6, 5, 0x30),
+            sw(6, 5, 8),
+            addi(11, 11, -1),
+            b_type(-16, 0, 11, 1),
+            EBREAK,
+        ];
+        program.resize(12, NOP);
+        program.push(addi(10, 10, 16));
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.run_until_ebreak(20);
+        assert_eq!(cpu.state.xs.reg(10), 17);
+
+        // The same through the block cache.
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        for _ in 0..10 {
+            if cpu.state.pc == DRAM_BASE + 0x1c {
+                break;
+            }
+            assert!(cpu.run_block(100).1.is_none());
+        }
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x1c);
+        assert_eq!(cpu.state.xs.reg(10), 17);
+    }
+
+    /// Spins until the timer interrupt set for mtime 100 is taken, returns the steps it took.
+    fn steps_to_timer_interrupt(tick_batch: u64) -> u64 {
+        let mut program = vec![jal(0, 0); 0x41];
+        program[0x40] = EBREAK;
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.set_tick_batch(tick_batch);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        cpu.state.csrs.set_mie(1 << 7);
+        cpu.state.csrs.set_mstatus(1 << 3);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u64>(CLINT_BASE + 0x4000, 100)
+            .unwrap();
+        let mut steps = 0;
+        while cpu.state.pc != DRAM_BASE + 0x100 {
+            cpu.one_step();
+            steps += 1;
+            assert!(steps < 1000, "no timer interrupt");
+        }
+        let mtime = cpu.mmu.bus.borrow().read::<u64>(CLINT_BASE + 0xbff8);
+        assert_eq!(Ok(cpu.state.csrs.time()), mtime);
+        steps
+    }
+
+    #[test]
+    fn timers_advance_in_batches() {
+        // mtime reaches 100 with the 100th step, the next one takes the interrupt.
+        let exact = steps_to_timer_interrupt(1);
+        assert_eq!(exact, 101);
+        let batched = steps_to_timer_interrupt(64);
+        assert!((exact..exact + 64).contains(&batched), "{}", batched);
+    }
+
+    #[test]
+    fn counters_advance_with_the_batches() {
+        let program = [WFI, ECALL, addi(10, 0, 1), addi(10, 10, 1), EBREAK];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.set_tick_batch(2);
+        cpu.state.csrs.set_mie(1 << 3);
+        // The ECALL traps to the first addi.
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 8);
+        let counters = |cpu: &Cpu| {
+            let csrs = &cpu.state.csrs;
+            assert_eq!(csrs.csr(0xc00), csrs.csr(0xb00));
+            assert_eq!(csrs.csr(0xc02), csrs.csr(0xb02));
+            (csrs.csr(0xb00), csrs.csr(0xb02))
+        };
+
+        cpu.one_step();
+        assert_eq!(counters(&cpu), (0, 0));
+        // Idle steps in WFI are cycles without retired instructions.
+        cpu.one_step();
+        assert!(cpu.waiting_for_interrupt);
+        assert_eq!(counters(&cpu), (2, 1));
+        cpu.one_step();
+        // An idle step polls the interrupt lines, the software interrupt wakes the hart at once.
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u32>(CLINT_BASE, 1)
+            .unwrap();
+        cpu.one_step();
+        assert!(!cpu.waiting_for_interrupt);
+        assert_eq!(counters(&cpu), (4, 1));
+        // A trapping instruction doesn't retire either.
+        cpu.one_step();
+        assert_eq!(cpu.state.pc, DRAM_BASE + 8);
+        cpu.one_step();
+        assert_eq!(counters(&cpu), (6, 2));
+    }
+
+    #[test]
+    fn snapshot_load_goes_back_to_the_saved_state() {
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        trace(&mut cpu, 10);
+        let mut snapshot = Vec::new();
+        cpu.save_snapshot(&mut snapshot).unwrap();
+        let (pc, xs, memory) = (cpu.state.pc, registers(&cpu), counts(&cpu));
+        let first = trace(&mut cpu, 40);
+        assert_ne!(counts(&cpu), memory);
+
+        cpu.load_snapshot(&snapshot[..]).unwrap();
+        assert_eq!(cpu.state.pc, pc);
+        assert_eq!(registers(&cpu), xs);
+        assert_eq!(counts(&cpu), memory);
+        assert!(
+            trace(&mut cpu, 40) == first,
+            "the restored machine took another way"
+        );
+
+        // A snapshot of another format is refused.
+        snapshot[0] ^= 1;
+        assert!(cpu.load_snapshot(&snapshot[..]).is_err());
+    }
+
+    #[test]
+    fn checkpoints_only_hold_the_pages_written_since_the_last_one() {
+        let dir = std::env::temp_dir().join(format!("checkpoints-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |n: usize| dir.join(format!("cp{}", n)).to_str().unwrap().to_string();
+        let dram = |cpu: &Cpu| {
+            let mut buf = vec![0; 0x10000];
+            cpu.mmu
+                .bus
+                .borrow()
+                .read_phys_bytes(DRAM_BASE, &mut buf)
+                .unwrap();
+            buf
+        };
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        trace(&mut cpu, 10);
+        assert!(cpu.save_checkpoint(io::sink(), Some("cp0")).is_err());
+        cpu.save_checkpoint(File::create(path(0)).unwrap(), None)
+            .unwrap();
+
+        // Zeros over some counts, a word across pages 4 and 5 and one in page 8.
+        {
+            let mut bus = cpu.mmu.bus.borrow_mut();
+            bus.write_phys_bytes(COUNTS, &[0; 16]).unwrap();
+            bus.write::<u32>(DRAM_BASE + 0x4ffe, 0x1122_3344).unwrap();
+            bus.write::<u64>(DRAM_BASE + 0x8000, 0x5566_7788).unwrap();
+        }
+        cpu.save_checkpoint(File::create(path(1)).unwrap(), Some(&path(0)))
+            .unwrap();
+        // Nothing was written since, the same state without any page.
+        cpu.save_checkpoint(File::create(path(2)).unwrap(), Some(&path(1)))
+            .unwrap();
+        let size = |n: usize| std::fs::metadata(path(n)).unwrap().len();
+        assert_eq!(size(1) - size(2), 4 * (8 + snapshot::CHUNK_SIZE as u64));
+
+        trace(&mut cpu, 20);
+        cpu.save_checkpoint(File::create(path(3)).unwrap(), Some(&path(2)))
+            .unwrap();
+        assert_eq!(size(3) - size(2), 8 + snapshot::CHUNK_SIZE as u64);
+
+        // Restoring the chain from a machine that ran elsewhere reproduces the last checkpoint.
+        let mut restored = Cpu::with_program(XLen::X64, &COUNTER);
+        restored.state.xs.set_reg(11, COUNTS);
+        trace(&mut restored, 50);
+        restored.load_snapshot_file(path(3)).unwrap();
+        assert_eq!(restored.state.pc, cpu.state.pc);
+        assert_eq!(registers(&restored), registers(&cpu));
+        assert!(dram(&restored) == dram(&cpu));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fatal_exception_reports_the_state_and_history() {
+        let program = [addi(10, 0, 7), addi(11, 0, 8), lw(12, 0, 0x10), EBREAK];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.one_step();
+        cpu.one_step();
+        // one_step panics with the report of this exception.
+        assert_eq!(cpu.exec(), Err(Trap::Exception(Exception::LoadFault(0x10))));
+        let report = cpu.fault_report(Exception::LoadFault(0x10));
+        assert_eq!(report.state.pc, DRAM_BASE + 8);
+        assert_eq!(
+            report.state.insn.as_ref().map(|(code, _)| *code),
+            Some(program[2])
+        );
+        assert_eq!((report.state.xs.reg(10), report.state.xs.reg(11)), (7, 8));
+        let history: Vec<_> = report
+            .state
+            .history
+            .iter()
+            .map(|(pc, code, _)| (*pc, *code))
+            .collect();
+        assert_eq!(
+            history,
+            [
+                (DRAM_BASE, program[0]),
+                (DRAM_BASE + 4, program[1]),
+                (DRAM_BASE + 8, program[2])
+            ]
+        );
+        let shown = report.to_string();
+        assert!(
+            shown.starts_with("fatal exception: Load access fault at 0x10 (cause 5)"),
+            "{}",
+            shown
+        );
+        assert!(
+            shown.contains("last 3 instructions, newest last:"),
+            "{}",
+            shown
+        );
+    }
+
+    #[test]
+    fn rv32_program_runs_at_32_bits() {
+        let program = [
+            addi(10, 0, -1),
+            // srai a1, a0, 4
+            i_type(0x404, 10, 5, 11, 0x13),
+            // slt a2, a0, zero
+            r_type(0, 0, 10, 2, 12, 0x33),
+            // mulh a3, a0, a0
+            muldiv(1, 13, 10, 10),
+            csrrw(0, 0x340, 10),
+            csrrs(14, 0x340, 0),
+            csrrs(15, 0x301, 0),
+            lui(16, 0x80000),
+            auipc(5, 0),
+            sw(10, 5, 0x100),
+            lw(17, 5, 0x100),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X32, &program);
+        cpu.run_until_ebreak(20);
+        let xs = &cpu.state.xs;
+        assert_eq!(
+            [10, 11, 12, 13, 14, 16, 17].map(|i| xs.reg(i)),
+            [
+                0xffff_ffff,
+                0xffff_ffff,
+                1,
+                0,
+                0xffff_ffff,
+                0x8000_0000,
+                0xffff_ffff
+            ]
+        );
+        // MXL 1
+        assert_eq!(xs.reg(15) >> 30, 1);
+    }
+
+    #[test]
+    fn rv32_rejects_rv64_only_encodings() {
+        let rv64_only = [
+            ld(10, 0, 0x100),
+            sd(10, 0, 0x100),
+            // addiw a0, a0, 1
+            i_type(1, 10, 0, 10, 0x1b),
+            // slli a0, a0, 32
+            i_type(32, 10, 1, 10, 0x13),
+            // srai a0, a0, 32
+            i_type(0x420, 10, 5, 10, 0x13),
+        ];
+        for &insn in rv64_only.iter() {
+            let mut cpu = Cpu::with_program(XLen::X32, &[insn, EBREAK]);
+            assert_eq!(
+                cpu.exec(),
+                Err(Trap::Exception(Exception::IllegalInstruction(insn as RegT))),
+                "{:#010x}",
+                insn
+            );
+        }
+    }
+
+    /// Steps until a watchpoint is hit or pc reaches `end`.
+    fn next_watch_hit(cpu: &mut Cpu, end: RegT) -> Option<WatchHit> {
+        while cpu.state.pc != end {
+            if let Some(StopReason::Watchpoint(hit)) = cpu.one_step() {
+                return Some(hit);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn watchpoints_stop_on_matching_accesses() {
+        let program = [
+            auipc(10, 0),
+            addi(11, 0, 42),
+            sw(11, 10, 0x100),
+            lw(12, 10, 0x104),
+            sw(11, 10, 0x104),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.add_watchpoint(DRAM_BASE + 0x100, 4, WatchKind::Write);
+        cpu.add_watchpoint(DRAM_BASE + 0x106, 2, WatchKind::Read);
+        let end = DRAM_BASE + 20;
+
+        let store = WatchHit {
+            pc: DRAM_BASE + 8,
+            addr: DRAM_BASE + 0x100,
+            size: 4,
+            is_write: true,
+            old: Some(0),
+            value: 42,
+        };
+        assert_eq!(next_watch_hit(&mut cpu, end), Some(store));
+        // The lw overlaps the read watchpoint without starting at it.
+        let load = WatchHit {
+            pc: DRAM_BASE + 12,
+            addr: DRAM_BASE + 0x104,
+            is_write: false,
+            old: None,
+            value: 0,
+            ..store
+        };
+        assert_eq!(next_watch_hit(&mut cpu, end), Some(load));
+        // The write to the read watchpoint goes through unnoticed.
+        assert_eq!(next_watch_hit(&mut cpu, end), None);
+        assert_eq!(cpu.state.xs.reg(11), 42);
+    }
+
+    #[test]
+    fn trap_from_machine_mode_records_mpp_machine() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[ECALL, EBREAK]);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 4);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.csrs.mcause(), 11);
+        assert_eq!((cpu.state.csrs.csr(0x300) >> 11) & 3, 3);
+    }
+
+    #[test]
+    fn timer_interrupt_stays_pending_until_mtimecmp_moves() {
+        let mut program = vec![jal(0, 0); 0x40];
+        program.extend(
+            [
+                csrrs(10, 0x344, 0),
+                // a1 = mtimecmp, set to the maximum
+                lui(11, (CLINT_BASE as u32 + 0x4000) >> 12),
+                addi(12, 0, -1),
+                sd(12, 11, 0),
+                csrrs(13, 0x344, 0),
+                EBREAK,
+            ]
+            .iter(),
+        );
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_csr(0x305, DRAM_BASE + 0x100);
+        cpu.state.csrs.set_csr(0x304, 1 << 7);
+        cpu.state.csrs.set_csr(0x300, 1 << 3);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u64>(CLINT_BASE + 0x4000, 5)
+            .unwrap();
+        cpu.run_until_ebreak(100);
+        assert_eq!(cpu.state.csrs.mcause(), 1 << 63 | 7);
+        // Taking the interrupt didn't clear MTIP, the write to mtimecmp did.
+        assert_eq!(cpu.state.xs.reg(10) & 1 << 7, 1 << 7);
+        assert_eq!(cpu.state.xs.reg(13) & 1 << 7, 0);
+    }
+
+    #[test]
+    fn time_reads_the_mtime_of_the_clint() {
+        const MTIME: RegT = CLINT_BASE + 0xbff8;
+        let mut cpu = Cpu::with_program(XLen::X64, &[sd(11, 10, 0), csrrs(12, 0xc01, 0), EBREAK]);
+        cpu.state.xs.set_reg(10, MTIME);
+        cpu.state.xs.set_reg(11, 0x1234_5678_9abc);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.xs.reg(12), 0x1234_5678_9abc);
+
+        // RV32 reads the upper half through timeh.
+        let program = [
+            sw(11, 10, 0),
+            sw(13, 10, 4),
+            csrrs(12, 0xc01, 0),
+            csrrs(14, 0xc81, 0),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X32, &program);
+        cpu.state.xs.set_reg(10, MTIME);
+        cpu.state.xs.set_reg(11, 5);
+        cpu.state.xs.set_reg(13, 1);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.xs.reg(12), 5);
+        assert_eq!(cpu.state.xs.reg(14), 1);
+
+        // time is read-only.
+        let mut cpu = Cpu::with_program(XLen::X64, &[csrrw(0, 0xc01, 10)]);
+        match cpu.one_step() {
+            Some(StopReason::Fatal(report)) => {
+                assert!(matches!(report.exception, Exception::IllegalInstruction(_)))
+            }
+            _ => panic!("writing time didn't fault"),
+        }
+    }
+
+    #[test]
+    fn stimecmp_raises_the_supervisor_timer_interrupt() {
+        const STIMECMP: u32 = 0x14d;
+        let mut program = vec![
+            csrrs(10, 0xc01, 0),
+            addi(10, 10, 20),
+            csrrw(0, STIMECMP, 10),
+            jal(0, 0),
+        ];
+        program.resize(0x40, NOP);
+        // The handler records sip before and after moving stimecmp forward.
+        program.extend(
+            [
+                csrrs(11, 0x144, 0),
+                csrrs(12, 0xc01, 0),
+                addi(12, 12, 1000),
+                csrrw(0, STIMECMP, 12),
+                csrrs(13, 0x144, 0),
+                EBREAK,
+            ]
+            .iter(),
+        );
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.set_tick_batch(1);
+        cpu.state.csrs.set_stimecmp(RegT::MAX);
+        cpu.state.csrs.set_menvcfg(envcfg::STCE);
+        cpu.state.csrs.set_mcounteren(1 << 1);
+        cpu.state.csrs.set_stvec(DRAM_BASE + 0x100);
+        cpu.state.csrs.set_mideleg(mip::STIP);
+        cpu.state.csrs.set_mie(mip::STIP);
+        cpu.state.csrs.set_sstatus(1 << 1);
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.run_until_ebreak(100);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Supervisor);
+        assert_eq!(cpu.state.csrs.scause(), 1 << 63 | 5);
+        assert_eq!(cpu.state.csrs.sepc(), DRAM_BASE + 12);
+        assert!(cpu.state.csrs.time() >= cpu.state.xs.reg(10));
+        // STIP follows stimecmp, it clears as soon as stimecmp moves past time.
+        assert_eq!(cpu.state.xs.reg(11) & mip::STIP, mip::STIP);
+        assert_eq!(cpu.state.xs.reg(13) & mip::STIP, 0);
+    }
+
+    #[test]
+    fn stimecmp_needs_stce_below_machine_mode() {
+        for &(menvcfg, mcounteren, allowed) in [
+            (0, 1 << 1, false),
+            (envcfg::STCE, 0, false),
+            (envcfg::STCE, 1 << 1, true),
+        ]
+        .iter()
+        {
+            let mut cpu = Cpu::with_program(XLen::X64, &[csrrw(0, 0x14d, 10), EBREAK]);
+            cpu.state.csrs.set_mtvec(DRAM_BASE + 4);
+            cpu.state.csrs.set_menvcfg(menvcfg);
+            cpu.state.csrs.set_mcounteren(mcounteren);
+            cpu.state.privilege = PrivilegeMode::Supervisor;
+            cpu.state.xs.set_reg(10, 7);
+            cpu.one_step();
+            let stimecmp = cpu.state.csrs.stimecmp();
+            assert_eq!(stimecmp == 7, allowed, "menvcfg {:#x}", menvcfg);
+            // Without STCE, M-mode keeps STIP in software. With it stimecmp 7 is still ahead.
+            cpu.state.csrs.set_stimecmp(7);
+            cpu.state.csrs.set_mip(mip::STIP);
+            assert_eq!(
+                cpu.state.csrs.csr(0x344) & mip::STIP == mip::STIP,
+                menvcfg == 0
+            );
+        }
+    }
+
+    /// Runs NOPs in `privilege` with SSIP pending and enabled in mie, returns the mode the hart
+    /// ended up in and whether the interrupt was taken. Both trap vectors point at the EBREAK
+    /// after the NOPs.
+    fn software_interrupt(
+        privilege: PrivilegeMode,
+        mideleg: RegT,
+        mstatus: RegT,
+    ) -> (PrivilegeMode, bool) {
+        let mut program = vec![NOP; 0x40];
+        program.push(EBREAK);
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_csr(0x305, DRAM_BASE + 0x100);
+        cpu.state.csrs.set_csr(0x105, DRAM_BASE + 0x100);
+        cpu.state.csrs.set_csr(0x303, mideleg);
+        cpu.state.csrs.set_csr(0x304, 1 << 1);
+        cpu.state.csrs.set_csr(0x344, 1 << 1);
+        cpu.state.csrs.set_csr(0x300, mstatus);
+        cpu.state.privilege = privilege;
+        cpu.run_until_ebreak(100);
+        let taken = cpu.state.csrs.csr(0x342) != 0 || cpu.state.csrs.csr(0x142) != 0;
+        (cpu.state.privilege, taken)
+    }
+
+    #[test]
+    fn interrupts_follow_the_delegation_and_enable_rules() {
+        let (m, s, u) = (
+            PrivilegeMode::Machine,
+            PrivilegeMode::Supervisor,
+            PrivilegeMode::User,
+        );
+        let (sie, mie) = (1 << 1, 1 << 3);
+        // A delegated interrupt is never taken in M-mode.
+        assert_eq!(software_interrupt(m, 1 << 1, mie | sie), (m, false));
+        // An undelegated one is always enabled below M-mode.
+        assert_eq!(software_interrupt(s, 0, 0), (m, true));
+        assert_eq!(software_interrupt(m, 0, 0), (m, false));
+        assert_eq!(software_interrupt(m, 0, mie), (m, true));
+        // A delegated one needs sstatus.SIE in S-mode, but not in U-mode.
+        assert_eq!(software_interrupt(s, 1 << 1, 0), (s, false));
+        assert_eq!(software_interrupt(s, 1 << 1, sie), (s, true));
+        assert_eq!(software_interrupt(u, 1 << 1, 0), (s, true));
+    }
+
+    #[test]
+    fn step_one_stops_at_the_trap_entry() {
+        let mut program = vec![ECALL; 0x41];
+        program[0x40] = EBREAK;
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        assert!(matches!(
+            cpu.step_one(),
+            StepOutcome::Trapped(Exception::MachineEnvCall)
+        ));
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE);
+        assert_eq!(cpu.state.csrs.mcause(), 11);
+        assert!(matches!(
+            cpu.step_one(),
+            StepOutcome::Trapped(Exception::Breakpoint)
+        ));
+    }
+
+    #[test]
+    fn step_one_leaves_pending_interrupts_for_the_next_step() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[addi(5, 0, 1), NOP]);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        cpu.state.csrs.set_mie(mip::MTIP);
+        cpu.state.csrs.set_mstatus(1 << 3);
+        cpu.state.csrs.set_interrupt_line(mip::MTIP, true);
+        assert!(matches!(cpu.step_one(), StepOutcome::Retired));
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        assert_eq!(cpu.state.xs.reg(5), 1);
+        assert_eq!(cpu.state.csrs.mcause(), 0);
+        assert!(cpu.one_step().is_none());
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE + 4);
+        assert_eq!(cpu.state.csrs.mcause(), 1 << 63 | 7);
+    }
+
+    #[test]
+    fn machine_timer_interrupts_are_never_delegated() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[NOP; 0x50]);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        cpu.state.csrs.set_stvec(DRAM_BASE + 0x80);
+        cpu.state.csrs.set_medeleg(RegT::MAX);
+        cpu.state.csrs.set_mideleg(RegT::MAX);
+        cpu.state.csrs.set_mie(mip::MTIP);
+        cpu.state.csrs.set_interrupt_line(mip::MTIP, true);
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        assert!(cpu.one_step().is_none());
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Machine);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.csrs.mcause(), 1 << 63 | 7);
+    }
+
+    /// Takes a supervisor timer interrupt delegated to S-mode from U-mode, after three NOPs, with
+    /// stvec in `mode`. Returns the hart once it reached the EBREAK at the handler.
+    fn delegated_timer_interrupt(mode: RegT, handler: RegT) -> Cpu {
+        let mut program = vec![NOP; 0x50];
+        program[(handler / 4) as usize] = EBREAK;
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_stvec((DRAM_BASE + 0x100) | mode);
+        cpu.state.csrs.set_mideleg(mip::STIP);
+        cpu.state.csrs.set_mie(mip::STIP);
+        // SIE set and SPIE clear, so the stacking shows.
+        cpu.state.csrs.set_sstatus(1 << 1);
+        cpu.state.privilege = PrivilegeMode::User;
+        for _ in 0..3 {
+            cpu.one_step();
+        }
+        cpu.state.csrs.set_mip(mip::STIP);
+        cpu.run_until_ebreak(10);
+        cpu
+    }
+
+    #[test]
+    fn a_delegated_interrupt_from_user_mode_goes_to_the_supervisor_vector() {
+        // Direct jumps to stvec, vectored to stvec + 4 * 5 for the supervisor timer.
+        for &(mode, handler) in [(0, 0x100), (1, 0x114)].iter() {
+            let cpu = delegated_timer_interrupt(mode, handler);
+            let csrs = &cpu.state.csrs;
+            assert_eq!(cpu.state.pc, DRAM_BASE + handler, "mode {}", mode);
+            assert_eq!(cpu.state.privilege, PrivilegeMode::Supervisor);
+            assert_eq!(csrs.scause(), 1 << 63 | 5);
+            assert_eq!(csrs.sepc(), DRAM_BASE + 12);
+            assert_eq!(csrs.stval(), 0);
+            let sstatus = csrs.csr(0x100);
+            // SPP is U, SPIE holds the SIE from before, which is cleared.
+            assert_eq!(sstatus & (1 << 8), 0);
+            assert_eq!(sstatus & (1 << 5), 1 << 5);
+            assert_eq!(sstatus & (1 << 1), 0);
+            // The M-mode trap CSRs stay untouched.
+            assert_eq!(csrs.mcause(), 0);
+            assert_eq!(csrs.mepc(), 0);
+        }
+    }
+
+    #[test]
+    fn instructions_straddling_a_page_translate_each_half() {
+        const CODE: u64 = 0x1000_0000;
+        let mut cpu = Cpu::with_program(XLen::X64, &[EBREAK]);
+        let mut tables = PageTables::new(DRAM_BASE + 0x10_0000);
+        // The frame of the second page lies below the frame of the first one.
+        let (low_frame, high_frame) = (DRAM_BASE + 0x5000, DRAM_BASE + 0x2000);
+        let flags = PTE_V | PTE_X | PTE_A;
+        tables.map(&mut cpu, CODE, low_frame, flags);
+        cpu.state.csrs.set_satp(tables.satp());
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.state.pc = CODE + 0xffe;
+        let fetch = |cpu: &Cpu| {
+            let p_low = cpu.mmu.translate_fetch(&cpu.state, cpu.state.pc, 2)?.p_addr;
+            cpu.read_straddling_code(p_low)
+        };
+
+        // A compressed instruction doesn't touch the unmapped second page.
+        let c_nop = 0x0001;
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u16>(low_frame + 0xffe, c_nop)
+            .unwrap();
+        assert_eq!(fetch(&cpu), Ok(c_nop as u32));
+
+        let word = addi(10, 0, 0x123);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u16>(low_frame + 0xffe, word as u16)
+            .unwrap();
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u16>(high_frame, (word >> 16) as u16)
+            .unwrap();
+        let fault = Exception::InstructionPageFault(CODE + 0x1000);
+        assert_eq!(fetch(&cpu), Err(fault));
+        assert_eq!(fault.tval(), CODE + 0x1000);
+
+        tables.map(&mut cpu, CODE + 0x1000, high_frame, flags);
+        assert_eq!(fetch(&cpu), Ok(word));
+    }
+
+    /// Starts at `program`, past the reset vector which would overwrite t0, a0 and a1.
+    fn builder(program: &[u32]) -> CpuBuilder {
+        let binary = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        CpuBuilder::new(binary)
+            .entry(DRAM_BASE)
+            .console(Box::new(io::sink()))
+    }
+
+    #[test]
+    fn boot_protocol_passes_the_hart_and_device_tree_to_the_entry() {
+        let program = [NOP, addi(5, 0, 1), EBREAK];
+        let mut cpu = builder(&program)
+            .entry(DRAM_BASE + 4)
+            .boot_protocol(0x8700_0000)
+            .build();
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 8);
+        assert_eq!(cpu.state.xs.reg(10), 0);
+        assert_eq!(cpu.state.xs.reg(11), 0x8700_0000);
+        assert_eq!(cpu.state.xs.reg(5), 1);
+    }
+
+    #[test]
+    fn harts_boot_through_the_reset_vector() {
+        const DTB: RegT = 0x8700_0000;
+        for &xlen in [XLen::X32, XLen::X64].iter() {
+            let binary = [addi(5, 0, 1), EBREAK]
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect();
+            let harts = CpuBuilder::new(binary)
+                .xlen(xlen)
+                .boot_protocol(DTB)
+                .console(Box::new(io::sink()))
+                .build_harts(2);
+            for (id, mut cpu) in harts.into_iter().enumerate() {
+                // Overwritten to check the reset vector loads them itself.
+                cpu.state.xs.set_reg(10, 0x55);
+                cpu.state.xs.set_reg(11, 0x55);
+                assert_eq!(cpu.state.pc, ROM_BASE);
+                for _ in 0..5 {
+                    assert!(cpu.one_step().is_none());
+                }
+                assert_eq!(cpu.state.pc, DRAM_BASE, "{:?}", xlen);
+                assert_eq!(cpu.state.xs.reg(10), id as RegT);
+                assert_eq!(cpu.state.xs.reg(11), DTB);
+                cpu.run_until_ebreak(10);
+                assert_eq!(cpu.state.xs.reg(5), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn registers_privilege_and_memory_are_configured() {
+        let program = [sw(6, 5, 0), lw(10, 5, 0), EBREAK];
+        let mut cpu = builder(&program)
+            .xlen(XLen::X32)
+            .dram(0x4000_0000, 4 << 20)
+            .entry(0x4000_0000)
+            .privilege(PrivilegeMode::Supervisor)
+            .reg(5, 0x403f_fff0)
+            .reg(6, 0x1_2345_6789)
+            .build();
+        assert_eq!(cpu.state.pc, 0x4000_0000);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Supervisor);
+        // The stack starts at the end of the DRAM, values are truncated to XLEN.
+        assert_eq!(cpu.state.xs.reg(2), 0x4040_0000);
+        assert_eq!(cpu.state.xs.reg(6), 0x2345_6789);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.xs.reg(10), 0x2345_6789);
+    }
+
+    /// `tests/data/rv32_selftest.S`, a hand-written RV32I program that checks itself like the
+    /// riscv-tests rv32ui-p tests and reports to the `tohost` at `DRAM_BASE + 0x1000`.
+    const RV32_SELFTEST: &[u8] = include_bytes!("../tests/data/rv32_selftest.bin");
+
+    #[test]
+    fn rv32_program_runs_to_a_pass_in_tohost() {
+        let tohost = DRAM_BASE + 0x1000;
+        let mut cpu = CpuBuilder::new(RV32_SELFTEST.to_vec())
+            .xlen(XLen::X32)
+            .build();
+        // From the reset vector in the boot ROM.
+        assert_eq!(cpu.state.pc, ROM_BASE);
+        cpu.add_watchpoint(tohost, 8, WatchKind::Write);
+        let stop = (0..200).find_map(|_| cpu.one_step());
+        assert!(matches!(stop, Some(StopReason::Watchpoint(_))));
+        let result = cpu.mmu.bus.borrow().read::<u32>(tohost).unwrap();
+        assert_eq!(result, 1, "check {} failed", result >> 1);
+    }
+
+    #[test]
+    fn rv32_pc_wraps_at_the_top_of_the_address_space() {
+        let at_top = |insn: u32| {
+            let mut program = vec![NOP; 0x3ff];
+            program.push(insn);
+            let mut cpu = builder(&program)
+                .xlen(XLen::X32)
+                .dram(0xffff_f000, 0x1000)
+                .entry(0xffff_fffc)
+                .build();
+            assert!(cpu.one_step().is_none());
+            cpu
+        };
+        let cpu = at_top(addi(5, 0, 1));
+        assert_eq!(cpu.state.pc, 0);
+        assert_eq!(cpu.state.xs.reg(5), 1);
+        // The target and the return address both wrap.
+        let cpu = at_top(jal(1, 8));
+        assert_eq!(cpu.state.pc, 4);
+        assert_eq!(cpu.state.xs.reg(1), 0);
+    }
+
+    #[test]
+    fn accesses_past_the_configured_memory_fault() {
+        const SRAM: u64 = 0x0800_0000;
+        let dram_end = DRAM_BASE + 16 * 1024 * 1024;
+        let program = [
+            sw(6, 5, -4),
+            lw(10, 5, -4),
+            sw(6, 7, 0x7f8),
+            lw(11, 7, 0x7f8),
+            EBREAK,
+            lw(12, 5, 0),
+            lw(13, 7, 0x7fc),
+        ];
+        let mut cpu = builder(&program)
+            .dram(DRAM_BASE, 16 * 1024 * 1024)
+            .memory(SRAM, 0x1000)
+            .reg(5, dram_end)
+            .reg(6, 0x1234)
+            // The end of the SRAM is in reach of a 12-bit offset from its middle.
+            .reg(7, SRAM + 0x804)
+            .build();
+        assert_eq!(cpu.state.xs.reg(2), dram_end);
+        cpu.run_until_ebreak(10);
+        assert_eq!(
+            (cpu.state.xs.reg(10), cpu.state.xs.reg(11)),
+            (0x1234, 0x1234)
+        );
+        for (pc, addr) in [(DRAM_BASE + 20, dram_end), (DRAM_BASE + 24, SRAM + 0x1000)].iter() {
+            cpu.state.pc = *pc;
+            match cpu.one_step() {
+                Some(StopReason::Fatal(report)) => {
+                    assert_eq!(report.exception, Exception::LoadFault(*addr))
+                }
+                _ => panic!("the load past the end of the memory at {:#x} was taken", pc),
+            }
+        }
+    }
+
+    #[test]
+    fn harts_share_the_configuration_but_not_their_ids() {
+        let harts = builder(&[EBREAK]).boot_protocol(0x100).build_harts(2);
+        for (id, hart) in harts.iter().enumerate() {
+            assert_eq!(hart.mmu.hart(), id);
+            assert_eq!(hart.state.csrs.csr(0xf14), id as RegT);
+            assert_eq!(hart.state.xs.reg(10), id as RegT);
+            assert_eq!(hart.state.xs.reg(11), 0x100);
+        }
+    }
+
+    /// The trace of a hart spinning in PAUSE until the timer interrupt at mtime 100, which counts
+    /// up in a0 and sets the timer 100 ahead, five times.
+    fn deterministic_trace() -> Vec<u8> {
+        let mut program = vec![PAUSE, jal(0, -4)];
+        program.resize(0x40, NOP);
+        program.extend([
+            addi(10, 10, 1),
+            addi(5, 0, 5),
+            b_type(20, 5, 10, 0),
+            ld(6, 11, 0),
+            addi(6, 6, 100),
+            sd(6, 11, 0),
+            MRET,
+            EBREAK,
+        ]);
+        let mut cpu = builder(&program)
+            .spin_detection(Some(SpinDetection { nop_run: Some(4) }))
+            .deterministic(true)
+            .build();
+        assert!(cpu.spin_detection.is_none());
+        let trace = SharedOutput::default();
+        cpu.add_hook(Box::new(crate::hook::Tracer::new(trace.clone())));
+        cpu.state.xs.set_reg(11, CLINT_BASE + 0x4000);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        cpu.state.csrs.set_mie(1 << 7);
+        cpu.state.csrs.set_mstatus(1 << 3);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u64>(CLINT_BASE + 0x4000, 100)
+            .unwrap();
+        cpu.run_until_ebreak(10_000);
+        assert_eq!(cpu.state.xs.reg(10), 5);
+        trace.contents()
+    }
+
+    #[test]
+    fn deterministic_runs_trace_the_same() {
+        let first = deterministic_trace();
+        assert_eq!(
+            String::from_utf8(deterministic_trace()),
+            String::from_utf8(first)
+        );
+    }
+
+    #[test]
+    fn traps_and_returns_are_logged_under_the_cpu_target() {
+        let mut program = vec![NOP; 0x41];
+        program[0] = ECALL;
+        program[1] = EBREAK;
+        program[0x40] = MRET;
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        logged();
+
+        assert!(cpu.one_step().is_none());
+        cpu.state.csrs.set_mepc(DRAM_BASE + 4);
+        assert!(cpu.one_step().is_none());
+        let trap = format!(
+            "{} cause 0xb epc 0x80000000 tval 0x0, Machine -> Machine at 0x80000100",
+            Trap::Exception(Exception::MachineEnvCall)
+        );
+        let expected = [trap, "mret to 0x80000004 in Machine".to_string()];
+        let lines: Vec<_> = logged()
+            .into_iter()
+            .filter(|(target, _)| target == "riscv_emulator::cpu")
+            .map(|(_, line)| line)
+            .collect();
+        assert_eq!(lines, expected);
+
+        // Past the program, where the DRAM is all zeros.
+        cpu.state.update_pc(DRAM_BASE + 0x200);
+        let illegal = Err(Trap::Exception(Exception::IllegalInstruction(0)));
+        assert_eq!(cpu.exec(), illegal);
+        let illegal = (
+            "riscv_emulator::cpu".to_string(),
+            "illegal instruction 0x00000000 at 0x80000200".to_string(),
+        );
+        assert!(logged().contains(&illegal));
+    }
+
+    #[test]
+    fn illegal_instructions_report_their_bits_in_xtval() {
+        // mhartid is read-only, so the CSR write rejects itself when it executes.
+        let write_mhartid = csrrw(0, 0xf14, 10);
+        for &(code, tval) in [
+            (0x1234_5677, 0x1234_5677),
+            (0xdead_0001, 0x0001),
+            (write_mhartid, write_mhartid as RegT),
+        ]
+        .iter()
+        {
+            let mut cpu = Cpu::with_program(XLen::X64, &[code, EBREAK]);
+            cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+            cpu.one_step();
+            assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+            assert_eq!(cpu.state.csrs.mcause(), 2);
+            assert_eq!(cpu.state.csrs.mtval(), tval, "{:#010x}", code);
+        }
+
+        // Delegated to S-mode, the bits go to stval.
+        let mut cpu = Cpu::with_program(XLen::X64, &[0x1234_5677, EBREAK]);
+        cpu.state.csrs.set_csr(0x105, DRAM_BASE + 0x100);
+        cpu.state.csrs.set_csr(0x302, 1 << 2);
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.one_step();
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Supervisor);
+        let stval = cpu.state.csrs.csr(0x143);
+        assert_eq!((stval, cpu.state.csrs.mtval()), (0x1234_5677, 0));
+    }
+
+    #[test]
+    fn illegal_instruction_without_a_handler_stops() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[0x1234_5677, EBREAK]);
+        match cpu.one_step() {
+            Some(StopReason::Fatal(report)) => {
+                assert_eq!(report.exception, Exception::IllegalInstruction(0x1234_5677))
+            }
+            _ => panic!("the illegal instruction was taken"),
+        }
+    }
+
+    /// Nothing is mapped there, physically or virtually.
+    const UNMAPPED: RegT = 0x4000_0000;
+
+    #[test]
+    fn fault_of_the_supervisor_trap_vector_goes_to_machine_mode() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[ECALL, EBREAK]);
+        let mut tables = PageTables::new(DRAM_BASE + 0x10_0000);
+        tables.map(&mut cpu, DRAM_BASE, DRAM_BASE, PTE_V | PTE_X | PTE_A);
+        cpu.state.csrs.set_satp(tables.satp());
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        // ECALL from S-mode and instruction page faults are delegated.
+        cpu.state.csrs.set_medeleg(1 << 9 | 1 << 12);
+        cpu.state.csrs.set_stvec(UNMAPPED);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 4);
+
+        cpu.run_until_ebreak(10);
+        // The trap handed to S-mode is left as it was taken.
+        assert_eq!(cpu.state.csrs.scause(), 9);
+        assert_eq!(cpu.state.csrs.sepc(), DRAM_BASE);
+        assert_eq!(cpu.state.csrs.mcause(), 12);
+        assert_eq!(cpu.state.csrs.mepc(), UNMAPPED);
+        assert_eq!(cpu.state.csrs.mtval(), UNMAPPED);
+        assert_eq!((cpu.state.csrs.mstatus().bits() >> 11) & 3, 1);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Machine);
+    }
+
+    #[test]
+    fn fault_of_the_machine_trap_vector_is_a_double_fault() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[ECALL, EBREAK]);
+        cpu.state.csrs.set_mtvec(UNMAPPED);
+        assert!(cpu.one_step().is_none());
+        match cpu.one_step() {
+            Some(StopReason::DoubleFault(fault)) => {
+                assert_eq!(fault.first, Trap::Exception(Exception::MachineEnvCall));
+                assert_eq!(fault.first_epc, DRAM_BASE);
+                assert_eq!(
+                    fault.report.exception,
+                    Exception::InstructionFault(UNMAPPED)
+                );
+                assert_eq!(fault.report.state.pc, UNMAPPED);
+                assert!(fault.to_string().starts_with("double fault"));
+            }
+            _ => panic!("the fault of the trap vector was taken"),
+        }
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE);
+        assert_eq!(cpu.state.csrs.mcause(), 11);
+    }
+
+    #[test]
+    fn fault_of_the_escalated_trap_vector_is_a_double_fault() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[ECALL, EBREAK]);
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.state.csrs.set_medeleg(1 << 9);
+        cpu.state.csrs.set_stvec(UNMAPPED);
+        cpu.state.csrs.set_mtvec(UNMAPPED + 0x1000);
+        let mut stop = None;
+        for _ in 0..3 {
+            stop = cpu.one_step();
+        }
+        match stop {
+            Some(StopReason::DoubleFault(fault)) => {
+                assert_eq!(
+                    fault.first,
+                    Trap::Exception(Exception::InstructionFault(UNMAPPED))
+                );
+                assert_eq!(fault.first_epc, UNMAPPED);
+                assert_eq!(fault.report.state.pc, UNMAPPED + 0x1000);
+            }
+            _ => panic!("no double fault"),
+        }
+        assert_eq!(cpu.state.csrs.sepc(), DRAM_BASE);
+        assert_eq!(cpu.state.csrs.mepc(), UNMAPPED);
+    }
+
+    /// Runs `program` for up to `steps` steps with `detection`, returns the hang it stopped at.
+    fn hang(program: &[u32], detection: HangDetection, steps: usize) -> Option<Hang> {
+        let mut cpu = Cpu::with_program(XLen::X64, program);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        cpu.set_hang_detection(Some(detection));
+        for _ in 0..steps {
+            if let Some(StopReason::SuspectedHang(report)) = cpu.one_step() {
+                return Some(report.hang);
+            }
+        }
+        None
+    }
+
+    const NO_HEURISTICS: HangDetection = HangDetection {
+        same_pc: None,
+        low_pc: false,
+        trap_storm: None,
+    };
+
+    #[test]
+    fn a_jump_to_itself_is_a_suspected_hang() {
+        let same_pc = HangDetection {
+            same_pc: Some(10),
+            ..NO_HEURISTICS
+        };
+        assert_eq!(hang(&[jal(0, 0)], same_pc, 20), Some(Hang::SamePc(10)));
+        // Counting up in a loop isn't stuck, and neither is waiting in WFI.
+        let count = [addi(10, 10, 1), jal(0, -4)];
+        assert_eq!(hang(&count, same_pc, 100), None);
+        assert_eq!(hang(&[WFI], same_pc, 100), None);
+    }
+
+    #[test]
+    fn a_jump_to_zero_is_a_suspected_hang() {
+        let low_pc = HangDetection {
+            low_pc: true,
+            ..NO_HEURISTICS
+        };
+        // jalr x0, 0(x0)
+        let jump_to_zero = i_type(0, 0, 0, 0, 0x67);
+        assert_eq!(hang(&[NOP, jump_to_zero], low_pc, 10), Some(Hang::LowPc));
+        assert_eq!(hang(&[NOP, jal(0, -4)], low_pc, 10), None);
+    }
+
+    #[test]
+    fn a_faulting_trap_handler_is_a_suspected_hang() {
+        let trap_storm = HangDetection {
+            trap_storm: Some(5),
+            ..NO_HEURISTICS
+        };
+        // The handler at +0x100 hits an illegal instruction right after its first one.
+        let mut program = vec![NOP; 0x41];
+        program[0] = 0;
+        assert_eq!(hang(&program, trap_storm, 20), Some(Hang::TrapStorm(5)));
+        // A handler skipping the illegal instruction returns before the next one.
+        let mut program = vec![NOP; 0x44];
+        program[..2].copy_from_slice(&[0, jal(0, -4)]);
+        program[0x40..].copy_from_slice(&[
+            csrrs(5, 0x341, 0),
+            addi(5, 5, 4),
+            csrrw(0, 0x341, 5),
+            MRET,
+        ]);
+        assert_eq!(hang(&program, trap_storm, 100), None);
+    }
+
+    #[test]
+    fn decoded_words_are_reused_until_fence_i() {
+        const FENCE_I: u32 = 0x0000_100f;
+        let program = [NOP, NOP, NOP, FENCE_I, NOP, EBREAK];
+        let stats = |capacity| {
+            let mut cpu = builder(&program).decode_cache(capacity).build();
+            cpu.run_until_ebreak(10);
+            cpu.decode_stats()
+        };
+        // Every address is fetched once, so each word gets to the decoder. The NOP after
+        // FENCE.I is decoded again.
+        assert_eq!(stats(16), DecodeStats { hits: 2, misses: 3 });
+        assert_eq!(stats(0), DecodeStats { hits: 0, misses: 5 });
+    }
+
+    /// Runs blocks until pc reaches `end`, returning the steps of every block.
+    fn run_blocks_to(cpu: &mut Cpu, end: RegT) -> Vec<u64> {
+        let mut blocks = Vec::new();
+        while cpu.state.pc != end {
+            let (steps, stop) = cpu.run_block(100);
+            assert!(stop.is_none(), "stopped at {:#x}", cpu.state.pc);
+            blocks.push(steps);
+            assert!(blocks.len() < 100, "pc never reached {:#x}", end);
+        }
+        blocks
+    }
+
+    /// Spins at its address, the end of the test programs run as blocks.
+    const SPIN: u32 = jal(0, 0);
+
+    #[test]
+    fn blocks_end_at_jumps_and_within_the_budget() {
+        let program = [
+            addi(10, 10, 1),
+            addi(11, 11, 2),
+            jal(0, 8),
+            NOP,
+            addi(12, 0, 3),
+            SPIN,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        assert_eq!(run_blocks_to(&mut cpu, DRAM_BASE + 20), [3, 2]);
+        let regs = (1..=3).map(|i| cpu.state.xs.reg(9 + i)).collect::<Vec<_>>();
+        assert_eq!(regs, [1, 2, 3]);
+
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        assert_eq!(cpu.run_block(2).0, 2);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 8);
+        // The cached block is entered at its first instruction only.
+        cpu.state.pc = DRAM_BASE;
+        assert_eq!(cpu.run_block(100).0, 3);
+
+        let mut cpu = builder(&program).block_cache(false).build();
+        assert_eq!(run_blocks_to(&mut cpu, DRAM_BASE + 20), [1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn a_store_into_the_block_ends_it() {
+        let program = [auipc(5, 0), sw(6, 5, 16), NOP, NOP, addi(10, 10, 1), SPIN];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.xs.set_reg(6, addi(10, 10, 100) as RegT);
+        // The rest of the block is decoded again with the patched instruction.
+        assert_eq!(run_blocks_to(&mut cpu, DRAM_BASE + 20), [2, 4]);
+        assert_eq!(cpu.state.xs.reg(10), 100);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn state_json_has_the_registers_and_trap_csrs() {
+        let program = [
+            addi(10, 0, 0x123),
+            lui(27, 0xabcde),
+            addi(31, 0, -1),
+            ECALL,
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 16);
+        cpu.run_until_ebreak(10);
+        let state = cpu.state_json();
+
+        assert_eq!(state["version"], STATE_JSON_VERSION);
+        assert_eq!(state["xlen"], 64);
+        assert_eq!(state["pc"], "0x80000010");
+        assert_eq!(state["privilege"], "machine");
+        let x = state["x"].as_object().unwrap();
+        assert_eq!(x.len(), 32);
+        assert_eq!(x["zero"], "0x0");
+        assert_eq!(x["a0"], "0x123");
+        assert_eq!(x["s11"], "0xffffffffabcde000");
+        assert_eq!(x["t6"], "0xffffffffffffffff");
+        let csrs = state["csrs"].as_object().unwrap();
+        assert_eq!(csrs.len(), STATE_JSON_CSRS.len());
+        assert_eq!(csrs["mcause"], "0xb");
+        assert_eq!(csrs["mepc"], "0x8000000c");
+        assert_eq!(csrs["satp"], "0x0");
+        assert!(csrs.contains_key("sstatus"));
+    }
 }
@@ -1,59 +1,882 @@
-use crate::trap::Exception;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, Read, Write},
+};
+
+use crate::{
+    mmu::{AccessContext, PAGE_SIZE},
+    trap::Exception,
+};
 
 use super::{
-    clint::Clint, memory::Memory, plic::Plic, uart::Uart, virtio::Virtio, Data, Device, CLINT_BASE,
-    CLINT_END, DRAM_BASE, DRAM_END, DRAM_SIZE, PLIC_BASE, PLIC_END, UART_BASE, UART_END,
-    VIRTIO_BASE, VIRTIO_END,
+    clint::Clint,
+    memory::{Memory, PageIndex},
+    plic::Plic,
+    syscon::{PowerLine, Syscon},
+    uart::{Uart, UartInput, UART1_IRQ, UART_IRQ},
+    virtio::{Virtio, VIRTIO_IRQ},
+    Data, Device, CLINT_BASE, CLINT_SIZE, DRAM_BASE, DRAM_SIZE, PLIC_BASE, PLIC_SIZE, SYSCON_BASE,
+    SYSCON_SIZE, UART1_BASE, UART_BASE, UART_SIZE, VIRTIO_BASE, VIRTIO_SIZE,
 };
 
+/// The device an address window is routed to. The built-in devices the cpu talks to directly are
+/// kept as typed fields, everything else is registered through `Bus::register_device`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Target {
+    Memory,
+    Clint,
+    Plic,
+    Virtio,
+    Device(usize),
+}
+
+/// An address window `[base, base + len)` routed to a device.
+#[derive(Clone, Copy, Debug)]
+struct Mapping {
+    base: u64,
+    len: u64,
+    target: Target,
+    /// The interrupt request raised by the device, if any.
+    irq: Option<u64>,
+}
+
+/// What the bus tracks for each hart sharing it.
+struct Hart {
+    /// DRAM pages holding instructions cached by the hart, one bit per page.
+    code_pages: Vec<u64>,
+    /// Set when a write hits a page in `code_pages`, the cached instructions may be stale.
+    code_written: bool,
+    /// Set when a write hits the CLINT or the PLIC, the interrupt lines they drive may have
+    /// changed.
+    lines_changed: bool,
+    /// The doubleword reserved by LR, dropped by any store to it.
+    reservation: Option<u64>,
+}
+
 pub struct Bus {
     memory: Memory,
+    dram_base: u64,
+    dram_size: u64,
     pub clint: Clint,
     pub plic: Plic,
-    pub uart: Uart,
     pub virtio: Virtio,
-}
-
-impl Device for Bus {
-    fn read<T>(&self, addr: u64) -> Result<T, Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        match addr {
-            CLINT_BASE..=CLINT_END => self.clint.read::<T>(addr),
-            PLIC_BASE..=PLIC_END => self.plic.read::<T>(addr),
-            UART_BASE..=UART_END => self.uart.read::<T>(addr),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio.read::<T>(addr),
-            DRAM_BASE..=DRAM_END => self.memory.read::<T>(addr),
-            _ => Err(Exception::LoadFault),
-        }
-    }
-
-    fn write<T>(&mut self, addr: u64, value: T) -> Result<(), Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        match addr {
-            CLINT_BASE..=CLINT_END => self.clint.write::<T>(addr, value),
-            PLIC_BASE..=PLIC_END => self.plic.write::<T>(addr, value),
-            UART_BASE..=UART_END => self.uart.write::<T>(addr, value),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio.write::<T>(addr, value),
-            DRAM_BASE..=DRAM_END => self.memory.write::<T>(addr, value),
-            _ => Err(Exception::StoreFault),
-        }
-    }
+    /// Input of the UART, nothing is received until it is fed.
+    pub uart_input: UartInput,
+    /// Input of the second UART at `UART1_BASE`.
+    pub uart1_input: UartInput,
+    /// Power requests written to the syscon.
+    pub power: PowerLine,
+    devices: Vec<Box<dyn Device>>,
+    /// Non-overlapping mappings sorted by base address.
+    mappings: Vec<Mapping>,
+    /// Indexed by hart id.
+    harts: Vec<Hart>,
+    /// Set while any hart holds a reservation, so stores only look for them when needed.
+    reserved: bool,
+    /// MMIO accesses per mapping base, counted after `count_mmio`.
+    mmio_accesses: Option<RefCell<HashMap<u64, u64>>>,
 }
 
 impl Bus {
     pub fn new(binary: Vec<u8>) -> Self {
-        Self {
-            memory: Memory::new_with_binary(DRAM_BASE, binary, DRAM_SIZE),
+        Self::with_dram(
+            binary,
+            DRAM_BASE,
+            DRAM_SIZE,
+            Box::new(io::stdout()),
+            Box::new(io::sink()),
+        )
+    }
+
+    /// Creates a bus with `dram_size` bytes of DRAM at `dram_base`, starting with `binary`. The
+    /// UART writes the console output to `console`, the second UART writes to `serial`.
+    pub fn with_dram(
+        binary: Vec<u8>,
+        dram_base: u64,
+        dram_size: usize,
+        console: Box<dyn Write>,
+        serial: Box<dyn Write>,
+    ) -> Self {
+        assert!(
+            binary.len() <= dram_size,
+            "the binary doesn't fit in {} bytes of DRAM",
+            dram_size
+        );
+        let uart = Uart::with_output(console);
+        let uart1 = Uart::with_output(serial);
+        let mut bus = Self {
+            memory: Memory::new_with_binary(binary, dram_size),
+            dram_base,
+            dram_size: dram_size as u64,
             clint: Clint::new(),
             plic: Plic::new(),
-            uart: Uart::new(),
             virtio: Virtio::new(),
+            uart_input: uart.input(),
+            uart1_input: uart1.input(),
+            power: PowerLine::default(),
+            devices: Vec::new(),
+            mappings: Vec::new(),
+            harts: Vec::new(),
+            reserved: false,
+            mmio_accesses: None,
+        };
+        bus.map(CLINT_BASE, CLINT_SIZE, Target::Clint, None);
+        bus.map(PLIC_BASE, PLIC_SIZE, Target::Plic, None);
+        bus.map(VIRTIO_BASE, VIRTIO_SIZE, Target::Virtio, Some(VIRTIO_IRQ));
+        bus.map(dram_base, dram_size as u64, Target::Memory, None);
+        bus.register_device(UART_BASE, UART_SIZE, Some(UART_IRQ), Box::new(uart));
+        bus.register_device(UART1_BASE, UART_SIZE, Some(UART1_IRQ), Box::new(uart1));
+        // User mode emulation puts DRAM over the low addresses, the guest has no syscon there.
+        if dram_base >= SYSCON_BASE + SYSCON_SIZE || dram_base + dram_size as u64 <= SYSCON_BASE {
+            let syscon = Syscon::new(bus.power.clone());
+            bus.register_device(SYSCON_BASE, SYSCON_SIZE, None, Box::new(syscon));
+        }
+        bus
+    }
+
+    /// Returns the address the DRAM starts at.
+    pub fn dram_base(&self) -> u64 {
+        self.dram_base
+    }
+
+    /// Returns the address right after the end of the DRAM.
+    pub fn dram_end(&self) -> u64 {
+        self.dram_base + self.dram_size
+    }
+
+    /// Adds a hart sharing the bus and returns its hart id. The CLINT and the PLIC get the
+    /// registers and contexts of the new hart.
+    pub fn add_hart(&mut self) -> usize {
+        self.harts.push(Hart {
+            code_pages: vec![0; self.dram_size.div_ceil(PAGE_SIZE * 64) as usize],
+            code_written: false,
+            lines_changed: false,
+            reservation: None,
+        });
+        self.clint.add_hart();
+        self.plic.add_hart();
+        self.harts.len() - 1
+    }
+
+    /// Maps `device` at `[base, base + len)`. If `irq` is set, the device is polled for
+    /// interrupts and its interrupts are routed to that PLIC source.
+    pub fn register_device(
+        &mut self,
+        base: u64,
+        len: u64,
+        irq: Option<u64>,
+        device: Box<dyn Device>,
+    ) {
+        self.devices.push(device);
+        let target = Target::Device(self.devices.len() - 1);
+        self.map(base, len, target, irq);
+    }
+
+    /// Maps `size` bytes of zeroed memory at `base` besides the DRAM, e.g. a small SRAM. Unlike
+    /// the DRAM's, the instructions fetched from it aren't cached.
+    pub fn add_memory(&mut self, base: u64, size: usize) {
+        self.register_device(base, size as u64, None, Box::new(Memory::new(size)));
+    }
+
+    pub fn read<T: Data>(&self, addr: u64) -> Result<T, Exception> {
+        self.read_mapped(addr, None)
+    }
+
+    /// Reads for the guest access `ctx` at its physical address, the device sees `ctx` too. A
+    /// fault is raised at the virtual address.
+    pub fn read_access<T: Data>(&self, ctx: &AccessContext) -> Result<T, Exception> {
+        self.read_mapped(ctx.p_addr, Some(ctx))
+            .map_err(|e| e.at(ctx.v_addr))
+    }
+
+    #[inline]
+    fn read_mapped<T: Data>(&self, addr: u64, ctx: Option<&AccessContext>) -> Result<T, Exception> {
+        // Nearly every access goes to the DRAM, so skip the mapping lookup for it.
+        let offset = addr.wrapping_sub(self.dram_base);
+        if offset < self.dram_size {
+            let value = self.memory.read(offset, T::SIZE).map_err(|e| e.at(addr))?;
+            return Ok(T::from_u64(value));
+        }
+        let mapping = self.find(addr, T::SIZE).ok_or(Exception::LoadFault(addr))?;
+        self.count_mmio_access(&mapping);
+        let device = self.device(mapping.target);
+        let offset = addr - mapping.base;
+        let value = match ctx {
+            Some(ctx) => device.read_access(ctx, offset, T::SIZE),
+            None => device.read(offset, T::SIZE),
+        }
+        .map_err(|e| e.at(addr))?;
+        Ok(T::from_u64(value))
+    }
+
+    pub fn write<T: Data>(&mut self, addr: u64, value: T) -> Result<(), Exception> {
+        self.write_mapped(addr, value, None)
+    }
+
+    /// Writes for the guest access `ctx` at its physical address, the device sees `ctx` too. A
+    /// fault is raised at the virtual address.
+    pub fn write_access<T: Data>(
+        &mut self,
+        ctx: &AccessContext,
+        value: T,
+    ) -> Result<(), Exception> {
+        self.write_mapped(ctx.p_addr, value, Some(ctx))
+            .map_err(|e| e.at(ctx.v_addr))
+    }
+
+    #[inline]
+    fn write_mapped<T: Data>(
+        &mut self,
+        addr: u64,
+        value: T,
+        ctx: Option<&AccessContext>,
+    ) -> Result<(), Exception> {
+        let offset = addr.wrapping_sub(self.dram_base);
+        if offset < self.dram_size {
+            self.memory
+                .write(offset, T::SIZE, value.to_u64())
+                .map_err(|e| e.at(addr))?;
+            self.dram_written(offset, T::SIZE as u64);
+            return Ok(());
+        }
+        let mapping = self
+            .find(addr, T::SIZE)
+            .ok_or(Exception::StoreFault(addr))?;
+        self.count_mmio_access(&mapping);
+        if matches!(mapping.target, Target::Clint | Target::Plic) {
+            self.notify_lines_changed();
+        }
+        // LR may have reserved memory added with `add_memory`.
+        if self.reserved {
+            self.invalidate_reservations(addr, T::SIZE as u64);
         }
+        let device = self.device_mut(mapping.target);
+        let offset = addr - mapping.base;
+        match ctx {
+            Some(ctx) => device.write_access(ctx, offset, T::SIZE, value.to_u64()),
+            None => device.write(offset, T::SIZE, value.to_u64()),
+        }
+        .map_err(|e| e.at(addr))
+    }
+
+    /// Reads `buf.len()` bytes at the physical address `addr`, copying the DRAM at once and going
+    /// through the devices a byte at a time elsewhere. On a fault the bytes before the faulting
+    /// address are already read.
+    pub fn read_phys_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = addr.wrapping_add(done as u64);
+            let len = self.dram_run(addr, buf.len() - done);
+            if len == 0 {
+                buf[done] = self.read::<u8>(addr)?;
+                done += 1;
+                continue;
+            }
+            let offset = addr - self.dram_base;
+            self.memory
+                .read_bytes(offset as usize, &mut buf[done..done + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at the physical address `addr` like `read_phys_bytes` reads. On a fault the
+    /// bytes before the faulting address are already written.
+    pub fn write_phys_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < data.len() {
+            let addr = addr.wrapping_add(done as u64);
+            let len = self.dram_run(addr, data.len() - done);
+            if len == 0 {
+                self.write::<u8>(addr, data[done])?;
+                done += 1;
+                continue;
+            }
+            let offset = addr - self.dram_base;
+            self.dram_written(offset, len as u64);
+            self.memory
+                .write_bytes(offset as usize, &data[done..done + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Returns how many of the `len` bytes at `addr` lie in the DRAM, 0 if `addr` doesn't.
+    fn dram_run(&self, addr: u64, len: usize) -> usize {
+        let offset = addr.wrapping_sub(self.dram_base);
+        if offset >= self.dram_size {
+            return 0;
+        }
+        (self.dram_size - offset).min(len as u64) as usize
+    }
+
+    /// Notes a write of `len` bytes at `offset` into the DRAM: the instructions cached from the
+    /// pages written may be stale and the reservations on them are dropped.
+    #[inline]
+    fn dram_written(&mut self, offset: u64, len: u64) {
+        let first = (offset / PAGE_SIZE) as usize;
+        let last = ((offset + len - 1) / PAGE_SIZE) as usize;
+        for hart in self.harts.iter_mut() {
+            for page in first..=last {
+                if (hart.code_pages[page / 64] >> (page % 64)) & 1 != 0 {
+                    hart.code_written = true;
+                }
+            }
+        }
+        if self.reserved {
+            let addr = self.dram_base + offset;
+            self.invalidate_reservations(addr, len);
+        }
+    }
+
+    /// Starts tracking the DRAM pages written, see `Memory::track_dirty`.
+    pub fn track_dirty_pages(&mut self) {
+        self.memory.track_dirty();
+    }
+
+    /// Returns true once `track_dirty_pages` was called.
+    pub fn tracks_dirty_pages(&self) -> bool {
+        self.memory.tracks_dirty()
+    }
+
+    /// Returns the DRAM pages written since the last call and starts over, see
+    /// `Memory::take_dirty`.
+    pub fn take_dirty_pages(&mut self) -> Vec<PageIndex> {
+        self.memory.take_dirty()
+    }
+
+    /// Returns the base, length and interrupt request of every address window, sorted by base.
+    pub fn windows(&self) -> Vec<(u64, u64, Option<u64>)> {
+        self.mappings
+            .iter()
+            .map(|mapping| (mapping.base, mapping.len, mapping.irq))
+            .collect()
+    }
+
+    /// Starts counting the accesses to every device, see `mmio_accesses`.
+    pub fn count_mmio(&mut self) {
+        if self.mmio_accesses.is_none() {
+            self.mmio_accesses = Some(RefCell::new(HashMap::new()));
+        }
+    }
+
+    fn count_mmio_access(&self, mapping: &Mapping) {
+        if let Some(accesses) = &self.mmio_accesses {
+            *accesses.borrow_mut().entry(mapping.base).or_insert(0) += 1;
+        }
+    }
+
+    /// Returns the accesses per device since `count_mmio`. Devices registered through
+    /// `register_device`, like the UART, are named after their base address.
+    pub fn mmio_accesses(&self) -> Vec<(String, u64)> {
+        let accesses = match &self.mmio_accesses {
+            Some(accesses) => accesses.borrow(),
+            None => return Vec::new(),
+        };
+        self.mappings
+            .iter()
+            .filter_map(|mapping| {
+                let count = *accesses.get(&mapping.base)?;
+                let name = match mapping.target {
+                    Target::Memory => "dram".to_string(),
+                    Target::Clint => "clint".to_string(),
+                    Target::Plic => "plic".to_string(),
+                    Target::Virtio => "virtio".to_string(),
+                    Target::Device(_) => format!("{:#x}", mapping.base),
+                };
+                Some((name, count))
+            })
+            .collect()
+    }
+
+    /// Marks the DRAM page containing `addr` as holding instructions cached by `hart`. Returns
+    /// false if `addr` isn't in the DRAM, writes to it can't be tracked so its instructions
+    /// mustn't be cached.
+    pub fn mark_code_page(&mut self, hart: usize, addr: u64) -> bool {
+        let offset = addr.wrapping_sub(self.dram_base);
+        if offset >= self.dram_size {
+            return false;
+        }
+        let page = (offset / PAGE_SIZE) as usize;
+        self.harts[hart].code_pages[page / 64] |= 1 << (page % 64);
+        true
+    }
+
+    /// Forgets all the pages marked by `mark_code_page` for `hart`.
+    pub fn clear_code_pages(&mut self, hart: usize) {
+        let hart = &mut self.harts[hart];
+        hart.code_pages.iter_mut().for_each(|bits| *bits = 0);
+        hart.code_written = false;
+    }
+
+    /// Returns true if a page marked by `mark_code_page` for `hart` was written since.
+    pub fn code_written(&self, hart: usize) -> bool {
+        self.harts[hart].code_written
+    }
+
+    /// Returns true if the interrupt lines of `hart` may have changed since
+    /// `clear_lines_changed`.
+    pub fn lines_changed(&self, hart: usize) -> bool {
+        self.harts[hart].lines_changed
+    }
+
+    pub fn clear_lines_changed(&mut self, hart: usize) {
+        self.harts[hart].lines_changed = false;
+    }
+
+    /// Makes every hart refresh its interrupt lines, e.g. after the PLIC latched an interrupt.
+    pub fn notify_lines_changed(&mut self) {
+        self.harts
+            .iter_mut()
+            .for_each(|hart| hart.lines_changed = true);
+    }
+
+    /// Registers a reservation of `hart` on the doubleword containing `addr`, for LR.
+    pub fn reserve(&mut self, hart: usize, addr: u64) {
+        self.harts[hart].reservation = Some(addr & !0x7);
+        self.reserved = true;
+    }
+
+    /// Drops the reservation of `hart` and returns true if it covered `addr`, for SC.
+    pub fn take_reservation(&mut self, hart: usize, addr: u64) -> bool {
+        self.harts[hart].reservation.take() == Some(addr & !0x7)
+    }
+
+    /// Drops the reservations on the doublewords overlapping the `len` bytes at `addr`, which are
+    /// being stored to.
+    #[cold]
+    fn invalidate_reservations(&mut self, addr: u64, len: u64) {
+        let (start, end) = (addr & !0x7, addr + len);
+        for hart in self.harts.iter_mut() {
+            if matches!(hart.reservation, Some(r) if r >= start && r < end) {
+                hart.reservation = None;
+            }
+        }
+        self.reserved = self.harts.iter().any(|hart| hart.reservation.is_some());
+    }
+
+    /// Writes the state of every device to a snapshot.
+    pub fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.memory.save(w)?;
+        self.save_devices(w)
+    }
+
+    /// Writes the state of every device like `save`, but only the given `pages` of the DRAM.
+    pub fn save_pages(&self, w: &mut dyn Write, pages: &[PageIndex]) -> io::Result<()> {
+        self.memory.save_pages(w, pages)?;
+        self.save_devices(w)
+    }
+
+    fn save_devices(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.clint.save(w)?;
+        self.plic.save(w)?;
+        self.virtio.save(w)?;
+        self.devices.iter().try_for_each(|device| device.save(w))
+    }
+
+    /// Restores the state written by `save`. The same devices must be registered.
+    pub fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.memory.restore(r)?;
+        self.restore_devices(r)
+    }
+
+    /// Restores the state written by `save_pages` over the DRAM of the snapshot it's based on.
+    pub fn restore_pages(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.memory.restore_pages(r)?;
+        self.restore_devices(r)
+    }
+
+    fn restore_devices(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.clint.restore(r)?;
+        self.plic.restore(r)?;
+        self.virtio.restore(r)?;
+        for device in self.devices.iter_mut() {
+            device.restore(r)?;
+        }
+        for hart in 0..self.harts.len() {
+            self.clear_code_pages(hart);
+            self.harts[hart].reservation = None;
+        }
+        self.reserved = false;
+        Ok(())
+    }
+
+    /// Lets the devices act on what the guest asked them to do, e.g. the virtio disk serves the
+    /// requests of a notify. Called after every step.
+    pub fn poll_devices(&mut self) {
+        Virtio::process_queue(self);
+    }
+
+    /// Polls every device mapped with an IRQ and makes its pending bit in the PLIC follow its
+    /// interrupt line. Returns true if any bit changed.
+    pub fn update_irq_lines(&mut self) -> bool {
+        let mut changed = false;
+        for idx in 0..self.mappings.len() {
+            let mapping = self.mappings[idx];
+            let irq = match mapping.irq {
+                Some(irq) => irq,
+                None => continue,
+            };
+            let level = self.device_mut(mapping.target).is_interrupting();
+            changed |= self.plic.set_level(irq, level);
+        }
+        changed
+    }
+
+    fn device(&self, target: Target) -> &dyn Device {
+        match target {
+            Target::Memory => &self.memory,
+            Target::Clint => &self.clint,
+            Target::Plic => &self.plic,
+            Target::Virtio => &self.virtio,
+            Target::Device(idx) => self.devices[idx].as_ref(),
+        }
+    }
+
+    fn device_mut(&mut self, target: Target) -> &mut dyn Device {
+        match target {
+            Target::Memory => &mut self.memory,
+            Target::Clint => &mut self.clint,
+            Target::Plic => &mut self.plic,
+            Target::Virtio => &mut self.virtio,
+            Target::Device(idx) => self.devices[idx].as_mut(),
+        }
+    }
+
+    /// Routes `[base, base + len)` to `target`. Panics if the window overlaps an existing one, or
+    /// if `irq` isn't a PLIC source or is another mapping's already.
+    fn map(&mut self, base: u64, len: u64, target: Target, irq: Option<u64>) {
+        if let Some(irq) = irq {
+            assert!(
+                Plic::is_source(irq),
+                "IRQ {} of {:?} at {:#x} isn't a PLIC source",
+                irq,
+                target,
+                base
+            );
+            if let Some(other) = self.mappings.iter().find(|m| m.irq == Some(irq)) {
+                panic!(
+                    "{:?} at {:#x} shares IRQ {} with {:?} at {:#x}",
+                    target, base, irq, other.target, other.base
+                );
+            }
+        }
+        let idx = self.mappings.partition_point(|m| m.base < base);
+        if let Some(prev) = idx.checked_sub(1).map(|i| &self.mappings[i]) {
+            assert!(
+                prev.base + prev.len <= base,
+                "{:?} at {:#x} overlaps {:?} at {:#x}",
+                target,
+                base,
+                prev.target,
+                prev.base
+            );
+        }
+        if let Some(next) = self.mappings.get(idx) {
+            assert!(
+                base + len <= next.base,
+                "{:?} at {:#x} overlaps {:?} at {:#x}",
+                target,
+                base,
+                next.target,
+                next.base
+            );
+        }
+        let mapping = Mapping {
+            base,
+            len,
+            target,
+            irq,
+        };
+        self.mappings.insert(idx, mapping);
+    }
+
+    /// Finds the mapping an access of `size` bytes at `addr` is routed to. Accesses spanning a
+    /// window boundary don't belong to any mapping.
+    fn find(&self, addr: u64, size: usize) -> Option<Mapping> {
+        let idx = self.mappings.partition_point(|m| m.base <= addr);
+        let mapping = self.mappings[idx.checked_sub(1)?];
+        let end = (addr - mapping.base).checked_add(size as u64)?;
+        if end <= mapping.len {
+            Some(mapping)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        cpu::{Cpu, CpuBuilder},
+        device::{ROM_BASE, ROM_SIZE},
+        testing::*,
+        XLen,
+    };
+
+    /// The offset, size and value of every write a `Probe` received.
+    type Writes = Rc<RefCell<Vec<(u64, usize, u64)>>>;
+
+    /// Reads back `tag` above the offset and records the writes.
+    struct Probe {
+        tag: u64,
+        writes: Writes,
+    }
+
+    impl Device for Probe {
+        fn read(&self, offset: u64, _size: usize) -> Result<u64, Exception> {
+            Ok(self.tag << 32 | offset)
+        }
+
+        fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+            self.writes.borrow_mut().push((offset, size, value));
+            Ok(())
+        }
+    }
+
+    fn probe(tag: u64) -> (Box<Probe>, Writes) {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let probe = Probe {
+            tag,
+            writes: writes.clone(),
+        };
+        (Box::new(probe), writes)
+    }
+
+    #[test]
+    fn dram_accesses_stop_at_its_end() {
+        let mut bus = Bus::new(Vec::new());
+        // The pages a hart caches code from are tracked up to the end.
+        bus.add_hart();
+        let end = DRAM_BASE + DRAM_SIZE as u64;
+        bus.write::<u32>(end - 4, 0x1234_5678).unwrap();
+        assert_eq!(bus.read::<u32>(end - 4), Ok(0x1234_5678));
+        assert_eq!(bus.read::<u8>(end - 1), Ok(0x12));
+        assert_eq!(bus.read::<u64>(end - 8), Ok(0x1234_5678 << 32));
+        // Nor may an access run past the end, or start there or below the start.
+        assert_eq!(bus.read::<u64>(end - 4), Err(Exception::LoadFault(end - 4)));
+        assert_eq!(bus.read::<u32>(end - 2), Err(Exception::LoadFault(end - 2)));
+        assert_eq!(
+            bus.write::<u64>(end - 4, 0),
+            Err(Exception::StoreFault(end - 4))
+        );
+        assert_eq!(bus.read::<u8>(end), Err(Exception::LoadFault(end)));
+        assert_eq!(bus.write::<u8>(end, 0), Err(Exception::StoreFault(end)));
+        assert_eq!(
+            bus.read::<u8>(DRAM_BASE - 1),
+            Err(Exception::LoadFault(DRAM_BASE - 1))
+        );
+        assert_eq!(bus.read::<u32>(end - 4), Ok(0x1234_5678));
+    }
+
+    #[test]
+    fn bulk_copies_go_through_devices_and_stop_at_a_fault() {
+        const BASE: u64 = 0x2000_0000;
+        let mut bus = Bus::new(Vec::new());
+        let (device, writes) = probe(1);
+        bus.register_device(BASE, 0x4, None, device);
+        bus.add_hart();
+        // The instructions of the second page are cached.
+        assert!(bus.mark_code_page(0, DRAM_BASE + PAGE_SIZE));
+
+        let data: Vec<u8> = (1..=8).collect();
+        bus.write_phys_bytes(DRAM_BASE + PAGE_SIZE - 4, &data)
+            .unwrap();
+        assert!(bus.code_written(0));
+        let mut buf = [0; 8];
+        bus.read_phys_bytes(DRAM_BASE + PAGE_SIZE - 4, &mut buf)
+            .unwrap();
+        assert_eq!(buf, &data[..]);
+
+        // The device sees byte accesses, the byte past its window faults.
+        assert_eq!(
+            bus.write_phys_bytes(BASE + 2, &[0xaa, 0xbb, 0xcc]),
+            Err(Exception::StoreFault(BASE + 4))
+        );
+        assert_eq!(*writes.borrow(), [(2, 1, 0xaa), (3, 1, 0xbb)]);
+        let mut buf = [0; 3];
+        assert_eq!(
+            bus.read_phys_bytes(BASE + 2, &mut buf),
+            Err(Exception::LoadFault(BASE + 4))
+        );
+        assert_eq!(buf, [2, 3, 0]);
+
+        // The part in the DRAM is copied before the end faults.
+        let end = DRAM_BASE + DRAM_SIZE as u64;
+        let mut buf = [0xff; 8];
+        assert_eq!(
+            bus.read_phys_bytes(end - 4, &mut buf),
+            Err(Exception::LoadFault(end))
+        );
+        assert_eq!(buf, [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn accesses_crossing_the_end_of_a_window_fault() {
+        let cpu = CpuBuilder::new(Vec::new()).build();
+        let mut bus = cpu.mmu.bus.borrow_mut();
+        let windows = [
+            (ROM_BASE, ROM_SIZE),
+            (SYSCON_BASE, SYSCON_SIZE),
+            (CLINT_BASE, CLINT_SIZE),
+            (PLIC_BASE, PLIC_SIZE),
+            (UART_BASE, UART_SIZE),
+            (UART1_BASE, UART_SIZE),
+            (VIRTIO_BASE, VIRTIO_SIZE),
+            (DRAM_BASE, DRAM_SIZE as u64),
+        ];
+        for (base, size) in windows {
+            let end = base + size;
+            // Right below the window is either nothing or the window before it.
+            match bus.find(base - 1, 1) {
+                Some(prev) => assert_eq!(prev.base + prev.len, base, "below {:#x}", base),
+                None => assert_eq!(
+                    bus.read::<u8>(base - 1),
+                    Err(Exception::LoadFault(base - 1))
+                ),
+            }
+            // Both ends go to the window, the device decides what its registers do.
+            let window = |addr| bus.find(addr, 1).map(|m| m.base);
+            assert_eq!(window(base), Some(base));
+            assert_eq!(window(end - 1), Some(base));
+            assert_eq!(bus.read::<u16>(end - 1), Err(Exception::LoadFault(end - 1)));
+            assert_eq!(
+                bus.write::<u16>(end - 1, 0),
+                Err(Exception::StoreFault(end - 1))
+            );
+            assert_eq!(bus.read::<u64>(end - 4), Err(Exception::LoadFault(end - 4)));
+        }
+        // The second UART follows the first one directly.
+        assert_eq!(bus.find(UART1_BASE - 1, 1).unwrap().base, UART_BASE);
+        assert_eq!(bus.read::<u8>(0), Err(Exception::LoadFault(0)));
+    }
+
+    #[test]
+    fn accesses_go_to_the_window_they_fall_in() {
+        const BASE: u64 = 0x4000_0000;
+        let mut bus = Bus::new(Vec::new());
+        // Registered out of order, right next to each other.
+        let (high, high_writes) = probe(2);
+        let (low, low_writes) = probe(1);
+        bus.register_device(BASE + 0x100, 0x100, None, high);
+        bus.register_device(BASE, 0x100, None, low);
+
+        assert_eq!(bus.read::<u64>(BASE), Ok(1 << 32));
+        assert_eq!(bus.read::<u64>(BASE + 0xf8), Ok(1 << 32 | 0xf8));
+        assert_eq!(bus.read::<u64>(BASE + 0x100), Ok(2 << 32));
+        assert_eq!(bus.read::<u32>(BASE + 0x1fc), Ok(0x1fc - 0x100));
+        bus.write::<u16>(BASE + 0x1fe, 0xabcd).unwrap();
+        assert_eq!(*high_writes.borrow(), [(0xfe, 2, 0xabcd)]);
+
+        // Accesses crossing the end of a window, or outside of every window, fault.
+        assert_eq!(
+            bus.read::<u64>(BASE + 0xfc),
+            Err(Exception::LoadFault(BASE + 0xfc))
+        );
+        assert_eq!(
+            bus.read::<u16>(BASE + 0x1ff),
+            Err(Exception::LoadFault(BASE + 0x1ff))
+        );
+        assert_eq!(
+            bus.read::<u8>(BASE + 0x200),
+            Err(Exception::LoadFault(BASE + 0x200))
+        );
+        assert_eq!(
+            bus.read::<u8>(BASE - 1),
+            Err(Exception::LoadFault(BASE - 1))
+        );
+        assert_eq!(
+            bus.write::<u64>(BASE + 0xfc, 0),
+            Err(Exception::StoreFault(BASE + 0xfc))
+        );
+        assert!(low_writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn guest_reaches_a_registered_device() {
+        let (probe, writes) = probe(3);
+        let program = [lui(11, 0x40000), sw(10, 11, 8), ld(12, 11, 0x10), EBREAK];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .register_device(0x4000_0000, 0x100, None, probe);
+        cpu.state.xs.set_reg(10, 0x55);
+        cpu.run_until_ebreak(10);
+        assert_eq!(*writes.borrow(), [(8, 4, 0x55)]);
+        assert_eq!(cpu.state.xs.reg(12), 3 << 32 | 0x10);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlapping_windows_are_rejected() {
+        let mut bus = Bus::new(Vec::new());
+        bus.register_device(UART_BASE + UART_SIZE - 8, 0x10, None, probe(1).0);
+    }
+
+    #[test]
+    #[should_panic(expected = "shares IRQ")]
+    fn devices_sharing_an_irq_are_rejected() {
+        let mut bus = Bus::new(Vec::new());
+        bus.register_device(0x4000_0000, 0x100, Some(UART_IRQ), probe(1).0);
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a PLIC source")]
+    fn irq_0_is_rejected() {
+        let mut bus = Bus::new(Vec::new());
+        bus.register_device(0x4000_0000, 0x100, Some(0), probe(1).0);
+    }
+
+    #[test]
+    fn every_pending_irq_reaches_the_plic() {
+        const SCLAIM: u64 = PLIC_BASE + 0x20_1004;
+        let mut bus = Bus::new(Vec::new());
+        bus.add_hart();
+        // Enable both UARTs for the S-mode context of hart 0.
+        let irqs = 1 << UART_IRQ | 1 << UART1_IRQ;
+        bus.write::<u32>(PLIC_BASE + 0x2080, irqs).unwrap();
+        assert!(!bus.update_irq_lines());
+
+        assert!(bus.uart_input.try_receive(b'a'));
+        assert!(bus.uart1_input.try_receive(b'b'));
+        assert!(bus.update_irq_lines());
+        assert_eq!(bus.read::<u32>(PLIC_BASE + 0x1000), Ok(irqs));
+        assert!(bus.plic.is_interrupting_supervisor(0));
+        // Both are claimed in turn, the lower ID first.
+        assert_eq!(bus.read::<u32>(SCLAIM), Ok(UART_IRQ as u32));
+        bus.write::<u32>(SCLAIM, UART_IRQ as u32).unwrap();
+        assert_eq!(bus.read::<u32>(SCLAIM), Ok(UART1_IRQ as u32));
+        bus.write::<u32>(SCLAIM, UART1_IRQ as u32).unwrap();
+        assert_eq!(bus.read::<u32>(SCLAIM), Ok(0));
+        assert!(!bus.plic.is_interrupting_supervisor(0));
+    }
+
+    #[test]
+    fn second_uart_has_its_own_output_and_input() {
+        let console = SharedOutput::default();
+        let serial = SharedOutput::default();
+        let mut bus = Bus::with_dram(
+            Vec::new(),
+            DRAM_BASE,
+            0x1000,
+            Box::new(console.clone()),
+            Box::new(serial.clone()),
+        );
+        for byte in b"ok\n" {
+            bus.write::<u8>(UART1_BASE, *byte).unwrap();
+        }
+        bus.write::<u8>(UART_BASE, b'\n').unwrap();
+        assert_eq!(serial.contents(), b"ok\n");
+        assert_eq!(console.contents(), b"\n");
+
+        assert!(bus.uart1_input.try_receive(b'x'));
+        assert_eq!(bus.read::<u8>(UART_BASE + 5).unwrap() & 1, 0);
+        assert_eq!(bus.read::<u8>(UART1_BASE + 5).unwrap() & 1, 1);
+        assert_eq!(bus.read::<u8>(UART1_BASE), Ok(b'x'));
     }
 }
@@ -1,86 +1,90 @@
-use std::convert::TryInto;
+use std::{
+    cell::Cell,
+    io::{self, Read, Write},
+    rc::Rc,
+};
 
-use crate::{cpu::CpuStatus, trap::Exception};
+use crate::{cpu::CpuStatus, register::mip, snapshot, trap::Exception};
 
-use super::{Data, Device, CLINT_BASE};
+use super::{read_reg, write_reg, Device};
 
-/// The address that a msip register starts. A msip is a machine mode software interrupt pending
-/// register, used to assert a software interrupt for a CPU.
-const MSIP: u64 = CLINT_BASE;
-/// The address that a msip register ends. `msip` is a 4-byte register.
-const MSIP_END: u64 = MSIP + 0x4;
+/// The address that the msip registers start. A msip is a machine mode software interrupt pending
+/// register, used to assert a software interrupt for a CPU. Each hart has a 4-byte `msip`.
+const MSIP: u64 = 0;
+const MSIP_SIZE: u64 = 0x4;
 
-/// The address that a mtimecmp register starts. A mtimecmp is a memory mapped machine mode timer
+/// The address that the mtimecmp registers start. A mtimecmp is a memory mapped machine mode timer
 /// compare register, used to trigger an interrupt when mtimecmp is greater than or equal to mtime.
-const MTIMECMP: u64 = CLINT_BASE + 0x4000;
-/// The address that a mtimecmp register ends. `mtimecmp` is a 8-byte register.
-const MTIMECMP_END: u64 = MTIMECMP + 0x8;
+/// Each hart has an 8-byte `mtimecmp`.
+const MTIMECMP: u64 = 0x4000;
+const MTIMECMP_SIZE: u64 = 0x8;
 
 /// The address that a timer register starts. A mtime is a machine mode timer register which runs
 /// at a constant frequency.
-const MTIME: u64 = CLINT_BASE + 0xbff8;
+const MTIME: u64 = 0xbff8;
 /// The address that a timer register ends. `mtime` is a 8-byte register.
 const MTIME_END: u64 = MTIME + 0x8;
 
+/// mtime, shared by the CLINT with the time CSR of every hart so that both read the same clock.
+pub type Timebase = Rc<Cell<u64>>;
+
+/// A register of the CLINT, along with the hart it belongs to.
+#[derive(Clone, Copy)]
+enum Reg {
+    Msip(usize),
+    Mtimecmp(usize),
+    Mtime,
+}
+
 /// The core-local interruptor (CLINT).
 pub struct Clint {
-    /// Machine mode software interrupt pending register, used to assert a software interrupt for
-    /// a CPU.
-    msip: u32,
-    /// Memory mapped machine mode timer compare register, used to trigger an interrupt when
+    /// Machine mode software interrupt pending registers, used to assert a software interrupt for
+    /// a CPU. Indexed by hart id.
+    msip: Vec<u32>,
+    /// Memory mapped machine mode timer compare registers, used to trigger an interrupt when
     /// mtimecmp is greater than or equal to mtime. There is an mtimecmp dedicated to each CPU.
-    mtimecmp: u64,
+    mtimecmp: Vec<u64>,
     /// Machine mode timer register which runs at a constant frequency.
-    mtime: u64,
+    mtime: Timebase,
 }
 impl Device for Clint {
-    fn read<T>(&self, addr: u64) -> Result<T, Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        // `reg` is the value of a target register in CLINT and `offset` is the byte of the start
-        // position in the register.
-        let (reg, offset) = match addr {
-            MSIP..=MSIP_END => (self.msip as u64, addr - MSIP),
-            MTIMECMP..=MTIMECMP_END => (self.mtimecmp, addr - MTIMECMP),
-            MTIME..=MTIME_END => (self.mtime, addr - MTIME),
-            _ => return Err(Exception::LoadFault),
-        };
-        let bytes = (reg >> (offset * 8)).to_le_bytes();
-        let bytes: [u8; T::SIZE] = bytes[bytes.len() - T::SIZE..]
-            .try_into()
-            .map_err(|_| Exception::LoadFault)?;
-        Ok(T::from_bytes(bytes))
+    fn read(&self, offset: u64, size: usize) -> Result<u64, Exception> {
+        // `reg_offset` is the byte of the start position in the register.
+        let (reg, reg_offset) = self
+            .locate(offset, size)
+            .ok_or(Exception::LoadFault(offset))?;
+        Ok(read_reg(self.reg(reg), reg_offset, size))
     }
 
-    fn write<T>(&mut self, addr: u64, value: T) -> Result<(), Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        // `reg` is the value of a target register in CLINT and `offset` is the byte of the start
-        // position in the register.
-        let (reg, offset) = match addr {
-            MSIP..=MSIP_END => (self.msip as u64, addr - MSIP),
-            MTIMECMP..=MTIMECMP_END => (self.mtimecmp, addr - MTIMECMP),
-            MTIME..=MTIME_END => (self.mtime, addr - MTIME),
-            _ => return Err(Exception::StoreFault),
-        };
-        let bytes = value.to_bytes();
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        // `reg_offset` is the byte of the start position in the register.
+        let (reg, reg_offset) = self
+            .locate(offset, size)
+            .ok_or(Exception::StoreFault(offset))?;
         // Store the new value to the target register.
-        let mut origin_bytes = reg.to_le_bytes();
-        for (idx, bit) in bytes.iter().enumerate() {
-            origin_bytes[offset as usize + idx] = *bit;
+        let value = write_reg(self.reg(reg), reg_offset, size, value);
+        match reg {
+            Reg::Msip(hart) => self.msip[hart] = value as u32,
+            Reg::Mtimecmp(hart) => self.mtimecmp[hart] = value,
+            Reg::Mtime => self.mtime.set(value),
         }
-        let reg = u64::from_le_bytes(origin_bytes);
+        Ok(())
+    }
 
-        match addr {
-            MSIP..=MSIP_END => self.msip = reg as u32,
-            MTIMECMP..=MTIMECMP_END => self.mtimecmp = reg,
-            MTIME..=MTIME_END => self.mtime = reg,
-            _ => return Err(Exception::StoreFault),
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        for (msip, mtimecmp) in self.msip.iter().zip(&self.mtimecmp) {
+            snapshot::write_u64(w, *msip as u64)?;
+            snapshot::write_u64(w, *mtimecmp)?;
         }
+        snapshot::write_u64(w, self.mtime.get())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for (msip, mtimecmp) in self.msip.iter_mut().zip(self.mtimecmp.iter_mut()) {
+            *msip = snapshot::read_u64(r)? as u32;
+            *mtimecmp = snapshot::read_u64(r)?;
+        }
+        self.mtime.set(snapshot::read_u64(r)?);
         Ok(())
     }
 }
@@ -88,35 +92,77 @@ impl Device for Clint {
 impl Clint {
     pub fn new() -> Self {
         Self {
-            msip: 0,
-            mtime: 0,
-            mtimecmp: 0,
+            msip: Vec::new(),
+            mtime: Timebase::default(),
+            mtimecmp: Vec::new(),
         }
     }
-    /// Increment the mtimer register. It's not a real-time value. The MTIP bit (MIP, 7) is enabled
-    /// when `mtime` is greater than or equal to `mtimecmp`.
-    pub fn increment(&mut self, state: &mut CpuStatus) {
-        self.mtime = self.mtime.wrapping_add(1);
-        let mut mip = state.csrs.mip();
-        if (self.msip & 1) != 0 {
-            // Enable the MSIP bit (MIP, 3).
-            mip.set_msoft(true);
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime.get()
+    }
+
+    /// Returns mtime for the time CSR of a hart to read, see `Csrs::set_timebase`.
+    pub fn timebase(&self) -> Timebase {
+        self.mtime.clone()
+    }
+
+    /// Adds the msip and mtimecmp registers of the next hart.
+    pub fn add_hart(&mut self) {
+        self.msip.push(0);
+        self.mtimecmp.push(0);
+    }
+
+    /// Finds the register an access of `size` bytes at `offset` hits and the offset of the access
+    /// in it. Accesses spanning two registers hit none.
+    fn locate(&self, offset: u64, size: usize) -> Option<(Reg, u64)> {
+        let harts = self.msip.len() as u64;
+        let (reg, start, end) = if (MSIP..MSIP + MSIP_SIZE * harts).contains(&offset) {
+            let hart = (offset - MSIP) / MSIP_SIZE;
+            let start = MSIP + hart * MSIP_SIZE;
+            (Reg::Msip(hart as usize), start, start + MSIP_SIZE)
+        } else if (MTIMECMP..MTIMECMP + MTIMECMP_SIZE * harts).contains(&offset) {
+            let hart = (offset - MTIMECMP) / MTIMECMP_SIZE;
+            let start = MTIMECMP + hart * MTIMECMP_SIZE;
+            (Reg::Mtimecmp(hart as usize), start, start + MTIMECMP_SIZE)
+        } else if (MTIME..MTIME_END).contains(&offset) {
+            (Reg::Mtime, MTIME, MTIME_END)
+        } else {
+            return None;
+        };
+        if offset + size as u64 > end {
+            return None;
         }
+        Some((reg, offset - start))
+    }
 
-        // 3.1.10 Machine Timer Registers (mtime and mtimecmp)
-        // "The interrupt remains posted until mtimecmp becomes greater than mtime (typically as a
-        // result of writing mtimecmp)."
-        if self.mtimecmp > self.mtime {
-            // Clear the MTIP bit (MIP, 7).
-            mip.set_mtimer(false);
+    fn reg(&self, reg: Reg) -> u64 {
+        match reg {
+            Reg::Msip(hart) => self.msip[hart] as u64,
+            Reg::Mtimecmp(hart) => self.mtimecmp[hart],
+            Reg::Mtime => self.mtime.get(),
         }
+    }
+
+    /// Increment the mtimer register by `ticks`. It's not a real-time value.
+    pub fn increment(&mut self, ticks: u64) {
+        self.mtime.set(self.mtime.get().wrapping_add(ticks));
+    }
+
+    /// Drives the MSIP bit (MIP, 3) of `hart` from its `msip` and the MTIP bit (MIP, 7) from its
+    /// `mtimecmp`, and the STIP bit (MIP, 5) from stimecmp if the hart enabled Sstc. All are
+    /// levels, a trap doesn't clear them.
+    pub fn update_pending(&self, hart: usize, state: &mut CpuStatus) {
+        state
+            .csrs
+            .set_interrupt_line(mip::MSIP, self.msip[hart] & 1 != 0);
         // 3.1.10 Machine Timer Registers (mtime and mtimecmp)
         // "A timer interrupt becomes pending whenever mtime contains a value greater than or equal
-        // to mtimecmp, treating the values as unsigned integers."
-        if self.mtime >= self.mtimecmp {
-            // Enable the MTIP bit (MIP, 7).
-            mip.set_mtimer(true);
-        }
-        state.csrs.set_mip(mip.bits());
+        // to mtimecmp, treating the values as unsigned integers. The interrupt remains posted
+        // until mtimecmp becomes greater than mtime (typically as a result of writing mtimecmp)."
+        state
+            .csrs
+            .set_interrupt_line(mip::MTIP, self.mtime.get() >= self.mtimecmp[hart]);
+        state.csrs.update_stimer();
     }
 }
@@ -0,0 +1,316 @@
+//! A linear 32-bpp framebuffer for bare-metal programs that draw graphics. The window starts with
+//! a page of control registers, followed by the pixels, a row of `width` little-endian x8r8g8b8
+//! words after another. The guest fills the pixels like memory and presents a frame by writing
+//! `PRESENT`, the host may present one on its own schedule through a `Screen`.
+
+use std::{
+    cell::RefCell,
+    convert::TryInto,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+    rc::Rc,
+};
+
+use log::warn;
+
+use crate::{snapshot, trap::Exception};
+
+use super::{Device, FRAMEBUFFER_BASE};
+
+/// The width in pixels, read-only.
+pub const FB_WIDTH: u64 = 0x00;
+/// The height in pixels, read-only.
+pub const FB_HEIGHT: u64 = 0x04;
+/// The bytes from the start of a row to the start of the next, read-only.
+pub const FB_STRIDE: u64 = 0x08;
+/// Frames are only presented while bit 0 is set.
+pub const FB_ENABLE: u64 = 0x0c;
+/// The number of frames presented so far, read-only.
+pub const FB_VSYNC: u64 = 0x10;
+/// Any write presents the current frame.
+pub const FB_PRESENT: u64 = 0x14;
+/// The offset of the first pixel in the window.
+pub const FB_PIXELS: u64 = 0x1000;
+
+/// Shows the frames the guest presents.
+pub trait FrameSink {
+    /// Called with the pixels of a frame, `height` rows of `width` x8r8g8b8 words.
+    fn frame(&mut self, width: u32, height: u32, pixels: &[u8]) -> io::Result<()>;
+}
+
+/// Writes every frame to a binary PPM file of its own, `frame-00000.ppm` onwards, in a directory.
+/// Works without a display, e.g. in CI.
+pub struct PpmDumper {
+    dir: PathBuf,
+    frames: u64,
+}
+
+impl PpmDumper {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            frames: 0,
+        }
+    }
+
+    /// The file frame `n` is written to.
+    pub fn path(&self, n: u64) -> PathBuf {
+        self.dir.join(format!("frame-{:05}.ppm", n))
+    }
+}
+
+impl FrameSink for PpmDumper {
+    fn frame(&mut self, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(self.path(self.frames))?);
+        write!(out, "P6\n{} {}\n255\n", width, height)?;
+        for pixel in pixels.chunks_exact(4) {
+            // x8r8g8b8 in little-endian order is b, g, r, x.
+            out.write_all(&[pixel[2], pixel[1], pixel[0]])?;
+        }
+        out.flush()?;
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+/// The registers and the pixels, shared with the `Screen`.
+struct FramebufferState {
+    width: u32,
+    height: u32,
+    enabled: bool,
+    vsync: u32,
+    pixels: Vec<u8>,
+    sink: Box<dyn FrameSink>,
+}
+
+impl FramebufferState {
+    /// Hands the current frame to the sink if the framebuffer is enabled.
+    fn present(&mut self) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.vsync = self.vsync.wrapping_add(1);
+        self.sink.frame(self.width, self.height, &self.pixels)
+    }
+}
+
+pub struct Framebuffer {
+    state: Rc<RefCell<FramebufferState>>,
+}
+
+impl Framebuffer {
+    /// Creates a disabled `width` x `height` framebuffer whose frames go to `sink`.
+    pub fn new(width: u32, height: u32, sink: Box<dyn FrameSink>) -> Self {
+        let state = FramebufferState {
+            width,
+            height,
+            enabled: false,
+            vsync: 0,
+            pixels: vec![0; width as usize * height as usize * 4],
+            sink,
+        };
+        Self {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// The size of the window: the control registers and the pixels, rounded up to a page.
+    pub fn size(&self) -> u64 {
+        let pixels = self.state.borrow().pixels.len() as u64;
+        FB_PIXELS + pixels.div_ceil(0x1000) * 0x1000
+    }
+
+    /// Returns the host side handle of the framebuffer.
+    pub fn screen(&self) -> Screen {
+        Screen {
+            state: self.state.clone(),
+        }
+    }
+
+    /// The `simple-framebuffer` node describing the framebuffer, mapped at `FRAMEBUFFER_BASE` by
+    /// `CpuBuilder::framebuffer`, for the device tree the guest is booted with.
+    pub fn device_tree_node(&self) -> String {
+        let state = self.state.borrow();
+        let addr = FRAMEBUFFER_BASE + FB_PIXELS;
+        format!(
+            "framebuffer@{:x} {{\n\
+             \tcompatible = \"simple-framebuffer\";\n\
+             \treg = <0x{:x} 0x{:x} 0x{:x} 0x{:x}>;\n\
+             \twidth = <{}>;\n\
+             \theight = <{}>;\n\
+             \tstride = <{}>;\n\
+             \tformat = \"x8r8g8b8\";\n\
+             }};\n",
+            addr,
+            addr >> 32,
+            addr & 0xffff_ffff,
+            state.pixels.len() as u64 >> 32,
+            state.pixels.len() as u64 & 0xffff_ffff,
+            state.width,
+            state.height,
+            state.width * 4
+        )
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&self, offset: u64, size: usize) -> Result<u64, Exception> {
+        let state = self.state.borrow();
+        if offset < FB_PIXELS {
+            if size != 4 {
+                return Err(Exception::LoadFault(offset));
+            }
+            return Ok(match offset {
+                FB_WIDTH => state.width as u64,
+                FB_HEIGHT => state.height as u64,
+                FB_STRIDE => state.width as u64 * 4,
+                FB_ENABLE => state.enabled as u64,
+                FB_VSYNC => state.vsync as u64,
+                _ => 0,
+            });
+        }
+        let start = (offset - FB_PIXELS) as usize;
+        let mut bytes = [0; 8];
+        // The rest of the last page reads as zeros.
+        if let Some(pixels) = state.pixels.get(start..start + size) {
+            bytes[..size].copy_from_slice(pixels);
+        }
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        let mut state = self.state.borrow_mut();
+        if offset < FB_PIXELS {
+            if size != 4 {
+                return Err(Exception::StoreFault(offset));
+            }
+            match offset {
+                FB_ENABLE => state.enabled = value & 1 == 1,
+                FB_PRESENT => {
+                    if let Err(e) = state.present() {
+                        warn!("framebuffer: failed to present a frame: {}", e);
+                    }
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+        let start = (offset - FB_PIXELS) as usize;
+        if let Some(pixels) = state.pixels.get_mut(start..start + size) {
+            pixels.copy_from_slice(&value.to_le_bytes()[..size]);
+        }
+        Ok(())
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        let state = self.state.borrow();
+        snapshot::write_u64(w, state.enabled as u64)?;
+        snapshot::write_u64(w, state.vsync as u64)?;
+        snapshot::write_sparse(w, &state.pixels)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.enabled = snapshot::read_u64(r)? != 0;
+        state.vsync = snapshot::read_u64(r)? as u32;
+        snapshot::read_sparse(r, &mut state.pixels)
+    }
+}
+
+/// The host side of a `Framebuffer`, e.g. for the run loop to present a frame every so many
+/// instructions.
+pub struct Screen {
+    state: Rc<RefCell<FramebufferState>>,
+}
+
+impl Screen {
+    /// Presents the current frame, unless the guest hasn't enabled the framebuffer.
+    pub fn present(&self) -> io::Result<()> {
+        self.state.borrow_mut().present()
+    }
+
+    /// Returns the x8r8g8b8 value of the pixel at `x`, `y`.
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        let state = self.state.borrow();
+        let start = (y as usize * state.width as usize + x as usize) * 4;
+        u32::from_le_bytes(state.pixels[start..start + 4].try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{cpu::CpuBuilder, device::DRAM_BASE, testing::*};
+
+    const BASE: u64 = FRAMEBUFFER_BASE;
+
+    fn add(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        r_type(0, rs2, rs1, 0, rd, 0x33)
+    }
+
+    #[test]
+    fn guest_draws_a_gradient_into_the_dumped_frame() {
+        const WIDTH: u32 = 8;
+        const HEIGHT: u32 = 2;
+        let dir = std::env::temp_dir().join(format!("framebuffer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let framebuffer = Framebuffer::new(WIDTH, HEIGHT, Box::new(PpmDumper::new(&dir)));
+        let screen = framebuffer.screen();
+        // Pixel i is i * 0x010203, one word after another, then enable and present.
+        let program = [
+            lui(5, (BASE >> 12) as u32),
+            addi(6, 0, 1),
+            sw(6, 5, FB_ENABLE as i32),
+            lui(7, 0x1),
+            add(7, 7, 5),
+            addi(28, 7, (WIDTH * HEIGHT * 4) as i32),
+            addi(29, 0, 0),
+            lui(30, 0x10),
+            addi(30, 30, 0x203),
+            sw(29, 7, 0),
+            add(29, 29, 30),
+            addi(7, 7, 4),
+            b_type(-12, 28, 7, 1),
+            sw(0, 5, FB_PRESENT as i32),
+            lw(10, 5, FB_VSYNC as i32),
+            EBREAK,
+        ];
+        let binary = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut cpu = CpuBuilder::new(binary)
+            .entry(DRAM_BASE)
+            .console(Box::new(io::sink()))
+            .framebuffer(framebuffer)
+            .build();
+        cpu.run_until_ebreak(200);
+        assert_eq!(cpu.state.xs.reg(10), 1);
+        assert_eq!(screen.pixel(3, 1), 11 * 0x010203);
+
+        let ppm = fs::read(dir.join("frame-00000.ppm")).unwrap();
+        let header = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT);
+        assert!(ppm.starts_with(header.as_bytes()));
+        let rgb = &ppm[header.len()..];
+        assert_eq!(rgb.len(), (WIDTH * HEIGHT * 3) as usize);
+        for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+            let i = i as u8;
+            assert_eq!(pixel, [i, 2 * i, 3 * i], "pixel {}", i);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn frames_are_only_presented_while_enabled() {
+        let framebuffer = Framebuffer::new(1, 1, Box::new(PpmDumper::new("/nonexistent")));
+        let screen = framebuffer.screen();
+        let mut device: Box<dyn Device> = Box::new(framebuffer);
+        assert_eq!(device.read(FB_STRIDE, 4), Ok(4));
+        assert!(screen.present().is_ok());
+        device.write(FB_PRESENT, 4, 0).unwrap();
+        assert_eq!(device.read(FB_VSYNC, 4), Ok(0));
+        device.write(FB_ENABLE, 4, 1).unwrap();
+        assert!(screen.present().is_err());
+        assert_eq!(device.read(FB_VSYNC, 4), Ok(1));
+    }
+}
@@ -1,50 +1,322 @@
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    io::{self, Read, Write},
+};
 
-use crate::trap::Exception;
+use crate::{snapshot, trap::Exception};
 
-use super::{Data, Device};
+use super::Device;
 
+/// Size of the blocks the memory is allocated in, a multiple of `snapshot::CHUNK_SIZE`.
+const BLOCK_SIZE: usize = 2 * 1024 * 1024;
+/// The granularity of the dirty page tracking, a chunk of a snapshot.
+const PAGE_SIZE: usize = snapshot::CHUNK_SIZE;
+
+/// The index of a `PAGE_SIZE` page of a `Memory`, counted from its start.
+pub type PageIndex = usize;
+
+/// Guest RAM, allocated a block at a time on the first write to it. Blocks never written read as
+/// zeros, so a large DRAM only costs the host what the guest touches.
 pub struct Memory {
-    data: Vec<u8>,
-    dram_base: u64,
+    blocks: Vec<Option<Box<[u8]>>>,
+    size: usize,
+    /// One bit per page written since the last `take_dirty`, once `track_dirty` was called.
+    dirty: Option<Vec<u64>>,
 }
 
 impl Device for Memory {
-    fn read<T>(&self, addr: u64) -> Result<T, Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        let start_idx = (addr - self.dram_base) as usize;
-        let v = self.data[start_idx..start_idx + std::mem::size_of::<T>()]
-            .try_into()
-            .map_err(|_| Exception::LoadFault)?;
-        let x = T::from_bytes(v);
-        Ok(x)
-    }
-
-    fn write<T>(&mut self, addr: u64, value: T) -> Result<(), Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        let bytes = value.to_bytes();
-        let start_idx = (addr - self.dram_base) as usize;
-
-        for (idx, bit) in bytes.iter().enumerate() {
-            self.data[start_idx + idx] = *bit;
+    fn read(&self, offset: u64, size: usize) -> Result<u64, Exception> {
+        let start_idx = self
+            .index(offset, size)
+            .ok_or(Exception::LoadFault(offset))?;
+        let mut bytes = [0; 8];
+        let (block, start) = (start_idx / BLOCK_SIZE, start_idx % BLOCK_SIZE);
+        if start + size <= BLOCK_SIZE {
+            if let Some(data) = &self.blocks[block] {
+                bytes[..size].copy_from_slice(&data[start..start + size]);
+            }
+        } else {
+            self.read_bytes(start_idx, &mut bytes[..size]);
+        }
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        let start_idx = self
+            .index(offset, size)
+            .ok_or(Exception::StoreFault(offset))?;
+        self.mark_dirty(start_idx, size);
+        let (block, start) = (start_idx / BLOCK_SIZE, start_idx % BLOCK_SIZE);
+        if start + size <= BLOCK_SIZE {
+            self.block_mut(block)[start..start + size]
+                .copy_from_slice(&value.to_le_bytes()[..size]);
+        } else {
+            self.write_bytes(start_idx, &value.to_le_bytes()[..size]);
         }
         Ok(())
     }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        let blocks = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, data)| Some((idx * BLOCK_SIZE, data.as_deref()?)));
+        snapshot::write_sparse_blocks(w, self.size, blocks)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        // The pages dropped may differ from the last checkpoint as well as the ones written.
+        for block in 0..self.blocks.len() {
+            if self.blocks[block].take().is_some() {
+                let len = (self.size - block * BLOCK_SIZE).min(BLOCK_SIZE);
+                self.mark_dirty(block * BLOCK_SIZE, len);
+            }
+        }
+        let size = self.size;
+        snapshot::read_sparse_chunks(r, size, |offset, chunk| {
+            if chunk.iter().any(|b| *b != 0) {
+                self.write_bytes(offset, chunk);
+            }
+        })
+    }
 }
 
 impl Memory {
-    pub fn new_with_binary(dram_base: u64, binary: Vec<u8>, cap: usize) -> Self {
-        let mut data = vec![0; cap];
-        data.splice(..binary.len(), binary.iter().cloned());
+    /// Creates `size` bytes of zeroed memory.
+    pub fn new(size: usize) -> Self {
         Self {
-            data: data,
-            dram_base: dram_base,
+            blocks: (0..size.div_ceil(BLOCK_SIZE)).map(|_| None).collect(),
+            size,
+            dirty: None,
+        }
+    }
+
+    pub fn new_with_binary(binary: Vec<u8>, cap: usize) -> Self {
+        let mut memory = Self::new(cap);
+        memory.write_bytes(0, &binary);
+        memory
+    }
+
+    /// Copies the bytes at `offset` into `buf`. The bus checks that they are all in the DRAM.
+    pub fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let (block, start) = ((offset + done) / BLOCK_SIZE, (offset + done) % BLOCK_SIZE);
+            let len = (BLOCK_SIZE - start).min(buf.len() - done);
+            let dest = &mut buf[done..done + len];
+            match &self.blocks[block] {
+                Some(data) => dest.copy_from_slice(&data[start..start + len]),
+                None => dest.iter_mut().for_each(|b| *b = 0),
+            }
+            done += len;
+        }
+    }
+
+    /// Copies `data` to `offset`. The bus checks that it fits in the DRAM.
+    pub fn write_bytes(&mut self, offset: usize, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.mark_dirty(offset, data.len());
+        let mut done = 0;
+        while done < data.len() {
+            let (block, start) = ((offset + done) / BLOCK_SIZE, (offset + done) % BLOCK_SIZE);
+            let len = (BLOCK_SIZE - start).min(data.len() - done);
+            self.block_mut(block)[start..start + len].copy_from_slice(&data[done..done + len]);
+            done += len;
+        }
+    }
+
+    /// Starts recording the pages written, for `take_dirty`.
+    pub fn track_dirty(&mut self) {
+        if self.dirty.is_none() {
+            self.dirty = Some(vec![0; self.size.div_ceil(PAGE_SIZE).div_ceil(64)]);
+        }
+    }
+
+    /// Returns true once `track_dirty` was called.
+    pub fn tracks_dirty(&self) -> bool {
+        self.dirty.is_some()
+    }
+
+    /// Returns the pages written since the last call, or since `track_dirty`, in increasing
+    /// order, and starts over.
+    pub fn take_dirty(&mut self) -> Vec<PageIndex> {
+        let mut pages = Vec::new();
+        for (word, bits) in self.dirty.iter_mut().flatten().enumerate() {
+            while *bits != 0 {
+                pages.push(word * 64 + bits.trailing_zeros() as usize);
+                *bits &= *bits - 1;
+            }
         }
+        pages
+    }
+
+    #[inline]
+    fn mark_dirty(&mut self, offset: usize, len: usize) {
+        if let Some(dirty) = &mut self.dirty {
+            for page in offset / PAGE_SIZE..=(offset + len - 1) / PAGE_SIZE {
+                dirty[page / 64] |= 1 << (page % 64);
+            }
+        }
+    }
+
+    /// Writes `pages` to a snapshot, zero pages included. `restore_pages` puts them back over
+    /// the memory of the snapshot they are based on.
+    pub fn save_pages(&self, w: &mut dyn Write, pages: &[PageIndex]) -> io::Result<()> {
+        snapshot::write_chunks(w, self.size, pages, |offset, chunk| {
+            self.read_bytes(offset, chunk)
+        })
+    }
+
+    /// Restores the pages written by `save_pages`, leaving the others as they are.
+    pub fn restore_pages(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let size = self.size;
+        snapshot::read_sparse_chunks(r, size, |offset, chunk| {
+            let block = offset / BLOCK_SIZE;
+            if self.blocks[block].is_some() || chunk.iter().any(|b| *b != 0) {
+                self.write_bytes(offset, chunk);
+            }
+        })
+    }
+
+    /// Returns the block at index `block`, allocating it if it was never written.
+    fn block_mut(&mut self, block: usize) -> &mut [u8] {
+        let len = (self.size - block * BLOCK_SIZE).min(BLOCK_SIZE);
+        self.blocks[block].get_or_insert_with(|| vec![0; len].into_boxed_slice())
+    }
+
+    /// Returns the index into the memory for an access of `size` bytes at `offset`, or None if
+    /// any byte of the access falls outside of it.
+    fn index(&self, offset: u64, size: usize) -> Option<usize> {
+        let end = offset.checked_add(size as u64)?;
+        if end > self.size as u64 || size > 8 {
+            return None;
+        }
+        offset.try_into().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A memory of two and a half blocks, so that the last one is partial.
+    const SIZE: usize = BLOCK_SIZE * 5 / 2;
+
+    fn allocated(memory: &Memory) -> usize {
+        memory.blocks.iter().filter(|data| data.is_some()).count()
+    }
+
+    #[test]
+    fn blocks_are_allocated_on_the_first_write() {
+        let mut memory = Memory::new_with_binary(vec![1, 2, 3], SIZE);
+        assert_eq!(allocated(&memory), 1);
+        assert_eq!(memory.read(0, 4), Ok(0x0003_0201));
+        assert_eq!(memory.read(SIZE as u64 - 8, 8), Ok(0));
+        let mut buf = [0xff; 16];
+        memory.read_bytes(BLOCK_SIZE - 8, &mut buf);
+        assert_eq!(buf, [0; 16]);
+        assert_eq!(allocated(&memory), 1);
+
+        memory.write(SIZE as u64 - 1, 1, 0xaa).unwrap();
+        assert_eq!(allocated(&memory), 2);
+        assert_eq!(
+            memory.blocks[2].as_ref().map(|data| data.len()),
+            Some(BLOCK_SIZE / 2)
+        );
+        assert_eq!(memory.read(SIZE as u64 - 1, 1), Ok(0xaa));
+        assert_eq!(
+            memory.read(SIZE as u64 - 1, 2),
+            Err(Exception::LoadFault(SIZE as u64 - 1))
+        );
+    }
+
+    #[test]
+    fn written_pages_are_tracked_until_taken() {
+        let mut memory = Memory::new(SIZE);
+        memory.write(0x10, 4, 1).unwrap();
+        memory.track_dirty();
+        assert!(memory.take_dirty().is_empty());
+        // A store across two pages, a copy across two blocks and a store at the very end.
+        memory
+            .write(PAGE_SIZE as u64 * 3 - 2, 4, 0x1122_3344)
+            .unwrap();
+        memory.write_bytes(BLOCK_SIZE - 8, &[0; 16]);
+        memory.write(SIZE as u64 - 1, 1, 0xaa).unwrap();
+        let block_pages = BLOCK_SIZE / PAGE_SIZE;
+        let pages = [2, 3, block_pages - 1, block_pages, (SIZE - 1) / PAGE_SIZE];
+        assert_eq!(memory.take_dirty(), pages);
+        assert!(memory.take_dirty().is_empty());
+
+        // Only those pages are saved, the zero ones too, and restored over another memory.
+        let mut saved = Vec::new();
+        memory.save_pages(&mut saved, &pages).unwrap();
+        let mut chunks = Vec::new();
+        snapshot::read_sparse_chunks(&mut &saved[..], SIZE, |offset, _| {
+            chunks.push(offset / PAGE_SIZE)
+        })
+        .unwrap();
+        assert_eq!(chunks, pages);
+        let mut restored = Memory::new(SIZE);
+        restored.write(0x10, 4, 1).unwrap();
+        restored
+            .write(BLOCK_SIZE as u64 - 4, 4, 0xffff_ffff)
+            .unwrap();
+        restored.restore_pages(&mut &saved[..]).unwrap();
+        let (mut buf, mut expected) = (vec![0; SIZE], vec![0; SIZE]);
+        restored.read_bytes(0, &mut buf);
+        memory.read_bytes(0, &mut expected);
+        assert!(buf == expected);
+    }
+
+    #[test]
+    fn behaves_like_flat_memory() {
+        let mut memory = Memory::new(SIZE);
+        let mut flat = vec![0u8; SIZE];
+        // Accesses near the block boundaries, misaligned ones straddling them included.
+        let mut seed = 1u64;
+        let mut next = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            seed >> 33
+        };
+        for _ in 0..2000 {
+            let boundary = BLOCK_SIZE * (1 + next() as usize % 2);
+            let offset = boundary - 16 + next() as usize % 32;
+            let size = 1 << (next() % 4);
+            let value = next() << 32 | next();
+            if next() % 2 == 0 {
+                memory.write(offset as u64, size, value).unwrap();
+                flat[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+            } else {
+                let mut bytes = [0; 8];
+                bytes[..size].copy_from_slice(&flat[offset..offset + size]);
+                assert_eq!(
+                    memory.read(offset as u64, size),
+                    Ok(u64::from_le_bytes(bytes))
+                );
+            }
+        }
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        memory.write_bytes(BLOCK_SIZE / 2, &data);
+        flat[BLOCK_SIZE / 2..BLOCK_SIZE / 2 + data.len()].copy_from_slice(&data);
+        let mut buf = vec![0; SIZE];
+        memory.read_bytes(0, &mut buf);
+        assert!(buf == flat);
+
+        // A snapshot restores the same contents, and only allocates the blocks holding data.
+        let mut snapshot = Vec::new();
+        memory.save(&mut snapshot).unwrap();
+        let mut expected = Vec::new();
+        snapshot::write_sparse(&mut expected, &flat).unwrap();
+        assert!(snapshot == expected);
+        let mut restored = Memory::new(SIZE);
+        restored.restore(&mut &snapshot[..]).unwrap();
+        restored.read_bytes(0, &mut buf);
+        assert!(buf == flat);
+        assert_eq!(allocated(&restored), 3);
     }
 }
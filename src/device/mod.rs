@@ -1,66 +1,138 @@
-use crate::trap::Exception;
+use std::io::{self, Read, Write};
+
+use crate::{mmu::AccessContext, trap::Exception};
 
 // use crate::trap::Exception;
 
 pub mod bus;
 pub mod clint;
+#[cfg(feature = "framebuffer")]
+pub mod framebuffer;
 mod memory;
 pub mod plic;
+pub mod rom;
+pub mod syscon;
 pub mod uart;
 pub mod virtio;
 
+/// The address of the boot ROM holding the reset vector, same as QEMU virt machine.
+pub const ROM_BASE: u64 = 0x1000;
+/// The size of the boot ROM.
+pub const ROM_SIZE: u64 = 0xf000;
+
 /// Default dram base.
 pub const DRAM_BASE: u64 = 0x80000000;
 /// Default dram size (128MiB).
 pub const DRAM_SIZE: usize = 128 * 1024 * 1024;
-/// The address which DRAM ends.
-const DRAM_END: u64 = DRAM_BASE + (DRAM_SIZE as u64);
 
 /// The  start address of CLINT.
 pub const CLINT_BASE: u64 = 0x200_0000;
-/// The address which the core-local interruptor (CLINT) ends.
-const CLINT_END: u64 = CLINT_BASE + 0x10000;
+/// The size of the core-local interruptor (CLINT).
+pub const CLINT_SIZE: u64 = 0x10000;
 
 // The  start address of PLIC.
 pub const PLIC_BASE: u64 = 0xc00_0000;
-/// The address which the platform-level interrupt controller (PLIC) ends.
-const PLIC_END: u64 = PLIC_BASE + 0x208000;
+/// The size of the platform-level interrupt controller (PLIC).
+pub const PLIC_SIZE: u64 = 0x400_0000;
+
+/// The address of the syscon (test finisher), same as QEMU virt machine.
+pub const SYSCON_BASE: u64 = 0x10_0000;
+/// The size of the syscon.
+pub const SYSCON_SIZE: u64 = 0x1000;
 
 /// The address which UART starts, same as QEMU virt machine.
 pub const UART_BASE: u64 = 0x1000_0000;
 /// The size of UART.
 pub const UART_SIZE: u64 = 0x100;
-/// The address which UART ends.
-const UART_END: u64 = UART_BASE + 0x100;
+
+/// The address of the second UART, right after the first.
+pub const UART1_BASE: u64 = 0x1000_0100;
 
 /// The address which virtio starts.
 pub const VIRTIO_BASE: u64 = 0x1000_1000;
-/// The address which virtio ends.
-const VIRTIO_END: u64 = VIRTIO_BASE + 0x1000;
+/// The size of virtio.
+pub const VIRTIO_SIZE: u64 = 0x1000;
+
+/// The address of the framebuffer's control registers, followed by its pixels.
+#[cfg(feature = "framebuffer")]
+pub const FRAMEBUFFER_BASE: u64 = 0x5000_0000;
 
+/// A memory mapped device. Addresses are offsets from the start of the device window, so the same
+/// device can be mapped anywhere on the bus.
+/// An access the device doesn't serve raises the access fault at its offset, the bus moves it to
+/// the physical address.
 pub trait Device {
-    fn read<T>(&self, addr: u64) -> Result<T, Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized;
-
-    fn write<T>(&mut self, addr: u64, value: T) -> Result<(), Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized;
+    /// Reads `size` bytes at `offset`.
+    fn read(&self, offset: u64, size: usize) -> Result<u64, Exception>;
+
+    /// Writes the low `size` bytes of `value` at `offset`.
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception>;
+
+    /// Reads like `read` for the guest access `ctx`, for devices that care which address the
+    /// guest used, e.g. a debug device.
+    fn read_access(
+        &self,
+        _ctx: &AccessContext,
+        offset: u64,
+        size: usize,
+    ) -> Result<u64, Exception> {
+        self.read(offset, size)
+    }
+
+    /// Writes like `write` for the guest access `ctx`.
+    fn write_access(
+        &mut self,
+        _ctx: &AccessContext,
+        offset: u64,
+        size: usize,
+        value: u64,
+    ) -> Result<(), Exception> {
+        self.write(offset, size, value)
+    }
+
+    /// Returns the level of the device's interrupt line, true while it has an interrupt the
+    /// driver hasn't served, e.g. a UART with received bytes to read. The PLIC pending bit of the
+    /// device follows it, see `Bus::update_irq_lines`.
+    fn is_interrupting(&mut self) -> bool {
+        false
+    }
+
+    /// Writes the guest visible state of the device to a snapshot.
+    fn save(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Restores the state written by `save`. Host side resources stay attached.
+    fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns a mask covering the low `size` bytes.
+fn size_mask(size: usize) -> u64 {
+    if size >= 8 {
+        u64::MAX
+    } else {
+        (1 << (size * 8)) - 1
+    }
+}
+
+/// Reads `size` bytes at byte `offset` of a little endian register.
+fn read_reg(reg: u64, offset: u64, size: usize) -> u64 {
+    (reg >> (offset * 8)) & size_mask(size)
+}
+
+/// Merges the low `size` bytes of `value` into a little endian register at byte `offset`.
+fn write_reg(reg: u64, offset: u64, size: usize, value: u64) -> u64 {
+    let mask = size_mask(size) << (offset * 8);
+    (reg & !mask) | ((value << (offset * 8)) & mask)
 }
 
-pub trait Data {
+/// A value that can be loaded from or stored to the bus. Values travel through devices as the low
+/// `SIZE` bytes of a `u64`.
+pub trait Data: Copy {
     const SIZE: usize;
-    fn from_bytes(bytes: [u8; Self::SIZE]) -> Self;
-    fn to_bytes(self) -> [u8; Self::SIZE];
-    fn from_u8(v: u8) -> Self;
-    fn from_u16(v: u16) -> Self;
-    fn from_u32(v: u32) -> Self;
     fn from_u64(v: u64) -> Self;
-    fn to_u8(self) -> u8;
-    fn to_u16(self) -> u16;
-    fn to_u32(self) -> u32;
     fn to_u64(self) -> u64;
 }
 
@@ -68,19 +140,7 @@ macro_rules! data_impl {
     ($($x:ty),*) => {
         $(impl Data for $x {
             const SIZE: usize = std::mem::size_of::<$x>();
-            fn from_bytes(bytes: [u8; Self::SIZE]) ->  Self {
-                Self::from_le_bytes(bytes)
-            }
-            fn to_bytes(self) -> [u8; Self::SIZE] {
-                self.to_le_bytes()
-            }
-            fn from_u8(v: u8) -> Self { v as $x }
-            fn from_u16(v: u16) -> Self { v as $x }
-            fn from_u32(v: u32) -> Self { v as $x }
             fn from_u64(v: u64) -> Self { v as $x }
-            fn to_u8(self) -> u8 { self as u8 }
-            fn to_u16(self) -> u16 { self as u16 }
-            fn to_u32(self) -> u32 { self as u32 }
             fn to_u64(self) -> u64 { self as u64 }
         })*
     };
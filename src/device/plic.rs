@@ -1,22 +1,26 @@
-use crate::trap::Exception;
+use std::io::{self, Read, Write};
 
-use super::{Data, Device, PLIC_BASE};
+use log::{trace, warn};
+
+use crate::{snapshot, trap::Exception};
+
+use super::Device;
 
 /// The address for interrupt source priority. 1024 4-byte registers exist. Each interrupt into the
 /// PLIC has a configurable priority, from 1-7, with 7 being the highest priority. A value of 0
 /// means do not interrupt, effectively disabling that interrupt.
-const SOURCE_PRIORITY: u64 = PLIC_BASE;
-const SOURCE_PRIORITY_END: u64 = PLIC_BASE + 0xfff;
+const SOURCE_PRIORITY: u64 = 0;
+const SOURCE_PRIORITY_END: u64 = 0xfff;
 /// The address range for interrupt pending bits. 32 4-byte (1024 bits) registers exist.
 ///
 /// https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc#memory-map
 /// base + 0x001000: Interrupt Pending bit 0-31
 /// base + 0x00107C: Interrupt Pending bit 992-1023
-const PENDING: u64 = PLIC_BASE + 0x1000;
-const PENDING_END: u64 = PLIC_BASE + 0x107f;
+const PENDING: u64 = 0x1000;
+const PENDING_END: u64 = 0x107f;
 
-/// The address range for enable registers. The maximum number of contexts is 15871 but this PLIC
-/// supports only 2 contexts.
+/// The address range for enable registers. The maximum number of contexts is 15871, this PLIC
+/// has two for each hart: context 2N is the M-mode and 2N+1 the S-mode of hart N.
 ///
 /// https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc#memory-map
 /// base + 0x002000: Enable bits for sources 0-31 on context 0
@@ -26,12 +30,12 @@ const PENDING_END: u64 = PLIC_BASE + 0x107f;
 /// base + 0x002080: Enable bits for sources 0-31 on context 1
 /// base + 0x002084: Enable bits for sources 32-63 on context 1
 /// ...
-/// base + 0x0020FF: Enable bits for sources 992-1023 on context 1
-const ENABLE: u64 = PLIC_BASE + 0x2000;
-const ENABLE_END: u64 = PLIC_BASE + 0x20ff;
+/// base + 0x1F1FFC: Enable bits for sources 992-1023 on context 15871
+const ENABLE: u64 = 0x2000;
+const ENABLE_END: u64 = 0x1f1fff;
 
-/// The address range for priority thresholds and claim/complete registers. The maximum number of
-/// contexts is 15871 but this PLIC supports only 2 contexts.
+/// The address range for priority thresholds and claim/complete registers, one 4 KiB page for
+/// each context.
 ///
 /// https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc#memory-map
 /// base + 0x200000: Priority threshold for context 0
@@ -41,9 +45,13 @@ const ENABLE_END: u64 = PLIC_BASE + 0x20ff;
 /// base + 0x200FFC: Reserved
 /// base + 0x201000: Priority threshold for context 1
 /// base + 0x201004: Claim/complete for context 1
-const THRESHOLD_AND_CLAIM: u64 = PLIC_BASE + 0x200000;
-const THRESHOLD_AND_CLAIM_END: u64 = PLIC_BASE + 0x201007;
+/// ...
+/// base + 0x3FFF004: Claim/complete for context 15871
+const THRESHOLD_AND_CLAIM: u64 = 0x200000;
+const THRESHOLD_AND_CLAIM_END: u64 = 0x3ffffff;
 
+/// Contexts of a hart: M-mode and S-mode.
+const CONTEXTS_PER_HART: usize = 2;
 const WORD_SIZE: u64 = 0x4;
 const CONTEXT_OFFSET: u64 = 0x1000;
 const SOURCE_NUM: u64 = 1024;
@@ -57,109 +65,133 @@ pub struct Plic {
     /// Interrupt pending bits. If bit 1 is set, a global interrupt 1 is pending. A pending bit in
     /// the PLIC core can be cleared by setting the associated enable bit then performing a claim.
     pending: [u32; 32],
-    /// Interrupt Enable Bit of Interrupt Source #0 to #1023 for 2 contexts.
-    enable: [u32; 64],
+    /// Interrupt Enable Bit of Interrupt Source #0 to #1023 for each context.
+    enable: Vec<u32>,
     /// The settings of a interrupt priority threshold of each context. The PLIC will mask all PLIC
     /// interrupts of a priority less than or equal to `threshold`.
-    threshold: [u32; 2],
+    threshold: Vec<u32>,
     /// The ID of the highest priority pending interrupt or zero if there is no pending interrupt
     /// for each context.
-    claim: [u32; 2],
+    claim: Vec<u32>,
 }
 
 impl Device for Plic {
-    fn read<T>(&self, addr: u64) -> Result<T, Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        if T::SIZE != WORD_SIZE as usize {
-            return Err(Exception::LoadFault);
+    fn read(&self, offset: u64, size: usize) -> Result<u64, Exception> {
+        if size != WORD_SIZE as usize {
+            return Err(Exception::LoadFault(offset));
         }
-        match addr {
+        match offset {
             SOURCE_PRIORITY..=SOURCE_PRIORITY_END => {
-                if (addr - SOURCE_PRIORITY).wrapping_rem(WORD_SIZE) != 0 {
-                    return Err(Exception::LoadFault);
+                if (offset - SOURCE_PRIORITY).wrapping_rem(WORD_SIZE) != 0 {
+                    return Err(Exception::LoadFault(offset));
                 }
-                let index = (addr - SOURCE_PRIORITY).wrapping_div(WORD_SIZE);
-                Ok(T::from_u32(self.priority[index as usize]))
+                let index = (offset - SOURCE_PRIORITY).wrapping_div(WORD_SIZE);
+                Ok(self.priority[index as usize] as u64)
             }
             PENDING..=PENDING_END => {
-                if (addr - PENDING).wrapping_rem(WORD_SIZE) != 0 {
-                    return Err(Exception::LoadFault);
+                if (offset - PENDING).wrapping_rem(WORD_SIZE) != 0 {
+                    return Err(Exception::LoadFault(offset));
                 }
-                let index = (addr - PENDING).wrapping_div(WORD_SIZE);
-                Ok(T::from_u32(self.pending[index as usize]))
+                let index = (offset - PENDING).wrapping_div(WORD_SIZE);
+                Ok(self.pending[index as usize] as u64)
             }
             ENABLE..=ENABLE_END => {
-                if (addr - ENABLE).wrapping_rem(WORD_SIZE) != 0 {
-                    return Err(Exception::LoadFault);
+                if (offset - ENABLE).wrapping_rem(WORD_SIZE) != 0 {
+                    return Err(Exception::LoadFault(offset));
                 }
-                let index = (addr - ENABLE).wrapping_div(WORD_SIZE);
-                Ok(T::from_u32(self.enable[index as usize]))
+                let index = (offset - ENABLE).wrapping_div(WORD_SIZE);
+                let enable = self
+                    .enable
+                    .get(index as usize)
+                    .ok_or(Exception::LoadFault(offset))?;
+                Ok(*enable as u64)
             }
             THRESHOLD_AND_CLAIM..=THRESHOLD_AND_CLAIM_END => {
-                let context = (addr - THRESHOLD_AND_CLAIM).wrapping_div(CONTEXT_OFFSET);
-                let offset = addr - (THRESHOLD_AND_CLAIM + CONTEXT_OFFSET * context);
-                if offset == 0 {
-                    Ok(T::from_u32(self.threshold[context as usize]))
-                } else if offset == 4 {
-                    Ok(T::from_u32(self.claim[context as usize]))
+                let context = (offset - THRESHOLD_AND_CLAIM).wrapping_div(CONTEXT_OFFSET);
+                let reg = offset - (THRESHOLD_AND_CLAIM + CONTEXT_OFFSET * context);
+                if context as usize >= self.claim.len() {
+                    Err(Exception::LoadFault(offset))
+                } else if reg == 0 {
+                    Ok(self.threshold[context as usize] as u64)
+                } else if reg == 4 {
+                    Ok(self.claim[context as usize] as u64)
                 } else {
-                    return Err(Exception::LoadFault);
+                    Err(Exception::LoadFault(offset))
                 }
             }
-            _ => return Err(Exception::LoadFault),
+            _ => Err(Exception::LoadFault(offset)),
         }
     }
 
-    fn write<T>(&mut self, addr: u64, value: T) -> Result<(), Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        if T::SIZE != WORD_SIZE as usize {
-            return Err(Exception::StoreFault);
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        if size != WORD_SIZE as usize {
+            return Err(Exception::StoreFault(offset));
         }
-        match addr {
+        match offset {
             SOURCE_PRIORITY..=SOURCE_PRIORITY_END => {
-                if (addr - SOURCE_PRIORITY).wrapping_rem(WORD_SIZE) != 0 {
-                    return Err(Exception::StoreFault);
+                if (offset - SOURCE_PRIORITY).wrapping_rem(WORD_SIZE) != 0 {
+                    return Err(Exception::StoreFault(offset));
+                }
+                let index = (offset - SOURCE_PRIORITY).wrapping_div(WORD_SIZE);
+                // Source 0 doesn't exist, its priority is hardwired to 0.
+                if index != 0 {
+                    self.priority[index as usize] = value as u32;
                 }
-                let index = (addr - SOURCE_PRIORITY).wrapping_div(WORD_SIZE);
-                self.priority[index as usize] = value.to_u32();
             }
             PENDING..=PENDING_END => {
-                if (addr - PENDING).wrapping_rem(WORD_SIZE) != 0 {
-                    return Err(Exception::StoreFault);
+                if (offset - PENDING).wrapping_rem(WORD_SIZE) != 0 {
+                    return Err(Exception::StoreFault(offset));
                 }
-                let index = (addr - PENDING).wrapping_div(WORD_SIZE);
-                self.pending[index as usize] = value.to_u32();
+                let index = (offset - PENDING).wrapping_div(WORD_SIZE);
+                self.pending[index as usize] = Self::without_source_0(index, value);
             }
             ENABLE..=ENABLE_END => {
-                if (addr - ENABLE).wrapping_rem(WORD_SIZE) != 0 {
-                    return Err(Exception::StoreFault);
+                if (offset - ENABLE).wrapping_rem(WORD_SIZE) != 0 {
+                    return Err(Exception::StoreFault(offset));
                 }
-                let index = (addr - ENABLE).wrapping_div(WORD_SIZE);
-                self.enable[index as usize] = value.to_u32();
+                let index = (offset - ENABLE).wrapping_div(WORD_SIZE);
+                let enable = self
+                    .enable
+                    .get_mut(index as usize)
+                    .ok_or(Exception::StoreFault(offset))?;
+                *enable = Self::without_source_0(index % 32, value);
+                self.update_claim();
             }
             THRESHOLD_AND_CLAIM..=THRESHOLD_AND_CLAIM_END => {
-                let context = (addr - THRESHOLD_AND_CLAIM).wrapping_div(CONTEXT_OFFSET);
-                let offset = addr - (THRESHOLD_AND_CLAIM + CONTEXT_OFFSET * context);
-                if offset == 0 {
-                    self.threshold[context as usize] = value.to_u32();
-                } else if offset == 4 {
+                let context = (offset - THRESHOLD_AND_CLAIM).wrapping_div(CONTEXT_OFFSET);
+                let reg = offset - (THRESHOLD_AND_CLAIM + CONTEXT_OFFSET * context);
+                if context as usize >= self.claim.len() {
+                    return Err(Exception::StoreFault(offset));
+                } else if reg == 0 {
+                    self.threshold[context as usize] = value as u32;
+                } else if reg == 4 {
                     //self.claim[context as usize] = value as u32;
                     // Clear pending bit.
-                    self.clear_pending(value.to_u64());
+                    self.clear_pending(value);
                 } else {
-                    return Err(Exception::StoreFault);
+                    return Err(Exception::StoreFault(offset));
                 }
             }
-            _ => return Err(Exception::StoreFault),
+            _ => return Err(Exception::StoreFault(offset)),
         }
         Ok(())
     }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        snapshot::write_u32s(w, &self.priority)?;
+        snapshot::write_u32s(w, &self.pending)?;
+        snapshot::write_u32s(w, &self.enable)?;
+        snapshot::write_u32s(w, &self.threshold)?;
+        snapshot::write_u32s(w, &self.claim)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        snapshot::read_u32s(r, &mut self.priority)?;
+        snapshot::read_u32s(r, &mut self.pending)?;
+        snapshot::read_u32s(r, &mut self.enable)?;
+        snapshot::read_u32s(r, &mut self.threshold)?;
+        snapshot::read_u32s(r, &mut self.claim)
+    }
 }
 
 impl Plic {
@@ -168,41 +200,212 @@ impl Plic {
         Self {
             priority: [0; 1024],
             pending: [0; 32],
-            enable: [0; 64],
-            threshold: [0; 2],
-            claim: [0; 2],
+            enable: Vec::new(),
+            threshold: Vec::new(),
+            claim: Vec::new(),
         }
     }
 
-    /// Sets IRQ bit in `pending`.
+    /// Adds the M-mode and S-mode contexts of the next hart.
+    pub fn add_hart(&mut self) {
+        let contexts = self.claim.len() + CONTEXTS_PER_HART;
+        self.enable.resize(contexts * 32, 0);
+        self.threshold.resize(contexts, 0);
+        self.claim.resize(contexts, 0);
+    }
+
+    /// Returns true if `irq` is the ID of an interrupt source, 1 to 1023. ID 0 means no
+    /// interrupt.
+    pub fn is_source(irq: u64) -> bool {
+        Self::pending_bit(irq).is_some()
+    }
+
+    /// Sets IRQ bit in `pending`. IDs that aren't a source are ignored.
     pub fn update_pending(&mut self, irq: u64) {
-        let index = irq.wrapping_div(WORD_SIZE);
-        self.pending[index as usize] = self.pending[index as usize] | (1 << irq);
+        trace!("irq {} pending", irq);
+        match Self::pending_bit(irq) {
+            Some((index, bit)) => {
+                self.pending[index] |= bit;
+                self.update_claim();
+            }
+            None => warn!(
+                "plic: ignoring the interrupt of {}, which isn't a source",
+                irq
+            ),
+        }
+    }
 
-        self.update_claim(irq);
+    /// Makes the pending bit of `irq` follow the interrupt line of its device, which is raised
+    /// while `level` is true. Returns true if the bit changed. IDs that aren't a source are
+    /// ignored.
+    pub fn set_level(&mut self, irq: u64, level: bool) -> bool {
+        let (index, bit) = match Self::pending_bit(irq) {
+            Some(pending_bit) => pending_bit,
+            None => {
+                warn!(
+                    "plic: ignoring the interrupt of {}, which isn't a source",
+                    irq
+                );
+                return false;
+            }
+        };
+        if (self.pending[index] & bit != 0) == level {
+            return false;
+        }
+        trace!("irq {} {}", irq, if level { "raised" } else { "lowered" });
+        self.pending[index] ^= bit;
+        self.update_claim();
+        true
     }
 
     /// Clears IRQ bit in `pending`.
     fn clear_pending(&mut self, irq: u64) {
-        let index = irq.wrapping_div(WORD_SIZE);
-        self.pending[index as usize] = self.pending[index as usize] & !(1 << irq);
+        trace!("irq {} completed", irq);
+        if let Some((index, bit)) = Self::pending_bit(irq) {
+            self.pending[index] &= !bit;
+            self.update_claim();
+        }
+    }
+
+    /// Returns the index of the word in `pending` and the bit within it of `irq`, `None` for
+    /// source 0, which doesn't exist, and for IDs past the last source.
+    fn pending_bit(irq: u64) -> Option<(usize, u32)> {
+        if irq == 0 || irq >= SOURCE_NUM {
+            return None;
+        }
+        Some(((irq / 32) as usize, 1 << (irq % 32)))
+    }
+
+    /// Returns `value` written to the word `index` of a bit array of sources, with the bit of
+    /// source 0 hardwired to 0.
+    fn without_source_0(index: u64, value: u64) -> u32 {
+        match index {
+            0 => value as u32 & !1,
+            _ => value as u32,
+        }
+    }
 
-        self.update_claim(0);
+    /// Returns true if the S-mode context of `hart` has an interrupt to claim, which drives its
+    /// mip.SEIP.
+    pub fn is_interrupting_supervisor(&self, hart: usize) -> bool {
+        self.claim[hart * CONTEXTS_PER_HART + 1] != 0
     }
 
-    /// Sets IRQ bit in `claim` for context 1.
-    fn update_claim(&mut self, irq: u64) {
+    /// Sets `claim` of the S-mode context of every hart to its pending and enabled interrupt
+    /// with the lowest ID, or 0 if there is none. Several sources may be pending at once, the
+    /// next one is claimable as soon as the previous one completes.
+    fn update_claim(&mut self) {
         // TODO: Support highest priority to the `claim` register.
-        // claim[1] is claim/complete registers for S-mode (context 1). SCLAIM.
-        if self.is_enable(1, irq) || irq == 0 {
-            self.claim[1] = irq as u32;
+        // Odd contexts are the claim/complete registers for S-mode. SCLAIM.
+        for context in (1..self.claim.len()).step_by(CONTEXTS_PER_HART) {
+            let enable = &self.enable[context * 32..(context + 1) * 32];
+            self.claim[context] = self
+                .pending
+                .iter()
+                .zip(enable)
+                .enumerate()
+                .find(|(_, (pending, enable))| *pending & *enable != 0)
+                .map_or(0, |(index, (pending, enable))| {
+                    index as u32 * 32 + (pending & enable).trailing_zeros()
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The PLIC of one hart with every source enabled for its S-mode context.
+    fn plic() -> Plic {
+        let mut plic = Plic::new();
+        plic.add_hart();
+        for word in 0..32 {
+            plic.write(ENABLE + 0x80 + word * 4, 4, !0).unwrap();
         }
+        plic
     }
 
-    /// Returns true if the enable bit for the `irq` of the `context` is set.
-    fn is_enable(&self, context: u64, irq: u64) -> bool {
-        let index = (irq.wrapping_rem(SOURCE_NUM)).wrapping_div(WORD_SIZE * 8);
-        let offset = (irq.wrapping_rem(SOURCE_NUM)).wrapping_rem(WORD_SIZE * 8);
-        return ((self.enable[(context * 32 + index) as usize] >> offset) & 1) == 1;
+    fn sclaim(plic: &Plic) -> u64 {
+        plic.read(THRESHOLD_AND_CLAIM + CONTEXT_OFFSET + 4, 4)
+            .unwrap()
+    }
+
+    #[test]
+    fn sources_past_the_first_word_are_pending_in_their_own_bit() {
+        let mut plic = plic();
+        plic.update_pending(40);
+        plic.update_pending(SOURCE_NUM - 1);
+        assert_eq!(plic.read(PENDING, 4), Ok(0));
+        assert_eq!(plic.read(PENDING + 4, 4), Ok(1 << 8));
+        assert_eq!(plic.read(PENDING_END - 3, 4), Ok(1 << 31));
+        // IDs past the last source are ignored.
+        plic.update_pending(SOURCE_NUM);
+        plic.update_pending(u64::MAX);
+
+        assert_eq!(sclaim(&plic), 40);
+        plic.write(THRESHOLD_AND_CLAIM + CONTEXT_OFFSET + 4, 4, 40)
+            .unwrap();
+        assert_eq!(sclaim(&plic), SOURCE_NUM - 1);
+    }
+
+    #[test]
+    fn source_0_is_hardwired_and_bad_accesses_fault() {
+        let mut plic = plic();
+        plic.write(SOURCE_PRIORITY, 4, 7).unwrap();
+        plic.write(SOURCE_PRIORITY + 4, 4, 7).unwrap();
+        assert_eq!(plic.read(SOURCE_PRIORITY, 4), Ok(0));
+        assert_eq!(plic.read(SOURCE_PRIORITY + 4, 4), Ok(7));
+        plic.write(SOURCE_PRIORITY_END - 3, 4, 1).unwrap();
+        assert_eq!(plic.read(SOURCE_PRIORITY_END - 3, 4), Ok(1));
+
+        // The bit of source 0 reads 0 in the pending and enable arrays.
+        assert_eq!(plic.read(ENABLE + 0x80, 4), Ok(0xffff_fffe));
+        plic.write(PENDING, 4, 1).unwrap();
+        assert_eq!(plic.read(PENDING, 4), Ok(0));
+        plic.update_pending(0);
+        assert_eq!(sclaim(&plic), 0);
+
+        // Completing IDs that aren't sources or aren't pending changes nothing.
+        plic.update_pending(5);
+        for &irq in [0, 6, SOURCE_NUM, u32::MAX as u64].iter() {
+            plic.write(THRESHOLD_AND_CLAIM + CONTEXT_OFFSET + 4, 4, irq)
+                .unwrap();
+            assert_eq!(sclaim(&plic), 5);
+        }
+
+        // Misaligned registers, the gaps in the map, contexts of harts that don't exist and
+        // sizes other than a word.
+        let bad = [
+            SOURCE_PRIORITY + 2,
+            PENDING + 1,
+            PENDING_END + 1,
+            ENABLE + 3,
+            ENABLE + 0x100,
+            ENABLE_END + 1,
+            THRESHOLD_AND_CLAIM + 8,
+            THRESHOLD_AND_CLAIM + 2 * CONTEXT_OFFSET,
+            THRESHOLD_AND_CLAIM_END - 3,
+            THRESHOLD_AND_CLAIM_END + 1,
+        ];
+        for &offset in bad.iter() {
+            assert_eq!(
+                plic.read(offset, 4),
+                Err(Exception::LoadFault(offset)),
+                "{:#x}",
+                offset
+            );
+            assert_eq!(
+                plic.write(offset, 4, 1),
+                Err(Exception::StoreFault(offset)),
+                "{:#x}",
+                offset
+            );
+        }
+        assert_eq!(plic.read(PENDING, 8), Err(Exception::LoadFault(PENDING)));
+        assert_eq!(
+            plic.write(PENDING, 1, 1),
+            Err(Exception::StoreFault(PENDING))
+        );
     }
 }
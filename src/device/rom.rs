@@ -0,0 +1,96 @@
+use crate::{trap::Exception, XLen};
+
+use super::Device;
+
+/// The offset of the address the reset vector jumps to, the firmware entry.
+const ENTRY_OFFSET: usize = 24;
+/// The offset of the device tree address the reset vector passes in a1.
+const DTB_OFFSET: usize = 32;
+
+/// Read-only memory, e.g. the boot ROM holding the reset vector. The window past the contents
+/// reads as zeros, writes raise a store access fault.
+pub struct Rom {
+    data: Vec<u8>,
+}
+
+impl Rom {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    /// The reset vector of the QEMU virt machine: loads the hart id into a0 and `dtb` into a1,
+    /// then jumps to `entry`. Both addresses are doublewords after the code, RV32 loads their low
+    /// words.
+    pub fn reset_vector(xlen: XLen, entry: u64, dtb: u64) -> Self {
+        // ld on RV64, lw on RV32.
+        let load = match xlen {
+            XLen::X32 => 0x2000,
+            XLen::X64 => 0x3000,
+        };
+        let code: [u32; 5] = [
+            // auipc t0, 0
+            0x0000_0297,
+            // csrr a0, mhartid
+            0xf140_2573,
+            // ld a1, 32(t0)
+            0x0202_8583 | load,
+            // ld t0, 24(t0)
+            0x0182_8283 | load,
+            // jr t0
+            0x0002_8067,
+        ];
+        let mut data: Vec<u8> = code.iter().flat_map(|word| word.to_le_bytes()).collect();
+        data.resize(ENTRY_OFFSET, 0);
+        data.extend_from_slice(&entry.to_le_bytes());
+        data.extend_from_slice(&dtb.to_le_bytes());
+        debug_assert_eq!(data.len(), DTB_OFFSET + 8);
+        Self::new(data)
+    }
+}
+
+impl Device for Rom {
+    fn read(&self, offset: u64, size: usize) -> Result<u64, Exception> {
+        let mut bytes = [0; 8];
+        let start = (offset as usize).min(self.data.len());
+        let end = (offset as usize + size).min(self.data.len());
+        bytes[..end - start].copy_from_slice(&self.data[start..end]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, offset: u64, _size: usize, _value: u64) -> Result<(), Exception> {
+        Err(Exception::StoreFault(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::CpuBuilder,
+        device::{ROM_BASE, ROM_SIZE},
+        testing::*,
+    };
+
+    #[test]
+    fn the_rom_reads_but_never_changes() {
+        let cpu = CpuBuilder::new(Vec::new())
+            .boot_protocol(0x8700_0000)
+            .build();
+        let mut bus = cpu.mmu.bus.borrow_mut();
+        assert_eq!(bus.read::<u32>(ROM_BASE), Ok(0x0000_0297));
+        assert_eq!(bus.read::<u64>(ROM_BASE + 24), Ok(DRAM_BASE));
+        assert_eq!(bus.read::<u64>(ROM_BASE + 32), Ok(0x8700_0000));
+        // The window past the contents reads as zeros.
+        assert_eq!(bus.read::<u16>(ROM_BASE + 39), Ok(0));
+        assert_eq!(bus.read::<u64>(ROM_BASE + ROM_SIZE - 8), Ok(0));
+        assert_eq!(
+            bus.write::<u32>(ROM_BASE, 0),
+            Err(Exception::StoreFault(ROM_BASE))
+        );
+        assert_eq!(
+            bus.write::<u8>(ROM_BASE + 0x100, 1),
+            Err(Exception::StoreFault(ROM_BASE + 0x100))
+        );
+        assert_eq!(bus.read::<u32>(ROM_BASE), Ok(0x0000_0297));
+    }
+}
@@ -0,0 +1,60 @@
+use std::{cell::Cell, rc::Rc};
+
+use log::warn;
+
+use crate::trap::Exception;
+
+use super::Device;
+
+/// Powers the machine off, the guest passed.
+const FINISHER_PASS: u64 = 0x5555;
+/// Powers the machine off, the guest failed with the exit code in the upper 16 bits.
+const FINISHER_FAIL: u64 = 0x3333;
+/// Resets the machine.
+const FINISHER_RESET: u64 = 0x7777;
+
+/// What the guest asked the syscon for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PowerRequest {
+    Exit(i32),
+    Reset,
+}
+
+/// The request written to the syscon, shared with the harts which take it after the step that
+/// wrote it.
+pub type PowerLine = Rc<Cell<Option<PowerRequest>>>;
+
+/// The SiFive test finisher of the QEMU virt machine, which lets the guest power the machine off
+/// or reset it with a 32-bit write.
+pub struct Syscon {
+    line: PowerLine,
+}
+
+impl Syscon {
+    pub fn new(line: PowerLine) -> Self {
+        Self { line }
+    }
+}
+
+impl Device for Syscon {
+    fn read(&self, _offset: u64, _size: usize) -> Result<u64, Exception> {
+        Ok(0)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        if offset != 0 || size != 4 {
+            return Ok(());
+        }
+        let request = match value & 0xffff {
+            FINISHER_PASS => PowerRequest::Exit(0),
+            FINISHER_FAIL => PowerRequest::Exit((value >> 16) as u16 as i32),
+            FINISHER_RESET => PowerRequest::Reset,
+            _ => {
+                warn!("syscon: ignoring the unknown command {:#x}", value);
+                return Ok(());
+            }
+        };
+        self.line.set(Some(request));
+        Ok(())
+    }
+}
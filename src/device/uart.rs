@@ -1,24 +1,26 @@
 use std::{
-    io::{Read, Write},
+    collections::VecDeque,
+    io::{self, Read, Write},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Condvar, Mutex,
     },
-    thread,
 };
 
-use crate::trap::Exception;
+use crate::{snapshot, trap::Exception};
 
-use super::{Data, Device, UART_BASE, UART_SIZE};
+use super::{Device, UART_SIZE};
 
 /// The interrupt request of UART.
 pub const UART_IRQ: u64 = 10;
+/// The interrupt request of the second UART. 11 is left to the RTC, as on the QEMU virt machine.
+pub const UART1_IRQ: u64 = 12;
 /// Receive holding register (for input bytes).
-const UART_RHR: u64 = UART_BASE + 0;
+pub const UART_RHR: u64 = 0;
 /// Transmit holding register (for output bytes).
-const UART_THR: u64 = UART_BASE + 0;
+pub const UART_THR: u64 = 0;
 /// Line control register.
-const _UART_LCR: u64 = UART_BASE + 3;
+const _UART_LCR: u64 = 3;
 /// Line status register.
 /// LSR BIT 0:
 ///     0 = no data in receive holding register or FIFO.
@@ -26,105 +28,281 @@ const _UART_LCR: u64 = UART_BASE + 3;
 /// LSR BIT 5:
 ///     0 = transmit holding register is full. 16550 will not accept any data for transmission.
 ///     1 = transmitter hold register (or FIFO) is empty. CPU can load the next character.
-const UART_LSR: u64 = UART_BASE + 5;
+pub const UART_LSR: u64 = 5;
 
 /// The receiver (RX) bit.
-const UART_LSR_RX: u8 = 1;
+pub const UART_LSR_RX: u8 = 1;
+/// The overrun error bit, a received byte was dropped because the FIFO was full. Cleared by
+/// reading the LSR.
+const UART_LSR_OE: u8 = 1 << 1;
 /// The transmitter (TX) bit.
 const UART_LSR_TX: u8 = 1 << 5;
 
+/// The number of received bytes the UART holds before the guest reads them, as on a 16550.
+pub const RX_FIFO_LEN: usize = 16;
+
+/// The registers and the receive FIFO, shared with the `UartInput`s.
+struct UartState {
+    regs: [u8; UART_SIZE as usize],
+    /// Received bytes in order, RHR reads the front.
+    rx: VecDeque<u8>,
+}
+
+impl UartState {
+    /// Takes the next received byte, 0 if there is none.
+    fn pop(&mut self) -> u8 {
+        let byte = self.rx.pop_front().unwrap_or(0);
+        if self.rx.is_empty() {
+            self.regs[UART_LSR as usize] &= !UART_LSR_RX;
+        }
+        byte
+    }
+
+    /// Appends `byte` to the FIFO, returns false if it's full.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.rx.len() == RX_FIFO_LEN {
+            return false;
+        }
+        self.rx.push_back(byte);
+        self.regs[UART_LSR as usize] |= UART_LSR_RX;
+        true
+    }
+}
+
 pub struct Uart {
-    /// Pair of an array for UART buffer and a conditional variable.
-    uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
-    /// Bit if an interrupt happens.
+    /// Pair of the UART state and a conditional variable, notified when received bytes are read.
+    uart: Arc<(Mutex<UartState>, Condvar)>,
+    /// Set while the receive FIFO holds bytes, which keeps the interrupt raised.
     interrupting: Arc<AtomicBool>,
+    /// Where transmitted bytes go, the host stdout by default.
+    output: Box<dyn Write>,
 }
 
 impl Device for Uart {
-    fn read<T>(&self, addr: u64) -> Result<T, Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        if T::SIZE != 1 {
-            return Err(Exception::LoadFault);
+    fn read(&self, offset: u64, size: usize) -> Result<u64, Exception> {
+        if size != 1 {
+            return Err(Exception::LoadFault(offset));
         }
         let (uart, cvar) = &*self.uart;
         let mut uart = uart.lock().expect("failed to get an UART object");
 
-        Ok(match addr {
+        Ok(match offset {
             UART_RHR => {
                 cvar.notify_one();
-                uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
-                T::from_u8(uart[(UART_RHR - UART_BASE) as usize])
+                let byte = uart.pop();
+                if uart.rx.is_empty() {
+                    self.interrupting.store(false, Ordering::Release);
+                }
+                byte as u64
+            }
+            UART_LSR => {
+                let lsr = uart.regs[UART_LSR as usize];
+                uart.regs[UART_LSR as usize] &= !UART_LSR_OE;
+                lsr as u64
             }
-            _ => T::from_u8(uart[(addr - UART_BASE) as usize]),
+            _ => uart.regs[offset as usize] as u64,
         })
     }
 
-    fn write<T>(&mut self, addr: u64, value: T) -> Result<(), Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
-        if T::SIZE != 1 {
-            return Err(Exception::StoreFault);
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), Exception> {
+        if size != 1 {
+            return Err(Exception::StoreFault(offset));
         }
         let (uart, _cvar) = &*self.uart;
         let mut uart = uart.lock().expect("failed to get an UART object");
-        Ok(match addr {
+        match offset {
             UART_THR => {
-                print!("{}", value.to_u8() as char);
-                std::io::stdout().flush().expect("failed to flush stdout");
+                // Flushed per line. Partial lines are left to the host, see `ConsoleOutput`.
+                let byte = value as u8;
+                let output = &mut self.output;
+                output
+                    .write_all(&[byte])
+                    .and_then(|_| match byte {
+                        b'\n' => output.flush(),
+                        _ => Ok(()),
+                    })
+                    .expect("failed to write the console output");
             }
             _ => {
-                uart[(addr - UART_BASE) as usize] = value.to_u8();
+                uart.regs[offset as usize] = value as u8;
             }
-        })
+        }
+        Ok(())
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        // Raised for as long as there are received bytes to read.
+        self.interrupting.load(Ordering::Acquire)
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        let (uart, _cvar) = &*self.uart;
+        let uart = uart.lock().expect("failed to get an UART object");
+        w.write_all(&uart.regs)?;
+        snapshot::write_u64(w, uart.rx.len() as u64)?;
+        let (front, back) = uart.rx.as_slices();
+        w.write_all(front)?;
+        w.write_all(back)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let (uart, cvar) = &*self.uart;
+        let mut uart = uart.lock().expect("failed to get an UART object");
+        r.read_exact(&mut uart.regs)?;
+        let len = snapshot::read_u64(r)? as usize;
+        if len > RX_FIFO_LEN {
+            return Err(snapshot::invalid_data("the UART FIFO is too long"));
+        }
+        let mut rx = vec![0; len];
+        r.read_exact(&mut rx)?;
+        uart.rx = rx.into();
+        self.interrupting.store(len != 0, Ordering::Release);
+        // The reader thread may be waiting for room in the FIFO.
+        cvar.notify_one();
+        Ok(())
     }
 }
 
 impl Uart {
-    pub fn new() -> Self {
-        let uart = Arc::new((Mutex::new([0; UART_SIZE as usize]), Condvar::new()));
+    /// Creates a UART transmitting to `output`, without any input attached, see `UartInput`.
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        let mut regs = [0; UART_SIZE as usize];
+        // Transmitter hold register is empty.
+        regs[UART_LSR as usize] |= UART_LSR_TX;
+        let state = UartState {
+            regs,
+            rx: VecDeque::with_capacity(RX_FIFO_LEN),
+        };
+        let uart = Arc::new((Mutex::new(state), Condvar::new()));
         let interrupting = Arc::new(AtomicBool::new(false));
+        Self {
+            uart,
+            interrupting,
+            output,
+        }
+    }
 
-        {
-            let (uart, _cvar) = &*uart;
-            let mut uart = uart.lock().expect("failed to get an UART object");
-            // Transmitter hold register is empty.
-            uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
+    /// Returns a handle for feeding received bytes to the UART.
+    pub fn input(&self) -> UartInput {
+        UartInput {
+            uart: self.uart.clone(),
+            interrupting: self.interrupting.clone(),
         }
-        let mut byte = [0; 1];
-        let cloned_uart = uart.clone();
-        let cloned_interrupting = interrupting.clone();
-        thread::spawn(move || loop {
-            match std::io::stdin().read(&mut byte) {
-                Ok(_) => {
-                    let (uart, cvar) = &*cloned_uart;
-                    let mut uart = uart.lock().expect("failed to get an UART object");
-                    // Wait for the thread to start up.
-                    while (uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) == 1 {
-                        uart = cvar.wait(uart).expect("the mutex is poisoned");
-                    }
-                    uart[0] = byte[0];
-                    cloned_interrupting.store(true, Ordering::Release);
-                    // Data has been receive.
-                    uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
-                }
-                Err(e) => {
-                    println!("{}", e);
-                }
+    }
+}
+
+/// Feeds received bytes to a `Uart`, either from a thread reading stdin or byte by byte from the
+/// run loop.
+#[derive(Clone)]
+pub struct UartInput {
+    uart: Arc<(Mutex<UartState>, Condvar)>,
+    interrupting: Arc<AtomicBool>,
+}
+
+impl UartInput {
+    /// Adds `byte` to the receive FIFO unless it's full. Returns whether the byte was taken, the
+    /// caller may offer it again later.
+    pub fn try_receive(&self, byte: u8) -> bool {
+        let (uart, _cvar) = &*self.uart;
+        let mut uart = uart.lock().expect("failed to get an UART object");
+        self.push(&mut uart, byte)
+    }
+
+    /// Adds `byte` to the receive FIFO like a serial line does: if the FIFO is full, the byte is
+    /// lost and the LSR reports an overrun.
+    pub fn receive(&self, byte: u8) {
+        let (uart, _cvar) = &*self.uart;
+        let mut uart = uart.lock().expect("failed to get an UART object");
+        if !self.push(&mut uart, byte) {
+            uart.regs[UART_LSR as usize] |= UART_LSR_OE;
+        }
+    }
+
+    fn push(&self, uart: &mut UartState, byte: u8) -> bool {
+        if !uart.push(byte) {
+            return false;
+        }
+        self.interrupting.store(true, Ordering::Release);
+        true
+    }
+
+    /// Adds `byte` to the receive FIFO, waiting for the guest to make room first. For a thread
+    /// reading the host stdin.
+    #[cfg(feature = "host")]
+    pub fn receive_blocking(&self, byte: u8) {
+        let (uart, cvar) = &*self.uart;
+        let mut uart = uart.lock().expect("failed to get an UART object");
+        while !self.push(&mut uart, byte) {
+            uart = cvar.wait(uart).expect("the mutex is poisoned");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uart() -> (Uart, UartInput) {
+        let uart = Uart::with_output(Box::new(io::sink()));
+        let input = uart.input();
+        (uart, input)
+    }
+
+    #[test]
+    fn a_pasted_line_is_read_back_in_order() {
+        let (mut uart, input) = uart();
+        let line: Vec<u8> = (0..100).map(|i| b'a' + i % 26).collect();
+        let mut offered = line.iter().peekable();
+        let mut received = Vec::new();
+        while received.len() < line.len() {
+            // The input fills the FIFO, then the guest drains it.
+            let mut taken = 0;
+            while offered.peek().is_some_and(|&&byte| input.try_receive(byte)) {
+                offered.next();
+                taken += 1;
             }
-        });
-        Self {
-            uart: uart,
-            interrupting: interrupting,
+            assert_eq!(taken, RX_FIFO_LEN.min(line.len() - received.len()));
+            assert!(uart.is_interrupting());
+            while uart.read(UART_LSR, 1).unwrap() as u8 & UART_LSR_RX != 0 {
+                received.push(uart.read(UART_RHR, 1).unwrap() as u8);
+            }
+            assert!(!uart.is_interrupting());
         }
+        assert_eq!(received, line);
     }
 
-    /// Return true if an interrupt is pending. Clear the interrupting flag by swapping a value.
-    pub fn is_interrupting(&self) -> bool {
-        self.interrupting.swap(false, Ordering::Acquire)
+    #[test]
+    fn a_byte_received_into_a_full_fifo_is_an_overrun() {
+        let (mut uart, input) = uart();
+        for byte in 0..=RX_FIFO_LEN as u8 {
+            input.receive(byte);
+        }
+        let lsr = uart.read(UART_LSR, 1).unwrap() as u8;
+        assert_eq!(lsr & (UART_LSR_RX | UART_LSR_OE), UART_LSR_RX | UART_LSR_OE);
+        // Reading the LSR clears the overrun.
+        assert_eq!(uart.read(UART_LSR, 1).unwrap() as u8 & UART_LSR_OE, 0);
+        let received: Vec<_> = (0..RX_FIFO_LEN)
+            .map(|_| uart.read(UART_RHR, 1).unwrap() as u8)
+            .collect();
+        assert_eq!(received, (0..RX_FIFO_LEN as u8).collect::<Vec<_>>());
+        assert_eq!(uart.read(UART_LSR, 1).unwrap() as u8 & UART_LSR_RX, 0);
+        assert!(!uart.is_interrupting());
+    }
+
+    #[test]
+    fn the_fifo_survives_a_snapshot() {
+        let (uart, input) = uart();
+        for &byte in b"abc" {
+            input.receive(byte);
+        }
+        let mut saved = Vec::new();
+        uart.save(&mut saved).unwrap();
+        let (mut restored, _) = self::uart();
+        restored.restore(&mut &saved[..]).unwrap();
+        assert!(restored.is_interrupting());
+        let received: Vec<_> = (0..3)
+            .map(|_| restored.read(UART_RHR, 1).unwrap() as u8)
+            .collect();
+        assert_eq!(received, b"abc");
     }
 }
@@ -1,8 +1,10 @@
-use std::convert::TryInto;
+use std::io::{self, Read, Write};
 
-use crate::trap::Exception;
+use log::{trace, warn};
 
-use super::{bus::Bus, Data, Device, VIRTIO_BASE};
+use crate::{snapshot, trap::Exception};
+
+use super::{bus::Bus, read_reg, write_reg, Device};
 
 /// The interrupt request of virtio.
 pub const VIRTIO_IRQ: u64 = 1;
@@ -21,73 +23,79 @@ const VIRTQ_DESC_F_WRITE: u64 = 2;
 /// This means the buffer contains a list of buffer descriptors.
 const _VIRTQ_DESC_F_INDIRECT: u64 = 4;
 
+// 5.2.6 Device Operation, the status of a request.
+/// The request succeeded.
+const VIRTIO_BLK_S_OK: u8 = 0;
+/// The request failed.
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+
 // 4.2.2 MMIO Device Register Layout
 // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
 /// Magic value. Always return 0x74726976 (a Little Endian equivalent of the "virt" string).
-const MAGIC: u64 = VIRTIO_BASE;
-const MAGIC_END: u64 = VIRTIO_BASE + 0x3;
+const MAGIC: u64 = 0;
+const MAGIC_END: u64 = 0x3;
 
 /// Device version number. 1 is legacy.
-const VERSION: u64 = VIRTIO_BASE + 0x4;
-const VERSION_END: u64 = VIRTIO_BASE + 0x7;
+const VERSION: u64 = 0x4;
+const VERSION_END: u64 = 0x7;
 
 /// Virtio Subsystem Device ID. 1 is network, 2 is block device.
-const DEVICE_ID: u64 = VIRTIO_BASE + 0x8;
-const DEVICE_ID_END: u64 = VIRTIO_BASE + 0xb;
+const DEVICE_ID: u64 = 0x8;
+const DEVICE_ID_END: u64 = 0xb;
 
 /// Virtio Subsystem Vendor ID. Always return 0x554d4551
-const VENDOR_ID: u64 = VIRTIO_BASE + 0xc;
-const VENDOR_ID_END: u64 = VIRTIO_BASE + 0xf;
+const VENDOR_ID: u64 = 0xc;
+const VENDOR_ID_END: u64 = 0xf;
 
 /// Flags representing features the device supports. Access to this register returns bits
 /// DeviceFeaturesSel ∗ 32 to (DeviceFeaturesSel ∗ 32) + 31.
-const DEVICE_FEATURES: u64 = VIRTIO_BASE + 0x10;
-const DEVICE_FEATURES_END: u64 = VIRTIO_BASE + 0x13;
+const DEVICE_FEATURES: u64 = 0x10;
+const DEVICE_FEATURES_END: u64 = 0x13;
 
 /// Device (host) features word selection.
-const DEVICE_FEATURES_SEL: u64 = VIRTIO_BASE + 0x14;
-const DEVICE_FEATURES_SEL_END: u64 = VIRTIO_BASE + 0x17;
+const DEVICE_FEATURES_SEL: u64 = 0x14;
+const DEVICE_FEATURES_SEL_END: u64 = 0x17;
 
 /// Flags representing device features understood and activated by the driver. Access to this
 /// register sets bits DriverFeaturesSel ∗ 32 to (DriverFeaturesSel ∗ 32) + 31.
-const DRIVER_FEATURES: u64 = VIRTIO_BASE + 0x20;
-const DRIVER_FEATURES_END: u64 = VIRTIO_BASE + 0x23;
+const DRIVER_FEATURES: u64 = 0x20;
+const DRIVER_FEATURES_END: u64 = 0x23;
 
 /// Activated (guest) features word selection.
-const DRIVER_FEATURES_SEL: u64 = VIRTIO_BASE + 0x24;
-const DRIVER_FEATURES_SEL_END: u64 = VIRTIO_BASE + 0x27;
+const DRIVER_FEATURES_SEL: u64 = 0x24;
+const DRIVER_FEATURES_SEL_END: u64 = 0x27;
 
 // 4.2.4 Legacy interface
 // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1560004
 /// Guest page size. The driver writes the guest page size in bytes to the register during
 /// initialization, before any queues are used. This value should be a power of 2 and is used by
 /// the device to calculate the Guest address of the first queue page. Write-only.
-const GUEST_PAGE_SIZE: u64 = VIRTIO_BASE + 0x28;
-const GUEST_PAGE_SIZE_END: u64 = VIRTIO_BASE + 0x2b;
+const GUEST_PAGE_SIZE: u64 = 0x28;
+const GUEST_PAGE_SIZE_END: u64 = 0x2b;
 
 /// Virtual queue index. Writing to this register selects the virtual queue that the following
 /// operations on the QueueNumMax, QueueNum, QueueAlign and QueuePFN registers apply to. The index
 /// number of the first queue is zero (0x0). Write-only.
-const QUEUE_SEL: u64 = VIRTIO_BASE + 0x30;
-const QUEUE_SEL_END: u64 = VIRTIO_BASE + 0x33;
+const QUEUE_SEL: u64 = 0x30;
+const QUEUE_SEL_END: u64 = 0x33;
 
 /// Maximum virtual queue size. Reading from the register returns the maximum size of the queue the
 /// device is ready to process or zero (0x0) if the queue is not available. This applies to the
 /// queue selected by writing to QueueSel and is allowed only when QueuePFN is set to zero (0x0),
 /// so when the queue is not actively used. Read-only. In QEMU, `VIRTIO_COUNT = 8`.
-const QUEUE_NUM_MAX: u64 = VIRTIO_BASE + 0x34;
-const QUEUE_NUM_MAX_END: u64 = VIRTIO_BASE + 0x37;
+const QUEUE_NUM_MAX: u64 = 0x34;
+const QUEUE_NUM_MAX_END: u64 = 0x37;
 
 /// Virtual queue size. Queue size is the number of elements in the queue, therefore size of the
 /// descriptor table and both available and used rings. Writing to this register notifies the
 /// device what size of the queue the driver will use. This applies to the queue selected by
 /// writing to QueueSel. Write-only.
-const QUEUE_NUM: u64 = VIRTIO_BASE + 0x38;
-const QUEUE_NUM_END: u64 = VIRTIO_BASE + 0x3b;
+const QUEUE_NUM: u64 = 0x38;
+const QUEUE_NUM_END: u64 = 0x3b;
 
 /// Used Ring alignment in the virtual queue.
-const QUEUE_ALIGN: u64 = VIRTIO_BASE + 0x3c;
-const QUEUE_ALIGN_END: u64 = VIRTIO_BASE + 0x3f;
+const QUEUE_ALIGN: u64 = 0x3c;
+const QUEUE_ALIGN_END: u64 = 0x3f;
 
 /// Guest physical page number of the virtual queue. Writing to this register notifies the device
 /// about location of the virtual queue in the Guest’s physical address space. This value is the
@@ -96,35 +104,48 @@ const QUEUE_ALIGN_END: u64 = VIRTIO_BASE + 0x3f;
 /// writes zero (0x0) to this register. Reading from this register returns the currently used page
 /// number of the queue, therefore a value other than zero (0x0) means that the queue is in use.
 /// Both read and write accesses apply to the queue selected by writing to QueueSel.
-const QUEUE_PFN: u64 = VIRTIO_BASE + 0x40;
-const QUEUE_PFN_END: u64 = VIRTIO_BASE + 0x43;
+const QUEUE_PFN: u64 = 0x40;
+const QUEUE_PFN_END: u64 = 0x43;
 
 // 4.2.2 MMIO Device Register Layout
 // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
 /// Queue notifier. Writing a queue index to this register notifies the device that there are new
 /// buffers to process in the queue. Write-only.
-const QUEUE_NOTIFY: u64 = VIRTIO_BASE + 0x50;
-const QUEUE_NOTIFY_END: u64 = VIRTIO_BASE + 0x53;
+const QUEUE_NOTIFY: u64 = 0x50;
+const QUEUE_NOTIFY_END: u64 = 0x53;
 
 /// Interrupt status. Reading from this register returns a bit mask of events that caused the
 /// device interrupt to be asserted.
-const INTERRUPT_STATUS: u64 = VIRTIO_BASE + 0x60;
-const INTERRUPT_STATUS_END: u64 = VIRTIO_BASE + 0x63;
+const INTERRUPT_STATUS: u64 = 0x60;
+const INTERRUPT_STATUS_END: u64 = 0x63;
+
+/// The InterruptStatus bit of a used buffer notification, the device used a buffer in a queue.
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+/// The InterruptStatus bit of a configuration change notification. The configuration space of the
+/// disk never changes, so the bit is never set.
+const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
 
 /// Interrupt acknowledge. Writing a value with bits set as defined in InterruptStatus to this
 /// register notifies the device that events causing the interrupt have been handled.
-const INTERRUPT_ACK: u64 = VIRTIO_BASE + 0x64;
-const INTERRUPT_ACK_END: u64 = VIRTIO_BASE + 0x67;
+const INTERRUPT_ACK: u64 = 0x64;
+const INTERRUPT_ACK_END: u64 = 0x67;
 
 /// Device status. Reading from this register returns the current device status flags. Writing
 /// non-zero values to this register sets the status flags, indicating the driver progress. Writing
 /// zero (0x0) to this register triggers a device reset.
-const STATUS: u64 = VIRTIO_BASE + 0x70;
-const STATUS_END: u64 = VIRTIO_BASE + 0x73;
+const STATUS: u64 = 0x70;
+const STATUS_END: u64 = 0x73;
+
+/// Device status bits the device acts on, "2.1 Device Status Field".
+/// "DRIVER_OK (4) Indicates that the driver is set up and ready to drive the device."
+const STATUS_DRIVER_OK: u32 = 4;
+/// "FAILED (128) Indicates that something went wrong in the guest, and it has given up on the
+/// device."
+const STATUS_FAILED: u32 = 128;
 
 /// Configuration space.
-const CONFIG: u64 = VIRTIO_BASE + 0x100;
-const CONFIG_END: u64 = VIRTIO_BASE + 0x107;
+const CONFIG: u64 = 0x100;
+const CONFIG_END: u64 = 0x107;
 
 /// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-230005
 /// "Each virtqueue can consist of up to 3 parts:
@@ -165,13 +186,13 @@ impl VirtqueueAddr {
         // Used Ring        | 4         | 6 + 8∗(Queue Size)
 
         let base_addr = virtio.queue_pfn as u64 * virtio.guest_page_size as u64;
-        let align = virtio.queue_align as u64;
+        let align = (virtio.queue_align as u64).max(1);
         let size = virtio.queue_num as u64;
-        let avail_ring_end = base_addr + (16 * size) + (6 + 2 * size);
+        let avail_ring_end = base_addr.wrapping_add(16 * size + 6 + 2 * size);
 
         Self {
             desc_addr: base_addr,
-            avail_addr: base_addr + 16 * size,
+            avail_addr: base_addr.wrapping_add(16 * size),
             // Used ring starts with the `queue_align` boundary after the available ring ends.
             used_addr: (avail_ring_end.wrapping_div(align) + 1).wrapping_mul(align),
         }
@@ -242,6 +263,7 @@ impl VirtqDesc {
 /// ```
 #[derive(Debug)]
 struct VirtqAvail {
+    #[allow(dead_code)]
     flags: u16,
     idx: u16,
     ring_start_addr: u64,
@@ -256,6 +278,11 @@ impl VirtqAvail {
         })
     }
 }
+/// The word of feature bits selected by a FEATURES_SEL register, 0 past the known ones.
+fn features_word(features: &[u32; 2], sel: u32) -> u32 {
+    features.get(sel as usize).copied().unwrap_or(0)
+}
+
 /// Paravirtualized drivers for IO virtualization.
 pub struct Virtio {
     id: u64,
@@ -276,11 +303,7 @@ pub struct Virtio {
 }
 
 impl Device for Virtio {
-    fn read<T>(&self, addr: u64) -> Result<T, Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
+    fn read(&self, addr: u64, size: usize) -> Result<u64, Exception> {
         // `reg` is the value of a target register in the virtio block device and `offset` is the
         // byte of the start position in the register.
         let (reg, offset) = match addr {
@@ -293,7 +316,7 @@ impl Device for Virtio {
             // See https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c#L86
             VENDOR_ID..=VENDOR_ID_END => (0x554d4551, addr - VENDOR_ID),
             DEVICE_FEATURES..=DEVICE_FEATURES_END => (
-                self.device_features[self.device_features_sel as usize],
+                features_word(&self.device_features, self.device_features_sel),
                 addr - DEVICE_FEATURES,
             ),
             QUEUE_NUM_MAX..=QUEUE_NUM_MAX_END => (QUEUE_SIZE as u32, addr - QUEUE_NUM_MAX),
@@ -303,26 +326,18 @@ impl Device for Virtio {
             }
             STATUS..=STATUS_END => (self.status, addr - STATUS),
             CONFIG..=CONFIG_END => {
-                if T::SIZE != 1 {
-                    return Err(Exception::LoadFault);
+                if size != 1 {
+                    return Err(Exception::LoadFault(addr));
                 }
                 let index = addr - CONFIG;
                 (self.config[index as usize] as u32, 0)
             }
-            _ => return Err(Exception::LoadFault),
+            _ => return Err(Exception::LoadFault(addr)),
         };
-        let bytes = (reg >> (offset * 8)).to_le_bytes();
-        let bytes: [u8; T::SIZE] = bytes[bytes.len() - T::SIZE..]
-            .try_into()
-            .map_err(|_| Exception::LoadFault)?;
-        Ok(T::from_bytes(bytes))
+        Ok(read_reg(reg as u64, offset, size))
     }
 
-    fn write<T>(&mut self, addr: u64, value: T) -> Result<(), Exception>
-    where
-        T: Data,
-        [(); <T as Data>::SIZE]: Sized,
-    {
+    fn write(&mut self, addr: u64, size: usize, value: u64) -> Result<(), Exception> {
         // `reg` is the value of a target register in the virtio block device and `offset` is the
         // byte of the start position in the register.
         let (reg, offset) = match addr {
@@ -330,58 +345,45 @@ impl Device for Virtio {
                 (self.device_features_sel, addr - DEVICE_FEATURES_SEL)
             }
             DRIVER_FEATURES..=DRIVER_FEATURES_END => (
-                self.driver_features[self.driver_features_sel as usize],
+                features_word(&self.driver_features, self.driver_features_sel),
                 addr - DRIVER_FEATURES,
             ),
             DRIVER_FEATURES_SEL..=DRIVER_FEATURES_SEL_END => {
                 (self.driver_features_sel, addr - DRIVER_FEATURES_SEL)
             }
             GUEST_PAGE_SIZE..=GUEST_PAGE_SIZE_END => (self.guest_page_size, addr - GUEST_PAGE_SIZE),
-            QUEUE_SEL..=QUEUE_SEL_END => {
-                if value.to_u8() != 0 {
-                    panic!("Multiple virtual queues are not supported.");
-                }
-                return Ok(());
-            }
+            // Only queue 0 exists, selecting another one is ignored.
+            QUEUE_SEL..=QUEUE_SEL_END => return Ok(()),
             QUEUE_NUM..=QUEUE_NUM_END => (self.queue_num, addr - QUEUE_NUM),
             QUEUE_ALIGN..=QUEUE_ALIGN_END => (self.queue_align, addr - QUEUE_ALIGN),
             QUEUE_PFN..=QUEUE_PFN_END => (self.queue_pfn, addr - QUEUE_PFN),
             QUEUE_NOTIFY..=QUEUE_NOTIFY_END => (self.queue_notify, addr - QUEUE_NOTIFY),
-            INTERRUPT_ACK..=INTERRUPT_ACK_END => {
-                (self.interrupt_status, addr - INTERRUPT_ACK)
-                /*
-                if (value & 0x1) == 1 {
-                    self.interrupt_status &= !0x1;
-                } else {
-                    panic!("unexpected value for INTERRUPT_ACK: {:#x}", value);
-                }
-                return Ok(());
-                */
-            }
+            INTERRUPT_ACK..=INTERRUPT_ACK_END => (0, addr - INTERRUPT_ACK),
             STATUS..=STATUS_END => (self.status, addr - STATUS),
             CONFIG..=CONFIG_END => {
-                if T::SIZE != 1 {
-                    return Err(Exception::StoreFault);
+                if size != 1 {
+                    return Err(Exception::StoreFault(addr));
                 }
                 let index = addr - CONFIG;
-                self.config[index as usize] = value.to_u8() >> (index * 8);
+                self.config[index as usize] = (value >> (index * 8)) as u8;
                 return Ok(());
             }
-            _ => return Err(Exception::StoreFault),
+            _ => return Err(Exception::StoreFault(addr)),
         };
 
-        let bytes = value.to_bytes();
         // Store the new value to the target register.
-        let mut origin_bytes = reg.to_le_bytes();
-        for (idx, bit) in bytes.iter().enumerate() {
-            origin_bytes[offset as usize + idx] = *bit;
-        }
-        let reg = u32::from_le_bytes(origin_bytes);
+        let reg = write_reg(reg as u64, offset, size, value) as u32;
         // Store the new register value to the target register.
         match addr {
             DEVICE_FEATURES_SEL..=DEVICE_FEATURES_SEL_END => self.device_features_sel = reg,
             DRIVER_FEATURES..=DRIVER_FEATURES_END => {
-                self.driver_features[self.driver_features_sel as usize] = reg
+                // Only the two words of feature bits the device knows about are kept.
+                if let Some(features) = self
+                    .driver_features
+                    .get_mut(self.driver_features_sel as usize)
+                {
+                    *features = reg;
+                }
             }
             DRIVER_FEATURES_SEL..=DRIVER_FEATURES_SEL_END => self.driver_features_sel = reg,
             GUEST_PAGE_SIZE..=GUEST_PAGE_SIZE_END => self.guest_page_size = reg,
@@ -389,26 +391,80 @@ impl Device for Virtio {
             QUEUE_ALIGN..=QUEUE_ALIGN_END => self.queue_align = reg,
             QUEUE_PFN..=QUEUE_PFN_END => self.queue_pfn = reg,
             QUEUE_NOTIFY..=QUEUE_NOTIFY_END => self.queue_notify = reg,
-            INTERRUPT_ACK..=INTERRUPT_ACK_END => self.interrupt_status = reg,
+            // The acknowledged events are cleared, which lowers the interrupt once none is left.
+            INTERRUPT_ACK..=INTERRUPT_ACK_END => {
+                self.interrupt_status &= !(reg & (INTERRUPT_USED_BUFFER | INTERRUPT_CONFIG_CHANGE))
+            }
             STATUS..=STATUS_END => {
                 self.status = reg;
                 // "Writing 0 into this field resets the device."
                 if self.status == 0 {
                     self.reset();
                 }
-                // DRIVER_OK bit (4) was set, so initialize `VirtqueueAddr`.
-                if self.status & 0x4 == 1 {
+                // The driver is set up, so initialize `VirtqueueAddr`.
+                if self.status & STATUS_DRIVER_OK != 0 {
                     self.init_virtqueue();
                 }
-                // FAILED (128) bit. Indicates that something went wrong in the guest.
-                if (self.status & 128) == 1 {
-                    panic!("virtio: device status FAILED");
+                if self.status & STATUS_FAILED != 0 {
+                    warn!("virtio: the driver set the device status FAILED");
                 }
             }
-            _ => return Err(Exception::StoreFault),
+            _ => return Err(Exception::StoreFault(addr)),
         }
         Ok(())
     }
+
+    /// Returns true until the driver acknowledged every event in InterruptStatus.
+    fn is_interrupting(&mut self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        snapshot::write_u64(w, self.id)?;
+        snapshot::write_u32s(w, &self.device_features)?;
+        snapshot::write_u32s(w, &self.driver_features)?;
+        snapshot::write_u32s(
+            w,
+            &[
+                self.device_features_sel,
+                self.driver_features_sel,
+                self.guest_page_size,
+                self.queue_num,
+                self.queue_align,
+                self.queue_pfn,
+                self.queue_notify,
+                self.interrupt_status,
+                self.status,
+            ],
+        )?;
+        w.write_all(&self.config)?;
+        // The disk image is written back by the guest, so its contents are part of the state.
+        snapshot::write_sparse(w, &self.disk)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.id = snapshot::read_u64(r)?;
+        snapshot::read_u32s(r, &mut self.device_features)?;
+        snapshot::read_u32s(r, &mut self.driver_features)?;
+        let mut regs = [0; 9];
+        snapshot::read_u32s(r, &mut regs)?;
+        let [device_features_sel, driver_features_sel, guest_page_size, queue_num, queue_align, queue_pfn, queue_notify, interrupt_status, status] =
+            regs;
+        self.device_features_sel = device_features_sel;
+        self.driver_features_sel = driver_features_sel;
+        self.guest_page_size = guest_page_size;
+        self.queue_num = queue_num;
+        self.queue_align = queue_align;
+        self.queue_pfn = queue_pfn;
+        self.queue_notify = queue_notify;
+        self.interrupt_status = interrupt_status;
+        self.status = status;
+        r.read_exact(&mut self.config)?;
+        snapshot::read_sparse(r, &mut self.disk)?;
+        // Recomputed from the registers on the next access.
+        self.virtqueue = None;
+        Ok(())
+    }
 }
 
 impl Virtio {
@@ -452,12 +508,12 @@ impl Virtio {
         let mut features = [0; 2];
         // VIRTIO_F_IN_ORDER(Bit 35). This feature indicates that all buffers are used by the device
         // in the same order in which they have been made available.
-        features[1] = features[1] | (1 << 3);
-        return features;
+        features[1] |= 1 << 3;
+        features
     }
 
     /// Initializes a virtqueue once the device initialization is finished by setting the DRIVER_OK
-    /// status bit (0x4).
+    /// status bit.
     fn init_virtqueue(&mut self) {
         let queue = VirtqueueAddr::new(self);
         self.virtqueue = Some(queue);
@@ -479,15 +535,8 @@ impl Virtio {
         // "Upon reset, the device MUST clear all bits in InterruptStatus and ready bits in the
         // QueueReady register for all queues in the device."
         self.interrupt_status = 0;
-    }
-
-    /// Returns true if an interrupt is pending.
-    pub fn is_interrupting(&mut self) -> bool {
-        if self.queue_notify != u32::MAX {
-            self.queue_notify = u32::MAX;
-            return true;
-        }
-        false
+        // The queue is set up again before the driver sets DRIVER_OK the next time.
+        self.virtqueue = None;
     }
 
     /// Sets the binary in the virtio disk.
@@ -495,72 +544,55 @@ impl Virtio {
         self.disk.extend(binary.iter().cloned());
     }
 
-    fn read_disk(&self, addr: u64) -> u8 {
-        self.disk[addr as usize]
-    }
-
-    fn write_disk(&mut self, addr: u64, value: u8) {
-        self.disk[addr as usize] = value
+    /// Returns true if the driver wrote QueueNotify since the last call.
+    fn take_notify(&mut self) -> bool {
+        std::mem::replace(&mut self.queue_notify, u32::MAX) != u32::MAX
     }
 
-    /// Accesses the disk via virtio. This is an associated function which takes a `bus` object to
-    /// read and write with a memory directly (DMA).
-    pub fn disk_access(bus: &mut Bus) -> Result<(), Exception> {
-        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
-        // "Used Buffer Notification
-        //     - bit 0 - the interrupt was asserted because the device has used a buffer in at
-        //     least one of the active virtual queues."
-        bus.virtio.interrupt_status |= 0x1;
-
+    /// Serves every request the driver made available since the last notify, then raises the
+    /// used buffer interrupt once for all of them. This is an associated function which takes a
+    /// `bus` object to read and write with a memory directly (DMA). Does nothing unless the
+    /// driver wrote QueueNotify.
+    pub fn process_queue(bus: &mut Bus) {
+        if !bus.virtio.take_notify() {
+            return;
+        }
         let virtq = bus.virtio.virtqueue();
-
-        let avail = VirtqAvail::new(bus, virtq.avail_addr)?;
-
-        let head_index =
-            bus.read::<u16>(avail.ring_start_addr + avail.idx as u64 % QUEUE_SIZE)? as u64;
-
-        // First descriptor.
-        let desc0 = VirtqDesc::new(bus, virtq.desc_addr + VRING_DESC_SIZE * head_index)?;
-        assert_eq!(desc0.flags & VIRTQ_DESC_F_NEXT, 1);
-
-        // Second descriptor.
-        let desc1 = VirtqDesc::new(bus, virtq.desc_addr + VRING_DESC_SIZE * desc0.next)?;
-        assert_eq!(desc1.flags & VIRTQ_DESC_F_NEXT, 1);
-
-        // 5.2.6 Device Operation
-        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2500006
-        // struct virtio_blk_req {
-        //   le32 type;
-        //   le32 reserved;
-        //   le64 sector;
-        //   u8 data[][512];
-        //   u8 status;
-        // };
-        let sector = bus.read::<u64>(desc0.addr.wrapping_add(8))?;
-
-        // Write to a device if the second bit of `flags` is set.
-        match (desc1.flags & VIRTQ_DESC_F_WRITE) == 0 {
-            true => {
-                // Read memory data and write it to a disk.
-                for i in 0..desc1.len {
-                    let data = bus.read::<u8>(desc1.addr + i)?;
-                    bus.virtio.write_disk(sector * SECTOR_SIZE + i, data);
-                }
-            }
-            false => {
-                // Read disk data and write it to memory.
-                for i in 0..desc1.len {
-                    let data = bus.virtio.read_disk(sector * SECTOR_SIZE + i);
-                    bus.write::<u8>(desc1.addr + i, data)?;
-                }
+        let avail = match VirtqAvail::new(bus, virtq.avail_addr) {
+            Ok(avail) => avail,
+            Err(e) => {
+                warn!("virtio: reading the available ring faulted: {}", e);
+                return;
             }
         };
+        let mut used = false;
+        // `id` counts the used requests, which are served in order, so it is also the index of
+        // the next available one.
+        while bus.virtio.id as u16 != avail.idx {
+            if let Err(e) = Virtio::complete_next(bus, virtq, &avail) {
+                warn!("virtio: the queue faulted: {}", e);
+                break;
+            }
+            used = true;
+        }
+        if used {
+            // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
+            // "Used Buffer Notification
+            //     - bit 0 - the interrupt was asserted because the device has used a buffer in at
+            //     least one of the active virtual queues."
+            bus.virtio.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
 
-        // Third descriptor address.
-        let desc2 = VirtqDesc::new(bus, virtq.desc_addr + VRING_DESC_SIZE * desc1.next)?;
-        assert_eq!(desc2.flags & VIRTQ_DESC_F_NEXT, 0);
-        // Tell success.
-        bus.write::<u8>(desc2.addr, 0)?;
+    /// Serves the next available request and returns it in the used ring.
+    fn complete_next(
+        bus: &mut Bus,
+        virtq: VirtqueueAddr,
+        avail: &VirtqAvail,
+    ) -> Result<(), Exception> {
+        let slot = bus.virtio.id % QUEUE_SIZE;
+        let head_index = bus.read::<u16>(avail.ring_start_addr.wrapping_add(2 * slot))? as u64;
+        Virtio::disk_access(bus, virtq, head_index);
 
         // 2.6.7.2 Device Requirements: Used Buffer Notification Suppression
         // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-400007
@@ -584,16 +616,311 @@ impl Virtio {
         // };
         // ```
         bus.write::<u32>(
-            virtq
-                .used_addr
-                .wrapping_add(4)
-                .wrapping_add((bus.virtio.id as u64 % QUEUE_SIZE) * 8),
+            virtq.used_addr.wrapping_add(4).wrapping_add(slot * 8),
             head_index as u32,
         )?;
 
         bus.virtio.id = bus.virtio.id.wrapping_add(1);
-        bus.write::<u16>(virtq.used_addr.wrapping_add(2), bus.virtio.id as u16)?;
+        bus.write::<u16>(virtq.used_addr.wrapping_add(2), bus.virtio.id as u16)
+    }
 
-        Ok(())
+    /// Reads the chain of descriptors starting at `head_index`. A chain longer than the queue
+    /// must loop, it's cut off there.
+    fn read_chain(
+        bus: &mut Bus,
+        virtq: VirtqueueAddr,
+        head_index: u64,
+    ) -> Result<Vec<VirtqDesc>, Exception> {
+        let mut chain = Vec::new();
+        let mut index = head_index;
+        while chain.len() < QUEUE_SIZE as usize {
+            let desc = VirtqDesc::new(bus, virtq.desc_addr.wrapping_add(VRING_DESC_SIZE * index))?;
+            index = desc.next;
+            let last = desc.flags & VIRTQ_DESC_F_NEXT == 0;
+            chain.push(desc);
+            if last {
+                break;
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Serves the block request whose descriptor chain starts at `head_index` and writes its
+    /// status. A request that isn't a chain of three descriptors or whose buffers can't be
+    /// accessed fails with an IOERR status, as far as the status can be written at all.
+    fn disk_access(bus: &mut Bus, virtq: VirtqueueAddr, head_index: u64) {
+        let chain = match Virtio::read_chain(bus, virtq, head_index) {
+            Ok(chain) => chain,
+            Err(e) => {
+                warn!("virtio: reading a descriptor faulted: {}, dropped", e);
+                return;
+            }
+        };
+        let status = match chain.as_slice() {
+            [header, data, _] => Virtio::transfer(bus, header, data).unwrap_or_else(|e| {
+                warn!("virtio: the request faulted: {}", e);
+                VIRTIO_BLK_S_IOERR
+            }),
+            _ => {
+                warn!("virtio: a request isn't a chain of three descriptors");
+                VIRTIO_BLK_S_IOERR
+            }
+        };
+        let status_desc = chain.last().expect("a chain has a descriptor");
+        if let Err(e) = bus.write::<u8>(status_desc.addr, status) {
+            warn!("virtio: writing the status of a request faulted: {}", e);
+        }
+    }
+
+    /// Moves the data of a request between the disk and the memory, returns the status.
+    fn transfer(bus: &mut Bus, header: &VirtqDesc, data: &VirtqDesc) -> Result<u8, Exception> {
+        // 5.2.6 Device Operation
+        // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2500006
+        // struct virtio_blk_req {
+        //   le32 type;
+        //   le32 reserved;
+        //   le64 sector;
+        //   u8 data[][512];
+        //   u8 status;
+        // };
+        let sector = bus.read::<u64>(header.addr.wrapping_add(8))?;
+        trace!(
+            "{} {} bytes at sector {}",
+            if data.flags & VIRTQ_DESC_F_WRITE == 0 {
+                "write"
+            } else {
+                "read"
+            },
+            data.len,
+            sector
+        );
+
+        // Requests reaching past the end of the disk fail without touching it.
+        let start = sector
+            .checked_mul(SECTOR_SIZE)
+            .filter(|start| start.saturating_add(data.len) <= bus.virtio.disk.len() as u64);
+        let start = match start {
+            Some(start) => start,
+            None => return Ok(VIRTIO_BLK_S_IOERR),
+        };
+        let sectors = start as usize..(start + data.len) as usize;
+        // Write to a device if the second bit of `flags` is set.
+        match (data.flags & VIRTQ_DESC_F_WRITE) == 0 {
+            true => {
+                // Read memory data and write it to a disk.
+                let mut buf = vec![0; data.len as usize];
+                bus.read_phys_bytes(data.addr, &mut buf)?;
+                bus.virtio.disk[sectors].copy_from_slice(&buf);
+            }
+            false => {
+                // Read disk data and write it to memory.
+                let buf = bus.virtio.disk[sectors].to_vec();
+                bus.write_phys_bytes(data.addr, &buf)?;
+            }
+        };
+        Ok(VIRTIO_BLK_S_OK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::{DRAM_BASE, PLIC_BASE, VIRTIO_BASE},
+        testing::logged,
+    };
+
+    /// The queue: the descriptor table, then the available ring, the used ring on the next page.
+    const QUEUE: u64 = DRAM_BASE + 0x1_0000;
+    const AVAIL: u64 = QUEUE + VRING_DESC_SIZE * QUEUE_SIZE;
+    const USED: u64 = QUEUE + 0x1000;
+    /// Request headers, data buffers and status bytes.
+    const BUFFERS: u64 = DRAM_BASE + 0x2_0000;
+
+    /// A bus with a 4-sector disk, sector n filled with n, and the queue set up by the driver.
+    fn bus() -> Bus {
+        let mut bus = Bus::new(Vec::new());
+        let disk = (0..4)
+            .flat_map(|n| [n as u8; SECTOR_SIZE as usize])
+            .collect();
+        bus.virtio.initialize(disk);
+        bus.write::<u32>(VIRTIO_BASE + GUEST_PAGE_SIZE, 0x1000)
+            .unwrap();
+        bus.write::<u32>(VIRTIO_BASE + QUEUE_NUM, QUEUE_SIZE as u32)
+            .unwrap();
+        bus.write::<u32>(VIRTIO_BASE + QUEUE_PFN, (QUEUE >> 12) as u32)
+            .unwrap();
+        bus
+    }
+
+    fn set_desc(bus: &mut Bus, index: u64, addr: u64, len: u32, flags: u64, next: u64) {
+        let desc = QUEUE + VRING_DESC_SIZE * index;
+        bus.write::<u64>(desc, addr).unwrap();
+        bus.write::<u32>(desc + 8, len).unwrap();
+        bus.write::<u16>(desc + 12, flags as u16).unwrap();
+        bus.write::<u16>(desc + 14, next as u16).unwrap();
+    }
+
+    /// Sets up a request of `sector` in descriptors `head` to `head + 2` and returns the address
+    /// of its status byte. `data` is the buffer, `read` moves the sector to it.
+    fn request(bus: &mut Bus, head: u64, sector: u64, data: u64, read: bool) -> u64 {
+        let header = BUFFERS + head * 0x10;
+        let status = BUFFERS + 0x100 + head;
+        bus.write::<u64>(header + 8, sector).unwrap();
+        bus.write::<u8>(status, 0xff).unwrap();
+        let data_flags = VIRTQ_DESC_F_NEXT | if read { VIRTQ_DESC_F_WRITE } else { 0 };
+        set_desc(bus, head, header, 16, VIRTQ_DESC_F_NEXT, head + 1);
+        set_desc(
+            bus,
+            head + 1,
+            data,
+            SECTOR_SIZE as u32,
+            data_flags,
+            head + 2,
+        );
+        set_desc(bus, head + 2, status, 1, VIRTQ_DESC_F_WRITE, 0);
+        status
+    }
+
+    /// Makes the chains at `heads` available and notifies the device.
+    fn submit(bus: &mut Bus, heads: &[u64]) {
+        let idx = bus.read::<u16>(AVAIL + 2).unwrap();
+        for (i, &head) in heads.iter().enumerate() {
+            let slot = (idx as u64 + i as u64) % QUEUE_SIZE;
+            bus.write::<u16>(AVAIL + 4 + 2 * slot, head as u16).unwrap();
+        }
+        bus.write::<u16>(AVAIL + 2, idx + heads.len() as u16)
+            .unwrap();
+        bus.write::<u32>(VIRTIO_BASE + QUEUE_NOTIFY, 0).unwrap();
+    }
+
+    fn used(bus: &Bus) -> (u16, Vec<u32>) {
+        let idx = bus.read::<u16>(USED + 2).unwrap();
+        let ring = (0..idx as u64)
+            .map(|i| bus.read::<u32>(USED + 4 + 8 * i).unwrap())
+            .collect();
+        (idx, ring)
+    }
+
+    #[test]
+    fn one_notify_serves_every_available_request() {
+        let mut bus = bus();
+        let (read_buf, write_buf) = (BUFFERS + 0x1000, BUFFERS + 0x2000);
+        bus.write_phys_bytes(write_buf, &[0xaa; SECTOR_SIZE as usize])
+            .unwrap();
+        let read_status = request(&mut bus, 0, 1, read_buf, true);
+        let write_status = request(&mut bus, 3, 2, write_buf, false);
+        submit(&mut bus, &[0, 3]);
+        // Nothing happens before the run loop polls the devices.
+        assert_eq!(used(&bus).0, 0);
+        assert!(!bus.update_irq_lines());
+
+        bus.poll_devices();
+        assert_eq!(used(&bus), (2, vec![0, 3]));
+        assert_eq!(bus.read::<u8>(read_status), Ok(VIRTIO_BLK_S_OK));
+        assert_eq!(bus.read::<u8>(write_status), Ok(VIRTIO_BLK_S_OK));
+        let mut data = [0; SECTOR_SIZE as usize];
+        bus.read_phys_bytes(read_buf, &mut data).unwrap();
+        assert_eq!(data, [1; SECTOR_SIZE as usize]);
+        assert_eq!(&bus.virtio.disk[2 * 512..3 * 512], &[0xaa; 512][..]);
+
+        // One used buffer notification for both.
+        assert_eq!(
+            bus.read::<u32>(VIRTIO_BASE + INTERRUPT_STATUS),
+            Ok(INTERRUPT_USED_BUFFER)
+        );
+        // Polling again without a notify serves nothing.
+        bus.poll_devices();
+        assert_eq!(used(&bus).0, 2);
+
+        // The next request continues in the available ring where the last one ended.
+        let status = request(&mut bus, 0, 3, read_buf, true);
+        submit(&mut bus, &[0]);
+        bus.poll_devices();
+        assert_eq!(used(&bus), (3, vec![0, 3, 0]));
+        assert_eq!(bus.read::<u8>(status), Ok(VIRTIO_BLK_S_OK));
+    }
+
+    #[test]
+    fn bad_requests_complete_with_an_error() {
+        let mut bus = bus();
+        // Past the end of the disk.
+        let past_end = request(&mut bus, 0, 4, BUFFERS + 0x1000, true);
+        // A data buffer outside of the memory.
+        let unmapped = request(&mut bus, 3, 0, 0x10, true);
+        // Two descriptors only, the second one takes the status.
+        let short = BUFFERS + 0x200;
+        set_desc(&mut bus, 6, BUFFERS, 16, VIRTQ_DESC_F_NEXT, 7);
+        set_desc(&mut bus, 7, short, 1, VIRTQ_DESC_F_WRITE, 0);
+        submit(&mut bus, &[0, 3, 6]);
+        bus.poll_devices();
+        assert_eq!(used(&bus), (3, vec![0, 3, 6]));
+        for &status in [past_end, unmapped, short].iter() {
+            assert_eq!(bus.read::<u8>(status), Ok(VIRTIO_BLK_S_IOERR));
+        }
+
+        // A chain looping back to itself ends at the queue size.
+        set_desc(&mut bus, 0, BUFFERS, 16, VIRTQ_DESC_F_NEXT, 0);
+        submit(&mut bus, &[0]);
+        bus.poll_devices();
+        assert_eq!(used(&bus).0, 4);
+    }
+
+    #[test]
+    fn driver_ok_sets_up_the_queue_until_the_reset() {
+        let mut bus = bus();
+        let set_status = |bus: &mut Bus, status: u32| {
+            bus.write::<u32>(VIRTIO_BASE + STATUS, status).unwrap();
+        };
+        // ACKNOWLEDGE and DRIVER.
+        set_status(&mut bus, 0x3);
+        assert!(bus.virtio.virtqueue.is_none());
+        set_status(&mut bus, 0x3 | STATUS_DRIVER_OK);
+        assert_eq!(bus.read::<u32>(VIRTIO_BASE + STATUS), Ok(0x7));
+        let queue = bus.virtio.virtqueue.expect("no virtqueue after DRIVER_OK");
+        assert_eq!(queue.desc_addr, QUEUE);
+        assert_eq!(queue.used_addr, USED);
+
+        set_status(&mut bus, 0);
+        assert!(bus.virtio.virtqueue.is_none());
+        assert_eq!(bus.read::<u32>(VIRTIO_BASE + STATUS), Ok(0));
+    }
+
+    #[test]
+    fn failed_is_logged() {
+        let mut bus = bus();
+        logged();
+        bus.write::<u32>(VIRTIO_BASE + STATUS, 0x3).unwrap();
+        assert!(logged().is_empty());
+        bus.write::<u32>(VIRTIO_BASE + STATUS, 0x3 | STATUS_FAILED)
+            .unwrap();
+        let warning = (
+            "riscv_emulator::device::virtio".to_string(),
+            "virtio: the driver set the device status FAILED".to_string(),
+        );
+        assert_eq!(logged(), [warning]);
+    }
+
+    #[test]
+    fn the_plic_pending_bit_follows_the_acknowledgement() {
+        const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
+        let mut bus = bus();
+        let pending = |bus: &Bus| bus.read::<u32>(PLIC_PENDING).unwrap() & 1 << VIRTIO_IRQ != 0;
+        for round in 0..2 {
+            request(&mut bus, 0, 0, BUFFERS + 0x1000, true);
+            submit(&mut bus, &[0]);
+            bus.poll_devices();
+            assert!(bus.update_irq_lines(), "round {}", round);
+            assert!(pending(&bus));
+            // The line stays up until the driver acknowledges the notification.
+            assert!(!bus.update_irq_lines());
+            let status = bus.read::<u32>(VIRTIO_BASE + INTERRUPT_STATUS).unwrap();
+            assert_eq!(status, INTERRUPT_USED_BUFFER);
+            bus.write::<u32>(VIRTIO_BASE + INTERRUPT_ACK, status)
+                .unwrap();
+            assert_eq!(bus.read::<u32>(VIRTIO_BASE + INTERRUPT_STATUS), Ok(0));
+            assert!(bus.update_irq_lines());
+            assert!(!pending(&bus));
+        }
     }
 }
@@ -0,0 +1,589 @@
+//! The C interface for embedding the emulator in frontends written in other languages, declared
+//! in `include/riscv_emulator.h`. A machine is an opaque handle with a single hart. Every function
+//! catches panics, a guest crashing the emulator poisons the machine instead of unwinding into
+//! the caller.
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    os::raw::{c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    rc::Rc,
+    slice,
+};
+
+use crate::{
+    cpu::{Cpu, CpuBuilder, StopReason},
+    device::DRAM_BASE,
+    symbols::{self, invalid_elf, read_u16, read_u32, read_u64},
+    RegT, XLen,
+};
+
+pub const RISCV_OK: c_int = 0;
+/// An argument was invalid, e.g. an address outside of the memory or a register beyond x31.
+pub const RISCV_ERROR: c_int = -1;
+/// The emulator panicked. The machine can only be freed.
+pub const RISCV_POISONED: c_int = -2;
+
+/// `riscv_machine_step` executed every instruction it was asked to.
+pub const RISCV_STOP_BUDGET: c_int = 0;
+/// The guest exited, see `riscv_machine_exit_code`.
+pub const RISCV_STOP_EXIT: c_int = 1;
+/// The guest raised an exception it can't handle, e.g. an access fault. pc points at the
+/// faulting instruction, or at the M-mode trap vector if fetching the handler faulted.
+pub const RISCV_STOP_FATAL: c_int = 2;
+
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+
+/// Receives `len` bytes of console output, `ctx` is the pointer passed along with the callback.
+pub type RiscvConsoleFn = extern "C" fn(ctx: *mut c_void, data: *const u8, len: usize);
+
+type ConsoleCallback = Rc<RefCell<Option<(RiscvConsoleFn, *mut c_void)>>>;
+
+/// A machine created by `riscv_machine_new`.
+pub struct RiscvMachine {
+    cpu: Cpu,
+    console: ConsoleCallback,
+    exit_code: Option<i32>,
+    poisoned: bool,
+}
+
+/// Passes the UART output to the registered callback, dropping it while there is none.
+struct CallbackConsole(ConsoleCallback);
+
+impl Write for CallbackConsole {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some((callback, ctx)) = *self.0.borrow() {
+            callback(ctx, buf.as_ptr(), buf.len());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `f` on the machine behind `machine`, returning `error` if the handle is null or
+/// poisoned and poisoning the machine if `f` panics.
+///
+/// # Safety
+///
+/// `machine` must be null or a handle from `riscv_machine_new` that hasn't been freed.
+unsafe fn with_machine<T>(
+    machine: *mut RiscvMachine,
+    error: T,
+    f: impl FnOnce(&mut RiscvMachine) -> T,
+) -> T {
+    let machine = match machine.as_mut() {
+        Some(machine) if !machine.poisoned => machine,
+        _ => return error,
+    };
+    match panic::catch_unwind(AssertUnwindSafe(|| f(machine))) {
+        Ok(result) => result,
+        Err(_) => {
+            machine.poisoned = true;
+            error
+        }
+    }
+}
+
+/// Turns a pointer and length from C into a slice, an empty one if `len` is 0.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes unless `len` is 0.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match len {
+        0 => Some(&[]),
+        _ if data.is_null() => None,
+        _ => Some(slice::from_raw_parts(data, len)),
+    }
+}
+
+/// Creates a machine of `xlen` bits, 32 or 64, with `mem_size` bytes of DRAM at 0x80000000
+/// where execution starts. Guests exit through semihosting. Returns null if the arguments are
+/// invalid.
+#[no_mangle]
+pub extern "C" fn riscv_machine_new(xlen: u32, mem_size: u64) -> *mut RiscvMachine {
+    let xlen = match xlen {
+        32 => XLen::X32,
+        64 => XLen::X64,
+        _ => return ptr::null_mut(),
+    };
+    if mem_size == 0 || mem_size > usize::MAX as u64 {
+        return ptr::null_mut();
+    }
+    let created = panic::catch_unwind(|| {
+        let console = ConsoleCallback::default();
+        let cpu = CpuBuilder::new(Vec::new())
+            .xlen(xlen)
+            .dram(DRAM_BASE, mem_size as usize)
+            .entry(DRAM_BASE)
+            .console(Box::new(CallbackConsole(console.clone())))
+            .semihosting(true)
+            .build();
+        RiscvMachine {
+            cpu,
+            console,
+            exit_code: None,
+            poisoned: false,
+        }
+    });
+    match created {
+        Ok(machine) => Box::into_raw(Box::new(machine)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Frees a machine created by `riscv_machine_new`. Null is ignored.
+///
+/// # Safety
+///
+/// `machine` must come from `riscv_machine_new` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_free(machine: *mut RiscvMachine) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+/// Sends the console output to `callback` from now on, null drops it.
+///
+/// # Safety
+///
+/// `machine` must be a live handle. `callback` is called with `ctx` during
+/// `riscv_machine_step` and must be safe to call with it.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_set_console(
+    machine: *mut RiscvMachine,
+    callback: Option<RiscvConsoleFn>,
+    ctx: *mut c_void,
+) -> c_int {
+    with_machine(machine, RISCV_ERROR, |machine| {
+        *machine.console.borrow_mut() = callback.map(|callback| (callback, ctx));
+        RISCV_OK
+    })
+}
+
+/// Copies `len` bytes from `data` to the guest physical address `addr`.
+///
+/// # Safety
+///
+/// `machine` must be a live handle and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_load_binary(
+    machine: *mut RiscvMachine,
+    addr: u64,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let data = match bytes(data, len) {
+        Some(data) => data,
+        None => return RISCV_ERROR,
+    };
+    with_machine(machine, RISCV_ERROR, |machine| {
+        match write_memory(&machine.cpu, addr, data) {
+            Ok(()) => RISCV_OK,
+            Err(()) => RISCV_ERROR,
+        }
+    })
+}
+
+/// Loads the segments of the RISC-V ELF executable in `data` at their physical addresses and
+/// moves pc to its entry point.
+///
+/// # Safety
+///
+/// `machine` must be a live handle and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_load_elf(
+    machine: *mut RiscvMachine,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let elf = match bytes(data, len) {
+        Some(elf) => elf,
+        None => return RISCV_ERROR,
+    };
+    with_machine(machine, RISCV_ERROR, |machine| {
+        match load_elf(&mut machine.cpu, elf) {
+            Ok(()) => RISCV_OK,
+            Err(_) => RISCV_ERROR,
+        }
+    })
+}
+
+/// Copies `len` bytes at the guest physical address `addr` to `buf`.
+///
+/// # Safety
+///
+/// `machine` must be a live handle and `buf` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_read_memory(
+    machine: *mut RiscvMachine,
+    addr: u64,
+    buf: *mut u8,
+    len: usize,
+) -> c_int {
+    if buf.is_null() && len != 0 {
+        return RISCV_ERROR;
+    }
+    with_machine(machine, RISCV_ERROR, |machine| {
+        let buf = if len == 0 {
+            &mut []
+        } else {
+            slice::from_raw_parts_mut(buf, len)
+        };
+        match machine.cpu.mmu.bus.borrow().read_phys_bytes(addr, buf) {
+            Ok(()) => RISCV_OK,
+            Err(_) => RISCV_ERROR,
+        }
+    })
+}
+
+/// Copies `len` bytes from `data` to the guest physical address `addr`.
+///
+/// # Safety
+///
+/// `machine` must be a live handle and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_write_memory(
+    machine: *mut RiscvMachine,
+    addr: u64,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    riscv_machine_load_binary(machine, addr, data, len)
+}
+
+/// Returns x`reg`, 0 for an invalid register or handle.
+///
+/// # Safety
+///
+/// `machine` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_get_reg(machine: *mut RiscvMachine, reg: u32) -> u64 {
+    with_machine(machine, 0, |machine| match reg {
+        0..=31 => machine.cpu.state.xs.reg(reg as u8),
+        _ => 0,
+    })
+}
+
+/// Sets x`reg` to `value`, truncated to XLEN bits. Writes to x0 are ignored.
+///
+/// # Safety
+///
+/// `machine` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_set_reg(
+    machine: *mut RiscvMachine,
+    reg: u32,
+    value: u64,
+) -> c_int {
+    with_machine(machine, RISCV_ERROR, |machine| match reg {
+        0..=31 => {
+            let xlen = machine.cpu.xlen;
+            machine.cpu.state.xs.set_reg(reg as u8, value & xlen.mask());
+            RISCV_OK
+        }
+        _ => RISCV_ERROR,
+    })
+}
+
+/// Returns pc, 0 for an invalid handle.
+///
+/// # Safety
+///
+/// `machine` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_get_pc(machine: *mut RiscvMachine) -> u64 {
+    with_machine(machine, 0, |machine| machine.cpu.state.pc)
+}
+
+/// Moves pc to `pc`, truncated to XLEN bits.
+///
+/// # Safety
+///
+/// `machine` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_set_pc(machine: *mut RiscvMachine, pc: u64) -> c_int {
+    with_machine(machine, RISCV_ERROR, |machine| {
+        machine.cpu.state.update_pc(pc);
+        RISCV_OK
+    })
+}
+
+/// Executes up to `steps` instructions and stores how many were executed in `taken` unless it
+/// is null. Returns `RISCV_STOP_BUDGET`, `RISCV_STOP_EXIT` or `RISCV_STOP_FATAL`, or
+/// `RISCV_POISONED` if the emulator panicked.
+///
+/// # Safety
+///
+/// `machine` must be a live handle and `taken` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_step(
+    machine: *mut RiscvMachine,
+    steps: u64,
+    taken: *mut u64,
+) -> c_int {
+    let mut done = 0;
+    let result = with_machine(machine, RISCV_POISONED, |machine| {
+        while done < steps {
+            let (block_steps, stop) = machine.cpu.run_block(steps - done);
+            done += block_steps;
+            match stop {
+                Some(StopReason::Exit(code)) => {
+                    machine.exit_code = Some(code);
+                    return RISCV_STOP_EXIT;
+                }
+                Some(StopReason::Fatal(_)) | Some(StopReason::DoubleFault(_)) => {
+                    return RISCV_STOP_FATAL
+                }
+                _ => {}
+            }
+        }
+        RISCV_STOP_BUDGET
+    });
+    if let Some(taken) = taken.as_mut() {
+        *taken = done;
+    }
+    result
+}
+
+/// Returns the code the guest exited with, or -1 if it hasn't exited.
+///
+/// # Safety
+///
+/// `machine` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_machine_exit_code(machine: *mut RiscvMachine) -> c_int {
+    with_machine(machine, -1, |machine| machine.exit_code.unwrap_or(-1))
+}
+
+fn write_memory(cpu: &Cpu, addr: u64, data: &[u8]) -> Result<(), ()> {
+    cpu.mmu
+        .bus
+        .borrow_mut()
+        .write_phys_bytes(addr, data)
+        .map_err(drop)
+}
+
+/// Loads the PT_LOAD segments of a 32 or 64-bit little endian ELF at their physical addresses,
+/// zeroing the part of a segment that isn't in the file.
+fn load_elf(cpu: &mut Cpu, elf: &[u8]) -> io::Result<()> {
+    let is_64 = match elf.get(..6) {
+        Some(b"\x7fELF\x01\x01") => false,
+        Some(b"\x7fELF\x02\x01") => true,
+        _ => return Err(invalid_elf("not a little endian ELF file")),
+    };
+    if read_u16(elf, 0x12)? != EM_RISCV {
+        return Err(invalid_elf("not a RISC-V ELF file"));
+    }
+    // The offsets of the fields used in the ELF and program headers, for ELF32 and ELF64.
+    let word = |offset32: usize, offset64: usize| -> io::Result<RegT> {
+        if is_64 {
+            read_u64(elf, offset64)
+        } else {
+            read_u32(elf, offset32).map(RegT::from)
+        }
+    };
+    let entry = word(0x18, 0x18)?;
+    let phoff = word(0x1c, 0x20)? as usize;
+    let phentsize = read_u16(elf, if is_64 { 0x36 } else { 0x2a })? as usize;
+    let phnum = read_u16(elf, if is_64 { 0x38 } else { 0x2c })? as usize;
+    for idx in 0..phnum {
+        let ph = phoff + idx * phentsize;
+        if read_u32(elf, ph)? != PT_LOAD {
+            continue;
+        }
+        let field = |offset32: usize, offset64: usize| -> io::Result<RegT> {
+            if is_64 {
+                read_u64(elf, ph + offset64)
+            } else {
+                read_u32(elf, ph + offset32).map(RegT::from)
+            }
+        };
+        let offset = field(0x04, 0x08)? as usize;
+        let paddr = field(0x0c, 0x18)?;
+        let filesz = field(0x10, 0x20)? as usize;
+        let memsz = field(0x14, 0x28)? as usize;
+        let mut segment = symbols::slice(elf, offset, filesz)?.to_vec();
+        segment.resize(memsz.max(filesz), 0);
+        write_memory(cpu, paddr, &segment)
+            .map_err(|_| invalid_elf("segment outside of the memory"))?;
+    }
+    cpu.state.update_pc(entry);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Prints "hi" through the UART, then exits with code 7 through semihosting.
+    const PROGRAM: [u32; 16] = [
+        lui(5, 0x10000),
+        addi(6, 0, b'h' as i32),
+        s_type(0, 6, 5, 0, 0x23),
+        addi(6, 0, b'i' as i32),
+        s_type(0, 6, 5, 0, 0x23),
+        addi(10, 0, 0x18),
+        auipc(11, 0),
+        addi(11, 11, 24),
+        0x01f0_1013,
+        EBREAK,
+        0x4070_5013,
+        0,
+        0x0002_0026,
+        0,
+        7,
+        0,
+    ];
+
+    extern "C" fn collect(ctx: *mut c_void, data: *const u8, len: usize) {
+        let console = unsafe { &mut *(ctx as *mut Vec<u8>) };
+        console.extend_from_slice(unsafe { slice::from_raw_parts(data, len) });
+    }
+
+    #[test]
+    fn a_program_runs_through_the_c_interface() {
+        assert!(riscv_machine_new(48, 1 << 20).is_null());
+        assert!(riscv_machine_new(64, 0).is_null());
+        let machine = riscv_machine_new(64, 1 << 20);
+        assert!(!machine.is_null());
+        let mut console = Vec::new();
+        let ctx = &mut console as *mut Vec<u8> as *mut c_void;
+        let program: Vec<u8> = PROGRAM.iter().flat_map(|w| w.to_le_bytes()).collect();
+        unsafe {
+            assert_eq!(
+                riscv_machine_set_console(machine, Some(collect), ctx),
+                RISCV_OK
+            );
+            let load = riscv_machine_load_binary(machine, DRAM_BASE, program.as_ptr(), 64);
+            assert_eq!(load, RISCV_OK);
+            let outside = riscv_machine_load_binary(machine, 0, program.as_ptr(), 4);
+            assert_eq!(outside, RISCV_ERROR);
+
+            let mut word = [0; 4];
+            let read = riscv_machine_read_memory(machine, DRAM_BASE + 4, word.as_mut_ptr(), 4);
+            assert_eq!(read, RISCV_OK);
+            assert_eq!(u32::from_le_bytes(word), PROGRAM[1]);
+            assert_eq!(riscv_machine_set_reg(machine, 32, 0), RISCV_ERROR);
+            assert_eq!(riscv_machine_set_reg(machine, 0, 5), RISCV_OK);
+            assert_eq!(riscv_machine_get_reg(machine, 0), 0);
+
+            let mut taken = 0;
+            assert_eq!(
+                riscv_machine_step(machine, 3, &mut taken),
+                RISCV_STOP_BUDGET
+            );
+            assert_eq!(taken, 3);
+            assert_eq!(riscv_machine_get_pc(machine), DRAM_BASE + 12);
+            assert_eq!(riscv_machine_get_reg(machine, 5), 0x1000_0000);
+            assert_eq!(riscv_machine_exit_code(machine), -1);
+
+            assert_eq!(
+                riscv_machine_step(machine, 100, &mut taken),
+                RISCV_STOP_EXIT
+            );
+            assert_eq!(taken, 7);
+            assert_eq!(riscv_machine_exit_code(machine), 7);
+            riscv_machine_free(machine);
+        }
+        assert_eq!(console, b"hi");
+    }
+
+    #[test]
+    fn an_illegal_instruction_stops_the_machine() {
+        let machine = riscv_machine_new(32, 1 << 20);
+        let mut taken = 0;
+        unsafe {
+            assert_eq!(
+                riscv_machine_step(machine, 10, &mut taken),
+                RISCV_STOP_FATAL
+            );
+            assert_eq!(taken, 1);
+            assert_eq!(riscv_machine_get_pc(machine), DRAM_BASE);
+            riscv_machine_free(machine);
+        }
+    }
+
+    /// An ELF32 executable with `code` in one segment at `addr`, followed by `bss` zeroed bytes.
+    fn elf32(code: &[u32], addr: u32, bss: u32) -> Vec<u8> {
+        let mut elf = vec![0; 0x54];
+        elf[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        elf[0x12..0x14].copy_from_slice(&EM_RISCV.to_le_bytes());
+        elf[0x18..0x1c].copy_from_slice(&addr.to_le_bytes());
+        elf[0x1c..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+        elf[0x2a..0x2c].copy_from_slice(&0x20u16.to_le_bytes());
+        elf[0x2c..0x2e].copy_from_slice(&1u16.to_le_bytes());
+        let len = code.len() as u32 * 4;
+        for (offset, value) in [
+            (0, PT_LOAD),
+            (4, 0x54),
+            (0xc, addr),
+            (0x10, len),
+            (0x14, len + bss),
+        ] {
+            elf[0x34 + offset..0x38 + offset].copy_from_slice(&value.to_le_bytes());
+        }
+        elf.extend(code.iter().flat_map(|w| w.to_le_bytes()));
+        elf
+    }
+
+    #[test]
+    fn elf_segments_are_loaded_at_their_physical_addresses() {
+        let machine = riscv_machine_new(32, 1 << 20);
+        let entry = DRAM_BASE as u32 + 0x1000;
+        let elf = elf32(&[addi(10, 0, 9), EBREAK], entry, 8);
+        unsafe {
+            let bss = riscv_machine_write_memory(machine, entry as u64 + 8, [0xff; 8].as_ptr(), 8);
+            assert_eq!(bss, RISCV_OK);
+            assert_eq!(
+                riscv_machine_load_elf(machine, elf.as_ptr(), elf.len()),
+                RISCV_OK
+            );
+            assert_eq!(riscv_machine_get_pc(machine), entry as u64);
+            let mut bss = [0xff; 8];
+            riscv_machine_read_memory(machine, entry as u64 + 8, bss.as_mut_ptr(), 8);
+            assert_eq!(bss, [0; 8]);
+            assert_eq!(
+                riscv_machine_step(machine, 1, ptr::null_mut()),
+                RISCV_STOP_BUDGET
+            );
+            assert_eq!(riscv_machine_get_reg(machine, 10), 9);
+
+            let outside = elf32(&[NOP], 0x1000, 0);
+            let load = riscv_machine_load_elf(machine, outside.as_ptr(), outside.len());
+            assert_eq!(load, RISCV_ERROR);
+            assert_eq!(
+                riscv_machine_load_elf(machine, elf.as_ptr(), 16),
+                RISCV_ERROR
+            );
+            riscv_machine_free(machine);
+        }
+    }
+
+    #[test]
+    fn a_panic_poisons_the_machine() {
+        let machine = riscv_machine_new(64, 1 << 20);
+        unsafe {
+            let result = with_machine(machine, RISCV_POISONED, |_| panic!("emulator bug"));
+            assert_eq!(result, RISCV_POISONED);
+            let mut taken = 5;
+            assert_eq!(riscv_machine_step(machine, 10, &mut taken), RISCV_POISONED);
+            assert_eq!(taken, 0);
+            assert_eq!(riscv_machine_set_pc(machine, DRAM_BASE), RISCV_ERROR);
+            assert_eq!(riscv_machine_exit_code(machine), -1);
+            riscv_machine_free(machine);
+            assert_eq!(
+                riscv_machine_step(ptr::null_mut(), 1, &mut taken),
+                RISCV_POISONED
+            );
+        }
+    }
+}
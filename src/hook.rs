@@ -0,0 +1,365 @@
+//! Hooks observing every executed instruction, e.g. for tracing, coverage or comparing against
+//! another simulator, the memory accesses and the changes of the privileged state.
+
+use std::{cell::RefCell, fmt, io::Write, rc::Rc};
+
+use crate::{
+    cpu::{Cpu, CpuStatus},
+    register::{
+        csrs::Csrs,
+        xs::{Xs, ABI_NAMES},
+    },
+    trap::{Exception, Interrupt, Trap},
+    PrivilegeMode, RegT,
+};
+
+pub use crate::mmu::{AccessContext, AccessType};
+
+/// What to do instead of executing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    /// Move pc past the instruction without executing it.
+    Skip,
+    /// Raise the exception as if the instruction did.
+    Raise(Exception),
+}
+
+/// Registered with `Cpu::add_hook`. Interrupts are taken before the hooks see the instruction.
+pub trait ExecHook {
+    /// Called before the instruction word `code` at `pc` is executed. The first hook returning an
+    /// action overrides the execution, later hooks aren't asked.
+    fn before(&mut self, _pc: RegT, _code: u32) -> Option<HookAction> {
+        None
+    }
+
+    /// Called after the instruction at `pc` was executed or overridden.
+    fn after(&mut self, _pc: RegT, _state: &CpuStatus, _result: Result<(), Exception>) {}
+
+    /// Called after `after` with the whole hart, for the hooks that need more than the state,
+    /// e.g. to read guest memory with `Mmu::peek`.
+    fn observe(&mut self, _cpu: &Cpu) {}
+}
+
+/// Serves ECALL in place of the execution environment, e.g. the Linux syscalls of user-mode
+/// emulation. Registered with `Cpu::set_ecall_handler`.
+pub trait EcallHandler {
+    /// Called for the ECALL at pc instead of raising an environment call exception. The handler
+    /// is responsible for moving pc past the ECALL.
+    fn ecall(&mut self, cpu: &mut Cpu) -> Result<(), Exception>;
+}
+
+/// Why the hart entered a trap handler or returned from one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Trap(Trap),
+    Mret,
+    Sret,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transition::Trap(trap) => write!(f, "{}", trap),
+            Transition::Mret => write!(f, "mret"),
+            Transition::Sret => write!(f, "sret"),
+        }
+    }
+}
+
+/// A change of the privileged state, reported to the `EventHook`s in the order it happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuEvent {
+    /// A CSR instruction at `pc` wrote `csr`, `new` is the legalized value it holds afterwards.
+    /// The CSRs a trap or an xRET updates are reported as well if their value changed, `pc` is
+    /// then the pc the trap was taken at or the xRET.
+    CsrWrite {
+        pc: RegT,
+        csr: u16,
+        old: RegT,
+        new: RegT,
+    },
+    /// `interrupt` is taken in `target`, interrupting the instruction at `epc`. The CSR writes and
+    /// the `Privilege` event of the trap follow.
+    Interrupt {
+        interrupt: Interrupt,
+        target: PrivilegeMode,
+        epc: RegT,
+    },
+    /// The hart went from `from` to `to`, continuing at `pc`. Reported for every trap and xRET,
+    /// even if the privilege stays the same.
+    Privilege {
+        from: PrivilegeMode,
+        to: PrivilegeMode,
+        cause: Transition,
+        pc: RegT,
+    },
+}
+
+impl fmt::Display for CpuEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CpuEvent::CsrWrite { pc, csr, old, new } => {
+                write!(f, "{:#018x}: csrw ", pc)?;
+                match Csrs::name(csr) {
+                    Some(name) => write!(f, "{}", name)?,
+                    None => write!(f, "{:#x}", csr)?,
+                }
+                write!(f, " {:#x} -> {:#x}", old, new)
+            }
+            CpuEvent::Interrupt {
+                interrupt,
+                target,
+                epc,
+            } => write!(f, "{:#018x}: {} to {:?}", epc, interrupt, target),
+            CpuEvent::Privilege {
+                from,
+                to,
+                cause,
+                pc,
+            } => write!(f, "{:#018x}: {}, {:?} -> {:?}", pc, cause, from, to),
+        }
+    }
+}
+
+/// Registered with `Cpu::add_event_hook`. Unlike `ExecHook` it doesn't slow down the execution of
+/// the instructions that don't touch the privileged state.
+pub trait EventHook {
+    fn event(&mut self, event: &CpuEvent);
+}
+
+/// Collects the events, e.g. for a test to assert on the sequence.
+impl EventHook for Rc<RefCell<Vec<CpuEvent>>> {
+    fn event(&mut self, event: &CpuEvent) {
+        self.borrow_mut().push(*event);
+    }
+}
+
+/// Registered with `Cpu::add_access_hook`. Sees every load, store and AMO of the guest once it's
+/// translated and allowed by PMP, and the fetch of every executed instruction. Accesses made for
+/// the host, e.g. by the monitor, aren't passed on.
+pub trait AccessHook {
+    fn access(&mut self, ctx: &AccessContext);
+}
+
+/// Collects the accesses, e.g. for a test to assert on the addresses.
+impl AccessHook for Rc<RefCell<Vec<AccessContext>>> {
+    fn access(&mut self, ctx: &AccessContext) {
+        self.borrow_mut().push(*ctx);
+    }
+}
+
+/// Writes every event on a line of its own.
+pub struct EventLog<W: Write> {
+    out: W,
+}
+
+impl<W: Write> EventLog<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> EventHook for EventLog<W> {
+    fn event(&mut self, event: &CpuEvent) {
+        // A broken trace output shouldn't stop the guest.
+        let _ = writeln!(self.out, "{}", event);
+    }
+}
+
+/// Writes the pc and instruction word of every executed instruction, followed by the registers
+/// it changed and the exception if it raised one. The first line also lists the registers that
+/// already held a value.
+pub struct Tracer<W: Write> {
+    out: W,
+    /// The registers after the previous instruction.
+    xs: Xs,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, xs: Xs::new() }
+    }
+}
+
+impl<W: Write> ExecHook for Tracer<W> {
+    fn before(&mut self, pc: RegT, code: u32) -> Option<HookAction> {
+        // A broken trace output shouldn't stop the guest.
+        let _ = write!(self.out, "{:#018x}: {:#010x}", pc, code);
+        None
+    }
+
+    fn after(&mut self, _pc: RegT, state: &CpuStatus, result: Result<(), Exception>) {
+        for (id, _, new) in Xs::diff(&self.xs, &state.xs) {
+            let _ = write!(self.out, " {}={:#x}", ABI_NAMES[id as usize], new);
+        }
+        self.xs.clone_from(&state.xs);
+        let _ = match result {
+            Ok(()) => writeln!(self.out),
+            Err(e) => writeln!(self.out, " -> {:?}", e),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{cpu::Cpu, device::DRAM_BASE, register::mip, testing::*, trap::Exception, XLen};
+
+    /// Counts the executed instructions by major opcode.
+    struct OpcodeCounter(Rc<RefCell<BTreeMap<u32, usize>>>);
+
+    impl ExecHook for OpcodeCounter {
+        fn before(&mut self, _pc: RegT, code: u32) -> Option<HookAction> {
+            *self.0.borrow_mut().entry(code & 0x7f).or_default() += 1;
+            None
+        }
+    }
+
+    /// Overrides the instruction at `pc`.
+    struct Override {
+        pc: RegT,
+        action: HookAction,
+    }
+
+    impl ExecHook for Override {
+        fn before(&mut self, pc: RegT, _code: u32) -> Option<HookAction> {
+            (pc == self.pc).then_some(self.action)
+        }
+    }
+
+    #[test]
+    fn hooks_see_every_executed_instruction() {
+        let program = [
+            addi(10, 0, 3),
+            addi(10, 10, -1),
+            b_type(-4, 0, 10, 1),
+            lui(11, 1),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        let counts = Rc::new(RefCell::new(BTreeMap::new()));
+        cpu.add_hook(Box::new(OpcodeCounter(counts.clone())));
+        cpu.run_until_ebreak(20);
+        assert_eq!(cpu.state.xs.reg(11), 0x1000);
+        // One addi before the loop and three turns of addi and bne.
+        let expected = BTreeMap::from([(0x13, 4), (0x37, 1), (0x63, 3)]);
+        assert_eq!(*counts.borrow(), expected);
+    }
+
+    #[test]
+    fn hook_actions_override_the_instruction() {
+        let program = [addi(10, 0, 1), addi(11, 0, 2), EBREAK];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.add_hook(Box::new(Override {
+            pc: DRAM_BASE,
+            action: HookAction::Skip,
+        }));
+        cpu.run_until_ebreak(10);
+        assert_eq!((cpu.state.xs.reg(10), cpu.state.xs.reg(11)), (0, 2));
+
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        cpu.add_hook(Box::new(Override {
+            pc: DRAM_BASE + 4,
+            action: HookAction::Raise(Exception::Breakpoint),
+        }));
+        cpu.one_step();
+        cpu.one_step();
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.csrs.mcause(), 3);
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE + 4);
+        assert_eq!(cpu.state.xs.reg(11), 0);
+    }
+
+    #[test]
+    fn tracer_writes_pcs_and_exceptions() {
+        let program = [addi(10, 0, 7), sw(10, 0, 0x10), EBREAK];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        let out = SharedOutput::default();
+        cpu.add_hook(Box::new(Tracer::new(out.clone())));
+        cpu.one_step();
+        cpu.one_step();
+        let trace = String::from_utf8(out.contents()).unwrap();
+        // The first line also shows the stack pointer set up before.
+        let sp = cpu.state.xs.get_by_name("sp").unwrap();
+        let first = format!(
+            "{:#018x}: {:#010x} sp={:#x} a0=0x7",
+            DRAM_BASE, program[0], sp
+        );
+        let second = format!(
+            "{:#018x}: {:#010x} -> StoreFault(16)",
+            DRAM_BASE + 4,
+            program[1]
+        );
+        assert_eq!(trace.lines().collect::<Vec<_>>(), [first, second]);
+    }
+
+    #[test]
+    fn a_delegated_timer_interrupt_switches_to_supervisor() {
+        const SSTATUS_SIE: RegT = 1 << 1;
+        const SSTATUS_SPIE: RegT = 1 << 5;
+        let mut cpu = Cpu::with_program(XLen::X64, &[NOP, NOP]);
+        cpu.state.csrs.set_mideleg(mip::STIP);
+        cpu.state.csrs.set_mie(mip::STIP);
+        cpu.state.csrs.set_stvec(DRAM_BASE + 0x100);
+        cpu.state.csrs.set_sstatus(SSTATUS_SIE);
+        cpu.state.csrs.set_mip(mip::STIP);
+        cpu.state.privilege = PrivilegeMode::User;
+        let events = Rc::new(RefCell::new(Vec::new()));
+        cpu.add_event_hook(Box::new(events.clone()));
+        cpu.one_step();
+
+        let cause = Trap::Interrupt(Interrupt::SupervisorTimer).cause_code(XLen::X64);
+        let write = |csr, old, new| CpuEvent::CsrWrite {
+            pc: DRAM_BASE,
+            csr,
+            old,
+            new,
+        };
+        // stval stays zero, it isn't reported.
+        let expected = [
+            CpuEvent::Interrupt {
+                interrupt: Interrupt::SupervisorTimer,
+                target: PrivilegeMode::Supervisor,
+                epc: DRAM_BASE,
+            },
+            write(0x141, 0, DRAM_BASE),
+            write(0x142, 0, cause),
+            write(0x100, SSTATUS_SIE, SSTATUS_SPIE),
+            CpuEvent::Privilege {
+                from: PrivilegeMode::User,
+                to: PrivilegeMode::Supervisor,
+                cause: Transition::Trap(Trap::Interrupt(Interrupt::SupervisorTimer)),
+                pc: DRAM_BASE + 0x100,
+            },
+        ];
+        assert_eq!(*events.borrow(), expected);
+    }
+
+    #[test]
+    fn csr_instructions_and_mret_are_logged() {
+        const MSTATUS_MPIE: RegT = 1 << 7;
+        let program = [addi(10, 0, 0x5a), csrrw(0, 0x340, 10), MRET, NOP];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_mepc(DRAM_BASE + 12);
+        let out = SharedOutput::default();
+        cpu.add_event_hook(Box::new(EventLog::new(out.clone())));
+        for _ in 0..3 {
+            cpu.one_step();
+        }
+        assert_eq!(cpu.state.privilege, PrivilegeMode::User);
+        let log = String::from_utf8(out.contents()).unwrap();
+        let expected = [
+            format!("{:#018x}: csrw mscratch 0x0 -> 0x5a", DRAM_BASE + 4),
+            format!(
+                "{:#018x}: csrw mstatus 0x0 -> {:#x}",
+                DRAM_BASE + 8,
+                MSTATUS_MPIE
+            ),
+            format!("{:#018x}: mret, Machine -> User", DRAM_BASE + 12),
+        ];
+        assert_eq!(log.lines().collect::<Vec<_>>(), expected);
+    }
+}
@@ -0,0 +1,843 @@
+//! IEEE 754 arithmetic with the RISC-V rounding modes and exception flags. An operation is first
+//! evaluated in f64 as a result `r` plus the error `e` of that result, `r + e` being the exact value
+//! (or close enough to tell its rounding), which is then rounded to the destination format. Double
+//! precision results too small for that are computed from scaled operands, the exact value then
+//! being `(r + e) * 2^scale`.
+
+use crate::{cpu::Cpu, trap::Exception};
+
+/// Invalid operation.
+pub const NV: u8 = 0x10;
+/// Divide by zero.
+pub const DZ: u8 = 0x08;
+/// Overflow.
+pub const OF: u8 = 0x04;
+/// Underflow.
+pub const UF: u8 = 0x02;
+/// Inexact.
+pub const NX: u8 = 0x01;
+
+/// Operands and results below this magnitude are scaled, the error of a result may not be
+/// representable otherwise.
+const TINY: f64 = f64::MIN_POSITIVE * (1u64 << 54) as f64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even.
+    Rne,
+    /// Round towards zero.
+    Rtz,
+    /// Round down (towards -inf).
+    Rdn,
+    /// Round up (towards +inf).
+    Rup,
+    /// Round to nearest, ties to max magnitude.
+    Rmm,
+}
+
+impl RoundingMode {
+    /// Resolves the rm field of an instruction, 7 selects the mode in frm. Reserved modes are
+    /// illegal.
+    pub fn new(cpu: &Cpu, rm: u32) -> Result<Self, Exception> {
+        let rm = if rm == 7 {
+            cpu.state.csrs.csr(0x002) as u32
+        } else {
+            rm
+        };
+        match rm {
+            0 => Ok(RoundingMode::Rne),
+            1 => Ok(RoundingMode::Rtz),
+            2 => Ok(RoundingMode::Rdn),
+            3 => Ok(RoundingMode::Rup),
+            4 => Ok(RoundingMode::Rmm),
+            _ => Err(Exception::IllegalInstruction(0)),
+        }
+    }
+
+    /// Returns true if a value of the sign `negative` between two representable magnitudes
+    /// rounds to the larger one, for the modes that don't round to nearest.
+    fn rounds_away(self, negative: bool) -> bool {
+        match self {
+            RoundingMode::Rup => !negative,
+            RoundingMode::Rdn => negative,
+            _ => false,
+        }
+    }
+
+    /// Whether a magnitude between `lo` and `lo + 1` rounds up to `lo + 1`. `d` is its distance
+    /// from the midpoint, `e` breaks a tie of `d`.
+    fn rounds_up(self, negative: bool, d: f64, e: f64, lo_odd: bool) -> bool {
+        match self {
+            RoundingMode::Rne | RoundingMode::Rmm => {
+                let d = if d != 0.0 { d } else { e };
+                if d != 0.0 {
+                    d > 0.0
+                } else {
+                    self == RoundingMode::Rmm || lo_odd
+                }
+            }
+            _ => self.rounds_away(negative),
+        }
+    }
+
+    /// The largest magnitude an overflowing result rounds to.
+    fn overflows_to_infinity(self, negative: bool) -> bool {
+        match self {
+            RoundingMode::Rne | RoundingMode::Rmm => true,
+            _ => self.rounds_away(negative),
+        }
+    }
+
+    fn integral(self, value: f64) -> f64 {
+        match self {
+            RoundingMode::Rne => value.round_ties_even(),
+            RoundingMode::Rtz => value.trunc(),
+            RoundingMode::Rdn => value.floor(),
+            RoundingMode::Rup => value.ceil(),
+            RoundingMode::Rmm => value.round(),
+        }
+    }
+}
+
+/// A floating-point format the instructions operate on.
+pub trait Float: Copy {
+    const CANONICAL_NAN: Self;
+    /// The smallest positive normal value.
+    const MIN_POSITIVE: f64;
+
+    fn to_f64(self) -> f64;
+
+    /// Converts a value known to be representable.
+    fn exact(value: f64) -> Self;
+
+    fn is_nan(self) -> bool;
+
+    fn is_snan(self) -> bool;
+
+    /// Rounds `(r + e) * 2^scale` to this format.
+    fn round(r: f64, e: f64, scale: i32, rm: RoundingMode, flags: &mut u8) -> Self;
+}
+
+impl Float for f32 {
+    const CANONICAL_NAN: Self = f32::from_bits(0x7fc0_0000);
+    const MIN_POSITIVE: f64 = f32::MIN_POSITIVE as f64;
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn exact(value: f64) -> Self {
+        value as f32
+    }
+
+    fn is_nan(self) -> bool {
+        self.is_nan()
+    }
+
+    fn is_snan(self) -> bool {
+        self.is_nan() && self.to_bits() & 0x40_0000 == 0
+    }
+
+    fn round(r: f64, e: f64, scale: i32, rm: RoundingMode, flags: &mut u8) -> Self {
+        // Scaled single precision results are still within the f64 range.
+        let (r, e) = (ldexp(r, scale), ldexp(e, scale));
+        let t = r as f32;
+        if t as f64 == r && e == 0.0 {
+            return t;
+        }
+        *flags |= NX;
+        let negative = r.is_sign_negative();
+        let (m, e) = if negative { (-r, -e) } else { (r, e) };
+        // 2^128, the magnitude after f32::MAX if the exponent were unbounded.
+        let limit = 2f64.powi(128);
+        if m >= limit {
+            *flags |= OF;
+            return overflow(rm, negative, f32::MAX);
+        }
+        if is_tiny(m, e, <Self as Float>::MIN_POSITIVE, 24, rm, negative) {
+            *flags |= UF;
+        }
+        // The representable magnitudes around the exact one.
+        let t = (m as f32) as f64;
+        let (lo, hi) = if t > m || (t == m && e < 0.0) {
+            ((t as f32).next_down() as f64, t)
+        } else {
+            let hi = (t as f32).next_up() as f64;
+            (t, if hi.is_infinite() { limit } else { hi })
+        };
+        // m and the midpoint are different f64 values unless equal, e breaks the tie.
+        let lo_odd = (lo as f32).to_bits() & 1 == 1;
+        let m = if rm.rounds_up(negative, m - (lo + hi) / 2.0, e, lo_odd) {
+            hi
+        } else {
+            lo
+        };
+        if m == limit {
+            *flags |= OF;
+        }
+        let value = m as f32;
+        if negative {
+            -value
+        } else {
+            value
+        }
+    }
+}
+
+impl Float for f64 {
+    const CANONICAL_NAN: Self = f64::from_bits(0x7ff8_0000_0000_0000);
+    const MIN_POSITIVE: f64 = f64::MIN_POSITIVE;
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    fn exact(value: f64) -> Self {
+        value
+    }
+
+    fn is_nan(self) -> bool {
+        self.is_nan()
+    }
+
+    fn is_snan(self) -> bool {
+        self.is_nan() && self.to_bits() & 0x8_0000_0000_0000 == 0
+    }
+
+    fn round(r: f64, e: f64, scale: i32, rm: RoundingMode, flags: &mut u8) -> Self {
+        if e == 0.0 && (scale == 0 || r == 0.0) {
+            return r;
+        }
+        let negative = r.is_sign_negative();
+        let (m, e) = if negative { (-r, -e) } else { (r, e) };
+        if m != 0.0 && exponent(m) + scale < -1076 {
+            // Below half the smallest subnormal, where none of the modes round to nearest.
+            *flags |= NX | UF;
+            let value = f64::from_bits(rm.rounds_away(negative) as u64);
+            return if negative { -value } else { value };
+        }
+        // The smallest normal magnitude in units of m.
+        let min = ldexp(f64::MIN_POSITIVE, -scale);
+        let value = if m > min || (m == min && e >= 0.0) {
+            // m is the nearest double already, the other modes may need the next one.
+            let m = if e == 0.0 {
+                m
+            } else {
+                *flags |= NX;
+                match rm {
+                    RoundingMode::Rne => m,
+                    RoundingMode::Rmm if 2.0 * e == m.next_up() - m => m.next_up(),
+                    RoundingMode::Rmm => m,
+                    _ if rm.rounds_away(negative) => {
+                        if e > 0.0 {
+                            m.next_up()
+                        } else {
+                            m
+                        }
+                    }
+                    _ if e < 0.0 => m.next_down(),
+                    _ => m,
+                }
+            };
+            let value = ldexp(m, scale);
+            if value.is_infinite() {
+                *flags |= OF | NX;
+                return overflow(rm, negative, f64::MAX);
+            }
+            value
+        } else {
+            // Below the normal range, in units of the smallest subnormal.
+            *flags |= NX;
+            if is_tiny(m, e, min, 53, rm, negative) {
+                *flags |= UF;
+            }
+            let q = ldexp(m, 1074 + scale);
+            let mut lo = q.floor();
+            if lo == q {
+                if e == 0.0 {
+                    *flags &= !(NX | UF);
+                    let value = f64::from_bits(lo as u64);
+                    return if negative { -value } else { value };
+                }
+                if e < 0.0 {
+                    lo -= 1.0;
+                }
+            }
+            let up = rm.rounds_up(negative, q - (lo + 0.5), e, lo % 2.0 == 1.0);
+            f64::from_bits(if up { lo + 1.0 } else { lo } as u64)
+        };
+        if negative {
+            -value
+        } else {
+            value
+        }
+    }
+}
+
+/// The value an overflowing result of the sign `negative` rounds to, `max` being the largest
+/// finite one.
+fn overflow<F: Float + std::ops::Neg<Output = F>>(rm: RoundingMode, negative: bool, max: F) -> F {
+    let value = if rm.overflows_to_infinity(negative) {
+        F::exact(f64::INFINITY)
+    } else {
+        max
+    };
+    if negative {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Whether the inexact magnitude `m + e` is tiny, below the smallest normal `min` once rounded to
+/// `p` bits with an unbounded exponent. RISC-V detects tininess after rounding.
+fn is_tiny(m: f64, e: f64, min: f64, p: i32, rm: RoundingMode, negative: bool) -> bool {
+    if m > min || (m == min && e >= 0.0) {
+        return false;
+    }
+    // The distance below min, and the step to the previous magnitude of p bits.
+    let d = min - m;
+    let step = ldexp(min, -p);
+    match rm {
+        RoundingMode::Rne | RoundingMode::Rmm => d - step / 2.0 > e,
+        _ if rm.rounds_away(negative) => d - step >= e,
+        _ => true,
+    }
+}
+
+/// `x * 2^n`, exact unless the result is subnormal.
+fn ldexp(mut x: f64, mut n: i32) -> f64 {
+    while n > 1000 {
+        x *= 2f64.powi(1000);
+        n -= 1000;
+    }
+    while n < -1000 {
+        x *= 2f64.powi(-1000);
+        n += 1000;
+    }
+    x * 2f64.powi(n)
+}
+
+/// The exponent of a finite non-zero `x`, so that `x * 2^-exponent` is in [1, 2).
+fn exponent(x: f64) -> i32 {
+    if x.abs() < f64::MIN_POSITIVE {
+        return exponent(x * 2f64.powi(64)) - 64;
+    }
+    ((x.to_bits() >> 52) & 0x7ff) as i32 - 1023
+}
+
+/// Whether an f64 operand or result is too small or large for the error of a result to be
+/// computed directly, zero included.
+fn out_of_range(x: f64) -> bool {
+    !(TINY..=f64::MAX).contains(&x.abs())
+}
+
+/// Moves an inexact error one step towards zero. The rounding only compares an error with powers
+/// of two, which an inexact one must not be mistaken for.
+fn toward_zero(e: f64) -> f64 {
+    f64::from_bits(e.to_bits() - 1)
+}
+
+/// `a + b` as the rounded sum and its exact error.
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    let bb = s - a;
+    (s, (a - (s - bb)) + (b - bb))
+}
+
+/// Evaluates `op` on the arguments as f64 and rounds its result. NaN arguments give the canonical
+/// NaN, an invalid operation on others as well. A result from an infinite argument is exact.
+fn arith<F: Float, const N: usize>(
+    args: [F; N],
+    rm: RoundingMode,
+    op: impl FnOnce([f64; N]) -> (f64, f64, i32),
+) -> (F, u8) {
+    let mut flags = 0;
+    if args.iter().any(|arg| arg.is_snan()) {
+        flags |= NV;
+    }
+    if args.iter().any(|arg| arg.is_nan()) {
+        return (F::CANONICAL_NAN, flags);
+    }
+    let args = args.map(F::to_f64);
+    let (r, e, scale) = op(args);
+    if r.is_nan() {
+        return (F::CANONICAL_NAN, flags | NV);
+    }
+    if args.iter().any(|arg| arg.is_infinite()) {
+        return (F::exact(r), flags);
+    }
+    (F::round(r, e, scale, rm, &mut flags), flags)
+}
+
+/// An exact zero sum of operands with different signs is -0 when rounding down, +0 otherwise.
+fn zero_sum_sign(r: f64, negative: bool, rm: RoundingMode) -> f64 {
+    if r == 0.0 && negative && rm == RoundingMode::Rdn {
+        -0.0
+    } else {
+        r
+    }
+}
+
+/// `a + b` as `(r + e) * 2^scale`, halving an overflowing sum.
+fn sum(a: f64, b: f64) -> (f64, f64, i32) {
+    let (r, e) = two_sum(a, b);
+    if r.is_infinite() && a.is_finite() && b.is_finite() {
+        let (r, e) = two_sum(a / 2.0, b / 2.0);
+        return (r, e, 1);
+    }
+    (r, e, 0)
+}
+
+pub fn add<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u8) {
+    arith([a, b], rm, |[a, b]| {
+        let (r, e, scale) = sum(a, b);
+        let negative = a.is_sign_negative() || b.is_sign_negative();
+        (zero_sum_sign(r, negative, rm), e, scale)
+    })
+}
+
+pub fn sub<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u8) {
+    arith([a, b], rm, |[a, b]| {
+        let (r, e, scale) = sum(a, -b);
+        let negative = a.is_sign_negative() || b.is_sign_positive();
+        (zero_sum_sign(r, negative, rm), e, scale)
+    })
+}
+
+pub fn mul<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u8) {
+    arith([a, b], rm, |[a, b]| {
+        let r = a * b;
+        if !a.is_finite() || !b.is_finite() || a == 0.0 || b == 0.0 {
+            return (r, 0.0, 0);
+        }
+        if [a, b, r].iter().copied().any(out_of_range) {
+            let (ea, eb) = (exponent(a), exponent(b));
+            let (a, b) = (ldexp(a, -ea), ldexp(b, -eb));
+            let r = a * b;
+            return (r, a.mul_add(b, -r), ea + eb);
+        }
+        (r, a.mul_add(b, -r), 0)
+    })
+}
+
+pub fn div<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u8) {
+    let (value, mut flags) = arith([a, b], rm, |[a, b]| {
+        let r = a / b;
+        if !a.is_finite() || !b.is_finite() || a == 0.0 || b == 0.0 {
+            return (r, 0.0, 0);
+        }
+        let quotient = |a: f64, b: f64| {
+            let r = a / b;
+            let residual = r.mul_add(b, -a);
+            if residual == 0.0 {
+                return (r, 0.0);
+            }
+            let e = -residual / b;
+            if e.mul_add(b, residual) != 0.0 {
+                (r, toward_zero(e))
+            } else {
+                (r, e)
+            }
+        };
+        if [a, b, r].iter().copied().any(out_of_range) {
+            let (ea, eb) = (exponent(a), exponent(b));
+            let (r, e) = quotient(ldexp(a, -ea), ldexp(b, -eb));
+            return (r, e, ea - eb);
+        }
+        let (r, e) = quotient(a, b);
+        (r, e, 0)
+    });
+    let (a, b) = (a.to_f64(), b.to_f64());
+    if b == 0.0 && a.is_finite() && a != 0.0 {
+        flags |= DZ;
+    }
+    (value, flags)
+}
+
+pub fn sqrt<F: Float>(a: F, rm: RoundingMode) -> (F, u8) {
+    arith([a], rm, |[a]| {
+        if !a.is_finite() || a <= 0.0 {
+            return (a.sqrt(), 0.0, 0);
+        }
+        // An even power of two keeps the root exact.
+        let (a, scale) = if a < TINY {
+            (ldexp(a, 1200), -600)
+        } else {
+            (a, 0)
+        };
+        let r = a.sqrt();
+        let residual = r.mul_add(r, -a);
+        let e = if residual == 0.0 {
+            0.0
+        } else {
+            toward_zero(-residual / (2.0 * r))
+        };
+        (r, e, scale)
+    })
+}
+
+/// `a * b + c` with a single rounding.
+pub fn mul_add<F: Float>(a: F, b: F, c: F, rm: RoundingMode) -> (F, u8) {
+    arith([a, b, c], rm, |[a, b, c]| {
+        let negative = (a * b).is_sign_negative() || c.is_sign_negative();
+        if [a, b, c].iter().any(|x| x.is_infinite()) || a == 0.0 || b == 0.0 {
+            return (zero_sum_sign(a.mul_add(b, c), negative, rm), 0.0, 0);
+        }
+        let (r, e, scale) = fused(a, b, c);
+        (zero_sum_sign(r, negative, rm), e, scale)
+    })
+}
+
+/// `a * b + c` for finite operands and a non-zero product as `(r + e) * 2^scale`, summing the
+/// 106 bit product and the addend as integers. Bits of the smaller shifted out are kept as a
+/// sticky bit, below those of the larger that decide the rounding.
+fn fused(a: f64, b: f64, c: f64) -> (f64, f64, i32) {
+    // The mantissa and exponent of a finite value.
+    let split = |x: f64| {
+        let bits = x.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i32;
+        let fraction = (bits & ((1 << 52) - 1)) as i128;
+        let (m, e) = if exponent == 0 {
+            (fraction, -1074)
+        } else {
+            (fraction | 1 << 52, exponent - 1075)
+        };
+        (if x.is_sign_negative() { -m } else { m }, e)
+    };
+    // Shifts the highest bit to bit 124, so that sums don't overflow when converted to f64.
+    let normalize = |(m, e): (i128, i32)| {
+        let shift = m.unsigned_abs().leading_zeros() as i32 - 3;
+        (m << shift, e - shift)
+    };
+    let ((ma, ea), (mb, eb)) = (split(a), split(b));
+    let (p, ep) = normalize((ma * mb, ea + eb));
+    let (n, scale) = if c == 0.0 {
+        (p, ep)
+    } else {
+        let (c, ec) = normalize(split(c));
+        let ((big, e), (small, d)) = if ep >= ec {
+            ((p, ep), (c, ep - ec))
+        } else {
+            ((c, ec), (p, ec - ep))
+        };
+        let magnitude = small.unsigned_abs();
+        let shifted = if d >= 128 {
+            (magnitude != 0) as u128
+        } else {
+            magnitude >> d | (magnitude & ((1 << d) - 1) != 0) as u128
+        } as i128;
+        (big + if small < 0 { -shifted } else { shifted }, e)
+    };
+    let r = n as f64;
+    // The error rounded to odd, so that it's never mistaken for a power of two.
+    let rest = n - r as i128;
+    let magnitude = rest.unsigned_abs();
+    let shift = (128 - magnitude.leading_zeros() as i32 - 53).max(0);
+    let odd = magnitude >> shift | (magnitude & ((1 << shift) - 1) != 0) as u128;
+    let e = ldexp(odd as f64, shift);
+    (r, if rest < 0 { -e } else { e }, scale)
+}
+
+/// Converts between formats, rounding to a narrower one.
+pub fn convert<F: Float, T: Float>(value: F, rm: RoundingMode) -> (T, u8) {
+    if value.is_nan() {
+        return (T::CANONICAL_NAN, if value.is_snan() { NV } else { 0 });
+    }
+    let value = value.to_f64();
+    if value.is_infinite() {
+        return (T::exact(value), 0);
+    }
+    let mut flags = 0;
+    (T::round(value, 0.0, 0, rm, &mut flags), flags)
+}
+
+/// The minimum or maximum, a NaN is only returned if both are NaN. -0 is less than +0.
+pub fn min_max<F: Float>(a: F, b: F, max: bool) -> (F, u8) {
+    let flags = if a.is_snan() || b.is_snan() { NV } else { 0 };
+    let value = match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        _ => {
+            let (x, y) = (a.to_f64(), b.to_f64());
+            let a_less = x < y || (x == y && x.is_sign_negative());
+            if a_less != max {
+                a
+            } else {
+                b
+            }
+        }
+    };
+    (value, flags)
+}
+
+/// Compares `a` and `b` with `cmp`. Any NaN makes the comparison false, and invalid if it's
+/// signaling or `quiet` is false.
+pub fn compare<F: Float>(a: F, b: F, quiet: bool, cmp: fn(&f64, &f64) -> bool) -> (bool, u8) {
+    if a.is_nan() || b.is_nan() {
+        let invalid = !quiet || a.is_snan() || b.is_snan();
+        return (false, if invalid { NV } else { 0 });
+    }
+    (cmp(&a.to_f64(), &b.to_f64()), 0)
+}
+
+/// Converts to an integer of `bits` bits, saturating out of range values and NaN.
+pub fn to_int<F: Float>(value: F, bits: u32, signed: bool, rm: RoundingMode) -> (i128, u8) {
+    let (min, max) = if signed {
+        (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+    } else {
+        (0, (1i128 << bits) - 1)
+    };
+    if value.is_nan() {
+        return (max, NV);
+    }
+    let value = value.to_f64();
+    let integral = rm.integral(value);
+    if integral < min as f64 {
+        (min, NV)
+    } else if integral >= (max + 1) as f64 {
+        (max, NV)
+    } else if integral != value {
+        (integral as i128, NX)
+    } else {
+        (integral as i128, 0)
+    }
+}
+
+pub fn from_int<F: Float>(value: i128, rm: RoundingMode) -> (F, u8) {
+    let r = value as f64;
+    let e = (value - r as i128) as f64;
+    let mut flags = 0;
+    (F::round(r, e, 0, rm, &mut flags), flags)
+}
+
+/// The fclass mask of `value`.
+pub fn classify<F: Float>(value: F) -> u32 {
+    let v = value.to_f64();
+    let negative = v.is_sign_negative();
+    let bit = if value.is_snan() {
+        8
+    } else if value.is_nan() {
+        9
+    } else if v.is_infinite() {
+        if negative {
+            0
+        } else {
+            7
+        }
+    } else if v == 0.0 {
+        if negative {
+            3
+        } else {
+            4
+        }
+    } else if v.abs() < F::MIN_POSITIVE {
+        if negative {
+            2
+        } else {
+            5
+        }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RoundingMode::*;
+
+    const MODES: [RoundingMode; 5] = [Rne, Rtz, Rdn, Rup, Rmm];
+
+    /// The f32 right above `x`.
+    fn up(x: f32) -> f32 {
+        x.next_up()
+    }
+
+    #[test]
+    fn ties_and_inexact_sums_round_in_every_mode() {
+        // 2^-24 is half an ulp of 1.0.
+        let half_ulp = 2f32.powi(-24);
+        let ties = [1.0, 1.0, 1.0, up(1.0), up(1.0)];
+        for (rm, expected) in MODES.iter().zip(ties) {
+            assert_eq!(add(1.0f32, half_ulp, *rm), (expected, NX), "{:?}", rm);
+        }
+        let negative_ties = [-1.0, -1.0, -up(1.0), -1.0, -up(1.0)];
+        for (rm, expected) in MODES.iter().zip(negative_ties) {
+            assert_eq!(add(-1.0f32, -half_ulp, *rm), (expected, NX), "{:?}", rm);
+        }
+        // 1/3 is below the midpoint of its neighbours 0x3eaaaaaa and 0x3eaaaaab.
+        let third = [
+            0x3eaa_aaab,
+            0x3eaa_aaaa,
+            0x3eaa_aaaa,
+            0x3eaa_aaab,
+            0x3eaa_aaab,
+        ];
+        for (rm, expected) in MODES.iter().zip(third) {
+            let (value, flags) = div(1.0f32, 3.0, *rm);
+            assert_eq!((value.to_bits(), flags), (expected, NX), "{:?}", rm);
+        }
+        assert_eq!(add(1.0f64, 2f64.powi(-53), Rne), (1.0, NX));
+        assert_eq!(add(1.0f64, 2f64.powi(-53), Rup), (1.0f64.next_up(), NX));
+        assert_eq!(add(1.5f32, 0.25, Rne), (1.75, 0));
+    }
+
+    #[test]
+    fn invalid_operations_give_the_canonical_nan() {
+        let nan = f32::CANONICAL_NAN.to_bits();
+        let (value, flags) = div(0.0f32, 0.0, Rne);
+        assert_eq!((value.to_bits(), flags), (nan, NV));
+        let (value, flags) = sqrt(-1.0f32, Rne);
+        assert_eq!((value.to_bits(), flags), (nan, NV));
+        let (value, flags) = sub(f32::INFINITY, f32::INFINITY, Rne);
+        assert_eq!((value.to_bits(), flags), (nan, NV));
+        // A quiet NaN operand propagates without a flag, a signaling one is invalid.
+        let (value, flags) = add(f32::from_bits(0x7fc0_1234), 1.0, Rne);
+        assert_eq!((value.to_bits(), flags), (nan, 0));
+        let (value, flags) = add(f32::from_bits(0x7f80_0001), 1.0, Rne);
+        assert_eq!((value.to_bits(), flags), (nan, NV));
+        assert_eq!(div(1.0f32, 0.0, Rne), (f32::INFINITY, DZ));
+        assert_eq!(div(-1.0f64, 0.0, Rne), (f64::NEG_INFINITY, DZ));
+    }
+
+    #[test]
+    fn overflow_and_underflow_follow_the_mode() {
+        assert_eq!(mul(f32::MAX, 2.0, Rne), (f32::INFINITY, OF | NX));
+        assert_eq!(mul(f32::MAX, 2.0, Rtz), (f32::MAX, OF | NX));
+        assert_eq!(mul(f32::MAX, -2.0, Rup), (-f32::MAX, OF | NX));
+        assert_eq!(mul(f64::MAX, 2.0, Rdn), (f64::MAX, OF | NX));
+        // 2^-126 - 2^-150 rounds up to the smallest normal, but is tiny after rounding to 24
+        // bits with an unbounded exponent.
+        let below_one = 1.0f32.next_down();
+        assert_eq!(
+            mul(f32::MIN_POSITIVE, below_one, Rne),
+            (f32::MIN_POSITIVE, UF | NX)
+        );
+        // Exact subnormal results don't underflow.
+        assert_eq!(
+            mul(f32::MIN_POSITIVE, 0.5, Rne),
+            (f32::MIN_POSITIVE / 2.0, 0)
+        );
+        let tiny = f64::from_bits(1);
+        assert_eq!(mul(tiny, 0.5, Rne), (0.0, UF | NX));
+        assert_eq!(mul(tiny, 0.5, Rup), (tiny, UF | NX));
+    }
+
+    #[test]
+    fn fused_multiply_add_rounds_once() {
+        // (1 + 2^-12)^2 = 1 + 2^-11 + 2^-24, whose last term a separate multiply rounds away.
+        let a = 1.0 + 2f32.powi(-12);
+        let c = -(1.0 + 2f32.powi(-11));
+        assert_eq!(mul_add(a, a, c, Rne), (2f32.powi(-24), 0));
+        assert_eq!(add(mul(a, a, Rne).0, c, Rne), (0.0, 0));
+        // An exact zero sum is -0 only when rounding down.
+        assert!(mul_add(1.0f64, 1.0, -1.0, Rne).0.is_sign_positive());
+        assert!(mul_add(1.0f64, 1.0, -1.0, Rdn).0.is_sign_negative());
+    }
+
+    #[test]
+    fn conversions_to_integers_saturate() {
+        assert_eq!(to_int(2.5f32, 32, true, Rne), (2, NX));
+        assert_eq!(to_int(2.5f32, 32, true, Rmm), (3, NX));
+        assert_eq!(to_int(-2.5f32, 32, true, Rdn), (-3, NX));
+        assert_eq!(to_int(-1.0f32, 32, false, Rne), (0, NV));
+        assert_eq!(to_int(-0.25f32, 32, false, Rtz), (0, NX));
+        assert_eq!(to_int(3e9f32, 32, true, Rne), (i32::MAX as i128, NV));
+        assert_eq!(to_int(f32::NAN, 64, false, Rne), (u64::MAX as i128, NV));
+        assert_eq!(
+            to_int(f64::NEG_INFINITY, 64, true, Rne),
+            (i64::MIN as i128, NV)
+        );
+        assert_eq!(from_int::<f32>(16_777_217, Rne), (16_777_216.0, NX));
+        assert_eq!(from_int::<f32>(16_777_217, Rup), (16_777_218.0, NX));
+        assert_eq!(from_int::<f64>(-3, Rne), (-3.0, 0));
+        assert_eq!(
+            convert::<f64, f32>(1.0 + 2f64.powi(-30), Rup),
+            (up(1.0), NX)
+        );
+        assert_eq!(convert::<f32, f64>(0.1, Rne), (0.1f32 as f64, 0));
+    }
+
+    #[test]
+    fn min_max_prefer_numbers_and_order_zeros() {
+        assert!(min_max(0.0f32, -0.0, false).0.is_sign_negative());
+        assert!(min_max(-0.0f32, 0.0, true).0.is_sign_positive());
+        assert_eq!(min_max(f32::NAN, 1.0, false), (1.0, 0));
+        assert_eq!(min_max(1.0, f32::from_bits(0x7f80_0001), true), (1.0, NV));
+        let (value, flags) = min_max(f32::NAN, f32::NAN, true);
+        assert_eq!((value.to_bits(), flags), (0x7fc0_0000, 0));
+        assert_eq!(compare(f32::NAN, 1.0, true, f64::eq), (false, 0));
+        assert_eq!(compare(f32::NAN, 1.0, false, f64::lt), (false, NV));
+        assert_eq!(compare(1.0f32, 2.0, false, f64::lt), (true, 0));
+    }
+
+    #[test]
+    fn classify_sets_one_bit_per_class() {
+        let values = [
+            f32::NEG_INFINITY,
+            -1.0,
+            -f32::MIN_POSITIVE / 2.0,
+            -0.0,
+            0.0,
+            f32::MIN_POSITIVE / 2.0,
+            1.0,
+            f32::INFINITY,
+            f32::from_bits(0x7f80_0001),
+            f32::NAN,
+        ];
+        for (bit, value) in values.iter().enumerate() {
+            assert_eq!(classify(*value), 1 << bit, "{:?}", value);
+        }
+        assert_eq!(classify(f64::from_bits(1)), 1 << 5);
+    }
+
+    #[test]
+    fn double_results_past_the_f64_range_are_scaled() {
+        let smallest = f64::from_bits(1);
+        // 2^-1200 is far below the smallest subnormal.
+        assert_eq!(mul(2f64.powi(-600), 2f64.powi(-600), Rne), (0.0, UF | NX));
+        assert_eq!(
+            mul(2f64.powi(-600), 2f64.powi(-600), Rup),
+            (smallest, UF | NX)
+        );
+        // 1.5 units of the smallest subnormal, a tie.
+        let tie = (1.5 * 2f64.powi(-537), 2f64.powi(-537));
+        assert_eq!(mul(tie.0, tie.1, Rne), (2.0 * smallest, UF | NX));
+        assert_eq!(mul(tie.0, tie.1, Rtz), (smallest, UF | NX));
+        assert_eq!(div(f64::MIN_POSITIVE, 2f64.powi(52), Rne), (smallest, 0));
+        assert_eq!(div(f64::MIN_POSITIVE, 3.0, Rne).1, UF | NX);
+        assert_eq!(sqrt(smallest, Rne), (2f64.powi(-537), 0));
+        // The nearest double to sqrt(2) is above it.
+        let root = 2f64.sqrt();
+        assert_eq!(sqrt(2.0, Rup), (root, NX));
+        assert_eq!(sqrt(2.0, Rdn), (root.next_down(), NX));
+        assert_eq!(add(f64::MAX, f64::MAX, Rne), (f64::INFINITY, OF | NX));
+        assert_eq!(add(f64::MAX, f64::MAX, Rtz), (f64::MAX, OF | NX));
+        assert_eq!(sub(f64::MAX, -f64::MAX, Rdn), (f64::MAX, OF | NX));
+    }
+
+    #[test]
+    fn double_fused_multiply_add_keeps_every_bit() {
+        // (1 + 2^-30)^2 - (1 + 2^-29) leaves only the 2^-60 of the 106 bit product.
+        let a = 1.0 + 2f64.powi(-30);
+        let c = -(1.0 + 2f64.powi(-29));
+        assert_eq!(mul_add(a, a, c, Rne), (2f64.powi(-60), 0));
+        // Addend bits far below the product only show as the sticky bit.
+        assert_eq!(mul_add(1.0, 1.0, 2f64.powi(-60), Rne), (1.0, NX));
+        assert_eq!(
+            mul_add(1.0, 1.0, 2f64.powi(-60), Rup),
+            (1.0f64.next_up(), NX)
+        );
+        assert_eq!(
+            mul_add(1.0, 1.0, -2f64.powi(-60), Rtz),
+            (1.0f64.next_down(), NX)
+        );
+        let (value, flags) = mul_add(f64::INFINITY, 0.0, 1.0, Rne);
+        assert_eq!((value.to_bits(), flags), (f64::CANONICAL_NAN.to_bits(), NV));
+    }
+}
@@ -1,8 +1,53 @@
-use crate::{RegT, XLen};
+#[cfg(not(feature = "dyn-insn"))]
+use self::{rva::*, rvb::*, rvd::*, rvf::*, rvi::*, rvm::*, rvzicbo::*};
+#[cfg(not(feature = "dyn-insn"))]
+use crate::{cpu::Cpu, trap::Exception, Executable};
+#[cfg(target_family = "wasm")]
+use crate::{InsnEntry, InsnMeta};
+use crate::{RegT, SRegT, XLen};
 
+mod fp;
 mod rva;
+mod rvb;
+mod rvd;
+mod rvf;
 mod rvi;
 mod rvm;
+mod rvzicbo;
+
+#[cfg(not(feature = "dyn-insn"))]
+insn_enum! {
+    Cpu,
+    Exception,
+    // RV32I/RV64I
+    Lui, Auipc, Jal, Jalr, Beq, Bne, Blt, Bge, Bltu, Bgeu, Lb, Lh, Lw, Lbu, Lhu, Sb, Sh, Sw, Addi,
+    Slti, Sltiu, Xori, Ori, Andi, Slli, Srli, Srai, Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or,
+    And, Fence, FenceTso, Pause, FenceI, Ecall, Ebreak, Csrrw, Csrrs, Csrrc, Csrrwi, Csrrsi,
+    Csrrci, Lwu, Ld, Sd, Addiw, Slliw, Sraiw, Srliw, Addw, Subw, Sllw, Sraw, Sret, Mret, Wfi,
+    SfenceVma,
+    // RV32A
+    LrW, ScW, AmoswapW, AmoaddW, AmoxorW, AmoandW, AmoorW, AmominW, AmomaxW, AmominuW, AmomaxuW,
+    // RV32M/RV64M
+    Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu, Divw, Remw, Remuw, Divuw,
+    // RV32F/RV64F
+    Flw, Fsw, FmaddS, FmsubS, FnmsubS, FnmaddS, FaddS, FsubS, FmulS, FdivS, FsqrtS, FsgnjS,
+    FsgnjnS, FsgnjxS, FminS, FmaxS, FcvtWS, FcvtWuS, FcvtLS, FcvtLuS, FmvXW, FeqS, FltS, FleS,
+    FclassS, FcvtSW, FcvtSWu, FcvtSL, FcvtSLu, FmvWX,
+    // RV32D/RV64D
+    Fld, Fsd, FmaddD, FmsubD, FnmsubD, FnmaddD, FaddD, FsubD, FmulD, FdivD, FsqrtD, FsgnjD,
+    FsgnjnD, FsgnjxD, FminD, FmaxD, FcvtSD, FcvtDS, FeqD, FltD, FleD, FclassD, FcvtWD, FcvtWuD,
+    FcvtLD, FcvtLuD, FcvtDW, FcvtDWu, FcvtDL, FcvtDLu, FmvXD, FmvDX,
+    // Zba/Zbb/Zbs
+    Sh1add, Sh2add, Sh3add, AddUw, Sh1addUw, Sh2addUw, Sh3addUw, SlliUw, Andn, Orn, Xnor, Clz, Ctz,
+    Cpop, Clzw, Ctzw, Cpopw, Max, Maxu, Min, Minu, SextB, SextH, ZextH32, ZextH64, Rol, Ror, Rori,
+    Rolw, Rorw, Roriw, OrcB, Rev8, Bclr, Bclri, Bext, Bexti, Binv, Binvi, Bset, Bseti,
+    // Zicbom/Zicboz
+    CboInval, CboClean, CboFlush, CboZero,
+}
+
+/// Instructions are 4-byte aligned since the C extension isn't supported, so bit 1 of a jump target
+/// must be clear as well.
+pub const INSN_ALIGN_MASK: RegT = 0x3;
 
 pub const fn reg_len() -> usize {
     std::mem::size_of::<RegT>() << 3
@@ -24,6 +69,35 @@ pub fn sext(value: RegT, len: usize) -> RegT {
     }
 }
 
+/// 按 XLEN 把寄存器视为二进制补码。
+fn signed(cpu: &crate::cpu::Cpu, reg: u32) -> SRegT {
+    sext(cpu.state.xs.reg(reg as u8), cpu.xlen.len()) as SRegT
+}
+
+/// Returns true if `code` is an encoding RV32 reserves within an instruction defined on both: the
+/// shifts and rotates by an immediate, e.g. SLLI, with shamt[5] set. Instructions only defined on
+/// RV64 are marked with `#[xlen(64)]` instead.
+pub fn is_rv64_only(code: u32) -> bool {
+    let funct3 = (code >> 12) & 0x7;
+    code & 0x7f == 0x13 && (funct3 == 1 || funct3 == 5) && code & (1 << 25) != 0
+}
+
+/// Returns true if `code` is an encoding RV64 reserves within an instruction defined on both: the
+/// RV32 encoding of REV8. Instructions only defined on RV32 are marked with `#[xlen(32)]` instead.
+pub fn is_rv32_only(code: u32) -> bool {
+    code & 0xfff0707f == 0x69805013
+}
+
+/// Returns the bits of `code` written to xtval when it is illegal. An encoding whose low two bits
+/// aren't 0b11 is 16 bits long, the halfword after it belongs to the next instruction.
+pub fn insn_bits(code: u32) -> RegT {
+    if code & 0b11 == 0b11 {
+        code as RegT
+    } else {
+        (code & 0xffff) as RegT
+    }
+}
+
 impl XLen {
     fn shamt_mask(&self) -> u32 {
         match self {
@@ -32,3 +106,85 @@ impl XLen {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{check_instruction_table, instruction_table, InsnDecoder, XLen, INSN_SLICE};
+
+    #[test]
+    fn every_encoding_decodes_to_its_instruction() {
+        // xorshift64，用来填充掩码之外的位。
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u32
+        };
+        for &xlen in [XLen::X32, XLen::X64].iter() {
+            let decoder = InsnDecoder::new(xlen);
+            for f in INSN_SLICE.iter() {
+                let (match_code, mask, only_xlen, insn_fn) = f();
+                if only_xlen.is_some_and(|only| only != xlen) {
+                    continue;
+                }
+                for &fill in [0, !0, random(), random(), random(), random()].iter() {
+                    let code = match_code | (fill & !mask);
+                    let expected = insn_fn(code).to_string();
+                    let insn = decoder
+                        .decode(code)
+                        .unwrap_or_else(|| panic!("{:#010x} of {} is illegal", code, expected));
+                    assert_eq!(insn.to_string(), expected, "{:#010x} on {:?}", code, xlen);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn special_cases_decode_before_the_general_encoding() {
+        let decoder = InsnDecoder::new(XLen::X64);
+        let name = |code: u32| decoder.decode(code).unwrap().to_string();
+        // FENCE w,0 是 PAUSE，其他的 pred 和 succ 仍是 FENCE。
+        assert_eq!(name(0x0100_000f), "pause");
+        assert_eq!(name(0x0110_000f), "fence w,w");
+        assert_eq!(name(0x0ff0_000f), "fence iorw,iorw");
+        assert_eq!(name(0x0100_008f), "fence w,0");
+        // fm = 1000 且 pred = succ = rw 是 FENCE.TSO，rs1 与 rd 不影响译码。
+        assert_eq!(name(0x8330_000f), "fence.tso");
+        assert_eq!(name(0x8335_050f), "fence.tso");
+        // 保留的 fm 与其他的 pred 和 succ 仍按 FENCE 译码。
+        assert_eq!(name(0x8ff0_000f), "fence iorw,iorw");
+        assert_eq!(name(0x5330_000f), "fence rw,rw");
+        assert_eq!(name(0x0000_000f), "fence 0,0");
+    }
+
+    #[test]
+    fn the_table_describes_every_instruction() {
+        check_instruction_table();
+        let table = instruction_table();
+        assert_eq!(table.len(), INSN_SLICE.len());
+        let find = |name: &str| {
+            table
+                .iter()
+                .find(|meta| meta.name == name)
+                .unwrap_or_else(|| panic!("{} isn't in the table", name))
+        };
+        let cases = [
+            ("addi", "I", None),
+            ("addw", "I", Some(XLen::X64)),
+            ("csrrw", "I", None),
+            ("lrw", "A", None),
+            ("divuw", "M", Some(XLen::X64)),
+            ("fmadds", "F", None),
+            ("fcvtld", "D", Some(XLen::X64)),
+            ("sh1add", "B", None),
+        ];
+        for (name, ext, xlen) in cases {
+            let meta = find(name);
+            assert_eq!((meta.ext, meta.xlen), (ext, xlen), "{}", name);
+        }
+        // ZEXT.H 在 RV32 和 RV64 上的编码不同，各自只属于一种 XLEN。
+        assert_eq!(find("zexth32").xlen, Some(XLen::X32));
+        assert_eq!(find("zexth64").xlen, Some(XLen::X64));
+    }
+}
@@ -1,12 +1,15 @@
 /// 原子指令
-use crate::{cpu::Cpu, trap::Exception, Executable, Format, Insn, RegT, INSN_SLICE};
+use crate::{cpu::Cpu, trap::Exception, Executable, Format, Insn, InsnEntry, RegT};
 use proc_macros::Instruction;
 
 use super::sext;
 
+/// 指令表 (instruction_table) 中本模块指令所属的扩展。
+const EXT: &str = "A";
+
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0x1000202f)]
   #[mask(0xf9f0707f)]
   ,LrW);
@@ -15,14 +18,24 @@ impl Executable for LrW {
     // x[rd] = LoadReserved32(M[x[rs1]])
     // 加载保留字(Load-Reserved Word). R-type, RV32A and RV64A.
     // 从内存中地址为 x[rs1]中加载四个字节，符号位扩展后写入 x[rd]，并对这个内存字注册保留。
-    fn exec(&self, _cpu: &mut Cpu) -> Result<(), Exception> {
-        todo!()
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let addr = cpu.state.xs.reg(self.rs1() as u8);
+        let value = cpu.mmu.load_reserved::<u32>(&cpu.state, addr)? as RegT;
+        let value = sext(value, 32);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, value & cpu.xlen.mask());
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
+        Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0x1800202f)]
   #[mask(0xf800707f)]
   ,ScW);
@@ -32,14 +45,24 @@ impl Executable for ScW {
     // 条件存入字(Store-Conditional Word). R-type, RV32A and RV64A.
     // 内存地址 x[rs1]上存在加载保留，将 x[rs2]寄存器中的 4 字节数存入该地址。
     // 如果存入成功，向寄存器 x[rd]中存入 0，否则存入一个非 0 的错误码。
-    fn exec(&self, _cpu: &mut Cpu) -> Result<(), Exception> {
-        todo!()
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let addr = cpu.state.xs.reg(self.rs1() as u8);
+        let src = cpu.state.xs.reg(self.rs2() as u8);
+        let stored = cpu
+            .mmu
+            .store_conditional::<u32>(&cpu.state, addr, src as u32)?;
+        cpu.state.xs.set_reg(self.rd() as u8, !stored as RegT);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
+        Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0x800202f)]
   #[mask(0xf800707f)]
   ,AmoswapW);
@@ -48,23 +71,22 @@ impl Executable for AmoswapW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8);
-        if addr % 4 != 0 {
-            return Err(Exception::LoadMisaligned);
-        }
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)? as RegT;
+        let value = cpu.mmu.amo::<u32>(&cpu.state, addr, |_| src as u32)? as RegT;
         let value = sext(value, 32);
-        cpu.mmu.store::<u32>(&cpu.state, addr, src as u32)?;
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0x202f)]
   #[mask(0xf800707f)]
   ,AmoaddW);
@@ -76,21 +98,24 @@ impl Executable for AmoaddW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8) as u32;
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)?;
-        cpu.mmu
-            .store::<u32>(&cpu.state, addr, (src.wrapping_add(value)) as u32)?;
+        let value = cpu
+            .mmu
+            .amo::<u32>(&cpu.state, addr, |t| src.wrapping_add(t))?;
         let value = sext(value as RegT, 32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0x2000202f)]
   #[mask(0xf800707f)]
   ,AmoxorW);
@@ -103,20 +128,22 @@ impl Executable for AmoxorW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8) as u32;
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)?;
-        cpu.mmu.store::<u32>(&cpu.state, addr, src ^ value)?;
+        let value = cpu.mmu.amo::<u32>(&cpu.state, addr, |t| src ^ t)?;
         let value = sext(value as RegT, 32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0x6000202f)]
   #[mask(0xf800707f)]
   ,AmoandW);
@@ -129,20 +156,22 @@ impl Executable for AmoandW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8) as u32;
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)?;
-        cpu.mmu.store::<u32>(&cpu.state, addr, src & value)?;
+        let value = cpu.mmu.amo::<u32>(&cpu.state, addr, |t| src & t)?;
         let value = sext(value as RegT, 32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0x4000202f)]
   #[mask(0xf800707f)]
   ,AmoorW);
@@ -155,20 +184,22 @@ impl Executable for AmoorW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8) as u32;
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)?;
-        cpu.mmu.store::<u32>(&cpu.state, addr, src | value)?;
+        let value = cpu.mmu.amo::<u32>(&cpu.state, addr, |t| src | t)?;
         let value = sext(value as RegT, 32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0x8000202f)]
   #[mask(0xf800707f)]
   ,AmominW);
@@ -181,21 +212,24 @@ impl Executable for AmominW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8) as u32 as i32;
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)?;
-        cpu.mmu
-            .store::<u32>(&cpu.state, addr, std::cmp::min(src, value as i32) as u32)?;
+        let value = cpu
+            .mmu
+            .amo::<u32>(&cpu.state, addr, |t| std::cmp::min(src, t as i32) as u32)?;
         let value = sext(value as RegT, 32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0xa000202f)]
   #[mask(0xf800707f)]
   ,AmomaxW);
@@ -208,21 +242,24 @@ impl Executable for AmomaxW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8) as u32 as i32;
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)?;
-        cpu.mmu
-            .store::<u32>(&cpu.state, addr, std::cmp::max(src, value as i32) as u32)?;
+        let value = cpu
+            .mmu
+            .amo::<u32>(&cpu.state, addr, |t| std::cmp::max(src, t as i32) as u32)?;
         let value = sext(value as RegT, 32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0xc000202f)]
   #[mask(0xf800707f)]
   ,AmominuW);
@@ -235,21 +272,25 @@ impl Executable for AmominuW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8);
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)? as RegT;
-        cpu.mmu
-            .store::<u32>(&cpu.state, addr, std::cmp::min(src, value) as u32)?;
+        let value = cpu
+            .mmu
+            .amo::<u32>(&cpu.state, addr, |t| std::cmp::min(src as u32, t))?
+            as RegT;
         let value = sext(value, 32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
 def_insn!(
   #[derive(Instruction)]
-  #[format(R)]
+  #[format(A)]
   #[match_code(0xe000202f)]
   #[mask(0xf800707f)]
   ,AmomaxuW);
@@ -262,14 +303,132 @@ impl Executable for AmomaxuW {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let addr = cpu.state.xs.reg(self.rs1() as u8);
         let src = cpu.state.xs.reg(self.rs2() as u8);
-        let value = cpu.mmu.load::<u32>(&cpu.state, addr)? as RegT;
-        cpu.mmu
-            .store::<u32>(&cpu.state, addr, std::cmp::max(src, value) as u32)?;
+        let value = cpu
+            .mmu
+            .amo::<u32>(&cpu.state, addr, |t| std::cmp::max(src as u32, t))?
+            as RegT;
         let value = sext(value, 32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if self.rl() {
+            cpu.release();
+        }
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::*, trap::Trap, XLen};
+
+    #[test]
+    fn ordering_bits_are_decoded_and_displayed() {
+        // 依次是 aq、rl 与显示的名字。
+        let cases = [
+            (false, false, "amoaddw"),
+            (false, true, "amoaddw.rl"),
+            (true, false, "amoaddw.aq"),
+            (true, true, "amoaddw.aqrl"),
+        ];
+        for &(aq, rl, name) in cases.iter() {
+            let code = amo(0, 2, 10, 11, 12) | (aq as u32) << 26 | (rl as u32) << 25;
+            let insn = AmoaddW::new(code);
+            assert_eq!((insn.aq(), insn.rl()), (aq, rl));
+            assert_eq!((insn.rd(), insn.rs1(), insn.rs2()), (10, 11, 12));
+            assert_eq!(insn.to_string(), name);
+        }
+        assert_eq!(
+            LrW::new(amo(0b00010, 2, 10, 11, 0) | 1 << 26).to_string(),
+            "lrw.aq"
+        );
+        assert_eq!(
+            ScW::new(amo(0b00011, 2, 10, 11, 12) | 1 << 25).to_string(),
+            "scw.rl"
+        );
+    }
+
+    /// 数据区，在程序之后。
+    const DATA: RegT = DRAM_BASE + 0x100;
+
+    /// 在 `xlen` 下执行一条字 AMO，a1 指向的内存原为 `mem`，a2 为 `src`。返回 a0 与内存的新值。
+    fn run_amo(xlen: XLen, insn: u32, mem: u32, src: RegT) -> (RegT, u32) {
+        let mut cpu = Cpu::with_program(xlen, &[insn, EBREAK]);
+        cpu.mmu.bus.borrow_mut().write::<u32>(DATA, mem).unwrap();
+        cpu.state.xs.set_reg(11, DATA);
+        cpu.state.xs.set_reg(12, src);
+        cpu.run_until_ebreak(2);
+        let new = cpu.mmu.bus.borrow().read::<u32>(DATA).unwrap();
+        (cpu.state.xs.reg(10), new)
+    }
+
+    #[test]
+    fn amos_return_the_old_value_and_store_the_result() {
+        // 依次是 funct5、内存原值、a2 与写回内存的值，按 32 位 -5 与 3 计算。
+        let cases: [(u32, u32, u32, u32); 9] = [
+            (0b00001, -5i32 as u32, 3, 3),
+            (0b00000, -5i32 as u32, 3, -2i32 as u32),
+            (0b00100, 0b1100, 0b1010, 0b0110),
+            (0b01100, 0b1100, 0b1010, 0b1000),
+            (0b01000, 0b1100, 0b1010, 0b1110),
+            (0b10000, -5i32 as u32, 3, -5i32 as u32),
+            (0b10100, -5i32 as u32, 3, 3),
+            (0b11000, -5i32 as u32, 3, 3),
+            (0b11100, -5i32 as u32, 3, -5i32 as u32),
+        ];
+        for &(funct5, mem, src, stored) in cases.iter() {
+            // rd 为符号扩展的原值，只用 rs2 的低 32 位。
+            let insn = amo(funct5, 2, 10, 11, 12);
+            let src64 = 0xdead_0000_0000_0000 | src as RegT;
+            let (rd, new) = run_amo(XLen::X64, insn, mem, src64);
+            assert_eq!(rd, mem as i32 as RegT, "{:05b}", funct5);
+            assert_eq!(new, stored, "{:05b}", funct5);
+        }
+
+        // RV32 的 amoadd.w 溢出回绕。
+        let (rd, new) = run_amo(XLen::X32, amo(0b00000, 2, 10, 11, 12), 0xffff_ffff, 2);
+        assert_eq!((rd, new), (0xffff_ffff, 1));
+    }
+
+    #[test]
+    fn sc_succeeds_only_on_the_reserved_address() {
+        let program = [
+            amo(0b00010, 2, 10, 11, 0),
+            addi(10, 10, 1),
+            amo(0b00011, 2, 13, 11, 10),
+            // 保留已被上一条 SC 用掉，这条失败。
+            amo(0b00011, 2, 14, 11, 12),
+            // 保留的是 DATA 所在的双字，对 DATA + 8 的 SC 失败。
+            amo(0b00010, 2, 0, 11, 0),
+            amo(0b00011, 2, 15, 15, 12),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.mmu.bus.borrow_mut().write::<u32>(DATA, 41).unwrap();
+        cpu.state.xs.set_reg(11, DATA);
+        cpu.state.xs.set_reg(12, 7);
+        cpu.state.xs.set_reg(15, DATA + 8);
+        cpu.run_until_ebreak(program.len() as u64);
+        assert_regs!(cpu.state.xs, a0 = 42, a3 = 0, a4 = 1, a5 = 1);
+        let bus = cpu.mmu.bus.borrow();
+        assert_eq!(bus.read::<u32>(DATA), Ok(42));
+        assert_eq!(bus.read::<u32>(DATA + 8), Ok(0));
+    }
+
+    #[test]
+    fn misaligned_amos_stop_a_machine_without_handlers() {
+        // mtvec 为 0，取处理程序失败，返回的是 AMO 的异常。
+        let mut cpu = Cpu::with_program(XLen::X64, &[amo(0b00001, 2, 10, 11, 12), EBREAK]);
+        cpu.state.xs.set_reg(11, DATA + 2);
+        assert_eq!(
+            cpu.try_run_until_ebreak(2),
+            Err(Trap::Exception(Exception::StoreMisaligned(DATA + 2)))
+        );
+
+        let mut cpu = Cpu::with_program(XLen::X64, &[amo(0b00001, 2, 10, 11, 12), EBREAK]);
+        cpu.state.xs.set_reg(11, DATA);
+        assert_eq!(cpu.try_run_until_ebreak(2), Ok(()));
+    }
+}
@@ -0,0 +1,1124 @@
+/// 位操作指令集：Zba、Zbb 和 Zbs
+use crate::{cpu::Cpu, trap::Exception, Executable, Format, Insn, InsnEntry, RegT};
+use proc_macros::Instruction;
+
+use super::{sext, signed};
+
+/// 指令表 (instruction_table) 中本模块指令所属的扩展。
+const EXT: &str = "B";
+
+/// 把 x[rs1]循环左移 shamt 位，只在 XLEN 位内循环。
+fn rotate_left(cpu: &Cpu, value: RegT, shamt: u32) -> RegT {
+    let len = cpu.xlen.len() as u32;
+    let shamt = shamt & (len - 1);
+    if shamt == 0 {
+        return value;
+    }
+    ((value << shamt) | (value >> (len - shamt))) & cpu.xlen.mask()
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x20002033)]
+    #[mask(0xfe00707f)]
+    ,Sh1add);
+
+impl Executable for Sh1add {
+    // x[rd] = x[rs2] + (x[rs1] ≪ 1)
+    // 移位1位后相加 (Shift Left by 1 and Add). R-type, RV32Zba and RV64Zba.
+    // 把 x[rs1]左移 1 位后加上 x[rs2]，结果写入 x[rd]。忽略算术溢出。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            ((rs1 << 1).wrapping_add(rs2)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x20004033)]
+    #[mask(0xfe00707f)]
+    ,Sh2add);
+
+impl Executable for Sh2add {
+    // x[rd] = x[rs2] + (x[rs1] ≪ 2)
+    // 移位2位后相加 (Shift Left by 2 and Add). R-type, RV32Zba and RV64Zba.
+    // 把 x[rs1]左移 2 位后加上 x[rs2]，结果写入 x[rd]。忽略算术溢出。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            ((rs1 << 2).wrapping_add(rs2)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x20006033)]
+    #[mask(0xfe00707f)]
+    ,Sh3add);
+
+impl Executable for Sh3add {
+    // x[rd] = x[rs2] + (x[rs1] ≪ 3)
+    // 移位3位后相加 (Shift Left by 3 and Add). R-type, RV32Zba and RV64Zba.
+    // 把 x[rs1]左移 3 位后加上 x[rs2]，结果写入 x[rd]。忽略算术溢出。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            ((rs1 << 3).wrapping_add(rs2)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x0800003b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,AddUw);
+
+impl Executable for AddUw {
+    // x[rd] = x[rs2] + zext(x[rs1][31:0])
+    // 无符号字相加 (Add Unsigned Word). R-type, RV64Zba.
+    // 把 x[rs1]的低 32 位零扩展后加上 x[rs2]，结果写入 x[rd]。忽略算术溢出。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            ((rs1 as u32 as RegT).wrapping_add(rs2)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x2000203b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,Sh1addUw);
+
+impl Executable for Sh1addUw {
+    // x[rd] = x[rs2] + (zext(x[rs1][31:0]) ≪ 1)
+    // 无符号字移位1位后相加 (Shift Unsigned Word Left by 1 and Add). R-type, RV64Zba.
+    // 把 x[rs1]的低 32 位零扩展并左移 1 位后加上 x[rs2]，结果写入 x[rd]。忽略算术溢出。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (((rs1 as u32 as RegT) << 1).wrapping_add(rs2)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x2000403b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,Sh2addUw);
+
+impl Executable for Sh2addUw {
+    // x[rd] = x[rs2] + (zext(x[rs1][31:0]) ≪ 2)
+    // 无符号字移位2位后相加 (Shift Unsigned Word Left by 2 and Add). R-type, RV64Zba.
+    // 把 x[rs1]的低 32 位零扩展并左移 2 位后加上 x[rs2]，结果写入 x[rd]。忽略算术溢出。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (((rs1 as u32 as RegT) << 2).wrapping_add(rs2)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x2000603b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,Sh3addUw);
+
+impl Executable for Sh3addUw {
+    // x[rd] = x[rs2] + (zext(x[rs1][31:0]) ≪ 3)
+    // 无符号字移位3位后相加 (Shift Unsigned Word Left by 3 and Add). R-type, RV64Zba.
+    // 把 x[rs1]的低 32 位零扩展并左移 3 位后加上 x[rs2]，结果写入 x[rd]。忽略算术溢出。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (((rs1 as u32 as RegT) << 3).wrapping_add(rs2)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x0800101b)]
+    #[mask(0xfc00707f)]
+    #[xlen(64)]
+    ,SlliUw);
+
+impl Executable for SlliUw {
+    // x[rd] = zext(x[rs1][31:0]) ≪ shamt
+    // 无符号字立即数逻辑左移 (Shift Left Unsigned Word Immediate). I-type, RV64Zba.
+    // 把 x[rs1]的低 32 位零扩展后左移 shamt 位，结果写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let shamt = self.imm() & 0x3f;
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 as u32 as RegT) << shamt);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x40007033)]
+    #[mask(0xfe00707f)]
+    ,Andn);
+
+impl Executable for Andn {
+    // x[rd] = x[rs1] & ~x[rs2]
+    // 取反后与 (AND with Inverted Operand). R-type, RV32Zbb and RV64Zbb.
+    // x[rs1]和按位取反的 x[rs2]按位与，结果写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 & !rs2) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x40006033)]
+    #[mask(0xfe00707f)]
+    ,Orn);
+
+impl Executable for Orn {
+    // x[rd] = x[rs1] | ~x[rs2]
+    // 取反后或 (OR with Inverted Operand). R-type, RV32Zbb and RV64Zbb.
+    // x[rs1]和按位取反的 x[rs2]按位或，结果写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 | !rs2) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x40004033)]
+    #[mask(0xfe00707f)]
+    ,Xnor);
+
+impl Executable for Xnor {
+    // x[rd] = ~(x[rs1] ^ x[rs2])
+    // 同或 (Exclusive NOR). R-type, RV32Zbb and RV64Zbb.
+    // x[rs1]和 x[rs2]按位异或后取反，结果写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (!(rs1 ^ rs2)) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x60001013)]
+    #[mask(0xfff0707f)]
+    ,Clz);
+
+impl Executable for Clz {
+    // x[rd] = clz(x[rs1])
+    // 前导零计数 (Count Leading Zero Bits). I-type, RV32Zbb and RV64Zbb.
+    // 统计 x[rs1]从最高位开始连续为 0 的位数，写入 x[rd]。x[rs1]为 0 时结果为 XLEN。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            ((rs1.leading_zeros() as usize - (64 - cpu.xlen.len())) as RegT) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x60101013)]
+    #[mask(0xfff0707f)]
+    ,Ctz);
+
+impl Executable for Ctz {
+    // x[rd] = ctz(x[rs1])
+    // 尾随零计数 (Count Trailing Zero Bits). I-type, RV32Zbb and RV64Zbb.
+    // 统计 x[rs1]从最低位开始连续为 0 的位数，写入 x[rd]。x[rs1]为 0 时结果为 XLEN。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (rs1.trailing_zeros().min(cpu.xlen.len() as u32) as RegT) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x60201013)]
+    #[mask(0xfff0707f)]
+    ,Cpop);
+
+impl Executable for Cpop {
+    // x[rd] = cpop(x[rs1])
+    // 置位计数 (Count Set Bits). I-type, RV32Zbb and RV64Zbb.
+    // 统计 x[rs1]中为 1 的位数，写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (rs1.count_ones() as RegT) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x6000101b)]
+    #[mask(0xfff0707f)]
+    #[xlen(64)]
+    ,Clzw);
+
+impl Executable for Clzw {
+    // x[rd] = clz(x[rs1][31:0])
+    // 字前导零计数 (Count Leading Zero Bits in Word). I-type, RV64Zbb.
+    // 统计 x[rs1]低 32 位中从第 31 位开始连续为 0 的位数，写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            ((rs1 as u32).leading_zeros() as RegT) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x6010101b)]
+    #[mask(0xfff0707f)]
+    #[xlen(64)]
+    ,Ctzw);
+
+impl Executable for Ctzw {
+    // x[rd] = ctz(x[rs1][31:0])
+    // 字尾随零计数 (Count Trailing Zero Bits in Word). I-type, RV64Zbb.
+    // 统计 x[rs1]低 32 位中从最低位开始连续为 0 的位数，写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            ((rs1 as u32).trailing_zeros() as RegT) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x6020101b)]
+    #[mask(0xfff0707f)]
+    #[xlen(64)]
+    ,Cpopw);
+
+impl Executable for Cpopw {
+    // x[rd] = cpop(x[rs1][31:0])
+    // 字置位计数 (Count Set Bits in Word). I-type, RV64Zbb.
+    // 统计 x[rs1]低 32 位中为 1 的位数，写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            ((rs1 as u32).count_ones() as RegT) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x0a006033)]
+    #[mask(0xfe00707f)]
+    ,Max);
+
+impl Executable for Max {
+    // x[rd] = max(x[rs1], x[rs2])
+    // 最大值 (Maximum). R-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]和 x[rs2]中较大的数写入 x[rd]，比较时视为 2 的补码。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = signed(cpu, self.rs1());
+        let rs2 = signed(cpu, self.rs2());
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1.max(rs2) as RegT) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x0a007033)]
+    #[mask(0xfe00707f)]
+    ,Maxu);
+
+impl Executable for Maxu {
+    // x[rd] = maxu(x[rs1], x[rs2])
+    // 无符号最大值 (Maximum, Unsigned). R-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]和 x[rs2]中较大的数写入 x[rd]，比较时视为无符号数。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(self.rd() as u8, rs1.max(rs2));
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x0a004033)]
+    #[mask(0xfe00707f)]
+    ,Min);
+
+impl Executable for Min {
+    // x[rd] = min(x[rs1], x[rs2])
+    // 最小值 (Minimum). R-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]和 x[rs2]中较小的数写入 x[rd]，比较时视为 2 的补码。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = signed(cpu, self.rs1());
+        let rs2 = signed(cpu, self.rs2());
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1.min(rs2) as RegT) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x0a005033)]
+    #[mask(0xfe00707f)]
+    ,Minu);
+
+impl Executable for Minu {
+    // x[rd] = minu(x[rs1], x[rs2])
+    // 无符号最小值 (Minimum, Unsigned). R-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]和 x[rs2]中较小的数写入 x[rd]，比较时视为无符号数。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(self.rd() as u8, rs1.min(rs2));
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x60401013)]
+    #[mask(0xfff0707f)]
+    ,SextB);
+
+impl Executable for SextB {
+    // x[rd] = sext(x[rs1][7:0])
+    // 字节符号扩展 (Sign-Extend Byte). I-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]的低 8 位符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (sext(rs1 & 0xff, 8)) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x60501013)]
+    #[mask(0xfff0707f)]
+    ,SextH);
+
+impl Executable for SextH {
+    // x[rd] = sext(x[rs1][15:0])
+    // 半字符号扩展 (Sign-Extend Halfword). I-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]的低 16 位符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (sext(rs1 & 0xffff, 16)) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x08004033)]
+    #[mask(0xfff0707f)]
+    #[xlen(32)]
+    ,ZextH32);
+
+impl Executable for ZextH32 {
+    // x[rd] = zext(x[rs1][15:0])
+    // 半字零扩展 (Zero-Extend Halfword). R-type, RV32Zbb.
+    // 把 x[rs1]的低 16 位零扩展后写入 x[rd]。RV64 上的编码是 ZextH64。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 & 0xffff) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x0800403b)]
+    #[mask(0xfff0707f)]
+    #[xlen(64)]
+    ,ZextH64);
+
+impl Executable for ZextH64 {
+    // x[rd] = zext(x[rs1][15:0])
+    // 半字零扩展 (Zero-Extend Halfword). R-type, RV64Zbb.
+    // 把 x[rs1]的低 16 位零扩展后写入 x[rd]。RV32 上的编码是 ZextH32。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 & 0xffff) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x60001033)]
+    #[mask(0xfe00707f)]
+    ,Rol);
+
+impl Executable for Rol {
+    // x[rd] = x[rs1] ≪≫ x[rs2]
+    // 循环左移 (Rotate Left). R-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]循环左移 x[rs2]位，结果写入 x[rd]。x[rs2]的低 5 位（如果是 RV64 则是低 6 位）代表移动位数。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (rotate_left(cpu, rs1, rs2 as u32)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x60005033)]
+    #[mask(0xfe00707f)]
+    ,Ror);
+
+impl Executable for Ror {
+    // x[rd] = x[rs1] ≫≪ x[rs2]
+    // 循环右移 (Rotate Right). R-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]循环右移 x[rs2]位，结果写入 x[rd]。x[rs2]的低 5 位（如果是 RV64 则是低 6 位）代表移动位数。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (rotate_left(cpu, rs1, (rs2 as u32).wrapping_neg())) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x60005013)]
+    #[mask(0xfc00707f)]
+    ,Rori);
+
+impl Executable for Rori {
+    // x[rd] = x[rs1] ≫≪ shamt
+    // 立即数循环右移 (Rotate Right Immediate). I-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]循环右移 shamt 位，结果写入 x[rd]。对于 RV32，仅当 shamt[5]=0 时，指令才是有效的。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let shamt = self.imm() & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, rotate_left(cpu, rs1, shamt.wrapping_neg()));
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x6000103b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,Rolw);
+
+impl Executable for Rolw {
+    // x[rd] = sext((x[rs1] ≪≫ x[rs2][4:0])[31:0])
+    // 字循环左移 (Rotate Left Word). R-type, RV64Zbb.
+    // 把 x[rs1]的低 32 位循环左移 x[rs2]的低 5 位，结果符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (sext((rs1 as u32).rotate_left(rs2 as u32) as RegT, 32)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x6000503b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,Rorw);
+
+impl Executable for Rorw {
+    // x[rd] = sext((x[rs1] ≫≪ x[rs2][4:0])[31:0])
+    // 字循环右移 (Rotate Right Word). R-type, RV64Zbb.
+    // 把 x[rs1]的低 32 位循环右移 x[rs2]的低 5 位，结果符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            (sext((rs1 as u32).rotate_right(rs2 as u32) as RegT, 32)) & cpu.xlen.mask(),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x6000501b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,Roriw);
+
+impl Executable for Roriw {
+    // x[rd] = sext((x[rs1] ≫≪ shamt)[31:0])
+    // 字立即数循环右移 (Rotate Right Word Immediate). I-type, RV64Zbb.
+    // 把 x[rs1]的低 32 位循环右移 shamt 位，结果符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let shamt = self.imm() & 0x1f;
+        cpu.state.xs.set_reg(
+            self.rd() as u8,
+            sext((rs1 as u32).rotate_right(shamt) as RegT, 32),
+        );
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x28705013)]
+    #[mask(0xfff0707f)]
+    ,OrcB);
+
+impl Executable for OrcB {
+    // x[rd] = orc.b(x[rs1])
+    // 按字节或合并 (Bitwise OR-Combine, Byte Granule). I-type, RV32Zbb and RV64Zbb.
+    // 对 x[rs1]的每个字节，若其中有位为 1 则结果中对应字节全为 1，否则全为 0，结果写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let value = (0..8).fold(0, |value, i| {
+            if rs1 >> (i * 8) & 0xff != 0 {
+                value | 0xff << (i * 8)
+            } else {
+                value
+            }
+        });
+        cpu.state.xs.set_reg(self.rd() as u8, value);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x69805013)]
+    #[mask(0xfdf0707f)]
+    ,Rev8);
+
+impl Executable for Rev8 {
+    // x[rd] = rev8(x[rs1])
+    // 字节反转 (Byte-Reverse Register). I-type, RV32Zbb and RV64Zbb.
+    // 把 x[rs1]中的字节顺序反转，结果写入 x[rd]。RV32 的编码为 0x69805013，RV64 的编码为 0x6b805013。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let value = rs1.swap_bytes() >> (64 - cpu.xlen.len());
+        cpu.state.xs.set_reg(self.rd() as u8, value);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x48001033)]
+    #[mask(0xfe00707f)]
+    ,Bclr);
+
+impl Executable for Bclr {
+    // x[rd] = x[rs1] & ~(1 ≪ x[rs2])
+    // 位清零 (Single-Bit Clear). R-type, RV32Zbs and RV64Zbs.
+    // 把 x[rs1]中由 x[rs2]的低 5 位（如果是 RV64 则是低 6 位）指定的位清零，结果写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let index = cpu.state.xs.reg(self.rs2() as u8) as u32 & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 & !(1 << index)) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x48001013)]
+    #[mask(0xfc00707f)]
+    ,Bclri);
+
+impl Executable for Bclri {
+    // x[rd] = x[rs1] & ~(1 ≪ shamt)
+    // 立即数位清零 (Single-Bit Clear Immediate). I-type, RV32Zbs and RV64Zbs.
+    // 把 x[rs1]中由 shamt 指定的位清零，结果写入 x[rd]。对于 RV32，仅当 shamt[5]=0 时，指令才是有效的。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let index = self.imm() & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 & !(1 << index)) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x48005033)]
+    #[mask(0xfe00707f)]
+    ,Bext);
+
+impl Executable for Bext {
+    // x[rd] = (x[rs1] ≫ x[rs2]) & 1
+    // 位提取 (Single-Bit Extract). R-type, RV32Zbs and RV64Zbs.
+    // 把 x[rs1]中由 x[rs2]的低 5 位（如果是 RV64 则是低 6 位）指定的位写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let index = cpu.state.xs.reg(self.rs2() as u8) as u32 & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 >> index & 1) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x48005013)]
+    #[mask(0xfc00707f)]
+    ,Bexti);
+
+impl Executable for Bexti {
+    // x[rd] = (x[rs1] ≫ shamt) & 1
+    // 立即数位提取 (Single-Bit Extract Immediate). I-type, RV32Zbs and RV64Zbs.
+    // 把 x[rs1]中由 shamt 指定的位写入 x[rd]。对于 RV32，仅当 shamt[5]=0 时，指令才是有效的。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let index = self.imm() & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 >> index & 1) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x68001033)]
+    #[mask(0xfe00707f)]
+    ,Binv);
+
+impl Executable for Binv {
+    // x[rd] = x[rs1] ^ (1 ≪ x[rs2])
+    // 位取反 (Single-Bit Invert). R-type, RV32Zbs and RV64Zbs.
+    // 把 x[rs1]中由 x[rs2]的低 5 位（如果是 RV64 则是低 6 位）指定的位取反，结果写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let index = cpu.state.xs.reg(self.rs2() as u8) as u32 & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 ^ 1 << index) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x68001013)]
+    #[mask(0xfc00707f)]
+    ,Binvi);
+
+impl Executable for Binvi {
+    // x[rd] = x[rs1] ^ (1 ≪ shamt)
+    // 立即数位取反 (Single-Bit Invert Immediate). I-type, RV32Zbs and RV64Zbs.
+    // 把 x[rs1]中由 shamt 指定的位取反，结果写入 x[rd]。对于 RV32，仅当 shamt[5]=0 时，指令才是有效的。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let index = self.imm() & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 ^ 1 << index) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x28001033)]
+    #[mask(0xfe00707f)]
+    ,Bset);
+
+impl Executable for Bset {
+    // x[rd] = x[rs1] | (1 ≪ x[rs2])
+    // 位置位 (Single-Bit Set). R-type, RV32Zbs and RV64Zbs.
+    // 把 x[rs1]中由 x[rs2]的低 5 位（如果是 RV64 则是低 6 位）指定的位置 1，结果写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let index = cpu.state.xs.reg(self.rs2() as u8) as u32 & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 | 1 << index) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x28001013)]
+    #[mask(0xfc00707f)]
+    ,Bseti);
+
+impl Executable for Bseti {
+    // x[rd] = x[rs1] | (1 ≪ shamt)
+    // 立即数位置位 (Single-Bit Set Immediate). I-type, RV32Zbs and RV64Zbs.
+    // 把 x[rs1]中由 shamt 指定的位置 1，结果写入 x[rd]。对于 RV32，仅当 shamt[5]=0 时，指令才是有效的。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let index = self.imm() & cpu.xlen.shamt_mask();
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 | 1 << index) & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cpu::Cpu, testing::*, RegT, XLen};
+
+    /// 由指令的 match_code 编码 rd = a0、rs1 = a1、rs2 = a2 的 R-type 指令。
+    const fn r(code: u32) -> u32 {
+        code | 12 << 20 | 11 << 15 | 10 << 7
+    }
+
+    /// 由 match_code 编码 rd = a0、rs1 = a1 的单操作数或立即数指令，`imm` 为 shamt 等字段。
+    const fn i(code: u32, imm: u32) -> u32 {
+        code | imm << 20 | 11 << 15 | 10 << 7
+    }
+
+    const SH1ADD: u32 = 0x2000_2033;
+    const SH3ADD: u32 = 0x2000_6033;
+    const ADD_UW: u32 = 0x0800_003b;
+    const SH2ADD_UW: u32 = 0x2000_403b;
+    const SLLI_UW: u32 = 0x0800_101b;
+    const ANDN: u32 = 0x4000_7033;
+    const ORN: u32 = 0x4000_6033;
+    const XNOR: u32 = 0x4000_4033;
+    const CLZ: u32 = 0x6000_1013;
+    const CTZ: u32 = 0x6010_1013;
+    const CPOP: u32 = 0x6020_1013;
+    const CLZW: u32 = 0x6000_101b;
+    const CTZW: u32 = 0x6010_101b;
+    const CPOPW: u32 = 0x6020_101b;
+    const MAX: u32 = 0x0a00_6033;
+    const MAXU: u32 = 0x0a00_7033;
+    const MIN: u32 = 0x0a00_4033;
+    const MINU: u32 = 0x0a00_5033;
+    const SEXT_B: u32 = 0x6040_1013;
+    const SEXT_H: u32 = 0x6050_1013;
+    const ZEXT_H_32: u32 = 0x0800_4033;
+    const ZEXT_H_64: u32 = 0x0800_403b;
+    const ROL: u32 = 0x6000_1033;
+    const ROR: u32 = 0x6000_5033;
+    const RORI: u32 = 0x6000_5013;
+    const ROLW: u32 = 0x6000_103b;
+    const RORW: u32 = 0x6000_503b;
+    const RORIW: u32 = 0x6000_501b;
+    const ORC_B: u32 = 0x2870_5013;
+    const REV8_32: u32 = 0x6980_5013;
+    const REV8_64: u32 = 0x6b80_5013;
+    const BCLR: u32 = 0x4800_1033;
+    const BEXT: u32 = 0x4800_5033;
+    const BINV: u32 = 0x6800_1033;
+    const BSET: u32 = 0x2800_1033;
+    const BSETI: u32 = 0x2800_1013;
+    const BEXTI: u32 = 0x4800_5013;
+
+    fn op64(code: u32, rs1: RegT, rs2: RegT) -> RegT {
+        exec_op(XLen::X64, r(code), rs1, rs2)
+    }
+
+    fn op32(code: u32, rs1: RegT, rs2: RegT) -> RegT {
+        exec_op(XLen::X32, r(code), rs1, rs2)
+    }
+
+    fn unary64(code: u32, imm: u32, rs1: RegT) -> RegT {
+        exec_op(XLen::X64, i(code, imm), rs1, 0)
+    }
+
+    fn unary32(code: u32, imm: u32, rs1: RegT) -> RegT {
+        exec_op(XLen::X32, i(code, imm), rs1, 0)
+    }
+
+    const MINUS_1: RegT = -1i64 as RegT;
+
+    #[test]
+    fn zba_adds_shifted_and_zero_extended_words() {
+        assert_eq!(op64(SH1ADD, 3, 100), 106);
+        assert_eq!(op64(SH3ADD, 3, 100), 124);
+        assert_eq!(op32(SH3ADD, 0x2000_0000, 1), 1);
+        // .uw 只取 rs1 的低 32 位
+        assert_eq!(op64(ADD_UW, 0xffff_ffff_8000_0000, 1), 0x8000_0001);
+        assert_eq!(op64(SH2ADD_UW, MINUS_1, 4), 0x4_0000_0000);
+        assert_eq!(unary64(SLLI_UW, 4, 0xffff_ffff_8000_0001), 0x8_0000_0010);
+    }
+
+    #[test]
+    fn zbb_logic_counts_and_extensions() {
+        assert_eq!(op64(ANDN, 0b1100, 0b1010), 0b0100);
+        assert_eq!(op64(ORN, 0b1100, MINUS_1 ^ 0b1010), 0b1110);
+        assert_eq!(op32(XNOR, 0xf0f0_f0f0, 0xff00_ff00), 0xf00f_f00f);
+
+        assert_eq!(unary64(CLZ, 0, 1), 63);
+        assert_eq!(unary32(CLZ, 0, 1), 31);
+        assert_eq!(unary64(CLZ, 0, 0), 64);
+        assert_eq!(unary32(CTZ, 0, 0), 32);
+        assert_eq!(unary64(CTZ, 0, 1 << 40), 40);
+        assert_eq!(unary64(CPOP, 0, 0xff00_ff00_0000_0001), 17);
+        assert_eq!(unary64(CLZW, 0, 0xffff_ffff_0000_0001), 31);
+        assert_eq!(unary64(CTZW, 0, 0xffff_ffff_0000_0000), 32);
+        assert_eq!(unary64(CPOPW, 0, 0xffff_ffff_0000_0003), 2);
+
+        assert_eq!(op64(MAX, MINUS_1, 1), 1);
+        assert_eq!(op64(MAXU, MINUS_1, 1), MINUS_1);
+        assert_eq!(op64(MIN, MINUS_1, 1), MINUS_1);
+        assert_eq!(op64(MINU, MINUS_1, 1), 1);
+        assert_eq!(op32(MIN, 0x8000_0000, 1), 0x8000_0000);
+        assert_eq!(op32(MINU, 0x8000_0000, 1), 1);
+
+        assert_eq!(unary64(SEXT_B, 0, 0x180), -128i64 as RegT);
+        assert_eq!(unary64(SEXT_H, 0, 0x8000), 0xffff_ffff_ffff_8000);
+        assert_eq!(unary32(SEXT_H, 0, 0x8000), 0xffff_8000);
+        assert_eq!(unary64(ZEXT_H_64, 0, 0xffff_ffff_1234_5678), 0x5678);
+        assert_eq!(unary32(ZEXT_H_32, 0, 0x1234_5678), 0x5678);
+    }
+
+    #[test]
+    fn zbb_rotates_and_byte_operations_follow_xlen() {
+        assert_eq!(op64(ROL, 0x8000_0000_0000_0001, 4), 0x18);
+        assert_eq!(op32(ROL, 0x8000_0001, 4), 0x18);
+        assert_eq!(op64(ROR, 1, 65), 0x8000_0000_0000_0000);
+        assert_eq!(op32(ROR, 1, 1), 0x8000_0000);
+        assert_eq!(unary64(RORI, 8, 0xff), 0xff00_0000_0000_0000);
+        assert_eq!(unary32(RORI, 8, 0xff), 0xff00_0000);
+        // 字操作只转低 32 位，结果符号扩展
+        assert_eq!(op64(ROLW, 0xffff_ffff_8000_0001, 4), 0x18);
+        assert_eq!(op64(RORW, 1, 1), 0xffff_ffff_8000_0000);
+        assert_eq!(unary64(RORIW, 4, 0x1), 0x0000_0000_1000_0000);
+
+        assert_eq!(
+            unary64(ORC_B, 0, 0x0001_0000_8000_0100),
+            0x00ff_0000_ff00_ff00
+        );
+        assert_eq!(unary32(ORC_B, 0, 0x0000_0100), 0x0000_ff00);
+        assert_eq!(
+            unary64(REV8_64, 0, 0x0102_0304_0506_0708),
+            0x0807_0605_0403_0201
+        );
+        assert_eq!(unary32(REV8_32, 0, 0x0102_0304), 0x0403_0201);
+    }
+
+    #[test]
+    fn zbs_works_on_the_bit_selected_by_the_low_bits() {
+        assert_eq!(op64(BCLR, 0xff, 3), 0xf7);
+        assert_eq!(op64(BSET, 0, 63), 1 << 63);
+        // 位号只取低 log2(XLEN) 位
+        assert_eq!(op32(BSET, 0, 33), 2);
+        assert_eq!(op64(BINV, 0b101, 2), 0b001);
+        assert_eq!(op64(BEXT, 0b1000, 3), 1);
+        assert_eq!(op64(BEXT, 0b1000, 2), 0);
+        assert_eq!(unary64(BSETI, 40, 0), 1 << 40);
+        assert_eq!(unary32(BEXTI, 31, 0x8000_0000), 1);
+    }
+
+    #[test]
+    fn rv32_rejects_the_rv64_encodings() {
+        for &insn in [
+            r(ADD_UW),
+            r(ROLW),
+            i(RORIW, 1),
+            i(CLZW, 0),
+            i(SLLI_UW, 1),
+            i(ZEXT_H_64, 0),
+            // rori 的移位量 32 只在 RV64 上有效
+            i(RORI, 32),
+        ]
+        .iter()
+        {
+            assert!(decodes(XLen::X64, insn), "{:#010x}", insn);
+            assert!(!decodes(XLen::X32, insn), "{:#010x}", insn);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn rv32_traps_on_the_word_operations() {
+        let mut cpu = Cpu::with_program(XLen::X32, &[i(RORIW, 1), EBREAK]);
+        cpu.run_until_ebreak(10);
+    }
+
+    #[test]
+    fn rv64_rejects_the_rv32_encodings() {
+        for &insn in [i(ZEXT_H_32, 0), i(REV8_32, 0)].iter() {
+            assert!(decodes(XLen::X32, insn), "{:#010x}", insn);
+            assert!(!decodes(XLen::X64, insn), "{:#010x}", insn);
+        }
+        assert!(!decodes(XLen::X32, i(REV8_64, 0)));
+    }
+}
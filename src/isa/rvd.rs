@@ -0,0 +1,863 @@
+/// 双精度浮点指令集
+use crate::{cpu::Cpu, trap::Exception, Executable, Format, Insn, InsnEntry, RegT};
+use proc_macros::Instruction;
+
+use super::{
+    fp::{self, RoundingMode},
+    rvf::{self, rs3, write_f32, write_x},
+    sext,
+};
+
+/// 指令表 (instruction_table) 中本模块指令所属的扩展。
+const EXT: &str = "D";
+
+/// 检查双精度浮点指令是否可用：除单精度浮点指令的条件外，misa 中 D 扩展也未关闭。
+fn check_enabled(cpu: &Cpu) -> Result<(), Exception> {
+    rvf::check_enabled(cpu)?;
+    if !cpu.state.csrs.has_extension('D') {
+        return Err(Exception::IllegalInstruction(0));
+    }
+    Ok(())
+}
+
+/// 检查双精度浮点指令是否可用，并取得指令 rm 字段选择的舍入模式。
+fn rounding_mode(cpu: &Cpu, code: u32) -> Result<RoundingMode, Exception> {
+    check_enabled(cpu)?;
+    RoundingMode::new(cpu, (code >> 12) & 0x7)
+}
+
+/// 写 f[rd]，累积异常标志并把浮点状态标记为 Dirty。
+fn write_f64(cpu: &mut Cpu, rd: u32, value: f64, flags: u8) {
+    cpu.state.fs.set_f64(rd as u8, value);
+    cpu.state.csrs.accrue_fflags(flags as RegT);
+    cpu.state.csrs.set_fs_dirty();
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x3007)]
+    #[mask(0x707f)]
+    ,Fld);
+
+impl Executable for Fld {
+    // f[rd] = M[x[rs1] + sext(offset)][63:0]
+    // 浮点加载双字 (Floating-point Load Doubleword). I-type, RV32D and RV64D.
+    // 从地址 x[rs1] + sign-extend(offset)读取双精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let data = cpu
+            .mmu
+            .load::<u64>(&cpu.state, rs1.wrapping_add(self.imm_sext()))?;
+        write_f64(cpu, self.rd(), f64::from_bits(data), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(S)]
+    #[match_code(0x3027)]
+    #[mask(0x707f)]
+    ,Fsd);
+
+impl Executable for Fsd {
+    // M[x[rs1] + sext(offset)] = f[rs2][63:0]
+    // 浮点存双字 (Floating-point Store Doubleword). S-type, RV32D and RV64D.
+    // 把寄存器 f[rs2]中的双精度浮点数存入内存地址 x[rs1] + sign-extend(offset)中。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let data = cpu.state.fs.reg(self.rs2() as u8);
+        cpu.mmu
+            .store::<u64>(&cpu.state, rs1.wrapping_add(self.imm_sext()), data)?;
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x2000043)]
+    #[mask(0x600007f)]
+    ,FmaddD);
+
+impl Executable for FmaddD {
+    // f[rd] = f[rs1]×f[rs2]+f[rs3]
+    // 双精度浮点乘加 (Floating-point Fused Multiply-Add, Double-Precision). R4-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]的乘积加上 f[rs3]，只舍入一次，结果写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let rs3 = cpu.state.fs.f64(rs3(self.code) as u8);
+        let (value, flags) = fp::mul_add(rs1, rs2, rs3, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x2000047)]
+    #[mask(0x600007f)]
+    ,FmsubD);
+
+impl Executable for FmsubD {
+    // f[rd] = f[rs1]×f[rs2]-f[rs3]
+    // 双精度浮点乘减 (Floating-point Fused Multiply-Subtract, Double-Precision). R4-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]的乘积减去 f[rs3]，只舍入一次，结果写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let rs3 = cpu.state.fs.f64(rs3(self.code) as u8);
+        let (value, flags) = fp::mul_add(rs1, rs2, -rs3, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x200004b)]
+    #[mask(0x600007f)]
+    ,FnmsubD);
+
+impl Executable for FnmsubD {
+    // f[rd] = -f[rs1]×f[rs2]+f[rs3]
+    // 双精度浮点乘取反减 (Floating-point Fused Negative Multiply-Subtract, Double-Precision). R4-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]的乘积取反后加上 f[rs3]，只舍入一次，结果写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let rs3 = cpu.state.fs.f64(rs3(self.code) as u8);
+        let (value, flags) = fp::mul_add(-rs1, rs2, rs3, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x200004f)]
+    #[mask(0x600007f)]
+    ,FnmaddD);
+
+impl Executable for FnmaddD {
+    // f[rd] = -f[rs1]×f[rs2]-f[rs3]
+    // 双精度浮点乘取反加 (Floating-point Fused Negative Multiply-Add, Double-Precision). R4-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]的乘积取反后减去 f[rs3]，只舍入一次，结果写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let rs3 = cpu.state.fs.f64(rs3(self.code) as u8);
+        let (value, flags) = fp::mul_add(-rs1, rs2, -rs3, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x2000053)]
+    #[mask(0xfe00007f)]
+    ,FaddD);
+
+impl Executable for FaddD {
+    // f[rd] = f[rs1] + f[rs2]
+    // 双精度浮点加 (Floating-point Add, Double-Precision). R-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]中的双精度浮点数相加，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::add(rs1, rs2, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xa000053)]
+    #[mask(0xfe00007f)]
+    ,FsubD);
+
+impl Executable for FsubD {
+    // f[rd] = f[rs1] - f[rs2]
+    // 双精度浮点减 (Floating-point Subtract, Double-Precision). R-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]中的双精度浮点数相减，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::sub(rs1, rs2, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x12000053)]
+    #[mask(0xfe00007f)]
+    ,FmulD);
+
+impl Executable for FmulD {
+    // f[rd] = f[rs1] × f[rs2]
+    // 双精度浮点乘 (Floating-point Multiply, Double-Precision). R-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]中的双精度浮点数相乘，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::mul(rs1, rs2, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x1a000053)]
+    #[mask(0xfe00007f)]
+    ,FdivD);
+
+impl Executable for FdivD {
+    // f[rd] = f[rs1] ÷ f[rs2]
+    // 双精度浮点除 (Floating-point Divide, Double-Precision). R-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]中的双精度浮点数相除，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::div(rs1, rs2, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x5a000053)]
+    #[mask(0xfff0007f)]
+    ,FsqrtD);
+
+impl Executable for FsqrtD {
+    // f[rd] = √f[rs1]
+    // 双精度浮点平方根 (Floating-point Square Root, Double-Precision). R-type, RV32D and RV64D.
+    // 计算 f[rs1]中的双精度浮点数的平方根，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let (value, flags) = fp::sqrt(rs1, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x22000053)]
+    #[mask(0xfe00707f)]
+    ,FsgnjD);
+
+impl Executable for FsgnjD {
+    // f[rd] = {f[rs2][63], f[rs1][62:0]}
+    // 双精度浮点符号注入 (Floating-point Sign Inject, Double-Precision). R-type, RV32D and RV64D.
+    // 用 f[rs2]的符号位和 f[rs1]的其余位构造双精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.fs.reg(self.rs2() as u8);
+        let value = (rs1 & !(1 << 63)) | (rs2 & 1 << 63);
+        write_f64(cpu, self.rd(), f64::from_bits(value), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x22001053)]
+    #[mask(0xfe00707f)]
+    ,FsgnjnD);
+
+impl Executable for FsgnjnD {
+    // f[rd] = {~f[rs2][63], f[rs1][62:0]}
+    // 双精度浮点符号取反注入 (Floating-point Sign Inject-Negate, Double-Precision). R-type, RV32D and RV64D.
+    // 用 f[rs2]取反的符号位和 f[rs1]的其余位构造双精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.fs.reg(self.rs2() as u8);
+        let value = (rs1 & !(1 << 63)) | (!rs2 & 1 << 63);
+        write_f64(cpu, self.rd(), f64::from_bits(value), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x22002053)]
+    #[mask(0xfe00707f)]
+    ,FsgnjxD);
+
+impl Executable for FsgnjxD {
+    // f[rd] = {f[rs1][63] ^ f[rs2][63], f[rs1][62:0]}
+    // 双精度浮点符号异或注入 (Floating-point Sign Inject-XOR, Double-Precision). R-type, RV32D and RV64D.
+    // 用 f[rs1]和 f[rs2]符号位的异或和 f[rs1]的其余位构造双精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.reg(self.rs1() as u8);
+        let rs2 = cpu.state.fs.reg(self.rs2() as u8);
+        let value = (rs1 & !(1 << 63)) | ((rs1 ^ rs2) & 1 << 63);
+        write_f64(cpu, self.rd(), f64::from_bits(value), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x2a000053)]
+    #[mask(0xfe00707f)]
+    ,FminD);
+
+impl Executable for FminD {
+    // f[rd] = min(f[rs1], f[rs2])
+    // 双精度浮点最小值 (Floating-point Minimum, Double-Precision). R-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]中较小的双精度浮点数写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::min_max(rs1, rs2, false);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x2a001053)]
+    #[mask(0xfe00707f)]
+    ,FmaxD);
+
+impl Executable for FmaxD {
+    // f[rd] = max(f[rs1], f[rs2])
+    // 双精度浮点最大值 (Floating-point Maximum, Double-Precision). R-type, RV32D and RV64D.
+    // 把 f[rs1]和 f[rs2]中较大的双精度浮点数写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::min_max(rs1, rs2, true);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x40100053)]
+    #[mask(0xfff0007f)]
+    ,FcvtSD);
+
+impl Executable for FcvtSD {
+    // f[rd] = f32_f64(f[rs1])
+    // 双精度浮点向单精度浮点转换 (Floating-point Convert to Single from Double). R-type, RV32D and RV64D.
+    // 把 f[rs1]中的双精度浮点数转换为单精度浮点数，舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let (value, flags) = fp::convert::<f64, f32>(rs1, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x42000053)]
+    #[mask(0xfff0007f)]
+    ,FcvtDS);
+
+impl Executable for FcvtDS {
+    // f[rd] = f64_f32(f[rs1])
+    // 单精度浮点向双精度浮点转换 (Floating-point Convert to Double from Single). R-type, RV32D and RV64D.
+    // 把 f[rs1]中的单精度浮点数转换为双精度浮点数，写入 f[rd]。转换总是精确的。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let (value, flags) = fp::convert::<f32, f64>(rs1, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xa2002053)]
+    #[mask(0xfe00707f)]
+    ,FeqD);
+
+impl Executable for FeqD {
+    // x[rd] = f[rs1] == f[rs2]
+    // 双精度浮点相等 (Floating-point Equals, Double-Precision). R-type, RV32D and RV64D.
+    // 若 f[rs1]和 f[rs2]中的双精度浮点数相等，向 x[rd]写入 1，否则写入 0。只有 signaling NaN 引发无效操作异常。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::compare(rs1, rs2, true, f64::eq);
+        write_x(cpu, self.rd(), value as RegT, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xa2001053)]
+    #[mask(0xfe00707f)]
+    ,FltD);
+
+impl Executable for FltD {
+    // x[rd] = f[rs1] < f[rs2]
+    // 双精度浮点小于 (Floating-point Less Than, Double-Precision). R-type, RV32D and RV64D.
+    // 若 f[rs1]中的双精度浮点数小于 f[rs2]，向 x[rd]写入 1，否则写入 0。任一操作数为 NaN 时引发无效操作异常。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::compare(rs1, rs2, false, f64::lt);
+        write_x(cpu, self.rd(), value as RegT, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xa2000053)]
+    #[mask(0xfe00707f)]
+    ,FleD);
+
+impl Executable for FleD {
+    // x[rd] = f[rs1] ≤ f[rs2]
+    // 双精度浮点小于等于 (Floating-point Less Than or Equal, Double-Precision). R-type, RV32D and RV64D.
+    // 若 f[rs1]中的双精度浮点数小于等于 f[rs2]，向 x[rd]写入 1，否则写入 0。任一操作数为 NaN 时引发无效操作异常。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f64(self.rs2() as u8);
+        let (value, flags) = fp::compare(rs1, rs2, false, f64::le);
+        write_x(cpu, self.rd(), value as RegT, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xe2001053)]
+    #[mask(0xfff0707f)]
+    ,FclassD);
+
+impl Executable for FclassD {
+    // x[rd] = classifyd(f[rs1])
+    // 双精度浮点分类 (Floating-point Classify, Double-Precision). R-type, RV32D and RV64D.
+    // 把一个表示 f[rs1]中双精度浮点数类别的掩码写入 x[rd]，掩码中只有一位被置位。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        write_x(cpu, self.rd(), fp::classify(rs1) as RegT, 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xc2000053)]
+    #[mask(0xfff0007f)]
+    ,FcvtWD);
+
+impl Executable for FcvtWD {
+    // x[rd] = sext(f64_to_i32(f[rs1]))
+    // 双精度浮点向字转换 (Floating-point Convert to Word from Double). R-type, RV32D and RV64D.
+    // 把 f[rs1]中的双精度浮点数转换为 32 位有符号整数，符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let (value, flags) = fp::to_int(rs1, 32, true, rm);
+        write_x(cpu, self.rd(), sext(value as RegT, 32), flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xc2100053)]
+    #[mask(0xfff0007f)]
+    ,FcvtWuD);
+
+impl Executable for FcvtWuD {
+    // x[rd] = sext(f64_to_u32(f[rs1]))
+    // 双精度浮点向无符号字转换 (Floating-point Convert to Unsigned Word from Double). R-type, RV32D and RV64D.
+    // 把 f[rs1]中的双精度浮点数转换为 32 位无符号整数，符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let (value, flags) = fp::to_int(rs1, 32, false, rm);
+        write_x(cpu, self.rd(), sext(value as RegT, 32), flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xc2200053)]
+    #[mask(0xfff0007f)]
+    #[xlen(64)]
+    ,FcvtLD);
+
+impl Executable for FcvtLD {
+    // x[rd] = sext(f64_to_i64(f[rs1]))
+    // 双精度浮点向长整型转换 (Floating-point Convert to Long from Double). R-type, RV64D.
+    // 把 f[rs1]中的双精度浮点数转换为 64 位有符号整数，写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let (value, flags) = fp::to_int(rs1, 64, true, rm);
+        write_x(cpu, self.rd(), sext(value as RegT, 64), flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xc2300053)]
+    #[mask(0xfff0007f)]
+    #[xlen(64)]
+    ,FcvtLuD);
+
+impl Executable for FcvtLuD {
+    // x[rd] = sext(f64_to_u64(f[rs1]))
+    // 双精度浮点向无符号长整型转换 (Floating-point Convert to Unsigned Long from Double). R-type, RV64D.
+    // 把 f[rs1]中的双精度浮点数转换为 64 位无符号整数，写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f64(self.rs1() as u8);
+        let (value, flags) = fp::to_int(rs1, 64, false, rm);
+        write_x(cpu, self.rd(), sext(value as RegT, 64), flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xd2000053)]
+    #[mask(0xfff0007f)]
+    ,FcvtDW);
+
+impl Executable for FcvtDW {
+    // f[rd] = f64_i32(x[rs1])
+    // 字向双精度浮点转换 (Floating-point Convert to Double from Word). R-type, RV32D and RV64D.
+    // 把 x[rs1]中的 32 位有符号整数转换为双精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let (value, flags) = fp::from_int(rs1 as i32 as i128, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xd2100053)]
+    #[mask(0xfff0007f)]
+    ,FcvtDWu);
+
+impl Executable for FcvtDWu {
+    // f[rd] = f64_u32(x[rs1])
+    // 无符号字向双精度浮点转换 (Floating-point Convert to Double from Unsigned Word). R-type, RV32D and RV64D.
+    // 把 x[rs1]中的 32 位无符号整数转换为双精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let (value, flags) = fp::from_int(rs1 as u32 as i128, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xd2200053)]
+    #[mask(0xfff0007f)]
+    #[xlen(64)]
+    ,FcvtDL);
+
+impl Executable for FcvtDL {
+    // f[rd] = f64_i64(x[rs1])
+    // 长整型向双精度浮点转换 (Floating-point Convert to Double from Long). R-type, RV64D.
+    // 把 x[rs1]中的 64 位有符号整数转换为双精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let (value, flags) = fp::from_int(rs1 as i64 as i128, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xd2300053)]
+    #[mask(0xfff0007f)]
+    #[xlen(64)]
+    ,FcvtDLu);
+
+impl Executable for FcvtDLu {
+    // f[rd] = f64_u64(x[rs1])
+    // 无符号长整型向双精度浮点转换 (Floating-point Convert to Double from Unsigned Long). R-type, RV64D.
+    // 把 x[rs1]中的 64 位无符号整数转换为双精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let (value, flags) = fp::from_int(rs1 as i128, rm);
+        write_f64(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xe2000053)]
+    #[mask(0xfff0707f)]
+    #[xlen(64)]
+    ,FmvXD);
+
+impl Executable for FmvXD {
+    // x[rd] = f[rs1][63:0]
+    // 双精度浮点移动到整数 (Floating-point Move Doubleword to Integer). R-type, RV64D.
+    // 把 f[rs1]中的双精度浮点数原样复制到 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let value = cpu.state.fs.reg(self.rs1() as u8);
+        write_x(cpu, self.rd(), value, 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xf2000053)]
+    #[mask(0xfff0707f)]
+    #[xlen(64)]
+    ,FmvDX);
+
+impl Executable for FmvDX {
+    // f[rd] = x[rs1][63:0]
+    // 整数移动到双精度浮点 (Floating-point Move Doubleword from Integer). R-type, RV64D.
+    // 把 x[rs1]原样复制到 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        write_f64(cpu, self.rd(), f64::from_bits(rs1), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cpu::Cpu, testing::*, RegT, XLen};
+
+    /// OP-FP 指令，`funct3` 为 rm 或功能码。
+    const fn fop(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32) -> u32 {
+        r_type(funct7, rs2, rs1, funct3, rd, 0x53)
+    }
+
+    const fn fld(rd: u32, rs1: u32, offset: i32) -> u32 {
+        i_type(offset, rs1, 3, rd, 0x07)
+    }
+
+    const fn fsd(rs2: u32, rs1: u32, offset: i32) -> u32 {
+        s_type(offset, rs2, rs1, 3, 0x27)
+    }
+
+    const fn fmv_d_x(rd: u32, rs1: u32) -> u32 {
+        fop(0x79, 0, rs1, 0, rd)
+    }
+
+    const fn fmv_x_d(rd: u32, rs1: u32) -> u32 {
+        fop(0x71, 0, rs1, 0, rd)
+    }
+
+    /// fcvt.s.d，按 frm 舍入。
+    const fn fcvt_s_d(rd: u32, rs1: u32) -> u32 {
+        fop(0x20, 1, rs1, 7, rd)
+    }
+
+    const fn fcvt_d_s(rd: u32, rs1: u32) -> u32 {
+        fop(0x21, 0, rs1, 7, rd)
+    }
+
+    /// 在 FS 为 Initial 的 hart 上执行程序，a1 为 `value` 的位模式。
+    fn run(xlen: XLen, program: &[u32], value: f64) -> Cpu {
+        let mut cpu = Cpu::with_program(xlen, program);
+        cpu.state.csrs.set_csr(0x300, 1 << 13);
+        cpu.state.xs.set_reg(11, value.to_bits() & xlen.mask());
+        cpu.run_until_ebreak(20);
+        cpu
+    }
+
+    #[test]
+    fn doubles_move_through_memory_and_registers() {
+        let program = [
+            auipc(12, 0),
+            fmv_d_x(1, 11),
+            fsd(1, 12, 0x100),
+            fld(2, 12, 0x100),
+            fmv_x_d(10, 2),
+            EBREAK,
+        ];
+        let cpu = run(XLen::X64, &program, -0.1);
+        assert_eq!(cpu.state.xs.reg(10), (-0.1f64).to_bits());
+        let stored = cpu.mmu.bus.borrow().read::<u64>(DRAM_BASE + 0x100);
+        assert_eq!(stored.unwrap(), (-0.1f64).to_bits());
+    }
+
+    #[test]
+    fn conversions_between_precisions_round() {
+        // 0.1 在单精度下不精确，舍入后再转回双精度得到单精度的值
+        let program = [
+            fmv_d_x(1, 11),
+            fcvt_s_d(2, 1),
+            fcvt_d_s(3, 2),
+            fmv_x_d(10, 3),
+            csrrs(12, 0x001, 0),
+            EBREAK,
+        ];
+        let cpu = run(XLen::X64, &program, 0.1);
+        assert_regs!(cpu.state.xs, a0 = (0.1f32 as f64).to_bits(), a2 = 0x01);
+        // 单精度结果经过 NaN-boxing
+        assert_eq!(cpu.state.fs.reg(2) >> 32, 0xffff_ffff);
+
+        let cpu = run(XLen::X64, &program, 1e300);
+        assert_regs!(cpu.state.xs, a0 = f64::INFINITY.to_bits(), a2 = 0x05);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn rv32_has_no_moves_to_doubles() {
+        run(XLen::X32, &[fmv_d_x(1, 11), EBREAK], 1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn rv32_has_no_moves_from_doubles() {
+        run(XLen::X32, &[fmv_x_d(10, 1), EBREAK], 1.0);
+    }
+
+    #[test]
+    fn clearing_f_clears_d() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[fmv_d_x(1, 11), EBREAK]);
+        cpu.state.csrs.set_csr(0x300, 1 << 13);
+        let misa = cpu.state.csrs.csr(0x301);
+        cpu.state.csrs.set_csr(0x301, misa & !(1 << 5));
+        assert_eq!(cpu.state.csrs.csr(0x301) & (1 << 3 | 1 << 5), 0);
+        cpu.state.csrs.set_csr(0x301, misa);
+        assert_eq!(cpu.state.csrs.csr(0x301), misa);
+        cpu.run_until_ebreak(10);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn d_needs_misa_d() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[fmv_d_x(1, 11), EBREAK]);
+        cpu.state.csrs.set_csr(0x300, 1 << 13);
+        let misa = cpu.state.csrs.csr(0x301);
+        cpu.state.csrs.set_csr(0x301, misa & !(1 << 3));
+        cpu.run_until_ebreak(10);
+    }
+
+    #[test]
+    fn rv32_loads_and_stores_doubles() {
+        let program = [auipc(12, 0), fld(1, 12, 0x100), fsd(1, 12, 0x108), EBREAK];
+        let mut cpu = Cpu::with_program(XLen::X32, &program);
+        cpu.state.csrs.set_csr(0x300, 1 << 13);
+        let bits: RegT = 2.5f64.to_bits();
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u64>(DRAM_BASE + 0x100, bits)
+            .unwrap();
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.fs.reg(1), bits);
+        let stored = cpu.mmu.bus.borrow().read::<u64>(DRAM_BASE + 0x108);
+        assert_eq!(stored.unwrap(), bits);
+    }
+}
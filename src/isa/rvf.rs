@@ -0,0 +1,871 @@
+/// 单精度浮点指令集
+use crate::{cpu::Cpu, trap::Exception, Executable, Format, Insn, InsnEntry, RegT};
+use proc_macros::Instruction;
+
+use super::{
+    fp::{self, RoundingMode},
+    sext,
+};
+
+/// 指令表 (instruction_table) 中本模块指令所属的扩展。
+const EXT: &str = "F";
+
+/// 检查浮点指令是否可用：misa 中 F 扩展未关闭且 mstatus.FS 不为 Off，否则为非法指令。
+pub(super) fn check_enabled(cpu: &Cpu) -> Result<(), Exception> {
+    if !cpu.state.csrs.has_extension('F') || cpu.state.csrs.mstatus().fs() == 0 {
+        return Err(Exception::IllegalInstruction(0));
+    }
+    Ok(())
+}
+
+/// 检查浮点指令是否可用，并取得指令 rm 字段选择的舍入模式。
+pub(super) fn rounding_mode(cpu: &Cpu, code: u32) -> Result<RoundingMode, Exception> {
+    check_enabled(cpu)?;
+    RoundingMode::new(cpu, (code >> 12) & 0x7)
+}
+
+/// R4-type 指令的第三个源寄存器。
+pub(super) fn rs3(code: u32) -> u32 {
+    code >> 27
+}
+
+/// 写 f[rd]，累积异常标志并把浮点状态标记为 Dirty。
+pub(super) fn write_f32(cpu: &mut Cpu, rd: u32, value: f32, flags: u8) {
+    cpu.state.fs.set_f32(rd as u8, value);
+    cpu.state.csrs.accrue_fflags(flags as RegT);
+    cpu.state.csrs.set_fs_dirty();
+}
+
+/// 写 x[rd] 并累积异常标志。
+pub(super) fn write_x(cpu: &mut Cpu, rd: u32, value: RegT, flags: u8) {
+    cpu.state.xs.set_reg(rd as u8, value & cpu.xlen.mask());
+    cpu.state.csrs.accrue_fflags(flags as RegT);
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x2007)]
+    #[mask(0x707f)]
+    ,Flw);
+
+impl Executable for Flw {
+    // f[rd] = M[x[rs1] + sext(offset)][31:0]
+    // 浮点加载字 (Floating-point Load Word). I-type, RV32F and RV64F.
+    // 从地址 x[rs1] + sign-extend(offset)读取单精度浮点数，NaN-boxing 后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let data = cpu
+            .mmu
+            .load::<u32>(&cpu.state, rs1.wrapping_add(self.imm_sext()))?;
+        write_f32(cpu, self.rd(), f32::from_bits(data), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(S)]
+    #[match_code(0x2027)]
+    #[mask(0x707f)]
+    ,Fsw);
+
+impl Executable for Fsw {
+    // M[x[rs1] + sext(offset)] = f[rs2][31:0]
+    // 浮点存字 (Floating-point Store Word). S-type, RV32F and RV64F.
+    // 把寄存器 f[rs2]中的低 32 位存入内存地址 x[rs1] + sign-extend(offset)中。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let data = cpu.state.fs.reg(self.rs2() as u8) as u32;
+        cpu.mmu
+            .store::<u32>(&cpu.state, rs1.wrapping_add(self.imm_sext()), data)?;
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x43)]
+    #[mask(0x600007f)]
+    ,FmaddS);
+
+impl Executable for FmaddS {
+    // f[rd] = f[rs1]×f[rs2]+f[rs3]
+    // 单精度浮点乘加 (Floating-point Fused Multiply-Add, Single-Precision). R4-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]的乘积加上 f[rs3]，只舍入一次，结果写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let rs3 = cpu.state.fs.f32(rs3(self.code) as u8);
+        let (value, flags) = fp::mul_add(rs1, rs2, rs3, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x47)]
+    #[mask(0x600007f)]
+    ,FmsubS);
+
+impl Executable for FmsubS {
+    // f[rd] = f[rs1]×f[rs2]-f[rs3]
+    // 单精度浮点乘减 (Floating-point Fused Multiply-Subtract, Single-Precision). R4-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]的乘积减去 f[rs3]，只舍入一次，结果写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let rs3 = cpu.state.fs.f32(rs3(self.code) as u8);
+        let (value, flags) = fp::mul_add(rs1, rs2, -rs3, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x4b)]
+    #[mask(0x600007f)]
+    ,FnmsubS);
+
+impl Executable for FnmsubS {
+    // f[rd] = -f[rs1]×f[rs2]+f[rs3]
+    // 单精度浮点乘取反减 (Floating-point Fused Negative Multiply-Subtract, Single-Precision). R4-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]的乘积取反后加上 f[rs3]，只舍入一次，结果写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let rs3 = cpu.state.fs.f32(rs3(self.code) as u8);
+        let (value, flags) = fp::mul_add(-rs1, rs2, rs3, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x4f)]
+    #[mask(0x600007f)]
+    ,FnmaddS);
+
+impl Executable for FnmaddS {
+    // f[rd] = -f[rs1]×f[rs2]-f[rs3]
+    // 单精度浮点乘取反加 (Floating-point Fused Negative Multiply-Add, Single-Precision). R4-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]的乘积取反后减去 f[rs3]，只舍入一次，结果写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let rs3 = cpu.state.fs.f32(rs3(self.code) as u8);
+        let (value, flags) = fp::mul_add(-rs1, rs2, -rs3, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x53)]
+    #[mask(0xfe00007f)]
+    ,FaddS);
+
+impl Executable for FaddS {
+    // f[rd] = f[rs1] + f[rs2]
+    // 单精度浮点加 (Floating-point Add, Single-Precision). R-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]中的单精度浮点数相加，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::add(rs1, rs2, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x8000053)]
+    #[mask(0xfe00007f)]
+    ,FsubS);
+
+impl Executable for FsubS {
+    // f[rd] = f[rs1] - f[rs2]
+    // 单精度浮点减 (Floating-point Subtract, Single-Precision). R-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]中的单精度浮点数相减，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::sub(rs1, rs2, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x10000053)]
+    #[mask(0xfe00007f)]
+    ,FmulS);
+
+impl Executable for FmulS {
+    // f[rd] = f[rs1] × f[rs2]
+    // 单精度浮点乘 (Floating-point Multiply, Single-Precision). R-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]中的单精度浮点数相乘，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::mul(rs1, rs2, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x18000053)]
+    #[mask(0xfe00007f)]
+    ,FdivS);
+
+impl Executable for FdivS {
+    // f[rd] = f[rs1] ÷ f[rs2]
+    // 单精度浮点除 (Floating-point Divide, Single-Precision). R-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]中的单精度浮点数相除，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::div(rs1, rs2, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x58000053)]
+    #[mask(0xfff0007f)]
+    ,FsqrtS);
+
+impl Executable for FsqrtS {
+    // f[rd] = √f[rs1]
+    // 单精度浮点平方根 (Floating-point Square Root, Single-Precision). R-type, RV32F and RV64F.
+    // 计算 f[rs1]中的单精度浮点数的平方根，结果舍入后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let (value, flags) = fp::sqrt(rs1, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x20000053)]
+    #[mask(0xfe00707f)]
+    ,FsgnjS);
+
+impl Executable for FsgnjS {
+    // f[rd] = {f[rs2][31], f[rs1][30:0]}
+    // 单精度浮点符号注入 (Floating-point Sign Inject, Single-Precision). R-type, RV32F and RV64F.
+    // 用 f[rs2]的符号位和 f[rs1]的其余位构造单精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8).to_bits();
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8).to_bits();
+        let value = (rs1 & 0x7fff_ffff) | (rs2 & 0x8000_0000);
+        write_f32(cpu, self.rd(), f32::from_bits(value), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x20001053)]
+    #[mask(0xfe00707f)]
+    ,FsgnjnS);
+
+impl Executable for FsgnjnS {
+    // f[rd] = {~f[rs2][31], f[rs1][30:0]}
+    // 单精度浮点符号取反注入 (Floating-point Sign Inject-Negate, Single-Precision). R-type, RV32F and RV64F.
+    // 用 f[rs2]取反的符号位和 f[rs1]的其余位构造单精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8).to_bits();
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8).to_bits();
+        let value = (rs1 & 0x7fff_ffff) | (!rs2 & 0x8000_0000);
+        write_f32(cpu, self.rd(), f32::from_bits(value), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x20002053)]
+    #[mask(0xfe00707f)]
+    ,FsgnjxS);
+
+impl Executable for FsgnjxS {
+    // f[rd] = {f[rs1][31] ^ f[rs2][31], f[rs1][30:0]}
+    // 单精度浮点符号异或注入 (Floating-point Sign Inject-XOR, Single-Precision). R-type, RV32F and RV64F.
+    // 用 f[rs1]和 f[rs2]符号位的异或和 f[rs1]的其余位构造单精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8).to_bits();
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8).to_bits();
+        let value = (rs1 & 0x7fff_ffff) | ((rs1 ^ rs2) & 0x8000_0000);
+        write_f32(cpu, self.rd(), f32::from_bits(value), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x28000053)]
+    #[mask(0xfe00707f)]
+    ,FminS);
+
+impl Executable for FminS {
+    // f[rd] = min(f[rs1], f[rs2])
+    // 单精度浮点最小值 (Floating-point Minimum, Single-Precision). R-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]中较小的单精度浮点数写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::min_max(rs1, rs2, false);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x28001053)]
+    #[mask(0xfe00707f)]
+    ,FmaxS);
+
+impl Executable for FmaxS {
+    // f[rd] = max(f[rs1], f[rs2])
+    // 单精度浮点最大值 (Floating-point Maximum, Single-Precision). R-type, RV32F and RV64F.
+    // 把 f[rs1]和 f[rs2]中较大的单精度浮点数写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::min_max(rs1, rs2, true);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xc0000053)]
+    #[mask(0xfff0007f)]
+    ,FcvtWS);
+
+impl Executable for FcvtWS {
+    // x[rd] = sext(f32_to_i32(f[rs1]))
+    // 单精度浮点向字转换 (Floating-point Convert to Word from Single). R-type, RV32F and RV64F.
+    // 把 f[rs1]中的单精度浮点数转换为 32 位有符号整数，符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let (value, flags) = fp::to_int(rs1, 32, true, rm);
+        write_x(cpu, self.rd(), sext(value as RegT, 32), flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xc0100053)]
+    #[mask(0xfff0007f)]
+    ,FcvtWuS);
+
+impl Executable for FcvtWuS {
+    // x[rd] = sext(f32_to_u32(f[rs1]))
+    // 单精度浮点向无符号字转换 (Floating-point Convert to Unsigned Word from Single). R-type, RV32F and RV64F.
+    // 把 f[rs1]中的单精度浮点数转换为 32 位无符号整数，符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let (value, flags) = fp::to_int(rs1, 32, false, rm);
+        write_x(cpu, self.rd(), sext(value as RegT, 32), flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xc0200053)]
+    #[mask(0xfff0007f)]
+    #[xlen(64)]
+    ,FcvtLS);
+
+impl Executable for FcvtLS {
+    // x[rd] = sext(f32_to_i64(f[rs1]))
+    // 单精度浮点向长整型转换 (Floating-point Convert to Long from Single). R-type, RV64F.
+    // 把 f[rs1]中的单精度浮点数转换为 64 位有符号整数，写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let (value, flags) = fp::to_int(rs1, 64, true, rm);
+        write_x(cpu, self.rd(), sext(value as RegT, 64), flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xc0300053)]
+    #[mask(0xfff0007f)]
+    #[xlen(64)]
+    ,FcvtLuS);
+
+impl Executable for FcvtLuS {
+    // x[rd] = sext(f32_to_u64(f[rs1]))
+    // 单精度浮点向无符号长整型转换 (Floating-point Convert to Unsigned Long from Single). R-type, RV64F.
+    // 把 f[rs1]中的单精度浮点数转换为 64 位无符号整数，写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let (value, flags) = fp::to_int(rs1, 64, false, rm);
+        write_x(cpu, self.rd(), sext(value as RegT, 64), flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xe0000053)]
+    #[mask(0xfff0707f)]
+    ,FmvXW);
+
+impl Executable for FmvXW {
+    // x[rd] = sext(f[rs1][31:0])
+    // 单精度浮点移动到整数 (Floating-point Move Word to Integer). R-type, RV32F and RV64F.
+    // 把 f[rs1]的低 32 位原样复制，符号位扩展后写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let value = cpu.state.fs.reg(self.rs1() as u8);
+        write_x(cpu, self.rd(), sext(value, 32), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xa0002053)]
+    #[mask(0xfe00707f)]
+    ,FeqS);
+
+impl Executable for FeqS {
+    // x[rd] = f[rs1] == f[rs2]
+    // 单精度浮点相等 (Floating-point Equals, Single-Precision). R-type, RV32F and RV64F.
+    // 若 f[rs1]和 f[rs2]中的单精度浮点数相等，向 x[rd]写入 1，否则写入 0。只有 signaling NaN 引发无效操作异常。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::compare(rs1, rs2, true, f64::eq);
+        write_x(cpu, self.rd(), value as RegT, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xa0001053)]
+    #[mask(0xfe00707f)]
+    ,FltS);
+
+impl Executable for FltS {
+    // x[rd] = f[rs1] < f[rs2]
+    // 单精度浮点小于 (Floating-point Less Than, Single-Precision). R-type, RV32F and RV64F.
+    // 若 f[rs1]中的单精度浮点数小于 f[rs2]，向 x[rd]写入 1，否则写入 0。任一操作数为 NaN 时引发无效操作异常。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::compare(rs1, rs2, false, f64::lt);
+        write_x(cpu, self.rd(), value as RegT, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xa0000053)]
+    #[mask(0xfe00707f)]
+    ,FleS);
+
+impl Executable for FleS {
+    // x[rd] = f[rs1] ≤ f[rs2]
+    // 单精度浮点小于等于 (Floating-point Less Than or Equal, Single-Precision). R-type, RV32F and RV64F.
+    // 若 f[rs1]中的单精度浮点数小于等于 f[rs2]，向 x[rd]写入 1，否则写入 0。任一操作数为 NaN 时引发无效操作异常。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        let rs2 = cpu.state.fs.f32(self.rs2() as u8);
+        let (value, flags) = fp::compare(rs1, rs2, false, f64::le);
+        write_x(cpu, self.rd(), value as RegT, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xe0001053)]
+    #[mask(0xfff0707f)]
+    ,FclassS);
+
+impl Executable for FclassS {
+    // x[rd] = classifys(f[rs1])
+    // 单精度浮点分类 (Floating-point Classify, Single-Precision). R-type, RV32F and RV64F.
+    // 把一个表示 f[rs1]中单精度浮点数类别的掩码写入 x[rd]，掩码中只有一位被置位。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.fs.f32(self.rs1() as u8);
+        write_x(cpu, self.rd(), fp::classify(rs1) as RegT, 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xd0000053)]
+    #[mask(0xfff0007f)]
+    ,FcvtSW);
+
+impl Executable for FcvtSW {
+    // f[rd] = f32_i32(x[rs1])
+    // 字向单精度浮点转换 (Floating-point Convert to Single from Word). R-type, RV32F and RV64F.
+    // 把 x[rs1]中的 32 位有符号整数转换为单精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let (value, flags) = fp::from_int(rs1 as i32 as i128, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xd0100053)]
+    #[mask(0xfff0007f)]
+    ,FcvtSWu);
+
+impl Executable for FcvtSWu {
+    // f[rd] = f32_u32(x[rs1])
+    // 无符号字向单精度浮点转换 (Floating-point Convert to Single from Unsigned Word). R-type, RV32F and RV64F.
+    // 把 x[rs1]中的 32 位无符号整数转换为单精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let (value, flags) = fp::from_int(rs1 as u32 as i128, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xd0200053)]
+    #[mask(0xfff0007f)]
+    #[xlen(64)]
+    ,FcvtSL);
+
+impl Executable for FcvtSL {
+    // f[rd] = f32_i64(x[rs1])
+    // 长整型向单精度浮点转换 (Floating-point Convert to Single from Long). R-type, RV64F.
+    // 把 x[rs1]中的 64 位有符号整数转换为单精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let (value, flags) = fp::from_int(rs1 as i64 as i128, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xd0300053)]
+    #[mask(0xfff0007f)]
+    #[xlen(64)]
+    ,FcvtSLu);
+
+impl Executable for FcvtSLu {
+    // f[rd] = f32_u64(x[rs1])
+    // 无符号长整型向单精度浮点转换 (Floating-point Convert to Single from Unsigned Long). R-type, RV64F.
+    // 把 x[rs1]中的 64 位无符号整数转换为单精度浮点数，写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rm = rounding_mode(cpu, self.code)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        let (value, flags) = fp::from_int(rs1 as i128, rm);
+        write_f32(cpu, self.rd(), value, flags);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0xf0000053)]
+    #[mask(0xfff0707f)]
+    ,FmvWX);
+
+impl Executable for FmvWX {
+    // f[rd] = x[rs1][31:0]
+    // 整数移动到单精度浮点 (Floating-point Move Word from Integer). R-type, RV32F and RV64F.
+    // 把 x[rs1]的低 32 位原样复制，NaN-boxing 后写入 f[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu)?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        write_f32(cpu, self.rd(), f32::from_bits(rs1 as u32), 0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cpu::Cpu, register::sstatus::Sstatus, testing::*, RegT, XLen};
+
+    const FFLAGS: u32 = 0x001;
+    const SSTATUS: u32 = 0x100;
+    const NX: RegT = 0x01;
+
+    /// OP-FP 指令，`funct3` 为 rm 或功能码。
+    const fn fop(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32) -> u32 {
+        r_type(funct7, rs2, rs1, funct3, rd, 0x53)
+    }
+
+    const fn fadd_s(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        fop(0x00, rs2, rs1, 7, rd)
+    }
+
+    const fn fdiv_s(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        fop(0x0c, rs2, rs1, 7, rd)
+    }
+
+    const fn fmv_w_x(rd: u32, rs1: u32) -> u32 {
+        fop(0x78, 0, rs1, 0, rd)
+    }
+
+    const fn fmv_x_w(rd: u32, rs1: u32) -> u32 {
+        fop(0x70, 0, rs1, 0, rd)
+    }
+
+    /// 执行程序，a1、a2 为 `a` 和 `b` 的位模式。`fs_on` 时 mstatus.FS 为 Initial。
+    fn run(xlen: XLen, program: &[u32], a: f32, b: f32, fs_on: bool) -> Cpu {
+        let mut cpu = Cpu::with_program(xlen, program);
+        cpu.state
+            .csrs
+            .set_csr(0x300, if fs_on { 1 << 13 } else { 0 });
+        cpu.state.xs.set_reg(11, a.to_bits() as RegT);
+        cpu.state.xs.set_reg(12, b.to_bits() as RegT);
+        cpu.run_until_ebreak(20);
+        cpu
+    }
+
+    const DIVIDE: [u32; 6] = [
+        fmv_w_x(1, 11),
+        fmv_w_x(2, 12),
+        fdiv_s(3, 1, 2),
+        fmv_x_w(10, 3),
+        csrrs(13, FFLAGS, 0),
+        EBREAK,
+    ];
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn fs_off_makes_float_instructions_illegal() {
+        run(XLen::X64, &DIVIDE, 1.0, 3.0, false);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn fs_off_makes_float_csrs_illegal() {
+        run(XLen::X64, &[csrrs(10, FFLAGS, 0), EBREAK], 0.0, 0.0, false);
+    }
+
+    #[test]
+    fn divide_rounds_with_frm_and_accrues_the_flags() {
+        let cpu = run(XLen::X64, &DIVIDE, 1.0, 3.0, true);
+        assert_eq!(
+            (cpu.state.xs.reg(10), cpu.state.xs.reg(13)),
+            (0x3eaa_aaab, NX)
+        );
+        // 写浮点寄存器把 FS 置为 Dirty，SD 随之置位
+        let mstatus = cpu.state.csrs.csr(0x300);
+        assert_eq!((mstatus >> 13) & 3, 3);
+        assert_eq!(mstatus >> 63, 1);
+
+        // rm 为 7 时使用 frm，此处为向零舍入
+        let mut cpu = Cpu::with_program(XLen::X64, &DIVIDE);
+        cpu.state.csrs.set_csr(0x300, 1 << 13);
+        cpu.state.csrs.set_csr(0x002, 1);
+        cpu.state.xs.set_reg(11, 1.0f32.to_bits() as RegT);
+        cpu.state.xs.set_reg(12, 3.0f32.to_bits() as RegT);
+        cpu.run_until_ebreak(20);
+        assert_eq!(
+            (cpu.state.xs.reg(10), cpu.state.xs.reg(13)),
+            (0x3eaa_aaaa, NX)
+        );
+    }
+
+    #[test]
+    fn only_writes_of_float_state_make_it_dirty() {
+        let program = [
+            fmv_x_w(10, 1),
+            csrrs(13, SSTATUS, 0),
+            fadd_s(3, 1, 1),
+            csrrs(14, SSTATUS, 0),
+            EBREAK,
+        ];
+        for &xlen in [XLen::X32, XLen::X64].iter() {
+            let mut cpu = Cpu::with_program(xlen, &program);
+            let mut sstatus = Sstatus::from(0);
+            sstatus.set_fs(2);
+            cpu.state.csrs.set_csr(SSTATUS as u16, sstatus.bits());
+            cpu.run_until_ebreak(20);
+            // 只读浮点寄存器的指令让 FS 保持 Clean，内核切换上下文时无需保存
+            let clean = Sstatus::from(cpu.state.xs.reg(13));
+            assert_eq!((clean.fs(), clean.sd()), (2, false));
+            assert_eq!(cpu.state.xs.reg(13) >> (xlen.len() - 1), 0);
+            let dirty = Sstatus::from(cpu.state.xs.reg(14));
+            assert_eq!((dirty.fs(), dirty.sd()), (3, true));
+            assert_eq!(cpu.state.xs.reg(14) >> (xlen.len() - 1), 1);
+            assert_eq!(cpu.state.csrs.mstatus().fs(), 3);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn reserved_rounding_mode_is_illegal() {
+        let reserved = fop(0x0c, 2, 1, 5, 3);
+        run(XLen::X64, &[reserved, EBREAK], 1.0, 3.0, true);
+    }
+
+    #[test]
+    fn single_values_are_nan_boxed() {
+        let program = [
+            fmv_w_x(1, 11),
+            fmv_x_w(10, 1),
+            fadd_s(3, 2, 2),
+            fmv_x_w(12, 3),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_csr(0x300, 1 << 13);
+        cpu.state.xs.set_reg(11, (-1.0f32).to_bits() as RegT);
+        // 高 32 位不全为 1 的值不是合法的单精度数，按规范 NaN 处理
+        cpu.state.fs.set_reg(2, 1.0f32.to_bits() as u64);
+        cpu.run_until_ebreak(20);
+        assert_eq!(cpu.state.fs.reg(1), 0xffff_ffff_bf80_0000);
+        // fmv.x.w 从第 31 位有符号扩展
+        assert_regs!(
+            cpu.state.xs,
+            a0 = 0xffff_ffff_bf80_0000u64,
+            a2 = 0x7fc0_0000
+        );
+        assert_eq!(cpu.state.fs.reg(3), 0xffff_ffff_7fc0_0000);
+    }
+
+    const FCVT_W_S: u32 = fop(0x60, 0, 1, 1, 10);
+    const FCVT_L_S: u32 = fop(0x60, 2, 1, 1, 10);
+
+    #[test]
+    fn conversions_to_integers_follow_xlen() {
+        let program = [fmv_w_x(1, 11), FCVT_W_S, EBREAK];
+        let cpu = run(XLen::X64, &program, -2.5, 0.0, true);
+        assert_eq!(cpu.state.xs.reg(10), -2i64 as RegT);
+        let cpu = run(XLen::X32, &program, -2.5, 0.0, true);
+        assert_eq!(cpu.state.xs.reg(10), 0xffff_fffe);
+
+        let program = [fmv_w_x(1, 11), FCVT_L_S, EBREAK];
+        let cpu = run(XLen::X64, &program, 3e10, 0.0, true);
+        assert_eq!(cpu.state.xs.reg(10), 30_000_001_024);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn rv32_has_no_64_bit_conversions() {
+        run(
+            XLen::X32,
+            &[fmv_w_x(1, 11), FCVT_L_S, EBREAK],
+            1.0,
+            0.0,
+            true,
+        );
+    }
+}
@@ -1,12 +1,22 @@
 /// 基础整数指令集
+use std::fmt;
+
 use crate::{
-    cpu::Cpu, trap::Exception, Executable, Format, Insn, PrivilegeMode, RegT, SRegT, XLen,
-    INSN_SLICE,
+    cpu::{Cpu, CsrPolicy},
+    hook::{CpuEvent, Transition},
+    register::csrs::Csrs,
+    semihosting,
+    trap::Exception,
+    Executable, Format, Insn, InsnEntry, PrivilegeMode, RegT, SRegT, XLen,
 };
 use bit_field::BitField;
+use log::trace;
 use proc_macros::Instruction;
 
-use super::sext;
+use super::{rvf::check_enabled, sext, signed};
+
+/// 指令表 (instruction_table) 中本模块指令所属的扩展。
+const EXT: &str = "I";
 
 def_insn!(
   #[derive(Instruction)]
@@ -20,11 +30,10 @@ impl Executable for Lui {
     // 高位立即数加载 (Load Upper Immediate). U-type, RV32I and RV64I.
     // 将符号位扩展的 20 位立即数 immediate 左移 12 位，并将低 12 位置零，写入 x[rd]中。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        cpu.state.xs.set_reg(
-            self.rd() as u8,
-            sext(self.imm() as RegT, self.imm_len()) & cpu.xlen.mask(),
-        );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, self.imm_sext() & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -41,12 +50,12 @@ impl Executable for Auipc {
     // PC 加立即数 (Add Upper Immediate to PC). U-type, RV32I and RV64I.
     // 把符号位扩展的 20 位（左移 12 位）立即数加到 pc 上，结果写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         cpu.state.xs.set_reg(
             self.rd() as u8,
             cpu.state.pc.wrapping_add(offset_sext) & cpu.xlen.mask(),
         );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -63,10 +72,10 @@ impl Executable for Jal {
     // 跳转并链接 (Jump and Link). J-type, RV32I and RV64I.
     // 把下一条指令的地址(pc+4)，然后把 pc 设置为当前值加上符号位扩展的offset。rd 默认为 x1。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
-        cpu.state.xs.set_reg(self.rd() as u8, cpu.state.pc + 4);
-        cpu.state
-            .update_pc(cpu.state.pc.wrapping_add(offset_sext) & cpu.xlen.mask());
+        let offset_sext = self.imm_sext();
+        let t = cpu.state.pc.wrapping_add(4) & cpu.xlen.mask();
+        cpu.state.jump(cpu.state.pc.wrapping_add(offset_sext))?;
+        cpu.state.xs.set_reg(self.rd() as u8, t);
         Ok(())
     }
 }
@@ -83,10 +92,10 @@ impl Executable for Jalr {
     // 跳转并寄存器链接 (Jump and Link Register). I-type, RV32I and RV64I.
     // 把 pc 设置为 x[rs1] + sign-extend(offset)，把计算出的地址的最低有效位设为 0，并将原 pc+4的值写入 f[rd]。rd 默认为 x1。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let t = cpu.state.pc + 4;
-        cpu.state.update_pc(rs1.wrapping_add(offset_sext) & !1);
+        let t = cpu.state.pc.wrapping_add(4) & cpu.xlen.mask();
+        cpu.state.jump(rs1.wrapping_add(offset_sext) & !1)?;
         cpu.state.xs.set_reg(self.rd() as u8, t);
         Ok(())
     }
@@ -106,11 +115,11 @@ impl Executable for Beq {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         let rs2 = cpu.state.xs.reg(self.rs2() as u8);
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         if rs1 == rs2 {
-            cpu.state.update_pc(cpu.state.pc.wrapping_add(offset_sext));
+            cpu.state.jump(cpu.state.pc.wrapping_add(offset_sext))?;
         } else {
-            cpu.state.update_pc(cpu.state.pc + 4);
+            cpu.state.advance_pc(4);
         }
         Ok(())
     }
@@ -130,11 +139,11 @@ impl Executable for Bne {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         let rs2 = cpu.state.xs.reg(self.rs2() as u8);
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         if rs1 != rs2 {
-            cpu.state.update_pc(cpu.state.pc.wrapping_add(offset_sext));
+            cpu.state.jump(cpu.state.pc.wrapping_add(offset_sext))?;
         } else {
-            cpu.state.update_pc(cpu.state.pc + 4);
+            cpu.state.advance_pc(4);
         }
         Ok(())
     }
@@ -152,14 +161,14 @@ impl Executable for Blt {
     // 小于时分支 (Branch if Less Than). B-type, RV32I and RV64I.
     // 若寄存器 x[rs1]的值小于寄存器 x[rs2]的值（均视为二进制补码），把 pc 的值设为当前值加上符号位扩展的偏移 offset。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT;
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as SRegT;
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let rs1 = signed(cpu, self.rs1());
+        let rs2 = signed(cpu, self.rs2());
+        let offset_sext = self.imm_sext();
 
         if rs1 < rs2 {
-            cpu.state.update_pc(cpu.state.pc.wrapping_add(offset_sext));
+            cpu.state.jump(cpu.state.pc.wrapping_add(offset_sext))?;
         } else {
-            cpu.state.update_pc(cpu.state.pc + 4);
+            cpu.state.advance_pc(4);
         }
         Ok(())
     }
@@ -177,14 +186,14 @@ impl Executable for Bge {
     // 大于等于时分支 (Branch if Greater Than or Equal). B-type, RV32I and RV64I.
     // 若寄存器 x[rs1]的值大于等于寄存器 x[rs2]的值（均视为二进制补码），把 pc 的值设为当前值加上符号位扩展的偏移 offset。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT;
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as SRegT;
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let rs1 = signed(cpu, self.rs1());
+        let rs2 = signed(cpu, self.rs2());
+        let offset_sext = self.imm_sext();
 
         if rs1 >= rs2 {
-            cpu.state.update_pc(cpu.state.pc.wrapping_add(offset_sext));
+            cpu.state.jump(cpu.state.pc.wrapping_add(offset_sext))?;
         } else {
-            cpu.state.update_pc(cpu.state.pc + 4);
+            cpu.state.advance_pc(4);
         }
         Ok(())
     }
@@ -204,12 +213,12 @@ impl Executable for Bltu {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         let rs2 = cpu.state.xs.reg(self.rs2() as u8);
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
 
         if rs1 < rs2 {
-            cpu.state.update_pc(cpu.state.pc.wrapping_add(offset_sext));
+            cpu.state.jump(cpu.state.pc.wrapping_add(offset_sext))?;
         } else {
-            cpu.state.update_pc(cpu.state.pc + 4);
+            cpu.state.advance_pc(4);
         }
         Ok(())
     }
@@ -229,12 +238,12 @@ impl Executable for Bgeu {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         let rs2 = cpu.state.xs.reg(self.rs2() as u8);
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
 
         if rs1 >= rs2 {
-            cpu.state.update_pc(cpu.state.pc.wrapping_add(offset_sext));
+            cpu.state.jump(cpu.state.pc.wrapping_add(offset_sext))?;
         } else {
-            cpu.state.update_pc(cpu.state.pc + 4);
+            cpu.state.advance_pc(4);
         }
         Ok(())
     }
@@ -254,13 +263,13 @@ impl Executable for Lb {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu
             .mmu
             .load::<u8>(&cpu.state, rs1.wrapping_add(offset_sext))?;
         let value = sext(data as RegT, 8) & cpu.xlen.mask();
         cpu.state.xs.set_reg(self.rd() as u8, value);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -279,13 +288,13 @@ impl Executable for Lh {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu
             .mmu
             .load::<u16>(&cpu.state, rs1.wrapping_add(offset_sext))?;
         let value = sext(data as RegT, 16) & cpu.xlen.mask();
         cpu.state.xs.set_reg(self.rd() as u8, value);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -304,13 +313,13 @@ impl Executable for Lw {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu
             .mmu
             .load::<u32>(&cpu.state, rs1.wrapping_add(offset_sext))?;
         let value = sext(data as RegT, 32) & cpu.xlen.mask();
         cpu.state.xs.set_reg(self.rd() as u8, value);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -329,12 +338,12 @@ impl Executable for Lbu {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu
             .mmu
             .load::<u8>(&cpu.state, rs1.wrapping_add(offset_sext))?;
         cpu.state.xs.set_reg(self.rd() as u8, data as u64);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -353,12 +362,12 @@ impl Executable for Lhu {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu
             .mmu
             .load::<u16>(&cpu.state, rs1.wrapping_add(offset_sext))?;
         cpu.state.xs.set_reg(self.rd() as u8, data as u64);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -377,12 +386,12 @@ impl Executable for Sb {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu.state.xs.reg(self.rs2() as u8).get_bits(0..8) as u8;
 
         cpu.mmu
             .store::<u8>(&cpu.state, rs1.wrapping_add(offset_sext), data)?;
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -401,11 +410,11 @@ impl Executable for Sh {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu.state.xs.reg(self.rs2() as u8).get_bits(0..16) as u16;
         cpu.mmu
             .store::<u16>(&cpu.state, rs1.wrapping_add(offset_sext), data)?;
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -424,11 +433,11 @@ impl Executable for Sw {
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu.state.xs.reg(self.rs2() as u8).get_bits(0..32) as u32;
         cpu.mmu
             .store::<u32>(&cpu.state, rs1.wrapping_add(offset_sext), data)?;
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -448,9 +457,9 @@ impl Executable for Addi {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         cpu.state.xs.set_reg(
             self.rd() as u8,
-            rs1.wrapping_add(sext(self.imm() as RegT, self.imm_len())),
+            rs1.wrapping_add(self.imm_sext()) & cpu.xlen.mask(),
         );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -467,16 +476,12 @@ impl Executable for Slti {
     // 小于立即数则置位(Set if Less Than Immediate). I-type, RV32I and RV64I.
     // 比较 x[rs1]和有符号扩展的 immediate，如果 x[rs1]更小，向 x[rd]写入 1，否则写入 0。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let imm_sext = sext(self.imm() as RegT, self.imm_len());
+        let rs1 = signed(cpu, self.rs1());
+        let imm_sext = self.imm_sext();
 
-        let v = if (rs1 as SRegT) < (imm_sext as SRegT) {
-            1
-        } else {
-            0
-        };
+        let v = if rs1 < imm_sext as SRegT { 1 } else { 0 };
         cpu.state.xs.set_reg(self.rd() as u8, v);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -494,10 +499,14 @@ impl Executable for Sltiu {
     // 比较 x[rs1]和有符号扩展的 immediate，比较时视为无符号数。如果 x[rs1]更小，向 x[rd]写入1，否则写入 0。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let imm_sext = sext(self.imm() as RegT, self.imm_len());
-        let v = if rs1 < imm_sext { 1 } else { 0 };
+        let imm_sext = self.imm_sext();
+        let v = if rs1 < imm_sext & cpu.xlen.mask() {
+            1
+        } else {
+            0
+        };
         cpu.state.xs.set_reg(self.rd() as u8, v);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -515,11 +524,11 @@ impl Executable for Xori {
     // x[rs1]和有符号扩展的 immediate 按位异或，结果写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let imm_sext = sext(self.imm() as RegT, self.imm_len());
+        let imm_sext = self.imm_sext();
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (rs1 ^ imm_sext) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -537,11 +546,11 @@ impl Executable for Ori {
     // 把寄存器 x[rs1]和有符号扩展的立即数 immediate 按位取或，结果写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let imm_sext = sext(self.imm() as RegT, self.imm_len());
+        let imm_sext = self.imm_sext();
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (rs1 | imm_sext) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -559,11 +568,11 @@ impl Executable for Andi {
     // 把符号位扩展的立即数和寄存器 x[rs1]上的值进行位与，结果写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let imm_sext = sext(self.imm() as RegT, self.imm_len());
+        let imm_sext = self.imm_sext();
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (rs1 & imm_sext) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -586,7 +595,7 @@ impl Executable for Slli {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (rs1.wrapping_shl(shamt)) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -609,7 +618,7 @@ impl Executable for Srli {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, rs1.wrapping_shr(shamt) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -627,13 +636,13 @@ impl Executable for Srai {
     // 把寄存器 x[rs1]右移 shamt 位，空位用 x[rs1]的最高位填充，结果写入 x[rd]。
     // 对于RV32I，仅当shamt[5]=0时，指令才是有效的。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT;
+        let rs1 = signed(cpu, self.rs1());
         let shamt = self.imm() & cpu.xlen.shamt_mask();
         cpu.state.xs.set_reg(
             self.rd() as u8,
             (rs1.wrapping_shr(shamt) as RegT) & cpu.xlen.mask(),
         );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -655,7 +664,7 @@ impl Executable for Add {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, rs1.wrapping_add(rs2) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -677,7 +686,7 @@ impl Executable for Sub {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, rs1.wrapping_sub(rs2) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -700,7 +709,7 @@ impl Executable for Sll {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, rs1.wrapping_shl(rs2) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -717,11 +726,11 @@ impl Executable for Slt {
     // 小于则置位(Set if Less Than). R-type, RV32I and RV64I.
     // 比较 x[rs1]和 x[rs2]中的数，如果 x[rs1]更小，向 x[rd]写入 1，否则写入 0。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT;
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as SRegT;
+        let rs1 = signed(cpu, self.rs1());
+        let rs2 = signed(cpu, self.rs2());
         let v = if rs1 < rs2 { 1 } else { 0 };
         cpu.state.xs.set_reg(self.rd() as u8, v);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -742,7 +751,7 @@ impl Executable for Sltu {
         let rs2 = cpu.state.xs.reg(self.rs2() as u8);
         let v = if rs1 < rs2 { 1 } else { 0 };
         cpu.state.xs.set_reg(self.rd() as u8, v);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -764,7 +773,7 @@ impl Executable for Xor {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (rs1 ^ rs2) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -787,7 +796,7 @@ impl Executable for Srl {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, rs1.wrapping_shr(rs2) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -805,13 +814,13 @@ impl Executable for Sra {
     // 把寄存器 x[rs1]右移 x[rs2]位，空位用 x[rs1]的最高位填充，结果写入 x[rd]。
     // x[rs2]的低 5 位（如果是 RV64I 则是低 6 位）为移动位数，高位则被忽略。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT;
+        let rs1 = signed(cpu, self.rs1());
         let rs2 = (cpu.state.xs.reg(self.rs2() as u8) as u32) & cpu.xlen.shamt_mask();
         cpu.state.xs.set_reg(
             self.rd() as u8,
             (rs1.wrapping_shr(rs2) as RegT) & cpu.xlen.mask(),
         );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -833,7 +842,7 @@ impl Executable for Or {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (rs1 | rs2) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -855,18 +864,75 @@ impl Executable for And {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (rs1 & rs2) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
 
+/// FENCE 的 pred 与 succ 中设备输入、设备输出、内存读、内存写各自的位。
+const FENCE_I: u32 = 0b1000;
+const FENCE_O: u32 = 0b0100;
+const FENCE_R: u32 = 0b0010;
+const FENCE_W: u32 = 0b0001;
+
+/// 按汇编的写法列出屏障排序的访问，例如 `iorw`，空集写作 `0`。
+fn fence_set(bits: u32) -> String {
+    let set: String = [
+        (FENCE_I, 'i'),
+        (FENCE_O, 'o'),
+        (FENCE_R, 'r'),
+        (FENCE_W, 'w'),
+    ]
+    .iter()
+    .filter(|&&(bit, _)| bits & bit != 0)
+    .map(|&(_, c)| c)
+    .collect();
+    if set.is_empty() {
+        "0".to_string()
+    } else {
+        set
+    }
+}
+
+/// 执行排序 `pred` 与 `succ` 的屏障。hart 轮流执行，访存本就是顺序一致的；但屏障之前的写入
+/// 要排在之后的访问前面时，结束当前 hart 的时间片，让其他 hart 及时看到写入，见
+/// `Cpu::take_release`。
+fn fence(cpu: &mut Cpu, pred: u32, succ: u32) {
+    if pred & (FENCE_O | FENCE_W) != 0 && succ != 0 {
+        cpu.release();
+    }
+    cpu.state.advance_pc(4);
+}
+
 def_insn!(
     #[derive(Instruction)]
     #[format(I)]
     #[match_code(0xf)]
     #[mask(0x707f)]
+    #[operands]
     ,Fence);
 
+impl Fence {
+    /// 屏障模式，立即数的 [11:8] 位。0000 是普通的 FENCE，1000 是 FENCE.TSO，其余保留。
+    pub fn fm(&self) -> u32 {
+        self.imm() >> 8
+    }
+
+    /// 屏障之前排序的访问，立即数的 [7:4] 位。
+    pub fn pred(&self) -> u32 {
+        (self.imm() >> 4) & 0xf
+    }
+
+    /// 屏障之后排序的访问，立即数的 [3:0] 位。
+    pub fn succ(&self) -> u32 {
+        self.imm() & 0xf
+    }
+
+    fn fmt_operands(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{}", fence_set(self.pred()), fence_set(self.succ()))
+    }
+}
+
 impl Executable for Fence {
     // Fence(pred, succ)
     // 同步内存和 I/O(Fence Memory and I/O). I-type, RV32I and RV64I.
@@ -874,8 +940,54 @@ impl Executable for Fence {
     // 及 I/O 访问对外部可见。比特中的第 3,2,1 和 0 位分别对应于设备输入，设备输出，内存读
     // 写。例如 fence r,rw，将前面读取与后面的读取和写入排序，使用 pred = 0010 和 succ = 0011
     // 进行编码。如果省略了参数，则表示 fence iorw, iorw，即对所有访存请求进行排序。
+    // 保留的 fm 以及 pred 或 succ 为空的编码按规范作为普通的 FENCE 执行，而不是非法指令。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        if self.fm() != 0 {
+            trace!(
+                "fence with reserved fm {:#06b} at {:#x}",
+                self.fm(),
+                cpu.state.pc
+            );
+        }
+        fence(cpu, self.pred(), self.succ());
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x8330000f)]
+    #[mask(0xfff0707f)]
+    #[mnemonic("fence.tso")]
+    ,FenceTso);
+
+impl Executable for FenceTso {
+    // Fence(RW, RW)
+    // 全存储定序屏障(Fence Total Store Order). I-type, RV32I and RV64I.
+    // fm = 1000，pred = succ = rw 的 FENCE：之前的读取排在之后的读写前面，之前的写入排在之后
+    // 的写入前面，但不必排在之后的读取前面。不认识它的实现作为 fence rw,rw 执行，同样正确。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        fence(cpu, FENCE_R | FENCE_W, FENCE_R | FENCE_W);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x0100000f)]
+    #[mask(0xffffffff)]
+    ,Pause);
+
+impl Executable for Pause {
+    // Fence(Store, 0)
+    // 暂停提示(Pause Hint). I-type, Zihintpause.
+    // FENCE w,0 的编码，提示处理器当前在自旋等待，可以降低指令的执行速率。作为 FENCE 执行，
+    // 开启自旋检测时让宿主线程空闲一步，见 `Cpu::set_spin_detection`。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.spin();
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -892,7 +1004,9 @@ impl Executable for FenceI {
     // 同步指令流(Fence Instruction Stream). I-type, RV32I and RV64I.
     // 使对内存指令区域的读写，对后续取指令可见。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.flush_insn_cache();
+        cpu.flush_decode_cache();
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -907,8 +1021,12 @@ def_insn!(
 impl Executable for Ecall {
     // RaiseException(EnvironmentCall)
     // 环境调用 (Environment Call). I-type, RV32I and RV64I.
-    // 通过引发环境调用异常来请求执行环境。
+    // 通过引发环境调用异常来请求执行环境。设置了 ECALL 处理器时 (如用户态模拟的 Linux 系统调用)，
+    // 改由处理器直接提供服务。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        if let Some(result) = cpu.handle_ecall() {
+            return result;
+        }
         match cpu.state.privilege {
             crate::PrivilegeMode::User => Err(Exception::UserEnvCall),
             crate::PrivilegeMode::Supervisor => Err(Exception::SupervisorEnvCall),
@@ -927,12 +1045,108 @@ def_insn!(
 impl Executable for Ebreak {
     // RaiseException(Breakpoint)
     // 环境断点 (Environment Breakpoint). I-type, RV32I and RV64I.
-    // 通过抛出断点异常的方式请求调试器。
-    fn exec(&self, _: &mut Cpu) -> Result<(), Exception> {
+    // 通过抛出断点异常的方式请求调试器。开启半主机 (semihosting) 时，前后分别是
+    // slli x0, x0, 0x1f 和 srai x0, x0, 7 的 EBREAK 改为请求宿主机执行 a0 指定的调用。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        if cpu.semihosting && semihosting::is_call(cpu) {
+            if let Some(code) = semihosting::call(cpu)? {
+                cpu.exit(code);
+            }
+            return Ok(());
+        }
         Err(Exception::Breakpoint)
     }
 }
 
+/// 检查当前特权级能否访问 CSR。csr[9:8] 是访问所需的最低特权级，csr[11:10] 为 0b11 的 CSR 只读，
+/// 写只读 CSR 或特权级不足都会触发非法指令异常。
+/// 浮点 CSR 和浮点指令一样在 mstatus.FS 为 Off 时不可访问。
+fn check_csr(cpu: &Cpu, csr_num: u16, write: bool) -> Result<(), Exception> {
+    // 严格模式下，访问未实现的 CSR 和在硬件上一样是非法指令。
+    if cpu.csr_policy == CsrPolicy::Strict && !Csrs::is_implemented(csr_num) {
+        return Err(Exception::IllegalInstruction(0));
+    }
+    let privilege = match cpu.state.privilege {
+        PrivilegeMode::User => 0,
+        PrivilegeMode::Supervisor => 1,
+        PrivilegeMode::Machine => 3,
+    };
+    if privilege < csr_num.get_bits(8..10) || (write && csr_num.get_bits(10..12) == 0b11) {
+        return Err(Exception::IllegalInstruction(0));
+    }
+    // mstatus.TVM 置位时 S 模式不能访问 satp。和其他非法指令异常一样，按 medeleg 委托。
+    if csr_num == 0x180
+        && cpu.state.privilege == PrivilegeMode::Supervisor
+        && cpu.state.csrs.mstatus().tvm()
+    {
+        return Err(Exception::IllegalInstruction(0));
+    }
+    if (0x001..=0x003).contains(&csr_num) {
+        check_enabled(cpu)?;
+    }
+    if (is_counter_high(csr_num) || matches!(csr_num, 0x15d | 0x31a)) && cpu.xlen == XLen::X64 {
+        return Err(Exception::IllegalInstruction(0));
+    }
+    // Sstc：M 模式以下只有 menvcfg.STCE 和 mcounteren.TM 都置位时才能访问 stimecmp。
+    if matches!(csr_num, 0x14d | 0x15d)
+        && cpu.state.privilege != PrivilegeMode::Machine
+        && (!cpu.state.csrs.menvcfg().stce() || cpu.state.csrs.mcounteren() & 1 << 1 == 0)
+    {
+        return Err(Exception::IllegalInstruction(0));
+    }
+    // cycle、time、instret 和 hpmcounter3–31 在 M 模式以下能否访问由 mcounteren 中对应的位决定，
+    // U 模式还需要 scounteren 中的位。
+    if (0xc00..=0xc1f).contains(&csr_num) || (0xc80..=0xc9f).contains(&csr_num) {
+        let bit = 1 << (csr_num & 0x1f);
+        let enabled = match cpu.state.privilege {
+            PrivilegeMode::Machine => bit,
+            PrivilegeMode::Supervisor => cpu.state.csrs.mcounteren() & bit,
+            PrivilegeMode::User => cpu.state.csrs.mcounteren() & cpu.state.csrs.scounteren() & bit,
+        };
+        if enabled == 0 {
+            return Err(Exception::IllegalInstruction(0));
+        }
+    }
+    Ok(())
+}
+
+/// 计数器高 32 位的 CSR，cycleh–hpmcounter31h 和 mcycleh–mhpmcounter31h，只在 RV32 上存在。
+fn is_counter_high(csr_num: u16) -> bool {
+    (0xc80..=0xc9f).contains(&csr_num) || (0xb80..=0xb9f).contains(&csr_num)
+}
+
+/// 读 CSR。RV32 上只能读到低 32 位，64 位计数器的高 32 位通过对应的 *h CSR 读出。
+fn read_csr(cpu: &Cpu, csr_num: u16) -> RegT {
+    match cpu.xlen {
+        XLen::X32 if is_counter_high(csr_num) => cpu.state.csrs.csr(csr_num - 0x80) >> 32,
+        _ => cpu.state.csrs.csr(csr_num) & cpu.xlen.mask(),
+    }
+}
+
+/// 写 CSR，并在 trace 日志中按名字记录写入的值，例如 "csrw satp <- 0x8000000000081234"。
+/// 注册了 event hook 时还报告写入前后的值。
+fn write_csr(cpu: &mut Cpu, csr_num: u16, value: RegT) {
+    trace!(
+        target: "riscv_emulator::cpu",
+        "csrw {} <- {:#x}",
+        Csrs::name(csr_num).map_or_else(|| format!("{:#x}", csr_num), str::to_string),
+        value
+    );
+    if !cpu.events_enabled() {
+        cpu.state.csrs.set_csr(csr_num, value);
+        return;
+    }
+    let old = cpu.state.csrs.csr(csr_num);
+    cpu.state.csrs.set_csr(csr_num, value);
+    let new = cpu.state.csrs.csr(csr_num);
+    cpu.emit(CpuEvent::CsrWrite {
+        pc: cpu.state.pc,
+        csr: csr_num,
+        old,
+        new,
+    });
+}
+
 def_insn!(
     #[derive(Instruction)]
     #[format(I)]
@@ -945,13 +1159,16 @@ impl Executable for Csrrw {
     // 读后写控制状态寄存器 (Control and Status Register Read and Write). I-type, RV32I and RV64I.
     // 记控制状态寄存器 csr 中的值为 t。把寄存器 x[rs1]的值写入 csr，再把 t 写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let scr_num = self.imm() as u16;
-        let t = cpu.state.csrs.csr(scr_num);
+        let csr_num = self.imm() as u16;
+        check_csr(cpu, csr_num, true)?;
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        cpu.state.csrs.set_csr(scr_num, rs1 & cpu.xlen.mask());
-
-        cpu.state.xs.set_reg(self.rd() as u8, t & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        // rd 为 x0 时不读 CSR。
+        if self.rd() != 0 {
+            let t = read_csr(cpu, csr_num);
+            cpu.state.xs.set_reg(self.rd() as u8, t);
+        }
+        write_csr(cpu, csr_num, rs1 & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -968,12 +1185,17 @@ impl Executable for Csrrs {
     // 读后置位控制状态寄存器 (Control and Status Register Read and Set). I-type, RV32I and RV64I.
     // 记控制状态寄存器 csr 中的值为 t。把 t 和寄存器 x[rs1]按位或的结果写入 csr，再把 t 写入x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let scr_num = self.imm() as u16;
-        let t = cpu.state.csrs.csr(scr_num);
+        let csr_num = self.imm() as u16;
+        // rs1 为 x0 时只读，不写 CSR。
+        let write = self.rs1() != 0;
+        check_csr(cpu, csr_num, write)?;
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        cpu.state.csrs.set_csr(scr_num, (t | rs1) & cpu.xlen.mask());
-        cpu.state.xs.set_reg(self.rd() as u8, t & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        let t = read_csr(cpu, csr_num);
+        if write {
+            write_csr(cpu, csr_num, (t | rs1) & cpu.xlen.mask());
+        }
+        cpu.state.xs.set_reg(self.rd() as u8, t);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -990,14 +1212,16 @@ impl Executable for Csrrc {
     // 读后清除控制状态寄存器 (Control and Status Register Read and Clear). I-type, RV32I and RV64I.
     // 记控制状态寄存器 csr 中的值为 t。把 t 和寄存器 x[rs1]按位与的结果写入 csr，再把 t 写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let scr_num = self.imm() as u16;
-        let t = cpu.state.csrs.csr(scr_num);
+        let csr_num = self.imm() as u16;
+        let write = self.rs1() != 0;
+        check_csr(cpu, csr_num, write)?;
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        cpu.state
-            .csrs
-            .set_csr(scr_num, (t & !rs1) & cpu.xlen.mask());
-        cpu.state.xs.set_reg(self.rd() as u8, t & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        let t = read_csr(cpu, csr_num);
+        if write {
+            write_csr(cpu, csr_num, (t & !rs1) & cpu.xlen.mask());
+        }
+        cpu.state.xs.set_reg(self.rd() as u8, t);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1014,12 +1238,15 @@ impl Executable for Csrrwi {
     // 立即数读后写控制状态寄存器 (Control and Status Register Read and Write Immediate). I-type, RV32I and RV64I.
     // 把控制状态寄存器 csr 中的值拷贝到 x[rd]中，再把五位的零扩展的立即数 zimm 的值写入csr。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let scr_num = self.imm() as u16;
+        let csr_num = self.imm() as u16;
         let zimm = self.rs1() as RegT;
-        let t = cpu.state.csrs.csr(scr_num);
-        cpu.state.xs.set_reg(self.rd() as u8, t & cpu.xlen.mask());
-        cpu.state.csrs.set_csr(scr_num, zimm);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        check_csr(cpu, csr_num, true)?;
+        if self.rd() != 0 {
+            let t = read_csr(cpu, csr_num);
+            cpu.state.xs.set_reg(self.rd() as u8, t);
+        }
+        write_csr(cpu, csr_num, zimm);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1033,15 +1260,18 @@ def_insn!(
 
 impl Executable for Csrrsi {
     // t = CSRs[csr]; CSRs[csr] = t | zimm; x[rd] = t
+    // 立即数读后置位控制状态寄存器 (Control and Status Register Read and Set Immediate). I-type, RV32I and RV64I.
+    // 记控制状态寄存器 csr 中的值为 t。把 t 和五位的零扩展的立即数 zimm 按位或的结果写入 csr，再把 t 写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let scr_num = self.imm() as u16;
+        let csr_num = self.imm() as u16;
         let zimm = self.rs1() as RegT;
-        let t = cpu.state.csrs.csr(scr_num);
-        cpu.state
-            .csrs
-            .set_csr(scr_num, (t | zimm) & cpu.xlen.mask());
-        cpu.state.xs.set_reg(self.rd() as u8, t & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        check_csr(cpu, csr_num, zimm != 0)?;
+        let t = read_csr(cpu, csr_num);
+        if zimm != 0 {
+            write_csr(cpu, csr_num, (t | zimm) & cpu.xlen.mask());
+        }
+        cpu.state.xs.set_reg(self.rd() as u8, t);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1058,14 +1288,15 @@ impl Executable for Csrrci {
     // 立即数读后清除控制状态寄存器 (Control and Status Register Read and Clear Immediate). Itype, RV32I and RV64I.
     // 记控制状态寄存器 csr 中的值为 t。把 t 和五位的零扩展的立即数 zimm 按位与的结果写入csr，再把 t 写入 x[rd]（csr 寄存器的第 5 位及更高位不变）。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let scr_num = self.imm() as u16;
+        let csr_num = self.imm() as u16;
         let zimm = self.rs1() as RegT;
-        let t = cpu.state.csrs.csr(scr_num);
-        cpu.state
-            .csrs
-            .set_csr(scr_num, (t & !zimm) & cpu.xlen.mask());
-        cpu.state.xs.set_reg(self.rd() as u8, t & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        check_csr(cpu, csr_num, zimm != 0)?;
+        let t = read_csr(cpu, csr_num);
+        if zimm != 0 {
+            write_csr(cpu, csr_num, (t & !zimm) & cpu.xlen.mask());
+        }
+        cpu.state.xs.set_reg(self.rd() as u8, t);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1075,6 +1306,7 @@ def_insn!(
     #[format(I)]
     #[match_code(0x6003)]
     #[mask(0x707f)]
+    #[xlen(64)]
     ,Lwu);
 
 impl Executable for Lwu {
@@ -1082,17 +1314,14 @@ impl Executable for Lwu {
     // 无符号字加载 (Load Word, Unsigned). I-type, RV64I.
     // 从地址 x[rs1] + sign-extend(offset)读取四个字节，零扩展后写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
 
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu
             .mmu
             .load::<u32>(&cpu.state, rs1.wrapping_add(offset_sext))?;
         cpu.state.xs.set_reg(self.rd() as u8, data as RegT);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1102,6 +1331,7 @@ def_insn!(
     #[format(I)]
     #[match_code(0x3003)]
     #[mask(0x707f)]
+    #[xlen(64)]
     ,Ld);
 
 impl Executable for Ld {
@@ -1109,16 +1339,13 @@ impl Executable for Ld {
     // 双字加载 (Load Doubleword). I-type, RV32I and RV64I.
     // 从地址 x[rs1] + sign-extend(offset)读取八个字节，写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu
             .mmu
             .load::<u64>(&cpu.state, rs1.wrapping_add(offset_sext))?;
         cpu.state.xs.set_reg(self.rd() as u8, data as RegT);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1128,6 +1355,7 @@ def_insn!(
     #[format(S)]
     #[match_code(0x3023)]
     #[mask(0x707f)]
+    #[xlen(64)]
     ,Sd);
 
 impl Executable for Sd {
@@ -1135,15 +1363,12 @@ impl Executable for Sd {
     // 存双字(Store Doubleword). S-type, RV64I only.
     // 将 x[rs2]中的 8 字节存入内存地址 x[rs1]+sign-extend(offset)。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let offset_sext = sext(self.imm() as RegT, self.imm_len());
+        let offset_sext = self.imm_sext();
         let data = cpu.state.xs.reg(self.rs2() as u8);
         cpu.mmu
             .store::<u64>(&cpu.state, rs1.wrapping_add(offset_sext), data)?;
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1153,6 +1378,7 @@ def_insn!(
     #[format(I)]
     #[match_code(0x1b)]
     #[mask(0x707f)]
+    #[xlen(64)]
     ,Addiw);
 
 impl Executable for Addiw {
@@ -1160,17 +1386,14 @@ impl Executable for Addiw {
     // 加立即数字(Add Word Immediate). I-type, RV64I.
     // 把符号位扩展的立即数加到 x[rs1]，将结果截断为 32 位，把符号位扩展的结果写入 x[rd]。忽略算术溢出。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let imm_sext = sext(self.imm() as RegT, self.imm_len());
+        let imm_sext = self.imm_sext();
 
         cpu.state.xs.set_reg(
             self.rd() as u8,
             sext(rs1.wrapping_add(imm_sext), 32) & cpu.xlen.mask(),
         );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1180,6 +1403,7 @@ def_insn!(
     #[format(I)]
     #[match_code(0x101b)]
     #[mask(0xfe00707f)]
+    #[xlen(64)]
     ,Slliw);
 
 impl Executable for Slliw {
@@ -1188,15 +1412,12 @@ impl Executable for Slliw {
     // 把寄存器 x[rs1]左移 shamt 位，空出的位置填入 0，结果截为 32 位，进行有符号扩展后写入x[rd]。
     // 仅当 shamt[5]=0 时，指令才是有效的。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         let shamt = self.imm() & cpu.xlen.shamt_mask();
 
-        let value = sext(rs1.wrapping_shl(shamt as u32), 32);
+        let value = sext(rs1.wrapping_shl(shamt), 32);
         cpu.state.xs.set_reg(self.rd() as u8, value);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1206,6 +1427,7 @@ def_insn!(
     #[format(I)]
     #[match_code(0x4000501b)]
     #[mask(0xfe00707f)]
+    #[xlen(64)]
     ,Sraiw);
 
 impl Executable for Sraiw {
@@ -1214,14 +1436,12 @@ impl Executable for Sraiw {
     // 把寄存器 x[rs1]的低 32 位右移 shamt 位，空位用 x[rs1][31]填充，结果进行有符号扩展后写入 x[rd]。
     // 仅当 shamt[5]=0 时指令有效。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as u32 as RegT;
-        let shamt = self.imm() & cpu.xlen.shamt_mask();
-        let value = sext(rs1.wrapping_shr(shamt as u32), 32 - shamt as usize);
+        // 低 32 位按 i32 算术右移，再有符号扩展到 XLEN。
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as i32;
+        let shamt = self.imm() & 0x1f;
+        let value = (rs1 >> shamt) as SRegT as RegT;
         cpu.state.xs.set_reg(self.rd() as u8, value);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1231,6 +1451,7 @@ def_insn!(
     #[format(I)]
     #[match_code(0x501b)]
     #[mask(0xfe00707f)]
+    #[xlen(64)]
     ,Srliw);
 
 impl Executable for Srliw {
@@ -1239,14 +1460,11 @@ impl Executable for Srliw {
     // 把寄存器 x[rs1]右移 shamt 位，空出的位置填入 0，结果截为 32 位，进行有符号扩展后写入
     // x[rd]。仅当 shamt[5]=0 时，指令才是有效的。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8) as u32 as RegT;
         let shamt = self.imm() & cpu.xlen.shamt_mask();
-        let value = sext(rs1.wrapping_shr(shamt as u32), 32);
+        let value = sext(rs1.wrapping_shr(shamt), 32);
         cpu.state.xs.set_reg(self.rd() as u8, value);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1256,6 +1474,7 @@ def_insn!(
   #[format(R)]
   #[match_code(0x3b)]
   #[mask(0xfe00707f)]
+  #[xlen(64)]
   ,Addw);
 
 impl Executable for Addw {
@@ -1263,16 +1482,13 @@ impl Executable for Addw {
     // 加字(Add Word). R-type, RV64I.
     // 把寄存器 x[rs2]加到寄存器 x[rs1]上，将结果截断为 32 位，把符号位扩展的结果写入 x[rd]。忽略算术溢出。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         let rs2 = cpu.state.xs.reg(self.rs2() as u8);
         cpu.state.xs.set_reg(
             self.rd() as u8,
             sext(rs1.wrapping_add(rs2), 32) & cpu.xlen.mask(),
         );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1282,6 +1498,7 @@ def_insn!(
     #[format(R)]
     #[match_code(0x4000003b)]
     #[mask(0xfe00707f)]
+    #[xlen(64)]
     ,Subw);
 
 impl Executable for Subw {
@@ -1289,16 +1506,13 @@ impl Executable for Subw {
     // 减去字(Substract Word). R-type, RV64I only.
     // x[rs1]减去 x[rs2]，结果截为 32 位，有符号扩展后写入 x[rd]。忽略算术溢出。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         let rs2 = cpu.state.xs.reg(self.rs2() as u8);
         cpu.state.xs.set_reg(
             self.rd() as u8,
             sext(rs1.wrapping_sub(rs2), 32) & cpu.xlen.mask(),
         );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1308,6 +1522,7 @@ def_insn!(
     #[format(R)]
     #[match_code(0x103b)]
     #[mask(0xfe00707f)]
+    #[xlen(64)]
     ,Sllw);
 
 impl Executable for Sllw {
@@ -1316,9 +1531,6 @@ impl Executable for Sllw {
     // 把寄存器 x[rs1]的低 32 位左移 x[rs2]位，空出的位置填入 0，结果进行有符号扩展后写入
     // x[rd]。x[rs2]的低 5 位代表移动位数，其高位则被忽略。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
         let rs1 = cpu.state.xs.reg(self.rs1() as u8);
         let rs2 = cpu.state.xs.reg(self.rs2() as u8);
 
@@ -1326,7 +1538,7 @@ impl Executable for Sllw {
             self.rd() as u8,
             sext(rs1.wrapping_shl((rs2 & 0x1f) as u32), 32),
         );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1336,6 +1548,7 @@ def_insn!(
     #[format(R)]
     #[match_code(0x4000503b)]
     #[mask(0xfe00707f)]
+    #[xlen(64)]
     ,Sraw);
 
 impl Executable for Sraw {
@@ -1344,17 +1557,14 @@ impl Executable for Sraw {
     // 把寄存器 x[rs1]的低 32 位右移 x[rs2]位，空位用 x[rs1][31]填充，结果进行有符号扩展后写入 x[rd]。
     // x[rs2]的低 5 位为移动位数，高位则被忽略。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        if let XLen::X32 = cpu.xlen {
-            return Err(Exception::InstructionFault);
-        }
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as u32 as RegT;
+        // 低 32 位按 i32 算术右移，再有符号扩展到 XLEN。
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as i32;
         let rs2 = (cpu.state.xs.reg(self.rs2() as u8) & 0x1f) as u32;
 
-        cpu.state.xs.set_reg(
-            self.rd() as u8,
-            sext(rs1.wrapping_shr(rs2), 32 - rs2 as usize),
-        );
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, (rs1 >> rs2) as SRegT as RegT);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -1370,10 +1580,19 @@ impl Executable for Sret {
     // ExceptionReturn(Supervisor)
     // 管理员模式例外返回(Supervisor-mode Exception Return). R-type, RV32I and RV64I 特权指令。
     // 从管理员模式的例外处理程序中返回，设置 pc 为 CSRs[spec]，权限模式为 CSRs[sstatus].SPP，
-    // CSRs[sstatus].SIE 为 CSRs[sstatus].SPIE，CSRs[sstatus].SPIE 为 1，CSRs[sstatus].spp 为 0
+    // CSRs[sstatus].SIE 为 CSRs[sstatus].SPIE，CSRs[sstatus].SPIE 为 1，CSRs[sstatus].spp 为 0。
+    // SRET 总是返回到比机器模式低的特权级，所以同时清除 CSRs[mstatus].MPRV。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        // println!("这里是 sret sepc: {:x} pc: {:X}", cpu.state.csrs.sepc(), cpu.state.pc);
+        match cpu.state.privilege {
+            PrivilegeMode::User => return Err(Exception::IllegalInstruction(0)),
+            PrivilegeMode::Supervisor if cpu.state.csrs.mstatus().tsr() => {
+                return Err(Exception::IllegalInstruction(0))
+            }
+            _ => {}
+        }
 
+        let (pc, privilege) = (cpu.state.pc, cpu.state.privilege);
+        let before = cpu.events_enabled().then(|| cpu.state.csrs.csr(0x100));
         cpu.state.update_pc(cpu.state.csrs.sepc());
         let mut sstatus = cpu.state.csrs.sstatus();
         cpu.state.privilege = sstatus.spp();
@@ -1381,6 +1600,14 @@ impl Executable for Sret {
         sstatus.set_spie(true);
         sstatus.set_spp(PrivilegeMode::User);
         cpu.state.csrs.set_sstatus(sstatus.bits());
+        let mut mstatus = cpu.state.csrs.mstatus();
+        mstatus.set_mprv(false);
+        cpu.state.csrs.set_mstatus(mstatus.bits());
+        trace!(target: "riscv_emulator::cpu", "sret to {:#x} in {:?}", cpu.state.pc, cpu.state.privilege);
+        cpu.trap_returned();
+        if let Some(before) = before {
+            cpu.emit_transition(pc, privilege, Transition::Sret, &[0x100], &[before]);
+        }
         Ok(())
     }
 }
@@ -1398,14 +1625,29 @@ impl Executable for Mret {
     // 从机器模式异常处理程序返回。将 pc 设置为 CSRs[mepc], 将特权级设置成
     // CSRs[mstatus].MPP, CSRs[mstatus].MIE 置成 CSRs[mstatus].MPIE, 并且将
     // CSRs[mstatus].MPIE 为 1;并且，如果支持用户模式，则将 CSR [mstatus].MPP 设置为 0。
+    // 返回到比机器模式低的特权级时清除 CSRs[mstatus].MPRV。只能在机器模式下执行。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        if cpu.state.privilege != PrivilegeMode::Machine {
+            return Err(Exception::IllegalInstruction(0));
+        }
+        let pc = cpu.state.pc;
+        let before = cpu.events_enabled().then(|| cpu.state.csrs.csr(0x300));
         cpu.state.update_pc(cpu.state.csrs.mepc());
         let mut mstatus = cpu.state.csrs.mstatus();
         cpu.state.privilege = mstatus.mpp();
+        if cpu.state.privilege != PrivilegeMode::Machine {
+            mstatus.set_mprv(false);
+        }
         mstatus.set_mie(mstatus.mpie());
         mstatus.set_mpie(true);
         mstatus.set_mpp(PrivilegeMode::User);
         cpu.state.csrs.set_mstatus(mstatus.bits());
+        trace!(target: "riscv_emulator::cpu", "mret to {:#x} in {:?}", cpu.state.pc, cpu.state.privilege);
+        cpu.trap_returned();
+        if let Some(before) = before {
+            let from = PrivilegeMode::Machine;
+            cpu.emit_transition(pc, from, Transition::Mret, &[0x300], &[before]);
+        }
         Ok(())
     }
 }
@@ -1422,8 +1664,15 @@ impl Executable for Wfi {
     // 等待中断(Wait for Interrupt). R-type, RV32I and RV64I 特权指令。
     // 如果没有待处理的中断，则使处理器处于空闲状态。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        cpu.state.update_pc(cpu.state.pc + 4);
-        todo!();
+        // mstatus.TW: WFI executed in a less privileged mode raises an illegal instruction.
+        if cpu.state.privilege != PrivilegeMode::Machine && cpu.state.csrs.mstatus().tw() {
+            return Err(Exception::IllegalInstruction(0));
+        }
+        cpu.state.advance_pc(4);
+        if !cpu.interrupt_pending() {
+            cpu.waiting_for_interrupt = true;
+        }
+        Ok(())
     }
 }
 
@@ -1441,7 +1690,479 @@ impl Executable for SfenceVma {
     // 会受到影响；否则，仅对 x[rs2]标识的地址空间的翻译进行排序。当 rs1=0 时，对所选地址
     // 空间中的所有虚拟地址的翻译进行排序；否则，仅对其中包含虚拟地址 x[rs1]的页面地址翻译进行排序。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        cpu.state.update_pc(cpu.state.pc + 4);
+        if cpu.state.privilege == PrivilegeMode::Supervisor && cpu.state.csrs.mstatus().tvm() {
+            return Err(Exception::IllegalInstruction(0));
+        }
+        cpu.flush_insn_cache();
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Fence;
+    use crate::{
+        cpu::{Cpu, CsrPolicy},
+        testing::*,
+        InsnDecoder, PrivilegeMode, RegT, XLen,
+    };
+
+    const fn jalr(rd: u32, rs1: u32, offset: i32) -> u32 {
+        i_type(offset, rs1, 0, rd, 0x67)
+    }
+
+    const fn sraiw(rd: u32, rs1: u32, shamt: i32) -> u32 {
+        i_type(0x400 | shamt, rs1, 5, rd, 0x1b)
+    }
+
+    const fn sraw(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        r_type(0x20, rs2, rs1, 5, rd, 0x3b)
+    }
+
+    // (rs1, shamt, 结果)：只移低 32 位，结果从第 31 位有符号扩展。
+    const SRAW_CASES: [(RegT, u32, RegT); 7] = [
+        (0xffff_ffff_8000_0000, 0, 0xffff_ffff_8000_0000),
+        (0xffff_ffff_8000_0000, 1, 0xffff_ffff_c000_0000),
+        (0xffff_ffff_8000_0000, 31, 0xffff_ffff_ffff_ffff),
+        (0x0000_0000_8000_0000, 4, 0xffff_ffff_f800_0000),
+        (0x1234_5678_7fff_ffff, 0, 0x0000_0000_7fff_ffff),
+        (0x1234_5678_7fff_ffff, 4, 0x0000_0000_07ff_ffff),
+        (0xffff_ffff_7fff_ffff, 31, 0),
+    ];
+
+    #[test]
+    fn taken_jumps_to_misaligned_targets_trap() {
+        // t0 = DRAM_BASE，目标都是 DRAM_BASE + 6 或 + 2。
+        let cases = [
+            (jal(1, 6), DRAM_BASE + 6),
+            (b_type(6, 0, 0, 0), DRAM_BASE + 6),
+            (jalr(1, 5, 2), DRAM_BASE + 2),
+        ];
+        for &(insn, target) in cases.iter() {
+            let mut cpu = Cpu::with_program(XLen::X64, &[insn, EBREAK]);
+            cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+            cpu.state.xs.set_reg(5, DRAM_BASE);
+            cpu.one_step();
+            assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+            assert_eq!(cpu.state.csrs.mcause(), 0);
+            assert_eq!(cpu.state.csrs.mtval(), target);
+            assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE);
+            // 不写链接寄存器。
+            assert_eq!(cpu.state.xs.reg(1), 0, "{:#010x}", insn);
+        }
+    }
+
+    #[test]
+    fn untaken_branch_and_jalr_bit_0_dont_trap() {
+        // bne zero, zero, 6
+        let mut cpu = Cpu::with_program(XLen::X64, &[b_type(6, 0, 0, 1), EBREAK]);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+
+        // JALR 清掉目标地址的第 0 位。
+        let mut cpu = Cpu::with_program(XLen::X64, &[jalr(1, 5, 9), NOP, EBREAK]);
+        cpu.state.xs.set_reg(5, DRAM_BASE);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 8);
+        assert_regs!(cpu.state.xs, ra = DRAM_BASE + 4);
+    }
+
+    #[test]
+    fn immediates_are_sign_extended_in_every_format() {
+        for &imm in [-2048, -1, 0, 1, 2047].iter() {
+            let expected = 100i64.wrapping_add(imm as i64) as RegT;
+            assert_eq!(exec_op(XLen::X64, addi(10, 11, imm), 100, 0), expected);
+        }
+        assert_eq!(
+            exec_op(XLen::X64, lui(10, 0xfffff), 0, 0),
+            0xffff_ffff_ffff_f000
+        );
+        assert_eq!(exec_op(XLen::X64, lui(10, 0x7ffff), 0, 0), 0x7fff_f000);
+
+        // S 和 I 的负偏移。
+        let program = [sd(12, 11, -2048), ld(10, 11, -2048), EBREAK];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.xs.set_reg(11, DRAM_BASE + 0x1000);
+        cpu.state.xs.set_reg(12, 0x1234);
+        cpu.run_until_ebreak(10);
+        assert_regs!(cpu.state.xs, a0 = 0x1234);
+        assert_eq!(
+            cpu.mmu.bus.borrow().read::<u64>(DRAM_BASE + 0x800),
+            Ok(0x1234)
+        );
+
+        // J 向前，B 向前，J 向后，B 向后。
+        let mut program = vec![NOP; 0x200];
+        program[0] = jal(0, 0x7f0);
+        program[0x7f0 / 4] = b_type(8, 0, 0, 0);
+        program[0x7f8 / 4] = jal(0, -0x7f0);
+        program[0x8 / 4] = b_type(-8, 0, 0, 0);
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        for &target in [0x7f0, 0x7f8, 0x8, 0].iter() {
+            cpu.one_step();
+            assert_eq!(cpu.state.pc, DRAM_BASE + target);
+        }
+    }
+
+    #[test]
+    fn unimplemented_csrs_trap_only_under_the_strict_policy() {
+        let program = [csrrw(10, 0x123, 11), csrrs(12, 0x123, 0), EBREAK];
+        // 宽松模式下 0x123 像普通寄存器一样保存写入的值。
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.xs.set_reg(11, 0x55);
+        cpu.run_until_ebreak(10);
+        assert_regs!(cpu.state.xs, a0 = 0, a2 = 0x55);
+
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.csr_policy = CsrPolicy::Strict;
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 8);
+        cpu.state.xs.set_reg(11, 0x55);
+        cpu.one_step();
+        assert_eq!(cpu.state.pc, DRAM_BASE + 8);
+        assert_eq!(cpu.state.csrs.mcause(), 2);
+        assert_eq!(cpu.state.csrs.mtval(), program[0] as RegT);
+        assert_eq!(cpu.state.csrs.csr(0x123), 0);
+        // 已实现的 CSR 照常访问。
+        let mut cpu = Cpu::with_program(XLen::X64, &[csrrs(10, 0x301, 0), EBREAK]);
+        cpu.csr_policy = CsrPolicy::Strict;
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.xs.reg(10), cpu.state.csrs.csr(0x301));
+    }
+
+    #[test]
+    fn fence_fields_come_from_the_immediate() {
+        // 依次是 fence iorw,iorw、fence r,rw 与 fence.tso 的 fm、pred 与 succ。
+        let cases = [
+            (0x0ff0_000f, 0b0000, 0b1111, 0b1111, "fence iorw,iorw"),
+            (0x0230_000f, 0b0000, 0b0010, 0b0011, "fence r,rw"),
+            (0x8330_000f, 0b1000, 0b0011, 0b0011, "fence.tso"),
+        ];
+        let decoder = InsnDecoder::new(XLen::X64);
+        for &(code, fm, pred, succ, text) in cases.iter() {
+            let fence = Fence::new(code);
+            assert_eq!((fence.fm(), fence.pred(), fence.succ()), (fm, pred, succ));
+            assert_eq!(decoder.decode(code).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn pause_retires_like_a_nop() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[PAUSE, addi(10, 0, 1), EBREAK]);
+        cpu.set_tick_batch(1);
+        cpu.one_step();
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        // minstret 计入了 PAUSE。
+        assert_eq!(cpu.state.csrs.csr(0xb02), 1);
+        cpu.run_until_ebreak(10);
+        assert_regs!(cpu.state.xs, a0 = 1);
+    }
+
+    #[test]
+    fn sraiw_shifts_the_low_word_and_sign_extends() {
+        for &(rs1, shamt, expected) in SRAW_CASES.iter() {
+            let result = exec_op(XLen::X64, sraiw(10, 11, shamt as i32), rs1, 0);
+            assert_eq!(result, expected, "sraiw {:#x}, {}", rs1, shamt);
+        }
+    }
+
+    #[test]
+    fn sraw_shifts_the_low_word_and_sign_extends() {
+        for &(rs1, shamt, expected) in SRAW_CASES.iter() {
+            // 只用 rs2 的低 5 位。
+            for &rs2 in [shamt as RegT, !0x1f | shamt as RegT].iter() {
+                let result = exec_op(XLen::X64, sraw(10, 11, 12), rs1, rs2);
+                assert_eq!(result, expected, "sraw {:#x}, {:#x}", rs1, rs2);
+            }
+        }
+    }
+
+    /// CSRRSI 和 CSRRCI，`zimm` 为 5 位无符号立即数。
+    const fn csrrsi(rd: u32, csr: u32, zimm: u32) -> u32 {
+        csr << 20 | zimm << 15 | 6 << 12 | rd << 7 | 0x73
+    }
+
+    const fn csrrci(rd: u32, csr: u32, zimm: u32) -> u32 {
+        csr << 20 | zimm << 15 | 7 << 12 | rd << 7 | 0x73
+    }
+
+    const FFLAGS: u32 = 0x001;
+    const SSCRATCH: u32 = 0x140;
+    const MSCRATCH: u32 = 0x340;
+    const MHARTID: u32 = 0xf14;
+
+    /// 在 `privilege` 下执行 `insn`。
+    fn run_csr_insn(privilege: PrivilegeMode, insn: u32) -> Cpu {
+        let mut cpu = Cpu::with_program(XLen::X64, &[insn, EBREAK]);
+        cpu.state.csrs.set_csr(MSCRATCH as u16, 0x5a);
+        cpu.state.privilege = privilege;
+        cpu.run_until_ebreak(10);
+        cpu
+    }
+
+    #[test]
+    fn csr_access_within_the_privilege_is_allowed() {
+        let cases = [
+            (PrivilegeMode::Supervisor, csrrs(10, SSCRATCH, 0)),
+            (PrivilegeMode::Machine, csrrs(10, MHARTID, 0)),
+            (PrivilegeMode::Machine, csrrsi(10, MHARTID, 0)),
+            (PrivilegeMode::Machine, csrrs(10, MSCRATCH, 0)),
+        ];
+        for &(privilege, insn) in cases.iter() {
+            let cpu = run_csr_insn(privilege, insn);
+            assert_eq!(cpu.state.pc, DRAM_BASE + 4, "{:#010x}", insn);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn csr_above_the_privilege_is_illegal() {
+        run_csr_insn(PrivilegeMode::Supervisor, csrrs(10, MSCRATCH, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn supervisor_csr_in_user_mode_is_illegal() {
+        run_csr_insn(PrivilegeMode::User, csrrs(10, SSCRATCH, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn writing_a_read_only_csr_is_illegal() {
+        run_csr_insn(PrivilegeMode::Machine, csrrw(0, MHARTID, 11));
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn setting_a_read_only_csr_from_a_zero_register_is_illegal() {
+        // rs1 不为 x0 即算写，与 x[rs1] 的值无关
+        run_csr_insn(PrivilegeMode::Machine, csrrs(10, MHARTID, 11));
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn clearing_a_read_only_csr_with_an_immediate_is_illegal() {
+        run_csr_insn(PrivilegeMode::Machine, csrrci(10, MHARTID, 1));
+    }
+
+    #[test]
+    fn csr_set_and_clear_without_bits_dont_write() {
+        // 写 fflags 会把 mstatus.FS 置为 Dirty，借此观察是否发生了写
+        let fs = |cpu: &Cpu| (cpu.state.csrs.csr(0x300) >> 13) & 3;
+        let reads = [
+            csrrs(10, FFLAGS, 0),
+            csrrsi(10, FFLAGS, 0),
+            csrrci(10, FFLAGS, 0),
+        ];
+        for &insn in reads.iter() {
+            let mut cpu = Cpu::with_program(XLen::X64, &[insn, EBREAK]);
+            cpu.state.csrs.set_csr(0x300, 1 << 13);
+            cpu.run_until_ebreak(10);
+            assert_eq!(fs(&cpu), 1, "{:#010x}", insn);
+        }
+        // 寄存器值为 0 时 rs1 不为 x0 仍然写
+        let mut cpu = Cpu::with_program(XLen::X64, &[csrrs(10, FFLAGS, 11), EBREAK]);
+        cpu.state.csrs.set_csr(0x300, 1 << 13);
+        cpu.run_until_ebreak(10);
+        assert_eq!(fs(&cpu), 3);
+    }
+
+    const SATP: u32 = 0x180;
+    const TVM: RegT = 1 << 20;
+    const TSR: RegT = 1 << 22;
+
+    /// 在 `privilege` 下以给定的 mstatus 执行 `insn`，其后是 EBREAK。
+    fn run_in(privilege: PrivilegeMode, mstatus: RegT, insn: u32) -> Cpu {
+        let mut cpu = Cpu::with_program(XLen::X64, &[insn, EBREAK]);
+        cpu.state.csrs.set_csr(0x300, mstatus);
+        cpu.state.csrs.set_csr(0x141, DRAM_BASE + 4);
+        cpu.state.csrs.set_csr(0x341, DRAM_BASE + 4);
+        cpu.state.privilege = privilege;
+        cpu.run_until_ebreak(10);
+        cpu
+    }
+
+    #[test]
+    fn tvm_and_tsr_only_affect_supervisor_mode() {
+        let s = PrivilegeMode::Supervisor;
+        for &insn in [csrrs(10, SATP, 0), csrrw(0, SATP, 0), SFENCE_VMA].iter() {
+            assert_eq!(run_in(s, 0, insn).state.pc, DRAM_BASE + 4, "{:#010x}", insn);
+            // M-mode 不受 TVM 影响
+            let cpu = run_in(PrivilegeMode::Machine, TVM, insn);
+            assert_eq!(cpu.state.pc, DRAM_BASE + 4, "{:#010x}", insn);
+        }
+        let cpu = run_in(s, 0, SRET);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::User);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn tvm_makes_reading_satp_illegal() {
+        run_in(PrivilegeMode::Supervisor, TVM, csrrs(10, SATP, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn tvm_makes_writing_satp_illegal() {
+        run_in(PrivilegeMode::Supervisor, TVM, csrrw(0, SATP, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn tvm_makes_sfence_vma_illegal() {
+        run_in(PrivilegeMode::Supervisor, TVM, SFENCE_VMA);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn tsr_makes_sret_illegal() {
+        run_in(PrivilegeMode::Supervisor, TSR, SRET);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn mret_in_supervisor_mode_is_illegal() {
+        run_in(PrivilegeMode::Supervisor, 0, MRET);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn mret_in_user_mode_is_illegal() {
+        run_in(PrivilegeMode::User, 0, MRET);
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Illegal instruction")]
+    fn sret_in_user_mode_is_illegal() {
+        run_in(PrivilegeMode::User, 0, SRET);
+    }
+
+    #[test]
+    fn sret_clears_mprv_and_mret_to_machine_mode_keeps_it() {
+        // SRET 总是返回到 M-mode 以下，因此清除 MPRV
+        let mprv = 1 << 17;
+        let spp_s = 1 << 8;
+        let cpu = run_in(PrivilegeMode::Machine, mprv | spp_s, SRET);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Supervisor);
+        assert_eq!(cpu.state.csrs.csr(0x300) & mprv, 0);
+        // MRET 回到 M-mode 时保留 MPRV
+        let mpp_m = 3 << 11;
+        let cpu = run_in(PrivilegeMode::Machine, mprv | mpp_m, MRET);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Machine);
+        assert_eq!(cpu.state.csrs.csr(0x300) & mprv, mprv);
+    }
+
+    const CYCLE: u32 = 0xc00;
+    const HPMCOUNTER3: u32 = 0xc03;
+    const HPMCOUNTER3H: u32 = 0xc83;
+    const MCYCLEH: u32 = 0xb80;
+    const MHPMCOUNTER3: u32 = 0xb03;
+    const MHPMEVENT3: u32 = 0x323;
+
+    #[test]
+    fn hpm_counters_read_as_zero_and_ignore_writes() {
+        let program = [
+            csrrw(0, MHPMCOUNTER3, 11),
+            csrrw(0, MHPMEVENT3, 11),
+            csrrs(10, MHPMCOUNTER3, 0),
+            csrrs(12, MHPMEVENT3, 0),
+            csrrs(13, HPMCOUNTER3, 0),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.xs.set_reg(10, 1);
+        cpu.state.xs.set_reg(11, 0x1234);
+        cpu.state.xs.set_reg(12, 1);
+        cpu.state.xs.set_reg(13, 1);
+        cpu.run_until_ebreak(10);
+        for &reg in [10, 12, 13].iter() {
+            assert_eq!(cpu.state.xs.reg(reg), 0, "x{}", reg);
+        }
+    }
+
+    #[test]
+    fn counters_below_m_mode_need_the_counteren_bits() {
+        let s = PrivilegeMode::Supervisor;
+        let u = PrivilegeMode::User;
+        let bit = |csr: u32| 1 << (csr & 0x1f);
+        // (特权级, mcounteren, scounteren, 是否允许)
+        for &csr in [CYCLE, HPMCOUNTER3].iter() {
+            let cases = [
+                (s, 0, bit(csr), false),
+                (s, bit(csr), 0, true),
+                (u, bit(csr), 0, false),
+                (u, 0, bit(csr), false),
+                (u, bit(csr), bit(csr), true),
+                // 别的计数器的位不算数
+                (u, !bit(csr), !bit(csr), false),
+            ];
+            for &(privilege, mcounteren, scounteren, allowed) in cases.iter() {
+                let mut cpu = Cpu::with_program(XLen::X64, &[csrrs(10, csr, 0), EBREAK]);
+                cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+                cpu.state.csrs.set_csr(0x306, mcounteren);
+                cpu.state.csrs.set_csr(0x106, scounteren);
+                cpu.state.privilege = privilege;
+                cpu.one_step();
+                let case = (csr, privilege, mcounteren, scounteren);
+                if allowed {
+                    assert_eq!(cpu.state.pc, DRAM_BASE + 4, "{:x?}", case);
+                } else {
+                    assert_eq!(cpu.state.pc, DRAM_BASE + 0x100, "{:x?}", case);
+                    assert_eq!(cpu.state.csrs.mcause(), 2, "{:x?}", case);
+                }
+            }
+        }
+        // counteren 只有 32 位
+        let mut cpu = Cpu::with_program(XLen::X64, &[]);
+        cpu.state.csrs.set_csr(0x306, !0);
+        assert_eq!(cpu.state.csrs.csr(0x306), 0xffff_ffff);
+    }
+
+    #[test]
+    fn counter_high_halves_exist_only_on_rv32() {
+        let program = [
+            csrrs(10, CYCLE | 0x80, 0),
+            csrrs(11, HPMCOUNTER3H, 0),
+            csrrs(12, MCYCLEH, 0),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X32, &program);
+        cpu.state.csrs.set_csr(0xb00, 6 << 32 | 1);
+        cpu.state.xs.set_reg(11, 1);
+        cpu.run_until_ebreak(10);
+        assert_regs!(cpu.state.xs, a0 = 6, a1 = 0, a2 = 6);
+
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        cpu.one_step();
+        assert_eq!(cpu.state.csrs.mcause(), 2);
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE);
+    }
+
+    #[test]
+    fn rv32_rejects_the_rv64_instructions() {
+        let slli_32 = i_type(32, 11, 1, 10, 0x13);
+        for &insn in [
+            ld(10, 11, 0),
+            sd(12, 11, 0),
+            i_type(0, 11, 6, 10, 0x03),     // lwu
+            i_type(1, 11, 0, 10, 0x1b),     // addiw
+            i_type(1, 11, 1, 10, 0x1b),     // slliw
+            r_type(0, 12, 11, 0, 10, 0x3b), // addw
+            muldivw(4, 10, 11, 12),         // divw
+            slli_32,
+        ]
+        .iter()
+        {
+            // a1 指向 DRAM 中的数据，访存指令在 RV64 上不会出错
+            let mut cpu = Cpu::with_program(XLen::X64, &[insn, EBREAK]);
+            cpu.state.xs.set_reg(11, DRAM_BASE + 0x100);
+            cpu.run_until_ebreak(10);
+            assert!(!decodes(XLen::X32, insn), "{:#010x}", insn);
+        }
+        assert_eq!(exec_op(XLen::X64, slli_32, 1, 0), 1 << 32);
+    }
+}
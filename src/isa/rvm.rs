@@ -1,8 +1,11 @@
 /// 乘除指令
-use crate::{cpu::Cpu, trap::Exception, Executable, Format, Insn, RegT, SRegT, INSN_SLICE};
+use crate::{cpu::Cpu, trap::Exception, Executable, Format, Insn, InsnEntry, RegT, SRegT};
 use proc_macros::Instruction;
 
-use super::sext;
+use super::{sext, signed};
+
+/// 指令表 (instruction_table) 中本模块指令所属的扩展。
+const EXT: &str = "M";
 
 def_insn!(
   #[derive(Instruction)]
@@ -21,7 +24,7 @@ impl Executable for Mul {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, rs1.wrapping_mul(rs2) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -38,14 +41,14 @@ impl Executable for Mulh {
     //  高位乘(Multiply High). R-type, RV32M and RV64M.
     // 把寄存器 x[rs2]乘到寄存器 x[rs1]上，都视为 2 的补码，将乘积的高位写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT as i128;
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as SRegT as i128;
+        let rs1 = signed(cpu, self.rs1()) as i128;
+        let rs2 = signed(cpu, self.rs2()) as i128;
 
         let value = rs1.wrapping_mul(rs2).wrapping_shr(cpu.xlen.len() as u32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (value as RegT) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -62,13 +65,13 @@ impl Executable for Mulhsu {
     // 高位有符号-无符号乘(Multiply High Signed-Unsigned). R-type, RV32M and RV64M.
     // 把寄存器 x[rs2]乘到寄存器 x[rs1]上，x[rs1]为 2 的补码，x[rs2]为无符号数，将乘积的高位写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT as i128 as u128;
+        let rs1 = signed(cpu, self.rs1()) as i128 as u128;
         let rs2 = cpu.state.xs.reg(self.rs2() as u8) as u128;
         let value = rs1.wrapping_mul(rs2).wrapping_shr(cpu.xlen.len() as u32);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (value as RegT) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -91,7 +94,7 @@ impl Executable for Mulhu {
         cpu.state
             .xs
             .set_reg(self.rd() as u8, (value as RegT) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -108,18 +111,15 @@ impl Executable for Div {
     // 除法(Divide). R-type, RV32M and RV64M.
     // 用寄存器 x[rs1]的值除以寄存器 x[rs2]的值，向零舍入，将这些数视为二进制补码，把商写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT;
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as SRegT;
+        let rs1 = signed(cpu, self.rs1());
+        let rs2 = signed(cpu, self.rs2());
 
-        let value = if rs2 == 0 {
-            (-1 as SRegT) as RegT
-        } else {
-            rs1.wrapping_div(rs2) as RegT
-        };
+        // 除以 0 得到 -1；溢出(最小值 ÷ -1)得到被除数本身，wrapping_div 正好如此。
+        let value = if rs2 == 0 { -1 } else { rs1.wrapping_div(rs2) };
         cpu.state
             .xs
-            .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+            .set_reg(self.rd() as u8, value as RegT & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -136,18 +136,15 @@ impl Executable for Divu {
     // 无符号除法(Divide, Unsigned). R-type, RV32M and RV64M.
     // 用寄存器 x[rs1]的值除以寄存器 x[rs2]的值，向零舍入，将这些数视为无符号数，把商写入x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8) & cpu.xlen.mask();
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8) & cpu.xlen.mask();
 
-        let value = if rs2 == 0 {
-            (-1 as SRegT) as RegT
-        } else {
-            rs1.wrapping_div(rs2)
-        };
+        // 除以 0 得到全 1。
+        let value = rs1.checked_div(rs2).unwrap_or(RegT::MAX);
         cpu.state
             .xs
             .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -164,16 +161,15 @@ impl Executable for Rem {
     // 求余数(Remainder). R-type, RV32M and RV64M.
     // x[rs1]除以 x[rs2]，向 0 舍入，都视为 2 的补码，余数写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as SRegT;
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as SRegT;
-        let value = if rs2 == 0 {
-            rs1 as RegT
-        } else {
-            (rs1.wrapping_rem(rs2) as RegT) & cpu.xlen.mask()
-        };
+        let rs1 = signed(cpu, self.rs1());
+        let rs2 = signed(cpu, self.rs2());
 
-        cpu.state.xs.set_reg(self.rd() as u8, value);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        // 除以 0 得到被除数；溢出(最小值 % -1)得到 0，wrapping_rem 正好如此。
+        let value = if rs2 == 0 { rs1 } else { rs1.wrapping_rem(rs2) };
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, value as RegT & cpu.xlen.mask());
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -190,15 +186,65 @@ impl Executable for Remu {
     // 求无符号数的余数(Remainder, Unsigned). R-type, RV32M and RV64M.
     // x[rs1]除以 x[rs2]，向 0 舍入，都视为无符号数，余数写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8);
-        let value = if rs2 == 0 {
-            rs1 as RegT
-        } else {
-            (rs1.wrapping_rem(rs2) as RegT) & cpu.xlen.mask()
-        };
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8) & cpu.xlen.mask();
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8) & cpu.xlen.mask();
+
+        // 除以 0 得到被除数。
+        let value = rs1.checked_rem(rs2).unwrap_or(rs1);
         cpu.state.xs.set_reg(self.rd() as u8, value);
-        cpu.state.update_pc(cpu.state.pc + 4);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x200403b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,Divw);
+
+impl Executable for Divw {
+    // x[rd] = sext(x[rs1][31:0] ÷s x[rs2][31:0])
+    // 字除法(Divide Word). R-type, RV64M only.
+    // 用寄存器 x[rs1]的低 32 位除以寄存器 x[rs2]的低 32 位，向零舍入，将这些数视为二进制补码，
+    // 把经符号位扩展的 32 位商写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as i32;
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as i32;
+
+        let value = if rs2 == 0 { -1 } else { rs1.wrapping_div(rs2) };
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, value as SRegT as RegT);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(R)]
+    #[match_code(0x200603b)]
+    #[mask(0xfe00707f)]
+    #[xlen(64)]
+    ,Remw);
+
+impl Executable for Remw {
+    // x[rd] = sext(x[rs1][31: 0] %𝑠 x[rs2][31: 0])
+    // 求余数字(Remainder Word). R-type, RV64M only.
+    // x[rs1]的低 32 位除以 x[rs2]的低 32 位，向 0 舍入，都视为 2 的补码，将余数的有符号扩展
+    // 写入 x[rd]。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as i32;
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as i32;
+
+        let value = if rs2 == 0 { rs1 } else { rs1.wrapping_rem(rs2) };
+        cpu.state
+            .xs
+            .set_reg(self.rd() as u8, value as SRegT as RegT);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -208,6 +254,7 @@ def_insn!(
     #[format(R)]
     #[match_code(0x200703b)]
     #[mask(0xfe00707f)]
+    #[xlen(64)]
     ,Remuw);
 
 impl Executable for Remuw {
@@ -216,17 +263,14 @@ impl Executable for Remuw {
     // x[rs1]的低 32 位除以 x[rs2]的低 32 位，向 0 舍入，都视为无符号数，将余数的有符号扩展
     // 写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) & 0xffff_ffff;
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8) & 0xffff_ffff;
-        let value = if rs2 == 0 {
-            sext(rs1, 32)
-        } else {
-            sext(rs1.wrapping_rem(rs2) as RegT, 32)
-        };
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as u32;
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as u32;
+
+        let value = rs1.checked_rem(rs2).unwrap_or(rs1);
         cpu.state
             .xs
-            .set_reg(self.rd() as u8, value & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+            .set_reg(self.rd() as u8, value as i32 as SRegT as RegT);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
@@ -236,6 +280,7 @@ def_insn!(
     #[format(R)]
     #[match_code(0x200503b)]
     #[mask(0xfe00707f)]
+    #[xlen(64)]
     ,Divuw);
 
 impl Executable for Divuw {
@@ -244,18 +289,137 @@ impl Executable for Divuw {
     // 用寄存器 x[rs1]的低 32 位除以寄存器 x[rs2]的低 32 位，向零舍入，将这些数视为无符号数，
     // 把经符号位扩展的 32 位商写入 x[rd]。
     fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
-        let rs1 = cpu.state.xs.reg(self.rs1() as u8) & 0xffff_ffff;
-        let rs2 = cpu.state.xs.reg(self.rs2() as u8) & 0xffff_ffff;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8) as u32;
+        let rs2 = cpu.state.xs.reg(self.rs2() as u8) as u32;
 
-        let value = if rs2 == 0 {
-            (-1 as SRegT) as RegT
-        } else {
-            rs1.wrapping_div(rs2)
-        };
+        let value = rs1.checked_div(rs2).unwrap_or(u32::MAX);
         cpu.state
             .xs
-            .set_reg(self.rd() as u8, sext(value, 32) & cpu.xlen.mask());
-        cpu.state.update_pc(cpu.state.pc + 4);
+            .set_reg(self.rd() as u8, value as i32 as SRegT as RegT);
+        cpu.state.advance_pc(4);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{testing::*, RegT, XLen};
+
+    const MUL: u32 = 0;
+    const MULH: u32 = 1;
+    const MULHSU: u32 = 2;
+    const MULHU: u32 = 3;
+    const DIV: u32 = 4;
+    const DIVU: u32 = 5;
+    const REM: u32 = 6;
+    const REMU: u32 = 7;
+
+    const NAMES: [&str; 8] = [
+        "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu",
+    ];
+
+    fn op(xlen: XLen, funct3: u32, rs1: RegT, rs2: RegT) -> RegT {
+        exec_op(xlen, muldiv(funct3, 10, 11, 12), rs1, rs2)
+    }
+
+    fn opw(funct3: u32, rs1: RegT, rs2: RegT) -> RegT {
+        exec_op(XLen::X64, muldivw(funct3, 10, 11, 12), rs1, rs2)
+    }
+
+    /// 每种运算都要覆盖的边界情况：除以 0、有符号溢出，以及操作数的各种符号组合。
+    fn edge_cases(bits: u32) -> Vec<(RegT, RegT)> {
+        let mask = RegT::MAX >> (64 - bits);
+        let min = 1 << (bits - 1);
+        let max = min - 1;
+        let neg = |v: i64| v as RegT & mask;
+        vec![
+            (7, 0),
+            (neg(-7), 0),
+            (min, 0),
+            (0, 0),
+            (min, neg(-1)),
+            (min, 1),
+            (max, neg(-1)),
+            (7, 2),
+            (neg(-7), 2),
+            (7, neg(-2)),
+            (neg(-7), neg(-2)),
+            (max, max),
+            (min, min),
+            (max, min),
+            (neg(-1), neg(-1)),
+            (neg(-1), max),
+        ]
+    }
+
+    /// 按规范表 7.1 用 128 位整数算出 `bits` 位的结果。
+    fn reference(bits: u32, funct3: u32, a: RegT, b: RegT) -> RegT {
+        let mask = RegT::MAX >> (64 - bits);
+        let (ua, ub) = ((a & mask) as i128, (b & mask) as i128);
+        let signed = |v: i128| {
+            if v >> (bits - 1) & 1 != 0 {
+                v - (1 << bits)
+            } else {
+                v
+            }
+        };
+        let (sa, sb) = (signed(ua), signed(ub));
+        let overflow = sa == -(1 << (bits - 1)) && sb == -1;
+        let result = match funct3 {
+            MUL => sa * sb,
+            MULH => (sa * sb) >> bits,
+            MULHSU => (sa * ub) >> bits,
+            MULHU => ((ua as u128 * ub as u128) >> bits) as i128,
+            DIV if sb == 0 => -1,
+            DIV if overflow => sa,
+            DIV => sa / sb,
+            DIVU if ub == 0 => -1,
+            DIVU => ua / ub,
+            REM if sb == 0 => sa,
+            REM if overflow => 0,
+            REM => sa % sb,
+            REMU if ub == 0 => ua,
+            REMU => ua % ub,
+            _ => unreachable!(),
+        };
+        result as RegT & mask
+    }
+
+    #[test]
+    fn every_operation_follows_the_spec_at_the_edges() {
+        for &(xlen, bits) in [(XLen::X32, 32), (XLen::X64, 64)].iter() {
+            for funct3 in MUL..=REMU {
+                for (a, b) in edge_cases(bits) {
+                    assert_eq!(
+                        op(xlen, funct3, a, b),
+                        reference(bits, funct3, a, b),
+                        "{} {:#x}, {:#x} on RV{}",
+                        NAMES[funct3 as usize],
+                        a,
+                        b,
+                        bits
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn word_ops_follow_the_spec_at_the_edges() {
+        // 高 32 位不参与运算。
+        let garbage = 0xdead_beef << 32;
+        for &funct3 in [DIV, DIVU, REM, REMU].iter() {
+            for (a, b) in edge_cases(32) {
+                let expected = reference(32, funct3, a, b) as i32 as RegT;
+                assert_eq!(
+                    opw(funct3, a | garbage, b | garbage),
+                    expected,
+                    "{}w {:#x}, {:#x}",
+                    NAMES[funct3 as usize],
+                    a,
+                    b
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,315 @@
+/// 缓存块操作指令集：Zicbom 和 Zicboz
+use crate::{
+    cpu::Cpu,
+    mmu::AccessType,
+    register::envcfg::{Cbie, Envcfg},
+    trap::Exception,
+    Executable, Format, Insn, InsnEntry, PrivilegeMode, RegT,
+};
+use proc_macros::Instruction;
+
+use super::sext;
+
+/// 指令表 (instruction_table) 中本模块指令所属的扩展。
+const EXT: &str = "Zicbo";
+
+/// 缓存块的大小，单位为字节。
+const CACHE_BLOCK_SIZE: RegT = 64;
+
+/// 检查能否以 a_type 访问 x[rs1]所在的缓存块。没有缓存需要维护，所以管理指令只做这项检查。
+/// 无论检查的是读还是写权限，异常都按存储报告，xtval 为 x[rs1]。
+fn check_block(cpu: &Cpu, addr: RegT, a_type: AccessType) -> Result<(), Exception> {
+    let block = addr & !(CACHE_BLOCK_SIZE - 1);
+    cpu.mmu
+        .check_access(&cpu.state, block, CACHE_BLOCK_SIZE as usize, a_type)
+        .map_err(|e| match e {
+            Exception::LoadPageFault(_) | Exception::StorePageFault(_) => {
+                Exception::StorePageFault(addr)
+            }
+            Exception::LoadFault(_) | Exception::StoreFault(_) => Exception::StoreFault(addr),
+            e => e,
+        })
+}
+
+/// 检查当前特权级能否执行缓存块操作：M 模式以下需要 menvcfg 中的使能位，U 模式还需要
+/// senvcfg 中的使能位，否则触发非法指令异常。
+fn check_enabled(cpu: &Cpu, enabled: fn(Envcfg) -> bool) -> Result<(), Exception> {
+    let csrs = &cpu.state.csrs;
+    let allowed = match cpu.state.privilege {
+        PrivilegeMode::Machine => true,
+        PrivilegeMode::Supervisor => enabled(csrs.menvcfg()),
+        PrivilegeMode::User => enabled(csrs.menvcfg()) && enabled(csrs.senvcfg()),
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(Exception::IllegalInstruction(0))
+    }
+}
+
+/// 当前特权级下 cbo.inval 的行为，由 menvcfg.CBIE 和 U 模式下的 senvcfg.CBIE 中较弱的一个决定。
+fn inval_mode(cpu: &Cpu) -> Cbie {
+    let csrs = &cpu.state.csrs;
+    let weaker = |a: Cbie, b: Cbie| if (a as u8) < (b as u8) { a } else { b };
+    match cpu.state.privilege {
+        PrivilegeMode::Machine => Cbie::Invalidate,
+        PrivilegeMode::Supervisor => csrs.menvcfg().cbie(),
+        PrivilegeMode::User => weaker(csrs.menvcfg().cbie(), csrs.senvcfg().cbie()),
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x0000200f)]
+    #[mask(0xfff07fff)]
+    ,CboInval);
+
+impl Executable for CboInval {
+    // Invalidate(M[x[rs1]])
+    // 作废缓存块 (Cache Block Invalidate). I-type, Zicbom.
+    // 作废 x[rs1]所在缓存块的副本，丢弃其中未写回的修改。需要写权限。
+    // CBIE 为 01 时按 cbo.flush 执行，只需要读权限。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let a_type = match inval_mode(cpu) {
+            Cbie::Illegal => return Err(Exception::IllegalInstruction(0)),
+            Cbie::Flush => AccessType::Load,
+            Cbie::Invalidate => AccessType::Store,
+        };
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        check_block(cpu, rs1, a_type)?;
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x0010200f)]
+    #[mask(0xfff07fff)]
+    ,CboClean);
+
+impl Executable for CboClean {
+    // Clean(M[x[rs1]])
+    // 清理缓存块 (Cache Block Clean). I-type, Zicbom.
+    // 把 x[rs1]所在缓存块中的修改写回内存。需要读权限。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu, |envcfg| envcfg.cbcfe())?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        check_block(cpu, rs1, AccessType::Load)?;
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x0020200f)]
+    #[mask(0xfff07fff)]
+    ,CboFlush);
+
+impl Executable for CboFlush {
+    // Clean(M[x[rs1]]); Invalidate(M[x[rs1]])
+    // 刷新缓存块 (Cache Block Flush). I-type, Zicbom.
+    // 把 x[rs1]所在缓存块中的修改写回内存，再作废其副本。需要读权限。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu, |envcfg| envcfg.cbcfe())?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        check_block(cpu, rs1, AccessType::Load)?;
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+def_insn!(
+    #[derive(Instruction)]
+    #[format(I)]
+    #[match_code(0x0040200f)]
+    #[mask(0xfff07fff)]
+    ,CboZero);
+
+impl Executable for CboZero {
+    // M[x[rs1] & ~63 +: 64] = 0
+    // 缓存块清零 (Cache Block Zero). I-type, Zicboz.
+    // 把 x[rs1]所在的、按 64 字节对齐的缓存块全部写为 0。先检查整块的写权限，出错时不写入
+    // 任何字节。
+    fn exec(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        check_enabled(cpu, |envcfg| envcfg.cbze())?;
+        let rs1 = cpu.state.xs.reg(self.rs1() as u8);
+        check_block(cpu, rs1, AccessType::Store)?;
+        let block = rs1 & !(CACHE_BLOCK_SIZE - 1);
+        for offset in (0..CACHE_BLOCK_SIZE).step_by(8) {
+            cpu.mmu.store::<u64>(&cpu.state, block + offset, 0)?;
+        }
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cpu::{Cpu, StopReason},
+        register::envcfg::{CBCFE, CBIE, CBZE},
+        testing::*,
+        trap::Exception,
+        PrivilegeMode, RegT, XLen,
+    };
+
+    const fn cbo(funct12: i32, rs1: u32) -> u32 {
+        i_type(funct12, rs1, 2, 0, 0x0f)
+    }
+
+    const INVAL: i32 = 0;
+    const CLEAN: i32 = 1;
+    const FLUSH: i32 = 2;
+    const ZERO: i32 = 4;
+
+    const DATA: u64 = DRAM_BASE + 0x1000;
+    const TABLES: u64 = DRAM_BASE + 0x10_0000;
+    /// 只读页的虚拟地址，映射到 DATA。
+    const READ_ONLY: u64 = 0x4000_0000;
+    /// 陷入处理程序的地址，那里是一条 EBREAK。
+    const MTVEC: u64 = DRAM_BASE + 0x100;
+
+    /// 执行 insn 的 hart，DATA 开始的 0x80 字节全为 0xff。
+    fn cpu_with(insn: u32) -> Cpu {
+        let mut image = vec![NOP; 0x41];
+        image[..2].copy_from_slice(&[insn, EBREAK]);
+        image[0x40] = EBREAK;
+        let mut cpu = Cpu::with_program(XLen::X64, &image);
+        cpu.state.csrs.set_mtvec(MTVEC);
+        // M 模式以下默认允许所有缓存块操作。
+        cpu.state.csrs.set_menvcfg(CBIE | CBCFE | CBZE);
+        for offset in (0..0x80).step_by(8) {
+            cpu.mmu
+                .bus
+                .borrow_mut()
+                .write::<u64>(DATA + offset, u64::MAX)
+                .unwrap();
+        }
+        cpu
+    }
+
+    fn data(cpu: &Cpu) -> Vec<u64> {
+        (0..0x80)
+            .step_by(8)
+            .map(|offset| cpu.mmu.bus.borrow().read::<u64>(DATA + offset).unwrap())
+            .collect()
+    }
+
+    /// 执行一步，返回引发的异常，无论它交给了处理程序还是停下了模拟器。
+    fn step(cpu: &mut Cpu) -> Result<(), Exception> {
+        match cpu.one_step() {
+            Some(StopReason::Fatal(report)) => Err(report.exception),
+            _ if cpu.state.pc == MTVEC => {
+                let csrs = &cpu.state.csrs;
+                Err(Exception::from(csrs.mcause()).at(csrs.mtval()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[test]
+    fn cbo_zero_clears_the_aligned_block() {
+        let mut cpu = cpu_with(cbo(ZERO, 11));
+        cpu.state.xs.set_reg(11, DATA + 0x40 + 0x23);
+        assert_eq!(step(&mut cpu), Ok(()));
+        let data = data(&cpu);
+        assert_eq!(data[..8], [u64::MAX; 8]);
+        assert_eq!(data[8..], [0; 8]);
+    }
+
+    /// 在 S 模式下以 x[rs1] = addr 执行 insn，READ_ONLY 映射为只读页。
+    fn run_in_s_mode(insn: u32, addr: RegT) -> (Cpu, Result<(), Exception>) {
+        let mut cpu = cpu_with(insn);
+        let mut tables = PageTables::new(TABLES);
+        tables.map(
+            &mut cpu,
+            DRAM_BASE,
+            DRAM_BASE,
+            PTE_V | PTE_R | PTE_X | PTE_A,
+        );
+        tables.map(&mut cpu, READ_ONLY, DATA, PTE_V | PTE_R | PTE_A);
+        cpu.state.csrs.set_satp(tables.satp());
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.state.xs.set_reg(11, addr);
+        let result = step(&mut cpu);
+        (cpu, result)
+    }
+
+    #[test]
+    fn block_operations_check_the_permission_they_need() {
+        let addr = READ_ONLY + 0x48;
+        let store_fault = Err(Exception::StorePageFault(addr));
+        for &op in [CLEAN, FLUSH].iter() {
+            assert_eq!(run_in_s_mode(cbo(op, 11), addr).1, Ok(()));
+        }
+        assert_eq!(run_in_s_mode(cbo(INVAL, 11), addr).1, store_fault);
+
+        // 出错的 cbo.zero 不写入任何字节。
+        let (cpu, result) = run_in_s_mode(cbo(ZERO, 11), addr);
+        assert_eq!(result, store_fault);
+        assert_eq!(data(&cpu), [u64::MAX; 16]);
+
+        // 没有映射的页，读也按存储报告。
+        let unmapped = READ_ONLY + 0x1000;
+        let fault = Err(Exception::StorePageFault(unmapped));
+        assert_eq!(run_in_s_mode(cbo(CLEAN, 11), unmapped).1, fault);
+    }
+
+    /// S 模式、不分页的 hart；PMP 让代码页可执行，DATA 只读，其余不可访问。
+    fn cpu_with_pmp(insn: u32, addr: RegT) -> Cpu {
+        let napot = |base: u64| (base | 0x7ff) >> 2;
+        let mut cpu = cpu_with(insn);
+        cpu.state.csrs.set_csr(0x3b0, napot(DRAM_BASE));
+        cpu.state.csrs.set_csr(0x3b1, napot(DATA));
+        cpu.state.csrs.set_csr(0x3a0, 0x19 << 8 | 0x1d);
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.state.xs.set_reg(11, addr);
+        cpu
+    }
+
+    #[test]
+    fn access_faults_are_reported_as_store_faults() {
+        for &(op, addr) in [(CLEAN, DATA + 0x1000), (INVAL, DATA), (ZERO, DATA)].iter() {
+            let mut cpu = cpu_with_pmp(cbo(op, 11), addr);
+            assert_eq!(step(&mut cpu), Err(Exception::StoreFault(addr)), "{}", op);
+        }
+    }
+
+    #[test]
+    fn envcfg_enables_block_operations_below_machine_mode() {
+        let illegal = Err(Exception::IllegalInstruction(0));
+        for &(op, field) in [(INVAL, CBIE), (CLEAN, CBCFE), (FLUSH, CBCFE), (ZERO, CBZE)].iter() {
+            // U 模式还需要 senvcfg 中的使能位。
+            for &(privilege, menvcfg, senvcfg, allowed) in [
+                (PrivilegeMode::Machine, 0, 0, true),
+                (PrivilegeMode::Supervisor, 0, field, false),
+                (PrivilegeMode::Supervisor, field, 0, true),
+                (PrivilegeMode::User, field, 0, false),
+                (PrivilegeMode::User, field, field, true),
+            ]
+            .iter()
+            {
+                let mut cpu = cpu_with(cbo(op, 11));
+                cpu.state.csrs.set_menvcfg(menvcfg);
+                cpu.state.csrs.set_senvcfg(senvcfg);
+                cpu.state.privilege = privilege;
+                cpu.state.xs.set_reg(11, DATA);
+                let expected = if allowed { Ok(()) } else { illegal };
+                assert_eq!(step(&mut cpu), expected, "{} in {:?}", op, privilege);
+            }
+        }
+
+        // CBIE 为 01 时 cbo.inval 按 cbo.flush 执行，只读的 DATA 也可以。
+        let mut cpu = cpu_with_pmp(cbo(INVAL, 11), DATA);
+        cpu.state.csrs.set_menvcfg(0x1 << 4);
+        assert_eq!(step(&mut cpu), Ok(()));
+        // 保留的编码 10 不会写入。
+        cpu.state.csrs.set_menvcfg(0x2 << 4);
+        assert_eq!(cpu.state.csrs.menvcfg().bits(), 0x1 << 4);
+    }
+}
@@ -0,0 +1,97 @@
+//! A RISC-V emulator running RV32/RV64 IMAFD programs and the xv6 kernel. The `riscv-emulator`
+//! binary is the command line frontend, the `ffi` module lets other languages embed it.
+
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+
+#[cfg(all(test, feature = "bench"))]
+mod benches;
+mod block;
+#[cfg(feature = "host")]
+pub mod config;
+#[cfg(feature = "host")]
+pub mod console;
+pub mod cosim;
+mod coverage;
+pub mod cpu;
+mod device;
+pub mod hook;
+mod isa;
+#[cfg(feature = "host")]
+pub mod linux;
+pub mod machine;
+mod mmu;
+#[cfg(feature = "host")]
+pub mod monitor;
+mod page;
+mod pmp;
+mod profile;
+mod register;
+mod report;
+pub mod reverse;
+mod sbi;
+mod semihosting;
+pub mod signature;
+mod snapshot;
+pub mod stats;
+mod symbols;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod trap;
+mod trigger;
+pub mod watchpoint;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[macro_use]
+extern crate macros;
+
+use cpu::Cpu;
+use trap::Exception;
+
+pub type RegT = u64;
+pub type SRegT = i64;
+
+#[derive(Debug, PartialEq, PartialOrd, Eq, Copy, Clone)]
+pub enum PrivilegeMode {
+    User = 0,
+    Supervisor = 1,
+    Machine = 2,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum XLen {
+    X32 = 32,
+    X64 = 64,
+}
+
+impl XLen {
+    #[allow(clippy::len_without_is_empty)]
+    pub const fn len(&self) -> usize {
+        match self {
+            XLen::X32 => 32,
+            XLen::X64 => 64,
+        }
+    }
+
+    pub const fn size(&self) -> usize {
+        self.len() >> 3
+    }
+
+    pub const fn mask(&self) -> RegT {
+        match self {
+            // 0xffffffff
+            XLen::X32 => ((1 as RegT) << (self.len() as RegT)) - 1,
+            // 0xffffffffffffffff
+            XLen::X64 => -1i64 as RegT,
+        }
+    }
+}
+
+init_insn!(Cpu, Exception);
+
+#[cfg(feature = "framebuffer")]
+pub use device::framebuffer;
+#[cfg(not(feature = "dyn-insn"))]
+pub use isa::Insn;
+pub use mmu::{TranslationInfo, WalkStep};
@@ -0,0 +1,698 @@
+//! User-mode emulation: runs a statically linked RV64 Linux program without a kernel. The program
+//! is laid out in a flat address space without translation and runs in U-mode, its ECALLs are
+//! served as Linux syscalls against the host.
+//!
+//! The address space is the DRAM, placed below the CLINT:
+//!
+//! ```text
+//! USER_BASE   the loaded segments, followed by the heap growing up with brk
+//!             anonymous mappings growing down
+//! stack_top   the stack growing down, STACK_SIZE bytes at the end of the DRAM
+//! ```
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::{
+    cpu::{Cpu, CpuBuilder},
+    device::CLINT_BASE,
+    hook::EcallHandler,
+    mmu::PAGE_SIZE,
+    symbols::{invalid_elf, read_u16, read_u32, read_u64, slice},
+    trap::Exception,
+    PrivilegeMode, RegT, XLen,
+};
+
+/// The lowest address a program is loaded at, Linux refuses to map anything below it.
+const USER_BASE: u64 = 0x10000;
+const USER_END: u64 = CLINT_BASE;
+const STACK_SIZE: u64 = 1024 * 1024;
+
+const EM_RISCV: u16 = 0xf3;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PHDR_SIZE: usize = 56;
+
+// Auxiliary vector entries.
+const AT_NULL: RegT = 0;
+const AT_PHDR: RegT = 3;
+const AT_PHENT: RegT = 4;
+const AT_PHNUM: RegT = 5;
+const AT_PAGESZ: RegT = 6;
+const AT_ENTRY: RegT = 9;
+const AT_RANDOM: RegT = 25;
+
+// Syscall numbers of the generic table RISC-V uses.
+const SYS_OPENAT: RegT = 56;
+const SYS_CLOSE: RegT = 57;
+const SYS_LSEEK: RegT = 62;
+const SYS_READ: RegT = 63;
+const SYS_WRITE: RegT = 64;
+const SYS_FSTAT: RegT = 80;
+const SYS_EXIT: RegT = 93;
+const SYS_EXIT_GROUP: RegT = 94;
+const SYS_CLOCK_GETTIME: RegT = 113;
+const SYS_BRK: RegT = 214;
+const SYS_MMAP: RegT = 222;
+
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const ENODEV: i64 = 19;
+const EINVAL: i64 = 22;
+const ESPIPE: i64 = 29;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS: i64 = 38;
+
+const AT_FDCWD: i64 = -100;
+const O_ACCMODE: RegT = 0o3;
+const O_WRONLY: RegT = 0o1;
+const O_RDWR: RegT = 0o2;
+const O_CREAT: RegT = 0o100;
+const O_EXCL: RegT = 0o200;
+const O_TRUNC: RegT = 0o1000;
+const O_APPEND: RegT = 0o2000;
+const MAP_FIXED: RegT = 0x10;
+const MAP_ANONYMOUS: RegT = 0x20;
+const CLOCK_REALTIME: RegT = 0;
+/// `S_IFCHR | 0620`, what a terminal looks like.
+const STDIO_MODE: u32 = 0o20620;
+/// The size of `struct stat` on RV64.
+const STAT_SIZE: usize = 128;
+/// The longest path openat accepts.
+const PATH_MAX: u64 = 4096;
+/// The most `read` buffers on the host at once, a larger read goes to the guest in chunks.
+const READ_CHUNK: u64 = 64 * 1024;
+
+/// A statically linked program laid out in the address space, see `load`.
+pub struct Program {
+    entry: RegT,
+    /// Where the program headers are found in memory, for the auxiliary vector.
+    phdr: RegT,
+    phnum: RegT,
+    /// The end of the loaded segments, where the heap starts.
+    end: RegT,
+    /// The AT_RANDOM bytes seeding the stack protector, see `seed`.
+    random: [u8; 16],
+}
+
+/// Lays out the segments of the static ELF executable `elf` in the address space and returns a
+/// builder starting the program in U-mode, along with what `Program::start` needs to finish the
+/// job.
+pub fn load(elf: &[u8]) -> io::Result<(CpuBuilder, Program)> {
+    if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+        return Err(invalid_elf("not a 64-bit little endian ELF file"));
+    }
+    if read_u16(elf, 0x12)? != EM_RISCV || read_u16(elf, 0x10)? != ET_EXEC {
+        return Err(invalid_elf("not a RISC-V executable"));
+    }
+    let entry = read_u64(elf, 0x18)?;
+    let phoff = read_u64(elf, 0x20)?;
+    let phentsize = read_u16(elf, 0x36)? as usize;
+    let phnum = read_u16(elf, 0x38)? as usize;
+
+    let mut image = Vec::new();
+    let mut phdr = 0;
+    for idx in 0..phnum {
+        let ph = phoff as usize + idx * phentsize;
+        let p_type = read_u32(elf, ph)?;
+        if p_type == PT_INTERP {
+            return Err(invalid_elf("only statically linked programs are supported"));
+        }
+        if p_type != PT_LOAD {
+            continue;
+        }
+        let offset = read_u64(elf, ph + 8)?;
+        let vaddr = read_u64(elf, ph + 16)?;
+        let filesz = read_u64(elf, ph + 32)?;
+        let memsz = read_u64(elf, ph + 40)?;
+        let end = vaddr.saturating_add(memsz);
+        if vaddr < USER_BASE || end > USER_END - STACK_SIZE || filesz > memsz {
+            return Err(invalid_elf("segment outside of the address space"));
+        }
+        if (offset..offset + filesz).contains(&phoff) {
+            phdr = vaddr + phoff - offset;
+        }
+        let start = (vaddr - USER_BASE) as usize;
+        if image.len() < (end - USER_BASE) as usize {
+            image.resize((end - USER_BASE) as usize, 0);
+        }
+        image[start..start + filesz as usize].copy_from_slice(slice(
+            elf,
+            offset as usize,
+            filesz as usize,
+        )?);
+    }
+    if image.is_empty() {
+        return Err(invalid_elf("no loadable segment"));
+    }
+
+    let program = Program {
+        entry,
+        phdr,
+        phnum: phnum as RegT,
+        end: USER_BASE + image.len() as u64,
+        random: [0x5a; 16],
+    };
+    let builder = CpuBuilder::new(image)
+        .xlen(XLen::X64)
+        .dram(USER_BASE, (USER_END - USER_BASE) as usize)
+        .entry(entry)
+        .privilege(PrivilegeMode::User);
+    Ok((builder, program))
+}
+
+impl Program {
+    /// Derives the AT_RANDOM bytes from `seed` rather than leaving them fixed. A run is still
+    /// reproducible, given the same seed.
+    pub fn seed(mut self, seed: u64) -> Self {
+        // splitmix64, any seed gives well mixed bytes.
+        let mut state = seed;
+        for chunk in self.random.chunks_mut(8) {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            chunk.copy_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+        }
+        self
+    }
+
+    /// Lays out `args` and the auxiliary vector on the stack of `cpu` as the RISC-V Linux ABI
+    /// describes, and serves the ECALLs of `cpu` from now on. The environment is left empty.
+    pub fn start(self, cpu: &mut Cpu, args: &[String]) -> io::Result<()> {
+        let stack_top = USER_END;
+        // The strings go to the top of the stack, the pointer arrays right below them.
+        let mut strings = Vec::new();
+        let mut string_offsets = Vec::new();
+        for arg in args {
+            string_offsets.push(strings.len() as u64);
+            strings.extend_from_slice(arg.as_bytes());
+            strings.push(0);
+        }
+        let random_offset = strings.len() as u64;
+        strings.extend_from_slice(&self.random);
+        let strings_addr = (stack_top - strings.len() as u64) & !0xf;
+
+        let mut words = vec![args.len() as RegT];
+        words.extend(string_offsets.iter().map(|offset| strings_addr + offset));
+        // The end of argv and the empty environment.
+        words.extend([0, 0]);
+        words.extend([
+            AT_PHDR,
+            self.phdr,
+            AT_PHENT,
+            PHDR_SIZE as RegT,
+            AT_PHNUM,
+            self.phnum,
+            AT_PAGESZ,
+            PAGE_SIZE,
+            AT_ENTRY,
+            self.entry,
+            AT_RANDOM,
+            strings_addr + random_offset,
+            AT_NULL,
+            0,
+        ]);
+        let sp = (strings_addr - words.len() as u64 * 8) & !0xf;
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let fault = |_| io::Error::new(io::ErrorKind::InvalidInput, "the arguments don't fit");
+        write_memory(cpu, strings_addr, &strings).map_err(fault)?;
+        write_memory(cpu, sp, &bytes).map_err(fault)?;
+        cpu.state.xs.set_reg(2, sp);
+
+        let brk = align_up(self.end).expect("the program ends below the stack");
+        cpu.set_ecall_handler(Box::new(Linux::new(brk, stack_top - STACK_SIZE)));
+        Ok(())
+    }
+}
+
+/// A file descriptor of the program.
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Serves the ECALLs of a program as Linux syscalls, the number in a7 and the arguments in a0 to
+/// a5. The result goes to a0, a negated errno on failure.
+pub struct Linux {
+    /// Indexed by file descriptor, closed descriptors are `None`.
+    fds: Vec<Option<Fd>>,
+    /// The start of the heap.
+    brk_start: u64,
+    brk: u64,
+    /// The lowest anonymous mapping, new ones are placed right below it.
+    mmap_bottom: u64,
+    started: Instant,
+}
+
+impl EcallHandler for Linux {
+    fn ecall(&mut self, cpu: &mut Cpu) -> Result<(), Exception> {
+        let xs = &cpu.state.xs;
+        let nr = xs.reg(17);
+        let args = [
+            xs.reg(10),
+            xs.reg(11),
+            xs.reg(12),
+            xs.reg(13),
+            xs.reg(14),
+            xs.reg(15),
+        ];
+        let result = match nr {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                cpu.exit(args[0] as i32);
+                Ok(0)
+            }
+            SYS_OPENAT => self.openat(cpu, args[0] as i64, args[1], args[2], args[3]),
+            SYS_CLOSE => self.close(args[0]),
+            SYS_LSEEK => self.lseek(args[0], args[1] as i64, args[2]),
+            SYS_READ => self.read(cpu, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(cpu, args[0], args[1], args[2]),
+            SYS_FSTAT => self.fstat(cpu, args[0], args[1]),
+            SYS_CLOCK_GETTIME => self.clock_gettime(cpu, args[0], args[1]),
+            SYS_BRK => Ok(self.brk(args[0])),
+            SYS_MMAP => self.mmap(args[1], args[3]),
+            _ => {
+                warn!("unimplemented syscall {} at {:#x}", nr, cpu.state.pc);
+                Err(ENOSYS)
+            }
+        };
+        let a0 = result.unwrap_or_else(|errno| errno.wrapping_neg() as RegT);
+        cpu.state.xs.set_reg(10, a0);
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+impl Linux {
+    fn new(brk: u64, mmap_top: u64) -> Self {
+        Self {
+            fds: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            brk_start: brk,
+            brk,
+            mmap_bottom: mmap_top,
+            started: Instant::now(),
+        }
+    }
+
+    fn fd(&mut self, fd: RegT) -> Result<&mut Fd, i64> {
+        self.fds
+            .get_mut(fd as usize)
+            .and_then(|fd| fd.as_mut())
+            .ok_or(EBADF)
+    }
+
+    fn openat(
+        &mut self,
+        cpu: &Cpu,
+        dirfd: i64,
+        path: RegT,
+        flags: RegT,
+        mode: RegT,
+    ) -> Result<RegT, i64> {
+        let path = read_c_string(cpu, path)?;
+        // Relative paths are resolved against the host working directory.
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(EINVAL);
+        }
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .mode(mode as u32);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        let file = options.open(path).map_err(errno)?;
+        let fd = Some(Fd::File(file));
+        match self.fds.iter().position(|fd| fd.is_none()) {
+            Some(idx) => {
+                self.fds[idx] = fd;
+                Ok(idx as RegT)
+            }
+            None => {
+                self.fds.push(fd);
+                Ok(self.fds.len() as RegT - 1)
+            }
+        }
+    }
+
+    fn close(&mut self, fd: RegT) -> Result<RegT, i64> {
+        self.fd(fd)?;
+        self.fds[fd as usize] = None;
+        Ok(0)
+    }
+
+    fn lseek(&mut self, fd: RegT, offset: i64, whence: RegT) -> Result<RegT, i64> {
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        match self.fd(fd)? {
+            Fd::File(file) => file.seek(pos).map_err(errno),
+            // Pipes and terminals can't seek.
+            _ => Err(ESPIPE),
+        }
+    }
+
+    /// Reads up to `len` bytes in chunks of `READ_CHUNK`. Like Linux, it returns a short count
+    /// when an error or a fault follows some bytes, and stops after a short read.
+    fn read(&mut self, cpu: &mut Cpu, fd: RegT, buf: RegT, len: RegT) -> Result<RegT, i64> {
+        let mut data = vec![0; len.min(READ_CHUNK) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(READ_CHUNK) as usize;
+            let n = match self.fd(fd)? {
+                Fd::Stdin => io::stdin().read(&mut data[..chunk]),
+                Fd::File(file) => file.read(&mut data[..chunk]),
+                _ => return Err(EBADF),
+            };
+            let n = match n {
+                Ok(n) => n,
+                Err(e) if done == 0 => return Err(errno(e)),
+                Err(_) => break,
+            };
+            if write_memory(cpu, buf.wrapping_add(done), &data[..n]).is_err() {
+                return if done == 0 { Err(EFAULT) } else { Ok(done) };
+            }
+            done += n as RegT;
+            // The end of a file, or a terminal with nothing more typed yet.
+            if n < chunk {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write(&mut self, cpu: &Cpu, fd: RegT, buf: RegT, len: RegT) -> Result<RegT, i64> {
+        let data = read_memory(cpu, buf, len)?;
+        match self.fd(fd)? {
+            Fd::Stdout => io::stdout()
+                .write_all(&data)
+                .and_then(|_| io::stdout().flush()),
+            Fd::Stderr => io::stderr().write_all(&data),
+            Fd::File(file) => file.write_all(&data),
+            Fd::Stdin => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        Ok(len)
+    }
+
+    fn fstat(&mut self, cpu: &mut Cpu, fd: RegT, statbuf: RegT) -> Result<RegT, i64> {
+        let mut stat = [0; STAT_SIZE];
+        let mut put = |offset: usize, value: u64, size: usize| {
+            stat[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        };
+        match self.fd(fd)? {
+            Fd::File(file) => {
+                let meta = file.metadata().map_err(errno)?;
+                put(0, meta.dev(), 8);
+                put(8, meta.ino(), 8);
+                put(16, meta.mode() as u64, 4);
+                put(20, meta.nlink(), 4);
+                put(24, meta.uid() as u64, 4);
+                put(28, meta.gid() as u64, 4);
+                put(32, meta.rdev(), 8);
+                put(48, meta.size(), 8);
+                put(56, meta.blksize(), 4);
+                put(64, meta.blocks(), 8);
+                put(72, meta.atime() as u64, 8);
+                put(80, meta.atime_nsec() as u64, 8);
+                put(88, meta.mtime() as u64, 8);
+                put(96, meta.mtime_nsec() as u64, 8);
+                put(104, meta.ctime() as u64, 8);
+                put(112, meta.ctime_nsec() as u64, 8);
+            }
+            _ => {
+                put(16, STDIO_MODE as u64, 4);
+                put(20, 1, 4);
+                put(56, 1024, 4);
+            }
+        }
+        write_memory(cpu, statbuf, &stat).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    fn clock_gettime(&mut self, cpu: &mut Cpu, clock: RegT, tp: RegT) -> Result<RegT, i64> {
+        let time = match clock {
+            CLOCK_REALTIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            // The monotonic and cpu time clocks all count from the start of the program.
+            _ => self.started.elapsed(),
+        };
+        let mut timespec = time.as_secs().to_le_bytes().to_vec();
+        timespec.extend_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+        write_memory(cpu, tp, &timespec).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    /// Moves the end of the heap to `addr` if it lies between its start and the mappings, and
+    /// returns the end of the heap.
+    fn brk(&mut self, addr: RegT) -> RegT {
+        if (self.brk_start..=self.mmap_bottom).contains(&addr) {
+            self.brk = addr;
+        }
+        self.brk
+    }
+
+    /// Only anonymous mappings are supported. They are never unmapped, nor does the address space
+    /// hand out freed memory again.
+    fn mmap(&mut self, len: RegT, flags: RegT) -> Result<RegT, i64> {
+        if flags & MAP_ANONYMOUS == 0 {
+            return Err(ENODEV);
+        }
+        if flags & MAP_FIXED != 0 || len == 0 {
+            return Err(EINVAL);
+        }
+        let size = align_up(len).ok_or(ENOMEM)?;
+        let addr = self
+            .mmap_bottom
+            .checked_sub(size)
+            .filter(|addr| align_up(self.brk).is_some_and(|brk| brk <= *addr))
+            .ok_or(ENOMEM)?;
+        self.mmap_bottom = addr;
+        Ok(addr)
+    }
+}
+
+/// Rounds `addr` up to a page boundary, `None` if that's past the end of the address space.
+fn align_up(addr: u64) -> Option<u64> {
+    Some(addr.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+/// Converts a host error to the errno of the guest. Linux numbers them the same on every
+/// architecture.
+fn errno(e: io::Error) -> i64 {
+    e.raw_os_error().map_or(EIO, |errno| errno as i64)
+}
+
+fn read_memory(cpu: &Cpu, addr: RegT, len: RegT) -> Result<Vec<u8>, i64> {
+    (0..len)
+        .map(|i| cpu.mmu.peek::<u8>(&cpu.state, addr.wrapping_add(i)))
+        .collect::<Result<_, _>>()
+        .map_err(|_| EFAULT)
+}
+
+fn write_memory(cpu: &mut Cpu, addr: RegT, data: &[u8]) -> Result<(), Exception> {
+    for (i, byte) in data.iter().enumerate() {
+        cpu.mmu
+            .store::<u8>(&cpu.state, addr.wrapping_add(i as u64), *byte)?;
+    }
+    Ok(())
+}
+
+fn read_c_string(cpu: &Cpu, addr: RegT) -> Result<String, i64> {
+    let mut bytes = Vec::new();
+    for i in 0..PATH_MAX {
+        match cpu.mmu.peek::<u8>(&cpu.state, addr.wrapping_add(i)) {
+            Ok(0) => return String::from_utf8(bytes).map_err(|_| EINVAL),
+            Ok(byte) => bytes.push(byte),
+            Err(_) => return Err(EFAULT),
+        }
+    }
+    Err(ENAMETOOLONG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::StopReason, testing::*};
+
+    /// A static executable of `code` in a single segment at `USER_BASE`.
+    fn elf(code: &[u32]) -> Vec<u8> {
+        let entry = 64 + PHDR_SIZE;
+        let mut elf = vec![0; entry];
+        elf[..8].copy_from_slice(b"\x7fELF\x02\x01\x01\x00");
+        elf[0x10..0x12].copy_from_slice(&ET_EXEC.to_le_bytes());
+        elf[0x12..0x14].copy_from_slice(&EM_RISCV.to_le_bytes());
+        elf[0x18..0x20].copy_from_slice(&(USER_BASE + entry as u64).to_le_bytes());
+        elf[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+        elf.extend(code.iter().flat_map(|word| word.to_le_bytes()));
+        let len = elf.len() as u64;
+        elf[64..68].copy_from_slice(&PT_LOAD.to_le_bytes());
+        elf[64 + 16..64 + 24].copy_from_slice(&USER_BASE.to_le_bytes());
+        elf[64 + 32..64 + 40].copy_from_slice(&len.to_le_bytes());
+        elf[64 + 40..64 + 48].copy_from_slice(&len.to_le_bytes());
+        elf
+    }
+
+    #[test]
+    fn a_program_runs_in_user_mode_and_exits() {
+        let code = [addi(10, 0, 42), addi(17, 0, SYS_EXIT as i32), ECALL];
+        let (builder, program) = load(&elf(&code)).unwrap();
+        let mut cpu = builder.build();
+        program.start(&mut cpu, &["a.out".to_string()]).unwrap();
+        assert_eq!(cpu.state.privilege, PrivilegeMode::User);
+        // argc
+        assert_eq!(cpu.mmu.peek::<u64>(&cpu.state, cpu.state.xs.reg(2)), Ok(1));
+        let stop = (0..10).find_map(|_| cpu.one_step());
+        assert!(matches!(stop, Some(StopReason::Exit(42))));
+    }
+
+    /// Runs the program loading the AT_RANDOM bytes into a0 and a1, returns the two registers.
+    fn random(seed: Option<u64>) -> (RegT, RegT) {
+        // argc, argv[0], the ends of argv and envp, then AT_RANDOM is the sixth pair.
+        let code = [ld(5, 2, 8 * (4 + 11)), ld(10, 5, 0), ld(11, 5, 8), EBREAK];
+        let (builder, program) = load(&elf(&code)).unwrap();
+        let program = match seed {
+            Some(seed) => program.seed(seed),
+            None => program,
+        };
+        let mut cpu = builder.build();
+        program.start(&mut cpu, &["a.out".to_string()]).unwrap();
+        cpu.run_until_ebreak(100);
+        (cpu.state.xs.reg(10), cpu.state.xs.reg(11))
+    }
+
+    #[test]
+    fn the_seed_decides_the_at_random_bytes() {
+        let fixed = random(None);
+        assert_eq!(fixed, (0x5a5a_5a5a_5a5a_5a5a, 0x5a5a_5a5a_5a5a_5a5a));
+
+        let seeded = random(Some(1));
+        assert_eq!(random(Some(1)), seeded);
+        assert_ne!(seeded, fixed);
+        assert_ne!(random(Some(2)), seeded);
+    }
+
+    /// Starts a program of nothing but ECALLs, for `syscall`.
+    fn ecalls() -> Cpu {
+        let (builder, program) = load(&elf(&[ECALL; 16])).unwrap();
+        let mut cpu = builder.build();
+        program.start(&mut cpu, &["a.out".to_string()]).unwrap();
+        cpu
+    }
+
+    /// Makes syscall `nr` with `args` and returns its result, negative errnos included.
+    fn syscall(cpu: &mut Cpu, nr: RegT, args: &[RegT]) -> i64 {
+        cpu.state.xs.set_reg(17, nr);
+        for (i, arg) in args.iter().enumerate() {
+            cpu.state.xs.set_reg(10 + i as u8, *arg);
+        }
+        assert!(cpu.one_step().is_none());
+        cpu.state.xs.reg(10) as i64
+    }
+
+    #[test]
+    fn brk_and_mmap_share_the_address_space() {
+        let mut cpu = ecalls();
+        let start = syscall(&mut cpu, SYS_BRK, &[0]) as u64;
+        assert!(start > USER_BASE);
+        assert_eq!(
+            syscall(&mut cpu, SYS_BRK, &[start + 0x3000]),
+            (start + 0x3000) as i64
+        );
+        // The heap can't shrink below its start.
+        assert_eq!(
+            syscall(&mut cpu, SYS_BRK, &[start - 8]),
+            (start + 0x3000) as i64
+        );
+
+        let anonymous = MAP_ANONYMOUS | 0x2;
+        let first = syscall(&mut cpu, SYS_MMAP, &[0, 0x1800, 3, anonymous]) as u64;
+        assert_eq!(first % PAGE_SIZE, 0);
+        let second = syscall(&mut cpu, SYS_MMAP, &[0, 0x1000, 3, anonymous]) as u64;
+        assert_eq!(second, first - 0x1000);
+        // The heap can't grow into the mappings.
+        assert_eq!(
+            syscall(&mut cpu, SYS_BRK, &[second + 8]),
+            (start + 0x3000) as i64
+        );
+        assert_eq!(syscall(&mut cpu, SYS_MMAP, &[0, 0x1000, 3, 0x2]), -ENODEV);
+        let fixed = anonymous | MAP_FIXED;
+        assert_eq!(syscall(&mut cpu, SYS_MMAP, &[0, 0x1000, 3, fixed]), -EINVAL);
+        assert_eq!(
+            syscall(&mut cpu, SYS_MMAP, &[0, 1 << 40, 3, anonymous]),
+            -ENOMEM
+        );
+        // Rounding the length up to a page would overflow.
+        assert_eq!(
+            syscall(&mut cpu, SYS_MMAP, &[0, RegT::MAX - 8, 3, anonymous]),
+            -ENOMEM
+        );
+        assert_eq!(syscall(&mut cpu, 1000, &[]), -ENOSYS);
+    }
+
+    #[test]
+    fn files_are_opened_on_the_host() {
+        let path = std::env::temp_dir().join(format!("linux-syscalls-{}", std::process::id()));
+        let mut cpu = ecalls();
+        let buf = cpu.state.xs.reg(2) - 0x1000;
+        let mut c_path = path.to_str().unwrap().as_bytes().to_vec();
+        c_path.push(0);
+        write_memory(&mut cpu, buf, &c_path).unwrap();
+        write_memory(&mut cpu, buf + 0x100, b"hello").unwrap();
+
+        let flags = O_RDWR | O_CREAT | O_TRUNC;
+        let open = [AT_FDCWD as RegT, buf, flags, 0o600];
+        assert_eq!(syscall(&mut cpu, SYS_OPENAT, &open), 3);
+        assert_eq!(syscall(&mut cpu, SYS_WRITE, &[3, buf + 0x100, 5]), 5);
+        assert_eq!(syscall(&mut cpu, SYS_LSEEK, &[3, 1, 0]), 1);
+        assert_eq!(syscall(&mut cpu, SYS_READ, &[3, buf + 0x200, 16]), 4);
+        assert_eq!(read_memory(&cpu, buf + 0x200, 4), Ok(b"ello".to_vec()));
+        // A count far beyond the file, or the memory, reads what there is.
+        assert_eq!(syscall(&mut cpu, SYS_LSEEK, &[3, 2, 0]), 2);
+        assert_eq!(syscall(&mut cpu, SYS_READ, &[3, buf + 0x200, RegT::MAX]), 3);
+        assert_eq!(read_memory(&cpu, buf + 0x200, 3), Ok(b"llo".to_vec()));
+        assert_eq!(syscall(&mut cpu, SYS_LSEEK, &[3, 0, 0]), 0);
+        assert_eq!(syscall(&mut cpu, SYS_READ, &[3, 0, RegT::MAX]), -EFAULT);
+        assert_eq!(syscall(&mut cpu, SYS_FSTAT, &[3, buf + 0x300]), 0);
+        assert_eq!(cpu.mmu.peek::<u64>(&cpu.state, buf + 0x300 + 48), Ok(5));
+        assert_eq!(syscall(&mut cpu, SYS_CLOSE, &[3]), 0);
+        assert_eq!(syscall(&mut cpu, SYS_CLOSE, &[3]), -EBADF);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+
+        // O_EXCL refuses the existing file with EEXIST, relative paths need AT_FDCWD.
+        let open = [AT_FDCWD as RegT, buf, flags | O_EXCL, 0o600];
+        assert_eq!(syscall(&mut cpu, SYS_OPENAT, &open), -17);
+        std::fs::remove_file(&path).unwrap();
+        write_memory(&mut cpu, buf, b"relative\0").unwrap();
+        assert_eq!(syscall(&mut cpu, SYS_OPENAT, &[5, buf, 0, 0]), -EINVAL);
+        assert_eq!(syscall(&mut cpu, SYS_LSEEK, &[1, 0, 0]), -ESPIPE);
+        assert_eq!(syscall(&mut cpu, SYS_WRITE, &[0, buf, 1]), -EBADF);
+    }
+}
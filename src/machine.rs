@@ -0,0 +1,492 @@
+//! A machine of one or more harts sharing a bus. The harts are stepped in turn, each running a
+//! slice of instructions before the next one gets its turn.
+
+use std::{
+    cell::Ref,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cpu::{Cpu, CpuBuilder, CpuStatus, StepOutcome, StopReason},
+    device::{bus::Bus, syscon::PowerRequest},
+};
+
+/// The number of steps a hart runs before the next hart's turn.
+const TIME_SLICE: u64 = 100;
+
+/// The number of steps between looks at the clock for `Machine::set_timeout`, few enough to stop
+/// soon after the deadline and many enough not to slow the machine down.
+const CLOCK_CHECK_STEPS: u64 = 4096;
+
+pub struct Machine {
+    /// Indexed by hart id.
+    harts: Vec<Cpu>,
+    /// The hart taking the next step.
+    current: usize,
+    /// Steps the current hart took in its slice.
+    slice_steps: u64,
+    /// A snapshot of the bus as created, with the binary loaded, restored by `reset`.
+    initial_bus: Vec<u8>,
+    /// The state each hart was created in, indexed by hart id.
+    initial_states: Vec<CpuStatus>,
+    /// The steps left before the machine stops, `None` for no limit.
+    fuel: Option<u64>,
+    /// The time the machine stops at, `None` for no limit.
+    deadline: Option<Instant>,
+    /// Steps taken since the clock was last looked at.
+    unchecked_steps: u64,
+}
+
+impl Machine {
+    /// Creates `harts` harts configured by `builder`, all of them sharing one bus.
+    pub fn new(builder: CpuBuilder, harts: usize) -> Self {
+        assert!(harts > 0, "a machine needs at least one hart");
+        let harts = builder.build_harts(harts);
+        let mut initial_bus = Vec::new();
+        harts[0]
+            .mmu
+            .bus
+            .borrow()
+            .save(&mut initial_bus)
+            .expect("writing to a Vec can't fail");
+        let initial_states = harts.iter().map(|hart| hart.state.clone()).collect();
+        Self {
+            harts,
+            current: 0,
+            slice_steps: 0,
+            initial_bus,
+            initial_states,
+            fuel: None,
+            deadline: None,
+            unchecked_steps: 0,
+        }
+    }
+
+    /// Stops the machine with `StopReason::FuelExhausted` after `fuel` more steps of all harts
+    /// together, counting idle steps waiting for an interrupt too. `None` removes the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Stops the machine with `StopReason::Timeout` once `timeout` of wall clock time passed from
+    /// now. The clock is only looked at every few thousand steps. `None` removes the limit.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.unchecked_steps = 0;
+    }
+
+    pub fn hart(&self, id: usize) -> &Cpu {
+        &self.harts[id]
+    }
+
+    pub fn hart_mut(&mut self, id: usize) -> &mut Cpu {
+        &mut self.harts[id]
+    }
+
+    pub fn bus(&self) -> Ref<'_, Bus> {
+        self.harts[0].mmu.bus.borrow()
+    }
+
+    /// Executes up to `budget` instructions of a basic block on the current hart, see
+    /// `Cpu::run_block`, moving on to the next hart once its slice is used up or it executed an
+    /// instruction with release semantics, see `Cpu::take_release`. A block doesn't run past the
+    /// end of the slice.
+    /// Returns the steps taken and the reason if the machine should stop afterwards.
+    pub fn run_block(&mut self, budget: u64) -> (u64, Option<StopReason>) {
+        self.run_current(budget, Cpu::run_block)
+    }
+
+    /// Executes exactly the instruction at pc of the current hart for the monitor's step command,
+    /// see `Cpu::step_one`. Unlike `run_block(1)`, pending interrupts are left for the next step.
+    pub fn step_one(&mut self) -> (u64, Option<StopReason>) {
+        self.run_current(1, |hart, _| match hart.step_one() {
+            StepOutcome::Stopped(stop) => (1, Some(stop)),
+            StepOutcome::Retired | StepOutcome::Trapped(_) | StepOutcome::Waiting => (1, None),
+        })
+    }
+
+    /// Runs the current hart for up to `budget` steps with `run`, carrying out resets, counting
+    /// the steps against the limits and moving on to the next hart at the end of the slice.
+    fn run_current(
+        &mut self,
+        budget: u64,
+        run: impl FnOnce(&mut Cpu, u64) -> (u64, Option<StopReason>),
+    ) -> (u64, Option<StopReason>) {
+        // A reset requested from outside the guest, see `Cpu::request_reset`.
+        if self.bus().power.get() == Some(PowerRequest::Reset) {
+            self.reset();
+            return (0, None);
+        }
+        if self.fuel == Some(0) {
+            let report = self.harts[self.current].state_report();
+            return (0, Some(StopReason::FuelExhausted(Box::new(report))));
+        }
+        let budget = budget
+            .min(TIME_SLICE - self.slice_steps)
+            .min(self.fuel.unwrap_or(u64::MAX));
+        let (steps, mut stop) = run(&mut self.harts[self.current], budget);
+        if let Some(StopReason::Reset) = stop {
+            self.reset();
+            return (steps, None);
+        }
+        if stop.is_none() {
+            stop = self.check_limits(self.current, steps);
+        }
+        let hart = &mut self.harts[self.current];
+        self.slice_steps += steps;
+        if hart.take_release() || self.slice_steps == TIME_SLICE {
+            self.slice_steps = 0;
+            self.current = (self.current + 1) % self.harts.len();
+        }
+        (steps, stop)
+    }
+
+    /// Takes the `steps` hart `id` just took off the fuel and stops the machine at the state of
+    /// the hart if the fuel ran out or the deadline passed.
+    fn check_limits(&mut self, id: usize, steps: u64) -> Option<StopReason> {
+        let fuel = self.fuel.as_mut().map(|fuel| {
+            *fuel = fuel.saturating_sub(steps);
+            *fuel
+        });
+        if fuel == Some(0) {
+            let report = self.harts[id].state_report();
+            return Some(StopReason::FuelExhausted(Box::new(report)));
+        }
+        let deadline = self.deadline?;
+        self.unchecked_steps += steps;
+        if self.unchecked_steps < CLOCK_CHECK_STEPS {
+            return None;
+        }
+        self.unchecked_steps = 0;
+        if Instant::now() < deadline {
+            return None;
+        }
+        let report = self.harts[id].state_report();
+        Some(StopReason::Timeout(Box::new(report)))
+    }
+
+    /// Resets the machine to the state it was created in, like a reboot: the memory holds the
+    /// loaded binary again, the devices and the harts start over. Disk contents written by the
+    /// guest are kept, like on a real disk. Hooks, watchpoints and host side resources such as the
+    /// console stay attached.
+    pub fn reset(&mut self) {
+        {
+            let mut bus = self.harts[0].mmu.bus.borrow_mut();
+            bus.restore(&mut &self.initial_bus[..])
+                .expect("the initial snapshot matches the bus");
+            bus.power.set(None);
+        }
+        for (hart, state) in self.harts.iter_mut().zip(self.initial_states.iter()) {
+            hart.reset(state.clone());
+        }
+        self.current = 0;
+        self.slice_steps = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{
+        device::{CLINT_BASE, DRAM_BASE, SYSCON_BASE},
+        testing::*,
+        RegT,
+    };
+
+    const MSIP: RegT = 1 << 3;
+    const MTIP: RegT = 1 << 7;
+
+    /// Starts every hart at `program`, past the reset vector.
+    fn machine(program: &[u32], harts: usize) -> Machine {
+        let binary = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let builder = CpuBuilder::new(binary)
+            .entry(DRAM_BASE)
+            .console(Box::new(io::sink()));
+        Machine::new(builder, harts)
+    }
+
+    #[test]
+    fn harts_have_their_own_ids_and_clint_registers() {
+        let mut machine = machine(&[NOP, NOP, EBREAK], 2);
+        assert_eq!(machine.hart(0).state.csrs.csr(0xf14), 0);
+        assert_eq!(machine.hart(1).state.csrs.csr(0xf14), 1);
+        // msip of hart 1, and an mtimecmp only hart 1 has reached.
+        {
+            let mut bus = machine.hart(0).mmu.bus.borrow_mut();
+            bus.write::<u32>(CLINT_BASE + 4, 1).unwrap();
+            bus.write::<u64>(CLINT_BASE + 0x4000, u64::MAX).unwrap();
+            bus.write::<u64>(CLINT_BASE + 0x4008, 0).unwrap();
+        }
+        for hart in 0..2 {
+            assert!(machine.hart_mut(hart).one_step().is_none());
+        }
+        let mip = |hart| machine.hart(hart).state.csrs.csr(0x344) & (MSIP | MTIP);
+        assert_eq!(mip(0), 0);
+        assert_eq!(mip(1), MSIP | MTIP);
+    }
+
+    #[test]
+    fn msip_interrupts_the_other_hart() {
+        let mut program = vec![
+            csrrs(10, 0xf14, 0),
+            lui(5, (CLINT_BASE >> 12) as u32),
+            b_type(16, 0, 10, 1),
+            // hart 0 raises msip of hart 1.
+            addi(6, 0, 1),
+            sw(6, 5, 4),
+            EBREAK,
+            // hart 1 waits for it.
+            WFI,
+            jal(0, -4),
+        ];
+        program.resize(0x40, NOP);
+        // The trap handler.
+        program.extend([csrrs(12, 0xf14, 0), csrrs(13, 0x342, 0), EBREAK]);
+        let mut machine = machine(&program, 2);
+        for hart in 0..2 {
+            let csrs = &mut machine.hart_mut(hart).state.csrs;
+            csrs.set_mtvec(DRAM_BASE + 0x100);
+            csrs.set_mstatus(1 << 3);
+        }
+        machine.hart_mut(1).state.csrs.set_mie(MSIP);
+
+        for _ in 0..10 {
+            assert!(machine.hart_mut(1).one_step().is_none());
+        }
+        assert!(machine.hart(1).waiting_for_interrupt);
+        machine.hart_mut(0).run_until_ebreak(10);
+        assert_eq!(machine.hart(0).state.pc, DRAM_BASE + 0x14);
+
+        let hart = machine.hart_mut(1);
+        hart.run_until_ebreak(10);
+        assert_eq!(hart.state.pc, DRAM_BASE + 0x108);
+        assert_eq!(hart.state.xs.reg(12), 1);
+        assert_eq!(hart.state.xs.reg(13), 1 << 63 | 3);
+        assert_eq!(machine.hart(0).state.csrs.csr(0x344) & MSIP, 0);
+    }
+
+    #[test]
+    fn a_store_from_another_hart_breaks_the_reservation() {
+        let program = [
+            csrrs(10, 0xf14, 0),
+            auipc(11, 0),
+            addi(11, 11, 0x1fc),
+            b_type(12, 0, 10, 1),
+            // hart 0
+            sw(0, 11, 0),
+            EBREAK,
+            // hart 1
+            amo(0b00010, 2, 12, 11, 0),
+            amo(0b00011, 2, 13, 11, 12),
+            EBREAK,
+        ];
+        for &store in [false, true].iter() {
+            let mut machine = machine(&program, 2);
+            // Up to the lr.w of hart 1.
+            for _ in 0..5 {
+                assert!(machine.hart_mut(1).one_step().is_none());
+            }
+            if store {
+                machine.hart_mut(0).run_until_ebreak(10);
+            }
+            let hart = machine.hart_mut(1);
+            hart.run_until_ebreak(10);
+            // sc.w writes 0 on success.
+            assert_eq!(hart.state.xs.reg(13), store as RegT, "store {}", store);
+        }
+    }
+
+    #[test]
+    fn a_release_ends_the_slice() {
+        const RL: u32 = 1 << 25;
+        const AQ: u32 = 1 << 26;
+        for &(ordering, released) in [(0, false), (AQ, false), (RL, true), (AQ | RL, true)].iter() {
+            let program = [
+                auipc(11, 0),
+                addi(11, 11, 0x100),
+                amo(0b00001, 2, 0, 11, 0) | ordering,
+                addi(10, 10, 1),
+                jal(0, -4),
+            ];
+            let mut machine = machine(&program, 2);
+            let (steps, stop) = machine.run_block(1000);
+            assert!(stop.is_none());
+            assert!(steps < TIME_SLICE);
+            assert_eq!(
+                machine.current, released as usize,
+                "ordering {:#x}",
+                ordering
+            );
+        }
+    }
+
+    #[test]
+    fn a_fence_ordering_writes_ends_the_slice() {
+        // fence rw,rw, fence r,rw, fence.tso and fence w,0, which is PAUSE.
+        let cases = [
+            (0x0330_000f, true),
+            (0x0230_000f, false),
+            (0x8330_000f, true),
+            (0x0100_000f, false),
+        ];
+        for &(fence, released) in cases.iter() {
+            let program = [addi(10, 10, 1), fence, jal(0, -8)];
+            let mut machine = machine(&program, 2);
+            let (steps, stop) = machine.run_block(1000);
+            assert!(stop.is_none());
+            assert!(steps < TIME_SLICE);
+            assert_eq!(machine.current, released as usize, "fence {:#010x}", fence);
+        }
+    }
+
+    #[test]
+    fn step_one_leaves_a_pending_interrupt_for_the_next_step() {
+        let mut program = vec![addi(10, 0, 1), addi(10, 10, 1)];
+        program.resize(0x40, NOP);
+        program.push(EBREAK);
+        let mut machine = machine(&program, 1);
+        let csrs = &mut machine.hart_mut(0).state.csrs;
+        csrs.set_mtvec(DRAM_BASE + 0x100);
+        csrs.set_mstatus(1 << 3);
+        csrs.set_mie(MSIP);
+        let bus = &machine.hart(0).mmu.bus;
+        bus.borrow_mut().write::<u32>(CLINT_BASE, 1).unwrap();
+
+        let (steps, stop) = machine.step_one();
+        assert!(stop.is_none());
+        assert_eq!(steps, 1);
+        assert_eq!(machine.hart(0).state.pc, DRAM_BASE + 4);
+        assert_eq!(machine.hart(0).state.xs.reg(10), 1);
+        // The next step of the run loop takes it.
+        machine.run_block(1);
+        assert_eq!(machine.hart(0).state.pc, DRAM_BASE + 0x100);
+        assert_eq!(machine.hart(0).state.xs.reg(10), 1);
+    }
+
+    #[test]
+    fn a_reboot_restores_the_image() {
+        const COUNTER: usize = 0x100;
+        // Increments the counter of the image, then asks the syscon for a reset.
+        let mut program = vec![
+            auipc(11, 0),
+            lw(12, 11, COUNTER as i32),
+            addi(12, 12, 1),
+            sw(12, 11, COUNTER as i32),
+            lui(13, (SYSCON_BASE >> 12) as u32),
+            lui(14, 0x7),
+            addi(14, 14, 0x777),
+            sw(14, 13, 0),
+            EBREAK,
+        ];
+        program.resize(COUNTER / 4, NOP);
+        program.push(5);
+        let mut machine = machine(&program, 2);
+        let counter = |machine: &Machine| {
+            machine
+                .bus()
+                .read::<u32>(DRAM_BASE + COUNTER as u64)
+                .unwrap()
+        };
+        machine.hart_mut(1).state.xs.set_reg(10, 42);
+        let step = |machine: &mut Machine| {
+            let (steps, stop) = machine.run_block(1);
+            assert!(stop.is_none());
+            assert_eq!(steps, 1);
+        };
+        for _ in 0..7 {
+            step(&mut machine);
+        }
+        assert_eq!(counter(&machine), 6);
+        // The syscon write resets the machine instead of reaching the ebreak.
+        step(&mut machine);
+        assert_eq!(counter(&machine), 5);
+        assert_eq!(machine.current, 0);
+        assert_eq!(machine.hart(0).state.pc, DRAM_BASE);
+        assert_eq!(machine.hart(1).state.xs.reg(10), 0);
+        // The guest boots again from the image value.
+        for _ in 0..4 {
+            step(&mut machine);
+        }
+        assert_eq!(counter(&machine), 6);
+    }
+
+    #[test]
+    fn the_monitor_can_reset_the_machine() {
+        let mut machine = machine(&[addi(10, 10, 1), jal(0, -4)], 1);
+        machine.run_block(10);
+        assert_ne!(machine.hart(0).state.xs.reg(10), 0);
+        machine.hart(0).request_reset();
+        let (steps, stop) = machine.run_block(10);
+        assert!(stop.is_none());
+        assert_eq!(steps, 0);
+        assert_eq!(machine.hart(0).state.xs.reg(10), 0);
+        assert_eq!(machine.hart(0).state.pc, DRAM_BASE);
+    }
+
+    #[test]
+    fn harts_take_turns_in_slices() {
+        // Counts the loop iterations in a0, one per two steps.
+        let mut machine = machine(&[addi(10, 10, 1), jal(0, -4)], 3);
+        let mut total = 0;
+        while total < 2 * TIME_SLICE + TIME_SLICE / 2 {
+            let (steps, stop) = machine.run_block(1000);
+            assert!(stop.is_none());
+            total += steps;
+        }
+        let counts: Vec<_> = (0..3)
+            .map(|hart| machine.hart(hart).state.xs.reg(10))
+            .collect();
+        let slice = TIME_SLICE / 2;
+        assert_eq!(counts, [slice, slice, slice / 2]);
+        // The next steps are still hart 2's.
+        machine.run_block(1000);
+        assert_eq!(machine.hart(0).state.xs.reg(10), slice);
+        assert!(machine.hart(2).state.xs.reg(10) > slice / 2);
+    }
+
+    #[test]
+    fn a_hanging_guest_runs_out_of_fuel() {
+        // Counts the loop iterations in a0, one per two steps after the NOP.
+        let mut machine = machine(&[NOP, addi(10, 10, 1), jal(0, -4)], 1);
+        machine.set_fuel(Some(1001));
+        let mut total = 0;
+        let report = loop {
+            let (steps, stop) = machine.run_block(u64::MAX);
+            total += steps;
+            match stop {
+                None => {}
+                Some(StopReason::FuelExhausted(report)) => break report,
+                _ => panic!("stopped for another reason"),
+            }
+        };
+        assert_eq!(total, 1001);
+        assert_eq!(report.pc, DRAM_BASE + 4);
+        assert_eq!(report.xs.reg(10), 500);
+        assert_eq!(report.history.last().unwrap().0, DRAM_BASE + 8);
+        // The machine stays stopped.
+        let (steps, stop) = machine.run_block(1);
+        assert_eq!(steps, 0);
+        assert!(matches!(stop, Some(StopReason::FuelExhausted(_))));
+    }
+
+    #[test]
+    fn a_hanging_guest_times_out() {
+        let mut machine = machine(&[jal(0, 0)], 1);
+        machine.set_timeout(Some(Duration::ZERO));
+        let mut total = 0;
+        let report = loop {
+            let (steps, stop) = machine.run_block(u64::MAX);
+            total += steps;
+            match stop {
+                None => {}
+                Some(StopReason::Timeout(report)) => break report,
+                _ => panic!("stopped for another reason"),
+            }
+        };
+        // The clock is only looked at every CLOCK_CHECK_STEPS steps.
+        assert_eq!(total, CLOCK_CHECK_STEPS);
+        assert_eq!(report.pc, DRAM_BASE);
+    }
+}
@@ -1,85 +1,695 @@
-#![feature(const_generics, const_evaluatable_checked)]
-#![allow(incomplete_features)]
-
 use std::{
     env,
-    fs::File,
-    io::{self, Read},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use cpu::Cpu;
-use trap::Exception;
+use log::info;
+#[cfg(feature = "framebuffer")]
+use riscv_emulator::framebuffer::{Framebuffer, PpmDumper};
+use riscv_emulator::{
+    config::MachineConfig,
+    console::{self, ConsoleFeed, ConsoleLog, ConsoleOutput, StdinConsole},
+    cosim,
+    cpu::{self, CpuBuilder, CsrPolicy, HangDetection, SpinDetection, StopReason},
+    hook, instruction_table, linux,
+    machine::Machine,
+    monitor::{self, Monitor},
+    signature::Signature,
+    watchpoint::WatchKind,
+    XLen,
+};
 
-mod cpu;
-mod device;
-mod isa;
-mod mmu;
-mod page;
-mod register;
-mod trap;
+const USAGE: &str =
+    "Usage: riscv-emulator [--machine <manifest>] [--xlen 32|64] [--harts <n>] \
+                     [--restore-snapshot <file>] \
+                     [--save-snapshot <file> --save-after <steps>] \
+                     [--monitor <socket> [--checkpoint-every <millions>]] \
+                     [--symbols <elf>]... [--trace <file>] [--trace-csr <file>] \
+                     [--coverage-out <file>] \
+                     [--profile-out <file> [--profile-period <n>] [--profile-depth <frames>]] \
+                     [--deterministic [--record-console <script>] | --replay-console <script> | \
+                     --record-console <script> | \
+                     [--raw-console] [--escape-char <letter>]] \
+                     [--serial <file>] [--console-uart 0|1] [--console-log <file>] \
+                     [--dram <base>:<size>] [--memory <base>:<size>]... [--start-pc <addr>] \
+                     [--framebuffer <width>x<height> [--frame-dir <dir>] [--frame-every <millions>]] \
+                     [--cosim <commit log> | --cosim-spike <isa>] [--semihosting] [--builtin-sbi] [--strict-csrs] [--max-steps <n>] \
+                     [--max-insns <n>] [--timeout <seconds>] \
+                     [--decode-cache <entries>] [--no-block-cache] [--spin-nops <n>] [--stats] \
+                     [--detect-hangs default|<same-pc[=n],low-pc,traps[=n]>] \
+                     [--dump-state-on-exit <file>] \
+                     [--signature <file> [--signature-granularity <bytes>] \
+                     [--signature-range <begin>:<end>]] \
+                     <filename> [image] | --user [--seed <n>] <elf> [args]... | --list-insns";
 
-#[macro_use]
-extern crate macros;
+/// The exit code of a run stopped by --max-insns or --timeout, the code timeout(1) uses.
+const LIMIT_EXIT_CODE: i32 = 124;
 
-pub type RegT = u64;
-pub type SRegT = i64;
+/// The steps in a row a cosim run lets the hart take without retiring an instruction before it
+/// reports the hart stuck, e.g. in WFI, where the reference goes on.
+const COSIM_MAX_IDLE: u64 = 1_000_000;
 
-#[derive(Debug, PartialEq, PartialOrd, Eq, Copy, Clone)]
-pub enum PrivilegeMode {
-    User = 0,
-    Supervisor = 1,
-    Machine = 2,
-}
-
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-pub enum XLen {
-    X32 = 32,
-    X64 = 64,
-}
+fn main() -> io::Result<()> {
+    // Diagnostics go to stderr, e.g. RUST_LOG=riscv_emulator::cpu=trace. The guest console only
+    // goes through the UART.
+    env_logger::init();
+    let mut args = env::args().skip(1);
+    let mut files = Vec::new();
+    let mut restore_snapshot = None;
+    let mut save_snapshot = None;
+    let mut save_after = None;
+    let mut monitor = None;
+    let mut symbol_files = Vec::new();
+    let mut trace = None;
+    let mut trace_csr = None;
+    let mut coverage_out = None;
+    let mut profile_out = None;
+    let mut profile_period = 10_000;
+    let mut profile_depth = 0;
+    let mut stats = false;
+    let mut dump_state = None;
+    let mut deterministic = false;
+    let mut spin_nops = None;
+    let mut hang_detection = None;
+    let mut csr_policy = CsrPolicy::Permissive;
+    let mut console_input = None;
+    let mut record_input = None;
+    let mut raw_console = false;
+    let mut serial_out = None;
+    let mut console_uart = None;
+    let mut console_log = None;
+    let mut escape = console::DEFAULT_ESCAPE;
+    let mut cosim_log = None;
+    let mut cosim_spike = None;
+    let mut machine_config = None;
+    let mut xlen = None;
+    let mut harts = None;
+    let mut semihosting = false;
+    let mut builtin_sbi = false;
+    let mut user = false;
+    let mut seed = None;
+    let mut max_steps = None;
+    let mut max_insns = None;
+    let mut start_pc = None;
+    let mut timeout = None;
+    let mut signature_out = None;
+    let mut signature_granularity = 4;
+    let mut signature_range = None;
+    let mut list_insns = false;
+    let mut decode_cache = cpu::DEFAULT_DECODE_CACHE_CAPACITY;
+    let mut block_cache = true;
+    let mut dram = None;
+    let mut memories = Vec::new();
+    let mut framebuffer = None;
+    let mut frame_dir = None;
+    let mut frame_every = None;
+    let mut checkpoint_every = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--machine" => {
+                machine_config = Some(MachineConfig::load(args.next().expect(USAGE))?);
+            }
+            "--xlen" => {
+                xlen = match args.next().expect(USAGE).as_str() {
+                    "32" => Some(XLen::X32),
+                    "64" => Some(XLen::X64),
+                    _ => panic!("xlen must be 32 or 64"),
+                }
+            }
+            "--harts" => {
+                let n = args.next().expect(USAGE);
+                harts = Some(n.parse::<usize>().expect("harts must be a number"));
+            }
+            "--restore-snapshot" => restore_snapshot = Some(args.next().expect(USAGE)),
+            "--save-snapshot" => save_snapshot = Some(args.next().expect(USAGE)),
+            "--save-after" => {
+                let steps = args.next().expect(USAGE);
+                save_after = Some(steps.parse::<u64>().expect("steps must be a number"));
+            }
+            "--cosim" => cosim_log = Some(args.next().expect(USAGE)),
+            "--cosim-spike" => cosim_spike = Some(args.next().expect(USAGE)),
+            "--deterministic" => deterministic = true,
+            "--semihosting" => semihosting = true,
+            "--builtin-sbi" => builtin_sbi = true,
+            "--strict-csrs" => csr_policy = CsrPolicy::Strict,
+            "--user" => user = true,
+            "--seed" => {
+                let n = args.next().expect(USAGE);
+                seed = Some(n.parse::<u64>().expect("the seed must be a number"));
+            }
+            "--no-block-cache" => block_cache = false,
+            "--spin-nops" => {
+                let n = args.next().expect(USAGE);
+                spin_nops = Some(n.parse::<u64>().expect("--spin-nops takes a number"));
+            }
+            "--detect-hangs" => {
+                hang_detection = Some(parse_hang_detection(&args.next().expect(USAGE)));
+            }
+            "--list-insns" => list_insns = true,
+            "--max-steps" => {
+                let steps = args.next().expect(USAGE);
+                max_steps = Some(steps.parse::<u64>().expect("steps must be a number"));
+            }
+            "--max-insns" => {
+                let n = args.next().expect(USAGE);
+                max_insns = Some(n.parse::<u64>().expect("--max-insns takes a number"));
+            }
+            "--timeout" => {
+                let seconds = args.next().expect(USAGE);
+                let seconds = seconds.parse::<f64>().expect("--timeout takes seconds");
+                timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "--decode-cache" => {
+                let entries = args.next().expect(USAGE);
+                decode_cache = entries.parse::<usize>().expect("entries must be a number");
+            }
+            "--signature" => signature_out = Some(args.next().expect(USAGE)),
+            "--signature-granularity" => {
+                let bytes = args.next().expect(USAGE);
+                signature_granularity = bytes.parse::<usize>().expect("bytes must be a number");
+            }
+            "--signature-range" => {
+                let range = args.next().expect(USAGE);
+                let (begin, end) = range.split_once(':').expect(USAGE);
+                let addr = |s| monitor::parse_number(s).expect("addresses must be numbers");
+                signature_range = Some((addr(begin), addr(end)));
+            }
+            // A replayed console only makes sense in a deterministic run.
+            "--replay-console" | "--console-input" => {
+                deterministic = true;
+                console_input = Some(args.next().expect(USAGE));
+            }
+            "--record-console" | "--record-input" => record_input = Some(args.next().expect(USAGE)),
+            "--raw-console" => raw_console = true,
+            "--escape-char" => {
+                let arg = args.next().expect(USAGE);
+                escape = console::parse_escape(&arg).expect("--escape-char takes a letter");
+            }
+            "--dram" => dram = Some(parse_region(&args.next().expect(USAGE))),
+            "--memory" => memories.push(parse_region(&args.next().expect(USAGE))),
+            "--framebuffer" => framebuffer = Some(parse_resolution(&args.next().expect(USAGE))),
+            "--frame-dir" => frame_dir = Some(PathBuf::from(args.next().expect(USAGE))),
+            "--frame-every" => {
+                let n = args.next().expect(USAGE);
+                let millions = n.parse::<u64>().expect("--frame-every takes a number");
+                frame_every = Some(millions.max(1) * 1_000_000);
+            }
+            "--start-pc" => {
+                let addr = args.next().expect(USAGE);
+                start_pc = Some(monitor::parse_number(&addr).expect("--start-pc takes an address"));
+            }
+            "--serial" => serial_out = Some(PathBuf::from(args.next().expect(USAGE))),
+            "--console-log" => console_log = Some(args.next().expect(USAGE)),
+            "--console-uart" => {
+                let uart = args.next().expect(USAGE).parse().expect(USAGE);
+                assert!(uart < 2, "{}", USAGE);
+                console_uart = Some(uart);
+            }
+            "--coverage-out" => coverage_out = Some(args.next().expect(USAGE)),
+            "--profile-out" => profile_out = Some(args.next().expect(USAGE)),
+            "--profile-period" => {
+                let n = args.next().expect(USAGE);
+                profile_period = n.parse::<u64>().expect("--profile-period takes a number");
+            }
+            "--profile-depth" => {
+                let n = args.next().expect(USAGE);
+                profile_depth = n.parse::<usize>().expect("--profile-depth takes a number");
+            }
+            "--stats" => stats = true,
+            "--dump-state-on-exit" => dump_state = Some(args.next().expect(USAGE)),
+            "--trace" => trace = Some(args.next().expect(USAGE)),
+            "--trace-csr" => trace_csr = Some(args.next().expect(USAGE)),
+            "--symbols" => symbol_files.push(args.next().expect(USAGE)),
+            "--monitor" => monitor = Some(Monitor::bind(args.next().expect(USAGE))?),
+            "--checkpoint-every" => {
+                let n = args.next().expect(USAGE);
+                let millions = n.parse::<u64>().expect("--checkpoint-every takes a number");
+                checkpoint_every = Some(millions.max(1) * 1_000_000);
+            }
+            _ => {
+                files.push(arg);
+                // Everything after the program are its arguments.
+                if user {
+                    files.extend(args.by_ref());
+                }
+            }
+        }
+    }
+    if list_insns {
+        return print_instruction_table(&mut io::stdout());
+    }
+    // A user-mode program gets the machine laid out by the loader.
+    if user && machine_config.is_some() {
+        panic!("--machine doesn't apply to --user");
+    }
+    // Flags given on the command line override the manifest.
+    let config = machine_config.unwrap_or_default();
+    let xlen = xlen.or(config.xlen).unwrap_or(XLen::X64);
+    let harts = harts.or(config.harts).unwrap_or(1);
+    let console_uart = console_uart.or(config.console_uart).unwrap_or(0);
+    let serial_out = serial_out.or_else(|| config.serial.clone());
+    let dram = dram.or(config.dram);
+    let start_pc = start_pc.or(config.start_pc);
+    if memories.is_empty() {
+        memories = config.regions.clone();
+    }
+    if files.is_empty() {
+        files.extend(
+            config
+                .kernel
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned()),
+        );
+    }
+    if files.len() == 1 {
+        files.extend(
+            config
+                .disk
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned()),
+        );
+    }
+    if files.is_empty()
+        || (files.len() > 2 && !user)
+        || save_snapshot.is_some() != save_after.is_some()
+        || (record_input.is_some() && console_input.is_some())
+        || (raw_console && (deterministic || record_input.is_some() || user))
+        || (cosim_log.is_some() && cosim_spike.is_some())
+    {
+        panic!("{}", USAGE);
+    }
+    let cosim = cosim_log.is_some() || cosim_spike.is_some();
+    // Snapshots, the monitor, cosim and user-mode emulation only know about a single hart.
+    if harts == 0
+        || (harts > 1
+            && (restore_snapshot.is_some()
+                || save_snapshot.is_some()
+                || monitor.is_some()
+                || cosim
+                || user))
+    {
+        panic!("--harts must be 1 with snapshots, the monitor, cosim or --user");
+    }
+    // The monitor pauses a hanging guest itself, and a cosim run ends with its log.
+    if (max_insns.is_some() || timeout.is_some()) && (monitor.is_some() || cosim) {
+        panic!("--max-insns and --timeout don't apply to the monitor or cosim");
+    }
+    if user && (xlen != XLen::X64 || console_input.is_some() || record_input.is_some()) {
+        panic!("--user only runs RV64 programs, which read the host stdin directly");
+    }
+    // Going backwards replays the steps since a checkpoint, which only repeat the recorded run
+    // without input from the host.
+    if let Some(every) = checkpoint_every {
+        match monitor.as_mut() {
+            Some(monitor) if deterministic && console_input.is_none() && record_input.is_none() => {
+                monitor.record_history(every)
+            }
+            _ => panic!("--checkpoint-every needs --monitor and --deterministic, without a script"),
+        }
+    }
+    if spin_nops.is_some() && deterministic {
+        panic!("--spin-nops doesn't apply to a deterministic run, which doesn't detect spinning");
+    }
+    // AT_RANDOM is the only entropy a guest gets, everything else is deterministic already.
+    if seed.is_some() && !user {
+        panic!("--seed only applies to --user");
+    }
+    // A user-mode program gets the address space laid out by the loader.
+    if user && (dram.is_some() || !memories.is_empty() || start_pc.is_some()) {
+        panic!("--dram, --memory and --start-pc don't apply to --user");
+    }
+    let mut file = File::open(&files[0])?;
+    let mut binary = Vec::new();
+    file.read_to_end(&mut binary)?;
 
-impl XLen {
-    pub const fn len(&self) -> usize {
-        match self {
-            XLen::X32 => 32,
-            XLen::X64 => 64,
+    let (mut builder, program) = if user {
+        let (builder, program) = linux::load(&binary)?;
+        let program = match seed {
+            Some(seed) => program.seed(seed),
+            None => program,
+        };
+        (builder, Some(program))
+    } else {
+        // Without a start pc the harts start at the reset vector in the boot ROM, which jumps to
+        // the DRAM.
+        let config = MachineConfig {
+            xlen: Some(xlen),
+            start_pc,
+            dram,
+            regions: memories,
+            disk: files.get(1).map(PathBuf::from),
+            ..config
+        };
+        (config.apply(CpuBuilder::new(binary))?, None)
+    };
+    // The UART that isn't the console writes to the --serial file.
+    let serial: Box<dyn Write> = match serial_out {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::sink()),
+    };
+    // The guest console is batched, see `ConsoleOutput`, and flushed before any diagnostics.
+    let mut console_out = ConsoleOutput::spawn(Box::new(io::stdout()));
+    let retired = Arc::new(AtomicU64::new(0));
+    if let Some(path) = console_log {
+        let log = BufWriter::new(File::create(path)?);
+        console_out.tee(ConsoleLog::new(Box::new(log), retired.clone()));
+    }
+    let console_box = Box::new(console_out.clone());
+    builder = match console_uart {
+        0 => builder.console(console_box).serial(serial),
+        _ => builder.console(serial).serial(console_box),
+    };
+    builder = builder
+        .semihosting(semihosting)
+        .builtin_sbi(builtin_sbi)
+        .csr_policy(csr_policy)
+        .decode_cache(decode_cache)
+        .block_cache(block_cache)
+        .hang_detection(hang_detection)
+        .spin_detection(Some(SpinDetection { nop_run: spin_nops }))
+        .deterministic(deterministic);
+    // Frames are dumped when the guest presents one, and every --frame-every million steps.
+    #[cfg(feature = "framebuffer")]
+    let screen = match framebuffer {
+        Some((width, height)) => {
+            let dir = frame_dir.unwrap_or_else(|| PathBuf::from("."));
+            let framebuffer = Framebuffer::new(width, height, Box::new(PpmDumper::new(dir)));
+            let screen = framebuffer.screen();
+            builder = builder.framebuffer(framebuffer);
+            Some(screen)
         }
+        None => None,
+    };
+    #[cfg(not(feature = "framebuffer"))]
+    if framebuffer.is_some() || frame_dir.is_some() || frame_every.is_some() {
+        panic!("--framebuffer needs the emulator built with the framebuffer feature");
     }
+    let mut machine = Machine::new(builder, harts);
+    if let Some(program) = program {
+        program.start(machine.hart_mut(0), &files)?;
+    }
+    // Hooks, coverage and symbols are attached to hart 0.
+    let cpu = machine.hart_mut(0);
 
-    pub const fn size(&self) -> usize {
-        self.len() >> 3
+    if let Some(path) = trace {
+        let out = BufWriter::new(File::create(path)?);
+        cpu.add_hook(Box::new(hook::Tracer::new(out)));
     }
 
-    pub const fn mask(&self) -> RegT {
-        match self {
-            // 0xffffffff
-            XLen::X32 => ((1 as RegT) << (self.len() as RegT)) - 1,
-            // 0xffffffffffffffff
-            XLen::X64 => -1i64 as RegT,
-        }
+    if let Some(path) = trace_csr {
+        let out = BufWriter::new(File::create(path)?);
+        cpu.add_event_hook(Box::new(hook::EventLog::new(out)));
     }
-}
 
-init_insn!(Cpu, Exception);
+    if coverage_out.is_some() {
+        cpu.enable_coverage();
+    }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if (args.len() != 2) && (args.len() != 3) {
-        panic!("Usage: riscv-emulator <filename> [image]");
+    if profile_out.is_some() {
+        cpu.enable_profiling(profile_period, profile_depth);
+    }
+
+    if stats {
+        cpu.enable_stats();
+    }
+
+    for path in symbol_files {
+        let origin = Path::new(&path).file_stem().unwrap().to_string_lossy();
+        cpu.symbols.add_elf(&origin, &fs::read(&path)?)?;
     }
-    let mut file = File::open(&args[1])?;
-    let mut binary = Vec::new();
-    file.read_to_end(&mut binary)?;
 
-    let mut cpu = Cpu::new(XLen::X64, binary, device::DRAM_BASE);
+    // The test ends by writing to tohost, without one it runs until --max-steps.
+    let signature = match (&signature_out, signature_range) {
+        (None, _) => None,
+        (Some(_), Some((begin, end))) => Some(Signature::new(begin, end, signature_granularity)?),
+        (Some(_), None) => Some(Signature::from_symbols(
+            &cpu.symbols,
+            signature_granularity,
+        )?),
+    };
+    let tohost = cpu.symbols.lookup("tohost").filter(|_| signature.is_some());
+    if let Some(tohost) = tohost {
+        cpu.add_watchpoint(tohost, 8, WatchKind::Write);
+    }
 
-    if args.len() == 3 {
-        let mut disk_image = Vec::new();
-        let mut file = File::open(&args[2])?;
-        file.read_to_end(&mut disk_image)?;
-        cpu.setup_disk(disk_image);
+    if let Some(path) = restore_snapshot {
+        cpu.load_snapshot_file(path)?;
     }
 
+    if cosim {
+        let reference: Box<dyn cosim::ReferenceModel> = match (cosim_log, cosim_spike) {
+            (Some(path), _) => Box::new(cosim::SpikeLog::new(BufReader::new(File::open(path)?))),
+            (None, Some(isa)) => {
+                let elf = Path::new(&files[0]);
+                Box::new(cosim::Spike::spawn(&isa, elf, cpu.state.pc)?)
+            }
+            (None, None) => unreachable!(),
+        };
+        match cosim::run(cpu, reference, COSIM_MAX_IDLE)? {
+            cosim::Outcome::Finished(count) => eprintln!("cosim: {} instructions matched", count),
+            cosim::Outcome::Mismatch(mismatch) => {
+                eprintln!("{}", mismatch);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // Console input arriving at host determined times is the only thing making two runs differ,
+    // a deterministic run only receives input from a console script, or records the steps the
+    // input arrived at to one.
+    let uart_input = match console_uart {
+        0 => machine.bus().uart_input.clone(),
+        _ => machine.bus().uart1_input.clone(),
+    };
+    let mut stdin_console = None;
+    let mut console = match (deterministic, console_input, record_input) {
+        (_, Some(path), _) => Some(ConsoleFeed::replay(File::open(path)?)?),
+        (_, None, Some(path)) => {
+            let script = BufWriter::new(File::create(path)?);
+            Some(ConsoleFeed::record(io::stdin(), Box::new(script)))
+        }
+        (true, None, None) => None,
+        // A program run with --user reads stdin through syscalls.
+        (false, None, None) if user => None,
+        (false, None, None) => {
+            let input = uart_input.clone();
+            stdin_console = Some(StdinConsole::spawn(input, escape, raw_console)?);
+            None
+        }
+    };
+
+    machine.set_fuel(max_insns);
+    machine.set_timeout(timeout);
+    let mut steps: u64 = 0;
+    let mut exit_code = None;
     loop {
-        cpu.one_step();
+        if let (Some(path), Some(save_after)) = (&save_snapshot, save_after) {
+            if steps == save_after {
+                let out = BufWriter::new(File::create(path)?);
+                machine.hart(0).save_snapshot(out)?;
+            }
+        }
+        if let Some(console) = console.as_mut() {
+            console.before_step(steps, &uart_input)?;
+        }
+        if let Some(monitor) = monitor.as_mut() {
+            if !monitor.before_step(machine.hart_mut(0))? {
+                break;
+            }
+        }
+        if stdin_console
+            .as_ref()
+            .is_some_and(StdinConsole::quit_requested)
+        {
+            break;
+        }
+        // Single steps while the console or the monitor look at every step. Otherwise whole basic
+        // blocks run at once, stopping at the steps something is due.
+        let budget = if console.is_some() || monitor.is_some() {
+            1
+        } else {
+            [save_after, max_steps]
+                .iter()
+                .flatten()
+                .filter(|&&at| at > steps)
+                .map(|at| at - steps)
+                .min()
+                .unwrap_or(u64::MAX)
+        };
+        let (taken, stop) = if monitor.as_ref().is_some_and(Monitor::stepping) {
+            machine.step_one()
+        } else {
+            machine.run_block(budget)
+        };
+        steps += taken;
+        #[cfg(feature = "framebuffer")]
+        if let (Some(screen), Some(every)) = (&screen, frame_every) {
+            if steps / every != (steps - taken) / every {
+                screen.present()?;
+            }
+        }
+        retired.store(machine.hart(0).state.csrs.csr(0xb02), Ordering::Relaxed);
+        if stop.is_some() {
+            console_out.flush()?;
+        }
+        if let (Some(monitor), Some(reason)) = (monitor.as_mut(), &stop) {
+            monitor.stopped(machine.hart(0), reason)?;
+        }
+        match stop {
+            Some(StopReason::Exit(code)) => {
+                exit_code = Some(code);
+                break;
+            }
+            Some(StopReason::Watchpoint(hit)) if Some(hit.addr) == tohost => break,
+            // The monitor pauses the machine instead, to look around.
+            Some(StopReason::Fatal(report)) if monitor.is_none() => {
+                eprintln!("{}", report);
+                exit_code = Some(1);
+                break;
+            }
+            Some(StopReason::DoubleFault(report)) if monitor.is_none() => {
+                eprintln!("{}", report);
+                exit_code = Some(1);
+                break;
+            }
+            Some(StopReason::SuspectedHang(report)) if monitor.is_none() => {
+                eprint!("stopped after {} steps, {}", steps, report);
+                exit_code = Some(1);
+                break;
+            }
+            Some(StopReason::FuelExhausted(report)) => {
+                eprint!("stopped after {} steps\n{}", steps, report);
+                exit_code = Some(LIMIT_EXIT_CODE);
+                break;
+            }
+            Some(StopReason::Timeout(report)) => {
+                let seconds = timeout.unwrap_or_default().as_secs_f64();
+                eprint!("stopped after {}s, at {} steps\n{}", seconds, steps, report);
+                exit_code = Some(LIMIT_EXIT_CODE);
+                break;
+            }
+            _ => {}
+        }
+        if Some(steps) == max_steps {
+            break;
+        }
+    }
+
+    // The run loop ends when the monitor or the console quits, the guest exits or hits a fatal
+    // exception, a test writes tohost, after --max-steps or on --max-insns and --timeout.
+    console_out.flush()?;
+    io::stdout().flush()?;
+    // Restores the terminal, `process::exit` below skips destructors.
+    drop(stdin_console);
+    if let (Some(path), Some(signature)) = (signature_out, signature) {
+        signature.write(&machine.bus(), BufWriter::new(File::create(path)?))?;
     }
+    let cpu = machine.hart(0);
+    let stats = cpu.decode_stats();
+    info!(
+        "decode cache: {} hits, {} misses, {:.1}% hit rate",
+        stats.hits,
+        stats.misses,
+        stats.hit_rate() * 100.0
+    );
+    if let Some(report) = cpu.stats() {
+        eprint!("{}", report);
+    }
+    if let Some(path) = dump_state {
+        let state = serde_json::to_string_pretty(&cpu.state_json())?;
+        fs::write(path, state + "\n")?;
+    }
+    if let (Some(path), Some(coverage)) = (coverage_out, cpu.coverage()) {
+        let mut out = BufWriter::new(File::create(path)?);
+        if cpu.symbols.is_empty() {
+            coverage.write_text(&mut out)?;
+        } else {
+            coverage.write_lcov(&mut out, &cpu.symbols)?;
+        }
+    }
+    if let (Some(path), Some(profile)) = (profile_out, cpu.profile()) {
+        let mut out = BufWriter::new(File::create(path)?);
+        profile.write_folded(&mut out, &cpu.symbols)?;
+    }
+    if let Some(code) = exit_code {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// Lists every instruction the emulator decodes, grouped by extension.
+fn print_instruction_table(out: &mut dyn Write) -> io::Result<()> {
+    let mut table: Vec<_> = instruction_table().iter().collect();
+    table.sort_by_key(|insn| (insn.ext, insn.name));
+    writeln!(out, "name       match      mask       format ext xlen")?;
+    for insn in table {
+        let xlen = insn
+            .xlen
+            .map_or("both".to_string(), |xlen| xlen.len().to_string());
+        writeln!(
+            out,
+            "{:<10} {:#010x} {:#010x} {:<6} {:<3} {}",
+            insn.name,
+            insn.match_code,
+            insn.mask,
+            format!("{:?}", insn.format),
+            insn.ext,
+            xlen
+        )?;
+    }
+    Ok(())
+}
+
+/// Parses the heuristics of --detect-hangs, `default` or a comma-separated list of the ones to
+/// use, e.g. `same-pc=1000,traps`. A heuristic without a threshold gets the default one.
+fn parse_hang_detection(arg: &str) -> HangDetection {
+    let default = HangDetection::default();
+    if arg == "default" {
+        return default;
+    }
+    let mut detection = HangDetection {
+        same_pc: None,
+        low_pc: false,
+        trap_storm: None,
+    };
+    for heuristic in arg.split(',') {
+        let (name, threshold) = match heuristic.split_once('=') {
+            Some((name, n)) => {
+                let n = n
+                    .parse::<u64>()
+                    .expect("--detect-hangs thresholds are numbers");
+                (name, Some(n))
+            }
+            None => (heuristic, None),
+        };
+        match name {
+            "same-pc" => detection.same_pc = threshold.or(default.same_pc),
+            "traps" => detection.trap_storm = threshold.or(default.trap_storm),
+            "low-pc" if threshold.is_none() => detection.low_pc = true,
+            _ => panic!("unknown --detect-hangs heuristic {}", heuristic),
+        }
+    }
+    detection
+}
+
+/// Parses a framebuffer resolution given as `<width>x<height>`, e.g. `640x480`.
+fn parse_resolution(arg: &str) -> (u32, u32) {
+    let (width, height) = arg.split_once('x').expect(USAGE);
+    let number = |s: &str| s.parse::<u32>().expect("the resolution takes numbers");
+    (number(width), number(height))
+}
+
+/// Parses a memory region given as `<base>:<size>`, both decimal or 0x-prefixed hex.
+fn parse_region(arg: &str) -> (u64, usize) {
+    let (base, size) = arg.split_once(':').expect(USAGE);
+    let number = |s| monitor::parse_number(s).expect("memory regions take numbers");
+    (number(base), number(size) as usize)
 }
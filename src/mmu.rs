@@ -1,129 +1,1357 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use log::debug;
+
 use crate::{
     cpu::CpuStatus,
-    device::{bus::Bus, Data, Device},
+    device::{bus::Bus, Data},
+    hook::AccessHook,
     page::{PageTableEnty, VirtualAddress},
     register::satp::Mode,
     trap::Exception,
-    XLen,
+    watchpoint::{WatchHit, Watchpoints},
+    PrivilegeMode, RegT, XLen,
 };
 
 /// Page size (4 KiB).
 pub const PAGE_SIZE: u64 = 4 * 1024;
 
+/// How loads and stores that aren't naturally aligned are handled.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MisalignedPolicy {
+    /// Split the access into byte accesses, each translated on its own, like hardware that
+    /// supports misaligned accesses.
+    Emulate,
+    /// Raise LoadMisaligned/StoreMisaligned with the offending address for software fixup.
+    Trap,
+}
+
 pub struct Mmu {
-    pub bus: Bus,
+    /// Shared by every hart of the machine.
+    pub bus: Rc<RefCell<Bus>>,
+    /// The id of the hart the MMU belongs to, as registered with the bus.
+    hart: usize,
     xlen: XLen,
+    misaligned_policy: MisalignedPolicy,
+    /// The last satp seen by `translate` and whether it selects the bare mode, so satp is only
+    /// decoded again when it changes.
+    satp_bare: Cell<(RegT, bool)>,
+    pub watchpoints: Watchpoints,
+    /// The last access that hit a watchpoint, taken by the cpu after every step.
+    watch_hit: Cell<Option<WatchHit>>,
+    access_hooks: RefCell<Vec<Box<dyn AccessHook>>>,
 }
 
 impl Mmu {
-    pub fn new(xlen: XLen, binary: Vec<u8>) -> Self {
+    /// Creates the MMU of a new hart on `bus`.
+    pub fn new(xlen: XLen, bus: Rc<RefCell<Bus>>) -> Self {
+        let hart = bus.borrow_mut().add_hart();
         Self {
-            bus: Bus::new(binary),
-            xlen: xlen,
+            bus,
+            hart,
+            xlen,
+            misaligned_policy: MisalignedPolicy::Emulate,
+            satp_bare: Cell::new((0, true)),
+            watchpoints: Watchpoints::new(),
+            watch_hit: Cell::new(None),
+            access_hooks: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn hart(&self) -> usize {
+        self.hart
+    }
+
+    pub fn set_misaligned_policy(&mut self, policy: MisalignedPolicy) {
+        self.misaligned_policy = policy;
+    }
+
+    pub fn add_access_hook(&mut self, hook: Box<dyn AccessHook>) {
+        self.access_hooks.get_mut().push(hook);
+    }
+
+    pub fn has_access_hooks(&self) -> bool {
+        !self.access_hooks.borrow().is_empty()
+    }
+
+    /// Passes a translated access to the access hooks.
+    #[inline]
+    pub fn observe(&self, ctx: &AccessContext) {
+        for hook in self.access_hooks.borrow_mut().iter_mut() {
+            hook.access(ctx);
         }
     }
 
     pub fn load<T>(&self, state: &CpuStatus, addr: u64) -> Result<T, Exception>
     where
         T: Data,
-        [(); <T as Data>::SIZE]: Sized,
     {
-        self.bus
-            .read::<T>(self.translate(state, addr, AccessType::LOAD)?)
+        let addr = addr & self.xlen.mask();
+        self.check_triggers(state, addr, AccessType::Load)?;
+        let value = self.read::<T>(state, addr, true)?;
+        if !self.watchpoints.is_empty() {
+            self.watch_load(state, addr, T::SIZE, value.to_u64());
+        }
+        Ok(value)
+    }
+
+    #[cold]
+    fn watch_load(&self, state: &CpuStatus, addr: u64, size: usize, value: u64) {
+        if self.watchpoints.hit(addr, size, false) {
+            self.watch_hit.set(Some(WatchHit {
+                pc: state.pc,
+                addr,
+                size,
+                is_write: false,
+                old: None,
+                value,
+            }));
+        }
+    }
+
+    /// Loads like the guest would without triggering watchpoints, e.g. for the monitor.
+    #[inline]
+    pub fn peek<T>(&self, state: &CpuStatus, addr: u64) -> Result<T, Exception>
+    where
+        T: Data,
+    {
+        self.read::<T>(state, addr & self.xlen.mask(), false)
+    }
+
+    /// Loads from `addr`, passing the access to the access hooks if `observed`.
+    #[inline]
+    fn read<T>(&self, state: &CpuStatus, addr: u64, observed: bool) -> Result<T, Exception>
+    where
+        T: Data,
+    {
+        if !addr.is_multiple_of(T::SIZE as u64) {
+            if self.misaligned_policy == MisalignedPolicy::Trap {
+                return Err(Exception::LoadMisaligned(addr));
+            }
+            // Translate every byte before touching the bus, the access may straddle two pages.
+            let mut ctxs = [AccessContext::default(); 8];
+            for (i, ctx) in ctxs[..T::SIZE].iter_mut().enumerate() {
+                *ctx = self.translate(state, addr.wrapping_add(i as u64), 1, AccessType::Load)?;
+            }
+            let mut value = 0;
+            for (i, ctx) in ctxs[..T::SIZE].iter().enumerate() {
+                value |= (self.bus_read::<u8>(ctx, observed)? as u64) << (i * 8);
+            }
+            return Ok(T::from_u64(value));
+        }
+        let ctx = self.translate(state, addr, T::SIZE, AccessType::Load)?;
+        self.bus_read::<T>(&ctx, observed)
+    }
+
+    #[inline]
+    fn bus_read<T: Data>(&self, ctx: &AccessContext, observed: bool) -> Result<T, Exception> {
+        if observed {
+            self.observe(ctx);
+        }
+        self.bus.borrow().read_access::<T>(ctx)
+    }
+
+    /// Loads like `load` and reserves the accessed address for a following `store_conditional`,
+    /// for LR. Like every atomic access it raises an exception on a misaligned address regardless
+    /// of the misaligned policy.
+    pub fn load_reserved<T>(&self, state: &CpuStatus, addr: u64) -> Result<T, Exception>
+    where
+        T: Data,
+    {
+        let addr = addr & self.xlen.mask();
+        if !addr.is_multiple_of(T::SIZE as u64) {
+            return Err(Exception::LoadMisaligned(addr));
+        }
+        self.check_triggers(state, addr, AccessType::Load)?;
+        let ctx = self.translate(state, addr, T::SIZE, AccessType::Load)?;
+        self.observe(&ctx);
+        let mut bus = self.bus.borrow_mut();
+        let value = bus.read_access::<T>(&ctx)?;
+        bus.reserve(self.hart, ctx.p_addr);
+        Ok(value)
+    }
+
+    /// Stores like `store` if the reservation of `load_reserved` still covers the address, for
+    /// SC. Any store to it, from this hart or another one, drops the reservation. Returns true if
+    /// the value was stored, the reservation is gone either way.
+    pub fn store_conditional<T>(
+        &mut self,
+        state: &CpuStatus,
+        addr: u64,
+        value: T,
+    ) -> Result<bool, Exception>
+    where
+        T: Data,
+    {
+        let addr = addr & self.xlen.mask();
+        if !addr.is_multiple_of(T::SIZE as u64) {
+            return Err(Exception::StoreMisaligned(addr));
+        }
+        self.check_triggers(state, addr, AccessType::Store)?;
+        let ctx = self.translate(state, addr, T::SIZE, AccessType::Store)?;
+        let mut bus = self.bus.borrow_mut();
+        if !bus.take_reservation(self.hart, ctx.p_addr) {
+            return Ok(false);
+        }
+        self.observe(&ctx);
+        bus.write_access::<T>(&ctx, value)?;
+        Ok(true)
+    }
+
+    /// Replaces the value at `addr` with `op` of it and returns the old value, for the AMOs. The
+    /// access needs both read and write permission and every failure is reported as a store's,
+    /// misaligned addresses included.
+    pub fn amo<T>(
+        &mut self,
+        state: &CpuStatus,
+        addr: u64,
+        op: impl FnOnce(T) -> T,
+    ) -> Result<T, Exception>
+    where
+        T: Data,
+    {
+        let addr = addr & self.xlen.mask();
+        if !addr.is_multiple_of(T::SIZE as u64) {
+            return Err(Exception::StoreMisaligned(addr));
+        }
+        self.check_triggers(state, addr, AccessType::Amo)?;
+        let ctx = self.translate(state, addr, T::SIZE, AccessType::Amo)?;
+        self.observe(&ctx);
+        let mut bus = self.bus.borrow_mut();
+        let old = bus.read_access::<T>(&ctx).map_err(|e| match e {
+            Exception::LoadFault(addr) => Exception::StoreFault(addr),
+            e => e,
+        })?;
+        let value = op(old);
+        bus.write_access::<T>(&ctx, value)?;
+        if !self.watchpoints.is_empty() && self.watchpoints.hit(addr, T::SIZE, true) {
+            self.watch_hit.set(Some(WatchHit {
+                pc: state.pc,
+                addr,
+                size: T::SIZE,
+                is_write: true,
+                old: Some(old.to_u64()),
+                value: value.to_u64(),
+            }));
+        }
+        Ok(old)
     }
 
     pub fn store<T>(&mut self, state: &CpuStatus, addr: u64, value: T) -> Result<(), Exception>
     where
         T: Data,
-        [(); <T as Data>::SIZE]: Sized,
     {
-        self.bus
-            .write::<T>(self.translate(state, addr, AccessType::STORE)?, value)
+        let addr = addr & self.xlen.mask();
+        self.check_triggers(state, addr, AccessType::Store)?;
+        if self.watchpoints.is_empty() {
+            return self.store_unwatched(state, addr, value);
+        }
+        self.watch_store(state, addr, value)
+    }
+
+    #[cold]
+    fn watch_store<T>(&mut self, state: &CpuStatus, addr: u64, value: T) -> Result<(), Exception>
+    where
+        T: Data,
+    {
+        if !self.watchpoints.hit(addr, T::SIZE, true) {
+            return self.store_unwatched(state, addr, value);
+        }
+        let old = self.peek::<T>(state, addr).ok().map(T::to_u64);
+        self.store_unwatched(state, addr, value)?;
+        self.watch_hit.set(Some(WatchHit {
+            pc: state.pc,
+            addr,
+            size: T::SIZE,
+            is_write: true,
+            old,
+            value: value.to_u64(),
+        }));
+        Ok(())
+    }
+
+    /// Raises a breakpoint exception if a trigger fires on an access of `a_type` to `addr`. The
+    /// guest's own triggers, unlike watchpoints they fire before the access.
+    #[inline]
+    pub fn check_triggers(
+        &self,
+        state: &CpuStatus,
+        addr: u64,
+        a_type: AccessType,
+    ) -> Result<(), Exception> {
+        if state.csrs.triggers_armed() && state.csrs.trigger_fires(&a_type, addr, state.privilege) {
+            return Err(Exception::Breakpoint);
+        }
+        Ok(())
+    }
+
+    /// Returns the last access that hit a watchpoint since the previous call.
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    #[inline]
+    fn store_unwatched<T>(
+        &mut self,
+        state: &CpuStatus,
+        addr: u64,
+        value: T,
+    ) -> Result<(), Exception>
+    where
+        T: Data,
+    {
+        if !addr.is_multiple_of(T::SIZE as u64) {
+            if self.misaligned_policy == MisalignedPolicy::Trap {
+                return Err(Exception::StoreMisaligned(addr));
+            }
+            // Translate every byte first so that a fault on the second page leaves memory intact.
+            let mut ctxs = [AccessContext::default(); 8];
+            for (i, ctx) in ctxs[..T::SIZE].iter_mut().enumerate() {
+                *ctx = self.translate(state, addr.wrapping_add(i as u64), 1, AccessType::Store)?;
+            }
+            let value = value.to_u64();
+            for (i, ctx) in ctxs[..T::SIZE].iter().enumerate() {
+                self.bus_write::<u8>(ctx, (value >> (i * 8)) as u8)?;
+            }
+            return Ok(());
+        }
+        let ctx = self.translate(state, addr, T::SIZE, AccessType::Store)?;
+        self.bus_write::<T>(&ctx, value)
+    }
+
+    #[inline]
+    fn bus_write<T: Data>(&self, ctx: &AccessContext, value: T) -> Result<(), Exception> {
+        self.observe(ctx);
+        self.bus.borrow_mut().write_access::<T>(ctx, value)
+    }
+
+    /// Reads `buf.len()` bytes at the virtual address `addr` like loads of the current privilege
+    /// mode, for the host: watchpoints and triggers don't see it. Each page is translated on its
+    /// own, on a fault the bytes of the pages before it are already read.
+    pub fn read_bytes(
+        &self,
+        state: &CpuStatus,
+        addr: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < buf.len() {
+            let v_addr = addr.wrapping_add(done as u64) & self.xlen.mask();
+            let len = page_run(v_addr, buf.len() - done);
+            let p_addr = self.translate(state, v_addr, len, AccessType::Load)?.p_addr;
+            self.bus
+                .borrow()
+                .read_phys_bytes(p_addr, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at the virtual address `addr` like `read_bytes` reads. On a fault the bytes
+    /// of the pages before it are already written.
+    pub fn write_bytes(
+        &mut self,
+        state: &CpuStatus,
+        addr: u64,
+        data: &[u8],
+    ) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < data.len() {
+            let v_addr = addr.wrapping_add(done as u64) & self.xlen.mask();
+            let len = page_run(v_addr, data.len() - done);
+            let p_addr = self
+                .translate(state, v_addr, len, AccessType::Store)?
+                .p_addr;
+            self.bus
+                .borrow_mut()
+                .write_phys_bytes(p_addr, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Checks that an access of `a_type` to the `size` bytes at `addr` is allowed, without
+    /// touching memory. For the cache-block management instructions, which only need the check.
+    pub fn check_access(
+        &self,
+        state: &CpuStatus,
+        addr: u64,
+        size: usize,
+        a_type: AccessType,
+    ) -> Result<(), Exception> {
+        self.translate(state, addr & self.xlen.mask(), size, a_type)
+            .map(drop)
+    }
+
+    /// Translates `addr` like an access of `a_type` to its first byte would, returning the PTEs
+    /// the walk read along with the result. The access hooks and watchpoints don't see it, for
+    /// debuggers asking what a virtual address maps to.
+    pub fn probe(
+        &self,
+        state: &CpuStatus,
+        addr: u64,
+        a_type: AccessType,
+    ) -> Result<TranslationInfo, Exception> {
+        let (walk, result) = self.trace(state, addr, a_type);
+        result.map(|(p_addr, page_size)| TranslationInfo {
+            walk,
+            p_addr,
+            page_size,
+        })
+    }
+
+    /// Like `probe`, but keeps the PTEs read before a fault, which tell where the walk went
+    /// wrong.
+    pub(crate) fn trace(
+        &self,
+        state: &CpuStatus,
+        addr: u64,
+        a_type: AccessType,
+    ) -> (Vec<WalkStep>, Result<(u64, u64), Exception>) {
+        let addr = addr & self.xlen.mask();
+        let mut walk = Vec::new();
+        let result = self
+            .walk_with(state, addr, &a_type, |step| walk.push(step))
+            .and_then(|(p_addr, page_size)| {
+                let privilege = self.privilege(state, &a_type);
+                self.check_pmp(state, addr, p_addr, 1, privilege, &a_type)?;
+                Ok((p_addr, page_size))
+            });
+        (walk, result)
     }
 
-    pub fn fetch(&self, state: &CpuStatus, addr: u64) -> Result<u32, Exception> {
-        self.bus
-            .read::<u32>(self.translate(state, addr, AccessType::FETCH)?)
+    /// Translates the address of an instruction fetch of `size` bytes. The instruction itself is
+    /// read by the cpu, which caches decoded instructions by physical address.
+    pub fn translate_fetch(
+        &self,
+        state: &CpuStatus,
+        addr: u64,
+        size: usize,
+    ) -> Result<AccessContext, Exception> {
+        self.translate(state, addr, size, AccessType::Fetch)
     }
 
+    /// Translates `addr` and checks the `size` bytes accessed at the physical address against
+    /// PMP.
     fn translate(
         &self,
         state: &CpuStatus,
         addr: u64,
+        size: usize,
         a_type: AccessType,
-    ) -> Result<u64, Exception> {
+    ) -> Result<AccessContext, Exception> {
+        let p_addr = self.walk(state, addr, &a_type)?;
+        let privilege = self.privilege(state, &a_type);
+        self.check_pmp(state, addr, p_addr, size, privilege, &a_type)?;
+        Ok(AccessContext {
+            v_addr: addr,
+            p_addr,
+            size,
+            a_type,
+            privilege,
+        })
+    }
+
+    /// Raises the access fault of `a_type` at `addr` if PMP doesn't allow the access to `p_addr`,
+    /// the address `addr` translated to.
+    fn check_pmp(
+        &self,
+        state: &CpuStatus,
+        addr: u64,
+        p_addr: u64,
+        size: usize,
+        privilege: PrivilegeMode,
+        a_type: &AccessType,
+    ) -> Result<(), Exception> {
+        if state.csrs.pmp().check(p_addr, size, privilege, a_type) {
+            return Ok(());
+        }
+        debug!(
+            "PMP denied {:?} of {} bytes at {:#x} in {:?}",
+            a_type, size, p_addr, privilege
+        );
+        Err(match a_type {
+            AccessType::Load => Exception::LoadFault(addr),
+            AccessType::Store | AccessType::Amo => Exception::StoreFault(addr),
+            AccessType::Fetch => Exception::InstructionFault(addr),
+        })
+    }
+
+    fn walk(&self, state: &CpuStatus, addr: u64, a_type: &AccessType) -> Result<u64, Exception> {
+        self.walk_with(state, addr, a_type, |_| {})
+            .map(|(p_addr, _)| p_addr)
+    }
+
+    /// Walks the page tables for `addr`, passing every PTE read to `visit`. Returns the physical
+    /// address and the size of the page mapping it.
+    fn walk_with(
+        &self,
+        state: &CpuStatus,
+        addr: u64,
+        a_type: &AccessType,
+        mut visit: impl FnMut(WalkStep),
+    ) -> Result<(u64, u64), Exception> {
+        if self.is_bare(state, a_type) {
+            return Ok((addr, PAGE_SIZE));
+        }
+
         let satp = state.csrs.satp();
         let mode = satp.mode(&self.xlen);
 
-        if mode == Mode::Bare {
-            return Ok(addr);
-        }
+        let exception = match a_type {
+            AccessType::Load => Exception::LoadPageFault(addr),
+            AccessType::Store | AccessType::Amo => Exception::StorePageFault(addr),
+            AccessType::Fetch => Exception::InstructionPageFault(addr),
+        };
 
-        let mut page_table_addr = satp.ppn(&self.xlen) * PAGE_SIZE;
         let v_addr = VirtualAddress(addr);
+        if !v_addr.is_canonical(&mode) {
+            return Err(exception);
+        }
 
-        // page-table entry
-        let mut pte: PageTableEnty;
+        let mut page_table_addr = satp.ppn(&self.xlen) * PAGE_SIZE;
         let vpos = v_addr.virtual_page_offsets(&mode);
-        let mut idx = (vpos.len() - 1) as i8;
-
-        let exception = match a_type {
-            AccessType::LOAD => Exception::LoadPageFault,
-            AccessType::STORE => Exception::StorePageFault,
-            AccessType::FETCH => Exception::InstructionPageFault,
+        // Sv32 has two levels of 10-bit VPN fields and 4-byte PTEs, Sv39 three of 9 bits and
+        // 8-byte PTEs.
+        let (vpn_bits, pte_size) = match mode {
+            Mode::Sv32 => (10, 4),
+            _ => (9, 8),
         };
 
-        loop {
-            pte = PageTableEnty(self.bus.read::<u64>(page_table_addr + vpos[idx as usize])?);
+        // The leaf PTE and the level it was found at, 0 for a 4 KiB page.
+        let mut leaf = None;
+        for level in (0..vpos.len()).rev() {
+            let pte_addr = page_table_addr + vpos[level];
+            // Page-table walks are supervisor accesses as far as PMP is concerned.
+            self.check_pmp(
+                state,
+                addr,
+                pte_addr,
+                pte_size,
+                PrivilegeMode::Supervisor,
+                a_type,
+            )?;
+            let pte = PageTableEnty(match mode {
+                // Sv32 的页表项只有 4 字节。
+                Mode::Sv32 => self.bus.borrow().read::<u32>(pte_addr)? as u64,
+                _ => self.bus.borrow().read::<u64>(pte_addr)?,
+            });
+            visit(WalkStep {
+                level,
+                pte_addr,
+                pte: pte.0,
+            });
 
-            if !pte.v() || (!pte.r() && pte.w()) {
+            if !pte.v() || (!pte.r() && pte.w()) || pte.reserved(&mode) != 0 {
                 return Err(exception);
             }
 
-            if pte.r() || pte.w() {
-                // Find leaf PTE
+            if pte.r() || pte.x() {
+                leaf = Some((level, pte));
                 break;
             }
 
-            idx -= 1;
-
             // next page-table addr
             page_table_addr = pte.ppn(&mode) * PAGE_SIZE;
+        }
+        // A pointer at level 0 leaves the walk without a leaf.
+        let (level, pte) = leaf.ok_or(exception)?;
 
-            if idx < 0 {
-                return Err(exception);
+        // U 模式只能访问 U 页。S 模式只有在 SUM 置起时才能读写 U 页，且永远不能执行 U
+        // 页上的指令。MPRV 置起时按 MPP 的特权级检查。
+        let mstatus = state.csrs.mstatus();
+        match (self.privilege(state, a_type), pte.u()) {
+            (PrivilegeMode::User, false) => return Err(exception),
+            (PrivilegeMode::Supervisor, true) if *a_type == AccessType::Fetch || !mstatus.sum() => {
+                return Err(exception)
             }
+            _ => {}
         }
 
+        // MXR 让只可执行的页也可以读。
+        let readable = pte.r() || (mstatus.mxr() && pte.x());
         match a_type {
-            AccessType::LOAD if !pte.r() => Err(Exception::LoadPageFault),
-            AccessType::STORE if !pte.w() => Err(Exception::StorePageFault),
-            AccessType::FETCH if !pte.x() => Err(Exception::InstructionPageFault),
+            AccessType::Load if !readable => Err(exception),
+            AccessType::Store if !pte.w() => Err(exception),
+            AccessType::Amo if !readable || !pte.w() => Err(exception),
+            AccessType::Fetch if !pte.x() => Err(exception),
             _ => {
-                let offset = v_addr.offset();
-                let ppns = pte.ppns(&mode);
-
-                match idx {
-                    0 => Ok(pte.ppn(&mode) << 12 | offset),
-                    // Huge page.
-                    1 => match mode {
-                        Mode::Sv32 => Ok((ppns[1] << 22) | (vpos[0] << 9) | offset),
-                        Mode::Sv39 => {
-                            Ok((ppns[2] << 30) | (ppns[1] << 21) | (vpos[0] << 9) | offset)
-                        }
-                        _ => unimplemented!(),
-                    },
-                    // Huge page. only sv39
-                    2 => Ok((ppns[2] << 30) | (vpos[1] << 18) | (vpos[0] << 9) | offset),
-                    _ => Err(exception),
+                // A superpage maps the VPN fields below its level, which must be zero in its PPN
+                // rather than be combined with the address.
+                let page_mask = (1 << (12 + level * vpn_bits)) - 1;
+                let base = pte.ppn(&mode) * PAGE_SIZE;
+                if base & page_mask != 0 {
+                    return Err(exception);
                 }
+                Ok((base | addr & page_mask, page_mask + 1))
             }
         }
     }
+
+    /// Returns the effective privilege of an access. In machine mode MPRV makes loads and stores,
+    /// but not instruction fetches, translate and check permissions as if running at MPP.
+    fn privilege(&self, state: &CpuStatus, a_type: &AccessType) -> PrivilegeMode {
+        let mstatus = state.csrs.mstatus();
+        match a_type {
+            AccessType::Load | AccessType::Store | AccessType::Amo
+                if state.privilege == PrivilegeMode::Machine && mstatus.mprv() =>
+            {
+                mstatus.mpp()
+            }
+            _ => state.privilege,
+        }
+    }
+
+    /// Returns true if an access isn't translated, either because it's done with machine mode
+    /// privilege or because satp selects the bare mode.
+    fn is_bare(&self, state: &CpuStatus, a_type: &AccessType) -> bool {
+        if self.privilege(state, a_type) == PrivilegeMode::Machine {
+            return true;
+        }
+
+        let satp = state.csrs.satp();
+        let (last_satp, bare) = self.satp_bare.get();
+        if satp.bits() == last_satp {
+            return bare;
+        }
+        let bare = satp.mode(&self.xlen) == Mode::Bare;
+        self.satp_bare.set((satp.bits(), bare));
+        bare
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Load,
+    Store,
+    Fetch,
+    /// The read-modify-write of an AMO, which needs both read and write permission and faults
+    /// like a store.
+    Amo,
+}
+
+/// A translated guest access, passed from the MMU to the bus, the devices and the access hooks
+/// so that they can match on either address. Watchpoints and triggers match on the virtual
+/// address, the decode cache on the physical one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessContext {
+    /// The address the guest accessed.
+    pub v_addr: u64,
+    /// The address `v_addr` translates to, the same one without translation.
+    pub p_addr: u64,
+    pub size: usize,
+    pub a_type: AccessType,
+    /// The effective privilege of the access, MPP for loads and stores under MPRV.
+    pub privilege: PrivilegeMode,
+}
+
+impl Default for AccessContext {
+    fn default() -> Self {
+        Self {
+            v_addr: 0,
+            p_addr: 0,
+            size: 0,
+            a_type: AccessType::Load,
+            privilege: PrivilegeMode::Machine,
+        }
+    }
+}
+
+/// A PTE read by a page-table walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    /// The level of the table, 0 for the one mapping 4 KiB pages.
+    pub level: usize,
+    pub pte_addr: u64,
+    pub pte: u64,
+}
+
+/// What `Mmu::probe` found out about a virtual address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslationInfo {
+    /// The PTEs read, from the root table down to the leaf. Empty if the access isn't
+    /// translated.
+    pub walk: Vec<WalkStep>,
+    pub p_addr: u64,
+    /// The size of the page mapping the address, 4 KiB if the access isn't translated.
+    pub page_size: u64,
+}
+
+impl TranslationInfo {
+    /// The permission and status bits of the leaf PTE, V to D, if the access is translated.
+    pub fn flags(&self) -> Option<u8> {
+        self.walk.last().map(|step| step.pte as u8)
+    }
+}
+
+/// Returns how many of the `len` bytes at `addr` lie in the page of `addr`.
+fn page_run(addr: u64, len: usize) -> usize {
+    (PAGE_SIZE - addr % PAGE_SIZE).min(len as u64) as usize
 }
 
-enum AccessType {
-    LOAD,
-    STORE,
-    FETCH,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::Cpu,
+        device::{Device, DRAM_BASE},
+        testing::*,
+        PrivilegeMode,
+    };
+
+    const TABLES: u64 = DRAM_BASE + 0x10_0000;
+    const CODE: u64 = 0x1000_0000;
+    const DATA: u64 = 0x2000_0000;
+    /// Where mtvec points, in the program of `sv39_cpu`.
+    const MTVEC: u64 = DRAM_BASE + 0x100;
+
+    /// A cpu in S-mode translating with empty Sv39 tables, its program mapped at `CODE` and
+    /// an ebreak at `MTVEC`.
+    fn sv39_cpu(program: &[u32]) -> (Cpu, PageTables) {
+        let mut image = vec![NOP; 0x41];
+        image[..program.len()].copy_from_slice(program);
+        image[0x40] = EBREAK;
+        let mut cpu = Cpu::with_program(XLen::X64, &image);
+        let mut tables = PageTables::new(TABLES);
+        tables.map(&mut cpu, CODE, DRAM_BASE, PTE_V | PTE_R | PTE_X | PTE_A);
+        cpu.state.csrs.set_satp(tables.satp());
+        cpu.state.csrs.set_mtvec(MTVEC);
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.state.pc = CODE;
+        (cpu, tables)
+    }
+
+    #[test]
+    fn misaligned_accesses_follow_the_policy() {
+        let addr = DRAM_BASE + 0x203;
+        let program = [sd(10, 11, 0), ld(12, 11, 0), EBREAK];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        cpu.state.xs.set_reg(10, 0x1122_3344_5566_7788);
+        cpu.state.xs.set_reg(11, addr);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.xs.reg(12), 0x1122_3344_5566_7788);
+
+        // 依次是 sd 和 ld 的 mcause。
+        for &(pc, mcause) in [(DRAM_BASE, 6), (DRAM_BASE + 4, 4)].iter() {
+            let mut cpu = Cpu::with_program(XLen::X64, &program);
+            cpu.mmu.set_misaligned_policy(MisalignedPolicy::Trap);
+            cpu.state.csrs.set_mtvec(MTVEC);
+            cpu.state.xs.set_reg(11, addr);
+            cpu.state.pc = pc;
+            cpu.one_step();
+            assert_eq!(cpu.state.pc, MTVEC);
+            assert_eq!(cpu.state.csrs.mcause(), mcause);
+            assert_eq!(cpu.state.csrs.mtval(), addr);
+        }
+    }
+
+    #[test]
+    fn amos_fault_like_stores() {
+        // DATA 只读，DATA + 0x1000 可读写。
+        let (mut cpu, mut tables) = sv39_cpu(&[EBREAK]);
+        tables.map(&mut cpu, DATA, DRAM_BASE + 0x2000, PTE_V | PTE_R | PTE_A);
+        let rw = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        tables.map(&mut cpu, DATA + 0x1000, DRAM_BASE + 0x3000, rw);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u32>(DRAM_BASE + 0x3000, 5)
+            .unwrap();
+
+        let add = cpu.mmu.amo::<u32>(&cpu.state, DATA + 4, |t| t + 2);
+        assert_eq!(add, Err(Exception::StorePageFault(DATA + 4)));
+        let add = cpu.mmu.amo::<u32>(&cpu.state, DATA + 0x1000, |t| t + 2);
+        assert_eq!(add, Ok(5));
+        assert_eq!(cpu.mmu.bus.borrow().read::<u32>(DRAM_BASE + 0x3000), Ok(7));
+
+        // 地址不对齐时 AMO 与 SC 报 store 的异常，LR 是读，报 load 的异常。依次是指令与 mcause。
+        let addr = DRAM_BASE + 0x202;
+        let cases = [
+            (amo(0b00000, 2, 10, 11, 12), 6),
+            (amo(0b00001, 2, 10, 11, 12), 6),
+            (amo(0b10100, 2, 10, 11, 12), 6),
+            (amo(0b00011, 2, 10, 11, 12), 6),
+            (amo(0b00010, 2, 10, 11, 0), 4),
+        ];
+        for &(insn, mcause) in cases.iter() {
+            let mut cpu = Cpu::with_program(XLen::X64, &[insn]);
+            cpu.state.csrs.set_mtvec(MTVEC);
+            cpu.state.xs.set_reg(11, addr);
+            cpu.one_step();
+            assert_eq!(cpu.state.pc, MTVEC);
+            assert_eq!(cpu.state.csrs.mcause(), mcause);
+            assert_eq!(cpu.state.csrs.mtval(), addr);
+        }
+    }
+
+    #[test]
+    fn misaligned_load_across_pages_reads_both_frames() {
+        // DATA 和 DATA + 0x1000 映射到不相邻的两个物理页，后一页在前。
+        let (mut cpu, mut tables) = sv39_cpu(&[lw(12, 11, 0), EBREAK]);
+        let flags = PTE_V | PTE_R | PTE_A;
+        tables.map(&mut cpu, DATA, DRAM_BASE + 0x5000, flags);
+        tables.map(&mut cpu, DATA + 0x1000, DRAM_BASE + 0x3000, flags);
+        {
+            let mut bus = cpu.mmu.bus.borrow_mut();
+            bus.write::<u16>(DRAM_BASE + 0x5ffe, 0x2211).unwrap();
+            bus.write::<u16>(DRAM_BASE + 0x3000, 0x4433).unwrap();
+            // 物理上紧接着的字节不能被读到。
+            bus.write::<u16>(DRAM_BASE + 0x6000, 0xeeee).unwrap();
+        }
+        cpu.mmu.set_misaligned_policy(MisalignedPolicy::Emulate);
+        cpu.state.xs.set_reg(11, DATA + 0xffe);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.pc, CODE + 4);
+        assert_eq!(cpu.state.xs.reg(12), 0x4433_2211);
+    }
+
+    #[test]
+    fn misaligned_store_across_pages_faults_before_writing() {
+        // DATA 映射了，DATA + 0x1000 没有。
+        let (mut cpu, mut tables) = sv39_cpu(&[EBREAK]);
+        let flags = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        tables.map(&mut cpu, DATA, DRAM_BASE + 0x2000, flags);
+
+        let store = cpu.mmu.store::<u64>(&cpu.state, DATA + 0xffc, !0);
+        assert_eq!(store, Err(Exception::StorePageFault(DATA + 0x1000)));
+        assert_eq!(cpu.mmu.bus.borrow().read::<u32>(DRAM_BASE + 0x2ffc), Ok(0));
+    }
+
+    #[test]
+    fn bulk_copies_translate_every_page_and_stop_at_the_hole() {
+        // DATA 与 DATA + 0x2000 映射了，中间的页没有。
+        let (mut cpu, mut tables) = sv39_cpu(&[EBREAK]);
+        let flags = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        tables.map(&mut cpu, DATA, DRAM_BASE + 0x5000, flags);
+        tables.map(&mut cpu, DATA + 0x2000, DRAM_BASE + 0x3000, flags);
+        let start = DATA + 0xf00;
+        let data: Vec<u8> = (0..0x1200).map(|i| i as u8 | 1).collect();
+
+        let write = cpu.mmu.write_bytes(&cpu.state, start, &data);
+        assert_eq!(write, Err(Exception::StorePageFault(DATA + 0x1000)));
+        // 洞之前的部分已经写入，之后的页没有动。
+        let mut written = vec![0; 0x100];
+        let bus = cpu.mmu.bus.borrow();
+        bus.read_phys_bytes(DRAM_BASE + 0x5f00, &mut written)
+            .unwrap();
+        assert_eq!(written, &data[..0x100]);
+        assert_eq!(bus.read::<u64>(DRAM_BASE + 0x3000), Ok(0));
+        drop(bus);
+
+        let mut buf = vec![0; 0x1200];
+        let read = cpu.mmu.read_bytes(&cpu.state, start, &mut buf);
+        assert_eq!(read, Err(Exception::LoadPageFault(DATA + 0x1000)));
+        assert_eq!(&buf[..0x100], &data[..0x100]);
+        assert!(buf[0x100..].iter().all(|b| *b == 0));
+
+        // 映射中间的页之后整段复制成功，跨页的部分落在各自的物理页。
+        tables.map(&mut cpu, DATA + 0x1000, DRAM_BASE + 0x7000, flags);
+        cpu.mmu.write_bytes(&cpu.state, start, &data).unwrap();
+        cpu.mmu.read_bytes(&cpu.state, start, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        let bus = cpu.mmu.bus.borrow();
+        assert_eq!(bus.read::<u8>(DRAM_BASE + 0x7000), Ok(data[0x100]));
+        assert_eq!(bus.read::<u8>(DRAM_BASE + 0x3000), Ok(data[0x1100]));
+    }
+
+    #[test]
+    fn satp_written_by_the_guest_takes_effect() {
+        let program = [
+            lw(10, 11, 0),
+            csrrw(0, 0x180, 12),
+            SFENCE_VMA,
+            lw(13, 11, 0),
+            EBREAK,
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        let mut tables = PageTables::new(TABLES);
+        tables.map(
+            &mut cpu,
+            DRAM_BASE,
+            DRAM_BASE,
+            PTE_V | PTE_R | PTE_X | PTE_A,
+        );
+        let flags = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        tables.map(&mut cpu, DRAM_BASE + 0x2000, DRAM_BASE + 0x3000, flags);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u32>(DRAM_BASE + 0x2000, 0x11)
+            .unwrap();
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u32>(DRAM_BASE + 0x3000, 0x22)
+            .unwrap();
+        cpu.state.xs.set_reg(11, DRAM_BASE + 0x2000);
+        cpu.state.xs.set_reg(12, tables.satp());
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.run_until_ebreak(10);
+        // 先是 bare，之后按 Sv39 翻译。
+        assert_eq!(cpu.state.xs.reg(10), 0x11);
+        assert_eq!(cpu.state.xs.reg(13), 0x22);
+
+        // M 模式不翻译，satp 是什么都一样。
+        let mut cpu = Cpu::with_program(XLen::X64, &[lw(10, 11, 0), EBREAK]);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u32>(DRAM_BASE + 0x2000, 0x11)
+            .unwrap();
+        cpu.state.csrs.set_satp(tables.satp());
+        cpu.state.xs.set_reg(11, DRAM_BASE + 0x2000);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.xs.reg(10), 0x11);
+    }
+
+    const MPP_S: RegT = 1 << 11;
+    const MPRV: RegT = 1 << 17;
+    const SUM: RegT = 1 << 18;
+    const MXR: RegT = 1 << 19;
+
+    fn page_fault(a_type: AccessType, addr: u64) -> Exception {
+        match a_type {
+            AccessType::Load => Exception::LoadPageFault(addr),
+            AccessType::Store | AccessType::Amo => Exception::StorePageFault(addr),
+            AccessType::Fetch => Exception::InstructionPageFault(addr),
+        }
+    }
+
+    #[test]
+    fn user_pages_follow_the_privilege_sum_and_mxr() {
+        use AccessType::*;
+        use PrivilegeMode::*;
+
+        let s_page = DATA;
+        let u_page = DATA + 0x1000;
+        let x_page = DATA + 0x2000;
+        let (mut cpu, mut tables) = sv39_cpu(&[EBREAK]);
+        let rwx = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+        tables.map(&mut cpu, s_page, DRAM_BASE + 0x2000, rwx);
+        tables.map(&mut cpu, u_page, DRAM_BASE + 0x3000, rwx | PTE_U);
+        tables.map(&mut cpu, x_page, DRAM_BASE + 0x4000, PTE_V | PTE_X | PTE_A);
+        let cases = [
+            // (特权级, mstatus, 页, 访问类型, 是否允许)
+            (User, 0, s_page, Load, false),
+            (User, 0, s_page, Fetch, false),
+            (User, SUM, s_page, Store, false),
+            (User, 0, u_page, Load, true),
+            (User, 0, u_page, Store, true),
+            (User, 0, u_page, Fetch, true),
+            (Supervisor, 0, s_page, Store, true),
+            (Supervisor, 0, u_page, Load, false),
+            (Supervisor, 0, u_page, Store, false),
+            (Supervisor, SUM, u_page, Load, true),
+            (Supervisor, SUM, u_page, Store, true),
+            (Supervisor, SUM, u_page, Fetch, false),
+            (Supervisor, 0, x_page, Load, false),
+            (Supervisor, MXR, x_page, Load, true),
+            (Supervisor, MXR, x_page, Store, false),
+            (Supervisor, MXR, x_page, Fetch, true),
+        ];
+        for &(privilege, mstatus, page, a_type, allowed) in cases.iter() {
+            cpu.state.privilege = privilege;
+            cpu.state.csrs.set_csr(0x300, mstatus);
+            let result = cpu.mmu.walk(&cpu.state, page + 8, &a_type);
+            assert_eq!(
+                result.is_ok(),
+                allowed,
+                "{:?} of {:#x} in {:?} with mstatus {:#x}: {:?}",
+                a_type,
+                page,
+                privilege,
+                mstatus,
+                result
+            );
+            if !allowed {
+                assert_eq!(result, Err(page_fault(a_type, page + 8)));
+            }
+        }
+    }
+
+    #[test]
+    fn page_faults_report_the_faulting_address_in_mtval() {
+        // CODE 之后的页没有映射。
+        let next_page = CODE + PAGE_SIZE;
+        let program = [lw(10, 11, 8), jal(0, PAGE_SIZE as i32)];
+        let cases = [(CODE, 13, DATA + 8), (CODE + 4, 12, next_page + 4)];
+        for &(pc, mcause, mtval) in cases.iter() {
+            let (mut cpu, _) = sv39_cpu(&program);
+            cpu.state.xs.set_reg(11, DATA);
+            cpu.state.pc = pc;
+
+            cpu.run_until_ebreak(10);
+            assert_eq!(cpu.state.pc, MTVEC);
+            assert_eq!(cpu.state.csrs.mcause(), mcause);
+            assert_eq!(cpu.state.csrs.mtval(), mtval);
+        }
+    }
+
+    #[test]
+    fn unimplemented_satp_modes_fault_every_access() {
+        // 快照恢复时 satp 不经过 legalize，可能是 Sv48。
+        let (mut cpu, tables) = sv39_cpu(&[EBREAK]);
+        let mut saved = Vec::new();
+        cpu.state.csrs.save(&mut saved).unwrap();
+        let satp = 9 << 60 | tables.satp() & !(0xf << 60);
+        saved[0x180 * 8..0x181 * 8].copy_from_slice(&satp.to_le_bytes());
+        cpu.state.csrs.restore(&mut &saved[..]).unwrap();
+        assert_eq!(cpu.state.csrs.satp().mode(&XLen::X64), Mode::Sv48);
+        for a_type in [AccessType::Load, AccessType::Store, AccessType::Fetch] {
+            let result = cpu.mmu.walk(&cpu.state, CODE, &a_type);
+            assert_eq!(result, Err(page_fault(a_type, CODE)));
+        }
+    }
+
+    /// The root of the tables `malformed_tables_fault` and `random_tables_translate_or_fault`
+    /// write, followed by a level 1 and a level 0 table.
+    const ROOT: u64 = DRAM_BASE + 0x20_0000;
+
+    /// A cpu in S-mode with SUM set, translating through the tables at `ROOT`.
+    fn walk_cpu() -> Cpu {
+        let (mut cpu, _) = sv39_cpu(&[EBREAK]);
+        cpu.state.csrs.set_satp(8 << 60 | ROOT >> 12);
+        cpu.state.csrs.set_csr(0x300, SUM);
+        cpu
+    }
+
+    /// Writes the root, level 1 and level 0 PTEs `ptes[2]`, `ptes[1]` and `ptes[0]` the walk of
+    /// `v_addr` reads, walks it and clears them again.
+    fn walk_through(
+        cpu: &Cpu,
+        ptes: [u64; 3],
+        v_addr: u64,
+        a_type: AccessType,
+    ) -> Result<u64, Exception> {
+        let addrs: Vec<u64> = (0..3)
+            .map(|level| ROOT + (2 - level) * PAGE_SIZE + (v_addr >> (12 + 9 * level) & 0x1ff) * 8)
+            .collect();
+        for (addr, pte) in addrs.iter().zip(ptes.iter()) {
+            cpu.mmu.bus.borrow_mut().write::<u64>(*addr, *pte).unwrap();
+        }
+        let result = cpu.mmu.walk(&cpu.state, v_addr, &a_type);
+        for addr in addrs.iter() {
+            cpu.mmu.bus.borrow_mut().write::<u64>(*addr, 0).unwrap();
+        }
+        result
+    }
+
+    /// A PTE pointing to the table after the one at `level`.
+    fn pointer(level: u64) -> u64 {
+        (ROOT + (3 - level) * PAGE_SIZE) >> 12 << 10 | PTE_V
+    }
+
+    #[test]
+    fn malformed_tables_fault() {
+        let cpu = walk_cpu();
+        let rwx = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+        // A 2 MiB page at 0x8020_0000.
+        let mega = 0x8020_0000 >> 12 << 10 | rwx;
+        let cases = [
+            // (PTEs, virtual address, physical address if it translates)
+            ([0, mega, pointer(2)], 0x1234_5678, Some(0x8034_5678)),
+            // The PPN has bits of the 4 KiB page set.
+            ([0, mega | 1 << 10, pointer(2)], 0x1234_5678, None),
+            ([0, mega | 1 << 54, pointer(2)], 0x1234_5678, None),
+            // PBMT and N.
+            ([0, mega, pointer(2) | 1 << 61], 0x1234_5678, None),
+            ([0, mega | 1 << 63, pointer(2)], 0x1234_5678, None),
+            // Bits 63:39 must be copies of bit 38.
+            ([0, mega, pointer(2)], 0x40_1234_5678, None),
+            (
+                [0, mega, pointer(2)],
+                0xffff_ffc0_1234_5678,
+                Some(0x8034_5678),
+            ),
+            // A pointer at level 0.
+            ([pointer(1), pointer(1), pointer(2)], 0x1234_5678, None),
+        ];
+        for &(ptes, v_addr, p_addr) in cases.iter() {
+            let expected = p_addr.ok_or(Exception::LoadPageFault(v_addr));
+            let result = walk_through(&cpu, ptes, v_addr, AccessType::Load);
+            assert_eq!(result, expected, "{:#x} through {:#x?}", v_addr, ptes);
+        }
+    }
+
+    /// What the privileged spec says the walk of `v_addr` through `ptes` gives in S-mode with SUM
+    /// set, pointers leading to the next table. None for a page fault.
+    fn spec_walk(ptes: [u64; 3], v_addr: u64, a_type: AccessType) -> Option<u64> {
+        if (v_addr as i64) << 25 >> 25 != v_addr as i64 {
+            return None;
+        }
+        for level in (0..3).rev() {
+            let pte = ptes[level];
+            if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W || pte >> 54 != 0 {
+                return None;
+            }
+            if pte & (PTE_R | PTE_X) == 0 {
+                continue;
+            }
+            let allowed = match a_type {
+                AccessType::Load => pte & PTE_R != 0,
+                AccessType::Store => pte & PTE_W != 0,
+                AccessType::Fetch => pte & PTE_X != 0 && pte & PTE_U == 0,
+                AccessType::Amo => unreachable!(),
+            };
+            let page_mask = (1 << (12 + 9 * level)) - 1;
+            let p_addr = (pte >> 10 & ((1 << 44) - 1)) << 12;
+            if !allowed || p_addr & page_mask != 0 {
+                return None;
+            }
+            return Some(p_addr | (v_addr & page_mask));
+        }
+        None
+    }
+
+    #[test]
+    fn random_tables_translate_or_fault() {
+        // xorshift64.
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let cpu = walk_cpu();
+        for _ in 0..5000 {
+            let mut ptes = [0; 3];
+            for (level, pte) in ptes.iter_mut().enumerate() {
+                let bits = random();
+                // Mostly well-formed entries, each with a chance of a reserved bit.
+                let reserved = if bits % 8 == 0 {
+                    1 << (54 + bits % 10)
+                } else {
+                    0
+                };
+                *pte = match bits >> 60 {
+                    0..=5 => pointer(level as u64) | bits & 0xf0,
+                    6..=12 => {
+                        // Aligned superpages, half of the time.
+                        let ppn = random() & ((1 << 44) - 1);
+                        let ppn = if bits & 1 << 59 != 0 {
+                            ppn >> (9 * level) << (9 * level)
+                        } else {
+                            ppn
+                        };
+                        // Without R or X the entry would be a pointer.
+                        let flags = match bits & (PTE_R | PTE_X) {
+                            0 => bits & 0xff | PTE_X,
+                            _ => bits & 0xff,
+                        };
+                        ppn << 10 | flags | PTE_V
+                    }
+                    // Anything but a pointer, which may lead anywhere.
+                    _ if bits & 0xf == PTE_V => bits & !PTE_V,
+                    _ => bits,
+                } | reserved;
+            }
+            let v_addr = match random() % 4 {
+                0 => random(),
+                _ => ((random() as i64) << 25 >> 25) as u64,
+            };
+            let a_type =
+                [AccessType::Load, AccessType::Store, AccessType::Fetch][random() as usize % 3];
+            let expected = spec_walk(ptes, v_addr, a_type).ok_or(page_fault(a_type, v_addr));
+            let result = walk_through(&cpu, ptes, v_addr, a_type);
+            assert_eq!(
+                result, expected,
+                "{:?} of {:#x} through {:#x?}",
+                a_type, v_addr, ptes
+            );
+        }
+    }
+
+    #[test]
+    fn probe_agrees_with_translate() {
+        let cpu = walk_cpu();
+        let rw = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        let rwx = rw | PTE_X;
+        // 0x1000_0000 is a 4 KiB page at DRAM_BASE + 0x3000, 0x2000_0000 a 2 MiB one at
+        // 0x8020_0000.
+        let ptes = [
+            (ROOT, pointer(2)),
+            (ROOT + 0x1000 + 0x80 * 8, pointer(1)),
+            (ROOT + 0x2000, (DRAM_BASE + 0x3000) >> 12 << 10 | rw),
+            (ROOT + 0x1000 + 0x100 * 8, 0x8020_0000 >> 12 << 10 | rwx),
+        ];
+        for &(addr, pte) in ptes.iter() {
+            cpu.mmu.bus.borrow_mut().write::<u64>(addr, pte).unwrap();
+        }
+        let cases = [
+            (0x1000_0123, AccessType::Store, 3, PAGE_SIZE),
+            (0x2012_3456, AccessType::Fetch, 2, 0x20_0000),
+            (0x1000_0123, AccessType::Fetch, 3, PAGE_SIZE),
+            (0x3000_0000, AccessType::Load, 2, PAGE_SIZE),
+        ];
+        for &(v_addr, a_type, levels, page_size) in cases.iter() {
+            let translated = cpu.mmu.translate(&cpu.state, v_addr, 1, a_type);
+            let probed = cpu.mmu.probe(&cpu.state, v_addr, a_type);
+            assert_eq!(
+                probed.clone().map(|info| info.p_addr),
+                translated.map(|ctx| ctx.p_addr),
+                "{:?} of {:#x}",
+                a_type,
+                v_addr
+            );
+            let (walk, _) = cpu.mmu.trace(&cpu.state, v_addr, a_type);
+            assert_eq!(walk.len(), levels);
+            assert_eq!(walk[0].pte_addr, ROOT);
+            if let Ok(info) = probed {
+                assert_eq!(info.page_size, page_size);
+                assert_eq!(info.walk, walk);
+                assert_eq!(info.flags(), Some(walk[levels - 1].pte as u8));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "fatal exception: Store/AMO page fault")]
+    fn store_page_faults_stop_the_emulator() {
+        let (mut cpu, _) = sv39_cpu(&[sw(10, 11, 8)]);
+        cpu.state.xs.set_reg(11, DATA);
+        cpu.run_until_ebreak(10);
+    }
+
+    /// A cpu in M-mode running `program` bare, with `DATA` mapped for S-mode and `mstatus` set.
+    fn mprv_cpu(program: &[u32], mstatus: RegT, flags: u64) -> Cpu {
+        let (mut cpu, mut tables) = sv39_cpu(program);
+        tables.map(&mut cpu, DATA, DRAM_BASE + 0x2000, flags);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u64>(DRAM_BASE + 0x2008, 0x1234)
+            .unwrap();
+        cpu.state.privilege = PrivilegeMode::Machine;
+        cpu.state.pc = DRAM_BASE;
+        cpu.state.csrs.set_csr(0x300, mstatus);
+        cpu.state.xs.set_reg(11, DATA);
+        cpu.state.xs.set_reg(12, 0x5678);
+        cpu
+    }
+
+    #[test]
+    fn mprv_translates_loads_and_stores_at_mpp() {
+        let flags = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        let mut cpu = mprv_cpu(
+            &[ld(10, 11, 8), sd(12, 11, 16), EBREAK],
+            MPRV | MPP_S,
+            flags,
+        );
+
+        // 取指不受 MPRV 影响，程序仍然在物理地址上运行。
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 8);
+        assert_eq!(cpu.state.xs.reg(10), 0x1234);
+        assert_eq!(
+            cpu.mmu.bus.borrow().read::<u64>(DRAM_BASE + 0x2010),
+            Ok(0x5678)
+        );
+    }
+
+    #[test]
+    fn mprv_checks_the_permissions_of_mpp() {
+        let s_page = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        let cases = [
+            // MPP 为 U，不能访问 S 页。
+            (MPRV, s_page, false),
+            (MPRV, s_page | PTE_U, true),
+            // MPP 为 S，访问 U 页要 SUM。
+            (MPRV | MPP_S, s_page | PTE_U, false),
+            (MPRV | MPP_S | SUM, s_page | PTE_U, true),
+        ];
+        for &(mstatus, flags, allowed) in cases.iter() {
+            let mut cpu = mprv_cpu(&[ld(10, 11, 8), EBREAK], mstatus, flags);
+            cpu.run_until_ebreak(10);
+            if allowed {
+                assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+                assert_eq!(cpu.state.xs.reg(10), 0x1234);
+                continue;
+            }
+            assert_eq!(cpu.state.pc, MTVEC);
+            assert_eq!(cpu.state.csrs.mcause(), 13);
+            assert_eq!(cpu.state.xs.reg(10), 0);
+        }
+    }
+
+    #[test]
+    fn mret_to_a_lower_mode_clears_mprv() {
+        for &(mpp, mprv) in [(MPP_S, 0), (3 << 11, MPRV)].iter() {
+            let mut cpu = Cpu::with_program(XLen::X64, &[MRET, EBREAK]);
+            cpu.state.csrs.set_csr(0x300, MPRV | mpp);
+            cpu.state.csrs.set_csr(0x341, DRAM_BASE + 4);
+            cpu.run_until_ebreak(10);
+            assert_eq!(cpu.state.csrs.csr(0x300) & MPRV, mprv, "{:#x}", mpp);
+        }
+    }
+
+    /// 按虚拟地址应答的调试设备。
+    struct VirtualEcho;
+
+    impl Device for VirtualEcho {
+        fn read(&self, offset: u64, _size: usize) -> Result<u64, Exception> {
+            Ok(offset)
+        }
+
+        fn write(&mut self, _offset: u64, _size: usize, _value: u64) -> Result<(), Exception> {
+            Ok(())
+        }
+
+        fn read_access(
+            &self,
+            ctx: &AccessContext,
+            _offset: u64,
+            _size: usize,
+        ) -> Result<u64, Exception> {
+            Ok(ctx.v_addr)
+        }
+    }
+
+    #[test]
+    fn access_hooks_see_the_virtual_and_physical_addresses() {
+        // DATA 映射到内存，DATA + 0x1000 映射到设备。
+        const ECHO: u64 = 0x4000_0000;
+        let program = [sd(10, 11, 0x10), ld(12, 11, 0x18), ld(13, 14, 8)];
+        let (mut cpu, mut tables) = sv39_cpu(&program);
+        let flags = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        tables.map(&mut cpu, DATA, DRAM_BASE + 0x2000, flags);
+        tables.map(&mut cpu, DATA + 0x1000, ECHO, flags);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .register_device(ECHO, 0x1000, None, Box::new(VirtualEcho));
+        cpu.state.xs.set_reg(11, DATA);
+        cpu.state.xs.set_reg(14, DATA + 0x1000);
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        cpu.add_access_hook(Box::new(accesses.clone()));
+        for _ in 0..program.len() {
+            cpu.one_step();
+        }
+        // 设备能看到虚拟地址，peek 不经过钩子。
+        assert_eq!(cpu.state.xs.reg(13), DATA + 0x1008);
+        assert_eq!(cpu.mmu.peek::<u64>(&cpu.state, DATA + 0x10), Ok(0));
+
+        let access = |v_addr, p_addr, size, a_type| AccessContext {
+            v_addr,
+            p_addr,
+            size,
+            a_type,
+            privilege: PrivilegeMode::Supervisor,
+        };
+        let fetch = |offset| access(CODE + offset, DRAM_BASE + offset, 4, AccessType::Fetch);
+        assert_eq!(
+            *accesses.borrow(),
+            [
+                fetch(0),
+                access(DATA + 0x10, DRAM_BASE + 0x2010, 8, AccessType::Store),
+                fetch(4),
+                access(DATA + 0x18, DRAM_BASE + 0x2018, 8, AccessType::Load),
+                fetch(8),
+                access(DATA + 0x1008, ECHO + 8, 8, AccessType::Load),
+            ]
+        );
+    }
 }
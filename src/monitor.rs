@@ -0,0 +1,925 @@
+//! A monitor for inspecting and controlling a running machine. Stdin belongs to the guest console,
+//! so commands are read line by line from clients of a unix socket, e.g.
+//! `socat - UNIX-CONNECT:<path>`. Receiving a command pauses the machine until `continue`.
+
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+};
+
+use crate::{
+    cpu::{Cpu, StopReason},
+    hook::AccessType,
+    register::csrs::{self, Csrs},
+    reverse::{History, ReverseStop},
+    trap::Trap,
+    watchpoint::{WatchHit, WatchKind},
+    RegT,
+};
+
+const HELP: &str = "\
+info registers    print the integer registers, pc and privilege mode
+x/Nx ADDR         dump N words of guest memory at virtual address or symbol ADDR
+csr NAME          print a CSR by name or by number, e.g. mstatus or 0x300
+translate ADDR [load|store|fetch]
+                  walk the page tables for virtual address or symbol ADDR, a load by default
+step [N]          execute N instructions, 1 by default
+continue          resume the machine
+reverse-step [N]  go back N steps, 1 by default, with --checkpoint-every
+reverse-continue  go back to the last breakpoint or watched access, with --checkpoint-every
+break ADDR        pause when pc reaches the address or symbol ADDR
+watch ADDR [LEN] [r|w|rw]
+                  pause after the guest writes (or reads) LEN bytes at ADDR, 8 by default
+unwatch ADDR      remove the watchpoints at ADDR
+dump-state        print pc, privilege, the integer registers and the trap CSRs as JSON
+snapshot save PATH
+                  save the machine state to the file PATH
+snapshot checkpoint PATH
+                  save a checkpoint to the file PATH, holding only the memory written since the
+                  previous checkpoint if there is one
+snapshot load PATH
+                  restore the machine state from a snapshot file, after the checkpoints it's
+                  based on
+reset             reset the machine to the state it booted in when it resumes
+quit              stop the emulator
+";
+
+const NO_HISTORY: &str = "no history to go back in, see --checkpoint-every";
+
+/// The socket is only polled every this many steps while the machine runs.
+const POLL_INTERVAL: u32 = 4096;
+
+/// What the run loop should do after a command.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Stay paused and wait for the next command.
+    Pause,
+    /// Execute the given number of instructions, then pause again.
+    Step(u64),
+    Continue,
+    Quit,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RunState {
+    Running,
+    Paused,
+    Stepping(u64),
+}
+
+pub struct Monitor {
+    listener: UnixListener,
+    client: Option<UnixStream>,
+    /// Bytes received from the client that don't form a complete line yet.
+    pending: Vec<u8>,
+    breakpoints: BTreeSet<RegT>,
+    run_state: RunState,
+    /// Set when resuming from a breakpoint so that it doesn't fire again right away.
+    skip_breakpoint: bool,
+    polls: u32,
+    /// The path of the last checkpoint saved, the parent of the next one.
+    last_checkpoint: Option<String>,
+    /// The checkpoints reverse-step and reverse-continue go back to, see `record_history`.
+    history: Option<History>,
+}
+
+impl Monitor {
+    /// Listens for clients on a unix socket at `path`, replacing a stale socket file.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            client: None,
+            pending: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            run_state: RunState::Running,
+            skip_breakpoint: false,
+            polls: 0,
+            last_checkpoint: None,
+            history: None,
+        })
+    }
+
+    /// Keeps a checkpoint every `interval` steps from now on, for reverse-step and
+    /// reverse-continue. Going back replays the steps since a checkpoint, so the run must be
+    /// deterministic.
+    pub fn record_history(&mut self, interval: u64) {
+        self.history = Some(History::new(interval));
+    }
+
+    /// Called by the run loop before every step. Returns false once the emulator should stop.
+    pub fn before_step(&mut self, cpu: &mut Cpu) -> io::Result<bool> {
+        if let Some(history) = self.history.as_mut() {
+            history.record(cpu)?;
+        }
+        match self.run_state {
+            RunState::Running => {
+                let skip = std::mem::take(&mut self.skip_breakpoint);
+                if !skip && self.breakpoints.contains(&cpu.state.pc) {
+                    self.stop(cpu, "breakpoint hit")?;
+                } else {
+                    self.polls += 1;
+                    if self.polls < POLL_INTERVAL {
+                        return Ok(true);
+                    }
+                    self.polls = 0;
+                    match self.next_line(false)? {
+                        Some(line) => {
+                            self.run_state = RunState::Paused;
+                            if !self.handle(cpu, &line)? {
+                                return Ok(false);
+                            }
+                        }
+                        None => return Ok(true),
+                    }
+                }
+            }
+            RunState::Stepping(0) => self.stop(cpu, "stepped")?,
+            RunState::Stepping(n) => {
+                self.run_state = RunState::Stepping(n - 1);
+                return Ok(true);
+            }
+            RunState::Paused => {}
+        }
+        while self.run_state == RunState::Paused {
+            let line = self.next_line(true)?.unwrap_or_default();
+            if !self.handle(cpu, &line)? {
+                return Ok(false);
+            }
+        }
+        // The step that resumed the machine needs to be counted as well.
+        self.before_step(cpu)
+    }
+
+    /// Returns true while the step command runs, the run loop then executes exactly one
+    /// instruction per step, see `Machine::step_one`.
+    pub fn stepping(&self) -> bool {
+        matches!(self.run_state, RunState::Stepping(_))
+    }
+
+    /// Pauses the machine after `Cpu::one_step` asked to stop.
+    pub fn stopped(&mut self, cpu: &Cpu, reason: &StopReason) -> io::Result<()> {
+        match reason {
+            StopReason::Watchpoint(hit) => {
+                self.reply(format!("{}\n", describe_hit(cpu, hit)).as_bytes())?;
+                self.stop(cpu, "watchpoint hit")
+            }
+            StopReason::Exit(code) => {
+                self.reply(format!("guest exited with code {}\n", code).as_bytes())
+            }
+            StopReason::Fatal(report) => {
+                self.reply(format!("{}\n", report).as_bytes())?;
+                self.stop(cpu, "fatal exception")
+            }
+            StopReason::DoubleFault(report) => {
+                self.reply(format!("{}\n", report).as_bytes())?;
+                self.stop(cpu, "double fault")
+            }
+            StopReason::Reset => self.reply(b"guest reset the machine\n"),
+            StopReason::FuelExhausted(report) | StopReason::Timeout(report) => {
+                self.reply(format!("{}", report).as_bytes())?;
+                self.stop(cpu, "run limit reached")
+            }
+            StopReason::SuspectedHang(report) => {
+                self.reply(format!("{}", report).as_bytes())?;
+                self.stop(cpu, "suspected hang")
+            }
+        }
+    }
+
+    fn stop(&mut self, cpu: &Cpu, reason: &str) -> io::Result<()> {
+        self.run_state = RunState::Paused;
+        let msg = format!("{} at pc {}\n", reason, describe(cpu, cpu.state.pc));
+        self.reply(msg.as_bytes())
+    }
+
+    /// Executes a line received from the client and updates the run state. Returns false on quit.
+    fn handle(&mut self, cpu: &mut Cpu, line: &str) -> io::Result<bool> {
+        let mut out = Vec::new();
+        let action = self.execute(cpu, line, &mut out)?;
+        self.reply(&out)?;
+        match action {
+            Action::Pause => {}
+            Action::Step(n) => self.run_state = RunState::Stepping(n),
+            Action::Continue => self.run_state = RunState::Running,
+            Action::Quit => return Ok(false),
+        }
+        self.skip_breakpoint = action != Action::Pause;
+        Ok(true)
+    }
+
+    /// Executes a single command, writing its output to `out`. Doesn't depend on the transport,
+    /// so any stream of commands can drive it.
+    pub fn execute(
+        &mut self,
+        cpu: &mut Cpu,
+        line: &str,
+        out: &mut dyn Write,
+    ) -> io::Result<Action> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(Action::Pause),
+        };
+        let arg = words.next();
+        match (command, arg) {
+            ("info", Some("registers")) | ("info", Some("reg")) => {
+                writeln!(
+                    out,
+                    "pc   {:#018x}  privilege {:?}",
+                    cpu.state.pc, cpu.state.privilege
+                )?;
+                write!(out, "{}", cpu.state.xs)?;
+            }
+            ("csr", Some(arg)) => match csrs::csr_number(arg) {
+                Some(num) => {
+                    // A CSR given by number is printed under its name if it has one.
+                    let name = Csrs::name(num).unwrap_or(arg);
+                    let value = cpu.state.csrs.csr(num);
+                    match num {
+                        // mcause and scause are followed by the cause they hold, the address is
+                        // in xtval.
+                        0x142 | 0x342 => {
+                            let trap = Trap::from_cause_code(value, cpu.xlen);
+                            writeln!(out, "{} {:#x} ({})", name, value, trap.name())?
+                        }
+                        _ => writeln!(out, "{} {:#x}", name, value)?,
+                    }
+                }
+                None => writeln!(out, "unknown csr {}", arg)?,
+            },
+            ("translate", Some(addr)) => {
+                let a_type = match words.next() {
+                    None | Some("load") => Some(AccessType::Load),
+                    Some("store") => Some(AccessType::Store),
+                    Some("fetch") => Some(AccessType::Fetch),
+                    Some(_) => None,
+                };
+                match (parse_addr(cpu, addr), a_type) {
+                    (Some(addr), Some(a_type)) => translate(cpu, addr, a_type, out)?,
+                    _ => writeln!(out, "usage: translate ADDR [load|store|fetch]")?,
+                }
+            }
+            ("step", None) => return Ok(Action::Step(1)),
+            ("step", Some(n)) => match parse_number(n) {
+                Some(n) => return Ok(Action::Step(n)),
+                None => writeln!(out, "invalid count {}", n)?,
+            },
+            ("continue", None) | ("c", None) => return Ok(Action::Continue),
+            ("reverse-step", n) => {
+                let count = n.map_or(Some(1), parse_number);
+                match (self.history.as_mut(), count) {
+                    (None, _) => writeln!(out, "{}", NO_HISTORY)?,
+                    (Some(history), Some(n)) => {
+                        let now = cpu.steps();
+                        let oldest = history.oldest().unwrap_or(now);
+                        let target = now.saturating_sub(n).max(oldest);
+                        match history.go_to(cpu, target) {
+                            Ok(()) => {
+                                let pc = describe(cpu, cpu.state.pc);
+                                writeln!(out, "stepped back {} steps to pc {}", now - target, pc)?
+                            }
+                            Err(e) => writeln!(out, "cannot step back: {}", e)?,
+                        }
+                    }
+                    (Some(_), None) => writeln!(out, "invalid count {}", n.unwrap_or_default())?,
+                }
+            }
+            ("reverse-continue", None) => match self.history.as_mut() {
+                None => writeln!(out, "{}", NO_HISTORY)?,
+                Some(history) => match history.reverse_continue(cpu, &self.breakpoints) {
+                    Ok(stop) => {
+                        let pc = describe(cpu, cpu.state.pc);
+                        match stop {
+                            ReverseStop::Breakpoint => {
+                                writeln!(out, "breakpoint hit at pc {}", pc)?
+                            }
+                            ReverseStop::Watchpoint(hit) => {
+                                writeln!(out, "{}", describe_hit(cpu, &hit))?;
+                                writeln!(out, "watchpoint hit by the next step at pc {}", pc)?
+                            }
+                            ReverseStop::Oldest => {
+                                writeln!(out, "reached the oldest checkpoint at pc {}", pc)?
+                            }
+                        }
+                    }
+                    Err(e) => writeln!(out, "cannot continue backwards: {}", e)?,
+                },
+            },
+            ("break", Some(addr)) => match parse_addr(cpu, addr) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    writeln!(out, "breakpoint at {}", describe(cpu, addr))?;
+                }
+                None => writeln!(out, "invalid address {}", addr)?,
+            },
+            ("watch", Some(addr)) => {
+                let len = words.next().map_or(Some(8), parse_number);
+                let kind = match words.next() {
+                    None | Some("w") => Some(WatchKind::Write),
+                    Some("r") => Some(WatchKind::Read),
+                    Some("rw") => Some(WatchKind::Access),
+                    Some(_) => None,
+                };
+                match (parse_addr(cpu, addr), len, kind) {
+                    (Some(addr), Some(len), Some(kind)) => {
+                        cpu.add_watchpoint(addr, len, kind);
+                        writeln!(
+                            out,
+                            "watchpoint at {} len {} {:?}",
+                            describe(cpu, addr),
+                            len,
+                            kind
+                        )?;
+                    }
+                    _ => writeln!(out, "usage: watch ADDR [LEN] [r|w|rw]")?,
+                }
+            }
+            ("unwatch", Some(addr)) => match parse_addr(cpu, addr) {
+                Some(addr) if cpu.mmu.watchpoints.remove(addr) => {
+                    writeln!(out, "removed watchpoints at {:#x}", addr)?
+                }
+                _ => writeln!(out, "no watchpoint at {}", addr)?,
+            },
+            #[cfg(feature = "json")]
+            ("dump-state", None) => writeln!(out, "{}", cpu.state_json())?,
+            ("snapshot", Some("save")) => match words.next() {
+                Some(path) => match save_snapshot(cpu, Path::new(path)) {
+                    Ok(()) => writeln!(out, "saved snapshot to {}", path)?,
+                    Err(e) => writeln!(out, "cannot save snapshot to {}: {}", path, e)?,
+                },
+                None => writeln!(out, "usage: snapshot save|load PATH")?,
+            },
+            ("snapshot", Some("checkpoint")) => match words.next() {
+                // Both take the pages written since their last checkpoint.
+                Some(_) if self.history.is_some() => writeln!(
+                    out,
+                    "cannot save a checkpoint while the history for reverse-step is recorded"
+                )?,
+                Some(path) => {
+                    let parent = self.last_checkpoint.as_deref();
+                    match save_checkpoint(cpu, Path::new(path), parent) {
+                        Ok(()) => {
+                            match parent {
+                                Some(parent) => {
+                                    writeln!(out, "saved checkpoint to {} after {}", path, parent)?
+                                }
+                                None => writeln!(out, "saved checkpoint to {}", path)?,
+                            }
+                            self.last_checkpoint = Some(path.to_string());
+                        }
+                        Err(e) => writeln!(out, "cannot save checkpoint to {}: {}", path, e)?,
+                    }
+                }
+                None => writeln!(out, "usage: snapshot checkpoint PATH")?,
+            },
+            ("snapshot", Some("load")) => match words.next() {
+                Some(path) => match cpu.load_snapshot_file(path) {
+                    Ok(()) => {
+                        if let Some(history) = self.history.as_mut() {
+                            history.clear();
+                        }
+                        let pc = describe(cpu, cpu.state.pc);
+                        writeln!(out, "restored snapshot from {}, pc {}", path, pc)?
+                    }
+                    Err(e) => writeln!(out, "cannot restore snapshot from {}: {}", path, e)?,
+                },
+                None => writeln!(out, "usage: snapshot save|load PATH")?,
+            },
+            ("reset", None) => {
+                cpu.request_reset();
+                writeln!(out, "the machine resets when it resumes")?;
+            }
+            ("quit", None) | ("q", None) => return Ok(Action::Quit),
+            ("help", None) => write!(out, "{}", HELP)?,
+            (x, Some(addr)) if x.starts_with("x/") && x.ends_with('x') => {
+                let count = &x[2..x.len() - 1];
+                let count = if count.is_empty() {
+                    Some(1)
+                } else {
+                    parse_number(count)
+                };
+                match (count, parse_addr(cpu, addr)) {
+                    (Some(count), Some(addr)) => dump_memory(cpu, addr, count, out)?,
+                    _ => writeln!(out, "usage: x/Nx ADDR")?,
+                }
+            }
+            _ => writeln!(out, "unknown command: {}, try help", line.trim())?,
+        }
+        Ok(Action::Pause)
+    }
+
+    /// Returns the next line from the client, waiting for a client and a line if `block` is set.
+    fn next_line(&mut self, block: bool) -> io::Result<Option<String>> {
+        loop {
+            if let Some(pos) = self.pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=pos).collect();
+                return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+            }
+            if self.client.is_none() {
+                self.listener.set_nonblocking(!block)?;
+                match self.listener.accept() {
+                    Ok((stream, _)) => self.client = Some(stream),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+            }
+            let client = self.client.as_mut().unwrap();
+            client.set_nonblocking(!block)?;
+            let mut buf = [0; 512];
+            match client.read(&mut buf) {
+                Ok(0) => {
+                    // Client went away, wait for the next one.
+                    self.client = None;
+                    self.pending.clear();
+                    if !block {
+                        return Ok(None);
+                    }
+                }
+                Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn reply(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(client) = self.client.as_mut() {
+            client.set_nonblocking(false)?;
+            if client.write_all(bytes).is_err() {
+                self.client = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Describes the access that hit a watchpoint.
+fn describe_hit(cpu: &Cpu, hit: &WatchHit) -> String {
+    let access = if hit.is_write { "write" } else { "read" };
+    let mut msg = format!(
+        "{} of {} bytes at {:#x} by {}",
+        access,
+        hit.size,
+        hit.addr,
+        describe(cpu, hit.pc)
+    );
+    if let Some(old) = hit.old {
+        msg += &format!(", old {:#x}", old);
+    }
+    msg + &format!(", value {:#x}", hit.value)
+}
+
+/// Saves a full snapshot of the machine to `path`, see `Cpu::save_snapshot`.
+fn save_snapshot(cpu: &Cpu, path: &Path) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    cpu.save_snapshot(&mut out)?;
+    out.flush()
+}
+
+/// Saves a checkpoint of the machine to `path`, see `Cpu::save_checkpoint`.
+fn save_checkpoint(cpu: &mut Cpu, path: &Path, parent: Option<&str>) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    cpu.save_checkpoint(&mut out, parent)?;
+    out.flush()
+}
+
+/// Dumps `count` 32-bit words at virtual address `addr`, four per line.
+fn dump_memory(cpu: &Cpu, addr: u64, count: u64, out: &mut dyn Write) -> io::Result<()> {
+    for i in 0..count {
+        let word_addr = addr.wrapping_add(i * 4);
+        if i % 4 == 0 {
+            write!(out, "{:#018x}:", word_addr)?;
+        }
+        match cpu.mmu.peek::<u32>(&cpu.state, word_addr) {
+            Ok(word) => write!(out, " {:#010x}", word)?,
+            Err(e) => {
+                return writeln!(out, "\ncannot access memory at {:#x}: {}", word_addr, e);
+            }
+        }
+        if i % 4 == 3 || i + 1 == count {
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the PTEs the walk of `addr` reads, then what it translates to or the fault it raises.
+fn translate(cpu: &Cpu, addr: u64, a_type: AccessType, out: &mut dyn Write) -> io::Result<()> {
+    let (walk, result) = cpu.mmu.trace(&cpu.state, addr, a_type);
+    for step in walk.iter() {
+        writeln!(
+            out,
+            "level {} pte at {:#x}: {:#x} {}",
+            step.level,
+            step.pte_addr,
+            step.pte,
+            pte_flags(step.pte)
+        )?;
+    }
+    match result {
+        Ok((p_addr, _)) if walk.is_empty() => {
+            writeln!(out, "{:#x} -> {:#x}, not translated", addr, p_addr)
+        }
+        Ok((p_addr, page_size)) => writeln!(
+            out,
+            "{:#x} -> {:#x} in a {} page",
+            addr,
+            p_addr,
+            format_size(page_size)
+        ),
+        Err(e) => writeln!(out, "{}", e),
+    }
+}
+
+/// The V, R, W, X, U, G, A and D bits of `pte` as `vrwxugad`, with a dash for each bit clear.
+fn pte_flags(pte: u64) -> String {
+    "vrwxugad"
+        .chars()
+        .enumerate()
+        .map(|(i, flag)| if pte >> i & 1 == 1 { flag } else { '-' })
+        .collect()
+}
+
+/// Formats a page size in KiB, MiB or GiB.
+fn format_size(size: u64) -> String {
+    match size {
+        size if size >= 1 << 30 => format!("{} GiB", size >> 30),
+        size if size >= 1 << 20 => format!("{} MiB", size >> 20),
+        size => format!("{} KiB", size >> 10),
+    }
+}
+
+/// Parses a hex number with a `0x` prefix or a decimal number.
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parses an address given as a number or a symbol name.
+fn parse_addr(cpu: &Cpu, s: &str) -> Option<u64> {
+    parse_number(s).or_else(|| cpu.symbols.lookup(s))
+}
+
+/// Formats `addr` along with the symbol covering it.
+fn describe(cpu: &Cpu, addr: u64) -> String {
+    match cpu.symbols.describe(addr) {
+        Some(symbol) => format!("{:#x} <{}>", addr, symbol),
+        None => format!("{:#x}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::StepOutcome, testing::*, PrivilegeMode, XLen};
+
+    #[test]
+    fn snapshot_load_goes_back_to_the_saved_state() {
+        let dir = std::env::temp_dir().join(format!("monitor-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshot = dir.join("counter.snap");
+        let mut monitor = Monitor::bind(dir.join("monitor.sock")).unwrap();
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        let mut execute = |cpu: &mut Cpu, line: &str| {
+            let mut out = Vec::new();
+            let action = monitor.execute(cpu, line, &mut out).unwrap();
+            assert_eq!(action, Action::Pause);
+            String::from_utf8(out).unwrap()
+        };
+
+        trace(&mut cpu, 10);
+        let save = format!("snapshot save {}", snapshot.display());
+        assert_eq!(
+            execute(&mut cpu, &save),
+            format!("saved snapshot to {}\n", snapshot.display())
+        );
+        let (pc, xs, memory) = (cpu.state.pc, registers(&cpu), counts(&cpu));
+        let first = trace(&mut cpu, 40);
+        assert_ne!(counts(&cpu), memory);
+
+        let load = format!("snapshot load {}", snapshot.display());
+        assert_eq!(
+            execute(&mut cpu, &load),
+            format!(
+                "restored snapshot from {}, pc {:#x}\n",
+                snapshot.display(),
+                pc
+            )
+        );
+        assert_eq!(cpu.state.pc, pc);
+        assert_eq!(registers(&cpu), xs);
+        assert_eq!(counts(&cpu), memory);
+        assert!(
+            trace(&mut cpu, 40) == first,
+            "the restored machine took another way"
+        );
+
+        let missing = dir.join("missing.snap");
+        let reply = execute(&mut cpu, &format!("snapshot load {}", missing.display()));
+        assert!(
+            reply.starts_with("cannot restore snapshot from"),
+            "{}",
+            reply
+        );
+        assert_eq!(
+            execute(&mut cpu, "snapshot save"),
+            "usage: snapshot save|load PATH\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_chain_to_the_previous_one() {
+        let dir = std::env::temp_dir().join(format!("monitor-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, second) = (dir.join("first.snap"), dir.join("second.snap"));
+        let mut monitor = Monitor::bind(dir.join("monitor.sock")).unwrap();
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        let mut execute = |cpu: &mut Cpu, line: &str| {
+            let mut out = Vec::new();
+            monitor.execute(cpu, line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        trace(&mut cpu, 10);
+        assert_eq!(
+            execute(
+                &mut cpu,
+                &format!("snapshot checkpoint {}", first.display())
+            ),
+            format!("saved checkpoint to {}\n", first.display())
+        );
+        trace(&mut cpu, 10);
+        assert_eq!(
+            execute(
+                &mut cpu,
+                &format!("snapshot checkpoint {}", second.display())
+            ),
+            format!(
+                "saved checkpoint to {} after {}\n",
+                second.display(),
+                first.display()
+            )
+        );
+        let (pc, memory) = (cpu.state.pc, counts(&cpu));
+        trace(&mut cpu, 40);
+
+        execute(&mut cpu, &format!("snapshot load {}", second.display()));
+        assert_eq!(cpu.state.pc, pc);
+        assert_eq!(counts(&cpu), memory);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reverse_continue_goes_back_to_the_watched_store() {
+        let dir = std::env::temp_dir().join(format!("monitor-reverse-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut monitor = Monitor::bind(dir.join("monitor.sock")).unwrap();
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        let execute = |monitor: &mut Monitor, cpu: &mut Cpu, line: &str| {
+            let mut out = Vec::new();
+            assert_eq!(monitor.execute(cpu, line, &mut out).unwrap(), Action::Pause);
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            execute(&mut monitor, &mut cpu, "reverse-step"),
+            format!("{}\n", NO_HISTORY)
+        );
+        monitor.record_history(8);
+        execute(&mut monitor, &mut cpu, "watch 0x80001008 4");
+        // Past the third store, into the watched word, as if the run went on after the stop.
+        for _ in 0..50 {
+            assert!(monitor.before_step(&mut cpu).unwrap());
+            cpu.one_step();
+        }
+        assert_eq!(
+            execute(&mut monitor, &mut cpu, "snapshot checkpoint /nonexistent"),
+            "cannot save a checkpoint while the history for reverse-step is recorded\n"
+        );
+
+        assert_eq!(
+            execute(&mut monitor, &mut cpu, "reverse-continue"),
+            "write of 4 bytes at 0x80001008 by 0x80000004, old 0x0, value 0x3\n\
+             watchpoint hit by the next step at pc 0x80000004\n"
+        );
+        assert_eq!(cpu.steps(), 9);
+        assert_eq!(counts(&cpu)[1], 0);
+        assert_eq!(
+            execute(&mut monitor, &mut cpu, "reverse-step 2"),
+            "stepped back 2 steps to pc 0x8000000c\n"
+        );
+        assert_eq!(cpu.state.xs.reg(10), 2);
+        assert_eq!(
+            execute(&mut monitor, &mut cpu, "reverse-step 100"),
+            "stepped back 7 steps to pc 0x80000000\n"
+        );
+        assert_eq!(cpu.state.xs.reg(10), 0);
+        assert_eq!(
+            execute(&mut monitor, &mut cpu, "reverse-continue"),
+            "reached the oldest checkpoint at pc 0x80000000\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn client_breaks_steps_and_quits() {
+        let dir = std::env::temp_dir().join(format!("monitor-client-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("monitor.sock");
+        let mut monitor = Monitor::bind(&socket).unwrap();
+        let mut client = UnixStream::connect(&socket).unwrap();
+        let commands = "break 0x80000008\ncontinue\ninfo registers\ncsr mscratch\nstep 2\nquit\n";
+        client.write_all(commands.as_bytes()).unwrap();
+
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        cpu.state.csrs.set_csr(0x340, 0x5a);
+        // The run loop of main, the commands are first read after POLL_INTERVAL steps.
+        let mut steps = 0;
+        let mut stepped = 0;
+        while monitor.before_step(&mut cpu).unwrap() {
+            if monitor.stepping() {
+                assert!(matches!(cpu.step_one(), StepOutcome::Retired));
+                stepped += 1;
+            } else {
+                assert!(cpu.one_step().is_none());
+            }
+            steps += 1;
+            assert!(steps < 2 * POLL_INTERVAL, "the monitor never quit");
+        }
+        assert_eq!(stepped, 2);
+        assert_eq!(cpu.state.pc, DRAM_BASE);
+        drop(monitor);
+
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        let lines: Vec<_> = replies.lines().collect();
+        assert!(
+            lines[0].starts_with("breakpoint at 0x80000008"),
+            "{}",
+            replies
+        );
+        assert!(
+            lines[1].starts_with("breakpoint hit at pc 0x80000008"),
+            "{}",
+            replies
+        );
+        assert_eq!(lines[2], "pc   0x0000000080000008  privilege Machine");
+        assert!(replies.contains("mscratch 0x5a\n"), "{}", replies);
+        assert!(
+            lines
+                .last()
+                .unwrap()
+                .starts_with("stepped at pc 0x80000000"),
+            "{}",
+            replies
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_commands_set_the_watchpoints() {
+        let dir = std::env::temp_dir().join(format!("monitor-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut monitor = Monitor::bind(dir.join("monitor.sock")).unwrap();
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        let mut execute = |cpu: &mut Cpu, line: &str| {
+            let mut out = Vec::new();
+            monitor.execute(cpu, line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            execute(&mut cpu, "watch 0x80001008 4"),
+            "watchpoint at 0x80001008 len 4 Write\n"
+        );
+        assert_eq!(
+            execute(&mut cpu, "watch 0x80001000 8 x"),
+            "usage: watch ADDR [LEN] [r|w|rw]\n"
+        );
+        let hit = loop {
+            if let Some(StopReason::Watchpoint(hit)) = cpu.one_step() {
+                break hit;
+            }
+        };
+        assert_eq!(
+            describe_hit(&cpu, &hit),
+            "write of 4 bytes at 0x80001008 by 0x80000004, old 0x0, value 0x3"
+        );
+
+        assert_eq!(
+            execute(&mut cpu, "unwatch 0x80001008"),
+            "removed watchpoints at 0x80001008\n"
+        );
+        assert_eq!(
+            execute(&mut cpu, "unwatch 0x80001008"),
+            "no watchpoint at 0x80001008\n"
+        );
+        // Further stores run through, `trace` checks that nothing stops them.
+        trace(&mut cpu, 20);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn csr_command_decodes_the_cause() {
+        let dir = std::env::temp_dir().join(format!("monitor-cause-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut monitor = Monitor::bind(dir.join("monitor.sock")).unwrap();
+        let mut cpu = Cpu::with_program(XLen::X32, &COUNTER);
+        cpu.state.csrs.set_csr(0x342, 0x8000_0007);
+        cpu.state.csrs.set_csr(0x142, 13);
+        let mut execute = |line: &str| {
+            let mut out = Vec::new();
+            monitor.execute(&mut cpu, line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            execute("csr mcause"),
+            "mcause 0x80000007 (Machine timer interrupt)\n"
+        );
+        assert_eq!(execute("csr scause"), "scause 0xd (Load page fault)\n");
+        // By number, printed under the name. The counters and PMP registers have names too.
+        assert_eq!(execute("csr 0x142"), "scause 0xd (Load page fault)\n");
+        assert_eq!(execute("csr pmpaddr15"), "pmpaddr15 0x0\n");
+        assert_eq!(execute("csr 0x7c0"), "0x7c0 0x0\n");
+        assert_eq!(
+            execute("x/1x 0x10"),
+            "0x0000000000000010:\ncannot access memory at 0x10: Load access fault at 0x10\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn translate_prints_the_walk() {
+        let dir = std::env::temp_dir().join(format!("monitor-translate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut monitor = Monitor::bind(dir.join("monitor.sock")).unwrap();
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        let mut execute = |cpu: &mut Cpu, line: &str| {
+            let mut out = Vec::new();
+            monitor.execute(cpu, line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            execute(&mut cpu, "translate 0x80000010"),
+            "0x80000010 -> 0x80000010, not translated\n"
+        );
+
+        let mut tables = PageTables::new(DRAM_BASE + 0x10_0000);
+        let flags = PTE_V | PTE_R | PTE_X | PTE_A;
+        let pte = tables.map(&mut cpu, 0x1000_0000, DRAM_BASE + 0x2000, flags);
+        cpu.state.csrs.set_satp(tables.satp());
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        let walk = format!(
+            "level 2 pte at 0x80100000: 0x20040401 v-------\n\
+             level 1 pte at 0x80101400: 0x20040801 v-------\n\
+             level 0 pte at {:#x}: 0x2000084b vr-x--a-\n",
+            pte
+        );
+        assert_eq!(
+            execute(&mut cpu, "translate 0x10000234 fetch"),
+            walk.clone() + "0x10000234 -> 0x80002234 in a 4 KiB page\n"
+        );
+        assert_eq!(
+            execute(&mut cpu, "translate 0x10000234 store"),
+            walk + "Store/AMO page fault at 0x10000234\n"
+        );
+        assert_eq!(
+            execute(&mut cpu, "translate 0x10000234 jump"),
+            "usage: translate ADDR [load|store|fetch]\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn dump_state_prints_the_state_json() {
+        let dir = std::env::temp_dir().join(format!("monitor-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut monitor = Monitor::bind(dir.join("monitor.sock")).unwrap();
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        trace(&mut cpu, 5);
+        let mut out = Vec::new();
+        monitor.execute(&mut cpu, "dump-state", &mut out).unwrap();
+        let state: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(state, cpu.state_json());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -10,7 +10,7 @@ impl PageTableEnty {
     // If non-leaf PTE. ppn points to the base address of the next level page table
     pub fn ppn(&self, mode: &Mode) -> u64 {
         match mode {
-            Mode::Sv32 => self.0.get_bits(10..),
+            Mode::Sv32 => self.0.get_bits(10..32),
             Mode::Sv39 => self.0.get_bits(10..54),
             _ => unimplemented!(),
         }
@@ -28,6 +28,16 @@ impl PageTableEnty {
         }
     }
 
+    /// Bits 63:54 of an Sv39 PTE: PBMT, N and the bits reserved for future use. Svpbmt and
+    /// Svnapot aren't implemented, so a PTE with any of them set is malformed. Sv32 has none.
+    pub fn reserved(&self, mode: &Mode) -> u64 {
+        match mode {
+            Mode::Sv32 => 0,
+            Mode::Sv39 => self.0.get_bits(54..64),
+            _ => unimplemented!(),
+        }
+    }
+
     /// V 位决定了该页表项的其余部分是否有效（V = 1 时有效）。若 V = 0，则任何遍历
     /// 到此页表项的虚址转换操作都会导致页错误。
     #[inline]
@@ -86,7 +96,7 @@ pub struct VirtualAddress(pub u64);
 impl VirtualAddress {
     pub fn virtual_page_offsets(&self, mode: &Mode) -> Vec<u64> {
         match mode {
-            Mode::Sv32 => vec![self.0.get_bits(12..21) << 2, self.0.get_bits(22..31) << 2],
+            Mode::Sv32 => vec![self.0.get_bits(12..22) << 2, self.0.get_bits(22..32) << 2],
             Mode::Sv39 => vec![
                 self.0.get_bits(12..21) << 3,
                 self.0.get_bits(21..30) << 3,
@@ -96,7 +106,14 @@ impl VirtualAddress {
         }
     }
 
-    pub fn offset(&self) -> u64 {
-        self.0.get_bits(0..12)
+    /// Sv39 addresses must have bits 63:39 all equal to bit 38, the others raise a page fault.
+    /// No address is canonical in the modes that aren't implemented, which a restored snapshot's
+    /// satp may still select.
+    pub fn is_canonical(&self, mode: &Mode) -> bool {
+        match mode {
+            Mode::Sv32 => true,
+            Mode::Sv39 => (self.0 as i64) << 25 >> 25 == self.0 as i64,
+            _ => false,
+        }
     }
 }
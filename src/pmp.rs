@@ -0,0 +1,420 @@
+use crate::{mmu::AccessType, PrivilegeMode, RegT};
+
+/// Number of PMP entries, pmpaddr0-15 configured by pmpcfg0-3.
+pub const PMP_ENTRIES: usize = 16;
+
+/// pmpcfg fields.
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0x3 << 3;
+const PMP_L: u8 = 1 << 7;
+
+/// Address-matching modes held in pmpcfg.A.
+const A_OFF: u8 = 0;
+const A_TOR: u8 = 1;
+const A_NA4: u8 = 2;
+const A_NAPOT: u8 = 3;
+
+/// mseccfg fields, the Smepmp machine-mode lockdown. MML and MMWP are sticky, only a reset
+/// clears them.
+pub const MSECCFG_MML: RegT = 1 << 0;
+pub const MSECCFG_MMWP: RegT = 1 << 1;
+pub const MSECCFG_RLB: RegT = 1 << 2;
+
+/// Returns the legal value of a pmpcfg byte: the reserved bits read as zero and the reserved
+/// combination W=1, R=0 loses W. Under mseccfg.MML that combination encodes the shared regions
+/// and is kept.
+pub fn legalize_cfg(cfg: u8, mml: bool) -> u8 {
+    let cfg = cfg & (PMP_R | PMP_W | PMP_X | PMP_A | PMP_L);
+    if cfg & PMP_R == 0 && !mml {
+        cfg & !PMP_W
+    } else {
+        cfg
+    }
+}
+
+/// Returns true if the pmpcfg byte locks its entry.
+pub fn is_locked(cfg: u8) -> bool {
+    cfg & PMP_L != 0
+}
+
+/// Returns true if the pmpcfg byte, under mseccfg.MML, adds a rule machine mode can execute
+/// from. Without mseccfg.RLB such writes are ignored.
+pub fn is_machine_executable(cfg: u8) -> bool {
+    mml_perms(
+        cfg & (PMP_R | PMP_W | PMP_X),
+        is_locked(cfg),
+        PrivilegeMode::Machine,
+    ) & PMP_X
+        != 0
+}
+
+/// The permissions an entry grants `privilege` under mseccfg.MML. Locked entries are M-mode-only
+/// rules and the others S/U-mode-only rules, except for the shared regions encoded by W=1, R=0
+/// and by a locked RWX.
+fn mml_perms(perms: u8, locked: bool, privilege: PrivilegeMode) -> u8 {
+    let machine = privilege == PrivilegeMode::Machine;
+    match (locked, perms) {
+        // Shared data, read-only for S/U-mode with X clear.
+        (false, PMP_W) if machine => PMP_R | PMP_W,
+        (false, PMP_W) => PMP_R,
+        (false, p) if p == PMP_W | PMP_X => PMP_R | PMP_W,
+        // Shared code, readable by machine mode with X set.
+        (true, PMP_W) => PMP_X,
+        (true, p) if p == PMP_W | PMP_X && machine => PMP_R | PMP_X,
+        (true, p) if p == PMP_W | PMP_X => PMP_X,
+        // Shared read-only data.
+        (true, p) if p == PMP_R | PMP_W | PMP_X => PMP_R,
+        (true, p) if machine => p,
+        (false, p) if !machine => p,
+        _ => 0,
+    }
+}
+
+/// Returns true if the pmpcfg byte selects top-of-range matching, which makes the previous
+/// pmpaddr the bottom of the range.
+pub fn is_tor(cfg: u8) -> bool {
+    (cfg & PMP_A) >> 3 == A_TOR
+}
+
+/// An active PMP entry, matching the physical addresses `start..=last`.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    start: u64,
+    last: u64,
+    perms: u8,
+    locked: bool,
+}
+
+/// Physical memory protection, the entries decoded from pmpcfg and pmpaddr. Rebuilt whenever
+/// one of those CSRs is written.
+#[derive(Clone, Debug, Default)]
+pub struct Pmp {
+    /// Active entries in priority order. TOR entries with an empty range are left out.
+    entries: Vec<Entry>,
+    /// Whether any entry is active. A hart without active entries behaves as if it had no PMP,
+    /// so that software unaware of PMP keeps working.
+    enabled: bool,
+    /// mseccfg.MML, entries bind either machine mode or S/U-mode.
+    mml: bool,
+    /// mseccfg.MMWP, machine-mode accesses matching no entry fail.
+    mmwp: bool,
+}
+
+impl Pmp {
+    /// Decodes the entries from the pmpcfg bytes and the pmpaddr registers, under the policy of
+    /// mseccfg.
+    pub fn update(&mut self, cfgs: &[u8; PMP_ENTRIES], addrs: &[RegT; PMP_ENTRIES], mseccfg: RegT) {
+        self.entries.clear();
+        self.enabled = false;
+        self.mml = mseccfg & MSECCFG_MML != 0;
+        self.mmwp = mseccfg & MSECCFG_MMWP != 0;
+        for (i, (&cfg, &addr)) in cfgs.iter().zip(addrs.iter()).enumerate() {
+            let (start, last) = match (cfg & PMP_A) >> 3 {
+                A_OFF => continue,
+                A_TOR => {
+                    self.enabled = true;
+                    let start = if i == 0 { 0 } else { addrs[i - 1] << 2 };
+                    let end = addr << 2;
+                    if start >= end {
+                        continue;
+                    }
+                    (start, end - 1)
+                }
+                A_NA4 => (addr << 2, (addr << 2) + 3),
+                A_NAPOT => {
+                    // The trailing ones of pmpaddr encode the size, 2^(ones + 3) bytes.
+                    let ones = addr.trailing_ones();
+                    let mask = if ones + 3 >= 64 {
+                        u64::MAX
+                    } else {
+                        (1 << (ones + 3)) - 1
+                    };
+                    ((addr << 2) & !mask, (addr << 2) | mask)
+                }
+                _ => unreachable!(),
+            };
+            self.enabled = true;
+            self.entries.push(Entry {
+                start,
+                last,
+                perms: cfg & (PMP_R | PMP_W | PMP_X),
+                locked: is_locked(cfg),
+            });
+        }
+    }
+
+    /// Returns true if an access of `size` bytes at the physical address `addr` is allowed.
+    ///
+    /// The lowest-numbered entry matching any byte of the access decides, and the access fails
+    /// if it doesn't cover all of them. Machine mode is only restricted by locked entries, and
+    /// S/U-mode accesses matching no entry fail. mseccfg.MML swaps in the rules of Smepmp, and
+    /// under mseccfg.MMWP machine-mode accesses matching no entry fail too.
+    pub fn check(
+        &self,
+        addr: u64,
+        size: usize,
+        privilege: PrivilegeMode,
+        a_type: &AccessType,
+    ) -> bool {
+        let last = addr.saturating_add(size as u64 - 1);
+        let perm = match a_type {
+            AccessType::Load => PMP_R,
+            AccessType::Store => PMP_W,
+            AccessType::Fetch => PMP_X,
+            AccessType::Amo => PMP_R | PMP_W,
+        };
+        match self
+            .entries
+            .iter()
+            .find(|entry| addr <= entry.last && last >= entry.start)
+        {
+            Some(entry) if addr < entry.start || last > entry.last => false,
+            Some(entry) if self.mml => {
+                mml_perms(entry.perms, entry.locked, privilege) & perm == perm
+            }
+            Some(entry) if privilege == PrivilegeMode::Machine && !entry.locked => true,
+            Some(entry) => entry.perms & perm == perm,
+            // Under MML machine mode can't execute from memory no entry covers.
+            None if privilege == PrivilegeMode::Machine => {
+                !self.mmwp && (!self.mml || perm != PMP_X)
+            }
+            None => !self.enabled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::Cpu, testing::*, XLen};
+
+    const NAPOT: u8 = A_NAPOT << 3;
+    const TOR: u8 = A_TOR << 3;
+
+    /// The pmpaddr of a NAPOT region of `size` bytes at `base`.
+    fn napot(base: u64, size: u64) -> RegT {
+        (base | (size / 2 - 1)) >> 2
+    }
+
+    fn pmp(entries: &[(u8, RegT)]) -> Pmp {
+        pmp_with(0, entries)
+    }
+
+    fn pmp_with(mseccfg: RegT, entries: &[(u8, RegT)]) -> Pmp {
+        let mut cfgs = [0; PMP_ENTRIES];
+        let mut addrs = [0; PMP_ENTRIES];
+        for (i, &(cfg, addr)) in entries.iter().enumerate() {
+            cfgs[i] = cfg;
+            addrs[i] = addr;
+        }
+        let mut pmp = Pmp::default();
+        pmp.update(&cfgs, &addrs, mseccfg);
+        pmp
+    }
+
+    fn allows(pmp: &Pmp, addr: u64, privilege: PrivilegeMode, a_type: AccessType) -> bool {
+        pmp.check(addr, 4, privilege, &a_type)
+    }
+
+    #[test]
+    fn napot_covers_an_aligned_power_of_two() {
+        let pmp = pmp(&[(NAPOT | PMP_R, napot(0x8000_0000, 0x1000))]);
+        let s = PrivilegeMode::Supervisor;
+        assert!(allows(&pmp, 0x8000_0000, s, AccessType::Load));
+        assert!(allows(&pmp, 0x8000_0ffc, s, AccessType::Load));
+        assert!(!allows(&pmp, 0x8000_0000, s, AccessType::Store));
+        assert!(!allows(&pmp, 0x8000_0000, s, AccessType::Fetch));
+        // AMOs need both read and write permission.
+        assert!(!allows(&pmp, 0x8000_0000, s, AccessType::Amo));
+        // No entry matches, only machine mode may access it.
+        assert!(!allows(&pmp, 0x8000_1000, s, AccessType::Load));
+        let m = PrivilegeMode::Machine;
+        assert!(allows(&pmp, 0x8000_1000, m, AccessType::Load));
+        assert!(allows(&pmp, 0x8000_0000, m, AccessType::Store));
+    }
+
+    #[test]
+    fn tor_starts_at_the_previous_address() {
+        let pmp = pmp(&[
+            (0, 0x8000_0000 >> 2),
+            (TOR | PMP_R | PMP_W, 0x8000_2000 >> 2),
+        ]);
+        let u = PrivilegeMode::User;
+        assert!(allows(&pmp, 0x8000_0000, u, AccessType::Store));
+        assert!(allows(&pmp, 0x8000_1ffc, u, AccessType::Store));
+        assert!(allows(&pmp, 0x8000_1ffc, u, AccessType::Amo));
+        assert!(!allows(&pmp, 0x7fff_fffc, u, AccessType::Load));
+        assert!(!allows(&pmp, 0x8000_2000, u, AccessType::Load));
+        // An access must lie within the entry as a whole.
+        assert!(!pmp.check(0x8000_1ffe, 4, u, &AccessType::Load));
+    }
+
+    #[test]
+    fn lowest_matching_entry_decides() {
+        let pmp = pmp(&[
+            (NAPOT, napot(0x8000_0000, 0x1000)),
+            (NAPOT | PMP_R | PMP_W | PMP_X, napot(0x8000_0000, 0x10_0000)),
+        ]);
+        let s = PrivilegeMode::Supervisor;
+        assert!(!allows(&pmp, 0x8000_0000, s, AccessType::Load));
+        assert!(allows(&pmp, 0x8000_1000, s, AccessType::Load));
+    }
+
+    #[test]
+    fn locked_entries_bind_machine_mode() {
+        let pmp = pmp(&[(PMP_L | NAPOT | PMP_R, napot(0x8000_0000, 0x1000))]);
+        let m = PrivilegeMode::Machine;
+        assert!(allows(&pmp, 0x8000_0000, m, AccessType::Load));
+        assert!(!allows(&pmp, 0x8000_0000, m, AccessType::Store));
+        assert!(!allows(&pmp, 0x8000_0000, m, AccessType::Fetch));
+    }
+
+    #[test]
+    fn mml_splits_entries_between_machine_and_user_modes() {
+        let pmp = pmp_with(
+            MSECCFG_MML,
+            &[
+                (PMP_L | NAPOT | PMP_R | PMP_X, napot(0x8000_0000, 0x1000)),
+                (NAPOT | PMP_R | PMP_W, napot(0x8000_1000, 0x1000)),
+                // Shared data, read-write for machine mode and read-only for the others.
+                (NAPOT | PMP_W, napot(0x8000_2000, 0x1000)),
+            ],
+        );
+        let (m, u) = (PrivilegeMode::Machine, PrivilegeMode::User);
+        assert!(allows(&pmp, 0x8000_0000, m, AccessType::Fetch));
+        assert!(!allows(&pmp, 0x8000_0000, u, AccessType::Load));
+        assert!(!allows(&pmp, 0x8000_1000, m, AccessType::Load));
+        assert!(allows(&pmp, 0x8000_1000, u, AccessType::Store));
+        assert!(allows(&pmp, 0x8000_2000, m, AccessType::Store));
+        assert!(allows(&pmp, 0x8000_2000, u, AccessType::Load));
+        assert!(!allows(&pmp, 0x8000_2000, u, AccessType::Store));
+        // Machine mode still reads and writes memory no entry covers, but can't execute from it.
+        assert!(allows(&pmp, 0x9000_0000, m, AccessType::Store));
+        assert!(!allows(&pmp, 0x9000_0000, m, AccessType::Fetch));
+    }
+
+    #[test]
+    fn mseccfg_bits_stick_and_rlb_unlocks_entries() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[EBREAK]);
+        let csrs = &mut cpu.state.csrs;
+        csrs.set_csr(0x747, MSECCFG_RLB);
+        csrs.set_csr(0x3a0, (PMP_L | NAPOT | PMP_R) as RegT);
+        // RLB lifts the lock.
+        csrs.set_csr(0x3a0, (PMP_L | NAPOT | PMP_R | PMP_W) as RegT);
+        let locked = (PMP_L | NAPOT | PMP_R | PMP_W) as RegT;
+        assert_eq!(csrs.csr(0x3a0), locked);
+        csrs.set_csr(0x747, MSECCFG_MML | MSECCFG_MMWP);
+        csrs.set_csr(0x747, 0);
+        assert_eq!(csrs.csr(0x747), MSECCFG_MML | MSECCFG_MMWP);
+        // RLB can't come back while an entry is locked, so entry 0 keeps its lock. Under MML
+        // entry 1 can't become a rule machine mode executes from.
+        csrs.set_csr(0x747, MSECCFG_RLB);
+        assert_eq!(csrs.csr(0x747), MSECCFG_MML | MSECCFG_MMWP);
+        csrs.set_csr(0x3a0, ((PMP_L | NAPOT | PMP_X) as RegT) << 8);
+        assert_eq!(csrs.csr(0x3a0), locked);
+    }
+
+    #[test]
+    fn mmwp_faults_machine_mode_stores_outside_the_entries() {
+        // The trap handler at +0x100 is an EBREAK.
+        let mut program = vec![NOP; 0x41];
+        program[..2].copy_from_slice(&[sw(10, 11, 0), EBREAK]);
+        program[0x40] = EBREAK;
+        let run = |entries: &[(u8, u64, u64)]| {
+            let mut cpu = Cpu::with_program(XLen::X64, &program);
+            let mut cfgs = 0;
+            for (i, &(cfg, base, size)) in entries.iter().enumerate() {
+                cpu.state.csrs.set_csr(0x3b0 + i as u16, napot(base, size));
+                cfgs |= (cfg as RegT) << (i * 8);
+            }
+            cpu.state.csrs.set_csr(0x3a0, cfgs);
+            cpu.state.csrs.set_csr(0x747, MSECCFG_MMWP);
+            cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+            cpu.state.xs.set_reg(10, 7);
+            cpu.state.xs.set_reg(11, DRAM_BASE + 0x1000);
+            cpu.run_until_ebreak(10);
+            cpu
+        };
+        let code = (NAPOT | PMP_R | PMP_X, DRAM_BASE, 0x1000);
+        let cpu = run(&[code]);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.csrs.mcause(), 7);
+        assert_eq!(cpu.mmu.bus.borrow().read::<u32>(DRAM_BASE + 0x1000), Ok(0));
+
+        let cpu = run(&[code, (NAPOT | PMP_R | PMP_W, DRAM_BASE + 0x1000, 0x1000)]);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        assert_eq!(cpu.mmu.bus.borrow().read::<u32>(DRAM_BASE + 0x1000), Ok(7));
+    }
+
+    #[test]
+    fn read_only_region_faults_stores_from_s_mode() {
+        // The trap handler at +0x100 is an EBREAK.
+        let mut program = vec![NOP; 0x41];
+        program[..3].copy_from_slice(&[lw(10, 11, 0), sw(10, 11, 4), EBREAK]);
+        program[0x40] = EBREAK;
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        // The first page of DRAM, with the program, is executable and the next read-only.
+        cpu.state.csrs.set_csr(0x3b0, napot(DRAM_BASE, 0x1000));
+        cpu.state
+            .csrs
+            .set_csr(0x3b1, napot(DRAM_BASE + 0x1000, 0x1000));
+        let cfgs = ((NAPOT | PMP_R) as RegT) << 8 | (NAPOT | PMP_R | PMP_X) as RegT;
+        cpu.state.csrs.set_csr(0x3a0, cfgs);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+        cpu.state.privilege = PrivilegeMode::Supervisor;
+        cpu.state.xs.set_reg(11, DRAM_BASE + 0x1000);
+        cpu.mmu
+            .bus
+            .borrow_mut()
+            .write::<u32>(DRAM_BASE + 0x1000, 7)
+            .unwrap();
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.csrs.mcause(), 7);
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE + 4);
+        assert_eq!(cpu.state.csrs.mtval(), DRAM_BASE + 0x1004);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Machine);
+        assert_eq!(cpu.state.xs.reg(10), 7);
+        assert_eq!(cpu.mmu.bus.borrow().read::<u32>(DRAM_BASE + 0x1004), Ok(0));
+    }
+
+    #[test]
+    fn denied_loads_and_fetches_trap_from_s_mode() {
+        // The trap handler at +0x100 is an EBREAK, the code at +0x200 is denied to S-mode.
+        let mut program = vec![NOP; 0x81];
+        program[..2].copy_from_slice(&[lw(10, 11, 8), EBREAK]);
+        program[0x40] = EBREAK;
+        program[0x80] = EBREAK;
+        let run = |pc: u64| {
+            let mut cpu = Cpu::with_program(XLen::X64, &program);
+            // Only the first 0x200 bytes of DRAM are accessible, and nothing else.
+            cpu.state.csrs.set_csr(0x3b0, napot(DRAM_BASE, 0x200));
+            cpu.state
+                .csrs
+                .set_csr(0x3a0, (NAPOT | PMP_R | PMP_X) as RegT);
+            cpu.state.csrs.set_mtvec(DRAM_BASE + 0x100);
+            cpu.state.privilege = PrivilegeMode::Supervisor;
+            cpu.state.xs.set_reg(10, 7);
+            cpu.state.xs.set_reg(11, DRAM_BASE + 0x1000);
+            cpu.state.pc = pc;
+            cpu.run_until_ebreak(10);
+            cpu
+        };
+        let cpu = run(DRAM_BASE);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Machine);
+        assert_eq!(cpu.state.csrs.mcause(), 5);
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE);
+        assert_eq!(cpu.state.csrs.mtval(), DRAM_BASE + 0x1008);
+        // The load didn't happen.
+        assert_eq!(cpu.state.xs.reg(10), 7);
+
+        let cpu = run(DRAM_BASE + 0x200);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+        assert_eq!(cpu.state.csrs.mcause(), 1);
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE + 0x200);
+        assert_eq!(cpu.state.csrs.mtval(), DRAM_BASE + 0x200);
+    }
+}
@@ -0,0 +1,231 @@
+//! A sampling profiler of the guest. Every so many retired instructions the pc is recorded,
+//! optionally with the return addresses found by following the frame pointer chain in s0. The
+//! samples are written as folded stacks, `vmlinux`memcpy;vmlinux`copy_page 12345`, which
+//! inferno and flamegraph.pl turn into a flame graph. Like coverage it's collected through an
+//! `ExecHook`, a cpu without profiling enabled doesn't pay for it.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    cpu::{Cpu, CpuStatus},
+    hook::ExecHook,
+    symbols::Symbols,
+    trap::Exception,
+    RegT, XLen,
+};
+
+/// s0, the frame pointer.
+const FP: u8 = 8;
+
+/// Sample counts per call chain.
+pub struct Profile {
+    period: u64,
+    depth: usize,
+    /// The pcs of every sampled call chain, innermost first.
+    samples: HashMap<Vec<RegT>, u64>,
+}
+
+impl Profile {
+    /// Samples every `period` retired instructions, following up to `depth` frames of the caller
+    /// chain.
+    pub fn new(period: u64, depth: usize) -> Self {
+        Self {
+            period: period.max(1),
+            depth,
+            samples: HashMap::new(),
+        }
+    }
+
+    /// The number of samples taken.
+    pub fn total(&self) -> u64 {
+        self.samples.values().sum()
+    }
+
+    /// Writes a line per call chain, outermost function first, sorted by the chain. Functions are
+    /// named after their symbol and the file it's from, as perf does, other addresses by their
+    /// value. Chains naming the same functions are merged.
+    pub fn write_folded(&self, w: &mut dyn Write, symbols: &Symbols) -> io::Result<()> {
+        let mut stacks = BTreeMap::new();
+        for (pcs, count) in &self.samples {
+            let frames: Vec<_> = pcs
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, &pc)| {
+                    // A return address may be past the end of the calling function when the call
+                    // is its last instruction.
+                    let addr = if i == 0 { pc } else { pc.wrapping_sub(1) };
+                    match symbols.function(addr) {
+                        Some((file, name)) => format!("{}`{}", file, name),
+                        None => format!("{:#x}", pc),
+                    }
+                })
+                .collect();
+            *stacks.entry(frames.join(";")).or_insert(0) += count;
+        }
+        for (stack, count) in stacks {
+            writeln!(w, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Samples the hart into the shared `Profile`.
+pub struct ProfileHook {
+    profile: Rc<RefCell<Profile>>,
+    /// Retired instructions until the next sample.
+    countdown: u64,
+    /// The pc of the instruction to sample, set when the countdown runs out.
+    due: Option<RegT>,
+}
+
+impl ProfileHook {
+    pub fn new(profile: Rc<RefCell<Profile>>) -> Self {
+        let countdown = profile.borrow().period;
+        Self {
+            profile,
+            countdown,
+            due: None,
+        }
+    }
+}
+
+impl ExecHook for ProfileHook {
+    fn after(&mut self, pc: RegT, _state: &CpuStatus, result: Result<(), Exception>) {
+        if result.is_err() {
+            return;
+        }
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.profile.borrow().period;
+            self.due = Some(pc);
+        }
+    }
+
+    fn observe(&mut self, cpu: &Cpu) {
+        let pc = match self.due.take() {
+            Some(pc) => pc,
+            None => return,
+        };
+        let mut profile = self.profile.borrow_mut();
+        let mut pcs = vec![pc];
+        // With frame pointers s0 points past the frame, which ends with the return address and
+        // the caller's s0. Callers' frames are further up the stack.
+        let size = cpu.xlen.size() as RegT;
+        let mut fp = cpu.state.xs.reg(FP);
+        while pcs.len() <= profile.depth && fp != 0 && fp.is_multiple_of(size) {
+            let (ra, next) = match (
+                read(cpu, fp.wrapping_sub(size)),
+                read(cpu, fp.wrapping_sub(2 * size)),
+            ) {
+                (Some(ra), Some(next)) => (ra, next),
+                _ => break,
+            };
+            if ra == 0 {
+                break;
+            }
+            pcs.push(ra);
+            if next <= fp {
+                break;
+            }
+            fp = next;
+        }
+        *profile.samples.entry(pcs).or_insert(0) += 1;
+    }
+}
+
+/// Reads a register sized word of guest memory at the virtual address `addr`.
+fn read(cpu: &Cpu, addr: RegT) -> Option<RegT> {
+    match cpu.xlen {
+        XLen::X32 => cpu.mmu.peek::<u32>(&cpu.state, addr).ok().map(RegT::from),
+        XLen::X64 => cpu.mmu.peek::<u64>(&cpu.state, addr).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::DRAM_BASE, testing::*};
+
+    const STT_FUNC: u8 = 2;
+    const RA: u32 = 1;
+    const SP: u32 = 2;
+    const S0: u32 = 8;
+
+    /// Calls `heavy` and `light` 20 times, `heavy` spins 40 times as long as `light`. Both set up a
+    /// frame like GCC with frame pointers does.
+    fn program() -> Vec<u32> {
+        let function = |turns: i32| {
+            [
+                addi(SP, SP, -16),
+                sd(RA, SP, 8),
+                sd(S0, SP, 0),
+                addi(S0, SP, 16),
+                addi(5, 0, turns),
+                addi(5, 5, -1),
+                b_type(-4, 0, 5, 1),
+                ld(RA, SP, 8),
+                ld(S0, SP, 0),
+                addi(SP, SP, 16),
+                i_type(0, RA, 0, 0, 0x67),
+            ]
+        };
+        // main at 0, heavy at 0x20 and light at 0x4c.
+        let mut program = vec![
+            addi(10, 0, 20),
+            jal(RA, 0x1c),
+            jal(RA, 0x44),
+            addi(10, 10, -1),
+            b_type(-12, 0, 10, 1),
+            EBREAK,
+            NOP,
+            NOP,
+        ];
+        program.extend(function(400));
+        program.extend(function(10));
+        program
+    }
+
+    #[test]
+    fn samples_follow_where_the_time_goes() {
+        let mut cpu = Cpu::with_program(XLen::X64, &program());
+        cpu.state.xs.set_reg(SP as u8, DRAM_BASE + 0x10000);
+        assert!(cpu.profile().is_none());
+        cpu.enable_profiling(7, 4);
+        cpu.run_until_ebreak(100_000);
+        cpu.symbols
+            .add_elf(
+                "guest",
+                &symbol_elf(&[
+                    ("main", DRAM_BASE, 0x20, STT_FUNC, 1),
+                    ("heavy", DRAM_BASE + 0x20, 0x2c, STT_FUNC, 1),
+                    ("light", DRAM_BASE + 0x4c, 0x2c, STT_FUNC, 1),
+                ]),
+            )
+            .unwrap();
+
+        let profile = cpu.profile().unwrap();
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded, &cpu.symbols).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let counts: HashMap<_, _> = folded
+            .lines()
+            .map(|line| {
+                let (stack, count) = line.rsplit_once(' ').unwrap();
+                (stack, count.parse::<u64>().unwrap())
+            })
+            .collect();
+        assert_eq!(counts.values().sum::<u64>(), profile.total());
+        let heavy = counts["guest`main;guest`heavy"];
+        let light = counts["guest`main;guest`light"];
+        // About 800 and 20 instructions a turn, the prologues and epilogues come on top.
+        let ratio = heavy as f64 / light as f64;
+        assert!((20.0..60.0).contains(&ratio), "{}", folded);
+        assert!(heavy + light > profile.total() * 9 / 10, "{}", folded);
+    }
+}
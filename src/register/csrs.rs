@@ -1,14 +1,30 @@
-use crate::RegT;
+use std::io::{self, Read, Write};
+
+use crate::{
+    device::clint::Timebase,
+    mmu::AccessType,
+    pmp::{self, Pmp, PMP_ENTRIES},
+    snapshot,
+    trigger::Triggers,
+    PrivilegeMode, RegT, XLen,
+};
 
 use super::{
-    medeleg::Medeleg, mideleg::Mideleg, mie::Mie, mip::Mip, mstatus::Mstatus, satp::Satp,
-    sstatus::Sstatus, xtvec::Xtvec,
+    envcfg::{self, Envcfg},
+    medeleg::Medeleg,
+    mideleg::Mideleg,
+    mie::Mie,
+    mip::{self, Mip},
+    mstatus::Mstatus,
+    satp::Satp,
+    sstatus::Sstatus,
+    xtvec::Xtvec,
 };
 
 macro_rules! csr {
     ($fnname:ident, $csr_num:expr, $register:ty) => {
         pub fn $fnname(&self) -> $register {
-            self.csrs[$csr_num].into()
+            self.csr($csr_num).into()
         }
     };
     ($fnname:ident, $set_fnname:ident, $csr_num:expr, $register:ty) => {
@@ -29,15 +45,547 @@ macro_rules! csr {
     };
 }
 
+/// The architectural names of the CSRs the emulator implements, sorted by number for `Csrs::name`.
+/// The hardware performance counters read as zero but are named too.
+static CSR_NAMES: [(&str, u16); 215] = [
+    ("fflags", 0x001),
+    ("frm", 0x002),
+    ("fcsr", 0x003),
+    ("sstatus", 0x100),
+    ("sie", 0x104),
+    ("stvec", 0x105),
+    ("scounteren", 0x106),
+    ("senvcfg", 0x10a),
+    ("sscratch", 0x140),
+    ("sepc", 0x141),
+    ("scause", 0x142),
+    ("stval", 0x143),
+    ("sip", 0x144),
+    ("stimecmp", 0x14d),
+    ("stimecmph", 0x15d),
+    ("satp", 0x180),
+    ("mstatus", 0x300),
+    ("misa", 0x301),
+    ("medeleg", 0x302),
+    ("mideleg", 0x303),
+    ("mie", 0x304),
+    ("mtvec", 0x305),
+    ("mcounteren", 0x306),
+    ("menvcfg", 0x30a),
+    ("menvcfgh", 0x31a),
+    ("mhpmevent3", 0x323),
+    ("mhpmevent4", 0x324),
+    ("mhpmevent5", 0x325),
+    ("mhpmevent6", 0x326),
+    ("mhpmevent7", 0x327),
+    ("mhpmevent8", 0x328),
+    ("mhpmevent9", 0x329),
+    ("mhpmevent10", 0x32a),
+    ("mhpmevent11", 0x32b),
+    ("mhpmevent12", 0x32c),
+    ("mhpmevent13", 0x32d),
+    ("mhpmevent14", 0x32e),
+    ("mhpmevent15", 0x32f),
+    ("mhpmevent16", 0x330),
+    ("mhpmevent17", 0x331),
+    ("mhpmevent18", 0x332),
+    ("mhpmevent19", 0x333),
+    ("mhpmevent20", 0x334),
+    ("mhpmevent21", 0x335),
+    ("mhpmevent22", 0x336),
+    ("mhpmevent23", 0x337),
+    ("mhpmevent24", 0x338),
+    ("mhpmevent25", 0x339),
+    ("mhpmevent26", 0x33a),
+    ("mhpmevent27", 0x33b),
+    ("mhpmevent28", 0x33c),
+    ("mhpmevent29", 0x33d),
+    ("mhpmevent30", 0x33e),
+    ("mhpmevent31", 0x33f),
+    ("mscratch", 0x340),
+    ("mepc", 0x341),
+    ("mcause", 0x342),
+    ("mtval", 0x343),
+    ("mip", 0x344),
+    ("pmpcfg0", 0x3a0),
+    ("pmpcfg1", 0x3a1),
+    ("pmpcfg2", 0x3a2),
+    ("pmpcfg3", 0x3a3),
+    ("pmpaddr0", 0x3b0),
+    ("pmpaddr1", 0x3b1),
+    ("pmpaddr2", 0x3b2),
+    ("pmpaddr3", 0x3b3),
+    ("pmpaddr4", 0x3b4),
+    ("pmpaddr5", 0x3b5),
+    ("pmpaddr6", 0x3b6),
+    ("pmpaddr7", 0x3b7),
+    ("pmpaddr8", 0x3b8),
+    ("pmpaddr9", 0x3b9),
+    ("pmpaddr10", 0x3ba),
+    ("pmpaddr11", 0x3bb),
+    ("pmpaddr12", 0x3bc),
+    ("pmpaddr13", 0x3bd),
+    ("pmpaddr14", 0x3be),
+    ("pmpaddr15", 0x3bf),
+    ("mseccfg", 0x747),
+    ("tselect", 0x7a0),
+    ("tdata1", 0x7a1),
+    ("tdata2", 0x7a2),
+    ("tdata3", 0x7a3),
+    ("tinfo", 0x7a4),
+    ("mcycle", 0xb00),
+    ("minstret", 0xb02),
+    ("mhpmcounter3", 0xb03),
+    ("mhpmcounter4", 0xb04),
+    ("mhpmcounter5", 0xb05),
+    ("mhpmcounter6", 0xb06),
+    ("mhpmcounter7", 0xb07),
+    ("mhpmcounter8", 0xb08),
+    ("mhpmcounter9", 0xb09),
+    ("mhpmcounter10", 0xb0a),
+    ("mhpmcounter11", 0xb0b),
+    ("mhpmcounter12", 0xb0c),
+    ("mhpmcounter13", 0xb0d),
+    ("mhpmcounter14", 0xb0e),
+    ("mhpmcounter15", 0xb0f),
+    ("mhpmcounter16", 0xb10),
+    ("mhpmcounter17", 0xb11),
+    ("mhpmcounter18", 0xb12),
+    ("mhpmcounter19", 0xb13),
+    ("mhpmcounter20", 0xb14),
+    ("mhpmcounter21", 0xb15),
+    ("mhpmcounter22", 0xb16),
+    ("mhpmcounter23", 0xb17),
+    ("mhpmcounter24", 0xb18),
+    ("mhpmcounter25", 0xb19),
+    ("mhpmcounter26", 0xb1a),
+    ("mhpmcounter27", 0xb1b),
+    ("mhpmcounter28", 0xb1c),
+    ("mhpmcounter29", 0xb1d),
+    ("mhpmcounter30", 0xb1e),
+    ("mhpmcounter31", 0xb1f),
+    ("mcycleh", 0xb80),
+    ("minstreth", 0xb82),
+    ("mhpmcounter3h", 0xb83),
+    ("mhpmcounter4h", 0xb84),
+    ("mhpmcounter5h", 0xb85),
+    ("mhpmcounter6h", 0xb86),
+    ("mhpmcounter7h", 0xb87),
+    ("mhpmcounter8h", 0xb88),
+    ("mhpmcounter9h", 0xb89),
+    ("mhpmcounter10h", 0xb8a),
+    ("mhpmcounter11h", 0xb8b),
+    ("mhpmcounter12h", 0xb8c),
+    ("mhpmcounter13h", 0xb8d),
+    ("mhpmcounter14h", 0xb8e),
+    ("mhpmcounter15h", 0xb8f),
+    ("mhpmcounter16h", 0xb90),
+    ("mhpmcounter17h", 0xb91),
+    ("mhpmcounter18h", 0xb92),
+    ("mhpmcounter19h", 0xb93),
+    ("mhpmcounter20h", 0xb94),
+    ("mhpmcounter21h", 0xb95),
+    ("mhpmcounter22h", 0xb96),
+    ("mhpmcounter23h", 0xb97),
+    ("mhpmcounter24h", 0xb98),
+    ("mhpmcounter25h", 0xb99),
+    ("mhpmcounter26h", 0xb9a),
+    ("mhpmcounter27h", 0xb9b),
+    ("mhpmcounter28h", 0xb9c),
+    ("mhpmcounter29h", 0xb9d),
+    ("mhpmcounter30h", 0xb9e),
+    ("mhpmcounter31h", 0xb9f),
+    ("cycle", 0xc00),
+    ("time", 0xc01),
+    ("instret", 0xc02),
+    ("hpmcounter3", 0xc03),
+    ("hpmcounter4", 0xc04),
+    ("hpmcounter5", 0xc05),
+    ("hpmcounter6", 0xc06),
+    ("hpmcounter7", 0xc07),
+    ("hpmcounter8", 0xc08),
+    ("hpmcounter9", 0xc09),
+    ("hpmcounter10", 0xc0a),
+    ("hpmcounter11", 0xc0b),
+    ("hpmcounter12", 0xc0c),
+    ("hpmcounter13", 0xc0d),
+    ("hpmcounter14", 0xc0e),
+    ("hpmcounter15", 0xc0f),
+    ("hpmcounter16", 0xc10),
+    ("hpmcounter17", 0xc11),
+    ("hpmcounter18", 0xc12),
+    ("hpmcounter19", 0xc13),
+    ("hpmcounter20", 0xc14),
+    ("hpmcounter21", 0xc15),
+    ("hpmcounter22", 0xc16),
+    ("hpmcounter23", 0xc17),
+    ("hpmcounter24", 0xc18),
+    ("hpmcounter25", 0xc19),
+    ("hpmcounter26", 0xc1a),
+    ("hpmcounter27", 0xc1b),
+    ("hpmcounter28", 0xc1c),
+    ("hpmcounter29", 0xc1d),
+    ("hpmcounter30", 0xc1e),
+    ("hpmcounter31", 0xc1f),
+    ("cycleh", 0xc80),
+    ("timeh", 0xc81),
+    ("instreth", 0xc82),
+    ("hpmcounter3h", 0xc83),
+    ("hpmcounter4h", 0xc84),
+    ("hpmcounter5h", 0xc85),
+    ("hpmcounter6h", 0xc86),
+    ("hpmcounter7h", 0xc87),
+    ("hpmcounter8h", 0xc88),
+    ("hpmcounter9h", 0xc89),
+    ("hpmcounter10h", 0xc8a),
+    ("hpmcounter11h", 0xc8b),
+    ("hpmcounter12h", 0xc8c),
+    ("hpmcounter13h", 0xc8d),
+    ("hpmcounter14h", 0xc8e),
+    ("hpmcounter15h", 0xc8f),
+    ("hpmcounter16h", 0xc90),
+    ("hpmcounter17h", 0xc91),
+    ("hpmcounter18h", 0xc92),
+    ("hpmcounter19h", 0xc93),
+    ("hpmcounter20h", 0xc94),
+    ("hpmcounter21h", 0xc95),
+    ("hpmcounter22h", 0xc96),
+    ("hpmcounter23h", 0xc97),
+    ("hpmcounter24h", 0xc98),
+    ("hpmcounter25h", 0xc99),
+    ("hpmcounter26h", 0xc9a),
+    ("hpmcounter27h", 0xc9b),
+    ("hpmcounter28h", 0xc9c),
+    ("hpmcounter29h", 0xc9d),
+    ("hpmcounter30h", 0xc9e),
+    ("hpmcounter31h", 0xc9f),
+    ("mvendorid", 0xf11),
+    ("marchid", 0xf12),
+    ("mimpid", 0xf13),
+    ("mhartid", 0xf14),
+];
+
+/// Returns the number of the CSR called `name`. A number written in hex like `0x300` is accepted
+/// as well.
+#[cfg(feature = "host")]
+pub fn csr_number(name: &str) -> Option<u16> {
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16).ok().filter(|num| *num < 4096);
+    }
+    Csrs::by_name(name)
+}
+
+/// Extensions reported by misa: A, B (Zba, Zbb and Zbs), D, F, I, M, S and U.
+const MISA_EXTENSIONS: RegT = 1 | 1 << 1 | 1 << 3 | 1 << 5 | 1 << 8 | 1 << 12 | 1 << 18 | 1 << 20;
+/// Extensions software can turn off by clearing their misa bit: D and F.
+const MISA_WRITABLE: RegT = 1 << 3 | 1 << 5;
+/// mstatus.FS, also visible through sstatus.
+const STATUS_FS: RegT = 0x3 << 13;
+/// mstatus.MPP.
+const STATUS_MPP: RegT = 0x3 << 11;
+/// Implemented mstatus fields: SIE, MIE, SPIE, MPIE, SPP, MPP, FS, MPRV, SUM, MXR, TVM, TW and
+/// TSR. SD is derived from FS.
+const MSTATUS_MASK: RegT = 0x7e_79aa;
+/// The mstatus fields visible through sstatus: SIE, SPIE, SPP, FS, SUM and MXR.
+const SSTATUS_MASK: RegT = 0x0c_6122;
+/// Exception codes that can be written to mcause/scause.
+const EXCEPTION_CODES: RegT = 0xbbff;
+/// Interrupt codes that can be written to mcause/scause.
+const INTERRUPT_CODES: RegT = 0xaaa;
+/// Exceptions medeleg can delegate: all the hart raises but ECALL from M-mode, whose bit is
+/// read-only zero.
+pub(crate) const MEDELEG_MASK: RegT = EXCEPTION_CODES & !(1 << 11);
+
+/// The mip bits CSR writes can change. MSIP, MTIP and MEIP follow the CLINT and the PLIC, STIP
+/// follows stimecmp while menvcfg.STCE is set.
+const MIP_WRITABLE: RegT = mip::SSIP | mip::STIP | mip::SEIP;
+/// The implemented menvcfg fields: FIOM, CBIE, CBCFE, CBZE and STCE. PBMTE is read-only zero
+/// without Svpbmt.
+const MENVCFG_MASK: RegT =
+    envcfg::FIOM | envcfg::CBIE | envcfg::CBCFE | envcfg::CBZE | envcfg::STCE;
+/// senvcfg has the same fields as menvcfg up to CBZE.
+const SENVCFG_MASK: RegT = envcfg::FIOM | envcfg::CBIE | envcfg::CBCFE | envcfg::CBZE;
+/// The sip bits CSR writes can change, if delegated in mideleg.
+const SIP_WRITABLE: RegT = mip::SSIP;
+/// Interrupts mideleg can delegate: SSI, STI and SEI. Machine-level interrupts are never
+/// delegated.
+pub(crate) const MIDELEG_MASK: RegT = mip::SSIP | mip::STIP | mip::SEIP;
+/// Implemented mie bits, the enables of the implemented interrupts.
+const MIE_MASK: RegT = 0xaaa;
+
+/// Turns a value written to the CSR into a legal one.
+type Legalize = fn(&Csrs, u16, RegT) -> RegT;
+
+/// WARL CSRs and the functions that turn a written value into a legal one. Every write goes
+/// through them, whether it comes from a CSR instruction or from trap handling.
+const LEGALIZE: [(u16, Legalize); 15] = [
+    (0x100, Csrs::legalize_sstatus),
+    (0x105, Csrs::legalize_xtvec),
+    (0x106, Csrs::legalize_xcounteren),
+    (0x10a, Csrs::legalize_envcfg),
+    (0x141, Csrs::legalize_xepc),
+    (0x142, Csrs::legalize_xcause),
+    (0x180, Csrs::legalize_satp),
+    (0x300, Csrs::legalize_mstatus),
+    (0x302, Csrs::legalize_medeleg),
+    (0x303, Csrs::legalize_mideleg),
+    (0x305, Csrs::legalize_xtvec),
+    (0x306, Csrs::legalize_xcounteren),
+    (0x341, Csrs::legalize_xepc),
+    (0x342, Csrs::legalize_xcause),
+    (0x747, Csrs::legalize_mseccfg),
+];
+
+#[derive(Clone)]
 pub struct Csrs {
     /// Control and status registers. RISC-V ISA sets aside a 12-bit encoding space (csr[11:0]) for
     /// up to 4096 CSRs.
     csrs: [RegT; 4096],
+    xlen: XLen,
+    /// The PMP entries decoded from pmpcfg and pmpaddr.
+    pmp: Pmp,
+    /// The triggers behind tselect, tdata1 and tdata2.
+    triggers: Triggers,
+    /// The CLINT's mtime, read through the time CSR. A hart without a bus keeps a clock of its
+    /// own that never advances.
+    time: Timebase,
+    /// Interrupt-pending lines driven by devices, ORed into mip. Refreshed before every
+    /// instruction, so they aren't part of snapshots.
+    lines: RegT,
 }
 
 impl Csrs {
-    pub fn new() -> Self {
-        Self { csrs: [0; 4096] }
+    pub fn new(xlen: XLen) -> Self {
+        let mut csrs = [0; 4096];
+        let mxl: RegT = match xlen {
+            XLen::X32 => 1,
+            XLen::X64 => 2,
+        };
+        csrs[0x301] = mxl << (xlen.len() - 2) | MISA_EXTENSIONS;
+        Self {
+            csrs,
+            xlen,
+            pmp: Pmp::default(),
+            triggers: Triggers::default(),
+            time: Timebase::default(),
+            lines: 0,
+        }
+    }
+
+    /// Returns the number of the CSR with the architectural name `name`, e.g. 0x180 for "satp".
+    pub fn by_name(name: &str) -> Option<u16> {
+        CSR_NAMES
+            .iter()
+            .find(|(csr_name, _)| *csr_name == name)
+            .map(|(_, num)| *num)
+    }
+
+    /// Returns the architectural name of the CSR `csr_num`, `None` for CSRs the emulator doesn't
+    /// know.
+    pub fn name(csr_num: u16) -> Option<&'static str> {
+        CSR_NAMES
+            .binary_search_by_key(&csr_num, |(_, num)| *num)
+            .ok()
+            .map(|i| CSR_NAMES[i].0)
+    }
+
+    /// Returns true if the emulator implements the CSR `csr_num`, the ones with a name. Under
+    /// `CsrPolicy::Strict` accessing the others is illegal.
+    pub fn is_implemented(csr_num: u16) -> bool {
+        Self::name(csr_num).is_some()
+    }
+
+    /// Returns true if the extension with the letter `ext` is enabled in misa.
+    pub fn has_extension(&self, ext: char) -> bool {
+        self.csrs[0x301] >> (ext as u8 - b'A') & 1 == 1
+    }
+
+    /// mstatus.SD, set together with a dirty FS.
+    fn status_sd(&self) -> RegT {
+        1 << (self.xlen.len() - 1)
+    }
+
+    /// Marks the floating-point state dirty, after an instruction wrote an f register or fcsr.
+    pub fn set_fs_dirty(&mut self) {
+        let dirty = STATUS_FS | self.status_sd();
+        self.csrs[0x100] |= dirty;
+        self.csrs[0x300] |= dirty;
+    }
+
+    /// Keeps the implemented mstatus fields. MPP keeps its old value if the reserved mode 2 is
+    /// written.
+    fn legalize_mstatus(&self, _: u16, value: RegT) -> RegT {
+        let value = value & MSTATUS_MASK;
+        if value & STATUS_MPP == 0x2 << 11 {
+            (value & !STATUS_MPP) | (self.csrs[0x300] & STATUS_MPP)
+        } else {
+            value
+        }
+    }
+
+    /// Keeps the sstatus fields, the rest of mstatus is left alone.
+    fn legalize_sstatus(&self, _: u16, value: RegT) -> RegT {
+        value & SSTATUS_MASK
+    }
+
+    /// ECALL from M-mode can't be delegated, neither can exceptions the hart never raises.
+    fn legalize_medeleg(&self, _: u16, value: RegT) -> RegT {
+        value & MEDELEG_MASK
+    }
+
+    /// Machine-level interrupts can't be delegated, their mideleg bits are read-only zero.
+    fn legalize_mideleg(&self, _: u16, value: RegT) -> RegT {
+        value & MIDELEG_MASK
+    }
+
+    /// Falls back to direct mode if one of the reserved modes 2 or 3 is written.
+    fn legalize_xtvec(&self, _: u16, value: RegT) -> RegT {
+        if value & 0x2 != 0 {
+            value & !0x3
+        } else {
+            value
+        }
+    }
+
+    /// Keeps the implemented fields of menvcfg or senvcfg. CBIE keeps its old value if the
+    /// reserved encoding 2 is written.
+    fn legalize_envcfg(&self, csr_num: u16, value: RegT) -> RegT {
+        let mask = if csr_num == 0x10a {
+            SENVCFG_MASK
+        } else {
+            MENVCFG_MASK
+        };
+        let value = value & mask;
+        if value & envcfg::CBIE == 0x2 << 4 {
+            (value & !envcfg::CBIE) | (self.csrs[csr_num as usize] & envcfg::CBIE)
+        } else {
+            value
+        }
+    }
+
+    /// mcounteren/scounteren are 32-bit registers, one enable per counter.
+    fn legalize_xcounteren(&self, _: u16, value: RegT) -> RegT {
+        value & 0xffff_ffff
+    }
+
+    /// mepc/sepc can only hold XLEN-bit instruction addresses: bit 0 is always zero, and so is
+    /// bit 1 without C.
+    fn legalize_xepc(&self, _: u16, value: RegT) -> RegT {
+        let value = value & self.xlen.mask();
+        if self.has_extension('C') {
+            value & !0x1
+        } else {
+            value & !0x3
+        }
+    }
+
+    /// Only Bare and the translation mode of the XLEN, Sv32 or Sv39, are implemented. "If satp is
+    /// written with an unsupported MODE, the entire write has no effect."
+    fn legalize_satp(&self, csr_num: u16, value: RegT) -> RegT {
+        let supported = match self.xlen {
+            XLen::X32 => true,
+            XLen::X64 => matches!(value >> 60, 0 | 8),
+        };
+        if supported {
+            value
+        } else {
+            self.csrs[csr_num as usize]
+        }
+    }
+
+    /// Only causes the hart can actually raise are kept, any other value leaves the CSR
+    /// unchanged.
+    fn legalize_xcause(&self, csr_num: u16, value: RegT) -> RegT {
+        let interrupt = 1 << (self.xlen.len() - 1);
+        let (codes, code) = if value & interrupt != 0 {
+            (INTERRUPT_CODES, value & !interrupt)
+        } else {
+            (EXCEPTION_CODES, value)
+        };
+        if code < 16 && codes >> code & 1 == 1 {
+            value
+        } else {
+            self.csrs[csr_num as usize]
+        }
+    }
+
+    /// MML and MMWP can be set but not cleared. RLB can't be set while an entry is locked.
+    fn legalize_mseccfg(&self, csr_num: u16, value: RegT) -> RegT {
+        let old = self.csrs[csr_num as usize];
+        let sticky = pmp::MSECCFG_MML | pmp::MSECCFG_MMWP;
+        let value = old & sticky | value & (sticky | pmp::MSECCFG_RLB);
+        if old & pmp::MSECCFG_RLB == 0 && (0..PMP_ENTRIES).any(|i| pmp::is_locked(self.pmpcfg(i))) {
+            value & !pmp::MSECCFG_RLB
+        } else {
+            value
+        }
+    }
+
+    /// Drives STIP from stimecmp: "a supervisor timer interrupt becomes pending whenever time
+    /// contains a value greater than or equal to stimecmp". Without STCE the line is low and STIP
+    /// is left to software.
+    pub fn update_stimer(&mut self) {
+        let pending = self.menvcfg().stce() && self.time() >= self.csrs[0x14d];
+        self.set_interrupt_line(mip::STIP, pending);
+    }
+
+    /// Makes the time CSR read `timebase`, the mtime of the CLINT on the bus of the hart.
+    pub fn set_timebase(&mut self, timebase: Timebase) {
+        self.time = timebase;
+    }
+
+    pub fn time(&self) -> RegT {
+        self.time.get()
+    }
+
+    pub fn pmp(&self) -> &Pmp {
+        &self.pmp
+    }
+
+    /// Whether any trigger is armed, checked before looking for one that fires.
+    pub fn triggers_armed(&self) -> bool {
+        self.triggers.armed()
+    }
+
+    /// Returns true if a trigger fires on an access of `a_type` to `addr` in `privilege`. M-mode
+    /// triggers only fire while mstatus.MIE is set, so that one hit inside the trap handler
+    /// doesn't trap again and again.
+    pub fn trigger_fires(&self, a_type: &AccessType, addr: RegT, privilege: PrivilegeMode) -> bool {
+        if privilege == PrivilegeMode::Machine && !self.mstatus().mie() {
+            return false;
+        }
+        self.triggers.hit(a_type, addr, privilege)
+    }
+
+    /// Returns the pmpcfg byte of PMP entry `i`. RV32 packs four of them in each of pmpcfg0-3,
+    /// RV64 eight in each of pmpcfg0 and pmpcfg2.
+    fn pmpcfg(&self, i: usize) -> u8 {
+        let per_csr = self.xlen.len() / 8;
+        let csr_num = 0x3a0 + i / per_csr * (per_csr / 4);
+        (self.csrs[csr_num] >> (i % per_csr * 8)) as u8
+    }
+
+    /// Returns true if the pmpcfg byte keeps its entry from being changed, it's locked and
+    /// mseccfg.RLB doesn't lift the lock.
+    fn pmp_locks(&self, cfg: u8) -> bool {
+        pmp::is_locked(cfg) && self.csrs[0x747] & pmp::MSECCFG_RLB == 0
+    }
+
+    fn update_pmp(&mut self) {
+        let mut cfgs = [0; PMP_ENTRIES];
+        for (i, cfg) in cfgs.iter_mut().enumerate() {
+            *cfg = self.pmpcfg(i);
+        }
+        let mut addrs = [0; PMP_ENTRIES];
+        addrs.copy_from_slice(&self.csrs[0x3b0..0x3b0 + PMP_ENTRIES]);
+        self.pmp.update(&cfgs, &addrs, self.csrs[0x747]);
+    }
+
+    /// Accrues the exception flags raised by a floating-point instruction into fflags.
+    pub fn accrue_fflags(&mut self, flags: RegT) {
+        if flags != 0 {
+            self.set_csr(0x001, self.csrs[0x001] | flags);
+        }
     }
 
     pub fn csr(&self, csr_num: u16) -> RegT {
@@ -46,7 +594,53 @@ impl Csrs {
             "csr_num must be one of [0~32). got: {}",
             csr_num
         );
-        self.csrs[csr_num as usize]
+        match csr_num {
+            // sie and sip are views of the delegated bits of mie and mip.
+            0x104 => self.csrs[0x304] & self.csrs[0x303],
+            0x144 => (self.csrs[0x344] | self.lines) & self.csrs[0x303],
+            0x344 => self.csrs[0x344] | self.lines,
+            // The upper halves of stimecmp and menvcfg on RV32.
+            0x15d | 0x31a => self.csrs[csr_num as usize - 0x10] >> 32,
+            // The hardware performance monitor has no events to count: hpmcounter3–31 and their
+            // RV32 high halves, the machine counters mhpmcounter3–31 behind them and the event
+            // selectors mhpmevent3–31 all read as zero.
+            0xc03..=0xc1f | 0xc83..=0xc9f | 0xb03..=0xb1f | 0xb83..=0xb9f | 0x323..=0x33f => 0,
+            0x7a0 => self.triggers.tselect(),
+            0x7a1 => self.triggers.tdata1(self.xlen),
+            0x7a2 => self.triggers.tdata2(),
+            // tdata3 has no fields for mcontrol. tinfo lists the one supported type, mcontrol.
+            0x7a3 => 0,
+            0x7a4 => 1 << 2,
+            // cycle and instret are views of mcycle and minstret, time of the CLINT's mtime.
+            0xc00 => self.csrs[0xb00],
+            0xc01 => self.time(),
+            0xc02 => self.csrs[0xb02],
+            _ => self.csrs[csr_num as usize],
+        }
+    }
+
+    /// Raises or lowers the interrupt-pending lines in `mask`, as the CLINT and the PLIC do.
+    pub fn set_interrupt_line(&mut self, mask: RegT, pending: bool) {
+        if pending {
+            self.lines |= mask;
+        } else {
+            self.lines &= !mask;
+        }
+    }
+
+    pub fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.csrs
+            .iter()
+            .try_for_each(|csr| snapshot::write_u64(w, *csr))?;
+        self.triggers.save(w)
+    }
+
+    pub fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for csr in self.csrs.iter_mut() {
+            *csr = snapshot::read_u64(r)?;
+        }
+        self.update_pmp();
+        self.triggers.restore(r)
     }
 
     pub fn set_csr(&mut self, csr_num: u16, value: RegT) {
@@ -55,6 +649,10 @@ impl Csrs {
             "csr_num must be one of [0~32). got: {}",
             csr_num
         );
+        let value = match LEGALIZE.iter().find(|(num, _)| *num == csr_num) {
+            Some((_, legalize)) => legalize(self, csr_num, value),
+            None => value,
+        };
         match csr_num {
             0x104 => {
                 // SIE
@@ -62,10 +660,149 @@ impl Csrs {
                 let mie = self.mie().bits();
                 self.set_mie((mie & !mideleg) | (value & mideleg));
             }
+            0x144 => {
+                // SIP
+                let mask = SIP_WRITABLE & self.mideleg().bits();
+                self.csrs[0x344] = (self.csrs[0x344] & !mask) | (value & mask);
+            }
+            0x304 => self.csrs[0x304] = value & MIE_MASK,
+            // mhpmcounter3–31 and mhpmevent3–31 are hardwired to zero, see `csr`.
+            0xb03..=0xb1f | 0xb83..=0xb9f | 0x323..=0x33f => {}
+            0x7a0 => self.triggers.set_tselect(value),
+            0x7a1 => self.triggers.set_tdata1(self.xlen, value),
+            0x7a2 => self.triggers.set_tdata2(value & self.xlen.mask()),
+            0x7a3 | 0x7a4 => {}
+            0x344 => {
+                let writable = if self.menvcfg().stce() {
+                    MIP_WRITABLE & !mip::STIP
+                } else {
+                    MIP_WRITABLE
+                };
+                self.csrs[0x344] = (self.csrs[0x344] & !writable) | (value & writable);
+            }
+            0x14d | 0x15d | 0x30a | 0x31a => {
+                // stimecmp and menvcfg are 64-bit, RV32 writes them a half at a time.
+                let (num, value) = match (self.xlen, csr_num) {
+                    (XLen::X64, _) => (csr_num as usize, value),
+                    (XLen::X32, 0x14d | 0x30a) => {
+                        let num = csr_num as usize;
+                        (num, self.csrs[num] & !0xffff_ffff | value & 0xffff_ffff)
+                    }
+                    (XLen::X32, _) => {
+                        let num = csr_num as usize - 0x10;
+                        (num, self.csrs[num] & 0xffff_ffff | value << 32)
+                    }
+                };
+                self.csrs[num] = if num == 0x30a {
+                    self.legalize_envcfg(0x30a, value)
+                } else {
+                    value
+                };
+                // STIP is read-only while stimecmp drives it.
+                if self.menvcfg().stce() {
+                    self.csrs[0x344] &= !mip::STIP;
+                }
+                self.update_stimer();
+            }
+            // cycle, time and instret follow mcycle, mtime and minstret, the CSR instructions can't
+            // write them.
+            0xc00..=0xc02 => {}
+            0x001..=0x003 => {
+                // fflags and frm are fields of fcsr.
+                let fcsr = self.csrs[0x003];
+                let fcsr = match csr_num {
+                    0x001 => (fcsr & !0x1f) | (value & 0x1f),
+                    0x002 => (fcsr & !0xe0) | ((value & 0x7) << 5),
+                    _ => value & 0xff,
+                };
+                self.csrs[0x001] = fcsr & 0x1f;
+                self.csrs[0x002] = fcsr >> 5;
+                self.csrs[0x003] = fcsr;
+                self.set_fs_dirty();
+            }
+            0x100 | 0x300 => {
+                // sstatus is a view of the supervisor fields of mstatus, SD only follows FS.
+                let value = if csr_num == 0x100 {
+                    (self.csrs[0x300] & !(SSTATUS_MASK | self.status_sd())) | value
+                } else {
+                    value
+                };
+                let value = if value & STATUS_FS == STATUS_FS {
+                    value | self.status_sd()
+                } else {
+                    value
+                };
+                self.csrs[0x300] = value;
+                self.csrs[0x100] = value & (SSTATUS_MASK | self.status_sd());
+            }
+            0x301 => {
+                // D depends on F, it can't stay on without it.
+                let value = if value & 1 << 5 == 0 {
+                    value & !(1 << 3)
+                } else {
+                    value
+                };
+                self.csrs[0x301] = (self.csrs[0x301] & !MISA_WRITABLE) | (value & MISA_WRITABLE);
+            }
+            0x3a0..=0x3a3 => {
+                // RV64 has no odd pmpcfg, pmpcfg0 and pmpcfg2 hold eight entries each.
+                if self.xlen == XLen::X64 && csr_num & 1 == 1 {
+                    return;
+                }
+                // Locked entries ignore writes. Under MML, so do the ones adding a rule machine mode
+                // could execute from.
+                let old = self.csrs[csr_num as usize];
+                let mml = self.csrs[0x747] & pmp::MSECCFG_MML != 0;
+                let rlb = self.csrs[0x747] & pmp::MSECCFG_RLB != 0;
+                let value = (0..self.xlen.len() / 8).fold(0, |cfgs, i| {
+                    let old_cfg = (old >> (i * 8)) as u8;
+                    let new_cfg = pmp::legalize_cfg((value >> (i * 8)) as u8, mml);
+                    let cfg = if self.pmp_locks(old_cfg)
+                        || (mml && !rlb && pmp::is_machine_executable(new_cfg))
+                    {
+                        old_cfg
+                    } else {
+                        new_cfg
+                    };
+                    cfgs | (cfg as RegT) << (i * 8)
+                });
+                self.csrs[csr_num as usize] = value;
+                self.update_pmp();
+            }
+            0x747 => {
+                self.csrs[0x747] = value;
+                self.update_pmp();
+            }
+            0x3b0..=0x3bf => {
+                // A locked entry also locks the bottom of its range if it's TOR.
+                let i = (csr_num - 0x3b0) as usize;
+                let cfg = self.pmpcfg(i);
+                let next_cfg = if i + 1 < PMP_ENTRIES {
+                    self.pmpcfg(i + 1)
+                } else {
+                    0
+                };
+                if self.pmp_locks(cfg) || (self.pmp_locks(next_cfg) && pmp::is_tor(next_cfg)) {
+                    return;
+                }
+                // pmpaddr holds bits 55:2 of an address on RV64, bits 33:2 on RV32.
+                let mask = match self.xlen {
+                    XLen::X32 => 0xffff_ffff,
+                    XLen::X64 => (1 << 54) - 1,
+                };
+                self.csrs[csr_num as usize] = value & mask;
+                self.update_pmp();
+            }
             _ => self.csrs[csr_num as usize] = value,
         }
     }
 
+    /// Advances mcycle by `cycles` and minstret by `retired`, the counts of a batch of steps.
+    pub fn advance_counters(&mut self, cycles: u64, retired: u64) {
+        self.csrs[0xb00] = self.csrs[0xb00].wrapping_add(cycles);
+        self.csrs[0xb02] = self.csrs[0xb02].wrapping_add(retired);
+    }
+
     csr!(satp, set_satp, 0x180, Satp);
     csr!(sstatus, set_sstatus, 0x100, Sstatus);
     csr!(mstatus, set_mstatus, 0x300, Mstatus);
@@ -81,5 +818,141 @@ impl Csrs {
     csr!(scause, set_scause, 0x142);
     csr!(mepc, set_mepc, 0x341);
     csr!(mcause, set_mcause, 0x342);
-    csr!(time, set_time, 0xc01);
+    csr!(scounteren, set_scounteren, 0x106);
+    csr!(mcounteren, set_mcounteren, 0x306);
+    csr!(mhartid, set_mhartid, 0xf14);
+    csr!(stimecmp, set_stimecmp, 0x14d);
+    csr!(menvcfg, set_menvcfg, 0x30a, Envcfg);
+    csr!(senvcfg, set_senvcfg, 0x10a, Envcfg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SSTATUS: u16 = 0x100;
+    const SEPC: u16 = 0x141;
+    const SCAUSE: u16 = 0x142;
+    const STVEC: u16 = 0x105;
+    const MSTATUS: u16 = 0x300;
+    const MTVEC: u16 = 0x305;
+    const MEPC: u16 = 0x341;
+    const MCAUSE: u16 = 0x342;
+
+    fn write(csrs: &mut Csrs, csr_num: u16, value: RegT) -> RegT {
+        csrs.set_csr(csr_num, value);
+        csrs.csr(csr_num)
+    }
+
+    #[test]
+    fn every_special_cased_csr_has_a_name() {
+        let special = LEGALIZE
+            .iter()
+            .map(|(num, _)| *num)
+            .chain([0x001, 0x002, 0x003, 0x104, 0x144, 0x301, 0x304, 0x344])
+            .chain([
+                0x143, 0x340, 0x343, 0xc01, 0xf14, 0x14d, 0x15d, 0x30a, 0x31a, 0x10a,
+            ])
+            .chain(0x3a0..=0x3a3)
+            .chain(0x3b0..0x3b0 + PMP_ENTRIES as u16)
+            .chain(0x7a0..=0x7a4)
+            .chain(0xc00..=0xc1f)
+            .chain(0xc80..=0xc9f)
+            .chain([0xb00, 0xb02, 0xb80, 0xb82])
+            .chain(0xb03..=0xb1f)
+            .chain(0xb83..=0xb9f)
+            .chain(0x323..=0x33f);
+        for num in special {
+            let name = Csrs::name(num).unwrap_or_else(|| panic!("csr {:#x} has no name", num));
+            assert_eq!(Csrs::by_name(name), Some(num), "{}", name);
+        }
+        for (name, num) in CSR_NAMES.iter() {
+            assert_eq!(Csrs::name(*num), Some(*name));
+            assert_eq!(Csrs::by_name(name), Some(*num));
+        }
+        assert_eq!(Csrs::name(0x7c0), None);
+        assert_eq!(Csrs::by_name("mstatush"), None);
+    }
+
+    #[test]
+    fn mstatus_keeps_the_implemented_fields() {
+        let mut csrs = Csrs::new(XLen::X64);
+        let sd = 1 << 63;
+        assert_eq!(write(&mut csrs, MSTATUS, RegT::MAX), MSTATUS_MASK | sd);
+        // The reserved MPP 2 keeps the old MPP.
+        write(&mut csrs, MSTATUS, 1 << 11);
+        assert_eq!(write(&mut csrs, MSTATUS, 2 << 11) & STATUS_MPP, 1 << 11);
+        assert_eq!(write(&mut csrs, MSTATUS, 3 << 11) & STATUS_MPP, 3 << 11);
+    }
+
+    #[test]
+    fn sstatus_is_a_view_of_mstatus() {
+        let mut csrs = Csrs::new(XLen::X64);
+        // MIE and MPP live in mstatus only.
+        let mie = 1 << 3;
+        write(&mut csrs, MSTATUS, mie | 3 << 11);
+        let sstatus = write(&mut csrs, SSTATUS, RegT::MAX);
+        assert_eq!(sstatus, SSTATUS_MASK | 1 << 63);
+        assert_eq!(csrs.csr(MSTATUS), SSTATUS_MASK | mie | 3 << 11 | 1 << 63);
+        write(&mut csrs, SSTATUS, 0);
+        assert_eq!(csrs.csr(MSTATUS), mie | 3 << 11);
+    }
+
+    #[test]
+    fn xepc_xtvec_and_xcause_hold_only_legal_values() {
+        let mut csrs = Csrs::new(XLen::X64);
+        // Without C, bits 0 and 1 of xepc are zero.
+        assert_eq!(write(&mut csrs, MEPC, 0x8000_0003), 0x8000_0000);
+        assert_eq!(write(&mut csrs, SEPC, 0x8000_0006), 0x8000_0004);
+        // The reserved modes fall back to direct.
+        assert_eq!(write(&mut csrs, MTVEC, 0x8000_0102), 0x8000_0100);
+        assert_eq!(write(&mut csrs, STVEC, 0x8000_0101), 0x8000_0101);
+        // Causes the hart can't raise leave xcause alone.
+        assert_eq!(write(&mut csrs, MCAUSE, 13), 13);
+        assert_eq!(write(&mut csrs, MCAUSE, 10), 13);
+        assert_eq!(write(&mut csrs, MCAUSE, 1 << 63 | 7), 1 << 63 | 7);
+        assert_eq!(write(&mut csrs, MCAUSE, 1 << 63 | 14), 1 << 63 | 7);
+        assert_eq!(write(&mut csrs, SCAUSE, 8), 8);
+        assert_eq!(write(&mut csrs, SCAUSE, 24), 8);
+
+        let mut csrs = Csrs::new(XLen::X32);
+        assert_eq!(write(&mut csrs, MEPC, 0x8000_0006), 0x8000_0004);
+        assert_eq!(write(&mut csrs, SEPC, 0x1_8000_0004), 0x8000_0004);
+        assert_eq!(write(&mut csrs, MCAUSE, 1 << 31 | 9), 1 << 31 | 9);
+    }
+
+    #[test]
+    fn mip_writes_leave_the_device_lines_alone() {
+        let mut csrs = Csrs::new(XLen::X64);
+        let writable = mip::SSIP | mip::STIP | mip::SEIP;
+        assert_eq!(write(&mut csrs, 0x344, RegT::MAX), writable);
+        // MTIP follows the CLINT, a write can't clear it.
+        csrs.set_interrupt_line(mip::MTIP, true);
+        assert_eq!(write(&mut csrs, 0x344, 0), mip::MTIP);
+        csrs.set_interrupt_line(mip::MTIP, false);
+        assert_eq!(csrs.csr(0x344), 0);
+        // Software can set SEIP on top of the PLIC's line, clearing it leaves the line.
+        csrs.set_interrupt_line(mip::SEIP, true);
+        assert_eq!(write(&mut csrs, 0x344, mip::SEIP), mip::SEIP);
+        assert_eq!(write(&mut csrs, 0x344, 0), mip::SEIP);
+    }
+
+    #[test]
+    fn sip_and_sie_are_views_of_the_delegated_bits() {
+        let mut csrs = Csrs::new(XLen::X64);
+        assert_eq!(write(&mut csrs, 0x302, RegT::MAX), 0xb3ff);
+        assert_eq!(write(&mut csrs, 0x303, RegT::MAX), MIDELEG_MASK);
+        assert_eq!(write(&mut csrs, 0x304, RegT::MAX), MIE_MASK);
+        write(&mut csrs, 0x303, mip::SSIP);
+        write(&mut csrs, 0x344, mip::STIP);
+        assert_eq!(csrs.csr(0x144), 0);
+        assert_eq!(write(&mut csrs, 0x144, RegT::MAX), mip::SSIP);
+        assert_eq!(csrs.csr(0x344), mip::SSIP | mip::STIP);
+        assert_eq!(csrs.csr(0x104), mip::SSIP);
+        // Clearing the delegation hides the bit again.
+        write(&mut csrs, 0x303, 0);
+        assert_eq!(csrs.csr(0x144), 0);
+        assert_eq!(write(&mut csrs, 0x104, 0), 0);
+        assert_eq!(csrs.csr(0x304), MIE_MASK);
+    }
 }
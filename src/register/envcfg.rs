@@ -0,0 +1,81 @@
+use bit_field::BitField;
+
+use crate::RegT;
+
+/// Fence of I/O implies Memory.
+pub const FIOM: RegT = 1 << 0;
+/// Cache Block Invalidate instruction Enable, a two-bit field.
+pub const CBIE: RegT = 0x3 << 4;
+/// Cache Block Clean and Flush instruction Enable.
+pub const CBCFE: RegT = 1 << 6;
+/// Cache Block Zero instruction Enable.
+pub const CBZE: RegT = 1 << 7;
+/// STimecmp Enable, only in menvcfg.
+pub const STCE: RegT = 1 << 63;
+
+/// What cbo.inval does in the modes the CBIE field applies to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Cbie {
+    /// cbo.inval raises an illegal instruction exception.
+    Illegal = 0,
+    /// cbo.inval is performed as cbo.flush.
+    Flush = 1,
+    /// cbo.inval invalidates the block.
+    Invalidate = 3,
+}
+
+/// menvcfg and senvcfg registers, the environment configuration of the next lower privilege
+/// mode. senvcfg only has the fields up to CBZE.
+#[derive(Clone, Copy, Debug)]
+pub struct Envcfg {
+    bits: RegT,
+}
+
+impl From<RegT> for Envcfg {
+    fn from(r: RegT) -> Self {
+        Self { bits: r }
+    }
+}
+
+impl Envcfg {
+    /// Returns the contents of the register as raw bits
+    #[inline]
+    pub fn bits(&self) -> RegT {
+        self.bits
+    }
+
+    /// Fence of I/O implies Memory. Harts run one access at a time, so it has no effect.
+    #[inline]
+    pub fn fiom(&self) -> bool {
+        self.bits.get_bit(0)
+    }
+
+    /// Cache Block Invalidate instruction Enable. The reserved encoding 2 is never held, see
+    /// `Csrs::legalize_envcfg`.
+    #[inline]
+    pub fn cbie(&self) -> Cbie {
+        match self.bits.get_bits(4..6) {
+            0 => Cbie::Illegal,
+            1 => Cbie::Flush,
+            _ => Cbie::Invalidate,
+        }
+    }
+
+    /// Cache Block Clean and Flush instruction Enable
+    #[inline]
+    pub fn cbcfe(&self) -> bool {
+        self.bits.get_bit(6)
+    }
+
+    /// Cache Block Zero instruction Enable
+    #[inline]
+    pub fn cbze(&self) -> bool {
+        self.bits.get_bit(7)
+    }
+
+    /// STimecmp Enable
+    #[inline]
+    pub fn stce(&self) -> bool {
+        self.bits.get_bit(63)
+    }
+}
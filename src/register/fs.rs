@@ -0,0 +1,66 @@
+use std::io::{self, Read, Write};
+
+use crate::snapshot;
+
+/// The canonical NaN of single precision, also what a single-precision read of a register that
+/// doesn't hold a NaN-boxed value returns.
+const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+
+/// Floating-point registers. They are 64 bits wide, a single-precision value is NaN-boxed by
+/// setting the upper 32 bits.
+#[derive(Clone)]
+pub struct Fs {
+    regs: [u64; 32],
+}
+
+impl Fs {
+    pub fn new() -> Self {
+        Self { regs: [0; 32] }
+    }
+
+    // Id must be one of [0~32).
+    pub fn reg(&self, id: u8) -> u64 {
+        debug_assert!(id < 32, "Id must be one of [0~32). got: {}", id);
+        self.regs[id as usize]
+    }
+
+    // Id must be one of [0~32).
+    pub fn set_reg(&mut self, id: u8, value: u64) {
+        debug_assert!(id < 32, "Id must be one of [0~32). got: {}", id);
+        self.regs[id as usize] = value;
+    }
+
+    pub fn f32(&self, id: u8) -> f32 {
+        let bits = self.reg(id);
+        if bits >> 32 == 0xffff_ffff {
+            f32::from_bits(bits as u32)
+        } else {
+            f32::from_bits(CANONICAL_NAN_F32)
+        }
+    }
+
+    pub fn set_f32(&mut self, id: u8, value: f32) {
+        self.set_reg(id, 0xffff_ffff_0000_0000 | value.to_bits() as u64);
+    }
+
+    pub fn f64(&self, id: u8) -> f64 {
+        f64::from_bits(self.reg(id))
+    }
+
+    pub fn set_f64(&mut self, id: u8, value: f64) {
+        self.set_reg(id, value.to_bits());
+    }
+
+    pub fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.regs
+            .iter()
+            .try_for_each(|reg| snapshot::write_u64(w, *reg))
+    }
+
+    pub fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for reg in self.regs.iter_mut() {
+            *reg = snapshot::read_u64(r)?;
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,17 @@ use bit_field::BitField;
 
 use crate::RegT;
 
+/// Supervisor software interrupt pending.
+pub const SSIP: RegT = 1 << 1;
+/// Machine software interrupt pending, driven by the CLINT's msip.
+pub const MSIP: RegT = 1 << 3;
+/// Supervisor timer interrupt pending.
+pub const STIP: RegT = 1 << 5;
+/// Machine timer interrupt pending, driven by the CLINT's mtimecmp.
+pub const MTIP: RegT = 1 << 7;
+/// Supervisor external interrupt pending, software can set it on top of the PLIC's line.
+pub const SEIP: RegT = 1 << 9;
+
 /// mip register
 #[derive(Clone, Copy, Debug)]
 pub struct Mip {
@@ -1,4 +1,6 @@
 pub mod csrs;
+pub mod envcfg;
+pub mod fs;
 pub mod medeleg;
 pub mod mideleg;
 pub mod mie;
@@ -27,7 +27,21 @@ impl Mstatus {
     }
 
     pub fn set_mpp(&mut self, pm: PrivilegeMode) {
-        self.bits.set_bits(11..13, pm as RegT);
+        let mpp = match pm {
+            PrivilegeMode::User => 0b00,
+            PrivilegeMode::Supervisor => 0b01,
+            PrivilegeMode::Machine => 0b11,
+        };
+        self.bits.set_bits(11..13, mpp);
+    }
+
+    pub fn set_mprv(&mut self, mprv: bool) {
+        self.bits.set_bit(17, mprv);
+    }
+
+    /// Sets the status of the floating-point state, see `fs`.
+    pub fn set_fs(&mut self, fs: RegT) {
+        self.bits.set_bits(13..15, fs & 0b11);
     }
 
     /// User Interrupt Enable
@@ -80,9 +94,17 @@ impl Mstatus {
             0b00 => PrivilegeMode::User,
             0b01 => PrivilegeMode::Supervisor,
             0b11 => PrivilegeMode::Machine,
-            _ => unreachable!(),
+            // Csrs legalizes writes, but a restored snapshot may still hold the reserved 0b10.
+            _ => PrivilegeMode::User,
         }
     }
+    /// Modify PRiVilege, loads and stores are translated and protected as though the privilege
+    /// mode were MPP
+    #[inline]
+    pub fn mprv(&self) -> bool {
+        self.bits.get_bit(17)
+    }
+
     /// Permit Supervisor User Memory access
     #[inline]
     pub fn sum(&self) -> bool {
@@ -136,10 +158,17 @@ impl Mstatus {
         is ratified, there should be read functions of these bits as well.
     */
 
+    /// Status of the floating-point state: 0 Off, 1 Initial, 2 Clean or 3 Dirty
+    #[inline]
+    pub fn fs(&self) -> RegT {
+        self.bits.get_bits(13..15)
+    }
+
     /// Whether either the FS field or XS field
-    /// signals the presence of some dirty state
+    /// signals the presence of some dirty state. Derived from them rather than read from the top
+    /// bit, which moves with XLEN.
     #[inline]
     pub fn sd(&self) -> bool {
-        self.bits.get_bit(std::mem::size_of::<usize>() * 8 - 1)
+        self.fs() == 3 || self.bits.get_bits(15..17) == 3
     }
 }
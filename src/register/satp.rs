@@ -14,6 +14,12 @@ impl From<RegT> for Satp {
 }
 
 impl Satp {
+    /// Returns the contents of the register as raw bits
+    #[inline]
+    pub fn bits(&self) -> RegT {
+        self.bits
+    }
+
     /// Current address-translation scheme
     #[inline]
     pub fn mode(&self, xlen: &XLen) -> Mode {
@@ -28,7 +34,8 @@ impl Satp {
             9 => Mode::Sv48,
             10 => Mode::Sv57,
             11 => Mode::Sv64,
-            _ => unreachable!(),
+            // Csrs ignores writes of reserved modes, but a restored snapshot may still hold one.
+            _ => Mode::Bare,
         }
     }
 
@@ -34,6 +34,11 @@ impl Sstatus {
         }
     }
 
+    /// Sets the status of the floating-point state, see `fs`.
+    pub fn set_fs(&mut self, fs: RegT) {
+        self.bits.set_bits(13..15, fs & 0b11);
+    }
+
     /// User Interrupt Enable
     #[inline]
     pub fn uie(&self) -> bool {
@@ -78,10 +83,17 @@ impl Sstatus {
         self.bits.get_bit(19)
     }
 
+    /// Status of the floating-point state: 0 Off, 1 Initial, 2 Clean or 3 Dirty
+    #[inline]
+    pub fn fs(&self) -> RegT {
+        self.bits.get_bits(13..15)
+    }
+
     /// Whether either the FS field or XS field
-    /// signals the presence of some dirty state
+    /// signals the presence of some dirty state. Derived from them rather than read from the top
+    /// bit, which moves with XLEN.
     #[inline]
     pub fn sd(&self) -> bool {
-        self.bits.get_bit(std::mem::size_of::<usize>() * 8 - 1)
+        self.fs() == 3 || self.bits.get_bits(15..17) == 3
     }
 }
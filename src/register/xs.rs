@@ -1,5 +1,18 @@
-use crate::RegT;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
 
+use crate::{snapshot, RegT};
+
+/// ABI names of the integer registers.
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+#[derive(Clone, PartialEq)]
 pub struct Xs {
     regs: [RegT; 32],
 }
@@ -24,4 +37,113 @@ impl Xs {
             self.regs[id as usize] = value
         }
     }
+
+    /// Returns the registers in order as (index, ABI name, value).
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &'static str, RegT)> + '_ {
+        ABI_NAMES
+            .iter()
+            .enumerate()
+            .map(move |(id, name)| (id as u8, *name, self.reg(id as u8)))
+    }
+
+    /// Returns the register called `name`, an ABI name like "a0", "fp" for s0, or "x10".
+    pub fn get_by_name(&self, name: &str) -> Option<RegT> {
+        let id = match name {
+            "fp" => 8,
+            _ => match name.strip_prefix('x').and_then(|n| n.parse::<u8>().ok()) {
+                Some(id) if id < 32 => id,
+                _ => ABI_NAMES.iter().position(|abi| *abi == name)? as u8,
+            },
+        };
+        Some(self.reg(id))
+    }
+
+    /// Returns the registers that differ between `before` and `after` as (index, before, after).
+    pub fn diff(before: &Xs, after: &Xs) -> Vec<(u8, RegT, RegT)> {
+        before
+            .iter()
+            .zip(after.iter())
+            .filter(|((_, _, old), (_, _, new))| old != new)
+            .map(|((id, _, old), (_, _, new))| (id, old, new))
+            .collect()
+    }
+
+    pub fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.regs
+            .iter()
+            .try_for_each(|reg| snapshot::write_u64(w, *reg))
+    }
+
+    pub fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for reg in self.regs.iter_mut() {
+            *reg = snapshot::read_u64(r)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the registers with their ABI names, four per line.
+impl fmt::Display for Xs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (id, name) in ABI_NAMES.iter().enumerate() {
+            let sep = if id % 4 == 3 { "\n" } else { "  " };
+            write!(f, "{:<4} {:#018x}{}", name, self.reg(id as u8), sep)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Xs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Xs {{\n{}}}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::Cpu, testing::*, XLen};
+
+    #[test]
+    fn registers_are_found_by_name() {
+        let mut xs = Xs::new();
+        for id in 1..32 {
+            xs.set_reg(id, id as RegT * 0x10);
+        }
+        assert_eq!(xs.get_by_name("zero"), Some(0));
+        assert_eq!(xs.get_by_name("a0"), Some(0xa0));
+        assert_eq!(xs.get_by_name("fp"), xs.get_by_name("s0"));
+        assert_eq!(xs.get_by_name("x31"), xs.get_by_name("t6"));
+        assert_eq!(xs.get_by_name("x32"), None);
+        assert_eq!(xs.get_by_name("a8"), None);
+        for (id, name, value) in xs.iter() {
+            assert_eq!(xs.get_by_name(name), Some(value));
+            assert_eq!(xs.get_by_name(&format!("x{}", id)), Some(value));
+        }
+        let dump = format!("{:?}", xs);
+        assert!(dump.contains("a0   0x00000000000000a0"), "{}", dump);
+        assert_eq!(dump.lines().count(), 10);
+    }
+
+    #[test]
+    fn diff_lists_the_written_registers() {
+        // a0 = 5; a1 = a0 + 1; a0 = a0; x0 = 9
+        let program = [
+            addi(10, 0, 5),
+            addi(11, 10, 1),
+            addi(10, 10, 0),
+            addi(0, 0, 9),
+        ];
+        let mut cpu = Cpu::with_program(XLen::X64, &program);
+        let before = cpu.state.xs.clone();
+        cpu.one_step();
+        cpu.one_step();
+        assert_eq!(Xs::diff(&before, &cpu.state.xs), [(10, 0, 5), (11, 0, 6)]);
+        // Writing the same value or x0 changes nothing.
+        let before = cpu.state.xs.clone();
+        cpu.one_step();
+        cpu.one_step();
+        assert_eq!(Xs::diff(&before, &cpu.state.xs), []);
+        assert_regs!(cpu.state.xs, a0 = 5, a1 = 6, zero = 0);
+    }
 }
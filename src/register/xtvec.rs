@@ -19,9 +19,11 @@ pub enum TrapMode {
 }
 
 impl TrapMode {
-    pub fn trap_pc(&self, base: RegT, cause: RegT, is_interrupt: bool) -> RegT {
+    /// Returns the address of the handler of a trap with the exception `code`, which is xcause
+    /// without the interrupt bit. Vectored mode only moves interrupts.
+    pub fn trap_pc(&self, base: RegT, code: RegT, is_interrupt: bool) -> RegT {
         let offset = if is_interrupt && &TrapMode::Vectored == self {
-            4 * cause
+            4 * code
         } else {
             0
         };
@@ -0,0 +1,187 @@
+//! Reports of the machine state for diagnosing a guest that hit a fatal exception, seems stuck or
+//! was stopped for running too long.
+
+use std::fmt;
+
+use crate::{
+    register::xs::Xs,
+    trap::{Exception, Trap},
+    PrivilegeMode, RegT,
+};
+
+/// The number of recently executed instructions kept for a report.
+pub const HISTORY_LEN: usize = 64;
+
+/// A ring buffer of the pc and instruction word of the last `HISTORY_LEN` executed instructions.
+pub struct History {
+    entries: [(RegT, u32); HISTORY_LEN],
+    next: usize,
+    len: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            entries: [(0, 0); HISTORY_LEN],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, pc: RegT, code: u32) {
+        self.entries[self.next] = (pc, code);
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.len = (self.len + 1).min(HISTORY_LEN);
+    }
+
+    /// Returns the recorded instructions, oldest first.
+    pub fn to_vec(&self) -> Vec<(RegT, u32)> {
+        let start = (self.next + HISTORY_LEN - self.len) % HISTORY_LEN;
+        (0..self.len)
+            .map(|i| self.entries[(start + i) % HISTORY_LEN])
+            .collect()
+    }
+}
+
+/// The state of a hart for a report: where it is, its registers and what it executed last.
+pub struct StateReport {
+    pub privilege: PrivilegeMode,
+    pub pc: RegT,
+    /// pc as `name+offset` if it is covered by a loaded symbol.
+    pub pc_symbol: Option<String>,
+    /// The instruction word at pc and its mnemonic, `None` if it wasn't executed last, e.g. the
+    /// exception was raised before the instruction could be fetched or decoded.
+    pub insn: Option<(u32, String)>,
+    pub xs: Xs,
+    /// mstatus, mcause, mepc, mtval and satp by name.
+    pub csrs: Vec<(&'static str, RegT)>,
+    /// The last executed instructions as (pc, instruction word, symbol), oldest first.
+    pub history: Vec<(RegT, u32, Option<String>)>,
+}
+
+impl fmt::Display for StateReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pc   {:#018x}", self.pc)?;
+        if let Some(symbol) = &self.pc_symbol {
+            write!(f, " <{}>", symbol)?;
+        }
+        write!(f, "  privilege {:?}", self.privilege)?;
+        match &self.insn {
+            Some((code, name)) => writeln!(f, "  insn {:#010x} ({})", code, name)?,
+            None => writeln!(f)?,
+        }
+        write!(f, "{}", self.xs)?;
+        for (name, value) in self.csrs.iter() {
+            writeln!(f, "{:<8} {:#018x}", name, value)?;
+        }
+        writeln!(f, "last {} instructions, newest last:", self.history.len())?;
+        for (pc, code, symbol) in self.history.iter() {
+            write!(f, "  {:#018x}: {:#010x}", pc, code)?;
+            match symbol {
+                Some(symbol) => writeln!(f, " <{}>", symbol)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The machine state at a fatal exception.
+pub struct FaultReport {
+    pub exception: Exception,
+    pub state: StateReport,
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "fatal exception: {} (cause {})",
+            self.exception,
+            self.exception.code()
+        )?;
+        write!(f, "{}", self.state)
+    }
+}
+
+/// A fault raised while fetching the first instruction of an M-mode trap handler, which leaves
+/// no handler to take it.
+pub struct DoubleFault {
+    /// The trap whose handler couldn't be fetched.
+    pub first: Trap,
+    /// The pc `first` was taken at.
+    pub first_epc: RegT,
+    /// The fault of the handler fetch, pc is the trap vector.
+    pub report: FaultReport,
+}
+
+impl fmt::Display for DoubleFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "double fault: fetching the handler of {} taken at {:#x} faulted",
+            self.first, self.first_epc
+        )?;
+        write!(f, "{}", self.report)
+    }
+}
+
+/// The heuristic of `HangDetection` that found the guest stuck.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hang {
+    /// The instruction at pc ran this many times in a row without changing a register, e.g. the
+    /// `j .` of a panic.
+    SamePc(u64),
+    /// pc went below the DRAM with translation off, e.g. after a call through a null pointer.
+    LowPc,
+    /// This many traps were taken without an xRET, e.g. a trap handler that faults right away.
+    TrapStorm(u64),
+}
+
+impl fmt::Display for Hang {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hang::SamePc(steps) => write!(f, "the same pc ran {} times in a row", steps),
+            Hang::LowPc => write!(f, "pc is below the DRAM with translation off"),
+            Hang::TrapStorm(traps) => write!(f, "{} traps were taken without an xRET", traps),
+        }
+    }
+}
+
+/// The machine state of a guest `HangDetection` found stuck.
+pub struct SuspectedHang {
+    pub hang: Hang,
+    pub state: StateReport,
+}
+
+impl fmt::Display for SuspectedHang {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "suspected hang: {}", self.hang)?;
+        write!(f, "{}", self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_keeps_the_last_instructions_oldest_first() {
+        let mut history = History::new();
+        assert!(history.to_vec().is_empty());
+        for pc in 0..3 {
+            history.push(pc, pc as u32);
+        }
+        assert_eq!(history.to_vec(), [(0, 0), (1, 1), (2, 2)]);
+        for pc in 3..HISTORY_LEN as RegT + 5 {
+            history.push(pc, pc as u32);
+        }
+        let kept = history.to_vec();
+        assert_eq!(kept.len(), HISTORY_LEN);
+        assert_eq!(kept[0], (5, 5));
+        assert_eq!(
+            kept[HISTORY_LEN - 1],
+            (HISTORY_LEN as RegT + 4, HISTORY_LEN as u32 + 4)
+        );
+    }
+}
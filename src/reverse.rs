@@ -0,0 +1,244 @@
+//! Running a hart backwards. Every so many steps a checkpoint is kept in host memory, a full one
+//! first and then only the pages written since the one before. Going back to step K restores the
+//! last checkpoint before K and replays the steps up to K, which land in the same state as the
+//! first time as long as the run is deterministic: `--deterministic`, and no console input in the
+//! steps replayed. Replaying a range while watching the breakpoints and watchpoints finds the last
+//! one hit before the current step, which is where `reverse_continue` stops.
+
+use std::{collections::BTreeSet, io};
+
+use crate::{
+    cpu::{Cpu, StopReason},
+    watchpoint::WatchHit,
+    RegT,
+};
+
+/// Why `History::reverse_continue` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReverseStop {
+    /// pc is at a breakpoint.
+    Breakpoint,
+    /// The next step makes this access, which hits a watchpoint.
+    Watchpoint(WatchHit),
+    /// Nothing was hit since the oldest checkpoint, the hart is back at it.
+    Oldest,
+}
+
+/// The checkpoints of a hart, taken by `record`.
+pub struct History {
+    interval: u64,
+    /// The step each checkpoint was taken at and its contents, in increasing order of steps.
+    checkpoints: Vec<(u64, Vec<u8>)>,
+}
+
+impl History {
+    /// Keeps a checkpoint every `interval` steps.
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval: interval.max(1),
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// Called before every step. Takes a checkpoint if the last one is `interval` steps old. The
+    /// history starts over if the hart went back in steps without it, i.e. the machine was reset.
+    /// Takes over the dirty page tracking of the hart, see `Cpu::save_checkpoint`.
+    pub fn record(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        let steps = cpu.steps();
+        match self.checkpoints.last() {
+            Some(&(last, _)) if steps < last => self.checkpoints.clear(),
+            Some(&(last, _)) if steps - last < self.interval => return Ok(()),
+            _ => {}
+        }
+        let parent = self
+            .checkpoints
+            .last()
+            .map(|(last, _)| format!("step {}", last));
+        let mut checkpoint = Vec::new();
+        cpu.save_checkpoint(&mut checkpoint, parent.as_deref())?;
+        self.checkpoints.push((steps, checkpoint));
+        Ok(())
+    }
+
+    /// The step of the oldest checkpoint, the furthest back the hart can go.
+    pub fn oldest(&self) -> Option<u64> {
+        self.checkpoints.first().map(|(steps, _)| *steps)
+    }
+
+    /// Forgets all checkpoints, e.g. after the hart was restored from a snapshot.
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+    }
+
+    /// Puts the hart back to the state it was in after `target` steps, which must be at or after
+    /// the oldest checkpoint and before the current step. The checkpoints after `target` are
+    /// dropped, the ones taken when the hart runs forward again replace them.
+    pub fn go_to(&mut self, cpu: &mut Cpu, target: u64) -> io::Result<()> {
+        let last = self
+            .checkpoints
+            .iter()
+            .rposition(|(steps, _)| *steps <= target)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("step {} is before the oldest checkpoint", target),
+                )
+            })?;
+        self.checkpoints.truncate(last + 1);
+        self.restore(cpu, last)?;
+        replay(cpu, target, |_, _, _| {})
+    }
+
+    /// Goes back to the last step before the current one where pc was at one of `breakpoints` or
+    /// the hart was about to make an access hitting a watchpoint. Goes back to the oldest
+    /// checkpoint if there is no such step.
+    pub fn reverse_continue(
+        &mut self,
+        cpu: &mut Cpu,
+        breakpoints: &BTreeSet<RegT>,
+    ) -> io::Result<ReverseStop> {
+        let oldest = self
+            .oldest()
+            .ok_or_else(|| io::Error::other("no checkpoint recorded yet"))?;
+        let now = cpu.steps();
+        let mut found = None;
+        // The segments between the checkpoints, the newest first, until one holds a hit.
+        for i in (0..self.checkpoints.len()).rev() {
+            let end = self.checkpoints.get(i + 1).map_or(now, |(steps, _)| *steps);
+            self.restore(cpu, i)?;
+            replay(cpu, end, |step, pc, hit| match hit {
+                Some(hit) => found = Some((step, ReverseStop::Watchpoint(hit))),
+                None if breakpoints.contains(&pc) => found = Some((step, ReverseStop::Breakpoint)),
+                None => {}
+            })?;
+            if found.is_some() {
+                break;
+            }
+        }
+        let (target, stop) = found.unwrap_or((oldest, ReverseStop::Oldest));
+        self.go_to(cpu, target)?;
+        Ok(stop)
+    }
+
+    /// Restores checkpoint `i` after the ones it's based on.
+    fn restore(&self, cpu: &mut Cpu, i: usize) -> io::Result<()> {
+        for (_, checkpoint) in &self.checkpoints[..=i] {
+            cpu.load_snapshot(&checkpoint[..])?;
+        }
+        // The memory is exactly as it was at the checkpoint, the next one holds the pages written
+        // from here on.
+        cpu.mmu.bus.borrow_mut().take_dirty_pages();
+        Ok(())
+    }
+}
+
+/// Steps the hart until it took `end` steps. Calls `visit` with the step, pc and the watchpoint
+/// hit if any of every step taken.
+fn replay(
+    cpu: &mut Cpu,
+    end: u64,
+    mut visit: impl FnMut(u64, RegT, Option<WatchHit>),
+) -> io::Result<()> {
+    while cpu.steps() < end {
+        let (step, pc) = (cpu.steps(), cpu.state.pc);
+        let hit = match cpu.one_step() {
+            Some(StopReason::Watchpoint(hit)) => Some(hit),
+            Some(StopReason::Reset) | Some(StopReason::Exit(_)) => {
+                return Err(io::Error::other("the replay ran past the end of the run"))
+            }
+            // The other reasons stopped the recorded run as well, which went on the same way.
+            _ => None,
+        };
+        visit(step, pc, hit);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::DRAM_BASE, testing::*, watchpoint::WatchKind, XLen};
+
+    fn counter() -> Cpu {
+        let mut cpu = Cpu::with_program(XLen::X64, &COUNTER);
+        cpu.state.xs.set_reg(11, COUNTS);
+        cpu
+    }
+
+    fn run(cpu: &mut Cpu, history: &mut History, steps: u64) {
+        for _ in 0..steps {
+            history.record(cpu).unwrap();
+            cpu.one_step();
+        }
+    }
+
+    #[test]
+    fn going_back_lands_in_the_state_of_the_first_run() {
+        let mut cpu = counter();
+        let mut history = History::new(10);
+        let mut states = Vec::new();
+        for _ in 0..100 {
+            states.push((cpu.state.pc, cpu.state.xs.reg(10), cpu.state.xs.reg(11)));
+            run(&mut cpu, &mut history, 1);
+        }
+        assert_eq!(history.oldest(), Some(0));
+
+        for target in [97, 55, 50, 3] {
+            history.go_to(&mut cpu, target).unwrap();
+            assert_eq!(cpu.steps(), target);
+            let state = (cpu.state.pc, cpu.state.xs.reg(10), cpu.state.xs.reg(11));
+            assert_eq!(state, states[target as usize]);
+            // The counts stored later are gone.
+            let next = cpu.state.xs.reg(11) + 4;
+            assert_eq!(cpu.mmu.bus.borrow().read::<u32>(next).unwrap(), 0);
+        }
+
+        // Forward again, the checkpoints taken on the way replace the dropped ones.
+        run(&mut cpu, &mut history, 60);
+        history.go_to(&mut cpu, 42).unwrap();
+        assert_eq!(cpu.state.xs.reg(10), states[42].1);
+    }
+
+    #[test]
+    fn reverse_continue_stops_before_the_store_that_hit_the_watchpoint() {
+        let mut cpu = counter();
+        let mut history = History::new(16);
+        // The 5th count goes to the watched word, the run goes on to the 30th.
+        let watched = COUNTS + 4 * 4;
+        cpu.add_watchpoint(watched, 4, WatchKind::Write);
+        run(&mut cpu, &mut history, 120);
+        assert_eq!(cpu.mmu.bus.borrow().read::<u32>(watched).unwrap(), 5);
+
+        let stop = history
+            .reverse_continue(&mut cpu, &BTreeSet::new())
+            .unwrap();
+        let hit = match stop {
+            ReverseStop::Watchpoint(hit) => hit,
+            stop => panic!("stopped for {:?}", stop),
+        };
+        assert_eq!((hit.addr, hit.value, hit.old), (watched, 5, Some(0)));
+        // pc is at the store, which hasn't happened yet.
+        assert_eq!(cpu.state.pc, DRAM_BASE + 4);
+        assert_eq!(hit.pc, cpu.state.pc);
+        assert_eq!(cpu.state.xs.reg(10), 5);
+        assert_eq!(cpu.mmu.bus.borrow().read::<u32>(watched).unwrap(), 0);
+        assert!(matches!(cpu.one_step(), Some(StopReason::Watchpoint(_))));
+        assert_eq!(cpu.mmu.bus.borrow().read::<u32>(watched).unwrap(), 5);
+
+        // Nothing hits before that.
+        cpu.mmu.watchpoints.remove(watched);
+        let breakpoints = BTreeSet::from([DRAM_BASE + 8]);
+        assert_eq!(
+            history.reverse_continue(&mut cpu, &breakpoints).unwrap(),
+            ReverseStop::Breakpoint
+        );
+        assert_eq!((cpu.state.pc, cpu.state.xs.reg(10)), (DRAM_BASE + 8, 4));
+        assert_eq!(
+            history
+                .reverse_continue(&mut cpu, &BTreeSet::new())
+                .unwrap(),
+            ReverseStop::Oldest
+        );
+        assert_eq!((cpu.steps(), cpu.state.pc), (0, DRAM_BASE));
+    }
+}
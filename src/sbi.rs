@@ -0,0 +1,416 @@
+//! A built-in SBI implementation, so an S-mode kernel boots without firmware such as OpenSBI. The
+//! hart starts in S-mode with the traps and interrupts delegated, and the ECALLs from S-mode are
+//! served here, dispatched on the extension id in a7 and the function id in a6. An error code is
+//! returned in a0 and a value in a1, the legacy extensions only return a0.
+
+use log::debug;
+
+use crate::{
+    cpu::Cpu,
+    device::{
+        uart::{UART_LSR, UART_LSR_RX, UART_RHR, UART_THR},
+        UART_BASE,
+    },
+    hook::EcallHandler,
+    register::envcfg,
+    trap::Exception,
+    PrivilegeMode, RegT, XLen,
+};
+
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+
+const EXT_LEGACY_SET_TIMER: RegT = 0x00;
+const EXT_LEGACY_PUTCHAR: RegT = 0x01;
+const EXT_LEGACY_GETCHAR: RegT = 0x02;
+const EXT_LEGACY_SHUTDOWN: RegT = 0x08;
+const EXT_BASE: RegT = 0x10;
+/// "TIME"
+const EXT_TIME: RegT = 0x5449_4d45;
+/// "sPI"
+const EXT_IPI: RegT = 0x73_5049;
+/// "SRST"
+const EXT_SRST: RegT = 0x5352_5354;
+/// "DBCN"
+const EXT_DBCN: RegT = 0x4442_434e;
+
+/// The extensions `probe_extension` reports as available.
+const EXTENSIONS: [RegT; 9] = [
+    EXT_LEGACY_SET_TIMER,
+    EXT_LEGACY_PUTCHAR,
+    EXT_LEGACY_GETCHAR,
+    EXT_LEGACY_SHUTDOWN,
+    EXT_BASE,
+    EXT_TIME,
+    EXT_IPI,
+    EXT_SRST,
+    EXT_DBCN,
+];
+
+/// SBI 2.0: the major version in bits 24..31, the minor version below.
+const SPEC_VERSION: RegT = 2 << 24;
+/// Not one of the registered implementation ids.
+const IMPL_ID: RegT = 0x5250_4d45;
+const IMPL_VERSION: RegT = 1;
+
+const SRST_SHUTDOWN: RegT = 0;
+const SRST_COLD_REBOOT: RegT = 1;
+const SRST_WARM_REBOOT: RegT = 2;
+/// The reset reason of a `system_reset` reporting a failure.
+const SRST_SYSTEM_FAILURE: RegT = 1;
+
+/// Exceptions left to the kernel: all but the ECALLs from S-mode, which are served here.
+const MEDELEG: RegT = !(1 << 9);
+/// Interrupts left to the kernel, the machine-level ones can't be delegated anyway.
+const MIDELEG: RegT = RegT::MAX;
+/// mcounteren: S-mode may read cycle, time and instret.
+const MCOUNTEREN: RegT = 0x7;
+
+/// Puts `cpu` into the state the firmware leaves a kernel in: S-mode with the traps delegated,
+/// the counters readable and Sstc on for `set_timer`. Every ECALL is passed to `Sbi`.
+pub fn install(cpu: &mut Cpu) {
+    cpu.state.privilege = PrivilegeMode::Supervisor;
+    let csrs = &mut cpu.state.csrs;
+    csrs.set_medeleg(MEDELEG);
+    csrs.set_mideleg(MIDELEG);
+    csrs.set_mcounteren(MCOUNTEREN);
+    match cpu.xlen {
+        XLen::X32 => csrs.set_csr(0x31a, envcfg::STCE >> 32),
+        XLen::X64 => csrs.set_menvcfg(envcfg::STCE),
+    }
+    cpu.set_ecall_handler(Box::new(Sbi));
+}
+
+/// Serves the ECALLs from S-mode. The others raise the environment call exception as usual, the
+/// kernel takes those from U-mode through the delegation.
+pub struct Sbi;
+
+impl EcallHandler for Sbi {
+    fn ecall(&mut self, cpu: &mut Cpu) -> Result<(), Exception> {
+        match cpu.state.privilege {
+            PrivilegeMode::User => return Err(Exception::UserEnvCall),
+            PrivilegeMode::Machine => return Err(Exception::MachineEnvCall),
+            PrivilegeMode::Supervisor => {}
+        }
+        let xs = &cpu.state.xs;
+        let (ext, fid) = (xs.reg(17), xs.reg(16));
+        let args = [xs.reg(10), xs.reg(11), xs.reg(12)];
+        let legacy = ext < EXT_BASE;
+        let (error, value) = match ext {
+            EXT_LEGACY_SET_TIMER => set_timer(cpu, args[0], args[1]),
+            EXT_LEGACY_PUTCHAR => {
+                write_console(cpu, args[0] as u8)?;
+                (SBI_SUCCESS, 0)
+            }
+            EXT_LEGACY_GETCHAR => match read_console(cpu)? {
+                Some(c) => (c as i64, 0),
+                None => (-1, 0),
+            },
+            EXT_LEGACY_SHUTDOWN => {
+                cpu.exit(0);
+                (SBI_SUCCESS, 0)
+            }
+            EXT_BASE => base(cpu, fid, args[0]),
+            EXT_TIME if fid == 0 => set_timer(cpu, args[0], args[1]),
+            // There is only the calling hart to send an IPI to, which needs none.
+            EXT_IPI if fid == 0 => (SBI_SUCCESS, 0),
+            EXT_SRST if fid == 0 => system_reset(cpu, args[0], args[1]),
+            EXT_DBCN => debug_console(cpu, fid, args)?,
+            _ => {
+                debug!(
+                    "unsupported SBI call {:#x}:{} at {:#x}",
+                    ext, fid, cpu.state.pc
+                );
+                (SBI_ERR_NOT_SUPPORTED, 0)
+            }
+        };
+        let mask = cpu.xlen.mask();
+        cpu.state.xs.set_reg(10, error as RegT & mask);
+        if !legacy {
+            cpu.state.xs.set_reg(11, value & mask);
+        }
+        cpu.state.advance_pc(4);
+        Ok(())
+    }
+}
+
+/// The base extension, which describes the implementation.
+fn base(cpu: &Cpu, fid: RegT, arg: RegT) -> (i64, RegT) {
+    let value = match fid {
+        0 => SPEC_VERSION,
+        1 => IMPL_ID,
+        2 => IMPL_VERSION,
+        3 => EXTENSIONS.contains(&arg) as RegT,
+        // mvendorid, marchid and mimpid.
+        4..=6 => cpu.state.csrs.csr(0xf11 + fid as u16 - 4),
+        _ => return (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    (SBI_SUCCESS, value)
+}
+
+/// Programs the next timer interrupt through stimecmp, which also clears a pending STIP. RV32
+/// passes the 64-bit time in a0 and a1.
+fn set_timer(cpu: &mut Cpu, low: RegT, high: RegT) -> (i64, RegT) {
+    let csrs = &mut cpu.state.csrs;
+    match cpu.xlen {
+        XLen::X32 => {
+            csrs.set_csr(0x15d, high);
+            csrs.set_csr(0x14d, low);
+        }
+        XLen::X64 => csrs.set_stimecmp(low),
+    }
+    (SBI_SUCCESS, 0)
+}
+
+/// A shutdown stops the machine, with exit code 1 if the kernel reported a system failure. Both
+/// reboots reset the machine.
+fn system_reset(cpu: &mut Cpu, reset_type: RegT, reason: RegT) -> (i64, RegT) {
+    match reset_type {
+        SRST_SHUTDOWN => cpu.exit((reason == SRST_SYSTEM_FAILURE) as i32),
+        SRST_COLD_REBOOT | SRST_WARM_REBOOT => cpu.request_reset(),
+        _ => return (SBI_ERR_INVALID_PARAM, 0),
+    }
+    (SBI_SUCCESS, 0)
+}
+
+/// The debug console extension: console_write, console_read and console_write_byte. The buffers
+/// are at physical addresses, split into a low and a high half.
+fn debug_console(cpu: &mut Cpu, fid: RegT, args: [RegT; 3]) -> Result<(i64, RegT), Exception> {
+    let addr = match cpu.xlen {
+        XLen::X32 => args[1] | args[2] << 32,
+        XLen::X64 => args[1],
+    };
+    let len = args[0] as usize;
+    let result = match fid {
+        0 => {
+            // The buffer has to be in memory, so it can't be longer than DRAM.
+            let dram_size = {
+                let bus = cpu.mmu.bus.borrow();
+                bus.dram_end() - bus.dram_base()
+            };
+            if args[0] > dram_size {
+                return Ok((SBI_ERR_INVALID_PARAM, 0));
+            }
+            let mut buf = vec![0; len];
+            if cpu
+                .mmu
+                .bus
+                .borrow()
+                .read_phys_bytes(addr, &mut buf)
+                .is_err()
+            {
+                return Ok((SBI_ERR_INVALID_ADDRESS, 0));
+            }
+            for c in buf.iter() {
+                write_console(cpu, *c)?;
+            }
+            (SBI_SUCCESS, len as RegT)
+        }
+        1 => {
+            let mut buf = Vec::new();
+            while buf.len() < len {
+                match read_console(cpu)? {
+                    Some(c) => buf.push(c),
+                    None => break,
+                }
+            }
+            if cpu
+                .mmu
+                .bus
+                .borrow_mut()
+                .write_phys_bytes(addr, &buf)
+                .is_err()
+            {
+                return Ok((SBI_ERR_INVALID_ADDRESS, 0));
+            }
+            (SBI_SUCCESS, buf.len() as RegT)
+        }
+        2 => {
+            write_console(cpu, args[0] as u8)?;
+            (SBI_SUCCESS, 0)
+        }
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    Ok(result)
+}
+
+/// Sends `c` through the UART, so it ends up wherever the console output goes.
+fn write_console(cpu: &Cpu, c: u8) -> Result<(), Exception> {
+    cpu.mmu
+        .bus
+        .borrow_mut()
+        .write::<u8>(UART_BASE + UART_THR, c)
+}
+
+/// Takes the next byte the UART received, if there is one.
+fn read_console(cpu: &Cpu) -> Result<Option<u8>, Exception> {
+    let bus = cpu.mmu.bus.borrow();
+    if bus.read::<u8>(UART_BASE + UART_LSR)? & UART_LSR_RX == 0 {
+        return Ok(None);
+    }
+    bus.read::<u8>(UART_BASE + UART_RHR).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::{CpuBuilder, StopReason},
+        device::{DRAM_BASE, DRAM_SIZE},
+        register::mip,
+        testing::*,
+    };
+
+    const DATA: u64 = DRAM_BASE + 0x200;
+
+    /// Loads `value` into x`rd`, for the extension ids that don't fit an ADDI.
+    fn li(rd: u32, value: i32) -> [u32; 2] {
+        let upper = value.wrapping_add(0x800) >> 12;
+        [
+            lui(rd, upper as u32),
+            addi(rd, rd, value.wrapping_sub(upper << 12)),
+        ]
+    }
+
+    /// Loads `DATA` into a1, zero-extended past the sign LUI gives bit 31.
+    fn data_addr() -> [u32; 4] {
+        let [lui, addi] = li(11, DATA as i32);
+        // SLLI and SRLI by 32, the top bit of the shift amount sits in the funct7 field.
+        let slli = r_type(1, 0, 11, 1, 11, 0x13);
+        let srli = r_type(1, 0, 11, 5, 11, 0x13);
+        [lui, addi, slli, srli]
+    }
+
+    /// Builds an S-mode kernel from the calls, each setting a7 and a6 and saving a0 and a1 to
+    /// the next two of s2..s11, then runs it to the final EBREAK.
+    fn run_calls(calls: &[(RegT, RegT, &[u32])], data: &[u8]) -> (Cpu, SharedOutput) {
+        let mut program = Vec::new();
+        for (i, (ext, fid, setup)) in calls.iter().enumerate() {
+            program.extend(setup.iter());
+            program.extend(li(17, *ext as i32));
+            program.push(addi(16, 0, *fid as i32));
+            program.push(ECALL);
+            program.push(addi(18 + 2 * i as u32, 10, 0));
+            program.push(addi(19 + 2 * i as u32, 11, 0));
+        }
+        program.push(EBREAK);
+        let mut binary: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        binary.resize((DATA - DRAM_BASE) as usize, 0);
+        binary.extend(data);
+        let console = SharedOutput::default();
+        let mut cpu = CpuBuilder::new(binary)
+            .builtin_sbi(true)
+            .console(Box::new(console.clone()))
+            .build();
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Supervisor);
+        assert_eq!(cpu.state.pc, DRAM_BASE);
+        cpu.run_until_ebreak(200);
+        (cpu, console)
+    }
+
+    #[test]
+    fn base_extension_describes_the_implementation() {
+        let probe = |ext: i32| li(10, ext);
+        let calls: [(RegT, RegT, &[u32]); 5] = [
+            (EXT_BASE, 0, &[]),
+            (EXT_BASE, 1, &[]),
+            (EXT_BASE, 3, &probe(EXT_TIME as i32)),
+            (EXT_BASE, 3, &probe(0x1234_5678)),
+            (0x1234_5678, 0, &[]),
+        ];
+        let (cpu, _) = run_calls(&calls, &[]);
+        let xs = &cpu.state.xs;
+        assert_eq!((xs.reg(18), xs.reg(19)), (0, SPEC_VERSION));
+        assert_eq!((xs.reg(20), xs.reg(21)), (0, IMPL_ID));
+        assert_eq!((xs.reg(22), xs.reg(23)), (0, 1));
+        assert_eq!((xs.reg(24), xs.reg(25)), (0, 0));
+        assert_eq!(xs.reg(26), SBI_ERR_NOT_SUPPORTED as RegT);
+    }
+
+    #[test]
+    fn set_timer_drives_stip() {
+        let calls: [(RegT, RegT, &[u32]); 1] = [(EXT_TIME, 0, &[addi(10, 0, 0)])];
+        let (mut cpu, _) = run_calls(&calls, &[]);
+        assert_eq!(cpu.state.xs.reg(18), 0);
+        assert_eq!(cpu.state.csrs.stimecmp(), 0);
+        assert_ne!(cpu.state.csrs.mip().bits() & mip::STIP, 0);
+        // A deadline in the future clears it again.
+        cpu.state.xs.set_reg(10, RegT::MAX);
+        cpu.state.xs.set_reg(17, EXT_TIME);
+        cpu.state.xs.set_reg(16, 0);
+        Sbi.ecall(&mut cpu).unwrap();
+        assert_eq!(cpu.state.csrs.mip().bits() & mip::STIP, 0);
+    }
+
+    #[test]
+    fn console_calls_use_the_uart() {
+        let write = [&data_addr()[..], &[addi(10, 0, 3)]].concat();
+        let read = [&data_addr()[..], &[addi(10, 0, 4)]].concat();
+        let calls: [(RegT, RegT, &[u32]); 5] = [
+            (EXT_LEGACY_PUTCHAR, 0, &[addi(10, 0, b'a' as i32)]),
+            (EXT_DBCN, 2, &[addi(10, 0, b'b' as i32)]),
+            (EXT_DBCN, 0, &write),
+            (EXT_LEGACY_GETCHAR, 0, &[]),
+            (EXT_DBCN, 1, &read),
+        ];
+        let (cpu, console) = run_calls(&calls, b"cde");
+        assert_eq!(console.contents(), b"abcde");
+        let xs = &cpu.state.xs;
+        assert_eq!((xs.reg(22), xs.reg(23)), (0, 3));
+        // Nothing was received.
+        assert_eq!(xs.reg(24), RegT::MAX);
+        assert_eq!((xs.reg(26), xs.reg(27)), (0, 0));
+    }
+
+    #[test]
+    fn console_write_rejects_a_length_beyond_memory() {
+        let huge = [&data_addr()[..], &[addi(10, 0, -1)]].concat();
+        let past_dram = [&data_addr()[..], &li(10, DRAM_SIZE as i32)[..]].concat();
+        let calls: [(RegT, RegT, &[u32]); 2] = [(EXT_DBCN, 0, &huge), (EXT_DBCN, 0, &past_dram)];
+        let (cpu, console) = run_calls(&calls, b"abc");
+        assert_eq!(console.contents(), b"");
+        let xs = &cpu.state.xs;
+        assert_eq!(xs.reg(18), SBI_ERR_INVALID_PARAM as RegT);
+        // Fits in DRAM as a length, but the buffer runs past its end.
+        assert_eq!(xs.reg(20), SBI_ERR_INVALID_ADDRESS as RegT);
+    }
+
+    #[test]
+    fn system_reset_shuts_the_machine_down() {
+        let calls: [(RegT, RegT, &[u32]); 2] = [
+            (EXT_IPI, 0, &[]),
+            (EXT_SRST, 0, &[addi(10, 0, 0), addi(11, 0, 1)]),
+        ];
+        let mut program = Vec::new();
+        for (ext, fid, setup) in calls.iter() {
+            program.extend(setup.iter());
+            program.extend(li(17, *ext as i32));
+            program.push(addi(16, 0, *fid as i32));
+            program.push(ECALL);
+        }
+        let binary = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut cpu = CpuBuilder::new(binary)
+            .builtin_sbi(true)
+            .console(Box::new(std::io::sink()))
+            .build();
+        let stop = (0..20).find_map(|_| cpu.one_step());
+        assert!(matches!(stop, Some(StopReason::Exit(1))));
+        assert_eq!(cpu.state.xs.reg(10), 0);
+    }
+
+    #[test]
+    fn user_ecalls_go_to_the_kernel() {
+        let mut cpu = CpuBuilder::new(ECALL.to_le_bytes().to_vec())
+            .builtin_sbi(true)
+            .console(Box::new(std::io::sink()))
+            .build();
+        cpu.state.csrs.set_stvec(DRAM_BASE + 0x100);
+        cpu.state.privilege = PrivilegeMode::User;
+        assert!(cpu.one_step().is_none());
+        assert_eq!(cpu.state.privilege, PrivilegeMode::Supervisor);
+        assert_eq!(cpu.state.csrs.scause(), 8);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x100);
+    }
+}
@@ -0,0 +1,223 @@
+//! RISC-V semihosting: host calls requested by an EBREAK between `slli x0, x0, 0x1f` and
+//! `srai x0, x0, 7`, with the operation in a0 and a pointer to its argument block in a1. Only the
+//! calls bare-metal test programs need for output and for reporting their result are supported.
+
+use log::debug;
+
+use crate::{
+    cpu::Cpu,
+    device::{uart::UART_THR, UART_BASE},
+    trap::Exception,
+    RegT, XLen,
+};
+
+/// `slli x0, x0, 0x1f`, the instruction right before the EBREAK.
+const ENTRY: u32 = 0x01f01013;
+/// `srai x0, x0, 7`, the instruction right after the EBREAK.
+const EXIT: u32 = 0x40705013;
+
+const SYS_WRITEC: RegT = 0x03;
+const SYS_WRITE0: RegT = 0x04;
+const SYS_CLOCK: RegT = 0x10;
+const SYS_EXIT: RegT = 0x18;
+const SYS_EXIT_EXTENDED: RegT = 0x20;
+
+/// The reason reported by SYS_EXIT when the program finished normally.
+const ADP_STOPPED_APPLICATION_EXIT: RegT = 0x20026;
+
+/// mtime is taken to tick at 10 MHz, the rate of QEMU's virt machine the guests are built for.
+const TICKS_PER_CENTISECOND: RegT = 100_000;
+
+/// Returns true if the EBREAK at pc is surrounded by the semihosting sequence. The three
+/// instructions are uncompressed and never straddle a page, so the neighbours are fetched like
+/// the EBREAK itself was.
+pub fn is_call(cpu: &Cpu) -> bool {
+    let fetch = |addr: RegT| -> Result<u32, Exception> {
+        let p_addr = cpu.mmu.translate_fetch(&cpu.state, addr, 4)?.p_addr;
+        cpu.mmu.bus.borrow().read::<u32>(p_addr)
+    };
+    let pc = cpu.state.pc;
+    matches!(
+        (fetch(pc.wrapping_sub(4)), fetch(pc.wrapping_add(4))),
+        (Ok(ENTRY), Ok(EXIT))
+    )
+}
+
+/// Performs the host call requested in a0 and a1 and moves past the EBREAK. The result is
+/// returned in a0. Returns the exit code if the program asked to exit.
+pub fn call(cpu: &mut Cpu) -> Result<Option<i32>, Exception> {
+    let op = cpu.state.xs.reg(10);
+    let arg = cpu.state.xs.reg(11);
+    let mut exit = None;
+    match op {
+        SYS_WRITEC => {
+            let c = cpu.mmu.peek::<u8>(&cpu.state, arg)?;
+            write_console(cpu, c)?;
+        }
+        SYS_WRITE0 => {
+            let mut addr = arg;
+            loop {
+                let c = cpu.mmu.peek::<u8>(&cpu.state, addr)?;
+                if c == 0 {
+                    break;
+                }
+                write_console(cpu, c)?;
+                addr = addr.wrapping_add(1);
+            }
+        }
+        SYS_CLOCK => {
+            let centiseconds = cpu.state.csrs.time() / TICKS_PER_CENTISECOND;
+            cpu.state.xs.set_reg(10, centiseconds & cpu.xlen.mask());
+        }
+        // RV32 passes the reason itself, without an exit code.
+        SYS_EXIT if cpu.xlen == XLen::X32 => {
+            exit = Some(exit_code(
+                arg,
+                (arg != ADP_STOPPED_APPLICATION_EXIT) as RegT,
+            ));
+        }
+        SYS_EXIT | SYS_EXIT_EXTENDED => {
+            let reason = read_field(cpu, arg, 0)?;
+            let subcode = read_field(cpu, arg, 1)?;
+            exit = Some(exit_code(reason, subcode));
+        }
+        _ => {
+            debug!("unsupported semihosting call {:#x}", op);
+            cpu.state.xs.set_reg(10, cpu.xlen.mask());
+        }
+    }
+    cpu.state.advance_pc(4);
+    Ok(exit)
+}
+
+/// Reads the `index`th XLEN-sized field of the argument block at `addr`.
+fn read_field(cpu: &Cpu, addr: RegT, index: RegT) -> Result<RegT, Exception> {
+    let addr = addr.wrapping_add(index * cpu.xlen.size() as RegT);
+    match cpu.xlen {
+        XLen::X32 => Ok(cpu.mmu.peek::<u32>(&cpu.state, addr)? as RegT),
+        XLen::X64 => cpu.mmu.peek::<u64>(&cpu.state, addr),
+    }
+}
+
+/// A normal exit reports the program's exit code, any other reason is a failure.
+fn exit_code(reason: RegT, subcode: RegT) -> i32 {
+    if reason == ADP_STOPPED_APPLICATION_EXIT {
+        subcode as i32
+    } else {
+        1
+    }
+}
+
+/// Sends `c` through the UART, so it ends up wherever the console output goes.
+fn write_console(cpu: &Cpu, c: u8) -> Result<(), Exception> {
+    cpu.mmu
+        .bus
+        .borrow_mut()
+        .write::<u8>(UART_BASE + UART_THR, c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::{CpuBuilder, StopReason},
+        testing::*,
+    };
+
+    const DATA: u64 = DRAM_BASE + 0x100;
+
+    /// Builds a hart running `program` with a1 pointing at `data`, at `DATA`.
+    fn build(xlen: XLen, program: &[u32], data: &[u8], semihosting: bool) -> (Cpu, SharedOutput) {
+        let mut binary: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        binary.resize(0x100, 0);
+        binary.extend(data);
+        let console = SharedOutput::default();
+        let cpu = CpuBuilder::new(binary)
+            .xlen(xlen)
+            .entry(DRAM_BASE)
+            .reg(11, DATA)
+            .console(Box::new(console.clone()))
+            .semihosting(semihosting)
+            .build();
+        (cpu, console)
+    }
+
+    /// Steps until the program exits, returning the exit code.
+    fn run_to_exit(cpu: &mut Cpu) -> i32 {
+        for _ in 0..100 {
+            match cpu.one_step() {
+                None => {}
+                Some(StopReason::Exit(code)) => return code,
+                Some(_) => panic!("stopped at {:#x}", cpu.state.pc),
+            }
+        }
+        panic!("the program never exited");
+    }
+
+    /// Runs to the EBREAK and executes it, which must raise a breakpoint.
+    fn assert_breakpoint(mut cpu: Cpu) {
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 0x200);
+        cpu.run_until_ebreak(10);
+        let ebreak = cpu.state.pc;
+        cpu.one_step();
+        assert_eq!(cpu.state.csrs.mcause(), 3);
+        assert_eq!(cpu.state.csrs.mepc(), ebreak);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 0x200);
+    }
+
+    #[test]
+    fn calls_write_the_console_and_exit() {
+        let program = [
+            addi(10, 0, SYS_WRITE0 as i32),
+            ENTRY,
+            EBREAK,
+            EXIT,
+            addi(10, 0, SYS_WRITEC as i32),
+            addi(11, 11, 1),
+            ENTRY,
+            EBREAK,
+            EXIT,
+            // An unsupported call fails with -1 in a0.
+            addi(10, 0, 0x7f),
+            ENTRY,
+            EBREAK,
+            EXIT,
+            addi(12, 10, 0),
+            addi(10, 0, SYS_EXIT as i32),
+            addi(11, 11, 7),
+            ENTRY,
+            EBREAK,
+            EXIT,
+        ];
+        let mut data = b"hi!\0\0\0\0\0".to_vec();
+        data.extend(ADP_STOPPED_APPLICATION_EXIT.to_le_bytes().iter());
+        data.extend(3u64.to_le_bytes().iter());
+        let (mut cpu, console) = build(XLen::X64, &program, &data, true);
+        assert_eq!(run_to_exit(&mut cpu), 3);
+        assert_eq!(console.contents(), b"hi!i");
+        assert_eq!(cpu.state.xs.reg(12), u64::MAX);
+    }
+
+    #[test]
+    fn rv32_exit_passes_the_reason() {
+        let program = [addi(10, 0, SYS_EXIT as i32), ENTRY, EBREAK, EXIT];
+        let (mut cpu, _) = build(XLen::X32, &program, &[], true);
+        cpu.state.xs.set_reg(11, ADP_STOPPED_APPLICATION_EXIT);
+        assert_eq!(run_to_exit(&mut cpu), 0);
+        let (mut cpu, _) = build(XLen::X32, &program, &[], true);
+        // ADP_Stopped_RunTimeErrorUnknown
+        cpu.state.xs.set_reg(11, 0x20023);
+        assert_eq!(run_to_exit(&mut cpu), 1);
+    }
+
+    #[test]
+    fn ebreak_is_a_breakpoint_outside_the_sequence_or_when_disabled() {
+        let program = [addi(10, 0, SYS_EXIT as i32), ENTRY, EBREAK, EXIT];
+        let (cpu, _) = build(XLen::X64, &program, &[], false);
+        assert_breakpoint(cpu);
+
+        let program = [addi(10, 0, SYS_EXIT as i32), NOP, EBREAK, EXIT];
+        let (cpu, _) = build(XLen::X64, &program, &[], true);
+        assert_breakpoint(cpu);
+    }
+}
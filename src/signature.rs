@@ -0,0 +1,118 @@
+//! Signatures of the RISC-V architectural tests (riscv-arch-test, run by RISCOF). A test stores
+//! its results between the `begin_signature` and `end_signature` symbols and signals the end by
+//! writing to `tohost`. The region is dumped the way the Sail reference model does, so that the
+//! two can be compared line by line.
+
+use std::io::{self, Write};
+
+use crate::{device::bus::Bus, symbols::Symbols, RegT};
+
+/// The memory region holding the signature of a test.
+pub struct Signature {
+    begin: RegT,
+    end: RegT,
+    /// The size of the words written per line, in bytes.
+    granularity: usize,
+}
+
+impl Signature {
+    /// A signature in `[begin, end)` written as `granularity` byte words.
+    pub fn new(begin: RegT, end: RegT, granularity: usize) -> io::Result<Self> {
+        if !matches!(granularity, 1 | 2 | 4 | 8) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the signature granularity must be 1, 2, 4 or 8 bytes",
+            ));
+        }
+        if begin > end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the signature ends before it begins",
+            ));
+        }
+        Ok(Self {
+            begin,
+            end,
+            granularity,
+        })
+    }
+
+    /// The region between the `begin_signature` and `end_signature` symbols.
+    pub fn from_symbols(symbols: &Symbols, granularity: usize) -> io::Result<Self> {
+        let lookup = |name| {
+            symbols.lookup(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no {} symbol, pass the test ELF with --symbols", name),
+                )
+            })
+        };
+        Self::new(
+            lookup("begin_signature")?,
+            lookup("end_signature")?,
+            granularity,
+        )
+    }
+
+    /// Writes one word per line as lowercase hex, zero padded to the granularity. The region is
+    /// read through the physical bus, a partial last word is padded with zeros.
+    pub fn write(&self, bus: &Bus, mut out: impl Write) -> io::Result<()> {
+        let size = self.granularity as RegT;
+        let mut addr = self.begin;
+        while addr < self.end {
+            let mut word = 0;
+            for i in (0..size).filter(|i| addr + i < self.end) {
+                let byte = bus
+                    .read::<u8>(addr + i)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                word |= (byte as u64) << (i * 8);
+            }
+            writeln!(out, "{:0width$x}", word, width = self.granularity * 2)?;
+            addr += size;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::Cpu, testing::*, XLen};
+
+    /// The signature of the `len` bytes at `DRAM_BASE`, which hold 0x00, 0x01, 0x02...
+    fn dump(len: RegT, granularity: usize) -> String {
+        let words: Vec<u32> = (0..8).map(|i| 0x0302_0100 + i * 0x0404_0404).collect();
+        let cpu = Cpu::with_program(XLen::X64, &words);
+        let signature = Signature::new(DRAM_BASE, DRAM_BASE + len, granularity).unwrap();
+        let mut out = Vec::new();
+        signature.write(&cpu.mmu.bus.borrow(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn words_are_written_one_per_line() {
+        assert_eq!(dump(8, 4), "03020100\n07060504\n");
+        assert_eq!(dump(16, 8), "0706050403020100\n0f0e0d0c0b0a0908\n");
+        assert_eq!(dump(3, 1), "00\n01\n02\n");
+        // A partial last word is padded with zeros.
+        assert_eq!(dump(6, 4), "03020100\n00000504\n");
+        assert_eq!(dump(0, 4), "");
+    }
+
+    #[test]
+    fn the_region_comes_from_the_symbols() {
+        let mut symbols = Symbols::new();
+        let elf = symbol_elf(&[
+            ("begin_signature", 0x8000_2000, 0, 0, 1),
+            ("end_signature", 0x8000_2040, 0, 0, 1),
+        ]);
+        symbols.add_elf("test", &elf).unwrap();
+        let signature = Signature::from_symbols(&symbols, 4).unwrap();
+        assert_eq!((signature.begin, signature.end), (0x8000_2000, 0x8000_2040));
+
+        let err = Signature::from_symbols(&Symbols::new(), 4).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(Signature::new(0x100, 0x200, 3).is_err());
+        assert!(Signature::new(0x200, 0x100, 4).is_err());
+    }
+}
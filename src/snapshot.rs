@@ -0,0 +1,166 @@
+//! Helpers for the machine snapshot format. A snapshot is a header followed by the state of the
+//! cpu and every device, each written by its own `save` method as little endian words in a fixed
+//! order, so a snapshot can only be restored by the version that wrote it.
+//!
+//! An incremental snapshot names its parent in the header and only holds the DRAM pages written
+//! since the parent was saved, it is restored over the machine restored from the parent.
+
+use std::io::{self, Read, Write};
+
+/// Identifies a snapshot file.
+const MAGIC: &[u8; 8] = b"RVEMSNAP";
+/// Bumped whenever the layout of any saved state changes.
+const VERSION: u64 = 7;
+
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The longest parent name accepted, so a corrupt header doesn't allocate much.
+const MAX_PARENT_LEN: u64 = 4096;
+
+/// Writes the header of a snapshot, naming its `parent` if it's incremental.
+pub fn write_header(w: &mut dyn Write, parent: Option<&str>) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    write_u64(w, VERSION)?;
+    let parent = parent.unwrap_or("").as_bytes();
+    write_u64(w, parent.len() as u64)?;
+    w.write_all(parent)
+}
+
+/// Rejects anything that isn't a snapshot written by this version. Returns the parent of an
+/// incremental snapshot.
+pub fn check_header(r: &mut dyn Read) -> io::Result<Option<String>> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a snapshot file"));
+    }
+    let version = read_u64(r)?;
+    if version != VERSION {
+        return Err(invalid_data(&format!(
+            "unsupported snapshot version {}, expected {}",
+            version, VERSION
+        )));
+    }
+    let len = read_u64(r)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if len > MAX_PARENT_LEN {
+        return Err(invalid_data("snapshot parent name too long"));
+    }
+    let mut parent = vec![0; len as usize];
+    r.read_exact(&mut parent)?;
+    String::from_utf8(parent)
+        .map(Some)
+        .map_err(|_| invalid_data("snapshot parent name isn't UTF-8"))
+}
+
+pub fn write_u64(w: &mut dyn Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u64(r: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn write_u32s(w: &mut dyn Write, values: &[u32]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|v| w.write_all(&v.to_le_bytes()))
+}
+
+pub fn read_u32s(r: &mut dyn Read, values: &mut [u32]) -> io::Result<()> {
+    for value in values.iter_mut() {
+        let mut bytes = [0; 4];
+        r.read_exact(&mut bytes)?;
+        *value = u32::from_le_bytes(bytes);
+    }
+    Ok(())
+}
+
+/// Size of the chunks `write_sparse` splits data into.
+pub const CHUNK_SIZE: usize = 4096;
+
+/// Writes `data` skipping the chunks that are all zero, which is most of the DRAM of a freshly
+/// booted guest.
+pub fn write_sparse(w: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+    write_sparse_blocks(w, data.len(), std::iter::once((0, data)))
+}
+
+/// Writes `len` bytes of data like `write_sparse`, given as the blocks that may hold anything but
+/// zeros along with their offsets, which are multiples of `CHUNK_SIZE`, in increasing order.
+pub fn write_sparse_blocks<'a>(
+    w: &mut dyn Write,
+    len: usize,
+    blocks: impl Iterator<Item = (usize, &'a [u8])>,
+) -> io::Result<()> {
+    write_u64(w, len as u64)?;
+    for (offset, block) in blocks {
+        for (idx, chunk) in block.chunks(CHUNK_SIZE).enumerate() {
+            if chunk.iter().any(|b| *b != 0) {
+                write_u64(w, (offset / CHUNK_SIZE + idx) as u64)?;
+                w.write_all(chunk)?;
+            }
+        }
+    }
+    write_u64(w, u64::MAX)
+}
+
+/// Writes the chunks at `indices` of `len` bytes of data in the format of `write_sparse`, whether
+/// they are all zero or not, each filled in by `read` from its offset. Reading them back with
+/// `read_sparse_chunks` leaves the other chunks as they were, for incremental snapshots.
+pub fn write_chunks(
+    w: &mut dyn Write,
+    len: usize,
+    indices: &[usize],
+    mut read: impl FnMut(usize, &mut [u8]),
+) -> io::Result<()> {
+    write_u64(w, len as u64)?;
+    let mut chunk = [0; CHUNK_SIZE];
+    for &idx in indices {
+        let offset = idx * CHUNK_SIZE;
+        let chunk = &mut chunk[..(len - offset).min(CHUNK_SIZE)];
+        read(offset, chunk);
+        write_u64(w, idx as u64)?;
+        w.write_all(chunk)?;
+    }
+    write_u64(w, u64::MAX)
+}
+
+/// Reads data written by `write_sparse`, the length must match `data`.
+pub fn read_sparse(r: &mut dyn Read, data: &mut [u8]) -> io::Result<()> {
+    data.iter_mut().for_each(|b| *b = 0);
+    read_sparse_chunks(r, data.len(), |offset, chunk| {
+        data[offset..offset + chunk.len()].copy_from_slice(chunk)
+    })
+}
+
+/// Reads `len` bytes of data written by `write_sparse`, handing every chunk that was written to
+/// `store` along with its offset. The chunks left out are all zero.
+pub fn read_sparse_chunks(
+    r: &mut dyn Read,
+    len: usize,
+    mut store: impl FnMut(usize, &[u8]),
+) -> io::Result<()> {
+    if read_u64(r)? != len as u64 {
+        return Err(invalid_data("snapshot data size mismatch"));
+    }
+    let mut chunk = [0; CHUNK_SIZE];
+    loop {
+        let idx = read_u64(r)?;
+        if idx == u64::MAX {
+            return Ok(());
+        }
+        let offset = (idx as usize)
+            .checked_mul(CHUNK_SIZE)
+            .filter(|offset| *offset < len)
+            .ok_or_else(|| invalid_data("snapshot chunk out of range"))?;
+        let chunk = &mut chunk[..(len - offset).min(CHUNK_SIZE)];
+        r.read_exact(chunk)?;
+        store(offset, chunk);
+    }
+}
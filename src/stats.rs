@@ -0,0 +1,202 @@
+//! Statistics about the emulator itself, to tell whether a change made it faster or changed what
+//! the guest does. Nothing is collected until `Cpu::enable_stats`. Unlike coverage they aren't
+//! collected through a hook: hooks make the cpu single-step, which would skew the speed measured.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{cpu::DecodeStats, trap::Trap};
+
+/// Counts collected while the cpu runs.
+pub(crate) struct Stats {
+    started: Instant,
+    retired: u64,
+    /// Executions per instruction word, turned into mnemonics by `Cpu::stats`.
+    codes: HashMap<u32, u64>,
+    /// Taken traps per cause.
+    traps: HashMap<&'static str, u64>,
+}
+
+impl Stats {
+    /// Starts collecting, the wall clock starts now. Not on wasm32, which has no clock.
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            retired: 0,
+            codes: HashMap::new(),
+            traps: HashMap::new(),
+        }
+    }
+
+    /// Counts the instruction word `code` that was executed. It retired unless it raised an
+    /// exception.
+    pub fn executed(&mut self, code: u32, retired: bool) {
+        *self.codes.entry(code).or_insert(0) += 1;
+        if retired {
+            self.retired += 1;
+        }
+    }
+
+    pub fn trap(&mut self, trap: Trap) {
+        *self.traps.entry(trap.name()).or_insert(0) += 1;
+    }
+
+    /// Summarizes the counts. `mnemonic` names an instruction word, the other figures come from
+    /// the caches of the cpu and the bus.
+    pub fn report(
+        &self,
+        mnemonic: impl Fn(u32) -> String,
+        decode: DecodeStats,
+        insn_cache_hit_rate: f64,
+        mmio: Vec<(String, u64)>,
+    ) -> StatsReport {
+        let mut insns = HashMap::new();
+        for (code, count) in self.codes.iter() {
+            *insns.entry(mnemonic(*code)).or_insert(0) += count;
+        }
+        StatsReport {
+            retired: self.retired,
+            elapsed: self.started.elapsed(),
+            insns: sorted(insns.into_iter().collect()),
+            traps: sorted(
+                self.traps
+                    .iter()
+                    .map(|(name, count)| (name.to_string(), *count))
+                    .collect(),
+            ),
+            decode,
+            insn_cache_hit_rate,
+            mmio: sorted(mmio),
+        }
+    }
+}
+
+/// Sorts the counts from the largest down, ties by name.
+fn sorted(mut counts: Vec<(String, u64)>) -> Vec<(String, u64)> {
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+/// The statistics collected since `Cpu::enable_stats`, returned by `Cpu::stats`.
+pub struct StatsReport {
+    /// Instructions that completed without raising an exception.
+    pub retired: u64,
+    /// Wall-clock time since the collection started.
+    pub elapsed: Duration,
+    /// Executions per mnemonic, the most executed first. Words that don't decode count as
+    /// `unknown`.
+    pub insns: Vec<(String, u64)>,
+    /// Taken traps per cause, the most frequent first.
+    pub traps: Vec<(String, u64)>,
+    pub decode: DecodeStats,
+    /// Fetches served from the decoded instruction cache, which skips the translation as well.
+    pub insn_cache_hit_rate: f64,
+    /// MMIO accesses per device, the busiest first.
+    pub mmio: Vec<(String, u64)>,
+}
+
+impl StatsReport {
+    /// Millions of retired instructions per wall-clock second.
+    pub fn mips(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.retired as f64 / secs / 1e6
+        }
+    }
+
+    /// How often the instructions called `mnemonic` were executed.
+    pub fn count(&self, mnemonic: &str) -> u64 {
+        self.insns
+            .iter()
+            .find(|(name, _)| name == mnemonic)
+            .map_or(0, |(_, count)| *count)
+    }
+}
+
+impl fmt::Display for StatsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} instructions retired in {:.3}s, {:.2} MIPS",
+            self.retired,
+            self.elapsed.as_secs_f64(),
+            self.mips()
+        )?;
+        writeln!(
+            f,
+            "decode cache {:.1}% hits, instruction cache {:.1}% hits",
+            self.decode.hit_rate() * 100.0,
+            self.insn_cache_hit_rate * 100.0
+        )?;
+        writeln!(f, "instructions:")?;
+        for (name, count) in self.insns.iter() {
+            writeln!(f, "  {:<12} {}", name, count)?;
+        }
+        writeln!(f, "traps:")?;
+        for (name, count) in self.traps.iter() {
+            writeln!(f, "  {:<36} {}", name, count)?;
+        }
+        writeln!(f, "mmio accesses:")?;
+        for (name, count) in self.mmio.iter() {
+            writeln!(f, "  {:<12} {}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cpu::Cpu,
+        device::UART_BASE,
+        testing::*,
+        trap::{Exception, Trap},
+        XLen,
+    };
+
+    /// Counts a1 down from 10 incrementing a0, writes the UART scratch register and calls into an
+    /// ECALL handler that is only an EBREAK.
+    const PROGRAM: [u32; 8] = [
+        addi(11, 0, 10),
+        addi(10, 10, 1),
+        addi(11, 11, -1),
+        b_type(-8, 0, 11, 1), // bne a1, x0
+        lui(5, (UART_BASE >> 12) as u32),
+        s_type(7, 0, 5, 0, 0x23), // sb x0, 7(t0)
+        ECALL,
+        EBREAK,
+    ];
+
+    #[test]
+    fn nothing_is_collected_until_enabled() {
+        let mut cpu = Cpu::with_program(XLen::X64, &PROGRAM);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 28);
+        cpu.run_until_ebreak(100);
+        assert!(cpu.stats().is_none());
+    }
+
+    #[test]
+    fn counts_instructions_traps_and_mmio() {
+        let mut cpu = Cpu::with_program(XLen::X64, &PROGRAM);
+        cpu.state.csrs.set_mtvec(DRAM_BASE + 28);
+        cpu.enable_stats();
+        cpu.run_until_ebreak(100);
+        let stats = cpu.stats().unwrap();
+
+        assert_eq!(stats.count("addi"), 21);
+        assert_eq!(stats.count("bne"), 10);
+        assert_eq!(stats.count("ecall"), 1);
+        assert_eq!(stats.count("ebreak"), 0);
+        // The ECALL raised an exception, it didn't retire.
+        assert_eq!(stats.retired, 33);
+        let ecall = Trap::Exception(Exception::MachineEnvCall).name();
+        assert_eq!(stats.traps, [(ecall.to_string(), 1)]);
+        assert_eq!(stats.mmio, [(format!("{:#x}", UART_BASE), 1)]);
+        assert!(stats.to_string().contains("  addi         21\n"));
+    }
+}
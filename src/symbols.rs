@@ -0,0 +1,243 @@
+//! Symbol tables read from ELF files, used to show addresses as `name+offset`. Only the symbol
+//! table is parsed, the image itself is still loaded as a raw binary.
+
+use std::{convert::TryInto, io};
+
+use crate::RegT;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SYM_SIZE: usize = 24;
+
+struct Symbol {
+    addr: RegT,
+    size: RegT,
+    name: String,
+    /// Index into `Symbols::origins` of the file the symbol was read from.
+    origin: usize,
+}
+
+/// Symbols of every loaded file sorted by address.
+#[derive(Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+    origins: Vec<String>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the function, object and label symbols of a 64-bit little endian ELF file. Once a
+    /// second file is added, names are qualified with `origin` as `origin:name`.
+    pub fn add_elf(&mut self, origin: &str, elf: &[u8]) -> io::Result<()> {
+        if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+            return Err(invalid_elf("not a 64-bit little endian ELF file"));
+        }
+        let shoff = read_u64(elf, 0x28)? as usize;
+        let shentsize = read_u16(elf, 0x3a)? as usize;
+        let shnum = read_u16(elf, 0x3c)? as usize;
+        let section = |idx: usize| -> io::Result<(u32, usize, usize, usize)> {
+            let sh = shoff + idx * shentsize;
+            Ok((
+                read_u32(elf, sh + 4)?,
+                read_u64(elf, sh + 24)? as usize,
+                read_u64(elf, sh + 32)? as usize,
+                read_u32(elf, sh + 40)? as usize,
+            ))
+        };
+
+        let origin_idx = self.origins.len();
+        for idx in 0..shnum {
+            let (sh_type, offset, size, link) = section(idx)?;
+            if sh_type != SHT_SYMTAB {
+                continue;
+            }
+            let (_, str_offset, str_size, _) = section(link)?;
+            let strtab = slice(elf, str_offset, str_size)?;
+            for sym in slice(elf, offset, size)?.chunks_exact(SYM_SIZE) {
+                let sym_type = sym[4] & 0xf;
+                let shndx = read_u16(sym, 6)?;
+                if !matches!(sym_type, STT_NOTYPE | STT_OBJECT | STT_FUNC) || shndx == 0 {
+                    continue;
+                }
+                let name = read_str(strtab, read_u32(sym, 0)? as usize)?;
+                // Skip local labels and mapping symbols.
+                if name.is_empty() || name.starts_with(".L") || name.starts_with('$') {
+                    continue;
+                }
+                self.symbols.push(Symbol {
+                    addr: read_u64(sym, 8)?,
+                    size: read_u64(sym, 16)?,
+                    name,
+                    origin: origin_idx,
+                });
+            }
+        }
+        self.origins.push(origin.to_string());
+        self.symbols.sort_by_key(|sym| sym.addr);
+        Ok(())
+    }
+
+    /// Returns the name of the symbol containing `addr` and the offset of `addr` into it. A symbol
+    /// without a size, e.g. an assembly label, contains everything up to the next symbol.
+    pub fn resolve(&self, addr: RegT) -> Option<(String, RegT)> {
+        let sym = self.containing(addr)?;
+        Some((self.qualified_name(sym), addr - sym.addr))
+    }
+
+    /// Returns the file and the name of the symbol containing `addr`, see `resolve`.
+    pub fn function(&self, addr: RegT) -> Option<(&str, &str)> {
+        let sym = self.containing(addr)?;
+        Some((&self.origins[sym.origin], &sym.name))
+    }
+
+    fn containing(&self, addr: RegT) -> Option<&Symbol> {
+        let idx = self.symbols.partition_point(|sym| sym.addr <= addr);
+        let sym = &self.symbols[idx.checked_sub(1)?];
+        if sym.size != 0 && addr - sym.addr >= sym.size {
+            return None;
+        }
+        Some(sym)
+    }
+
+    /// Formats `addr` as `name+0x14`, or just `name` at the start of a symbol.
+    pub fn describe(&self, addr: RegT) -> Option<String> {
+        self.resolve(addr).map(|(name, offset)| match offset {
+            0 => name,
+            offset => format!("{}+{:#x}", name, offset),
+        })
+    }
+
+    /// Returns the address of the symbol called `name`, which may be qualified as `origin:name`.
+    pub fn lookup(&self, name: &str) -> Option<RegT> {
+        let (origin, name) = match name.split_once(':') {
+            Some((origin, name)) => (Some(self.origins.iter().position(|o| o == origin)?), name),
+            None => (None, name),
+        };
+        self.symbols
+            .iter()
+            .find(|sym| sym.name == name && origin.is_none_or(|o| o == sym.origin))
+            .map(|sym| sym.addr)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Iterates over the address and name of every symbol in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (RegT, String)> + '_ {
+        self.symbols
+            .iter()
+            .map(move |sym| (sym.addr, self.qualified_name(sym)))
+    }
+
+    fn qualified_name(&self, sym: &Symbol) -> String {
+        if self.origins.len() > 1 {
+            format!("{}:{}", self.origins[sym.origin], sym.name)
+        } else {
+            sym.name.clone()
+        }
+    }
+}
+
+pub(crate) fn invalid_elf(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn slice(data: &[u8], offset: usize, len: usize) -> io::Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| invalid_elf("truncated ELF file"))
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+    Ok(u16::from_le_bytes(
+        slice(data, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(
+        slice(data, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+pub(crate) fn read_u64(data: &[u8], offset: usize) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(
+        slice(data, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+fn read_str(strtab: &[u8], offset: usize) -> io::Result<String> {
+    let bytes = strtab
+        .get(offset..)
+        .ok_or_else(|| invalid_elf("symbol name out of range"))?;
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::symbol_elf as elf;
+
+    const STT_SECTION: u8 = 3;
+
+    fn kernel() -> Vec<u8> {
+        elf(&[
+            ("loop", 0x1010, 0, STT_NOTYPE, 1),
+            ("main", 0x1000, 0x10, STT_FUNC, 1),
+            (".Lbranch", 0x1008, 0, STT_NOTYPE, 1),
+            (".text", 0x1000, 0, STT_SECTION, 1),
+            ("printf", 0, 0, STT_FUNC, 0),
+        ])
+    }
+
+    #[test]
+    fn addresses_resolve_to_the_symbol_covering_them() {
+        let mut symbols = Symbols::new();
+        symbols.add_elf("kernel", &kernel()).unwrap();
+        // Local labels, section symbols and undefined symbols are skipped.
+        for name in [".Lbranch", ".text", "printf"].iter() {
+            assert_eq!(symbols.lookup(name), None, "{}", name);
+        }
+
+        assert_eq!(symbols.describe(0xfff), None);
+        assert_eq!(symbols.describe(0x1000).as_deref(), Some("main"));
+        assert_eq!(symbols.describe(0x100c).as_deref(), Some("main+0xc"));
+        // A label without a size extends up to the next symbol.
+        assert_eq!(symbols.describe(0x1234).as_deref(), Some("loop+0x224"));
+        assert_eq!(symbols.lookup("main"), Some(0x1000));
+        assert_eq!(symbols.lookup("loop"), Some(0x1010));
+    }
+
+    #[test]
+    fn names_are_qualified_once_a_second_file_is_loaded() {
+        let mut symbols = Symbols::new();
+        symbols.add_elf("kernel", &kernel()).unwrap();
+        let user = elf(&[("main", 0x2000, 8, STT_FUNC, 1)]);
+        symbols.add_elf("user", &user).unwrap();
+
+        assert_eq!(symbols.describe(0x1004).as_deref(), Some("kernel:main+0x4"));
+        assert_eq!(symbols.describe(0x2004).as_deref(), Some("user:main+0x4"));
+        assert_eq!(symbols.describe(0x2008), None);
+        assert_eq!(symbols.lookup("user:main"), Some(0x2000));
+        assert_eq!(symbols.lookup("kernel:main"), Some(0x1000));
+        assert_eq!(symbols.lookup("boot:main"), None);
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let mut symbols = Symbols::new();
+        assert!(symbols.add_elf("raw", &[0x13, 0, 0, 0]).is_err());
+        let mut truncated = kernel();
+        truncated.truncate(100);
+        assert!(symbols.add_elf("kernel", &truncated).is_err());
+        assert_eq!(symbols.lookup("main"), None);
+    }
+}
@@ -0,0 +1,525 @@
+//! Helpers for tests running short programs: a hart with the program at `DRAM_BASE`, a loop
+//! stepping it to an EBREAK, and encoders for the common instructions so the program can be
+//! written without an assembler.
+
+// Not every encoder has a user in every build, e.g. some are only used by the benchmarks.
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    convert::TryInto,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    cpu::{Cpu, CpuBuilder, StopReason},
+    isa::{is_rv32_only, is_rv64_only},
+    trap::Trap,
+    InsnDecoder, RegT, XLen,
+};
+
+pub use crate::device::DRAM_BASE;
+
+pub const EBREAK: u32 = 0x0010_0073;
+pub const ECALL: u32 = 0x0000_0073;
+pub const MRET: u32 = 0x3020_0073;
+pub const SRET: u32 = 0x1020_0073;
+pub const WFI: u32 = 0x1050_0073;
+/// SFENCE.VMA with rs1 and rs2 zero, fencing every address and ASID.
+pub const SFENCE_VMA: u32 = 0x1200_0073;
+pub const NOP: u32 = 0x0000_0013;
+/// FENCE w,0, the Zihintpause hint of a spin-wait loop.
+pub const PAUSE: u32 = 0x0100_000f;
+
+impl Cpu {
+    /// Creates a hart in M-mode with `program` at `DRAM_BASE`, where it starts.
+    pub fn with_program(xlen: XLen, program: &[u32]) -> Cpu {
+        let binary = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        Cpu::new(xlen, binary, DRAM_BASE)
+    }
+
+    /// Steps until pc reaches an EBREAK, which is left unexecuted. Panics with the report if an
+    /// exception stops the machine, or if `max_steps` run out.
+    pub fn run_until_ebreak(&mut self, max_steps: u64) {
+        match self.step_to_ebreak(max_steps) {
+            Some(StopReason::Fatal(report)) => panic!("{}", report),
+            Some(StopReason::DoubleFault(report)) => panic!("{}", report),
+            _ => {}
+        }
+    }
+
+    /// Like `run_until_ebreak`, but returns the trap that stopped the machine instead of
+    /// panicking, for programs expected to fault. A double fault returns the first trap, the
+    /// one the program raised.
+    pub fn try_run_until_ebreak(&mut self, max_steps: u64) -> Result<(), Trap> {
+        match self.step_to_ebreak(max_steps) {
+            Some(StopReason::Fatal(report)) => Err(report.exception.into()),
+            Some(StopReason::DoubleFault(fault)) => Err(fault.first),
+            _ => Ok(()),
+        }
+    }
+
+    /// Steps until an EBREAK or an exception stopping the machine, which is returned.
+    fn step_to_ebreak(&mut self, max_steps: u64) -> Option<StopReason> {
+        for _ in 0..max_steps {
+            if self.at_ebreak() {
+                return None;
+            }
+            match self.one_step() {
+                stop @ Some(StopReason::Fatal(_)) | stop @ Some(StopReason::DoubleFault(_)) => {
+                    return stop
+                }
+                _ => {}
+            }
+        }
+        panic!(
+            "no EBREAK within {} steps, pc {:#x}",
+            max_steps, self.state.pc
+        );
+    }
+
+    fn at_ebreak(&self) -> bool {
+        let insn = self
+            .mmu
+            .translate_fetch(&self.state, self.state.pc, 4)
+            .map(|ctx| ctx.p_addr)
+            .and_then(|p_addr| self.mmu.bus.borrow().read::<u32>(p_addr));
+        insn == Ok(EBREAK)
+    }
+}
+
+/// Counts up in a0 and stores every count to the next word at a1, forever.
+pub const COUNTER: [u32; 4] = [addi(10, 10, 1), sw(10, 11, 0), addi(11, 11, 4), jal(0, -12)];
+pub const COUNTS: u64 = DRAM_BASE + 0x1000;
+
+/// Runs `steps` steps, returning pc and the registers after each.
+pub fn trace(cpu: &mut Cpu, steps: usize) -> Vec<(RegT, Vec<RegT>)> {
+    (0..steps)
+        .map(|_| {
+            assert!(cpu.one_step().is_none());
+            (cpu.state.pc, registers(cpu))
+        })
+        .collect()
+}
+
+/// The integer registers, x0 to x31.
+pub fn registers(cpu: &Cpu) -> Vec<RegT> {
+    (0..32).map(|i| cpu.state.xs.reg(i)).collect()
+}
+
+/// The first 0x20 doublewords at `COUNTS`.
+pub fn counts(cpu: &Cpu) -> Vec<u64> {
+    (0..0x20)
+        .map(|i| cpu.mmu.bus.borrow().read::<u64>(COUNTS + i * 8).unwrap())
+        .collect()
+}
+
+/// An output the test reads back, e.g. the console or a trace. Clones write to the same bytes.
+#[derive(Clone, Default)]
+pub struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl SharedOutput {
+    /// The bytes written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// The lines `ThreadLog` recorded on this thread, as target and message.
+    static LOG: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Records the log lines of every thread separately, the tests running in parallel log as
+/// well.
+struct ThreadLog;
+
+impl log::Log for ThreadLog {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let line = (record.target().to_string(), record.args().to_string());
+        LOG.with(|log| log.borrow_mut().push(line));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Returns the lines logged on this thread since the last call.
+pub fn logged() -> Vec<(String, String)> {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&ThreadLog).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+    LOG.with(|log| std::mem::take(&mut *log.borrow_mut()))
+}
+
+/// Asserts the values of the registers given by name, e.g. `assert_regs!(cpu.state.xs, a0 = 1,
+/// sp = 0x100)`. A mismatch prints the whole register file.
+#[macro_export]
+macro_rules! assert_regs {
+    ($xs:expr, $($name:ident = $value:expr),+ $(,)?) => {{
+        let xs = &$xs;
+        $(
+            let actual = xs
+                .get_by_name(stringify!($name))
+                .expect(concat!("no register ", stringify!($name)));
+            assert_eq!(
+                actual,
+                ($value) as $crate::RegT,
+                "{} differs, registers:\n{}",
+                stringify!($name),
+                xs
+            );
+        )+
+    }};
+}
+
+pub use crate::assert_regs;
+
+/// Executes the single instruction `insn` with `rs1` in a1 and `rs2` in a2 and returns a0, for
+/// the register instructions encoded with rd a0, rs1 a1 and rs2 a2.
+pub fn exec_op(xlen: XLen, insn: u32, rs1: RegT, rs2: RegT) -> RegT {
+    let mut cpu = Cpu::with_program(xlen, &[insn, EBREAK]);
+    cpu.state.xs.set_reg(11, rs1);
+    cpu.state.xs.set_reg(12, rs2);
+    cpu.run_until_ebreak(10);
+    cpu.state.xs.reg(10)
+}
+
+/// Returns true if a hart of `xlen` decodes `code` rather than raising an illegal instruction.
+pub fn decodes(xlen: XLen, code: u32) -> bool {
+    let reserved = match xlen {
+        XLen::X32 => is_rv64_only(code),
+        XLen::X64 => is_rv32_only(code),
+    };
+    !reserved && InsnDecoder::new(xlen).decode(code).is_some()
+}
+
+// The flags of a page table entry.
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+pub const PTE_U: u64 = 1 << 4;
+pub const PTE_A: u64 = 1 << 6;
+pub const PTE_D: u64 = 1 << 7;
+
+/// Sv39 page tables written to the DRAM of a hart, e.g. one from `Cpu::with_program`.
+pub struct PageTables {
+    root: u64,
+    /// Where the next table goes.
+    next: u64,
+}
+
+impl PageTables {
+    /// Starts with an empty root table at `base`, which must be page aligned and zeroed. The
+    /// tables added take the pages after it.
+    pub fn new(base: u64) -> Self {
+        Self {
+            root: base,
+            next: base + 0x1000,
+        }
+    }
+
+    /// The satp value selecting the tables.
+    pub fn satp(&self) -> RegT {
+        8 << 60 | self.root >> 12
+    }
+
+    /// Maps the 4 KiB page at `v_addr` to `p_addr`, with `flags` in the leaf PTE. Returns the
+    /// address of the PTE.
+    pub fn map(&mut self, cpu: &mut Cpu, v_addr: u64, p_addr: u64, flags: u64) -> u64 {
+        let mut bus = cpu.mmu.bus.borrow_mut();
+        let mut table = self.root;
+        for level in (1..3).rev() {
+            let pte_addr = table + (v_addr >> (12 + 9 * level) & 0x1ff) * 8;
+            let entry = bus.read::<u64>(pte_addr).unwrap();
+            table = if entry & PTE_V == 0 {
+                let next = self.next;
+                self.next += 0x1000;
+                bus.write::<u64>(pte_addr, next >> 12 << 10 | PTE_V)
+                    .unwrap();
+                next
+            } else {
+                entry >> 10 << 12
+            };
+        }
+        let pte_addr = table + (v_addr >> 12 & 0x1ff) * 8;
+        bus.write::<u64>(pte_addr, p_addr >> 12 << 10 | flags)
+            .unwrap();
+        pte_addr
+    }
+}
+
+/// The DRAM of the fuzzed machines, small enough to set up quickly on every run and large enough
+/// for page tables and virtqueues.
+const FUZZ_DRAM_SIZE: usize = 1 << 20;
+const FUZZ_MAX_STEPS: u64 = 10_000;
+/// The bytes describing one access of `fuzz_bus`: the operation and size, the address and the
+/// value.
+const FUZZ_ACCESS_LEN: usize = 1 + 8 + 8;
+
+/// The `run` fuzz target: runs arbitrary bytes as a program. Whatever the guest does, the
+/// emulator must not panic, every failure has to surface as a trap taken by the guest or as a
+/// `StopReason`. The first byte picks the XLEN, the rest is loaded at the start of the DRAM.
+pub fn fuzz_program(data: &[u8]) {
+    let (xlen, program) = match data.split_first() {
+        Some((&first, program)) if first & 1 == 1 => (XLen::X32, program),
+        Some((_, program)) => (XLen::X64, program),
+        None => return,
+    };
+    let mut cpu = CpuBuilder::new(program.to_vec())
+        .xlen(xlen)
+        .dram(DRAM_BASE, FUZZ_DRAM_SIZE)
+        .disk(vec![0; 4096])
+        .console(Box::new(io::sink()))
+        .semihosting(true)
+        .build();
+    let mut steps = 0;
+    while steps < FUZZ_MAX_STEPS {
+        let (taken, stop) = cpu.run_block(FUZZ_MAX_STEPS - steps);
+        steps += taken.max(1);
+        match stop {
+            Some(StopReason::Exit(_))
+            | Some(StopReason::Fatal(_))
+            | Some(StopReason::DoubleFault(_)) => break,
+            _ => {}
+        }
+    }
+}
+
+/// The `bus` fuzz target: performs arbitrary reads and writes on the physical bus, the DRAM, the
+/// MMIO registers of every device and the holes between them. Accesses may fault but must never
+/// panic.
+pub fn fuzz_bus(data: &[u8]) {
+    let cpu = CpuBuilder::new(Vec::new())
+        .dram(DRAM_BASE, FUZZ_DRAM_SIZE)
+        .disk(vec![0; 4096])
+        .console(Box::new(io::sink()))
+        .build();
+    let mut bus = cpu.mmu.bus.borrow_mut();
+    for access in data.chunks_exact(FUZZ_ACCESS_LEN) {
+        let op = access[0];
+        let mut addr = u64::from_le_bytes(access[1..9].try_into().unwrap());
+        let value = u64::from_le_bytes(access[9..17].try_into().unwrap());
+        // Bit 3 keeps the address near one of the devices or the DRAM, so the interesting
+        // registers are hit more often than the whole 64-bit space would allow.
+        if op & 0x8 != 0 {
+            addr = [
+                0x0200_0000,
+                0x0c00_0000,
+                0x1000_0000,
+                0x1000_1000,
+                DRAM_BASE,
+            ][(addr >> 32) as usize % 5]
+                + (addr & 0xffff);
+        }
+        let _ = match op & 0x7 {
+            0 => bus.read::<u8>(addr).map(drop),
+            1 => bus.read::<u16>(addr).map(drop),
+            2 => bus.read::<u32>(addr).map(drop),
+            3 => bus.read::<u64>(addr).map(drop),
+            4 => bus.write::<u8>(addr, value as u8),
+            5 => bus.write::<u16>(addr, value as u16),
+            6 => bus.write::<u32>(addr, value as u32),
+            _ => bus.write::<u64>(addr, value),
+        };
+        // Let the devices act on what was written, e.g. the virtio disk on a notify.
+        bus.poll_devices();
+        bus.update_irq_lines();
+    }
+}
+
+/// R-type: `funct7 rs2 rs1 funct3 rd opcode`.
+pub const fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+/// I-type, `imm` is the signed 12-bit immediate.
+pub const fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm as u32) & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+/// S-type, `imm` is the signed 12-bit offset.
+pub const fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+/// U-type, `imm` holds the upper 20 bits in its low bits.
+pub const fn u_type(imm: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xf_ffff) << 12 | rd << 7 | opcode
+}
+
+/// B-type, `offset` is the signed byte offset from the branch.
+pub const fn b_type(offset: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | 0x63
+}
+
+/// J-type, `offset` is the signed byte offset from the instruction.
+pub const fn j_type(offset: i32, rd: u32, opcode: u32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | rd << 7
+        | opcode
+}
+
+pub const fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(imm, rs1, 0, rd, 0x13)
+}
+
+pub const fn auipc(rd: u32, imm: u32) -> u32 {
+    u_type(imm, rd, 0x17)
+}
+
+/// CSRRW, `csr` is the CSR number.
+pub const fn csrrw(rd: u32, csr: u32, rs1: u32) -> u32 {
+    csr << 20 | rs1 << 15 | 1 << 12 | rd << 7 | 0x73
+}
+
+/// CSRRS, `csr` is the CSR number. With rs1 zero it is `csrr`.
+pub const fn csrrs(rd: u32, csr: u32, rs1: u32) -> u32 {
+    csr << 20 | rs1 << 15 | 2 << 12 | rd << 7 | 0x73
+}
+
+pub const fn jal(rd: u32, offset: i32) -> u32 {
+    j_type(offset, rd, 0x6f)
+}
+
+pub const fn ld(rd: u32, rs1: u32, offset: i32) -> u32 {
+    i_type(offset, rs1, 3, rd, 0x03)
+}
+
+pub const fn lw(rd: u32, rs1: u32, offset: i32) -> u32 {
+    i_type(offset, rs1, 2, rd, 0x03)
+}
+
+pub const fn lui(rd: u32, imm: u32) -> u32 {
+    u_type(imm, rd, 0x37)
+}
+
+pub const fn sw(rs2: u32, rs1: u32, offset: i32) -> u32 {
+    s_type(offset, rs2, rs1, 2, 0x23)
+}
+
+pub const fn sd(rs2: u32, rs1: u32, offset: i32) -> u32 {
+    s_type(offset, rs2, rs1, 3, 0x23)
+}
+
+/// The M extension, `funct3` picks the operation: 0 mul, 1 mulh, 2 mulhsu, 3 mulhu, 4 div,
+/// 5 divu, 6 rem, 7 remu.
+pub const fn muldiv(funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r_type(1, rs2, rs1, funct3, rd, 0x33)
+}
+
+/// The word operations of the M extension on RV64, `funct3` as for `muldiv`.
+pub const fn muldivw(funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r_type(1, rs2, rs1, funct3, rd, 0x3b)
+}
+
+/// The A extension with aq and rl clear, `funct5` picks the operation, e.g. 0b00010 lr,
+/// 0b00011 sc, 0b00001 amoswap. `width` is 2 for words and 3 for doublewords.
+pub const fn amo(funct5: u32, width: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r_type(funct5 << 2, rs2, rs1, width, rd, 0x2f)
+}
+
+/// Builds a 64-bit ELF file with nothing but a symbol table holding `(name, addr, size, type,
+/// section index)` entries, for `Symbols::add_elf`.
+pub fn symbol_elf(symbols: &[(&str, RegT, RegT, u8, u16)]) -> Vec<u8> {
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 24];
+    for (name, addr, size, sym_type, shndx) in symbols {
+        symtab.extend((strtab.len() as u32).to_le_bytes());
+        symtab.extend([*sym_type, 0]);
+        symtab.extend(shndx.to_le_bytes());
+        symtab.extend(addr.to_le_bytes());
+        symtab.extend(size.to_le_bytes());
+        strtab.extend(name.bytes().chain([0]));
+    }
+
+    let mut elf = vec![0; 64];
+    elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    let symtab_offset = elf.len();
+    elf.extend(&symtab);
+    let strtab_offset = elf.len();
+    elf.extend(&strtab);
+    let shoff = elf.len();
+    elf[0x28..0x30].copy_from_slice(&(shoff as u64).to_le_bytes());
+    elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+    elf[0x3c..0x3e].copy_from_slice(&3u16.to_le_bytes());
+    // The null section, the symbol table and its string table, as (type, offset, size, link).
+    let sections = [
+        (0u32, 0, 0, 0),
+        (2, symtab_offset, symtab.len(), 2),
+        (3, strtab_offset, strtab.len(), 0),
+    ];
+    for &(sh_type, offset, size, link) in sections.iter() {
+        let mut sh = vec![0; 64];
+        sh[4..8].copy_from_slice(&sh_type.to_le_bytes());
+        sh[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+        sh[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+        sh[40..44].copy_from_slice(&(link as u32).to_le_bytes());
+        elf.extend(sh);
+    }
+    elf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    /// Feeds every input kept for the fuzz target `target` to `fuzz`: the regressions checked in
+    /// and the corpus of a local cargo-fuzz run, if there is one.
+    fn replay(target: &str, fuzz: fn(&[u8])) -> usize {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz");
+        let mut replayed = 0;
+        for dir in ["regressions", "corpus"].iter() {
+            let entries = match fs::read_dir(root.join(dir).join(target)) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries {
+                let path = entry.unwrap().path();
+                let data = fs::read(&path).unwrap();
+                let result = std::panic::catch_unwind(|| fuzz(&data));
+                assert!(result.is_ok(), "{} panicked", path.display());
+                replayed += 1;
+            }
+        }
+        replayed
+    }
+
+    #[test]
+    fn fuzz_inputs_replay_without_panicking() {
+        assert!(replay("run", fuzz_program) > 0);
+        assert!(replay("bus", fuzz_bus) > 0);
+    }
+}
@@ -1,5 +1,7 @@
+use std::fmt;
+
 #[allow(dead_code)]
-use crate::RegT;
+use crate::{RegT, XLen};
 
 /// Trap Cause
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -20,6 +22,44 @@ impl From<Interrupt> for Trap {
     }
 }
 
+impl Trap {
+    /// The value written to xcause: the code, with the interrupt bit at XLEN-1 for interrupts.
+    pub fn cause_code(&self, xlen: XLen) -> RegT {
+        match self {
+            Trap::Interrupt(i) => i.code() | 1 << (xlen.len() - 1),
+            Trap::Exception(e) => e.code(),
+        }
+    }
+
+    /// Turns a value read from xcause back into the trap, the inverse of `cause_code`. Exceptions
+    /// come back without their xtval payload.
+    pub fn from_cause_code(cause: RegT, xlen: XLen) -> Self {
+        let interrupt = 1 << (xlen.len() - 1);
+        if cause & interrupt != 0 {
+            Trap::Interrupt((cause & !interrupt).into())
+        } else {
+            Trap::Exception(cause.into())
+        }
+    }
+
+    /// The name of the cause, without the address an exception may carry.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Trap::Interrupt(i) => i.name(),
+            Trap::Exception(e) => e.name(),
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::Interrupt(i) => i.fmt(f),
+            Trap::Exception(e) => e.fmt(f),
+        }
+    }
+}
+
 #[allow(dead_code)]
 /// Interrupt
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -39,24 +79,75 @@ pub enum Interrupt {
 /// Exception
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Exception {
-    InstructionMisaligned,
-    InstructionFault,
-    IllegalInstruction,
+    /// Carries the misaligned target address.
+    InstructionMisaligned(RegT),
+    /// Carries the faulting address, see `at`.
+    InstructionFault(RegT),
+    /// Carries the bits of the illegal instruction. Instructions raise it with 0, the cpu fills in
+    /// the bits of the instruction it was executing.
+    IllegalInstruction(RegT),
     Breakpoint,
-    LoadMisaligned,
-    LoadFault,
-    StoreMisaligned,
-    StoreFault,
+    /// Carries the misaligned load address.
+    LoadMisaligned(RegT),
+    /// Carries the faulting address, see `at`.
+    LoadFault(RegT),
+    /// Carries the misaligned store address.
+    StoreMisaligned(RegT),
+    /// Carries the faulting address, see `at`.
+    StoreFault(RegT),
     UserEnvCall,
     SupervisorEnvCall,
     MachineEnvCall,
-    InstructionPageFault,
-    LoadPageFault,
-    StorePageFault,
+    /// Carries the faulting virtual address.
+    InstructionPageFault(RegT),
+    /// Carries the faulting virtual address.
+    LoadPageFault(RegT),
+    /// Carries the faulting virtual address.
+    StorePageFault(RegT),
     Unknown,
 }
 
+impl From<RegT> for Interrupt {
+    /// Turns an interrupt code, without the interrupt bit, into the interrupt.
+    fn from(code: RegT) -> Self {
+        match code {
+            0 => Interrupt::UserSoft,
+            1 => Interrupt::SupervisorSoft,
+            3 => Interrupt::MachineSoft,
+            4 => Interrupt::UserTimer,
+            5 => Interrupt::SupervisorTimer,
+            7 => Interrupt::MachineTimer,
+            8 => Interrupt::UserExternal,
+            9 => Interrupt::SupervisorExternal,
+            11 => Interrupt::MachineExternal,
+            _ => Interrupt::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 impl Interrupt {
+    /// The name of the interrupt in the privileged spec's cause table.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interrupt::UserSoft => "User software interrupt",
+            Interrupt::SupervisorSoft => "Supervisor software interrupt",
+            Interrupt::MachineSoft => "Machine software interrupt",
+            Interrupt::UserTimer => "User timer interrupt",
+            Interrupt::SupervisorTimer => "Supervisor timer interrupt",
+            Interrupt::MachineTimer => "Machine timer interrupt",
+            Interrupt::UserExternal => "User external interrupt",
+            Interrupt::SupervisorExternal => "Supervisor external interrupt",
+            Interrupt::MachineExternal => "Machine external interrupt",
+            Interrupt::Unknown => "Unknown interrupt",
+        }
+    }
+
     pub fn code(&self) -> RegT {
         match self {
             Interrupt::UserSoft => 0,
@@ -73,36 +164,189 @@ impl Interrupt {
     }
 }
 
+impl From<RegT> for Exception {
+    /// Turns an exception code into the exception. The value carried by some exceptions isn't
+    /// part of the code, it comes back as 0.
+    fn from(code: RegT) -> Self {
+        match code {
+            0 => Exception::InstructionMisaligned(0),
+            1 => Exception::InstructionFault(0),
+            2 => Exception::IllegalInstruction(0),
+            3 => Exception::Breakpoint,
+            4 => Exception::LoadMisaligned(0),
+            5 => Exception::LoadFault(0),
+            6 => Exception::StoreMisaligned(0),
+            7 => Exception::StoreFault(0),
+            8 => Exception::UserEnvCall,
+            9 => Exception::SupervisorEnvCall,
+            11 => Exception::MachineEnvCall,
+            12 => Exception::InstructionPageFault(0),
+            13 => Exception::LoadPageFault(0),
+            15 => Exception::StorePageFault(0),
+            _ => Exception::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    /// The name of the exception, followed by the address for the exceptions carrying one.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.name();
+        match self {
+            Exception::InstructionMisaligned(addr)
+            | Exception::InstructionFault(addr)
+            | Exception::LoadMisaligned(addr)
+            | Exception::LoadFault(addr)
+            | Exception::StoreMisaligned(addr)
+            | Exception::StoreFault(addr)
+            | Exception::InstructionPageFault(addr)
+            | Exception::LoadPageFault(addr)
+            | Exception::StorePageFault(addr) => write!(f, "{} at {:#x}", name, addr),
+            _ => f.write_str(name),
+        }
+    }
+}
+
 impl Exception {
+    /// The name of the exception in the privileged spec's cause table.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exception::InstructionMisaligned(_) => "Instruction address misaligned",
+            Exception::InstructionFault(_) => "Instruction access fault",
+            Exception::IllegalInstruction(_) => "Illegal instruction",
+            Exception::Breakpoint => "Breakpoint",
+            Exception::LoadMisaligned(_) => "Load address misaligned",
+            Exception::LoadFault(_) => "Load access fault",
+            Exception::StoreMisaligned(_) => "Store/AMO address misaligned",
+            Exception::StoreFault(_) => "Store/AMO access fault",
+            Exception::UserEnvCall => "Environment call from U-mode",
+            Exception::SupervisorEnvCall => "Environment call from S-mode",
+            Exception::MachineEnvCall => "Environment call from M-mode",
+            Exception::InstructionPageFault(_) => "Instruction page fault",
+            Exception::LoadPageFault(_) => "Load page fault",
+            Exception::StorePageFault(_) => "Store/AMO page fault",
+            Exception::Unknown => "Unknown exception",
+        }
+    }
+
     pub fn code(&self) -> RegT {
         match self {
-            Exception::InstructionMisaligned => 0,
-            Exception::InstructionFault => 1,
-            Exception::IllegalInstruction => 2,
+            Exception::InstructionMisaligned(_) => 0,
+            Exception::InstructionFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
             Exception::Breakpoint => 3,
-            Exception::LoadMisaligned => 4,
-            Exception::LoadFault => 5,
-            Exception::StoreMisaligned => 6,
-            Exception::StoreFault => 7,
+            Exception::LoadMisaligned(_) => 4,
+            Exception::LoadFault(_) => 5,
+            Exception::StoreMisaligned(_) => 6,
+            Exception::StoreFault(_) => 7,
             Exception::UserEnvCall => 8,
             Exception::SupervisorEnvCall => 9,
             Exception::MachineEnvCall => 11,
-            Exception::InstructionPageFault => 12,
-            Exception::LoadPageFault => 13,
-            Exception::StorePageFault => 15,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
             Exception::Unknown => 8888,
         }
     }
 
-    pub fn is_fatal(&self) -> bool {
+    /// The value written to xtval when the exception is taken.
+    pub fn tval(&self) -> RegT {
         match self {
-            Exception::InstructionFault
-            | Exception::IllegalInstruction
-            | Exception::InstructionMisaligned
-            | Exception::LoadFault
-            | Exception::StorePageFault
-            | Exception::StoreMisaligned => true,
-            _ => false,
+            Exception::InstructionMisaligned(addr)
+            | Exception::InstructionFault(addr)
+            | Exception::LoadMisaligned(addr)
+            | Exception::LoadFault(addr)
+            | Exception::StoreMisaligned(addr)
+            | Exception::StoreFault(addr)
+            | Exception::InstructionPageFault(addr)
+            | Exception::LoadPageFault(addr)
+            | Exception::StorePageFault(addr) => *addr,
+            Exception::IllegalInstruction(bits) => *bits,
+            _ => 0,
         }
     }
+
+    /// Moves an access fault to `addr`. A device raises it at the offset it was accessed at, the
+    /// bus moves it to the address of the access. Other exceptions are returned as they are.
+    pub fn at(self, addr: RegT) -> Self {
+        match self {
+            Exception::InstructionFault(_) => Exception::InstructionFault(addr),
+            Exception::LoadFault(_) => Exception::LoadFault(addr),
+            Exception::StoreFault(_) => Exception::StoreFault(addr),
+            e => e,
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Exception::StorePageFault(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXCEPTIONS: [Exception; 14] = [
+        Exception::InstructionMisaligned(0),
+        Exception::InstructionFault(0),
+        Exception::IllegalInstruction(0),
+        Exception::Breakpoint,
+        Exception::LoadMisaligned(0),
+        Exception::LoadFault(0),
+        Exception::StoreMisaligned(0),
+        Exception::StoreFault(0),
+        Exception::UserEnvCall,
+        Exception::SupervisorEnvCall,
+        Exception::MachineEnvCall,
+        Exception::InstructionPageFault(0),
+        Exception::LoadPageFault(0),
+        Exception::StorePageFault(0),
+    ];
+
+    const INTERRUPTS: [Interrupt; 9] = [
+        Interrupt::UserSoft,
+        Interrupt::SupervisorSoft,
+        Interrupt::MachineSoft,
+        Interrupt::UserTimer,
+        Interrupt::SupervisorTimer,
+        Interrupt::MachineTimer,
+        Interrupt::UserExternal,
+        Interrupt::SupervisorExternal,
+        Interrupt::MachineExternal,
+    ];
+
+    #[test]
+    fn cause_codes_decode_back_into_the_trap() {
+        for &xlen in [XLen::X32, XLen::X64].iter() {
+            for &e in EXCEPTIONS.iter() {
+                let trap = Trap::Exception(e);
+                assert_eq!(Trap::from_cause_code(trap.cause_code(xlen), xlen), trap);
+            }
+            for &i in INTERRUPTS.iter() {
+                let trap = Trap::Interrupt(i);
+                assert_eq!(Trap::from_cause_code(trap.cause_code(xlen), xlen), trap);
+            }
+        }
+        let timer = Trap::Interrupt(Interrupt::MachineTimer);
+        assert_eq!(timer.cause_code(XLen::X32), 0x8000_0007);
+        assert_eq!(timer.cause_code(XLen::X64), 0x8000_0000_0000_0007);
+        assert_eq!(Exception::from(10), Exception::Unknown);
+        assert_eq!(Interrupt::from(2), Interrupt::Unknown);
+    }
+
+    #[test]
+    fn traps_display_the_spec_names() {
+        let fault = Trap::Exception(Exception::StorePageFault(0x1000));
+        assert_eq!(fault.to_string(), "Store/AMO page fault at 0x1000");
+        assert_eq!(fault.name(), "Store/AMO page fault");
+        let illegal = Exception::IllegalInstruction(0xffff_ffff);
+        assert_eq!(illegal.to_string(), "Illegal instruction");
+        assert_eq!(illegal.tval(), 0xffff_ffff);
+        let fault = Exception::LoadFault(0x10).at(0x8000_0010);
+        assert_eq!(fault.to_string(), "Load access fault at 0x80000010");
+        assert_eq!(fault.tval(), 0x8000_0010);
+        assert_eq!(Exception::Breakpoint.at(0x10), Exception::Breakpoint);
+        let timer = Trap::Interrupt(Interrupt::SupervisorTimer);
+        assert_eq!(timer.to_string(), "Supervisor timer interrupt");
+    }
 }
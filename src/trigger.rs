@@ -0,0 +1,281 @@
+//! The trigger module of the debug specification (Sdtrig), reduced to what guest debug stubs use
+//! for hardware breakpoints and watchpoints: a few address match triggers in the mcontrol format
+//! raising a breakpoint exception before the matching instruction or access.
+
+use std::io::{self, Read, Write};
+
+use crate::{mmu::AccessType, snapshot, PrivilegeMode, RegT, XLen};
+
+/// Number of triggers selectable through tselect.
+pub const TRIGGERS: usize = 4;
+
+/// tdata1.type of an address/data match trigger.
+const TYPE_MCONTROL: RegT = 2;
+
+/// mcontrol fields.
+const MCONTROL_LOAD: RegT = 1 << 0;
+const MCONTROL_STORE: RegT = 1 << 1;
+const MCONTROL_EXECUTE: RegT = 1 << 2;
+const MCONTROL_U: RegT = 1 << 3;
+const MCONTROL_S: RegT = 1 << 4;
+const MCONTROL_M: RegT = 1 << 6;
+/// The mcontrol fields that can be written. Everything else reads as zero: the trigger belongs
+/// to M-mode rather than debug mode, matches the address in tdata2 exactly, fires before the
+/// instruction or access and its action is a breakpoint exception.
+const MCONTROL_WRITABLE: RegT =
+    MCONTROL_LOAD | MCONTROL_STORE | MCONTROL_EXECUTE | MCONTROL_U | MCONTROL_S | MCONTROL_M;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Trigger {
+    /// The writable mcontrol fields of tdata1, see `MCONTROL_WRITABLE`.
+    control: RegT,
+    /// The address to match.
+    tdata2: RegT,
+}
+
+impl Trigger {
+    /// Returns true if the trigger fires on an access of `a_type` to `addr` in `privilege`.
+    fn matches(&self, a_type: &AccessType, addr: RegT, privilege: PrivilegeMode) -> bool {
+        let kind = match a_type {
+            AccessType::Load => MCONTROL_LOAD,
+            AccessType::Store => MCONTROL_STORE,
+            AccessType::Fetch => MCONTROL_EXECUTE,
+            AccessType::Amo => MCONTROL_LOAD | MCONTROL_STORE,
+        };
+        let mode = match privilege {
+            PrivilegeMode::User => MCONTROL_U,
+            PrivilegeMode::Supervisor => MCONTROL_S,
+            PrivilegeMode::Machine => MCONTROL_M,
+        };
+        self.control & kind != 0 && self.control & mode != 0 && self.tdata2 == addr
+    }
+}
+
+/// The triggers behind tselect, tdata1 and tdata2.
+#[derive(Clone, Debug, Default)]
+pub struct Triggers {
+    tselect: usize,
+    triggers: [Trigger; TRIGGERS],
+    /// Whether any trigger matches anything, so the fetch and the MMU skip the checks otherwise.
+    armed: bool,
+}
+
+impl Triggers {
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    pub fn tselect(&self) -> RegT {
+        self.tselect as RegT
+    }
+
+    /// Selecting a trigger that doesn't exist leaves tselect unchanged, which is how debuggers
+    /// count the triggers.
+    pub fn set_tselect(&mut self, value: RegT) {
+        if value < TRIGGERS as RegT {
+            self.tselect = value as usize;
+        }
+    }
+
+    /// Every trigger is an mcontrol trigger, type sits in the top four bits.
+    pub fn tdata1(&self, xlen: XLen) -> RegT {
+        TYPE_MCONTROL << (xlen.len() - 4) | self.triggers[self.tselect].control
+    }
+
+    /// Writing a type other than mcontrol, e.g. 0, disables the selected trigger.
+    pub fn set_tdata1(&mut self, xlen: XLen, value: RegT) {
+        let control = if value >> (xlen.len() - 4) & 0xf == TYPE_MCONTROL {
+            value & MCONTROL_WRITABLE
+        } else {
+            0
+        };
+        self.triggers[self.tselect].control = control;
+        self.update_armed();
+    }
+
+    pub fn tdata2(&self) -> RegT {
+        self.triggers[self.tselect].tdata2
+    }
+
+    pub fn set_tdata2(&mut self, value: RegT) {
+        self.triggers[self.tselect].tdata2 = value;
+    }
+
+    fn update_armed(&mut self) {
+        let enables = MCONTROL_LOAD | MCONTROL_STORE | MCONTROL_EXECUTE;
+        self.armed = self.triggers.iter().any(|t| t.control & enables != 0);
+    }
+
+    /// Returns true if a trigger fires on an access of `a_type` to `addr` in `privilege`.
+    pub fn hit(&self, a_type: &AccessType, addr: RegT, privilege: PrivilegeMode) -> bool {
+        self.triggers
+            .iter()
+            .any(|t| t.matches(a_type, addr, privilege))
+    }
+
+    pub fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        snapshot::write_u64(w, self.tselect as u64)?;
+        self.triggers.iter().try_for_each(|t| {
+            snapshot::write_u64(w, t.control)?;
+            snapshot::write_u64(w, t.tdata2)
+        })
+    }
+
+    pub fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.set_tselect(snapshot::read_u64(r)?);
+        for t in self.triggers.iter_mut() {
+            t.control = snapshot::read_u64(r)? & MCONTROL_WRITABLE;
+            t.tdata2 = snapshot::read_u64(r)?;
+        }
+        self.update_armed();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::Cpu, testing::*};
+
+    const MSTATUS_MIE: RegT = 1 << 3;
+    const DATA: u64 = DRAM_BASE + 0x1000;
+    /// The trap handler of `run`, an EBREAK.
+    const MTVEC: u64 = DRAM_BASE + 0x100;
+    const TSELECT: u16 = 0x7a0;
+    const TDATA1: u16 = 0x7a1;
+    const TDATA2: u16 = 0x7a2;
+    const TDATA3: u16 = 0x7a3;
+    const TINFO: u16 = 0x7a4;
+
+    /// tdata1 of an mcontrol trigger on RV64.
+    const fn mcontrol(fields: RegT) -> RegT {
+        TYPE_MCONTROL << 60 | fields
+    }
+
+    /// Sets a0 and a1, stores a0 at DATA through a2, then sets a3.
+    const PROGRAM: [u32; 5] = [
+        addi(10, 0, 1),
+        addi(11, 0, 2),
+        sw(10, 12, 0),
+        addi(13, 0, 3),
+        EBREAK,
+    ];
+
+    /// Runs `PROGRAM` in `privilege` with trigger 1 set to `tdata1` and `tdata2`, up to its
+    /// EBREAK or the one of the trap handler.
+    fn run(privilege: PrivilegeMode, tdata1: RegT, tdata2: RegT) -> Cpu {
+        let mut image = vec![NOP; 0x41];
+        image[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+        image[0x40] = EBREAK;
+        let mut cpu = Cpu::with_program(XLen::X64, &image);
+        let csrs = &mut cpu.state.csrs;
+        csrs.set_mtvec(MTVEC);
+        csrs.set_csr(0x300, MSTATUS_MIE);
+        csrs.set_csr(TSELECT, 1);
+        csrs.set_csr(TDATA1, tdata1);
+        csrs.set_csr(TDATA2, tdata2);
+        cpu.state.xs.set_reg(12, DATA);
+        cpu.state.privilege = privilege;
+        cpu.run_until_ebreak(10);
+        cpu
+    }
+
+    /// The cause of the trap `run` took, if it took one.
+    fn trap(cpu: &Cpu) -> Option<RegT> {
+        (cpu.state.pc == MTVEC).then(|| cpu.state.csrs.mcause())
+    }
+
+    fn stored(cpu: &Cpu) -> u32 {
+        cpu.mmu.bus.borrow().read::<u32>(DATA).unwrap()
+    }
+
+    #[test]
+    fn execute_trigger_breaks_before_the_instruction() {
+        let tdata1 = mcontrol(MCONTROL_EXECUTE | MCONTROL_M);
+        let cpu = run(PrivilegeMode::Machine, tdata1, DRAM_BASE + 4);
+        assert_eq!(trap(&cpu), Some(3));
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE + 4);
+        assert_eq!(cpu.state.xs.reg(10), 1);
+        assert_eq!(cpu.state.xs.reg(11), 0);
+    }
+
+    #[test]
+    fn store_trigger_suppresses_the_store() {
+        let tdata1 = mcontrol(MCONTROL_STORE | MCONTROL_S);
+        let cpu = run(PrivilegeMode::Supervisor, tdata1, DATA);
+        assert_eq!(trap(&cpu), Some(3));
+        assert_eq!(cpu.state.csrs.mepc(), DRAM_BASE + 8);
+        assert_eq!(stored(&cpu), 0);
+        assert_eq!(cpu.state.xs.reg(11), 2);
+        assert_eq!(cpu.state.xs.reg(13), 0);
+
+        // A load trigger on the same address lets the store through.
+        let tdata1 = mcontrol(MCONTROL_LOAD | MCONTROL_S);
+        let cpu = run(PrivilegeMode::Supervisor, tdata1, DATA);
+        assert_eq!(trap(&cpu), None);
+        assert_eq!(stored(&cpu), 1);
+    }
+
+    #[test]
+    fn triggers_fire_only_in_their_modes() {
+        let execute = MCONTROL_EXECUTE | MCONTROL_S | MCONTROL_M;
+        let cpu = run(PrivilegeMode::User, mcontrol(execute), DRAM_BASE + 4);
+        assert_eq!(trap(&cpu), None);
+
+        // In M-mode only while MIE is set, otherwise a trigger hit in the handler would loop.
+        let mut cpu = Cpu::with_program(XLen::X64, &PROGRAM);
+        cpu.state.csrs.set_csr(TDATA1, mcontrol(execute));
+        cpu.state.csrs.set_csr(TDATA2, DRAM_BASE + 4);
+        cpu.state.xs.set_reg(12, DATA);
+        cpu.run_until_ebreak(10);
+        assert_eq!(cpu.state.pc, DRAM_BASE + 16);
+    }
+
+    #[test]
+    fn trigger_csrs_read_back_their_legal_values() {
+        let mut cpu = Cpu::with_program(XLen::X64, &[]);
+        let csrs = &mut cpu.state.csrs;
+        csrs.set_csr(TSELECT, 2);
+        csrs.set_csr(TSELECT, TRIGGERS as RegT);
+        assert_eq!(csrs.csr(TSELECT), 2);
+        assert_eq!(csrs.csr(TINFO), 1 << TYPE_MCONTROL);
+
+        csrs.set_csr(TDATA1, mcontrol(!0 >> 4));
+        assert_eq!(csrs.csr(TDATA1), mcontrol(MCONTROL_WRITABLE));
+        assert!(csrs.triggers_armed());
+        // Any other type disables the trigger.
+        csrs.set_csr(TDATA1, MCONTROL_WRITABLE);
+        assert_eq!(csrs.csr(TDATA1), mcontrol(0));
+        assert!(!csrs.triggers_armed());
+        csrs.set_csr(TDATA3, 5);
+        assert_eq!(csrs.csr(TDATA3), 0);
+
+        // RV32 keeps the type in bits 31:28.
+        let mut cpu = Cpu::with_program(XLen::X32, &[]);
+        cpu.state
+            .csrs
+            .set_csr(TDATA1, TYPE_MCONTROL << 28 | MCONTROL_LOAD);
+        assert_eq!(
+            cpu.state.csrs.csr(TDATA1),
+            TYPE_MCONTROL << 28 | MCONTROL_LOAD
+        );
+    }
+
+    #[test]
+    fn snapshot_restores_the_triggers() {
+        let mut triggers = Triggers::default();
+        triggers.set_tselect(3);
+        triggers.set_tdata1(XLen::X64, mcontrol(MCONTROL_LOAD | MCONTROL_U));
+        triggers.set_tdata2(DATA);
+        let mut bytes = Vec::new();
+        triggers.save(&mut bytes).unwrap();
+
+        let mut restored = Triggers::default();
+        restored.restore(&mut bytes.as_slice()).unwrap();
+        assert_eq!(restored.tselect(), 3);
+        assert_eq!(restored.tdata2(), DATA);
+        assert!(restored.armed());
+        assert!(restored.hit(&AccessType::Load, DATA, PrivilegeMode::User));
+        assert!(!restored.hit(&AccessType::Store, DATA, PrivilegeMode::User));
+    }
+}
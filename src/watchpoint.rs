@@ -0,0 +1,87 @@
+//! Watchpoints stopping the machine when the guest accesses a range of virtual addresses.
+
+use crate::RegT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Both reads and writes.
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, is_write: bool) -> bool {
+        match self {
+            WatchKind::Read => !is_write,
+            WatchKind::Write => is_write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+/// An access that hit a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// pc of the instruction that made the access.
+    pub pc: RegT,
+    pub addr: RegT,
+    pub size: usize,
+    pub is_write: bool,
+    /// The value in memory before a write, `None` for reads or if it couldn't be read.
+    pub old: Option<u64>,
+    /// The value read or written.
+    pub value: u64,
+}
+
+struct Watchpoint {
+    start: RegT,
+    /// Exclusive.
+    end: RegT,
+    kind: WatchKind,
+}
+
+/// Watched ranges sorted by start address.
+#[derive(Default)]
+pub struct Watchpoints {
+    ranges: Vec<Watchpoint>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn add(&mut self, addr: RegT, len: RegT, kind: WatchKind) {
+        let idx = self.ranges.partition_point(|w| w.start <= addr);
+        self.ranges.insert(
+            idx,
+            Watchpoint {
+                start: addr,
+                end: addr.saturating_add(len.max(1)),
+                kind,
+            },
+        );
+    }
+
+    /// Removes the watchpoints starting at `addr`, returns false if there were none.
+    pub fn remove(&mut self, addr: RegT) -> bool {
+        let len = self.ranges.len();
+        self.ranges.retain(|w| w.start != addr);
+        self.ranges.len() != len
+    }
+
+    /// Returns true if an access of `size` bytes at `addr` overlaps a matching watchpoint.
+    pub fn hit(&self, addr: RegT, size: usize, is_write: bool) -> bool {
+        let end = addr.saturating_add(size as RegT);
+        // Only ranges starting before the end of the access can overlap it.
+        let idx = self.ranges.partition_point(|w| w.start < end);
+        self.ranges[..idx]
+            .iter()
+            .any(|w| w.end > addr && w.kind.matches(is_write))
+    }
+}
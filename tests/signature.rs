@@ -0,0 +1,28 @@
+//! Runs `tests/data/signature_rv64i` with `--signature` and compares the dump with the expected
+//! signature checked in next to it.
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[test]
+fn signature_matches_the_expected_one() {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("signature_rv64i.signature");
+    let _ = fs::remove_file(&out);
+    let status = Command::new(env!("CARGO_BIN_EXE_riscv-emulator"))
+        .arg("--symbols")
+        .arg(data.join("signature_rv64i.elf"))
+        .arg("--signature")
+        .arg(&out)
+        .args(["--max-steps", "1000"])
+        .arg(data.join("signature_rv64i.bin"))
+        .stdin(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let expected = fs::read_to_string(data.join("signature_rv64i.signature")).unwrap();
+    assert_eq!(fs::read_to_string(&out).unwrap(), expected);
+}